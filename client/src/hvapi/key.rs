@@ -0,0 +1,74 @@
+//! Reads the hypercall key the loader generated for this boot.
+//!
+//! The loader stores the key in the volatile `shared::VMCALL_KEY_VARIABLE` firmware variable. Windows only
+//! returns firmware variables to processes holding `SeSystemEnvironmentPrivilege`, which requires an
+//! elevated client. Without the variable, the key built into the hypervisor image is used.
+
+use {
+    shared::{PASSWORD, VARIABLE_VENDOR, VMCALL_KEY_VARIABLE},
+    std::{ffi::c_void, mem::size_of, ptr, sync::OnceLock},
+    windows_sys::Win32::{
+        Foundation::{CloseHandle, GetLastError, HANDLE, LUID},
+        Security::{
+            AdjustTokenPrivileges, LookupPrivilegeValueW, LUID_AND_ATTRIBUTES, SE_PRIVILEGE_ENABLED, SE_SYSTEM_ENVIRONMENT_NAME,
+            TOKEN_ADJUST_PRIVILEGES, TOKEN_PRIVILEGES,
+        },
+        System::{
+            Threading::{GetCurrentProcess, OpenProcessToken},
+            WindowsProgramming::GetFirmwareEnvironmentVariableW,
+        },
+    },
+};
+
+/// The key read on first use.
+static VMCALL_KEY: OnceLock<u64> = OnceLock::new();
+
+/// Returns the value RAX has to hold for a CPUID to be taken as a hypercall.
+pub fn vmcall_key() -> u64 {
+    *VMCALL_KEY.get_or_init(|| match read_vmcall_key() {
+        Some(key) => key,
+        None => {
+            log::warn!("Failed to read {} (error {}), using the key built into the hypervisor", VMCALL_KEY_VARIABLE, unsafe { GetLastError() });
+            PASSWORD
+        }
+    })
+}
+
+/// Reads the key from the firmware variable.
+fn read_vmcall_key() -> Option<u64> {
+    enable_system_environment_privilege();
+
+    let name = wide(VMCALL_KEY_VARIABLE);
+    let vendor = wide(&format!("{{{}}}", VARIABLE_VENDOR));
+    let mut key = [0u8; 8];
+
+    let size = unsafe { GetFirmwareEnvironmentVariableW(name.as_ptr(), vendor.as_ptr(), key.as_mut_ptr() as *mut c_void, key.len() as u32) };
+    (size as usize == key.len()).then(|| u64::from_le_bytes(key))
+}
+
+/// Enables `SeSystemEnvironmentPrivilege`, which elevated processes hold but have disabled by default.
+fn enable_system_environment_privilege() {
+    let mut token: HANDLE = ptr::null_mut();
+    if unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_ADJUST_PRIVILEGES, &mut token) } == 0 {
+        return;
+    }
+
+    let mut luid = LUID { LowPart: 0, HighPart: 0 };
+    if unsafe { LookupPrivilegeValueW(ptr::null(), SE_SYSTEM_ENVIRONMENT_NAME, &mut luid) } != 0 {
+        let privileges = TOKEN_PRIVILEGES {
+            PrivilegeCount: 1,
+            Privileges: [LUID_AND_ATTRIBUTES {
+                Luid: luid,
+                Attributes: SE_PRIVILEGE_ENABLED,
+            }],
+        };
+        unsafe { AdjustTokenPrivileges(token, 0, &privileges, size_of::<TOKEN_PRIVILEGES>() as u32, ptr::null_mut(), ptr::null_mut()) };
+    }
+
+    unsafe { CloseHandle(token) };
+}
+
+/// Encodes a string as a NUL terminated UTF-16 string.
+fn wide(text: &str) -> Vec<u16> {
+    text.encode_utf16().chain(Some(0)).collect()
+}
@@ -7,10 +7,20 @@
 
 use {
     crate::{pemem::djb2_hash, ssn::Syscall},
-    shared::{ClientCommand, ClientDataPayload, Command, HookData, ProcessMemoryOperation, PASSWORD},
+    shared::{
+        hypercall::{EptViewReport, StatsReport},
+        vmcall::{
+            EventRecord, ProcessEntry, SyscallWatchEntry, VmcallNumber, VmcallRequest, VmcallResponse, VmcallStatus, MAX_MEMORY_LENGTH,
+            MAX_SYSCALL_WATCHES, MEMORY_FORCE_WRITE, PROCESS_NAME_LENGTH, SYSCALL_WATCH_ANY, VMCALL_VERSION,
+        },
+        ClientCommand, ClientDataPayload, Command, HookData, ProcessMemoryOperation,
+    },
     std::arch::asm,
 };
 
+mod key;
+pub mod probe;
+
 /// Struct to encapsulate the result of a CPUID instruction.
 #[derive(Debug)]
 pub struct CpuidResult {
@@ -55,7 +65,8 @@ impl HypervisorCommunicator {
             payload: ClientDataPayload::Hook(hook_data),
         };
 
-        let result = Self::call_hypervisor(client_command.as_ptr());
+        let request = client_command.encode();
+        let result = Self::call_hypervisor(request.as_ptr());
 
         if result.eax == 1 {
             log::debug!("Successfully managed EPT hook for function: {}", function_name);
@@ -85,7 +96,8 @@ impl HypervisorCommunicator {
             payload: command_payload,
         };
 
-        let result = Self::call_hypervisor(client_command.as_ptr());
+        let request = client_command.encode();
+        let result = Self::call_hypervisor(request.as_ptr());
 
         if result.eax == 1 {
             log::debug!("Opened process with CR3: {:#x}", communicator.process_cr3);
@@ -98,7 +110,7 @@ impl HypervisorCommunicator {
 
     /// Sends a command to the hypervisor using CPUID.
     fn call_hypervisor(command_rcx: u64) -> CpuidResult {
-        let mut rax = PASSWORD;
+        let mut rax = key::vmcall_key();
         let mut rbx;
         let mut rcx = command_rcx;
         let mut rdx;
@@ -124,6 +136,292 @@ impl HypervisorCommunicator {
         }
     }
 
+    /// Issues a VMCALL hypercall, see `shared::vmcall`.
+    ///
+    /// Raises #UD without the hypervisor, or with a key the hypervisor doesn't take.
+    fn vmcall(request: VmcallRequest) -> Option<VmcallResponse> {
+        let mut registers = request.encode(key::vmcall_key());
+
+        unsafe {
+            asm!(
+            "vmcall",
+            inout("rax") registers.rax,
+            inout("rcx") registers.rcx,
+            inout("rdx") registers.rdx,
+            inout("r8") registers.r8,
+            inout("r9") registers.r9,
+            in("r10") registers.r10,
+            in("r11") registers.r11,
+            in("r12") registers.r12,
+            options(nostack),
+            );
+        }
+
+        VmcallResponse::decode(&registers)
+    }
+
+    /// Checks the hypervisor answers VMCALL hypercalls and speaks the version of this client.
+    ///
+    /// Returns the version of the hypervisor.
+    pub fn ping() -> Option<u32> {
+        let response = Self::vmcall(VmcallRequest {
+            number: VmcallNumber::Ping as u64,
+            args: [VMCALL_VERSION as u64, 0, 0, 0, 0],
+        })?;
+
+        match response.status {
+            VmcallStatus::Success => Some(response.values[0] as u32),
+            status => {
+                log::error!("Ping failed with {:?}, the hypervisor speaks version {:#x}", status, response.values[0]);
+                None
+            }
+        }
+    }
+
+    /// Reads guest virtual memory without the kernel's help.
+    ///
+    /// `cr3` is the address space to read, zero for the one of this process. Returns the bytes read, fewer than
+    /// the buffer holds when the hypervisor found a page that isn't mapped.
+    pub fn read_virtual(cr3: u64, address: u64, buffer: &mut [u8]) -> Option<usize> {
+        buffer.fill(0);
+        Self::copy_memory(VmcallNumber::ReadGva, cr3, address, buffer.as_mut_ptr() as u64, buffer.len(), 0)
+    }
+
+    /// Writes guest virtual memory without the kernel's help.
+    ///
+    /// `cr3` is the address space to write, zero for the one of this process. With `force`, pages the guest maps
+    /// read-only are written as well. Returns the bytes written, see `read_virtual`.
+    pub fn write_virtual(cr3: u64, address: u64, buffer: &[u8], force: bool) -> Option<usize> {
+        let flags = if force { MEMORY_FORCE_WRITE } else { 0 };
+        Self::touch(buffer);
+        Self::copy_memory(VmcallNumber::WriteGva, cr3, address, buffer.as_ptr() as u64, buffer.len(), flags)
+    }
+
+    /// Reads guest physical memory, returns the bytes read, see `read_virtual`.
+    pub fn read_physical(address: u64, buffer: &mut [u8]) -> Option<usize> {
+        buffer.fill(0);
+        Self::copy_memory(VmcallNumber::ReadGpa, 0, address, buffer.as_mut_ptr() as u64, buffer.len(), 0)
+    }
+
+    /// Writes guest physical memory, returns the bytes written, see `read_virtual`.
+    pub fn write_physical(address: u64, buffer: &[u8]) -> Option<usize> {
+        Self::touch(buffer);
+        Self::copy_memory(VmcallNumber::WriteGpa, 0, address, buffer.as_ptr() as u64, buffer.len(), 0)
+    }
+
+    /// Finds a process by the name of its image, ignoring case, without the kernel's help.
+    ///
+    /// Windows keeps the first `PROCESS_NAME_LENGTH` bytes of a name, a longer one is compared on those. Returns
+    /// the process with its EPROCESS as a guest-physical address and its CR3, `None` if no process has the name.
+    pub fn find_process(name: &str) -> Option<ProcessEntry> {
+        let name = name.as_bytes();
+        Self::touch(name);
+        let response = Self::vmcall(VmcallRequest {
+            number: VmcallNumber::FindProcess as u64,
+            args: [name.as_ptr() as u64, name.len() as u64, 0, 0, 0],
+        })?;
+
+        match response.status {
+            VmcallStatus::Success => {
+                let mut entry = ProcessEntry {
+                    eprocess: response.values[0],
+                    directory_table_base: response.values[1],
+                    process_id: response.values[2],
+                    ..Default::default()
+                };
+                let length = name.len().min(PROCESS_NAME_LENGTH);
+                entry.image_file_name[..length].copy_from_slice(&name[..length]);
+                Some(entry)
+            }
+            status => {
+                log::error!("Finding {:?} failed with {:?}", String::from_utf8_lossy(name), status);
+                None
+            }
+        }
+    }
+
+    /// Returns the address of an export of ntoskrnl.exe, as the hypervisor reads it from the image of the kernel.
+    pub fn resolve_kernel_export(name: &str) -> Option<u64> {
+        let name = name.as_bytes();
+        Self::touch(name);
+        let response = Self::vmcall(VmcallRequest {
+            number: VmcallNumber::ResolveKernelExport as u64,
+            args: [name.as_ptr() as u64, name.len() as u64, 0, 0, 0],
+        })?;
+
+        match response.status {
+            VmcallStatus::Success => Some(response.values[0]),
+            status => {
+                log::error!("Resolving {:?} failed with {:?}", String::from_utf8_lossy(name), status);
+                None
+            }
+        }
+    }
+
+    /// Lists the processes of the guest, as the process list of the kernel links them.
+    pub fn processes() -> Option<Vec<ProcessEntry>> {
+        let mut entries = vec![ProcessEntry::default(); 256];
+
+        loop {
+            let response = Self::vmcall(VmcallRequest {
+                number: VmcallNumber::EnumerateProcesses as u64,
+                args: [entries.as_mut_ptr() as u64, entries.len() as u64, 0, 0, 0],
+            })?;
+
+            match response.status {
+                // New processes may have started since, make room for a few more.
+                VmcallStatus::Success if response.values[0] > response.values[1] => {
+                    entries.resize(response.values[0] as usize + 16, ProcessEntry::default())
+                }
+                VmcallStatus::Success => {
+                    entries.truncate(response.values[1] as usize);
+                    return Some(entries);
+                }
+                status => {
+                    log::error!("Listing the processes failed with {:?}", status);
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// Watches a syscall, `None` for every one, made in the address space of `cr3`, zero for every address space.
+    ///
+    /// The hypervisor logs the syscalls a rule matches, `read_events` returns them.
+    pub fn watch_syscall(number: Option<u32>, cr3: u64) -> Option<()> {
+        Self::manage_syscall_watch(VmcallNumber::SyscallWatchAdd, number, cr3)
+    }
+
+    /// Stops watching a syscall `watch_syscall` watched.
+    pub fn unwatch_syscall(number: Option<u32>, cr3: u64) -> Option<()> {
+        Self::manage_syscall_watch(VmcallNumber::SyscallWatchRemove, number, cr3)
+    }
+
+    fn manage_syscall_watch(call: VmcallNumber, number: Option<u32>, cr3: u64) -> Option<()> {
+        let number = number.map_or(SYSCALL_WATCH_ANY, u64::from);
+        let response = Self::vmcall(VmcallRequest {
+            number: call as u64,
+            args: [number, cr3, 0, 0, 0],
+        })?;
+
+        match response.status {
+            VmcallStatus::Success => Some(()),
+            status => {
+                log::error!("{:?} of syscall {:#x} failed with {:?}", call, number, status);
+                None
+            }
+        }
+    }
+
+    /// Lists the syscalls watched, with the syscalls each rule matched.
+    pub fn syscall_watches() -> Option<Vec<SyscallWatchEntry>> {
+        let mut entries = vec![SyscallWatchEntry::default(); MAX_SYSCALL_WATCHES];
+        let response = Self::vmcall(VmcallRequest {
+            number: VmcallNumber::SyscallWatchList as u64,
+            args: [entries.as_mut_ptr() as u64, entries.len() as u64, 0, 0, 0],
+        })?;
+
+        match response.status {
+            VmcallStatus::Success => {
+                entries.truncate(response.values[1] as usize);
+                Some(entries)
+            }
+            status => {
+                log::error!("Listing the syscall watches failed with {:?}", status);
+                None
+            }
+        }
+    }
+
+    /// Returns the syscalls the hypervisor intercepted, those a rule matched and the VM exits taken for them.
+    pub fn syscall_stats() -> Option<[u64; 3]> {
+        let response = Self::vmcall(VmcallRequest {
+            number: VmcallNumber::GetSyscallStats as u64,
+            args: [0; 5],
+        })?;
+
+        match response.status {
+            VmcallStatus::Success => Some(response.values),
+            status => {
+                log::error!("Reading the syscall counters failed with {:?}", status);
+                None
+            }
+        }
+    }
+
+    /// Compares the pages of all EPT hooks with their clean copies, the hypervisor logs the modified bytes as
+    /// events for `read_events`. Returns the pages audited, those the guest sees modified and the runs of modified
+    /// bytes.
+    pub fn audit_hooks() -> Option<[u64; 3]> {
+        let response = Self::vmcall(VmcallRequest {
+            number: VmcallNumber::AuditHooks as u64,
+            args: [0; 5],
+        })?;
+
+        match response.status {
+            VmcallStatus::Success => Some(response.values),
+            status => {
+                log::error!("Auditing the hooks failed with {:?}", status);
+                None
+            }
+        }
+    }
+
+    /// Takes up to `count` of the oldest events of the hypervisor, returns them and the events lost since the
+    /// previous call.
+    pub fn read_events(count: usize) -> Option<(Vec<EventRecord>, u64)> {
+        let mut records = vec![EventRecord::default(); count];
+        let response = Self::vmcall(VmcallRequest {
+            number: VmcallNumber::ReadEvents as u64,
+            args: [records.as_mut_ptr() as u64, records.len() as u64, 0, 0, 0],
+        })?;
+
+        match response.status {
+            VmcallStatus::Success => {
+                records.truncate(response.values[0] as usize);
+                Some((records, response.values[1]))
+            }
+            status => {
+                log::error!("Reading the events failed with {:?}", status);
+                None
+            }
+        }
+    }
+
+    /// Reads a byte of every page of a buffer, so that the hypervisor finds them present.
+    fn touch(buffer: &[u8]) {
+        for page in buffer.chunks(0x1000) {
+            unsafe { std::ptr::read_volatile(page.as_ptr()) };
+        }
+    }
+
+    /// Copies memory with a memory VMCALL, `MAX_MEMORY_LENGTH` bytes at a time.
+    ///
+    /// The hypervisor walks the page tables of this process for the buffer and doesn't page it in, the callers
+    /// touch its pages first. Returns the bytes copied, `None` if a call failed.
+    fn copy_memory(number: VmcallNumber, cr3: u64, address: u64, buffer: u64, length: usize, flags: u64) -> Option<usize> {
+        let mut copied = 0;
+
+        while copied < length as u64 {
+            let chunk = (length as u64 - copied).min(MAX_MEMORY_LENGTH);
+            let response = Self::vmcall(VmcallRequest {
+                number: number as u64,
+                args: [cr3, address.wrapping_add(copied), buffer + copied, chunk, flags],
+            })?;
+
+            match response.status {
+                VmcallStatus::Success => copied += chunk,
+                VmcallStatus::PartialCopy => return Some((copied + response.values[0]) as usize),
+                status => {
+                    log::error!("{:?} of {:#x} failed with {:?}", number, address.wrapping_add(copied), status);
+                    return None;
+                }
+            }
+        }
+
+        Some(copied as usize)
+    }
+
     /// Reads memory from the opened process using the stored CR3.
     pub fn read_process_memory(&self, address: u64, buffer: &mut [u8]) -> Option<()> {
         log::debug!("Reading memory from address: {:#x}", address);
@@ -141,7 +439,8 @@ impl HypervisorCommunicator {
             payload: ClientDataPayload::Memory(memory_operation),
         };
 
-        let result = Self::call_hypervisor(client_command.as_ptr());
+        let request = client_command.encode();
+        let result = Self::call_hypervisor(request.as_ptr());
 
         if result.eax == 1 {
             log::debug!("Memory read successfully");
@@ -169,7 +468,8 @@ impl HypervisorCommunicator {
             payload: ClientDataPayload::Memory(memory_operation),
         };
 
-        let result = Self::call_hypervisor(client_command.as_ptr());
+        let request = client_command.encode();
+        let result = Self::call_hypervisor(request.as_ptr());
 
         if result.eax == 1 {
             log::debug!("Memory written successfully");
@@ -179,4 +479,62 @@ impl HypervisorCommunicator {
             None
         }
     }
+
+    /// Reports the EPT view every processor runs the guest with, one record per processor that started.
+    ///
+    /// Returns the number of records the hypervisor filled, processors past the end of `reports` are left out.
+    pub fn query_ept_views(&self, reports: &mut [EptViewReport]) -> Option<usize> {
+        reports.fill(EptViewReport::default());
+
+        let memory_operation = ProcessMemoryOperation {
+            process_id: None,
+            guest_cr3: None,
+            address: None,
+            buffer: reports.as_mut_ptr() as u64,
+            buffer_size: std::mem::size_of_val(reports) as u64,
+        };
+
+        let client_command = ClientCommand {
+            command: Command::QueryEptViews,
+            payload: ClientDataPayload::Memory(memory_operation),
+        };
+
+        let request = client_command.encode();
+        let result = Self::call_hypervisor(request.as_ptr());
+
+        if result.eax == 1 {
+            Some(reports.iter().take_while(|report| report.state != 0).count())
+        } else {
+            log::error!("Failed to query the EPT views");
+            None
+        }
+    }
+
+    /// Reports the counters of the hypervisor, the NMIs of the guest among them.
+    pub fn query_stats(&self) -> Option<StatsReport> {
+        let mut report = StatsReport::default();
+
+        let memory_operation = ProcessMemoryOperation {
+            process_id: None,
+            guest_cr3: None,
+            address: None,
+            buffer: &mut report as *mut StatsReport as u64,
+            buffer_size: std::mem::size_of::<StatsReport>() as u64,
+        };
+
+        let client_command = ClientCommand {
+            command: Command::QueryStats,
+            payload: ClientDataPayload::Memory(memory_operation),
+        };
+
+        let request = client_command.encode();
+        let result = Self::call_hypervisor(request.as_ptr());
+
+        if result.eax == 1 {
+            Some(report)
+        } else {
+            log::error!("Failed to query the stats");
+            None
+        }
+    }
 }
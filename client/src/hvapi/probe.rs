@@ -0,0 +1,51 @@
+//! Probes what the hypervisor reports to a guest from user mode, to check a boot with `hyperv_masquerade`.
+//!
+//! Only CPUID is available at CPL 3, the synthetic MSRs and the hypercall page need a kernel-mode probe.
+
+use {
+    shared::hyperv::{is_hyperv_vendor, HV_ACCESS_HYPERCALL_MSRS, HYPERV_INTERFACE_SIGNATURE},
+    std::arch::x86_64::__cpuid,
+};
+
+/// Bit 31 of ECX for CPUID with EAX=1, indicating hypervisor presence.
+const HYPERVISOR_PRESENT_BIT: u32 = 1 << 31;
+
+/// The Hyper-V interface the hypervisor CPUID leaves report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HypervInterface {
+    /// The highest hypervisor leaf, EAX of leaf 0x40000000.
+    pub max_leaf: u32,
+
+    /// The partition privileges, EAX of leaf 0x40000003.
+    pub privileges: u32,
+}
+
+impl HypervInterface {
+    /// Returns whether the partition may access the guest OS ID and hypercall MSRs.
+    pub fn has_hypercall_msrs(&self) -> bool {
+        self.privileges & HV_ACCESS_HYPERCALL_MSRS != 0
+    }
+}
+
+/// Returns the Hyper-V interface the processor reports, `None` if leaf 1 reports no hypervisor or the hypervisor
+/// leaves don't name Hyper-V and its interface.
+pub fn hyperv_interface() -> Option<HypervInterface> {
+    // Safety: CPUID is available on every x86_64 processor.
+    let (features, vendor, interface) = unsafe { (__cpuid(1), __cpuid(0x4000_0000), __cpuid(0x4000_0001)) };
+
+    if features.ecx & HYPERVISOR_PRESENT_BIT == 0 || !is_hyperv_vendor(vendor.ebx, vendor.ecx, vendor.edx) {
+        log::debug!("No Hyper-V vendor in the hypervisor leaves");
+        return None;
+    }
+    if interface.eax != HYPERV_INTERFACE_SIGNATURE || vendor.eax < 0x4000_0003 {
+        log::debug!("The hypervisor leaves name Hyper-V without its interface");
+        return None;
+    }
+
+    // Safety: the hypervisor reports leaf 0x40000003.
+    let privileges = unsafe { __cpuid(0x4000_0003) }.eax;
+    Some(HypervInterface {
+        max_leaf: vendor.eax,
+        privileges,
+    })
+}
@@ -15,7 +15,9 @@ use {
 };
 
 /// Global allocator instance with a heap size of `HEAP_SIZE`.
-#[global_allocator]
+///
+/// Host tests keep the allocator of `std`, nothing calls `heap_init` there.
+#[cfg_attr(not(test), global_allocator)]
 pub static mut HEAP: ListHeap<TOTAL_HEAP_SIZE> = ListHeap::new();
 
 /// Initializes the linked list heap.
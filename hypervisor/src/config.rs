@@ -0,0 +1,88 @@
+//! Settings the hypervisor image was configured with, see `shared::hvconfig`.
+//!
+//! The image applies its configuration blob and then the loader handoff before virtualizing the
+//! processors, the VM-exit handlers only read the settings afterwards.
+
+use {
+    core::sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    shared::{
+        features::HvFeatureFlags,
+        hvconfig::{HvConfig, CONFIG_FLAG_FULL_TLB_FLUSHES, CONFIG_FLAG_LOG_EPT_HOOKS, DEFAULT_TSC_SKEW_LIMIT},
+        PASSWORD,
+    },
+};
+
+/// The value RAX has to hold for a CPUID to be taken as a hypercall.
+static VMCALL_KEY: AtomicU64 = AtomicU64::new(PASSWORD);
+
+/// Whether every EPT hook the guest runs into is logged.
+static LOG_EPT_HOOKS: AtomicBool = AtomicBool::new(false);
+
+/// Whether TLB flushes invalidate all VPIDs instead of the narrowest sufficient scope.
+static FULL_TLB_FLUSHES: AtomicBool = AtomicBool::new(false);
+
+/// The most cycles RDTSC compensation may hold the time stamp counter of a processor back.
+static TSC_SKEW_LIMIT: AtomicU64 = AtomicU64::new(DEFAULT_TSC_SKEW_LIMIT as u64);
+
+/// The `HvFeatureFlags` bits chosen for this boot.
+static FEATURES: AtomicU64 = AtomicU64::new(HvFeatureFlags::DEFAULT.bits());
+
+/// Applies a configuration blob.
+///
+/// # Arguments
+///
+/// * `config` - The checked configuration of the image.
+pub fn apply(config: &HvConfig) {
+    VMCALL_KEY.store(config.vmcall_key, Ordering::Relaxed);
+    LOG_EPT_HOOKS.store(config.flags & CONFIG_FLAG_LOG_EPT_HOOKS != 0, Ordering::Relaxed);
+    FULL_TLB_FLUSHES.store(config.flags & CONFIG_FLAG_FULL_TLB_FLUSHES != 0, Ordering::Relaxed);
+    TSC_SKEW_LIMIT.store(config.tsc_skew_limit() as u64, Ordering::Relaxed);
+}
+
+/// Replaces the hypercall key with the one the loader generated for this boot.
+///
+/// # Arguments
+///
+/// * `key` - The value RAX has to hold for a CPUID to be taken as a hypercall.
+pub fn set_vmcall_key(key: u64) {
+    VMCALL_KEY.store(key, Ordering::Relaxed);
+}
+
+/// Returns the value RAX has to hold for a CPUID, and RCX for a VMCALL, to be taken as a hypercall.
+pub fn vmcall_key() -> u64 {
+    VMCALL_KEY.load(Ordering::Relaxed)
+}
+
+/// Returns whether every EPT hook the guest runs into is logged.
+pub fn log_ept_hooks() -> bool {
+    LOG_EPT_HOOKS.load(Ordering::Relaxed)
+}
+
+/// Returns whether TLB flushes invalidate all VPIDs instead of the narrowest sufficient scope.
+pub fn full_tlb_flushes() -> bool {
+    FULL_TLB_FLUSHES.load(Ordering::Relaxed)
+}
+
+/// Returns the most cycles RDTSC compensation may hold the time stamp counter of a processor back.
+pub fn tsc_skew_limit() -> u64 {
+    TSC_SKEW_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Replaces the features with the ones the loader chose for this boot.
+///
+/// # Arguments
+///
+/// * `features` - The features passed in the handoff.
+pub fn set_features(features: HvFeatureFlags) {
+    FEATURES.store(features.bits(), Ordering::Relaxed);
+}
+
+/// Returns the features enabled for this boot.
+pub fn features() -> HvFeatureFlags {
+    HvFeatureFlags::from_bits(FEATURES.load(Ordering::Relaxed))
+}
+
+/// Returns whether all of `features` are enabled.
+pub fn has_feature(features: HvFeatureFlags) -> bool {
+    HvFeatureFlags::from_bits(FEATURES.load(Ordering::Relaxed)).contains(features)
+}
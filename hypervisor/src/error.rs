@@ -185,6 +185,12 @@ pub enum HypervisorError {
     #[error("Too many hooks")]
     TooManyHooks,
 
+    #[error("The hook does not fit into the page of the function")]
+    HookCrossesPageBoundary,
+
+    #[error("The function to hook does not start with the expected code")]
+    UnexpectedPrologue,
+
     #[error("Failed to get current hook")]
     HookNotFound,
 
@@ -239,9 +245,24 @@ pub enum HypervisorError {
     #[error("EPT misconfiguration error")]
     EptMisconfiguration,
 
-    #[error("Large page table unmapping error")]
-    LargePageUnmapError,
+    #[error("The 4KB pages of a split large page cannot be merged back")]
+    LargePageMergeError,
 
     #[error("Guest page table unmapping error")]
     GuestPageUnmapError,
+
+    #[error("VM entry failed while checking or loading the guest state")]
+    VmEntryFailed,
+
+    #[error("EPT 1GB pages are unsupported")]
+    EptHugePagesUnsupported,
+
+    #[error("The views of EPT hooks are not switched with #VE on this processor")]
+    VirtualizationExceptionsUnavailable,
+
+    #[error("The guest did not map the #VE pages where it said, or the IDT does not reach the #VE vector")]
+    VeHandlerNotMapped,
+
+    #[error("The EPT view does not exist on this processor")]
+    EptViewNotFound,
 }
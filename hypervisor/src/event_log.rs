@@ -0,0 +1,150 @@
+//! The events of the hypervisor `VmcallNumber::ReadEvents` drains, the latest `EventRecord`s of all processors.
+//!
+//! Events are logged from VM exits and drained by hypercalls on any processor, under a spin lock held for a copy.
+//! A full log drops its oldest event and counts it, `read_events` returns the count so that the reader can drain
+//! more often.
+
+use {crate::intel::vcpu, alloc::vec::Vec, shared::vmcall::EventRecord, spin::Mutex};
+
+/// The most events the log holds.
+pub const EVENT_LOG_CAPACITY: usize = 256;
+
+/// A ring of events, the oldest one dropped for a new one when it is full.
+#[derive(Debug)]
+pub struct EventLog {
+    /// The events, `length` of them from `start` on, wrapping around.
+    records: [EventRecord; EVENT_LOG_CAPACITY],
+
+    /// The index of the oldest event.
+    start: usize,
+
+    /// The events held.
+    length: usize,
+
+    /// The events dropped since `take_lost` was called.
+    lost: u64,
+}
+
+impl EventLog {
+    /// Creates an empty log.
+    pub const fn new() -> Self {
+        Self {
+            records: [EventRecord {
+                kind: 0,
+                processor: 0,
+                values: [0; 3],
+            }; EVENT_LOG_CAPACITY],
+            start: 0,
+            length: 0,
+            lost: 0,
+        }
+    }
+
+    /// Returns the events held.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    /// Returns whether the log holds no event.
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Adds an event, dropping the oldest one when the log is full.
+    pub fn push(&mut self, record: EventRecord) {
+        if self.length == EVENT_LOG_CAPACITY {
+            self.start = (self.start + 1) % EVENT_LOG_CAPACITY;
+            self.length -= 1;
+            self.lost += 1;
+        }
+
+        self.records[(self.start + self.length) % EVENT_LOG_CAPACITY] = record;
+        self.length += 1;
+    }
+
+    /// Removes and returns the oldest event, `None` if the log is empty.
+    pub fn pop(&mut self) -> Option<EventRecord> {
+        if self.length == 0 {
+            return None;
+        }
+
+        let record = self.records[self.start];
+        self.start = (self.start + 1) % EVENT_LOG_CAPACITY;
+        self.length -= 1;
+        Some(record)
+    }
+
+    /// Returns the events dropped since the previous call.
+    pub fn take_lost(&mut self) -> u64 {
+        core::mem::take(&mut self.lost)
+    }
+}
+
+impl Default for EventLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The events of all processors.
+static EVENT_LOG: Mutex<EventLog> = Mutex::new(EventLog::new());
+
+/// Logs an event of the current processor.
+///
+/// # Arguments
+///
+/// * `kind` - The `shared::vmcall::EVENT_*` kind of the event.
+/// * `values` - The values of the event, as the kind defines them.
+pub fn log_event(kind: u32, values: [u64; 3]) {
+    EVENT_LOG.lock().push(EventRecord {
+        kind,
+        processor: vcpu::current_apic_id(),
+        values,
+    });
+}
+
+/// Removes the oldest events from the log.
+///
+/// # Arguments
+///
+/// * `count` - The most events to remove.
+///
+/// # Returns
+///
+/// The events, oldest first, and the events dropped since the previous call.
+pub fn drain(count: usize) -> (Vec<EventRecord>, u64) {
+    let mut log = EVENT_LOG.lock();
+    let records = core::iter::from_fn(|| log.pop()).take(count).collect();
+    (records, log.take_lost())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(value: u64) -> EventRecord {
+        EventRecord {
+            kind: 1,
+            processor: 0,
+            values: [value, 0, 0],
+        }
+    }
+
+    #[test]
+    fn full_logs_drop_the_oldest_events() {
+        let mut log = EventLog::new();
+        assert_eq!(log.pop(), None);
+
+        for value in 0..EVENT_LOG_CAPACITY as u64 + 3 {
+            log.push(event(value));
+        }
+        assert_eq!(log.len(), EVENT_LOG_CAPACITY);
+        assert_eq!(log.take_lost(), 3);
+        assert_eq!(log.take_lost(), 0);
+
+        assert_eq!(log.pop(), Some(event(3)));
+        log.push(event(0x1000));
+        assert_eq!(core::iter::from_fn(|| log.pop()).last(), Some(event(0x1000)));
+        assert!(log.is_empty());
+    }
+}
@@ -2,10 +2,10 @@ use {crate::intel::vm::Vm, core::mem::size_of};
 
 /// Number of stack pages per logical processor.
 /// Includes size of `Vm` in pages plus 0x1000 (4096) pages for padding.
-/// - Size of `Vm`: 1027 pages (0x403 pages).
+/// - Size of `Vm`: 1061 pages (0x425 pages).
 /// - Padding: 4096 pages (0x1000 pages).
-/// - Total: 1027 + 4096 pages = 5123 pages (0x1403 pages).
-/// - Total size in bytes: 5123 * 4096 = 20,971,520 bytes (20 MB).
+/// - Total: 1061 + 4096 pages = 5157 pages (0x1425 pages).
+/// - Total size in bytes: 5157 * 4096 = 21,123,072 bytes (20 MB).
 pub const STACK_PAGES_PER_PROCESSOR: usize = (size_of::<Vm>() / 0x1000) + 0x1000;
 
 /// Total heap size (64 MB) shared across all logical processors.
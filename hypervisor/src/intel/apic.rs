@@ -0,0 +1,82 @@
+//! The local APIC of the current processor, for the interprocessor interrupts the hypervisor sends on its own.
+//!
+//! The guest owns the local APIC, so the hypervisor only ever writes the interrupt command register, in
+//! whichever mode the guest put the APIC in. The host identity maps the xAPIC page like all physical memory.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 11.6 ISSUING INTERPROCESSOR INTERRUPTS
+
+use {
+    crate::intel::support::{rdmsr, wrmsr},
+    core::ptr,
+    x86::msr,
+};
+
+/// [Bit 10] IA32_APIC_BASE: the local APIC is in x2APIC mode.
+const X2APIC_ENABLE: u64 = 1 << 10;
+
+/// [Bits 51:12] IA32_APIC_BASE: the physical address of the xAPIC page.
+const APIC_BASE_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// The offset of the low half of the interrupt command register in the xAPIC page.
+const XAPIC_ICR_LOW: u64 = 0x300;
+
+/// The offset of the high half of the interrupt command register in the xAPIC page.
+const XAPIC_ICR_HIGH: u64 = 0x310;
+
+/// [Bits 10:8] ICR: delivery mode NMI, the vector is ignored.
+const DELIVERY_MODE_NMI: u64 = 0b100 << 8;
+
+/// [Bit 12] ICR: the xAPIC has not accepted the previous IPI yet.
+const DELIVERY_STATUS_PENDING: u32 = 1 << 12;
+
+/// [Bit 14] ICR: level assert, required for every delivery mode but INIT level de-assert.
+const LEVEL_ASSERT: u64 = 1 << 14;
+
+/// Builds the interrupt command sending an NMI to one processor.
+///
+/// # Arguments
+///
+/// * `apic_id` - The APIC ID of the processor.
+/// * `x2apic` - Whether the local APIC is in x2APIC mode, which takes a 32-bit destination in the high half.
+pub fn nmi_command(apic_id: u32, x2apic: bool) -> u64 {
+    let destination = if x2apic {
+        u64::from(apic_id) << 32
+    } else {
+        u64::from(apic_id & 0xFF) << 56
+    };
+    destination | LEVEL_ASSERT | DELIVERY_MODE_NMI
+}
+
+/// Sends an NMI to the processor with the APIC ID `apic_id`.
+pub fn send_nmi(apic_id: u32) {
+    let apic_base = rdmsr(msr::IA32_APIC_BASE);
+    let x2apic = apic_base & X2APIC_ENABLE != 0;
+    let command = nmi_command(apic_id, x2apic);
+
+    if x2apic {
+        wrmsr(msr::IA32_X2APIC_ICR, command);
+        return;
+    }
+
+    // Writing the low half sends the IPI, so the destination goes first.
+    let page = apic_base & APIC_BASE_MASK;
+    unsafe {
+        ptr::write_volatile((page + XAPIC_ICR_HIGH) as *mut u32, (command >> 32) as u32);
+        ptr::write_volatile((page + XAPIC_ICR_LOW) as *mut u32, command as u32);
+        while ptr::read_volatile((page + XAPIC_ICR_LOW) as *const u32) & DELIVERY_STATUS_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nmis_use_the_destination_of_the_apic_mode() {
+        assert_eq!(nmi_command(3, false), 0x0300_0000_0000_4400);
+        assert_eq!(nmi_command(0x1_0003, false), 0x0300_0000_0000_4400);
+        assert_eq!(nmi_command(0x1_0003, true), 0x0001_0003_0000_4400);
+    }
+}
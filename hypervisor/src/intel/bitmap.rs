@@ -1,8 +1,13 @@
-use bitfield::BitMut;
+use {
+    bitfield::{Bit, BitMut},
+    core::ops::RangeInclusive,
+    x86::msr,
+};
 
 /// Enum representing the type of MSR access.
 ///
 /// There are two types of MSR access: reading from an MSR and writing to an MSR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MsrAccessType {
     /// Read access to an MSR.
     Read,
@@ -20,11 +25,21 @@ pub enum MsrOperation {
     Unhook,
 }
 
+/// The MSRs covered by the low read and write bitmaps.
+pub const LOW_MSRS: RangeInclusive<u32> = 0x0000_0000..=0x0000_1FFF;
+
+/// The MSRs covered by the high read and write bitmaps.
+pub const HIGH_MSRS: RangeInclusive<u32> = 0xC000_0000..=0xC000_1FFF;
+
+/// The synthetic MSRs of Hyper-V.
+pub const HYPERV_MSRS: RangeInclusive<u32> = 0x4000_0000..=0x4000_00FF;
+
 /// Represents the MSR Bitmap structure used in VMX.
 ///
 /// In processors that support the 1-setting of the “use MSR bitmaps” VM-execution control,
 /// the VM-execution control fields include the 64-bit physical address of four contiguous
-/// MSR bitmaps, which are each 1-KByte in size.
+/// MSR bitmaps, which are each 1-KByte in size. Accesses to MSRs outside `LOW_MSRS` and `HIGH_MSRS`
+/// always cause a VM exit.
 ///
 /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.6.9 MSR-Bitmap Address
 #[repr(C, align(4096))]
@@ -66,20 +81,280 @@ impl MsrBitmap {
     /// * `access` - Specifies the access type read or write for the MSR operation.
     /// * `operation` - Specifies the operation hook (mask) or unhook (unmask) to perform on the MSR.
     pub fn modify_msr_interception(&mut self, msr: u32, access: MsrAccessType, operation: MsrOperation) {
-        let msr_low = msr & 0x1FFF;
-        let msr_index = (msr_low >> 3) as usize;
-        let msr_bit = (msr_low & 7) as u8;
-
-        let bitmap_section = match (msr >= 0xC000_0000, access) {
-            (true, MsrAccessType::Write) => &mut self.write_high_msrs,
-            (true, MsrAccessType::Read) => &mut self.read_high_msrs,
-            (false, MsrAccessType::Write) => &mut self.write_low_msrs,
-            (false, MsrAccessType::Read) => &mut self.read_low_msrs,
+        self.set(msr, access, matches!(operation, MsrOperation::Hook));
+    }
+
+    /// Makes RDMSR of `msr` cause a VM exit.
+    pub fn intercept_read(&mut self, msr: u32) {
+        self.set(msr, MsrAccessType::Read, true);
+    }
+
+    /// Makes WRMSR of `msr` cause a VM exit.
+    pub fn intercept_write(&mut self, msr: u32) {
+        self.set(msr, MsrAccessType::Write, true);
+    }
+
+    /// Lets the guest read and write `msr` without a VM exit.
+    ///
+    /// MSRs outside `LOW_MSRS` and `HIGH_MSRS` can't be passed through and are left as they are.
+    pub fn passthrough(&mut self, msr: u32) {
+        self.set(msr, MsrAccessType::Read, false);
+        self.set(msr, MsrAccessType::Write, false);
+    }
+
+    /// Makes RDMSR of every MSR in `msrs` cause a VM exit.
+    pub fn intercept_read_range(&mut self, msrs: RangeInclusive<u32>) {
+        msrs.for_each(|msr| self.intercept_read(msr));
+    }
+
+    /// Makes WRMSR of every MSR in `msrs` cause a VM exit.
+    pub fn intercept_write_range(&mut self, msrs: RangeInclusive<u32>) {
+        msrs.for_each(|msr| self.intercept_write(msr));
+    }
+
+    /// Lets the guest read and write every MSR in `msrs` without a VM exit.
+    pub fn passthrough_range(&mut self, msrs: RangeInclusive<u32>) {
+        msrs.for_each(|msr| self.passthrough(msr));
+    }
+
+    /// Returns whether `access` to `msr` causes a VM exit, which it always does outside of the bitmaps.
+    pub fn is_intercepted(&self, msr: u32, access: MsrAccessType) -> bool {
+        match Self::position(msr, access) {
+            Some((offset, bit)) => self.as_bytes()[offset].bit(bit),
+            None => true,
+        }
+    }
+
+    /// Sets or clears the bit of `access` to `msr`, MSRs outside of the bitmaps are ignored.
+    fn set(&mut self, msr: u32, access: MsrAccessType, intercept: bool) {
+        if let Some((offset, bit)) = Self::position(msr, access) {
+            self.as_bytes_mut()[offset].set_bit(bit, intercept);
+        }
+    }
+
+    /// Returns the byte offset into the bitmap page and the bit in that byte controlling `access` to `msr`.
+    ///
+    /// The page holds the read bitmaps of the low and high MSRs followed by their write bitmaps, 1 KiB each.
+    fn position(msr: u32, access: MsrAccessType) -> Option<(usize, usize)> {
+        let section = match (access, LOW_MSRS.contains(&msr), HIGH_MSRS.contains(&msr)) {
+            (MsrAccessType::Read, true, _) => 0,
+            (MsrAccessType::Read, _, true) => 1,
+            (MsrAccessType::Write, true, _) => 2,
+            (MsrAccessType::Write, _, true) => 3,
+            _ => return None,
         };
 
-        match operation {
-            MsrOperation::Hook => bitmap_section[msr_index].set_bit(msr_bit as usize, true),
-            MsrOperation::Unhook => bitmap_section[msr_index].set_bit(msr_bit as usize, false),
+        let index = (msr & 0x1FFF) as usize;
+        Some((section * 0x400 + index / 8, index % 8))
+    }
+
+    /// Returns the four bitmaps as one page.
+    fn as_bytes(&self) -> &[u8; 0x1000] {
+        // Safety: the structure is four contiguous 1 KiB byte arrays without padding.
+        unsafe { &*(self as *const Self as *const [u8; 0x1000]) }
+    }
+
+    /// Returns the four bitmaps as one mutable page.
+    fn as_bytes_mut(&mut self) -> &mut [u8; 0x1000] {
+        // Safety: see `as_bytes`.
+        unsafe { &mut *(self as *mut Self as *mut [u8; 0x1000]) }
+    }
+}
+
+/// What the hypervisor does with an access to an MSR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsrAction {
+    /// The access doesn't cause a VM exit. If it does anyway, e.g. outside the bitmaps, it is emulated.
+    Passthrough,
+
+    /// The access causes a VM exit and the handler emulates it.
+    Emulate,
+
+    /// The access causes a VM exit and the handler injects #GP(0).
+    InjectGp,
+}
+
+/// The actions for a range of MSRs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsrRule {
+    /// The first MSR of the range.
+    pub first: u32,
+
+    /// The last MSR of the range.
+    pub last: u32,
+
+    /// The action for RDMSR.
+    pub read: MsrAction,
+
+    /// The action for WRMSR.
+    pub write: MsrAction,
+}
+
+/// The most rules a policy holds.
+const MAX_MSR_RULES: usize = 16;
+
+/// Which MSR accesses the bitmap intercepts and what the RDMSR and WRMSR exit handler does with them.
+///
+/// Built with `rule` in a constant, see `DEFAULT_MSR_POLICY`. The first rule covering an MSR applies.
+#[derive(Debug, Clone, Copy)]
+pub struct MsrPolicy {
+    rules: [Option<MsrRule>; MAX_MSR_RULES],
+}
+
+impl MsrPolicy {
+    /// Creates a policy without any rules.
+    pub const fn new() -> Self {
+        Self {
+            rules: [None; MAX_MSR_RULES],
+        }
+    }
+
+    /// Adds a rule for the MSRs `first..=last`.
+    ///
+    /// # Panics
+    ///
+    /// Panics, at compile time for constants, if `first` is above `last` or the policy already holds `MAX_MSR_RULES` rules.
+    pub const fn rule(mut self, first: u32, last: u32, read: MsrAction, write: MsrAction) -> Self {
+        assert!(first <= last, "the range of an MSR rule is empty");
+
+        let mut index = 0;
+        while self.rules[index].is_some() {
+            index += 1;
+            assert!(index < MAX_MSR_RULES, "too many MSR rules");
+        }
+        self.rules[index] = Some(MsrRule { first, last, read, write });
+        self
+    }
+
+    /// Returns the rule covering `msr`, if any.
+    pub fn find(&self, msr: u32) -> Option<&MsrRule> {
+        self.rules.iter().flatten().find(|rule| (rule.first..=rule.last).contains(&msr))
+    }
+
+    /// Returns what the handler does with an access to `msr`.
+    ///
+    /// MSRs without a rule are emulated inside the bitmaps. Outside of them, only the synthetic MSRs of
+    /// Hyper-V are answered with #GP(0) under VMware, which implements them, and every MSR otherwise, as
+    /// anti-cheats probe them to detect a hypervisor.
+    pub fn action(&self, msr: u32, access: MsrAccessType) -> MsrAction {
+        if let Some(rule) = self.find(msr) {
+            return match access {
+                MsrAccessType::Read => rule.read,
+                MsrAccessType::Write => rule.write,
+            };
         }
+
+        if LOW_MSRS.contains(&msr) || HIGH_MSRS.contains(&msr) {
+            MsrAction::Passthrough
+        } else if cfg!(feature = "vmware") && !HYPERV_MSRS.contains(&msr) {
+            MsrAction::Emulate
+        } else {
+            MsrAction::InjectGp
+        }
+    }
+
+    /// Sets the bits of every MSR with a rule in `bitmap`, other MSRs are left as they are.
+    pub fn apply(&self, bitmap: &mut MsrBitmap) {
+        for rule in self.rules.iter().flatten() {
+            for (access, action) in [(MsrAccessType::Read, rule.read), (MsrAccessType::Write, rule.write)] {
+                for msr in rule.first..=rule.last {
+                    bitmap.set(msr, access, action != MsrAction::Passthrough);
+                }
+            }
+        }
+    }
+}
+
+impl Default for MsrPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The policy the hypervisor starts with.
+///
+/// * IA32_EFER is emulated, so the hypervisor sees long mode and NXE being changed.
+/// * IA32_FEATURE_CONTROL reads return the register locked, VMX outside SMX enabled as `VmxExposure` decides.
+/// * IA32_LSTAR writes are emulated to find the kernel, the handler passes them through after the first one.
+/// * The VMX capability MSRs are read-only, writes fail as they do on hardware, reads follow `VmxExposure`.
+/// * IA32_TSC_AUX stays passed through, RDTSCP returns the value the guest wrote without a VM exit.
+pub static DEFAULT_MSR_POLICY: MsrPolicy = MsrPolicy::new()
+    .rule(msr::IA32_EFER, msr::IA32_EFER, MsrAction::Emulate, MsrAction::Emulate)
+    .rule(msr::IA32_FEATURE_CONTROL, msr::IA32_FEATURE_CONTROL, MsrAction::Emulate, MsrAction::Passthrough)
+    .rule(msr::IA32_LSTAR, msr::IA32_LSTAR, MsrAction::Passthrough, MsrAction::Emulate)
+    .rule(msr::IA32_VMX_BASIC, msr::IA32_VMX_VMFUNC, MsrAction::Emulate, MsrAction::InjectGp)
+    .rule(msr::IA32_TSC_AUX, msr::IA32_TSC_AUX, MsrAction::Passthrough, MsrAction::Passthrough);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boundary_msrs_map_to_their_quadrant() {
+        assert_eq!(MsrBitmap::position(0x0000_0000, MsrAccessType::Read), Some((0x000, 0)));
+        assert_eq!(MsrBitmap::position(0x0000_1FFF, MsrAccessType::Read), Some((0x3FF, 7)));
+        assert_eq!(MsrBitmap::position(0xC000_0000, MsrAccessType::Read), Some((0x400, 0)));
+        assert_eq!(MsrBitmap::position(0xC000_1FFF, MsrAccessType::Read), Some((0x7FF, 7)));
+        assert_eq!(MsrBitmap::position(0x0000_1FFF, MsrAccessType::Write), Some((0xBFF, 7)));
+        assert_eq!(MsrBitmap::position(0xC000_0000, MsrAccessType::Write), Some((0xC00, 0)));
+        assert_eq!(MsrBitmap::position(0xC000_1FFF, MsrAccessType::Write), Some((0xFFF, 7)));
+        assert_eq!(MsrBitmap::position(0x0000_2000, MsrAccessType::Read), None);
+        assert_eq!(MsrBitmap::position(0xC000_2000, MsrAccessType::Write), None);
+        assert_eq!(MsrBitmap::position(0x4000_0000, MsrAccessType::Read), None);
+    }
+
+    #[test]
+    fn intercepts_land_in_the_named_fields() {
+        let mut bitmap = MsrBitmap::new();
+        bitmap.intercept_read(0x1FFF);
+        bitmap.intercept_write(0xC000_0000);
+        bitmap.intercept_read(0xC000_1FFF);
+
+        assert_eq!(bitmap.read_low_msrs[0x3FF], 0x80);
+        assert_eq!(bitmap.write_high_msrs[0], 0x01);
+        assert_eq!(bitmap.read_high_msrs[0x3FF], 0x80);
+        assert_eq!(bitmap.write_low_msrs, [0; 0x400]);
+
+        assert!(bitmap.is_intercepted(0x1FFF, MsrAccessType::Read));
+        assert!(!bitmap.is_intercepted(0x1FFF, MsrAccessType::Write));
+        assert!(bitmap.is_intercepted(0x4000_0000, MsrAccessType::Write));
+
+        bitmap.passthrough(0xC000_0000);
+        assert_eq!(bitmap.write_high_msrs[0], 0);
+    }
+
+    #[test]
+    fn ranges_cover_both_ends() {
+        let mut bitmap = MsrBitmap::new();
+        bitmap.intercept_write_range(0x1FF8..=0x1FFF);
+        assert_eq!(bitmap.write_low_msrs[0x3FF], 0xFF);
+
+        bitmap.passthrough_range(0x1FFE..=0x1FFF);
+        assert_eq!(bitmap.write_low_msrs[0x3FF], 0x3F);
+
+        bitmap.intercept_read_range(0x1FFF..=0x2001);
+        assert_eq!(bitmap.read_low_msrs[0x3FF], 0x80);
+    }
+
+    #[test]
+    fn default_policy_matches_the_bitmap() {
+        let mut bitmap = MsrBitmap::new();
+        DEFAULT_MSR_POLICY.apply(&mut bitmap);
+
+        assert!(bitmap.is_intercepted(msr::IA32_EFER, MsrAccessType::Read));
+        assert!(bitmap.is_intercepted(msr::IA32_EFER, MsrAccessType::Write));
+        assert!(bitmap.is_intercepted(msr::IA32_FEATURE_CONTROL, MsrAccessType::Read));
+        assert!(!bitmap.is_intercepted(msr::IA32_FEATURE_CONTROL, MsrAccessType::Write));
+        assert!(!bitmap.is_intercepted(msr::IA32_LSTAR, MsrAccessType::Read));
+        assert!(bitmap.is_intercepted(msr::IA32_LSTAR, MsrAccessType::Write));
+        assert!(bitmap.is_intercepted(msr::IA32_VMX_BASIC, MsrAccessType::Read));
+        assert!(bitmap.is_intercepted(msr::IA32_VMX_VMFUNC, MsrAccessType::Write));
+        assert!(!bitmap.is_intercepted(msr::IA32_GS_BASE, MsrAccessType::Write));
+        assert!(!bitmap.is_intercepted(msr::IA32_TSC_AUX, MsrAccessType::Read));
+
+        assert_eq!(DEFAULT_MSR_POLICY.action(msr::IA32_VMX_BASIC, MsrAccessType::Write), MsrAction::InjectGp);
+        assert_eq!(DEFAULT_MSR_POLICY.action(msr::IA32_EFER, MsrAccessType::Read), MsrAction::Emulate);
+        assert_eq!(DEFAULT_MSR_POLICY.action(msr::IA32_GS_BASE, MsrAccessType::Write), MsrAction::Passthrough);
+        assert_eq!(DEFAULT_MSR_POLICY.action(0x4000_0000, MsrAccessType::Read), MsrAction::InjectGp);
     }
 }
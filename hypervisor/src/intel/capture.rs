@@ -27,6 +27,20 @@ extern "efiapi" {
     /// This function involves inline assembly and direct manipulation of register values, requiring
     /// careful consideration of calling context to ensure system stability.
     pub fn capture_registers(registers: &mut GuestRegisters) -> bool;
+
+    /// Continues at the state captured by `capture_registers`, without entering VMX non-root operation.
+    ///
+    /// Used by a processor that gave up virtualizing itself: `capture_registers` appears to return again,
+    /// with the value of `registers.rax`.
+    ///
+    /// # Arguments
+    ///
+    /// - `registers`: The state to continue at, anywhere but below `registers.rsp` on the stack it refers to.
+    ///
+    /// # Safety
+    ///
+    /// The stack `registers.rsp` points into must still hold the frame of the `capture_registers` caller.
+    pub fn restore_registers(registers: &GuestRegisters) -> !;
 }
 
 /// Represents the state of guest general-purpose registers along with RFLAGS, RSP, and RIP.
@@ -76,6 +90,51 @@ pub struct GuestRegisters {
     pub hook_lstar: u64,
 }
 
+impl GuestRegisters {
+    /// Returns the general-purpose register with the number exit qualifications use, `0` for RAX to `15` for R15.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The number of the register, only bits 3:0 count.
+    pub fn gpr(&self, index: u64) -> u64 {
+        let gprs = [
+            self.rax, self.rcx, self.rdx, self.rbx, self.rsp, self.rbp, self.rsi, self.rdi, self.r8, self.r9, self.r10, self.r11, self.r12, self.r13,
+            self.r14, self.r15,
+        ];
+        gprs[index as usize & 0xF]
+    }
+
+    /// Sets the general-purpose register with the number exit qualifications use, `0` for RAX to `15` for R15.
+    ///
+    /// RSP is only loaded from the guest-state area on VM entries, callers write that too.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The number of the register, only bits 3:0 count.
+    /// * `value` - The new value of the register.
+    pub fn set_gpr(&mut self, index: u64, value: u64) {
+        let gprs = [
+            &mut self.rax,
+            &mut self.rcx,
+            &mut self.rdx,
+            &mut self.rbx,
+            &mut self.rsp,
+            &mut self.rbp,
+            &mut self.rsi,
+            &mut self.rdi,
+            &mut self.r8,
+            &mut self.r9,
+            &mut self.r10,
+            &mut self.r11,
+            &mut self.r12,
+            &mut self.r13,
+            &mut self.r14,
+            &mut self.r15,
+        ];
+        *gprs[index as usize & 0xF] = value;
+    }
+}
+
 #[repr(C)]
 #[repr(align(16))]
 #[derive(Clone, Copy, Default)]
@@ -208,3 +267,112 @@ capture_registers:
     registers_xmm14 = const mem::offset_of!(GuestRegisters, xmm14),
     registers_xmm15 = const mem::offset_of!(GuestRegisters, xmm15),
 );
+
+global_asm!(
+    r#"
+// Restores the XMM registers, RFLAGS and the general purpose registers and continues at RIP on RSP.
+//
+// extern "efiapi" fn restore_registers(registers: &GuestRegisters) -> !
+.global restore_registers
+restore_registers:
+    // Restore XMM registers.
+    movaps  xmm0, [rcx + {registers_xmm0}]
+    movaps  xmm1, [rcx + {registers_xmm1}]
+    movaps  xmm2, [rcx + {registers_xmm2}]
+    movaps  xmm3, [rcx + {registers_xmm3}]
+    movaps  xmm4, [rcx + {registers_xmm4}]
+    movaps  xmm5, [rcx + {registers_xmm5}]
+    movaps  xmm6, [rcx + {registers_xmm6}]
+    movaps  xmm7, [rcx + {registers_xmm7}]
+    movaps  xmm8, [rcx + {registers_xmm8}]
+    movaps  xmm9, [rcx + {registers_xmm9}]
+    movaps  xmm10, [rcx + {registers_xmm10}]
+    movaps  xmm11, [rcx + {registers_xmm11}]
+    movaps  xmm12, [rcx + {registers_xmm12}]
+    movaps  xmm13, [rcx + {registers_xmm13}]
+    movaps  xmm14, [rcx + {registers_xmm14}]
+    movaps  xmm15, [rcx + {registers_xmm15}]
+
+    // Switch to the captured stack and push RIP as the return address.
+    mov     rsp, [rcx + {registers_rsp}]
+    push    qword ptr [rcx + {registers_rip}]
+
+    // Restore RFLAGS.
+    push    qword ptr [rcx + {registers_rflags}]
+    popfq
+
+    // Restore general purpose registers, RCX last as it holds the pointer.
+    mov     rax, [rcx + {registers_rax}]
+    mov     rdx, [rcx + {registers_rdx}]
+    mov     rbx, [rcx + {registers_rbx}]
+    mov     rbp, [rcx + {registers_rbp}]
+    mov     rsi, [rcx + {registers_rsi}]
+    mov     rdi, [rcx + {registers_rdi}]
+    mov     r8,  [rcx + {registers_r8}]
+    mov     r9,  [rcx + {registers_r9}]
+    mov     r10, [rcx + {registers_r10}]
+    mov     r11, [rcx + {registers_r11}]
+    mov     r12, [rcx + {registers_r12}]
+    mov     r13, [rcx + {registers_r13}]
+    mov     r14, [rcx + {registers_r14}]
+    mov     r15, [rcx + {registers_r15}]
+    mov     rcx, [rcx + {registers_rcx}]
+
+    ret
+"#,
+    registers_rax = const mem::offset_of!(GuestRegisters, rax),
+    registers_rcx = const mem::offset_of!(GuestRegisters, rcx),
+    registers_rdx = const mem::offset_of!(GuestRegisters, rdx),
+    registers_rbx = const mem::offset_of!(GuestRegisters, rbx),
+    registers_rsp = const mem::offset_of!(GuestRegisters, rsp),
+    registers_rbp = const mem::offset_of!(GuestRegisters, rbp),
+    registers_rsi = const mem::offset_of!(GuestRegisters, rsi),
+    registers_rdi = const mem::offset_of!(GuestRegisters, rdi),
+    registers_r8  = const mem::offset_of!(GuestRegisters, r8),
+    registers_r9  = const mem::offset_of!(GuestRegisters, r9),
+    registers_r10 = const mem::offset_of!(GuestRegisters, r10),
+    registers_r11 = const mem::offset_of!(GuestRegisters, r11),
+    registers_r12 = const mem::offset_of!(GuestRegisters, r12),
+    registers_r13 = const mem::offset_of!(GuestRegisters, r13),
+    registers_r14 = const mem::offset_of!(GuestRegisters, r14),
+    registers_r15 = const mem::offset_of!(GuestRegisters, r15),
+    registers_rip = const mem::offset_of!(GuestRegisters, rip),
+    registers_rflags = const mem::offset_of!(GuestRegisters, rflags),
+    registers_xmm0 = const mem::offset_of!(GuestRegisters, xmm0),
+    registers_xmm1 = const mem::offset_of!(GuestRegisters, xmm1),
+    registers_xmm2 = const mem::offset_of!(GuestRegisters, xmm2),
+    registers_xmm3 = const mem::offset_of!(GuestRegisters, xmm3),
+    registers_xmm4 = const mem::offset_of!(GuestRegisters, xmm4),
+    registers_xmm5 = const mem::offset_of!(GuestRegisters, xmm5),
+    registers_xmm6 = const mem::offset_of!(GuestRegisters, xmm6),
+    registers_xmm7 = const mem::offset_of!(GuestRegisters, xmm7),
+    registers_xmm8 = const mem::offset_of!(GuestRegisters, xmm8),
+    registers_xmm9 = const mem::offset_of!(GuestRegisters, xmm9),
+    registers_xmm10 = const mem::offset_of!(GuestRegisters, xmm10),
+    registers_xmm11 = const mem::offset_of!(GuestRegisters, xmm11),
+    registers_xmm12 = const mem::offset_of!(GuestRegisters, xmm12),
+    registers_xmm13 = const mem::offset_of!(GuestRegisters, xmm13),
+    registers_xmm14 = const mem::offset_of!(GuestRegisters, xmm14),
+    registers_xmm15 = const mem::offset_of!(GuestRegisters, xmm15),
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gprs_follow_the_exit_qualification_encoding() {
+        let mut registers = GuestRegisters::default();
+        for index in 0..16 {
+            registers.set_gpr(index, 0x100 + index);
+        }
+
+        assert_eq!(registers.rax, 0x100);
+        assert_eq!(registers.rsp, 0x104);
+        assert_eq!(registers.rdi, 0x107);
+        assert_eq!(registers.r8, 0x108);
+        assert_eq!(registers.r15, 0x10F);
+        assert_eq!(registers.rip, 0);
+        assert!((0..16).all(|index| registers.gpr(index) == 0x100 + index));
+    }
+}
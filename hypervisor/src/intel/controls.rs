@@ -26,12 +26,31 @@ pub enum VmxControl {
 ///
 /// Returns the adjusted control value based on system capabilities and the requested value.
 pub fn adjust_vmx_controls(control: VmxControl, requested_value: u64) -> u64 {
+    let capabilities = unsafe { msr::rdmsr(capability_msr(control)) };
+    let allowed0 = capabilities as u32;
+    let allowed1 = (capabilities >> 32) as u32;
+    let mut effective_value = u32::try_from(requested_value).unwrap();
+    effective_value |= allowed0;
+    effective_value &= allowed1;
+    u64::from(effective_value)
+}
+
+/// Returns the capability MSR reporting the allowed settings of a VMX control field.
+///
+/// # Arguments
+///
+/// * `control` - The type of VMX control.
+///
+/// # Returns
+///
+/// The TRUE capability MSR where the processor supports it, the original one otherwise.
+pub fn capability_msr(control: VmxControl) -> u32 {
     const IA32_VMX_BASIC_VMX_CONTROLS_FLAG: u64 = 1 << 55;
 
     let vmx_basic = unsafe { msr::rdmsr(msr::IA32_VMX_BASIC) };
     let true_cap_msr_supported = (vmx_basic & IA32_VMX_BASIC_VMX_CONTROLS_FLAG) != 0;
 
-    let cap_msr = match (control, true_cap_msr_supported) {
+    match (control, true_cap_msr_supported) {
         (VmxControl::PinBased, true) => msr::IA32_VMX_TRUE_PINBASED_CTLS,
         (VmxControl::PinBased, false) => msr::IA32_VMX_PINBASED_CTLS,
         (VmxControl::ProcessorBased, true) => msr::IA32_VMX_TRUE_PROCBASED_CTLS,
@@ -42,13 +61,5 @@ pub fn adjust_vmx_controls(control: VmxControl, requested_value: u64) -> u64 {
         (VmxControl::VmEntry, false) => msr::IA32_VMX_ENTRY_CTLS,
         // There is no TRUE MSR for IA32_VMX_PROCBASED_CTLS2. Just use IA32_VMX_PROCBASED_CTLS2 unconditionally.
         (VmxControl::ProcessorBased2, _) => msr::IA32_VMX_PROCBASED_CTLS2,
-    };
-
-    let capabilities = unsafe { msr::rdmsr(cap_msr) };
-    let allowed0 = capabilities as u32;
-    let allowed1 = (capabilities >> 32) as u32;
-    let mut effective_value = u32::try_from(requested_value).unwrap();
-    effective_value |= allowed0;
-    effective_value &= allowed1;
-    u64::from(effective_value)
+    }
 }
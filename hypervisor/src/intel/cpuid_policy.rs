@@ -0,0 +1,514 @@
+//! What the guest sees from `CPUID`, as a table of actions keyed by leaf and subleaf.
+//!
+//! The exit handler looks the leaf up in `CPUID_POLICY` after it executed `CPUID` on the host and applies the
+//! action to the result, leaves without a rule are passed through unchanged. The table is built from the
+//! feature flags of the handoff on the first `CPUID` exit and may be changed at runtime afterwards.
+//!
+//! The VMX bit the guest reads from leaf 1 also decides what IA32_FEATURE_CONTROL and the VMX capability
+//! MSRs report, see `VmxExposure`, so the MSR exit handler can't contradict CPUID.
+
+use {
+    crate::{config, intel::hyperv},
+    alloc::vec::Vec,
+    core::ops::RangeInclusive,
+    lazy_static::lazy_static,
+    shared::features::HvFeatureFlags,
+    spin::RwLock,
+    x86::{
+        cpuid::{cpuid, CpuIdResult},
+        msr,
+    },
+};
+
+/// Bit 5 of ECX for CPUID with EAX=1, indicating VMX support.
+pub const VMX_SUPPORT_BIT: u32 = 1 << 5;
+
+/// Bit 31 of ECX for CPUID with EAX=1, indicating hypervisor presence.
+pub const HYPERVISOR_PRESENT_BIT: u32 = 1 << 31;
+
+/// The leaves reserved for hypervisors.
+///
+/// Reference: https://learn.microsoft.com/en-us/virtualization/hyper-v-on-windows/tlfs/feature-discovery
+pub const HYPERVISOR_LEAVES: RangeInclusive<u32> = 0x4000_0000..=0x4000_00FF;
+
+/// Computes the result of a leaf in place of the processor, see `CpuidAction::Handler`.
+///
+/// # Arguments
+///
+/// * `leaf` - The leaf the guest queried, EAX.
+/// * `subleaf` - The subleaf the guest queried, ECX.
+/// * `result` - The result of the host, which the handler changes.
+pub type CpuidHandler = fn(leaf: u32, subleaf: u32, result: &mut CpuIdResult);
+
+/// What the guest sees from a leaf.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuidAction {
+    /// The result of the host, unchanged.
+    Passthrough,
+
+    /// The result of the host with the bits of `clear` cleared and then the bits of `set` set.
+    Mask { clear: CpuIdResult, set: CpuIdResult },
+
+    /// The given registers in place of the result of the host.
+    Replace(CpuIdResult),
+
+    /// The result of the host after a handler got to change it.
+    Handler(CpuidHandler),
+}
+
+impl CpuidAction {
+    /// Returns an action clearing `bits` of ECX.
+    pub const fn clear_ecx(bits: u32) -> Self {
+        Self::Mask {
+            clear: registers(0, 0, bits, 0),
+            set: registers(0, 0, 0, 0),
+        }
+    }
+
+    /// Applies the action to the result of the host.
+    ///
+    /// # Arguments
+    ///
+    /// * `leaf` - The leaf the guest queried.
+    /// * `subleaf` - The subleaf the guest queried.
+    /// * `result` - The result of the host, replaced by what the guest sees.
+    pub fn apply(&self, leaf: u32, subleaf: u32, result: &mut CpuIdResult) {
+        match *self {
+            Self::Passthrough => {}
+            Self::Mask { clear, set } => {
+                result.eax = result.eax & !clear.eax | set.eax;
+                result.ebx = result.ebx & !clear.ebx | set.ebx;
+                result.ecx = result.ecx & !clear.ecx | set.ecx;
+                result.edx = result.edx & !clear.edx | set.edx;
+            }
+            Self::Replace(registers) => *result = registers,
+            Self::Handler(handler) => handler(leaf, subleaf, result),
+        }
+    }
+
+    /// Returns the action doing what `self` and then `other` do, if a single action can.
+    fn then(self, other: Self) -> Option<Self> {
+        match (self, other) {
+            (action, Self::Passthrough) | (Self::Passthrough, action) => Some(action),
+            (_, Self::Replace(registers)) => Some(Self::Replace(registers)),
+            (Self::Replace(mut registers), mask @ Self::Mask { .. }) => {
+                mask.apply(0, 0, &mut registers);
+                Some(Self::Replace(registers))
+            }
+            (
+                Self::Mask { clear, set },
+                Self::Mask {
+                    clear: other_clear,
+                    set: other_set,
+                },
+            ) => Some(Self::Mask {
+                clear: registers(clear.eax | other_clear.eax, clear.ebx | other_clear.ebx, clear.ecx | other_clear.ecx, clear.edx | other_clear.edx),
+                set: registers(
+                    set.eax & !other_clear.eax | other_set.eax,
+                    set.ebx & !other_clear.ebx | other_set.ebx,
+                    set.ecx & !other_clear.ecx | other_set.ecx,
+                    set.edx & !other_clear.edx | other_set.edx,
+                ),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the result made of the given registers.
+pub const fn registers(eax: u32, ebx: u32, ecx: u32, edx: u32) -> CpuIdResult {
+    CpuIdResult { eax, ebx, ecx, edx }
+}
+
+/// The action for a range of leaves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuidRule {
+    /// The leaves the rule covers.
+    pub leaves: RangeInclusive<u32>,
+
+    /// The subleaf the rule covers, `None` for all of them.
+    pub subleaf: Option<u32>,
+
+    /// What the guest sees from the leaves.
+    pub action: CpuidAction,
+}
+
+/// The table of CPUID rules.
+///
+/// A rule for a single subleaf takes precedence over a rule for all subleaves, and among rules that are
+/// equally specific the one added last wins.
+#[derive(Debug, Clone, Default)]
+pub struct CpuidPolicy {
+    rules: Vec<CpuidRule>,
+}
+
+impl CpuidPolicy {
+    /// Creates a policy without any rules, which passes every leaf through.
+    pub const fn new() -> Self {
+        Self { rules: Vec::new() }
+    }
+
+    /// Creates the policy the feature flags of the handoff ask for.
+    ///
+    /// # Arguments
+    ///
+    /// * `features` - The features enabled for this boot.
+    pub fn from_features(features: HvFeatureFlags) -> Self {
+        let mut policy = Self::new();
+
+        if features.contains(HvFeatureFlags::HIDE_CPUID_LEAF) {
+            policy.add(1, None, CpuidAction::clear_ecx(HYPERVISOR_PRESENT_BIT));
+
+            // VMware answers its own hypervisor leaves, which the guest tools rely on.
+            if !cfg!(feature = "vmware") {
+                policy.add_range(HYPERVISOR_LEAVES, None, CpuidAction::Handler(unsupported_leaf));
+            }
+        } else if hyperv::masquerades(features) {
+            // Hyper-V sets the hypervisor present bit, which the processor doesn't if nothing runs below us.
+            policy.add(
+                1,
+                None,
+                CpuidAction::Mask {
+                    clear: registers(0, 0, 0, 0),
+                    set: registers(0, 0, HYPERVISOR_PRESENT_BIT, 0),
+                },
+            );
+            policy.add_range(HYPERVISOR_LEAVES, None, CpuidAction::Replace(registers(0, 0, 0, 0)));
+            for (leaf, result) in hyperv::LEAVES {
+                policy.add(leaf, None, CpuidAction::Replace(result));
+            }
+        } else if features.contains(HvFeatureFlags::FAKE_HYPERVISOR_LEAVES) {
+            // "Illusion" in EBX, ECX and EDX, and 0x40000001 as the highest hypervisor leaf.
+            policy.add_range(HYPERVISOR_LEAVES, None, CpuidAction::Replace(registers(0, 0, 0, 0)));
+            policy.add(0x4000_0000, None, CpuidAction::Replace(registers(0x4000_0001, 0x756c_6c49, 0x6e6f_6973, 0)));
+        }
+
+        if features.contains(HvFeatureFlags::HIDE_VMX) {
+            policy.add(1, None, CpuidAction::clear_ecx(VMX_SUPPORT_BIT));
+        }
+
+        policy
+    }
+
+    /// Adds `action` for `leaf`, see `add_range`.
+    pub fn add(&mut self, leaf: u32, subleaf: Option<u32>, action: CpuidAction) {
+        self.add_range(leaf..=leaf, subleaf, action);
+    }
+
+    /// Adds `action` for every leaf in `leaves`.
+    ///
+    /// An action for the same leaves and subleaf as an existing rule is combined with it, e.g. two masks become one
+    /// mask clearing the bits of both, and replaces it where the two can't be combined.
+    ///
+    /// # Arguments
+    ///
+    /// * `leaves` - The leaves the rule covers.
+    /// * `subleaf` - The subleaf the rule covers, `None` for all of them.
+    /// * `action` - What the guest sees from the leaves.
+    pub fn add_range(&mut self, leaves: RangeInclusive<u32>, subleaf: Option<u32>, action: CpuidAction) {
+        if let Some(rule) = self.rules.iter_mut().find(|rule| rule.leaves == leaves && rule.subleaf == subleaf) {
+            rule.action = rule.action.then(action).unwrap_or(action);
+            return;
+        }
+
+        self.rules.push(CpuidRule { leaves, subleaf, action });
+    }
+
+    /// Removes the rule for exactly `leaf` and `subleaf`.
+    ///
+    /// # Returns
+    ///
+    /// The action of the removed rule, `None` if there was none.
+    pub fn remove(&mut self, leaf: u32, subleaf: Option<u32>) -> Option<CpuidAction> {
+        let index = self
+            .rules
+            .iter()
+            .position(|rule| rule.leaves == (leaf..=leaf) && rule.subleaf == subleaf)?;
+        Some(self.rules.remove(index).action)
+    }
+
+    /// Returns the action for `leaf` and `subleaf`, `None` if the leaf is passed through.
+    pub fn lookup(&self, leaf: u32, subleaf: u32) -> Option<CpuidAction> {
+        let matching = |exact: bool| {
+            self.rules
+                .iter()
+                .rev()
+                .find(|rule| rule.leaves.contains(&leaf) && rule.subleaf == exact.then_some(subleaf))
+        };
+
+        matching(true).or_else(|| matching(false)).map(|rule| rule.action)
+    }
+
+    /// Returns the rules in the order they were added.
+    pub fn rules(&self) -> &[CpuidRule] {
+        &self.rules
+    }
+
+    /// Returns whether the guest sees the VMX bit of leaf 1 on a processor that supports VMX.
+    pub fn guest_sees_vmx(&self) -> bool {
+        let mut result = registers(0, 0, VMX_SUPPORT_BIT, 0);
+        if let Some(action) = self.lookup(1, 0) {
+            action.apply(1, 0, &mut result);
+        }
+        result.ecx & VMX_SUPPORT_BIT != 0
+    }
+}
+
+lazy_static! {
+    /// The CPUID policy of all processors.
+    pub static ref CPUID_POLICY: RwLock<CpuidPolicy> = RwLock::new(CpuidPolicy::from_features(config::features()));
+}
+
+/// Returns the result of a leaf as the guest sees it after `CPUID_POLICY`.
+///
+/// # Arguments
+///
+/// * `leaf` - The leaf.
+/// * `subleaf` - The subleaf.
+pub fn seen(leaf: u32, subleaf: u32) -> CpuIdResult {
+    let mut result = cpuid!(leaf, subleaf);
+    if let Some(action) = CPUID_POLICY.read().lookup(leaf, subleaf) {
+        action.apply(leaf, subleaf, &mut result);
+    }
+    result
+}
+
+/// The VMX capability MSRs, IA32_VMX_BASIC to IA32_VMX_VMFUNC.
+pub const VMX_CAPABILITY_MSRS: RangeInclusive<u32> = msr::IA32_VMX_BASIC..=msr::IA32_VMX_VMFUNC;
+
+/// [Bit 0] IA32_FEATURE_CONTROL: lock bit, the MSR can't be written until the next reset.
+const FEATURE_CONTROL_LOCK: u64 = 1 << 0;
+
+/// [Bit 1] IA32_FEATURE_CONTROL: VMXON is allowed inside SMX operation.
+const FEATURE_CONTROL_VMXON_INSIDE_SMX: u64 = 1 << 1;
+
+/// [Bit 2] IA32_FEATURE_CONTROL: VMXON is allowed outside SMX operation.
+const FEATURE_CONTROL_VMXON_OUTSIDE_SMX: u64 = 1 << 2;
+
+/// [Bit 55] IA32_VMX_BASIC: the IA32_VMX_TRUE_*_CTLS MSRs exist.
+const VMX_BASIC_TRUE_CONTROLS: u64 = 1 << 55;
+
+/// [Bit 63] IA32_VMX_PROCBASED_CTLS: "activate secondary controls" may be 1, IA32_VMX_PROCBASED_CTLS2 exists.
+const PROCBASED_CTLS_SECONDARY_CONTROLS: u64 = 1 << 63;
+
+/// How the guest sees VMX, decided by the VMX bit of CPUID leaf 1 it reads.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 24.7 ENABLING AND ENTERING VMX OPERATION
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: A.1 BASIC VMX INFORMATION
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmxExposure {
+    /// CPUID reports no VMX, IA32_FEATURE_CONTROL has VMXON disabled and the capability MSRs raise #GP.
+    Hidden,
+
+    /// CPUID reports VMX, which IA32_FEATURE_CONTROL has locked disabled outside SMX the way firmware can. The
+    /// capability MSRs read the values of the processor, as they do bare metal.
+    Locked,
+
+    /// CPUID reports VMX, enabled outside SMX in a locked IA32_FEATURE_CONTROL, with `EXPOSE_NESTED_VMX`. The
+    /// capability MSRs report VMX without secondary and true controls, so the MSRs those enumerate raise #GP.
+    /// The hypervisor doesn't run nested guests, VMXON still raises #GP.
+    Nested,
+}
+
+impl VmxExposure {
+    /// Returns the exposure for the VMX bit the guest reads and the features of the handoff.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_sees_vmx` - Whether CPUID leaf 1 reports VMX to the guest.
+    /// * `features` - The features enabled for this boot.
+    pub fn of(guest_sees_vmx: bool, features: HvFeatureFlags) -> Self {
+        if !guest_sees_vmx {
+            Self::Hidden
+        } else if features.contains(HvFeatureFlags::EXPOSE_NESTED_VMX) {
+            Self::Nested
+        } else {
+            Self::Locked
+        }
+    }
+
+    /// Returns the exposure of `CPUID_POLICY` as it is now.
+    pub fn current() -> Self {
+        Self::of(CPUID_POLICY.read().guest_sees_vmx(), config::features())
+    }
+
+    /// Returns the IA32_FEATURE_CONTROL the guest reads, always locked as firmware leaves it.
+    ///
+    /// # Arguments
+    ///
+    /// * `host` - The IA32_FEATURE_CONTROL of the processor.
+    pub fn feature_control(self, host: u64) -> u64 {
+        match self {
+            Self::Hidden => (host | FEATURE_CONTROL_LOCK) & !(FEATURE_CONTROL_VMXON_INSIDE_SMX | FEATURE_CONTROL_VMXON_OUTSIDE_SMX),
+            Self::Locked => (host | FEATURE_CONTROL_LOCK) & !FEATURE_CONTROL_VMXON_OUTSIDE_SMX,
+            Self::Nested => host | FEATURE_CONTROL_LOCK | FEATURE_CONTROL_VMXON_OUTSIDE_SMX,
+        }
+    }
+
+    /// Returns the value of a VMX capability MSR the guest reads.
+    ///
+    /// # Arguments
+    ///
+    /// * `msr` - The MSR in `VMX_CAPABILITY_MSRS`.
+    /// * `host` - Reads an MSR of the processor.
+    ///
+    /// # Returns
+    ///
+    /// The value, `None` if RDMSR raises #GP(0) as it does for MSRs the reported capabilities don't enumerate.
+    pub fn capability(self, msr: u32, host: impl Fn(u32) -> u64) -> Option<u64> {
+        match self {
+            Self::Hidden => None,
+            Self::Locked => Some(host(msr)),
+            Self::Nested => match msr {
+                msr::IA32_VMX_BASIC => Some(host(msr) & !VMX_BASIC_TRUE_CONTROLS),
+                msr::IA32_VMX_PROCBASED_CTLS => Some(host(msr) & !PROCBASED_CTLS_SECONDARY_CONTROLS),
+                msr::IA32_VMX_PROCBASED_CTLS2 | msr::IA32_VMX_EPT_VPID_CAP | msr::IA32_VMX_VMFUNC => None,
+                msr::IA32_VMX_TRUE_PINBASED_CTLS..=msr::IA32_VMX_TRUE_ENTRY_CTLS => None,
+                _ => Some(host(msr)),
+            },
+        }
+    }
+}
+
+/// Returns what the processor returns for a leaf above its highest basic and extended leaves: the highest
+/// basic leaf.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: CPUID—CPU Identification
+fn unsupported_leaf(_leaf: u32, subleaf: u32, result: &mut CpuIdResult) {
+    let highest_basic_leaf = cpuid!(0).eax;
+    *result = cpuid!(highest_basic_leaf, subleaf);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: CpuIdResult = registers(0x1111_1111, 0x2222_2222, 0xFFFF_FFFF, 0x4444_4444);
+
+    fn seen(policy: &CpuidPolicy, leaf: u32, subleaf: u32) -> CpuIdResult {
+        let mut result = HOST;
+        if let Some(action) = policy.lookup(leaf, subleaf) {
+            action.apply(leaf, subleaf, &mut result);
+        }
+        result
+    }
+
+    #[test]
+    fn masks_on_the_same_leaf_combine() {
+        let mut policy = CpuidPolicy::new();
+        policy.add(1, None, CpuidAction::clear_ecx(HYPERVISOR_PRESENT_BIT));
+        policy.add(1, None, CpuidAction::clear_ecx(VMX_SUPPORT_BIT));
+
+        assert_eq!(policy.rules().len(), 1);
+        assert_eq!(seen(&policy, 1, 0).ecx, !(HYPERVISOR_PRESENT_BIT | VMX_SUPPORT_BIT));
+        assert_eq!(seen(&policy, 1, 0).eax, HOST.eax);
+        assert_eq!(seen(&policy, 2, 0), HOST);
+    }
+
+    #[test]
+    fn exact_subleaves_take_precedence() {
+        let mut policy = CpuidPolicy::new();
+        policy.add(7, Some(1), CpuidAction::Replace(registers(1, 2, 3, 4)));
+        policy.add(7, None, CpuidAction::Replace(registers(5, 6, 7, 8)));
+
+        assert_eq!(seen(&policy, 7, 1), registers(1, 2, 3, 4));
+        assert_eq!(seen(&policy, 7, 0), registers(5, 6, 7, 8));
+
+        assert_eq!(policy.remove(7, Some(1)), Some(CpuidAction::Replace(registers(1, 2, 3, 4))));
+        assert_eq!(policy.remove(7, Some(1)), None);
+        assert_eq!(seen(&policy, 7, 1), registers(5, 6, 7, 8));
+    }
+
+    #[test]
+    fn later_rules_override_ranges() {
+        let mut policy = CpuidPolicy::new();
+        policy.add_range(HYPERVISOR_LEAVES, None, CpuidAction::Replace(registers(0, 0, 0, 0)));
+        policy.add(0x4000_0000, None, CpuidAction::Replace(registers(0x4000_0001, 1, 2, 3)));
+
+        assert_eq!(seen(&policy, 0x4000_0000, 0), registers(0x4000_0001, 1, 2, 3));
+        assert_eq!(seen(&policy, 0x4000_00FF, 0), registers(0, 0, 0, 0));
+        assert_eq!(seen(&policy, 0x4000_0100, 0), HOST);
+
+        policy.add(0x4000_0000, None, CpuidAction::Passthrough);
+        assert_eq!(seen(&policy, 0x4000_0000, 0), registers(0x4000_0001, 1, 2, 3));
+    }
+
+    #[test]
+    fn handlers_see_the_host_result() {
+        let mut policy = CpuidPolicy::new();
+        policy.add(0xD, None, CpuidAction::Handler(|leaf, subleaf, result| result.eax = leaf + subleaf));
+
+        assert_eq!(seen(&policy, 0xD, 1).eax, 0xE);
+        assert_eq!(seen(&policy, 0xD, 1).ebx, HOST.ebx);
+    }
+
+    #[test]
+    fn features_select_the_rules() {
+        let hidden = CpuidPolicy::from_features(HvFeatureFlags::HIDE_CPUID_LEAF | HvFeatureFlags::HIDE_VMX);
+        assert_eq!(seen(&hidden, 1, 0).ecx, !(HYPERVISOR_PRESENT_BIT | VMX_SUPPORT_BIT));
+        assert_eq!(hidden.lookup(0x4000_0001, 0).is_some(), !cfg!(feature = "vmware"));
+
+        let faked = CpuidPolicy::from_features(HvFeatureFlags::FAKE_HYPERVISOR_LEAVES);
+        assert_eq!(seen(&faked, 1, 0), HOST);
+        assert_eq!(seen(&faked, 0x4000_0000, 0), registers(0x4000_0001, 0x756c_6c49, 0x6e6f_6973, 0));
+        assert_eq!(seen(&faked, 0x4000_0003, 0), registers(0, 0, 0, 0));
+
+        assert!(CpuidPolicy::from_features(HvFeatureFlags::empty()).rules().is_empty());
+    }
+
+    #[test]
+    fn masquerading_reports_hyperv() {
+        let hyperv = CpuidPolicy::from_features(HvFeatureFlags::HYPERV_MASQUERADE | HvFeatureFlags::HIDE_VMX);
+        assert_eq!(seen(&hyperv, 1, 0).ecx, !VMX_SUPPORT_BIT);
+
+        let vendor = seen(&hyperv, 0x4000_0000, 0);
+        assert_eq!(vendor.eax, 0x4000_0005);
+        assert!(shared::hyperv::is_hyperv_vendor(vendor.ebx, vendor.ecx, vendor.edx));
+        assert_eq!(seen(&hyperv, 0x4000_0001, 0).eax, u32::from_le_bytes(*b"Hv#1"));
+        assert_eq!(seen(&hyperv, 0x4000_0006, 0), registers(0, 0, 0, 0));
+
+        // Hiding the hypervisor takes precedence, the loader never passes both.
+        let hidden = CpuidPolicy::from_features(HvFeatureFlags::HYPERV_MASQUERADE | HvFeatureFlags::HIDE_CPUID_LEAF);
+        assert_eq!(seen(&hidden, 1, 0).ecx, !HYPERVISOR_PRESENT_BIT);
+    }
+
+    #[test]
+    fn vmx_msrs_follow_cpuid() {
+        let exposure = |features| VmxExposure::of(CpuidPolicy::from_features(features).guest_sees_vmx(), features);
+        assert_eq!(exposure(HvFeatureFlags::HIDE_VMX | HvFeatureFlags::EXPOSE_NESTED_VMX), VmxExposure::Hidden);
+        assert_eq!(exposure(HvFeatureFlags::HIDE_CPUID_LEAF), VmxExposure::Locked);
+        assert_eq!(exposure(HvFeatureFlags::EXPOSE_NESTED_VMX), VmxExposure::Nested);
+
+        // Firmware enabled VMXON outside SMX and locked the MSR.
+        assert_eq!(VmxExposure::Hidden.feature_control(0x5), 0x1);
+        assert_eq!(VmxExposure::Locked.feature_control(0x7), 0x3);
+        assert_eq!(VmxExposure::Nested.feature_control(0x0), 0x5);
+
+        let host = |msr: u32| u64::MAX - u64::from(msr);
+        assert_eq!(VmxExposure::Hidden.capability(msr::IA32_VMX_BASIC, host), None);
+        assert_eq!(VmxExposure::Hidden.capability(msr::IA32_VMX_CR0_FIXED0, host), None);
+        assert_eq!(VmxExposure::Locked.capability(msr::IA32_VMX_EPT_VPID_CAP, host), Some(host(msr::IA32_VMX_EPT_VPID_CAP)));
+    }
+
+    #[test]
+    fn nested_vmx_reports_a_reduced_set() {
+        let host = |_| u64::MAX;
+        let nested = |msr| VmxExposure::Nested.capability(msr, host);
+
+        assert_eq!(nested(msr::IA32_VMX_BASIC), Some(!(1 << 55)));
+        assert_eq!(nested(msr::IA32_VMX_PROCBASED_CTLS), Some(!(1 << 63)));
+        assert_eq!(nested(msr::IA32_VMX_PINBASED_CTLS), Some(u64::MAX));
+        assert_eq!(nested(msr::IA32_VMX_CR4_FIXED1), Some(u64::MAX));
+
+        // The MSRs enumerated by the secondary and true controls the set leaves out don't exist.
+        for msr in [
+            msr::IA32_VMX_PROCBASED_CTLS2,
+            msr::IA32_VMX_EPT_VPID_CAP,
+            msr::IA32_VMX_VMFUNC,
+            msr::IA32_VMX_TRUE_PROCBASED_CTLS,
+        ] {
+            assert_eq!(nested(msr), None);
+        }
+        assert!(VMX_CAPABILITY_MSRS.contains(&0x491) && !VMX_CAPABILITY_MSRS.contains(&0x492));
+    }
+}
@@ -0,0 +1,191 @@
+//! Keeps the CR0 and CR4 the guest intends apart from the ones it runs with.
+//!
+//! Bits set in a guest/host mask belong to the hypervisor: the guest reads them from the read shadow, and MOV
+//! to CR0/CR4, CLTS and LMSW exit when they would change them. The masks hold every bit VMX operation fixes, so
+//! the read shadow keeps the value the guest wrote while the processor runs with the fixed bits forced, and CR0
+//! also monitors CD and WP. The bits outside the masks the guest reads and writes natively.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.6.6 Guest/Host Masks and Read Shadows for CR0 and CR4
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 24.8 RESTRICTIONS ON VMX OPERATION
+
+use {
+    crate::intel::support::{rdmsr, vmread, vmwrite},
+    x86::{
+        msr,
+        vmx::vmcs::{self, control::SecondaryControls},
+    },
+    x86_64::registers::control::Cr0Flags,
+};
+
+/// The CR0 bits monitored beyond the fixed ones. Credits to @vmctx
+pub const CR0_MONITORED: u64 = Cr0Flags::CACHE_DISABLE.bits() | Cr0Flags::WRITE_PROTECT.bits();
+
+/// The CR4 bits monitored beyond the fixed ones.
+pub const CR4_MONITORED: u64 = 0;
+
+/// The guest/host mask of one control register and the bits VMX operation fixes in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CrShadow {
+    /// The bits the processor requires set, IA32_VMX_CRx_FIXED0.
+    pub fixed0: u64,
+
+    /// The bits the processor allows set, IA32_VMX_CRx_FIXED1.
+    pub fixed1: u64,
+
+    /// The guest/host mask, the fixed bits and the monitored ones.
+    pub mask: u64,
+}
+
+impl CrShadow {
+    /// Creates the shadow of a control register.
+    ///
+    /// # Arguments
+    ///
+    /// * `fixed0` - The bits the processor requires set.
+    /// * `fixed1` - The bits the processor allows set.
+    /// * `monitored` - The bits that exit on changes although VMX operation doesn't fix them.
+    pub const fn new(fixed0: u64, fixed1: u64, monitored: u64) -> Self {
+        Self {
+            fixed0,
+            fixed1,
+            mask: fixed0 | !fixed1 | monitored,
+        }
+    }
+
+    /// Returns the shadow of CR0 in the current VMCS.
+    ///
+    /// With "unrestricted guest" the guest may clear CR0.PE and CR0.PG, which stay monitored.
+    pub fn cr0() -> Self {
+        const PE_PG: u64 = Cr0Flags::PROTECTED_MODE_ENABLE.bits() | Cr0Flags::PAGING.bits();
+
+        let fixed0 = rdmsr(msr::IA32_VMX_CR0_FIXED0);
+        let fixed1 = rdmsr(msr::IA32_VMX_CR0_FIXED1);
+        let unrestricted_guest = vmread(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS) & SecondaryControls::UNRESTRICTED_GUEST.bits() as u64 != 0;
+
+        if unrestricted_guest {
+            Self::new(fixed0 & !PE_PG, fixed1, CR0_MONITORED | PE_PG)
+        } else {
+            Self::new(fixed0, fixed1, CR0_MONITORED)
+        }
+    }
+
+    /// Returns the shadow of CR4 on the current processor.
+    pub fn cr4() -> Self {
+        Self::new(rdmsr(msr::IA32_VMX_CR4_FIXED0), rdmsr(msr::IA32_VMX_CR4_FIXED1), CR4_MONITORED)
+    }
+
+    /// Returns the value the processor runs the guest with for the value the guest intends.
+    ///
+    /// # Arguments
+    ///
+    /// * `intended` - The value the guest wrote.
+    pub const fn guest_value(&self, intended: u64) -> u64 {
+        (intended | self.fixed0) & self.fixed1
+    }
+
+    /// Returns the value the guest reads: the read shadow for the bits of the mask, the guest value for the others.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest` - The value of the guest-state area.
+    /// * `read_shadow` - The read shadow.
+    pub const fn effective(&self, guest: u64, read_shadow: u64) -> u64 {
+        read_shadow & self.mask | guest & !self.mask
+    }
+
+    /// Returns whether a MOV to the register exits, which it does when it changes a bit of the mask.
+    ///
+    /// # Arguments
+    ///
+    /// * `read_shadow` - The read shadow.
+    /// * `value` - The source operand of the MOV.
+    pub const fn exits(&self, read_shadow: u64, value: u64) -> bool {
+        (read_shadow ^ value) & self.mask != 0
+    }
+}
+
+/// Writes the guest/host masks and initial read shadows of the current VMCS.
+///
+/// # Arguments
+///
+/// * `cr0` - The CR0 the guest starts with and reads.
+/// * `cr4` - The CR4 the guest reads, without CR4.VMXE which VMX operation forces.
+pub fn setup(cr0: u64, cr4: u64) {
+    vmwrite(vmcs::control::CR0_GUEST_HOST_MASK, CrShadow::cr0().mask);
+    vmwrite(vmcs::control::CR4_GUEST_HOST_MASK, CrShadow::cr4().mask);
+
+    vmwrite(vmcs::control::CR0_READ_SHADOW, cr0);
+    vmwrite(vmcs::control::CR4_READ_SHADOW, cr4);
+}
+
+/// Sets the CR0 of the guest: the read shadow to the value it intends, the guest CR0 to the one VMX allows.
+///
+/// # Arguments
+///
+/// * `intended` - The value the guest wrote.
+pub fn write_guest_cr0(intended: u64) {
+    vmwrite(vmcs::control::CR0_READ_SHADOW, intended);
+    vmwrite(vmcs::guest::CR0, CrShadow::cr0().guest_value(intended));
+}
+
+/// Sets the CR4 of the guest: the read shadow to the value it intends, the guest CR4 to the one VMX allows.
+///
+/// # Arguments
+///
+/// * `intended` - The value the guest wrote.
+pub fn write_guest_cr4(intended: u64) {
+    vmwrite(vmcs::control::CR4_READ_SHADOW, intended);
+    vmwrite(vmcs::guest::CR4, CrShadow::cr4().guest_value(intended));
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, x86_64::registers::control::Cr4Flags};
+
+    /// IA32_VMX_CR0_FIXED0 and FIXED1 of common processors: PE, NE and PG required, the upper half disallowed.
+    const CR0: CrShadow = CrShadow::new(0x8000_0021, 0xFFFF_FFFF, CR0_MONITORED);
+
+    /// IA32_VMX_CR4_FIXED0 and FIXED1 of common processors: VMXE required.
+    const CR4: CrShadow = CrShadow::new(0x2000, 0x0077_2FFF, CR4_MONITORED);
+
+    #[test]
+    fn masks_cover_the_fixed_and_monitored_bits() {
+        assert_eq!(CR0.mask, 0xFFFF_FFFF_8000_0021 | CR0_MONITORED);
+        assert_eq!(CR4.mask, !0x0077_2FFF | 0x2000);
+    }
+
+    #[test]
+    fn guests_read_the_values_they_intend() {
+        // Clearing NE leaves it set in the guest, the guest still reads it clear.
+        let intended = 0x8005_0013;
+        let guest = CR0.guest_value(intended);
+        assert_eq!(guest, 0x8005_0033);
+        assert_eq!(CR0.effective(guest, intended), intended);
+
+        // VMXE is forced but hidden.
+        let intended = 0x0035_06F8;
+        assert_eq!(CR4.guest_value(intended), intended | 0x2000);
+        assert_eq!(CR4.effective(CR4.guest_value(intended), intended), intended);
+
+        // Bits outside the mask come from the guest value.
+        assert_eq!(CR0.effective(0x8005_003B, 0x8005_0033), 0x8005_003B);
+    }
+
+    #[test]
+    fn unrestricted_guests_may_clear_pe_and_pg() {
+        const PE_PG: u64 = 0x8000_0001;
+        let unrestricted = CrShadow::new(0x8000_0021 & !PE_PG, 0xFFFF_FFFF, CR0_MONITORED | PE_PG);
+        assert_eq!(unrestricted.mask, CR0.mask);
+        assert_eq!(unrestricted.guest_value(0x0000_0010), 0x0000_0030);
+        assert_eq!(CR0.guest_value(0x0000_0010), 0x8000_0031);
+    }
+
+    #[test]
+    fn writes_exit_when_they_change_masked_bits() {
+        let shadow = 0x8005_0033;
+        assert!(!CR0.exits(shadow, shadow | Cr0Flags::TASK_SWITCHED.bits()));
+        assert!(CR0.exits(shadow, shadow & !Cr0Flags::WRITE_PROTECT.bits()));
+        assert!(CR0.exits(shadow, shadow & !Cr0Flags::PAGING.bits()));
+        assert!(CR4.exits(0x6F8, 0x6F8 | Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS.bits()));
+    }
+}
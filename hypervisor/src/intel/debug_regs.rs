@@ -0,0 +1,233 @@
+//! Virtualizes the debug registers of the guest.
+//!
+//! With "MOV-DR exiting" every MOV to or from DR0–DR7 of the guest exits and is served from the shadow the VM
+//! keeps: DR0–DR3 and DR6 in `DebugRegisters`, DR7 in the guest-state area, which VM entries load and VM exits
+//! save with the debug controls. The guest's DR0–DR3 and DR6 only sit in the hardware registers while it runs,
+//! the host's are saved right before every VM entry and restored right after every VM exit, together with the
+//! DR7 the VM exit reset to `0x400`. A hardware breakpoint the host arms while debugging the hypervisor thus
+//! never fires in the guest, and the guest's breakpoints never fire in VM exit handlers. Registers are only
+//! written where the values of the host and the guest differ, so the swap is a handful of reads as long as
+//! neither uses them.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 18.2 DEBUG REGISTERS
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 28.5.1 Loading Host Control Registers, Debug Registers, MSRs
+
+use {
+    crate::intel::support::{
+        dr0_read, dr0_write, dr1_read, dr1_write, dr2_read, dr2_write, dr3_read, dr3_write, dr6_read, dr6_write, dr7_read, dr7_write, vmread, vmwrite,
+    },
+    x86::vmx::vmcs,
+};
+
+/// [Bits 31:17, 11:4] DR6: reserved, always 1.
+const DR6_RESERVED_ONE: u64 = 0xFFFE_0FF0;
+
+/// The value of DR7 after reset and after VM exits, no breakpoint enabled.
+pub const DR7_INIT: u64 = 0x400;
+
+/// [Bits 11, 12, 14, 15] DR7: reserved, always 0.
+const DR7_RESERVED_ZERO: u64 = 0xD800;
+
+/// [Bit 13] DR7: general detect, MOV DR raises #DB.
+pub const DR7_GD: u64 = 1 << 13;
+
+/// [Bit 13] DR6: the #DB was raised by general detect.
+pub const DR6_BD: u64 = 1 << 13;
+
+/// DR0–DR3 and DR6 of one side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DebugRegisters {
+    /// The breakpoint addresses in DR0–DR3.
+    pub dr: [u64; 4],
+
+    /// The debug status in DR6.
+    pub dr6: u64,
+}
+
+impl DebugRegisters {
+    /// The registers after reset and INIT.
+    pub const INIT: Self = Self {
+        dr: [0; 4],
+        dr6: 0xFFFF_0FF0,
+    };
+
+    /// Reads the hardware registers.
+    pub fn read() -> Self {
+        Self {
+            dr: [dr0_read(), dr1_read(), dr2_read(), dr3_read()],
+            dr6: dr6_read(),
+        }
+    }
+
+    /// Writes the hardware registers, `current` holding the values they have, so only the differing ones are written.
+    ///
+    /// # Arguments
+    ///
+    /// * `current` - The values of the hardware registers.
+    pub fn load(&self, current: &Self) {
+        let writers: [fn(u64); 4] = [dr0_write, dr1_write, dr2_write, dr3_write];
+        for ((write, &value), &current) in writers.iter().zip(self.dr.iter()).zip(current.dr.iter()) {
+            if value != current {
+                write(value);
+            }
+        }
+        if self.dr6 != current.dr6 {
+            dr6_write(self.dr6);
+        }
+    }
+}
+
+/// The debug registers of the guest and the host on one processor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DebugState {
+    /// The guest's DR0–DR3 and DR6 while the host runs.
+    pub guest: DebugRegisters,
+
+    /// The host's DR0–DR3 and DR6 while the guest runs.
+    pub host: DebugRegisters,
+
+    /// The host's DR7 while the guest runs.
+    pub host_dr7: u64,
+}
+
+impl DebugState {
+    /// Captures the state of the current processor, whose guest starts with the current hardware registers.
+    pub fn capture() -> Self {
+        let current = DebugRegisters::read();
+        Self {
+            guest: current,
+            host: current,
+            host_dr7: dr7_read(),
+        }
+    }
+
+    /// Loads the guest's registers, right before a VM entry.
+    pub fn before_vm_entry(&mut self) {
+        self.host = DebugRegisters::read();
+        self.host_dr7 = dr7_read();
+        self.guest.load(&self.host);
+    }
+
+    /// Saves the guest's registers, a #DB may have changed DR6, and restores the host's, right after a VM exit.
+    pub fn after_vm_exit(&mut self) {
+        self.guest = DebugRegisters::read();
+        self.host.load(&self.guest);
+        if self.host_dr7 != DR7_INIT {
+            dr7_write(self.host_dr7);
+        }
+    }
+
+    /// Returns the value of a debug register the guest reads.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The debug register, `0` to `3`, `6` or `7`.
+    pub fn read(&self, index: u8) -> u64 {
+        match index {
+            0..=3 => self.guest.dr[index as usize],
+            6 => self.guest.dr6,
+            _ => vmread(vmcs::guest::DR7),
+        }
+    }
+
+    /// Writes a debug register of the guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The debug register, `0` to `3`, `6` or `7`.
+    /// * `value` - The value, checked by `checked_dr_write`.
+    pub fn write(&mut self, index: u8, value: u64) {
+        match index {
+            0..=3 => self.guest.dr[index as usize] = value,
+            6 => self.guest.dr6 = value,
+            _ => vmwrite(vmcs::guest::DR7, value),
+        }
+    }
+}
+
+/// How the guest accesses a debug register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DrAccess {
+    /// Access the debug register with this number, DR4 and DR5 resolved to DR6 and DR7.
+    Register(u8),
+
+    /// Raise #UD, DR4 and DR5 are reserved with CR4.DE set.
+    InvalidOpcode,
+
+    /// Raise #DB, DR7.GD is set.
+    GeneralDetect,
+}
+
+/// Resolves the debug register a MOV DR accesses.
+///
+/// # Arguments
+///
+/// * `index` - The debug register of the instruction.
+/// * `debugging_extensions` - Whether CR4.DE is set.
+/// * `dr7` - The DR7 of the guest.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 18.2.2 Debug Registers DR4 and DR5
+pub fn resolve(index: u8, debugging_extensions: bool, dr7: u64) -> DrAccess {
+    if matches!(index, 4 | 5) && debugging_extensions {
+        return DrAccess::InvalidOpcode;
+    }
+
+    if dr7 & DR7_GD != 0 {
+        return DrAccess::GeneralDetect;
+    }
+
+    match index {
+        4 => DrAccess::Register(6),
+        5 => DrAccess::Register(7),
+        _ => DrAccess::Register(index),
+    }
+}
+
+/// Checks a value MOV to DR writes the way the instruction does.
+///
+/// # Arguments
+///
+/// * `index` - The debug register, `0` to `3`, `6` or `7`.
+/// * `value` - The source operand.
+///
+/// # Returns
+///
+/// The value the register takes, `None` if the instruction raises #GP(0) for setting bits 63:32 of DR6 or DR7.
+pub fn checked_dr_write(index: u8, value: u64) -> Option<u64> {
+    match index {
+        0..=3 => Some(value),
+        _ if value >> 32 != 0 => None,
+        6 => Some(value | DR6_RESERVED_ONE),
+        _ => Some(value & !DR7_RESERVED_ZERO | DR7_INIT),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dr4_and_dr5_alias_dr6_and_dr7_without_de() {
+        assert_eq!(resolve(4, false, DR7_INIT), DrAccess::Register(6));
+        assert_eq!(resolve(5, false, DR7_INIT), DrAccess::Register(7));
+        assert_eq!(resolve(4, true, DR7_INIT), DrAccess::InvalidOpcode);
+        assert_eq!(resolve(5, true, DR7_INIT | DR7_GD), DrAccess::InvalidOpcode);
+        assert_eq!(resolve(2, true, DR7_INIT), DrAccess::Register(2));
+        assert_eq!(resolve(7, false, DR7_INIT | DR7_GD), DrAccess::GeneralDetect);
+    }
+
+    #[test]
+    fn dr6_and_dr7_writes_are_normalized() {
+        assert_eq!(checked_dr_write(0, 0xFFFF_F801_2345_6789), Some(0xFFFF_F801_2345_6789));
+        assert_eq!(checked_dr_write(6, 1 << 32), None);
+        assert_eq!(checked_dr_write(7, 1 << 40), None);
+
+        // The reserved bits of DR6 read as 1, RTM and B0–B3 as written.
+        assert_eq!(checked_dr_write(6, 0), Some(0xFFFE_0FF0));
+        assert_eq!(checked_dr_write(6, 0x1_4001), Some(0xFFFF_4FF1));
+
+        // Bit 10 of DR7 reads as 1, bits 11, 12, 14 and 15 as 0.
+        assert_eq!(checked_dr_write(7, 0), Some(DR7_INIT));
+        assert_eq!(checked_dr_write(7, 0xFFFF_FFFF), Some(0xFFFF_27FF));
+    }
+}
@@ -2,7 +2,7 @@
 //!
 //! Facilitates the creation and manipulation of the Global Descriptor Table (GDT),
 //! Interrupt Descriptor Table (IDT), and Task State Segment (TSS) necessary for VMX operations.
-//! Supports both host and guest environments, ensuring compatibility and proper setup for virtualization.
+//! Used for the guest environment, the host runs on the tables of `intel::host_arch`.
 //! Credits to Satoshi Tanda: https://github.com/tandasat/Hello-VT-rp/blob/main/hypervisor/src/intel_vt/descriptors.rs
 
 use {
@@ -10,13 +10,11 @@ use {
     alloc::vec::Vec,
     x86::{
         dtables::DescriptorTablePointer,
-        segmentation::{
-            cs, BuildDescriptor, CodeSegmentType, Descriptor, DescriptorBuilder, GateDescriptorBuilder, SegmentDescriptorBuilder, SegmentSelector,
-        },
+        segmentation::{cs, BuildDescriptor, Descriptor, DescriptorBuilder, GateDescriptorBuilder, SegmentSelector},
     },
 };
 
-/// Represents the descriptor tables (GDT and IDT) for the host and guest.
+/// Represents the descriptor tables (GDT and IDT) for the guest.
 /// Contains the GDT, IDT, TSS, and their respective register pointers.
 #[repr(C, align(4096))]
 pub struct Descriptors {
@@ -89,44 +87,6 @@ impl Descriptors {
         descriptors
     }
 
-    /// Creates a new GDT with TSS from scratch for the host.
-    ///
-    /// Initializes a GDT with essential descriptors, including a TSS descriptor,
-    /// tailored for host operation in a VMX environment.
-    ///
-    /// # Returns
-    /// A `Descriptors` instance with a newly created GDT for the host.
-    pub fn initialize_for_host() -> Self {
-        log::debug!("Creating a new GDT with TSS for host");
-
-        let mut descriptors = Descriptors {
-            gdt: Vec::new(),
-            gdtr: DescriptorTablePointer::<u64>::default(),
-            idt: Vec::new(),
-            idtr: DescriptorTablePointer::<u64>::default(),
-            cs: SegmentSelector::from_raw(0),
-            tr: SegmentSelector::from_raw(0),
-            tss: TaskStateSegment::default(),
-        };
-
-        descriptors.gdt.push(0);
-        descriptors.gdt.push(Self::code_segment_descriptor().as_u64());
-        descriptors.gdt.push(Self::task_segment_descriptor(&descriptors.tss).as_u64());
-        descriptors.gdt.push(0);
-
-        descriptors.gdtr = DescriptorTablePointer::new_from_slice(&descriptors.gdt);
-        descriptors.cs = SegmentSelector::new(1, x86::Ring::Ring0);
-        descriptors.tr = SegmentSelector::new(2, x86::Ring::Ring0);
-
-        // Initialize the IDT with empty descriptors for the host
-        descriptors.idt = Self::copy_current_idt();
-        descriptors.idtr = DescriptorTablePointer::new_from_slice(&descriptors.idt);
-
-        log::debug!("New GDT with TSS and IDT created for host successfully!");
-
-        descriptors
-    }
-
     /// Builds a descriptor for the Task State Segment (TSS).
     ///
     /// Configures a TSS descriptor based on the provided TSS's base and limit,
@@ -145,41 +105,6 @@ impl Descriptors {
             .dpl(x86::Ring::Ring0)
             .finish()
     }
-
-    /// Constructs a code segment descriptor for use in the GDT.
-    ///
-    /// Creates a descriptor representing a code segment with standard access rights,
-    /// suitable for execution in a protected or long mode environment.
-    ///
-    /// # Returns
-    ///
-    /// A `Descriptor` instance configured as a code segment.
-    fn code_segment_descriptor() -> Descriptor {
-        DescriptorBuilder::code_descriptor(0, u32::MAX, CodeSegmentType::ExecuteAccessed)
-            .present()
-            .dpl(x86::Ring::Ring0)
-            .limit_granularity_4kb()
-            .l()
-            .finish()
-    }
-
-    /// Copies the current IDT for the guest.
-    fn copy_current_idt() -> Vec<u64> {
-        log::trace!("Copying current IDT");
-
-        // Get the current IDTR
-        let current_idtr = sidt();
-
-        // Create a slice from the current IDT entries.
-        let current_idt = unsafe { core::slice::from_raw_parts(current_idtr.base.cast::<u64>(), usize::from(current_idtr.limit + 1) / 8) };
-
-        // Create a new IDT from the slice.
-        let new_idt = current_idt.to_vec();
-
-        log::trace!("Copied current IDT");
-
-        new_idt
-    }
 }
 
 /// Represents the Task State Segment (TSS).
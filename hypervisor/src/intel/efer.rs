@@ -0,0 +1,246 @@
+//! Keeps the IA32_EFER of the guest apart from the host's and tracks its switches into and out of IA-32e mode.
+//!
+//! The guest is virtualized before the boot manager, which goes from real mode through protected mode into
+//! long mode on the application processors, so its IA32_EFER changes while the host keeps running in long
+//! mode. VM entries load the guest's IA32_EFER from the guest-state area and VM exits save it and load the
+//! host's, WRMSR to IA32_EFER exits and only writes the guest-state field. IA-32e mode is activated by the
+//! MOV to CR0 setting CR0.PG with EFER.LME set and deactivated by the one clearing it, which exit as CR0.PG
+//! is monitored under "unrestricted guest". `switch_mode` sets EFER.LMA and the "IA-32e mode guest" entry
+//! control together, as VM entries require them to match.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 10.8.5 Initializing IA-32e Mode
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.3.1.1 Checks on Guest Control Registers, Debug Registers, and MSRs
+
+use {
+    crate::intel::support::{rdmsr, vmread, vmwrite, wrmsr},
+    x86::{
+        msr,
+        vmx::vmcs::{self, control::EntryControls},
+    },
+    x86_64::registers::control::{Cr0Flags, Cr4Flags},
+};
+
+/// [Bit 0] IA32_EFER: SYSCALL enable.
+pub const SCE: u64 = 1 << 0;
+
+/// [Bit 8] IA32_EFER: IA-32e mode enable.
+pub const LME: u64 = 1 << 8;
+
+/// [Bit 10] IA32_EFER: IA-32e mode active, read-only.
+pub const LMA: u64 = 1 << 10;
+
+/// [Bit 11] IA32_EFER: execute-disable bit enable.
+pub const NXE: u64 = 1 << 11;
+
+/// [Bit 13] Segment access rights: 64-bit code segment.
+const AR_L: u64 = 1 << 13;
+
+/// The features of the processor the bits of IA32_EFER depend on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EferFeatures {
+    /// CPUID.80000001H:EDX.SYSCALL[bit 11], EFER.SCE may be set.
+    pub syscall: bool,
+
+    /// CPUID.80000001H:EDX.XD[bit 20], EFER.NXE may be set.
+    pub execute_disable: bool,
+}
+
+impl EferFeatures {
+    /// Reads the features of the current processor.
+    pub fn read() -> Self {
+        let features = x86::cpuid::CpuId::new().get_extended_processor_and_feature_identifiers();
+        Self {
+            syscall: features.as_ref().is_some_and(|features| features.has_syscall_sysret()),
+            execute_disable: features.as_ref().is_some_and(|features| features.has_execute_disable()),
+        }
+    }
+}
+
+/// Checks a value WRMSR writes to IA32_EFER the way the instruction does.
+///
+/// # Arguments
+///
+/// * `value` - EDX:EAX of WRMSR.
+/// * `efer` - The current IA32_EFER of the guest.
+/// * `paging` - Whether the guest has CR0.PG set.
+/// * `features` - The features of the processor.
+///
+/// # Returns
+///
+/// The value IA32_EFER takes, EFER.LMA unchanged, `None` if the instruction raises #GP(0).
+pub fn checked_efer_write(value: u64, efer: u64, paging: bool, features: EferFeatures) -> Option<u64> {
+    let mut defined = LME | LMA;
+    if features.syscall {
+        defined |= SCE;
+    }
+    if features.execute_disable {
+        defined |= NXE;
+    }
+
+    // #GP(0) if setting reserved bits, SCE and NXE included without the features
+    if value & !defined != 0 {
+        return None;
+    }
+
+    // #GP(0) if changing EFER.LME while paging is enabled
+    if paging && (value ^ efer) & LME != 0 {
+        return None;
+    }
+
+    Some(value & !LMA | efer & LMA)
+}
+
+/// How a MOV to CR0 changes the mode of the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModeSwitch {
+    /// The guest stays in or out of IA-32e mode.
+    Unchanged,
+
+    /// Setting CR0.PG with EFER.LME set activates IA-32e mode.
+    Activate,
+
+    /// Clearing CR0.PG in compatibility mode deactivates IA-32e mode.
+    Deactivate,
+}
+
+/// Returns how a MOV to CR0 changes the mode of the guest.
+///
+/// # Arguments
+///
+/// * `cr0` - The current CR0 of the guest.
+/// * `new_cr0` - The CR0 the instruction writes.
+/// * `cr4` - The CR4 of the guest.
+/// * `efer` - The IA32_EFER of the guest.
+/// * `code_64bit` - Whether the guest runs 64-bit code, IA-32e mode with CS.L set.
+///
+/// # Returns
+///
+/// The switch, `None` if the instruction raises #GP(0) for enabling paging with EFER.LME set and CR4.PAE
+/// clear, or for disabling it in 64-bit mode.
+pub fn mode_switch(cr0: u64, new_cr0: u64, cr4: u64, efer: u64, code_64bit: bool) -> Option<ModeSwitch> {
+    let paging = cr0 & Cr0Flags::PAGING.bits() != 0;
+    let new_paging = new_cr0 & Cr0Flags::PAGING.bits() != 0;
+
+    if !paging && new_paging && efer & LME != 0 {
+        if cr4 & Cr4Flags::PHYSICAL_ADDRESS_EXTENSION.bits() == 0 {
+            return None;
+        }
+        return Some(ModeSwitch::Activate);
+    }
+
+    if paging && !new_paging && efer & LMA != 0 {
+        if code_64bit {
+            return None;
+        }
+        return Some(ModeSwitch::Deactivate);
+    }
+
+    Some(ModeSwitch::Unchanged)
+}
+
+/// Returns whether VM entries and VM exits switch IA32_EFER, which they do where the processor supports it.
+fn switched() -> bool {
+    vmread(vmcs::control::VMENTRY_CONTROLS) & EntryControls::LOAD_IA32_EFER.bits() as u64 != 0
+}
+
+/// Returns the IA32_EFER of the guest.
+pub fn read_guest() -> u64 {
+    if switched() {
+        vmread(vmcs::guest::IA32_EFER_FULL)
+    } else {
+        rdmsr(msr::IA32_EFER)
+    }
+}
+
+/// Writes the IA32_EFER of the guest.
+///
+/// # Arguments
+///
+/// * `value` - The value, checked by `checked_efer_write`.
+pub fn write_guest(value: u64) {
+    if switched() {
+        vmwrite(vmcs::guest::IA32_EFER_FULL, value);
+    } else {
+        // Without the controls the host shares IA32_EFER, whose LMA and LME VM exits set again.
+        wrmsr(msr::IA32_EFER, value);
+    }
+}
+
+/// Returns whether the guest runs 64-bit code, IA-32e mode with CS.L set.
+pub fn code_64bit() -> bool {
+    let ia32e = vmread(vmcs::control::VMENTRY_CONTROLS) & EntryControls::IA32E_MODE_GUEST.bits() as u64 != 0;
+    ia32e && vmread(vmcs::guest::CS_ACCESS_RIGHTS) & AR_L != 0
+}
+
+/// Activates or deactivates IA-32e mode, setting EFER.LMA and the "IA-32e mode guest" entry control.
+///
+/// # Arguments
+///
+/// * `switch` - The switch returned by `mode_switch`.
+pub fn switch_mode(switch: ModeSwitch) {
+    let active = match switch {
+        ModeSwitch::Unchanged => return,
+        ModeSwitch::Activate => true,
+        ModeSwitch::Deactivate => false,
+    };
+
+    let efer = read_guest();
+    write_guest(if active { efer | LMA } else { efer & !LMA });
+
+    let ia32e = EntryControls::IA32E_MODE_GUEST.bits() as u64;
+    let entry = vmread(vmcs::control::VMENTRY_CONTROLS);
+    vmwrite(vmcs::control::VMENTRY_CONTROLS, if active { entry | ia32e } else { entry & !ia32e });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FEATURES: EferFeatures = EferFeatures {
+        syscall: true,
+        execute_disable: true,
+    };
+
+    const PE: u64 = Cr0Flags::PROTECTED_MODE_ENABLE.bits();
+    const PG: u64 = Cr0Flags::PAGING.bits();
+    const PAE: u64 = Cr4Flags::PHYSICAL_ADDRESS_EXTENSION.bits();
+
+    #[test]
+    fn efer_writes_follow_wrmsr() {
+        // The trampoline of an application processor sets LME before paging.
+        assert_eq!(checked_efer_write(LME | NXE, 0, false, FEATURES), Some(LME | NXE));
+        assert_eq!(checked_efer_write(SCE | LME | NXE, LME | LMA | NXE, true, FEATURES), Some(SCE | LME | LMA | NXE));
+
+        // LMA is read-only.
+        assert_eq!(checked_efer_write(LME | LMA, LME, false, FEATURES), Some(LME));
+        assert_eq!(checked_efer_write(LME, LME | LMA, true, FEATURES), Some(LME | LMA));
+
+        assert_eq!(checked_efer_write(0, LME | LMA, true, FEATURES), None);
+        assert_eq!(checked_efer_write(LME, 0, true, FEATURES), None);
+        assert_eq!(checked_efer_write(1 << 12, 0, false, FEATURES), None);
+        assert_eq!(
+            checked_efer_write(
+                NXE,
+                0,
+                false,
+                EferFeatures {
+                    execute_disable: false,
+                    ..FEATURES
+                }
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn paging_with_lme_switches_to_ia32e_mode() {
+        assert_eq!(mode_switch(PE, PE | PG, PAE, LME, false), Some(ModeSwitch::Activate));
+        assert_eq!(mode_switch(PE, PE | PG, 0, LME, false), None);
+        assert_eq!(mode_switch(PE, PE | PG, PAE, 0, false), Some(ModeSwitch::Unchanged));
+
+        assert_eq!(mode_switch(PE | PG, PE, PAE, LME | LMA, false), Some(ModeSwitch::Deactivate));
+        assert_eq!(mode_switch(PE | PG, PE, PAE, LME | LMA, true), None);
+        assert_eq!(mode_switch(PE | PG, PE, PAE, 0, false), Some(ModeSwitch::Unchanged));
+        assert_eq!(mode_switch(PE | PG, PE | PG, PAE, LME | LMA, true), Some(ModeSwitch::Unchanged));
+    }
+}
@@ -0,0 +1,795 @@
+//! Software version of the checks the processor performs on VM entry.
+//!
+//! A failed `vmlaunch` only reports an instruction error such as "invalid host state", and a guest state
+//! the processor rejects only shows up as exit reason 33. The checks of Intel® 64 and IA-32 Architectures
+//! Software Developer's Manual: 27.2 CHECKS ON VMX CONTROLS AND HOST-STATE AREA and 27.3.1 Checks on the
+//! Guest State Area are repeated here on a `VmcsSnapshot`, so the log names the field that is wrong.
+//!
+//! Nothing in this module touches the processor, the snapshot is captured by `intel::entry_failure` and
+//! the tests build theirs by hand. Checks the hypervisor can't get wrong, e.g. on fields it never uses
+//! such as the PDPTEs or the MSR load areas, are left out.
+
+use {
+    alloc::vec::Vec,
+    core::fmt,
+    x86::vmx::vmcs::{control, guest, host},
+};
+
+/// The fields logged when a VM entry fails, with the names they are logged with.
+pub const FIELDS: &[(&str, u32)] = &[
+    /* Control fields */
+    ("Pin-based VM-execution controls", control::PINBASED_EXEC_CONTROLS),
+    ("Primary processor-based VM-execution controls", control::PRIMARY_PROCBASED_EXEC_CONTROLS),
+    ("Secondary processor-based VM-execution controls", control::SECONDARY_PROCBASED_EXEC_CONTROLS),
+    ("VM-exit controls", control::VMEXIT_CONTROLS),
+    ("VM-entry controls", control::VMENTRY_CONTROLS),
+    ("Exception bitmap", control::EXCEPTION_BITMAP),
+    ("Page-fault error-code mask", control::PAGE_FAULT_ERR_CODE_MASK),
+    ("Page-fault error-code match", control::PAGE_FAULT_ERR_CODE_MATCH),
+    ("CR3-target count", control::CR3_TARGET_COUNT),
+    ("VM-exit MSR-store count", control::VMEXIT_MSR_STORE_COUNT),
+    ("VM-exit MSR-load count", control::VMEXIT_MSR_LOAD_COUNT),
+    ("VM-entry MSR-load count", control::VMENTRY_MSR_LOAD_COUNT),
+    ("VM-entry interruption-information field", control::VMENTRY_INTERRUPTION_INFO_FIELD),
+    ("VM-entry exception error code", control::VMENTRY_EXCEPTION_ERR_CODE),
+    ("VM-entry instruction length", control::VMENTRY_INSTRUCTION_LEN),
+    ("TPR threshold", control::TPR_THRESHOLD),
+    ("CR0 guest/host mask", control::CR0_GUEST_HOST_MASK),
+    ("CR4 guest/host mask", control::CR4_GUEST_HOST_MASK),
+    ("CR0 read shadow", control::CR0_READ_SHADOW),
+    ("CR4 read shadow", control::CR4_READ_SHADOW),
+    ("I/O bitmap A address", control::IO_BITMAP_A_ADDR_FULL),
+    ("I/O bitmap B address", control::IO_BITMAP_B_ADDR_FULL),
+    ("MSR bitmap address", control::MSR_BITMAPS_ADDR_FULL),
+    ("TSC offset", control::TSC_OFFSET_FULL),
+    ("EPT pointer", control::EPTP_FULL),
+    ("VPID", control::VPID),
+    /* Host-state fields */
+    ("Host CR0", host::CR0),
+    ("Host CR3", host::CR3),
+    ("Host CR4", host::CR4),
+    ("Host RSP", host::RSP),
+    ("Host RIP", host::RIP),
+    ("Host ES selector", host::ES_SELECTOR),
+    ("Host CS selector", host::CS_SELECTOR),
+    ("Host SS selector", host::SS_SELECTOR),
+    ("Host DS selector", host::DS_SELECTOR),
+    ("Host FS selector", host::FS_SELECTOR),
+    ("Host GS selector", host::GS_SELECTOR),
+    ("Host TR selector", host::TR_SELECTOR),
+    ("Host FS base", host::FS_BASE),
+    ("Host GS base", host::GS_BASE),
+    ("Host TR base", host::TR_BASE),
+    ("Host GDTR base", host::GDTR_BASE),
+    ("Host IDTR base", host::IDTR_BASE),
+    ("Host IA32_SYSENTER_CS", host::IA32_SYSENTER_CS),
+    ("Host IA32_SYSENTER_ESP", host::IA32_SYSENTER_ESP),
+    ("Host IA32_SYSENTER_EIP", host::IA32_SYSENTER_EIP),
+    ("Host IA32_PAT", host::IA32_PAT_FULL),
+    ("Host IA32_EFER", host::IA32_EFER_FULL),
+    /* Guest-state fields */
+    ("Guest CR0", guest::CR0),
+    ("Guest CR3", guest::CR3),
+    ("Guest CR4", guest::CR4),
+    ("Guest DR7", guest::DR7),
+    ("Guest RSP", guest::RSP),
+    ("Guest RIP", guest::RIP),
+    ("Guest RFLAGS", guest::RFLAGS),
+    ("Guest ES selector", guest::ES_SELECTOR),
+    ("Guest CS selector", guest::CS_SELECTOR),
+    ("Guest SS selector", guest::SS_SELECTOR),
+    ("Guest DS selector", guest::DS_SELECTOR),
+    ("Guest FS selector", guest::FS_SELECTOR),
+    ("Guest GS selector", guest::GS_SELECTOR),
+    ("Guest LDTR selector", guest::LDTR_SELECTOR),
+    ("Guest TR selector", guest::TR_SELECTOR),
+    ("Guest ES base", guest::ES_BASE),
+    ("Guest CS base", guest::CS_BASE),
+    ("Guest SS base", guest::SS_BASE),
+    ("Guest DS base", guest::DS_BASE),
+    ("Guest FS base", guest::FS_BASE),
+    ("Guest GS base", guest::GS_BASE),
+    ("Guest LDTR base", guest::LDTR_BASE),
+    ("Guest TR base", guest::TR_BASE),
+    ("Guest ES limit", guest::ES_LIMIT),
+    ("Guest CS limit", guest::CS_LIMIT),
+    ("Guest SS limit", guest::SS_LIMIT),
+    ("Guest DS limit", guest::DS_LIMIT),
+    ("Guest FS limit", guest::FS_LIMIT),
+    ("Guest GS limit", guest::GS_LIMIT),
+    ("Guest LDTR limit", guest::LDTR_LIMIT),
+    ("Guest TR limit", guest::TR_LIMIT),
+    ("Guest ES access rights", guest::ES_ACCESS_RIGHTS),
+    ("Guest CS access rights", guest::CS_ACCESS_RIGHTS),
+    ("Guest SS access rights", guest::SS_ACCESS_RIGHTS),
+    ("Guest DS access rights", guest::DS_ACCESS_RIGHTS),
+    ("Guest FS access rights", guest::FS_ACCESS_RIGHTS),
+    ("Guest GS access rights", guest::GS_ACCESS_RIGHTS),
+    ("Guest LDTR access rights", guest::LDTR_ACCESS_RIGHTS),
+    ("Guest TR access rights", guest::TR_ACCESS_RIGHTS),
+    ("Guest GDTR base", guest::GDTR_BASE),
+    ("Guest GDTR limit", guest::GDTR_LIMIT),
+    ("Guest IDTR base", guest::IDTR_BASE),
+    ("Guest IDTR limit", guest::IDTR_LIMIT),
+    ("Guest IA32_DEBUGCTL", guest::IA32_DEBUGCTL_FULL),
+    ("Guest IA32_SYSENTER_CS", guest::IA32_SYSENTER_CS),
+    ("Guest IA32_SYSENTER_ESP", guest::IA32_SYSENTER_ESP),
+    ("Guest IA32_SYSENTER_EIP", guest::IA32_SYSENTER_EIP),
+    ("Guest IA32_PAT", guest::IA32_PAT_FULL),
+    ("Guest IA32_EFER", guest::IA32_EFER_FULL),
+    ("Guest interruptibility state", guest::INTERRUPTIBILITY_STATE),
+    ("Guest activity state", guest::ACTIVITY_STATE),
+    ("Guest pending debug exceptions", guest::PENDING_DBG_EXCEPTIONS),
+    ("Guest VMCS link pointer", guest::LINK_PTR_FULL),
+];
+
+/// The capability MSRs the checks compare the VMCS against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmxCapabilities {
+    /// `IA32_VMX_CR0_FIXED0`, bits that must be set in CR0.
+    pub cr0_fixed0: u64,
+
+    /// `IA32_VMX_CR0_FIXED1`, bits that may be set in CR0.
+    pub cr0_fixed1: u64,
+
+    /// `IA32_VMX_CR4_FIXED0`, bits that must be set in CR4.
+    pub cr4_fixed0: u64,
+
+    /// `IA32_VMX_CR4_FIXED1`, bits that may be set in CR4.
+    pub cr4_fixed1: u64,
+
+    /// The (TRUE) capability MSR of the pin-based controls, allowed 0-settings low, allowed 1-settings high.
+    pub pin_based: u64,
+
+    /// The (TRUE) capability MSR of the primary processor-based controls.
+    pub primary_processor_based: u64,
+
+    /// `IA32_VMX_PROCBASED_CTLS2`.
+    pub secondary_processor_based: u64,
+
+    /// The (TRUE) capability MSR of the VM-exit controls.
+    pub exit: u64,
+
+    /// The (TRUE) capability MSR of the VM-entry controls.
+    pub entry: u64,
+
+    /// MAXPHYADDR, the physical-address width of CPUID leaf 0x80000008.
+    pub physical_address_width: u8,
+}
+
+/// The VMCS fields of `FIELDS` and the capabilities of the processor.
+#[derive(Debug, Clone)]
+pub struct VmcsSnapshot {
+    /// The value of every field in `FIELDS`, `None` if it wasn't read.
+    values: [Option<u64>; FIELDS.len()],
+
+    /// The capabilities of the processor the VMCS was captured on.
+    pub capabilities: VmxCapabilities,
+}
+
+impl VmcsSnapshot {
+    /// Creates a snapshot without any field values.
+    pub fn new(capabilities: VmxCapabilities) -> Self {
+        Self {
+            values: [None; FIELDS.len()],
+            capabilities,
+        }
+    }
+
+    /// Records the value of the field `encoding`, which must be in `FIELDS`.
+    pub fn set(&mut self, encoding: u32, value: u64) {
+        let index = FIELDS.iter().position(|&(_, field)| field == encoding);
+        debug_assert!(index.is_some(), "VMCS field {:#x} is not part of the snapshot", encoding);
+        if let Some(index) = index {
+            self.values[index] = Some(value);
+        }
+    }
+
+    /// Returns the value of the field `encoding`, `0` if it wasn't read.
+    pub fn get(&self, encoding: u32) -> u64 {
+        FIELDS
+            .iter()
+            .position(|&(_, field)| field == encoding)
+            .and_then(|index| self.values[index])
+            .unwrap_or(0)
+    }
+
+    /// Returns the name, encoding and value of every field in `FIELDS`.
+    pub fn fields(&self) -> impl Iterator<Item = (&'static str, u32, Option<u64>)> + '_ {
+        FIELDS
+            .iter()
+            .zip(self.values.iter())
+            .map(|(&(name, encoding), &value)| (name, encoding, value))
+    }
+}
+
+/// A check the snapshot fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FailedCheck {
+    /// The field or part of a field that is wrong, e.g. `CS.DPL`.
+    pub field: &'static str,
+
+    /// What is wrong with it, e.g. `!= SS.DPL`.
+    pub problem: &'static str,
+}
+
+impl fmt::Display for FailedCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.field, self.problem)
+    }
+}
+
+const CR0_PE: u64 = 1 << 0;
+const CR0_PG: u64 = 1 << 31;
+const CR4_PAE: u64 = 1 << 5;
+const CR4_PCIDE: u64 = 1 << 17;
+
+const PRIMARY_ACTIVATE_SECONDARY_CONTROLS: u64 = 1 << 31;
+const SECONDARY_ENABLE_EPT: u64 = 1 << 1;
+const SECONDARY_UNRESTRICTED_GUEST: u64 = 1 << 7;
+const EXIT_HOST_ADDRESS_SPACE_SIZE: u64 = 1 << 9;
+const ENTRY_LOAD_DEBUG_CONTROLS: u64 = 1 << 2;
+const ENTRY_IA32E_MODE_GUEST: u64 = 1 << 9;
+const ENTRY_LOAD_IA32_PAT: u64 = 1 << 14;
+const ENTRY_LOAD_IA32_EFER: u64 = 1 << 15;
+
+const EFER_LME: u64 = 1 << 8;
+const EFER_LMA: u64 = 1 << 10;
+/// SCE, LME, LMA and NXE, the bits of IA32_EFER that aren't reserved.
+const EFER_DEFINED: u64 = 1 << 0 | EFER_LME | EFER_LMA | 1 << 11;
+
+const RFLAGS_FIXED1: u64 = 1 << 1;
+const RFLAGS_IF: u64 = 1 << 9;
+const RFLAGS_VM: u64 = 1 << 17;
+/// Bits 63:22, 15, 5 and 3 of RFLAGS.
+const RFLAGS_RESERVED: u64 = !((1 << 22) - 1) | 1 << 15 | 1 << 5 | 1 << 3;
+
+const AR_S: u32 = 1 << 4;
+const AR_P: u32 = 1 << 7;
+const AR_L: u32 = 1 << 13;
+const AR_DB: u32 = 1 << 14;
+const AR_G: u32 = 1 << 15;
+const AR_UNUSABLE: u32 = 1 << 16;
+/// Bits 11:8 and 31:17 of the access rights.
+const AR_RESERVED: u32 = 0xf00 | !((1 << 17) - 1);
+
+const ACTIVITY_STATE_HLT: u64 = 1;
+const ACTIVITY_STATE_WAIT_FOR_SIPI: u64 = 3;
+
+const INTERRUPTIBILITY_STI: u64 = 1 << 0;
+const INTERRUPTIBILITY_MOV_SS: u64 = 1 << 1;
+
+/// The host runs on 4-level paging, so linear addresses are canonical at 48 bits.
+const LINEAR_ADDRESS_WIDTH: u32 = 48;
+
+/// Runs every check on `snapshot`.
+///
+/// # Returns
+///
+/// The checks the snapshot fails, in the order the processor performs them, empty if it passes all of them.
+pub fn check(snapshot: &VmcsSnapshot) -> Vec<FailedCheck> {
+    let mut failures = Checks(Vec::new());
+    check_controls(snapshot, &mut failures);
+    check_host_state(snapshot, &mut failures);
+    check_guest_state(snapshot, &mut failures);
+    failures.0
+}
+
+/// The failures found so far.
+struct Checks(Vec<FailedCheck>);
+
+impl Checks {
+    /// Records a failure unless `passed`.
+    fn expect(&mut self, passed: bool, field: &'static str, problem: &'static str) {
+        if !passed {
+            self.0.push(FailedCheck { field, problem });
+        }
+    }
+}
+
+/// Returns whether `address` is canonical.
+fn is_canonical(address: u64) -> bool {
+    let shift = u64::BITS - LINEAR_ADDRESS_WIDTH;
+    ((address << shift) as i64 >> shift) as u64 == address
+}
+
+/// Returns whether `address` has no bits set beyond the physical-address width.
+fn fits_physical_address_width(snapshot: &VmcsSnapshot, address: u64) -> bool {
+    address >> snapshot.capabilities.physical_address_width == 0
+}
+
+/// Returns whether the secondary processor-based control `bit` is in effect.
+fn has_secondary_control(snapshot: &VmcsSnapshot, bit: u64) -> bool {
+    snapshot.get(control::PRIMARY_PROCBASED_EXEC_CONTROLS) & PRIMARY_ACTIVATE_SECONDARY_CONTROLS != 0
+        && snapshot.get(control::SECONDARY_PROCBASED_EXEC_CONTROLS) & bit != 0
+}
+
+/// 27.2.1 Checks on VMX Controls.
+fn check_controls(snapshot: &VmcsSnapshot, checks: &mut Checks) {
+    let caps = &snapshot.capabilities;
+    let controls = [
+        ("Pin-based VM-execution controls", control::PINBASED_EXEC_CONTROLS, caps.pin_based),
+        ("Primary processor-based VM-execution controls", control::PRIMARY_PROCBASED_EXEC_CONTROLS, caps.primary_processor_based),
+        ("VM-exit controls", control::VMEXIT_CONTROLS, caps.exit),
+        ("VM-entry controls", control::VMENTRY_CONTROLS, caps.entry),
+    ];
+
+    for (name, encoding, capability) in controls {
+        let value = snapshot.get(encoding);
+        checks.expect(value & !(capability >> 32) == 0, name, "set bits the processor requires to be 0");
+        checks.expect((capability & 0xffff_ffff) & !value == 0, name, "clear bits the processor requires to be 1");
+    }
+
+    let primary = snapshot.get(control::PRIMARY_PROCBASED_EXEC_CONTROLS);
+    if primary & PRIMARY_ACTIVATE_SECONDARY_CONTROLS != 0 {
+        let secondary = snapshot.get(control::SECONDARY_PROCBASED_EXEC_CONTROLS);
+        checks.expect(
+            secondary & !(caps.secondary_processor_based >> 32) == 0,
+            "Secondary processor-based VM-execution controls",
+            "set bits the processor requires to be 0",
+        );
+        checks.expect(
+            secondary & SECONDARY_UNRESTRICTED_GUEST == 0 || secondary & SECONDARY_ENABLE_EPT != 0,
+            "Unrestricted guest",
+            "is set without enable EPT",
+        );
+    }
+
+    let entry = snapshot.get(control::VMENTRY_CONTROLS);
+    let exit = snapshot.get(control::VMEXIT_CONTROLS);
+    checks.expect(
+        entry & ENTRY_IA32E_MODE_GUEST == 0 || exit & EXIT_HOST_ADDRESS_SPACE_SIZE != 0,
+        "IA-32e mode guest",
+        "is set without the host address-space size VM-exit control",
+    );
+}
+
+/// 27.2.2 Checks on Host Control Registers, MSRs, and SSP and 27.2.3 Checks on Host Segment and
+/// Descriptor-Table Registers, for a host in 64-bit mode.
+fn check_host_state(snapshot: &VmcsSnapshot, checks: &mut Checks) {
+    let caps = &snapshot.capabilities;
+    let cr0 = snapshot.get(host::CR0);
+    let cr4 = snapshot.get(host::CR4);
+
+    checks.expect(caps.cr0_fixed0 & !cr0 == 0, "Host CR0", "clears bits IA32_VMX_CR0_FIXED0 requires");
+    checks.expect(cr0 & !caps.cr0_fixed1 == 0, "Host CR0", "sets bits IA32_VMX_CR0_FIXED1 forbids");
+    checks.expect(caps.cr4_fixed0 & !cr4 == 0, "Host CR4", "clears bits IA32_VMX_CR4_FIXED0 requires");
+    checks.expect(cr4 & !caps.cr4_fixed1 == 0, "Host CR4", "sets bits IA32_VMX_CR4_FIXED1 forbids");
+    checks.expect(fits_physical_address_width(snapshot, snapshot.get(host::CR3)), "Host CR3", "exceeds the physical-address width");
+    checks.expect(is_canonical(snapshot.get(host::IA32_SYSENTER_ESP)), "Host IA32_SYSENTER_ESP", "is not canonical");
+    checks.expect(is_canonical(snapshot.get(host::IA32_SYSENTER_EIP)), "Host IA32_SYSENTER_EIP", "is not canonical");
+
+    let selectors = [
+        ("Host ES selector", host::ES_SELECTOR),
+        ("Host CS selector", host::CS_SELECTOR),
+        ("Host SS selector", host::SS_SELECTOR),
+        ("Host DS selector", host::DS_SELECTOR),
+        ("Host FS selector", host::FS_SELECTOR),
+        ("Host GS selector", host::GS_SELECTOR),
+        ("Host TR selector", host::TR_SELECTOR),
+    ];
+    for (name, encoding) in selectors {
+        checks.expect(snapshot.get(encoding) & 0x7 == 0, name, "has RPL or TI set");
+    }
+    checks.expect(snapshot.get(host::CS_SELECTOR) != 0, "Host CS selector", "is null");
+    checks.expect(snapshot.get(host::TR_SELECTOR) != 0, "Host TR selector", "is null");
+
+    let bases = [
+        ("Host FS base", host::FS_BASE),
+        ("Host GS base", host::GS_BASE),
+        ("Host TR base", host::TR_BASE),
+        ("Host GDTR base", host::GDTR_BASE),
+        ("Host IDTR base", host::IDTR_BASE),
+    ];
+    for (name, encoding) in bases {
+        checks.expect(is_canonical(snapshot.get(encoding)), name, "is not canonical");
+    }
+
+    // 27.2.4 Checks Related to Address-Space Size, the hypervisor itself runs in 64-bit mode.
+    checks.expect(
+        snapshot.get(control::VMEXIT_CONTROLS) & EXIT_HOST_ADDRESS_SPACE_SIZE != 0,
+        "Host address-space size",
+        "is clear although the host runs in 64-bit mode",
+    );
+    checks.expect(cr4 & CR4_PAE != 0, "Host CR4.PAE", "is clear");
+    checks.expect(is_canonical(snapshot.get(host::RIP)), "Host RIP", "is not canonical");
+}
+
+/// The guest-state fields of one segment register.
+struct Segment {
+    name: &'static str,
+    selector: u64,
+    base: u64,
+    limit: u64,
+    access_rights: u32,
+}
+
+impl Segment {
+    /// Reads the fields from the snapshot.
+    fn read(snapshot: &VmcsSnapshot, name: &'static str, selector: u32, base: u32, limit: u32, access_rights: u32) -> Self {
+        Self {
+            name,
+            selector: snapshot.get(selector),
+            base: snapshot.get(base),
+            limit: snapshot.get(limit),
+            access_rights: snapshot.get(access_rights) as u32,
+        }
+    }
+
+    fn usable(&self) -> bool {
+        self.access_rights & AR_UNUSABLE == 0
+    }
+
+    fn segment_type(&self) -> u32 {
+        self.access_rights & 0xf
+    }
+
+    fn dpl(&self) -> u64 {
+        u64::from(self.access_rights >> 5) & 0x3
+    }
+
+    fn rpl(&self) -> u64 {
+        self.selector & 0x3
+    }
+
+    /// Checks the reserved bits and the granularity against the limit.
+    fn check_reserved_and_granularity(&self, checks: &mut Checks) {
+        checks.expect(self.access_rights & AR_RESERVED == 0, self.name, "access rights set reserved bits");
+        checks.expect(self.limit & 0xfff == 0xfff || self.access_rights & AR_G == 0, self.name, "G is set with limit bits 11:0 not all 1");
+        checks.expect(self.limit & 0xfff0_0000 == 0 || self.access_rights & AR_G != 0, self.name, "G is clear with limit bits 31:20 set");
+    }
+}
+
+/// 27.3.1 Checks on the Guest State Area.
+fn check_guest_state(snapshot: &VmcsSnapshot, checks: &mut Checks) {
+    let caps = &snapshot.capabilities;
+    let entry = snapshot.get(control::VMENTRY_CONTROLS);
+    let ia32e_guest = entry & ENTRY_IA32E_MODE_GUEST != 0;
+    let unrestricted = has_secondary_control(snapshot, SECONDARY_UNRESTRICTED_GUEST);
+
+    /* 27.3.1.1 Checks on Guest Control Registers, Debug Registers, and MSRs */
+    let cr0 = snapshot.get(guest::CR0);
+    let cr4 = snapshot.get(guest::CR4);
+    let cr0_fixed0 = if unrestricted {
+        caps.cr0_fixed0 & !(CR0_PE | CR0_PG)
+    } else {
+        caps.cr0_fixed0
+    };
+
+    checks.expect(cr0_fixed0 & !cr0 == 0, "Guest CR0", "clears bits IA32_VMX_CR0_FIXED0 requires");
+    checks.expect(cr0 & !caps.cr0_fixed1 == 0, "Guest CR0", "sets bits IA32_VMX_CR0_FIXED1 forbids");
+    checks.expect(cr0 & CR0_PG == 0 || cr0 & CR0_PE != 0, "Guest CR0.PG", "is set without CR0.PE");
+    checks.expect(caps.cr4_fixed0 & !cr4 == 0, "Guest CR4", "clears bits IA32_VMX_CR4_FIXED0 requires");
+    checks.expect(cr4 & !caps.cr4_fixed1 == 0, "Guest CR4", "sets bits IA32_VMX_CR4_FIXED1 forbids");
+
+    if entry & ENTRY_LOAD_DEBUG_CONTROLS != 0 {
+        checks.expect(snapshot.get(guest::DR7) >> 32 == 0, "Guest DR7", "sets bits 63:32");
+    }
+
+    if ia32e_guest {
+        checks.expect(cr0 & CR0_PG != 0, "Guest CR0.PG", "is clear for an IA-32e mode guest");
+        checks.expect(cr4 & CR4_PAE != 0, "Guest CR4.PAE", "is clear for an IA-32e mode guest");
+    } else {
+        checks.expect(cr4 & CR4_PCIDE == 0, "Guest CR4.PCIDE", "is set outside IA-32e mode");
+    }
+
+    checks.expect(fits_physical_address_width(snapshot, snapshot.get(guest::CR3)), "Guest CR3", "exceeds the physical-address width");
+    checks.expect(is_canonical(snapshot.get(guest::IA32_SYSENTER_ESP)), "Guest IA32_SYSENTER_ESP", "is not canonical");
+    checks.expect(is_canonical(snapshot.get(guest::IA32_SYSENTER_EIP)), "Guest IA32_SYSENTER_EIP", "is not canonical");
+
+    if entry & ENTRY_LOAD_IA32_PAT != 0 {
+        let pat = snapshot.get(guest::IA32_PAT_FULL);
+        let valid = pat.to_le_bytes().iter().all(|memory_type| matches!(memory_type, 0 | 1 | 4 | 5 | 6 | 7));
+        checks.expect(valid, "Guest IA32_PAT", "has an entry with a reserved memory type");
+    }
+
+    if entry & ENTRY_LOAD_IA32_EFER != 0 {
+        let efer = snapshot.get(guest::IA32_EFER_FULL);
+        checks.expect(efer & !EFER_DEFINED == 0, "Guest IA32_EFER", "sets reserved bits");
+        checks.expect((efer & EFER_LMA != 0) == ia32e_guest, "IA32_EFER.LMA", "mismatch with VM-entry controls");
+        checks.expect(cr0 & CR0_PG == 0 || (efer & EFER_LMA != 0) == (efer & EFER_LME != 0), "IA32_EFER.LMA", "!= IA32_EFER.LME with CR0.PG set");
+    }
+
+    /* 27.3.1.2 Checks on Guest Segment Registers */
+    let rflags = snapshot.get(guest::RFLAGS);
+    let virtual_8086 = rflags & RFLAGS_VM != 0;
+    let es = Segment::read(snapshot, "ES", guest::ES_SELECTOR, guest::ES_BASE, guest::ES_LIMIT, guest::ES_ACCESS_RIGHTS);
+    let cs = Segment::read(snapshot, "CS", guest::CS_SELECTOR, guest::CS_BASE, guest::CS_LIMIT, guest::CS_ACCESS_RIGHTS);
+    let ss = Segment::read(snapshot, "SS", guest::SS_SELECTOR, guest::SS_BASE, guest::SS_LIMIT, guest::SS_ACCESS_RIGHTS);
+    let ds = Segment::read(snapshot, "DS", guest::DS_SELECTOR, guest::DS_BASE, guest::DS_LIMIT, guest::DS_ACCESS_RIGHTS);
+    let fs = Segment::read(snapshot, "FS", guest::FS_SELECTOR, guest::FS_BASE, guest::FS_LIMIT, guest::FS_ACCESS_RIGHTS);
+    let gs = Segment::read(snapshot, "GS", guest::GS_SELECTOR, guest::GS_BASE, guest::GS_LIMIT, guest::GS_ACCESS_RIGHTS);
+    let ldtr = Segment::read(snapshot, "LDTR", guest::LDTR_SELECTOR, guest::LDTR_BASE, guest::LDTR_LIMIT, guest::LDTR_ACCESS_RIGHTS);
+    let tr = Segment::read(snapshot, "TR", guest::TR_SELECTOR, guest::TR_BASE, guest::TR_LIMIT, guest::TR_ACCESS_RIGHTS);
+
+    checks.expect(tr.selector & 0x4 == 0, "TR selector", "has TI set");
+    checks.expect(!ldtr.usable() || ldtr.selector & 0x4 == 0, "LDTR selector", "has TI set");
+    if !virtual_8086 && !unrestricted {
+        checks.expect(ss.rpl() == cs.rpl(), "SS.RPL", "!= CS.RPL");
+    }
+
+    checks.expect(is_canonical(tr.base), "TR base", "is not canonical");
+    checks.expect(is_canonical(fs.base), "FS base", "is not canonical");
+    checks.expect(is_canonical(gs.base), "GS base", "is not canonical");
+    checks.expect(!ldtr.usable() || is_canonical(ldtr.base), "LDTR base", "is not canonical");
+    checks.expect(cs.base >> 32 == 0, "CS base", "sets bits 63:32");
+    for segment in [&ss, &ds, &es] {
+        checks.expect(!segment.usable() || segment.base >> 32 == 0, segment.name, "base sets bits 63:32");
+    }
+
+    if virtual_8086 {
+        for segment in [&cs, &ss, &ds, &es, &fs, &gs] {
+            checks.expect(segment.base == segment.selector << 4, segment.name, "base is not the selector shifted by 4 in virtual-8086 mode");
+            checks.expect(segment.limit == 0xffff, segment.name, "limit is not 0xFFFF in virtual-8086 mode");
+            checks.expect(segment.access_rights == 0xf3, segment.name, "access rights are not 0xF3 in virtual-8086 mode");
+        }
+    } else {
+        let cs_type = cs.segment_type();
+        let cs_types_allowed = matches!(cs_type, 9 | 11 | 13 | 15) || (unrestricted && cs_type == 3);
+        checks.expect(cs_types_allowed, "CS", "type is not an accessed code segment");
+        checks.expect(cs.access_rights & AR_S != 0, "CS", "is a system segment");
+        checks.expect(cs.access_rights & AR_P != 0, "CS", "is not present");
+        match cs_type {
+            3 => checks.expect(cs.dpl() == 0, "CS.DPL", "is not 0 for a data segment"),
+            9 | 11 => checks.expect(cs.dpl() == ss.dpl(), "CS.DPL", "!= SS.DPL"),
+            13 | 15 => checks.expect(cs.dpl() <= ss.dpl(), "CS.DPL", "> SS.DPL for a conforming code segment"),
+            _ => {}
+        }
+        checks.expect(!(ia32e_guest && cs.access_rights & AR_L != 0 && cs.access_rights & AR_DB != 0), "CS", "has both L and D set");
+        cs.check_reserved_and_granularity(checks);
+
+        if ss.usable() {
+            checks.expect(matches!(ss.segment_type(), 3 | 7), "SS", "type is not a read/write data segment");
+            checks.expect(ss.access_rights & AR_S != 0, "SS", "is a system segment");
+            checks.expect(ss.access_rights & AR_P != 0, "SS", "is not present");
+            ss.check_reserved_and_granularity(checks);
+        }
+        if !unrestricted {
+            checks.expect(ss.dpl() == ss.rpl(), "SS.DPL", "!= SS.RPL");
+        }
+        if cs_type == 3 || cr0 & CR0_PE == 0 {
+            checks.expect(ss.dpl() == 0, "SS.DPL", "is not 0");
+        }
+
+        for segment in [&ds, &es, &fs, &gs] {
+            if !segment.usable() {
+                continue;
+            }
+
+            let segment_type = segment.segment_type();
+            checks.expect(segment_type & 0x1 != 0, segment.name, "type is not accessed");
+            checks.expect(segment_type & 0x8 == 0 || segment_type & 0x2 != 0, segment.name, "is an execute-only code segment");
+            checks.expect(segment.access_rights & AR_S != 0, segment.name, "is a system segment");
+            checks.expect(segment.access_rights & AR_P != 0, segment.name, "is not present");
+            if !unrestricted && segment_type <= 11 {
+                checks.expect(segment.dpl() >= segment.rpl(), segment.name, "DPL < RPL");
+            }
+            segment.check_reserved_and_granularity(checks);
+        }
+    }
+
+    let tr_type = tr.segment_type();
+    checks.expect(tr_type == 11 || (!ia32e_guest && tr_type == 3), "TR", "type is not a busy TSS");
+    checks.expect(tr.access_rights & AR_S == 0, "TR", "is not a system segment");
+    checks.expect(tr.access_rights & AR_P != 0, "TR", "is not present");
+    checks.expect(tr.usable(), "TR", "is unusable");
+    tr.check_reserved_and_granularity(checks);
+
+    if ldtr.usable() {
+        checks.expect(ldtr.segment_type() == 2, "LDTR", "type is not an LDT");
+        checks.expect(ldtr.access_rights & AR_S == 0, "LDTR", "is not a system segment");
+        checks.expect(ldtr.access_rights & AR_P != 0, "LDTR", "is not present");
+        ldtr.check_reserved_and_granularity(checks);
+    }
+
+    /* 27.3.1.3 Checks on Guest Descriptor-Table Registers */
+    checks.expect(is_canonical(snapshot.get(guest::GDTR_BASE)), "GDTR base", "is not canonical");
+    checks.expect(is_canonical(snapshot.get(guest::IDTR_BASE)), "IDTR base", "is not canonical");
+    checks.expect(snapshot.get(guest::GDTR_LIMIT) >> 16 == 0, "GDTR limit", "sets bits 31:16");
+    checks.expect(snapshot.get(guest::IDTR_LIMIT) >> 16 == 0, "IDTR limit", "sets bits 31:16");
+
+    /* 27.3.1.4 Checks on Guest RIP, RFLAGS, and SSP */
+    let rip = snapshot.get(guest::RIP);
+    if ia32e_guest && cs.access_rights & AR_L != 0 {
+        checks.expect(is_canonical(rip), "Guest RIP", "is not canonical");
+    } else {
+        checks.expect(rip >> 32 == 0, "Guest RIP", "sets bits 63:32 outside 64-bit mode");
+    }
+
+    checks.expect(rflags & RFLAGS_RESERVED == 0, "Guest RFLAGS", "sets reserved bits");
+    checks.expect(rflags & RFLAGS_FIXED1 != 0, "Guest RFLAGS", "clears bit 1");
+    if ia32e_guest || cr0 & CR0_PE == 0 {
+        checks.expect(!virtual_8086, "Guest RFLAGS.VM", "is set in IA-32e mode or real mode");
+    }
+
+    let interruption_info = snapshot.get(control::VMENTRY_INTERRUPTION_INFO_FIELD);
+    let injects_external_interrupt = interruption_info & (1 << 31) != 0 && (interruption_info >> 8) & 0x7 == 0;
+    checks.expect(!injects_external_interrupt || rflags & RFLAGS_IF != 0, "Guest RFLAGS.IF", "is clear while injecting an external interrupt");
+
+    /* 27.3.1.5 Checks on Guest Non-Register State */
+    let activity_state = snapshot.get(guest::ACTIVITY_STATE);
+    checks.expect(activity_state <= ACTIVITY_STATE_WAIT_FOR_SIPI, "Guest activity state", "is not in the range 0-3");
+    checks.expect(activity_state != ACTIVITY_STATE_HLT || ss.dpl() == 0, "Guest activity state", "is HLT with SS.DPL != 0");
+
+    let interruptibility = snapshot.get(guest::INTERRUPTIBILITY_STATE);
+    checks.expect(interruptibility >> 5 == 0, "Guest interruptibility state", "sets reserved bits 31:5");
+    checks.expect(
+        interruptibility & (INTERRUPTIBILITY_STI | INTERRUPTIBILITY_MOV_SS) != (INTERRUPTIBILITY_STI | INTERRUPTIBILITY_MOV_SS),
+        "Guest interruptibility state",
+        "blocks by both STI and MOV SS",
+    );
+    checks.expect(
+        interruptibility & INTERRUPTIBILITY_STI == 0 || rflags & RFLAGS_IF != 0,
+        "Guest interruptibility state",
+        "blocks by STI with RFLAGS.IF clear",
+    );
+
+    // Bits 63:17, 15 and 13:4 of the pending debug exceptions are reserved.
+    let pending_debug_exceptions = snapshot.get(guest::PENDING_DBG_EXCEPTIONS);
+    checks.expect(pending_debug_exceptions & (!0x1_ffff | 1 << 15 | 0x3ff0) == 0, "Guest pending debug exceptions", "sets reserved bits");
+
+    let link_pointer = snapshot.get(guest::LINK_PTR_FULL);
+    if link_pointer != u64::MAX {
+        checks.expect(link_pointer & 0xfff == 0, "Guest VMCS link pointer", "is not FFFFFFFF_FFFFFFFF and not page-aligned");
+        checks.expect(fits_physical_address_width(snapshot, link_pointer), "Guest VMCS link pointer", "exceeds the physical-address width");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Capabilities that allow every control and only require CR0.PE, CR0.NE, CR0.PG and CR4.VMXE.
+    fn capabilities() -> VmxCapabilities {
+        VmxCapabilities {
+            cr0_fixed0: 0x8000_0021,
+            cr0_fixed1: 0xffff_ffff,
+            cr4_fixed0: 0x2000,
+            cr4_fixed1: 0x003f_ffff,
+            pin_based: 0xffff_ffff_0000_0000,
+            primary_processor_based: 0xffff_ffff_0000_0000,
+            secondary_processor_based: 0xffff_ffff_0000_0000,
+            exit: 0xffff_ffff_0000_0000,
+            entry: 0xffff_ffff_0000_0000,
+            physical_address_width: 39,
+        }
+    }
+
+    /// The state the hypervisor enters a 64-bit guest with.
+    fn valid_snapshot() -> VmcsSnapshot {
+        let mut snapshot = VmcsSnapshot::new(capabilities());
+        let fields = [
+            (control::PRIMARY_PROCBASED_EXEC_CONTROLS, PRIMARY_ACTIVATE_SECONDARY_CONTROLS),
+            (control::SECONDARY_PROCBASED_EXEC_CONTROLS, SECONDARY_ENABLE_EPT),
+            (control::VMEXIT_CONTROLS, EXIT_HOST_ADDRESS_SPACE_SIZE),
+            (control::VMENTRY_CONTROLS, ENTRY_IA32E_MODE_GUEST | ENTRY_LOAD_DEBUG_CONTROLS),
+            (host::CR0, 0x8005_0033),
+            (host::CR3, 0x7f00_0000),
+            (host::CR4, 0x2020),
+            (host::CS_SELECTOR, 0x8),
+            (host::TR_SELECTOR, 0x10),
+            (host::RIP, 0x7e00_1000),
+            (guest::CR0, 0x8005_0033),
+            (guest::CR3, 0x1ad000),
+            (guest::CR4, 0x2020),
+            (guest::DR7, 0x400),
+            (guest::RIP, 0xffff_f800_1234_5000),
+            (guest::RFLAGS, 0x2),
+            (guest::CS_SELECTOR, 0x38),
+            (guest::CS_LIMIT, 0xffff_ffff),
+            (guest::CS_ACCESS_RIGHTS, 0xa09b),
+            (guest::SS_SELECTOR, 0x30),
+            (guest::SS_LIMIT, 0xffff_ffff),
+            (guest::SS_ACCESS_RIGHTS, 0xc093),
+            (guest::DS_SELECTOR, 0x30),
+            (guest::DS_LIMIT, 0xffff_ffff),
+            (guest::DS_ACCESS_RIGHTS, 0xc093),
+            (guest::ES_SELECTOR, 0x30),
+            (guest::ES_LIMIT, 0xffff_ffff),
+            (guest::ES_ACCESS_RIGHTS, 0xc093),
+            (guest::FS_ACCESS_RIGHTS, AR_UNUSABLE as u64),
+            (guest::GS_ACCESS_RIGHTS, AR_UNUSABLE as u64),
+            (guest::LDTR_ACCESS_RIGHTS, AR_UNUSABLE as u64),
+            (guest::TR_SELECTOR, 0x40),
+            (guest::TR_BASE, 0x7d00_0000),
+            (guest::TR_LIMIT, 0x67),
+            (guest::TR_ACCESS_RIGHTS, 0x8b),
+            (guest::GDTR_BASE, 0x7d00_1000),
+            (guest::GDTR_LIMIT, 0x57),
+            (guest::IDTR_BASE, 0x7d00_2000),
+            (guest::IDTR_LIMIT, 0xfff),
+            (guest::LINK_PTR_FULL, u64::MAX),
+        ];
+        for (encoding, value) in fields {
+            snapshot.set(encoding, value);
+        }
+        snapshot
+    }
+
+    /// Returns whether `snapshot` fails the check of `field` with `problem`.
+    fn fails(snapshot: &VmcsSnapshot, field: &str, problem: &str) -> bool {
+        check(snapshot).iter().any(|failure| failure.field == field && failure.problem == problem)
+    }
+
+    #[test]
+    fn valid_state_passes() {
+        assert_eq!(check(&valid_snapshot()), Vec::new());
+    }
+
+    #[test]
+    fn unread_fields_read_as_zero() {
+        let snapshot = VmcsSnapshot::new(capabilities());
+        assert_eq!(snapshot.get(guest::CR0), 0);
+        assert!(snapshot.fields().all(|(_, _, value)| value.is_none()));
+        assert!(fails(&snapshot, "Host CS selector", "is null"));
+    }
+
+    #[test]
+    fn code_segment_dpl_must_match_stack_segment() {
+        let mut snapshot = valid_snapshot();
+        snapshot.set(guest::SS_SELECTOR, 0x33);
+        snapshot.set(guest::SS_ACCESS_RIGHTS, 0xc0f3);
+        assert!(fails(&snapshot, "CS.DPL", "!= SS.DPL"));
+        assert!(fails(&snapshot, "SS.RPL", "!= CS.RPL"));
+    }
+
+    #[test]
+    fn efer_lma_must_match_the_entry_controls() {
+        let mut snapshot = valid_snapshot();
+        snapshot.set(control::VMENTRY_CONTROLS, ENTRY_IA32E_MODE_GUEST | ENTRY_LOAD_IA32_EFER);
+        snapshot.set(guest::IA32_EFER_FULL, EFER_LME);
+        assert!(fails(&snapshot, "IA32_EFER.LMA", "mismatch with VM-entry controls"));
+
+        snapshot.set(guest::IA32_EFER_FULL, EFER_LME | EFER_LMA);
+        assert_eq!(check(&snapshot), Vec::new());
+    }
+
+    #[test]
+    fn task_register_must_be_a_busy_tss() {
+        let mut snapshot = valid_snapshot();
+        snapshot.set(guest::TR_ACCESS_RIGHTS, 0x89);
+        assert_eq!(
+            check(&snapshot),
+            [FailedCheck {
+                field: "TR",
+                problem: "type is not a busy TSS"
+            }]
+        );
+    }
+
+    #[test]
+    fn granularity_must_match_the_limit() {
+        let mut snapshot = valid_snapshot();
+        snapshot.set(guest::DS_ACCESS_RIGHTS, 0x4093);
+        assert!(fails(&snapshot, "DS", "G is clear with limit bits 31:20 set"));
+    }
+
+    #[test]
+    fn controls_must_respect_the_capabilities() {
+        let mut snapshot = valid_snapshot();
+        snapshot.capabilities.pin_based = 0x0000_0016_0000_0016;
+        assert!(fails(&snapshot, "Pin-based VM-execution controls", "clear bits the processor requires to be 1"));
+
+        snapshot.set(control::PINBASED_EXEC_CONTROLS, 0x17);
+        assert!(fails(&snapshot, "Pin-based VM-execution controls", "set bits the processor requires to be 0"));
+    }
+
+    #[test]
+    fn unrestricted_guest_relaxes_cr0_but_needs_ept() {
+        let mut snapshot = valid_snapshot();
+        snapshot.set(guest::CR0, 0x8005_0032);
+        assert!(fails(&snapshot, "Guest CR0", "clears bits IA32_VMX_CR0_FIXED0 requires"));
+        assert!(fails(&snapshot, "Guest CR0.PG", "is set without CR0.PE"));
+
+        snapshot.set(control::SECONDARY_PROCBASED_EXEC_CONTROLS, SECONDARY_UNRESTRICTED_GUEST);
+        assert!(!fails(&snapshot, "Guest CR0", "clears bits IA32_VMX_CR0_FIXED0 requires"));
+        assert!(fails(&snapshot, "Unrestricted guest", "is set without enable EPT"));
+    }
+
+    #[test]
+    fn canonical_addresses() {
+        assert!(is_canonical(0x0000_7fff_ffff_ffff));
+        assert!(is_canonical(0xffff_8000_0000_0000));
+        assert!(!is_canonical(0x0000_8000_0000_0000));
+    }
+}
@@ -0,0 +1,70 @@
+//! Explains a failed VM entry from the VMCS that is still current.
+//!
+//! Captures a `VmcsSnapshot` of the fields listed in `intel::entry_checks`, logs every one of them and
+//! then the checks the snapshot fails. Only called once the entry failed, so the log level is `error`
+//! throughout.
+
+use {
+    crate::intel::{
+        controls::{capability_msr, VmxControl},
+        entry_checks::{self, VmcsSnapshot, VmxCapabilities, FIELDS},
+        support::rdmsr,
+    },
+    core::fmt,
+    log::*,
+    x86::{cpuid::CpuId, msr},
+};
+
+/// Logs the VMCS and the checks it fails.
+///
+/// # Arguments
+///
+/// * `cause` - How the entry failed, e.g. the VM-instruction error.
+pub fn report(cause: impl fmt::Debug) {
+    error!("VM entry failed: {:?}", cause);
+
+    let snapshot = capture();
+    for (name, _, value) in snapshot.fields() {
+        match value {
+            Some(value) => error!("  {:<50} {:#018x}", name, value),
+            None => error!("  {:<50} (not supported)", name),
+        }
+    }
+
+    let failures = entry_checks::check(&snapshot);
+    if failures.is_empty() {
+        error!("The VMCS passes every check repeated in software, the processor rejected something not checked");
+    }
+    for failure in failures {
+        error!("Failed VM-entry check: {}", failure);
+    }
+}
+
+/// Reads the fields of `FIELDS` from the current VMCS, fields the processor doesn't support are left out.
+pub fn capture() -> VmcsSnapshot {
+    let mut snapshot = VmcsSnapshot::new(capabilities());
+    for &(_, encoding) in FIELDS {
+        if let Ok(value) = unsafe { x86::bits64::vmx::vmread(encoding) } {
+            snapshot.set(encoding, value);
+        }
+    }
+    snapshot
+}
+
+/// Reads the capability MSRs of the current processor.
+fn capabilities() -> VmxCapabilities {
+    VmxCapabilities {
+        cr0_fixed0: rdmsr(msr::IA32_VMX_CR0_FIXED0),
+        cr0_fixed1: rdmsr(msr::IA32_VMX_CR0_FIXED1),
+        cr4_fixed0: rdmsr(msr::IA32_VMX_CR4_FIXED0),
+        cr4_fixed1: rdmsr(msr::IA32_VMX_CR4_FIXED1),
+        pin_based: rdmsr(capability_msr(VmxControl::PinBased)),
+        primary_processor_based: rdmsr(capability_msr(VmxControl::ProcessorBased)),
+        secondary_processor_based: rdmsr(capability_msr(VmxControl::ProcessorBased2)),
+        exit: rdmsr(capability_msr(VmxControl::VmExit)),
+        entry: rdmsr(capability_msr(VmxControl::VmEntry)),
+        physical_address_width: CpuId::new()
+            .get_processor_capacity_feature_info()
+            .map_or(36, |info| info.physical_address_bits()),
+    }
+}
@@ -7,17 +7,26 @@
 
 use {
     crate::{
+        allocator::box_zeroed,
         error::HypervisorError,
         intel::{
-            invept::invept_all_contexts,
-            invvpid::invvpid_all_contexts,
+            memory_map::{PhysicalMemoryMap, PHYSICAL_MEMORY_MAP},
             mtrr::{MemoryType, Mtrr},
+            shootdown::{self, Scope},
+            support::rdmsr,
         },
     },
+    alloc::boxed::Box,
     bitfield::bitfield,
-    core::ptr::addr_of,
+    core::{
+        ptr::addr_of,
+        sync::atomic::{AtomicU64, Ordering},
+    },
     log::*,
-    x86::bits64::paging::{pd_index, pdpt_index, pml4_index, pt_index, VAddr, BASE_PAGE_SHIFT, BASE_PAGE_SIZE, HUGE_PAGE_SIZE, LARGE_PAGE_SIZE},
+    x86::{
+        bits64::paging::{pd_index, pdpt_index, pml4_index, pt_index, VAddr, BASE_PAGE_SHIFT, BASE_PAGE_SIZE, HUGE_PAGE_SIZE, LARGE_PAGE_SIZE},
+        msr::IA32_VMX_EPT_VPID_CAP,
+    },
 };
 
 /// Represents the entire Extended Page Table structure.
@@ -25,6 +34,11 @@ use {
 /// EPT is a set of nested page tables similar to the standard x86-64 paging mechanism.
 /// It consists of 4 levels: PML4, PDPT, PD, and PT.
 ///
+/// Every processor builds its own EPT in its `Vm` and is the only one that changes it, in VMX root operation,
+/// while the processor itself can't translate through it. Changes therefore only flush the caches of the
+/// current processor, and tables that are unlinked are freed right away. Hooks are applied to the EPT of every
+/// processor by `hook_sync`.
+///
 /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 29.3.2 EPT Translation Mechanism
 #[repr(C, align(4096))]
 pub struct Ept {
@@ -34,38 +48,69 @@ pub struct Ept {
     pdpt: Pdpt,
     /// Array of Page Directory Table (PDT).
     pd: [Pd; 512],
-    /// Page Table (PT).
-    pt: Pt,
+    /// Page Tables (PT) for the large pages with more than one memory type, the first one usually maps the low 2MB.
+    pt: [Pt; MTRR_PAGE_TABLES],
+    /// Page Directory Pointer Tables (PDPT) mapping physical memory above 512GB with 1GB pages.
+    high_pdpt: [Pdpt; HIGH_PDPTS],
 }
 
+/// The number of large pages `Ept::build_identity` can split where an MTRR boundary falls inside them.
+pub const MTRR_PAGE_TABLES: usize = 8;
+
+/// The number of 512GB windows above the first one the EPT can map, anywhere in the physical address space.
+pub const HIGH_PDPTS: usize = 4;
+
+/// The end of the guest-physical addresses the first PML4 entry maps with 2MB pages.
+pub const LOW_EPT_END: u64 = 512 * HUGE_PAGE_SIZE as u64;
+
+/// A PDE that is writable but not readable, which the processor reports as an EPT misconfiguration.
+/// `build_identity` puts it where the memory map has nothing, so the first access can be logged and mapped.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 29.3.3.1 EPT Misconfigurations
+const MISCONFIGURATION_TRAP: Entry = Entry(0b010);
+
+/// An entry that maps nothing, with #VE suppressed so only the views of hooked pages are convertible.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.5.7.1 Convertible EPT Violations
+const NOT_PRESENT: Entry = Entry(1 << 63);
+
+/// [Bit 17] IA32_VMX_EPT_VPID_CAP: EPT PDPTEs can map 1-GByte pages.
+const EPT_1GB_PAGES: u64 = 1 << 17;
+
 impl Ept {
     /// Initializes the Extended Page Table (EPT) structure.
     pub fn init(&mut self) {
-        self.pml4 = Pml4(Table { entries: [Entry(0); 512] });
-        self.pdpt = Pdpt(Table { entries: [Entry(0); 512] });
-        self.pd = [Pd(Table { entries: [Entry(0); 512] }); 512];
-        self.pt = Pt(Table { entries: [Entry(0); 512] });
+        self.pml4 = Pml4(Table { entries: [NOT_PRESENT; 512] });
+        self.pdpt = Pdpt(Table { entries: [NOT_PRESENT; 512] });
+        self.pd = [Pd(Table { entries: [NOT_PRESENT; 512] }); 512];
+        self.pt = [Pt(Table { entries: [NOT_PRESENT; 512] }); MTRR_PAGE_TABLES];
+        self.high_pdpt = [Pdpt(Table { entries: [NOT_PRESENT; 512] }); HIGH_PDPTS];
     }
 
     /// Builds an identity-mapped Extended Page Table (EPT) structure with considerations for Memory Type Range Registers (MTRR).
     /// This function initializes the EPT with a 1:1 physical-to-virtual memory mapping,
     /// setting up the required PML4, PDPT, and PD entries for the initial memory range.
     ///
+    /// Every 2MB page gets the memory type the MTRRs assign to it. Large pages an MTRR boundary falls inside,
+    /// like the low 2MB with the fixed ranges, are mapped with 4KB pages of their own type instead.
+    ///
+    /// Only the pages `PHYSICAL_MEMORY_MAP` touches are mapped, the others get `MISCONFIGURATION_TRAP`. Ranges
+    /// above 512GB are mapped with 1GB pages where the processor supports them.
+    ///
     /// # Returns
     /// A result indicating the success or failure of the operation. In case of failure,
     /// a `HypervisorError` is returned, detailing the nature of the error.
-    ///
-    /// # Errors
-    /// This function returns an `Err(HypervisorError::MemoryTypeResolutionError)` if it fails
-    /// to resolve memory types based on MTRR settings for any page.
     pub fn build_identity(&mut self) -> Result<(), HypervisorError> {
         // Initialize a new MTRR instance for memory type resolution.
-        let mut mtrr = Mtrr::new();
+        let mtrr = Mtrr::new();
         trace!("{mtrr:#x?}");
+        let memory_map = PHYSICAL_MEMORY_MAP.read();
+        trace!("{memory_map:#x?}");
         trace!("Initializing EPTs");
 
         // Start with a physical address (pa) of 0.
         let mut pa = 0u64;
+        let mut free_page_tables = self.pt.iter_mut();
 
         // Configure the first PML4 entry to point to the PDPT. This sets up the root of our page table.
         self.pml4.0.entries[0].set_readable(true);
@@ -80,47 +125,177 @@ impl Ept {
             pdpte.set_executable(true);
             pdpte.set_pfn(addr_of!(self.pd[i]) as u64 >> BASE_PAGE_SHIFT);
 
-            // Configure each PDE within a PD. The first PD manages the first 2MB with 4KB granularity.
             for pde in &mut self.pd[i].0.entries {
-                if pa == 0 {
-                    // Handle the special case for the first 2MB to ensure MTRR types are correctly applied.
-                    pde.set_readable(true);
-                    pde.set_writable(true);
-                    pde.set_executable(true);
-                    pde.set_pfn(addr_of!(self.pt) as u64 >> BASE_PAGE_SHIFT);
-
-                    // Configure the PT entries for the first 2MB, respecting MTRR settings.
-                    for pte in &mut self.pt.0.entries {
-                        let memory_type = mtrr
-                            .find(pa..pa + BASE_PAGE_SIZE as u64)
-                            .ok_or(HypervisorError::MemoryTypeResolutionError)?;
+                if !memory_map.contains(pa) {
+                    // Nothing exists here, trap the first access instead of handing out unbacked memory.
+                    *pde = MISCONFIGURATION_TRAP;
+                    pa += LARGE_PAGE_SIZE as u64;
+                    continue;
+                }
+
+                pde.set_readable(true);
+                pde.set_writable(true);
+                pde.set_executable(true);
+
+                if let Some(memory_type) = mtrr.page_type(pa, LARGE_PAGE_SIZE as u64) {
+                    // The whole 2MB has one memory type, map it with a large page.
+                    pde.set_memory_type(memory_type as u64);
+                    pde.set_large(true);
+                    pde.set_pfn(pa >> BASE_PAGE_SHIFT);
+                } else if let Some(pt) = free_page_tables.next() {
+                    // An MTRR boundary falls inside the 2MB, map every 4KB page with its own memory type.
+                    trace!("Splitting the 2MB page at {pa:#x} along the MTRRs");
+                    pde.set_pfn(addr_of!(*pt) as u64 >> BASE_PAGE_SHIFT);
+
+                    for (j, pte) in pt.0.entries.iter_mut().enumerate() {
+                        let page_pa = pa + (j * BASE_PAGE_SIZE) as u64;
                         pte.set_readable(true);
                         pte.set_writable(true);
                         pte.set_executable(true);
-                        pte.set_memory_type(memory_type as u64);
-                        pte.set_pfn(pa >> BASE_PAGE_SHIFT);
-                        pa += BASE_PAGE_SIZE as u64;
+                        pte.set_memory_type(mtrr.memory_type(page_pa) as u64);
+                        pte.set_pfn(page_pa >> BASE_PAGE_SHIFT);
                     }
                 } else {
-                    // For the rest of the physical address space, configure PD entries for large pages (2MB).
-                    let memory_type = mtrr
-                        .find(pa..pa + LARGE_PAGE_SIZE as u64)
-                        .ok_or(HypervisorError::MemoryTypeResolutionError)?;
-
-                    pde.set_readable(true);
-                    pde.set_writable(true);
-                    pde.set_executable(true);
-                    pde.set_memory_type(memory_type as u64);
+                    // UC is the only type that is safe for every part of the page.
+                    warn!("No page table left to split the 2MB page at {pa:#x} along the MTRRs, mapping it uncacheable");
+                    pde.set_memory_type(MemoryType::Uncacheable as u64);
                     pde.set_large(true);
                     pde.set_pfn(pa >> BASE_PAGE_SHIFT);
-                    pa += LARGE_PAGE_SIZE as u64;
                 }
+
+                pa += LARGE_PAGE_SIZE as u64;
             }
         }
 
+        self.map_high_ranges(&memory_map, &mtrr);
+
         Ok(())
     }
 
+    /// Maps the ranges of the memory map above 512GB with 1GB pages.
+    ///
+    /// # Arguments
+    ///
+    /// * `memory_map` - The physical memory map of this boot.
+    /// * `mtrr` - The MTRRs the memory types come from.
+    fn map_high_ranges(&mut self, memory_map: &PhysicalMemoryMap, mtrr: &Mtrr) {
+        for range in memory_map.ranges().filter(|range| range.end > LOW_EPT_END) {
+            let mut pa = range.start.max(LOW_EPT_END) & !(HUGE_PAGE_SIZE as u64 - 1);
+
+            while pa < range.end {
+                if let Err(e) = self.map_huge_page(pa, mtrr) {
+                    warn!("Leaving {:#x?} to be mapped on demand: {:?}", pa..range.end, e);
+                    break;
+                }
+                pa += HUGE_PAGE_SIZE as u64;
+            }
+        }
+    }
+
+    /// Identity maps the 1GB page at `pa` above 512GB, giving its 512GB window one of the high PDPTs first.
+    ///
+    /// # Arguments
+    ///
+    /// * `pa` - The physical address of the page, 1GB aligned.
+    /// * `mtrr` - The MTRRs the memory type comes from, UC if the page has more than one.
+    fn map_huge_page(&mut self, pa: u64, mtrr: &Mtrr) -> Result<(), HypervisorError> {
+        if rdmsr(IA32_VMX_EPT_VPID_CAP) & EPT_1GB_PAGES == 0 {
+            return Err(HypervisorError::EptHugePagesUnsupported);
+        }
+
+        let pml4e = &mut self.pml4.0.entries[pml4_index(VAddr::from(pa))];
+        let pdpt = if pml4e.readable() {
+            self.high_pdpt
+                .iter_mut()
+                .find(|pdpt| addr_of!(**pdpt) as u64 >> BASE_PAGE_SHIFT == pml4e.pfn())
+        } else {
+            // A PDPT maps at least one page as soon as it is given a window, so an empty one is free.
+            self.high_pdpt
+                .iter_mut()
+                .find(|pdpt| pdpt.0.entries.iter().all(|entry| entry.0 == NOT_PRESENT.0))
+        }
+        .ok_or(HypervisorError::PageTablesUnavailable)?;
+
+        if !pml4e.readable() {
+            trace!("Mapping the 512GB window at {:#x} with a high PDPT", pa & !(LOW_EPT_END - 1));
+            pml4e.set_readable(true);
+            pml4e.set_writable(true);
+            pml4e.set_executable(true);
+            pml4e.set_pfn(addr_of!(*pdpt) as u64 >> BASE_PAGE_SHIFT);
+        }
+
+        let memory_type = mtrr.page_type(pa, HUGE_PAGE_SIZE as u64).unwrap_or(MemoryType::Uncacheable);
+        let pdpte = &mut pdpt.0.entries[pdpt_index(VAddr::from(pa))];
+        *pdpte = NOT_PRESENT;
+        pdpte.set_readable(true);
+        pdpte.set_writable(true);
+        pdpte.set_executable(true);
+        pdpte.set_memory_type(memory_type as u64);
+        pdpte.set_large(true);
+        pdpte.set_pfn(pa >> BASE_PAGE_SHIFT);
+
+        Ok(())
+    }
+
+    /// Checks if a guest physical address lies in a 2MB page `build_identity` left out of the memory map.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address to check.
+    pub fn is_misconfiguration_trap(&self, guest_pa: u64) -> bool {
+        let guest_pa = VAddr::from(guest_pa);
+        guest_pa.as_u64() < LOW_EPT_END && self.pd[pdpt_index(guest_pa)].0.entries[pd_index(guest_pa)].0 == MISCONFIGURATION_TRAP.0
+    }
+
+    /// Maps the page of a guest physical address the identity map left out, after the guest accessed it anyway.
+    ///
+    /// This handles memory hot-plugged MMIO and firmware quirks place outside the memory map. Below 512GB the
+    /// 2MB page is mapped, above it the 1GB page.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address the guest accessed.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(true)` if the page was mapped, `Ok(false)` if it was mapped already and the exit has another cause.
+    pub fn map_on_demand(&mut self, guest_pa: u64) -> Result<bool, HypervisorError> {
+        let mtrr = Mtrr::new();
+        let guest_pa = VAddr::from(guest_pa);
+
+        if guest_pa.as_u64() >= LOW_EPT_END {
+            let pml4e = &self.pml4.0.entries[pml4_index(guest_pa)];
+            let mapped = pml4e.readable()
+                && self
+                    .high_pdpt
+                    .iter()
+                    .any(|pdpt| addr_of!(*pdpt) as u64 >> BASE_PAGE_SHIFT == pml4e.pfn() && pdpt.0.entries[pdpt_index(guest_pa)].readable());
+            if mapped {
+                return Ok(false);
+            }
+
+            self.map_huge_page(guest_pa.align_down_to_huge_page().as_u64(), &mtrr)?;
+            return Ok(true);
+        }
+
+        let pde = &mut self.pd[pdpt_index(guest_pa)].0.entries[pd_index(guest_pa)];
+        if pde.0 != MISCONFIGURATION_TRAP.0 && pde.0 != NOT_PRESENT.0 {
+            return Ok(false);
+        }
+
+        let large_page_pa = guest_pa.align_down_to_large_page().as_u64();
+        let memory_type = mtrr.page_type(large_page_pa, LARGE_PAGE_SIZE as u64).unwrap_or(MemoryType::Uncacheable);
+        *pde = NOT_PRESENT;
+        pde.set_readable(true);
+        pde.set_writable(true);
+        pde.set_executable(true);
+        pde.set_memory_type(memory_type as u64);
+        pde.set_large(true);
+        pde.set_pfn(large_page_pa >> BASE_PAGE_SHIFT);
+
+        Ok(true)
+    }
+
     /// Translates a guest physical address to a host physical address using the EPT.
     ///
     /// This function traverses the EPT hierarchy (PML4, PDPT, PD, PT) to translate the given
@@ -157,7 +332,7 @@ impl Ept {
 
         // Check if the PML4 entry is present (readable).
         if !pml4_entry.readable() {
-            error!("PML4 entry is not present: {:#x}", guest_pa);
+            trace!("PML4 entry is not present: {:#x}", guest_pa);
             return Err(HypervisorError::InvalidPml4Entry);
         }
 
@@ -170,7 +345,7 @@ impl Ept {
 
         // Check if the PDPT entry is present (readable).
         if !pdpt_entry.readable() {
-            error!("PDPT entry is not present: {:#x}", guest_pa);
+            trace!("PDPT entry is not present: {:#x}", guest_pa);
             return Err(HypervisorError::InvalidPdptEntry);
         }
 
@@ -189,7 +364,7 @@ impl Ept {
 
         // Check if the PD entry is present (readable).
         if !pd_entry.readable() {
-            error!("PD entry is not present: {:#x}", guest_pa);
+            trace!("PD entry is not present: {:#x}", guest_pa);
             return Err(HypervisorError::InvalidPdEntry);
         }
 
@@ -247,62 +422,214 @@ impl Ept {
     /// This is necessary to apply more granular hooks and reduce the number of
     /// page faults that occur when the guest tries to access a page that is hooked.
     ///
+    /// The new page table maps the same memory with the permissions and the memory type of the large page.
+    ///
     /// # Arguments
     ///
     /// * `guest_pa`: The guest physical address within the 2MB page that needs to be split.
-    /// * `pt`: The page table to use for the split operation.
     ///
     /// # Returns
     ///
     /// A `Result<(), HypervisorError>` indicating if the operation was successful.
-    pub fn split_2mb_to_4kb(&mut self, guest_pa: u64, pt: &mut Pt) -> Result<(), HypervisorError> {
+    pub fn split_2mb(&mut self, guest_pa: u64) -> Result<(), HypervisorError> {
         trace!("Splitting 2mb page into 4kb pages: {:#x}", guest_pa);
 
         let guest_pa = VAddr::from(guest_pa);
-
-        let pdpt_index = pdpt_index(guest_pa);
-        let pd_index = pd_index(guest_pa);
-        let pde = &mut self.pd[pdpt_index].0.entries[pd_index];
+        let pde = &mut self.pd[pdpt_index(guest_pa)].0.entries[pd_index(guest_pa)];
 
         // We can only split large pages and not page directories.
         // If it's a page directory, it is already split.
-        //
         if !pde.large() {
             trace!("Page is already split: {:x}.", guest_pa);
             return Err(HypervisorError::PageAlreadySplit);
         }
 
-        // Get the memory type of the large page, before we unmap (reset) it.
-        let memory_type = pde.memory_type();
+        let large_page = *pde;
+        let mut pt = unsafe { box_zeroed::<Pt>() };
+
+        // Map the physical memory of the large page with 4KB pages of the same attributes.
+        for (i, pte) in pt.0.entries.iter_mut().enumerate() {
+            pte.set_readable(large_page.readable());
+            pte.set_writable(large_page.writable());
+            pte.set_executable(large_page.executable());
+            pte.set_memory_type(large_page.memory_type());
+            pte.set_pfn(large_page.pfn() + i as u64);
+            pte.set_suppress_ve(large_page.suppress_ve());
+        }
+
+        store_entry(pde, Entry::table(Box::into_raw(pt) as u64));
+
+        // Cached translations of the large page must not be combined with the 4KB ones.
+        shootdown::flush_ept(Scope::CurrentProcessor);
+
+        Ok(())
+    }
+
+    /// Maps a split 2MB page with a large page again, once the last hook in it is removed.
+    ///
+    /// The page table is freed once it is unlinked, the EPT views of the processor referencing it must be synced
+    /// before the next VM entry. Only tables `split_2mb` created are merged, and only if their 4KB pages map
+    /// contiguous memory with the same permissions and memory type again.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - A guest physical address within the 2MB page.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the page is a large page afterwards, `HypervisorError::LargePageMergeError` if the 4KB pages differ.
+    pub fn merge_back(&mut self, guest_pa: u64) -> Result<(), HypervisorError> {
+        trace!("Merging 4kb pages back into a 2mb page: {:#x}", guest_pa);
+
+        let guest_pa = VAddr::from(guest_pa);
+        let table_pa = {
+            let pde = &self.pd[pdpt_index(guest_pa)].0.entries[pd_index(guest_pa)];
+            if pde.large() {
+                return Ok(());
+            }
+            pde.pfn() << BASE_PAGE_SHIFT
+        };
+
+        // The tables of the MTRR splits live in the EPT and map more than one memory type.
+        if self.pt.iter().any(|pt| addr_of!(*pt) as u64 == table_pa) {
+            return Err(HypervisorError::LargePageMergeError);
+        }
+
+        let first = unsafe { (*(table_pa as *const Pt)).0.entries[0] };
+        let mergeable = first.pfn() % (LARGE_PAGE_SIZE >> BASE_PAGE_SHIFT) as u64 == 0
+            && unsafe { &*(table_pa as *const Pt) }.0.entries.iter().enumerate().all(|(i, pte)| {
+                pte.pfn() == first.pfn() + i as u64
+                    && pte.readable() == first.readable()
+                    && pte.writable() == first.writable()
+                    && pte.executable() == first.executable()
+                    && pte.memory_type() == first.memory_type()
+                    && pte.suppress_ve() == first.suppress_ve()
+            });
+        if !mergeable {
+            trace!("The 4kb pages of {:x} differ, keeping the page table", guest_pa);
+            return Err(HypervisorError::LargePageMergeError);
+        }
 
-        // Zero out the PD entry to ensure it's clean.
-        *pde = Entry(0);
+        let mut large_page = Entry(0);
+        large_page.set_readable(first.readable());
+        large_page.set_writable(first.writable());
+        large_page.set_executable(first.executable());
+        large_page.set_memory_type(first.memory_type());
+        large_page.set_large(true);
+        large_page.set_pfn(first.pfn());
+        large_page.set_suppress_ve(first.suppress_ve());
+        store_entry(&mut self.pd[pdpt_index(guest_pa)].0.entries[pd_index(guest_pa)], large_page);
 
-        trace!("Dumping EPT entries while splitting......");
+        shootdown::flush_ept(Scope::CurrentProcessor);
+        drop(unsafe { Box::from_raw(table_pa as *mut Pt) });
 
-        // Map the unmapped physical memory to 4KB pages.
-        for (i, pte) in &mut pt.0.entries.iter_mut().enumerate() {
-            // Zero out the PT entry to ensure it's clean.
-            *pte = Entry(0);
+        Ok(())
+    }
 
-            let pa = (guest_pa.as_usize() + i * BASE_PAGE_SIZE) as u64;
-            pte.set_readable(true);
-            pte.set_writable(true);
-            pte.set_executable(true);
-            pte.set_memory_type(memory_type);
-            pte.set_pfn(pa >> BASE_PAGE_SHIFT);
+    /// Returns the page table mapping the 4KB pages of a split 2MB page.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - A guest physical address within the 2MB page.
+    ///
+    /// # Returns
+    ///
+    /// `HypervisorError::PageTableNotFound` if the page is still a large page.
+    fn page_table_mut(&mut self, guest_pa: VAddr) -> Result<&mut Pt, HypervisorError> {
+        let pde = &self.pd[pdpt_index(guest_pa)].0.entries[pd_index(guest_pa)];
+        if pde.large() || !pde.readable() {
+            return Err(HypervisorError::PageTableNotFound);
+        }
+
+        // The host identity maps all physical memory, the page table is either one of the MTRR splits or a `split_2mb` one.
+        Ok(unsafe { &mut *((pde.pfn() << BASE_PAGE_SHIFT) as *mut Pt) })
+    }
 
-            // trace!("PTE at index {}: {:#x?}", i, pte);
+    /// Returns the entries of the PML4, the views of `ept_views` share the PDPTs above `LOW_EPT_END`.
+    pub fn pml4_entries(&self) -> &[Entry; 512] {
+        &self.pml4.0.entries
+    }
+
+    /// Returns the entries of the PDPT mapping the guest physical addresses below `LOW_EPT_END`.
+    pub fn pdpt_entries(&self) -> &[Entry; 512] {
+        &self.pdpt.0.entries
+    }
+
+    /// Returns the entries of the page directory mapping the 1GB page of a guest physical address below `LOW_EPT_END`.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - A guest physical address within the 1GB page.
+    pub fn pd_entries(&self, guest_pa: u64) -> &[Entry; 512] {
+        &self.pd[pdpt_index(VAddr::from(guest_pa))].0.entries
+    }
+
+    /// Returns the entries of the page table mapping the 4KB pages of a split 2MB page.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - A guest physical address within the 2MB page.
+    ///
+    /// # Returns
+    ///
+    /// `HypervisorError::PageTableNotFound` if the page is still a large page.
+    pub fn page_table_entries(&self, guest_pa: u64) -> Result<&[Entry; 512], HypervisorError> {
+        let guest_pa = VAddr::from(guest_pa);
+        let pde = &self.pd[pdpt_index(guest_pa)].0.entries[pd_index(guest_pa)];
+        if pde.large() || !pde.readable() {
+            return Err(HypervisorError::PageTableNotFound);
         }
 
-        // Update the PDE to point to the new page table.
-        pde.set_readable(true);
-        pde.set_writable(true);
-        pde.set_executable(true);
-        pde.set_memory_type(0); // Table 29-6. Format of an EPT Page-Directory Entry (PDE) that References an EPT Page Table: 6:3 Reserved (must be 0)
-        pde.set_large(false); // This is no longer a large page.
-        pde.set_pfn((pt as *mut _ as u64) >> BASE_PAGE_SHIFT);
+        Ok(unsafe { &(*((pde.pfn() << BASE_PAGE_SHIFT) as *const Pt)).0.entries })
+    }
 
+    /// Builds the two EPT entries a hooked 4KB page is toggled between.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address of the hooked page, within a split 2MB page.
+    /// * `shadow_pa` - The host physical address of the shadow page with the patched code.
+    ///
+    /// # Returns
+    ///
+    /// The views, with the memory type of the current mapping of the page. Both leave #VE unsuppressed.
+    pub fn hook_views(&mut self, guest_pa: u64, shadow_pa: u64) -> Result<HookViews, HypervisorError> {
+        let guest_pa = VAddr::from(guest_pa).align_down_to_base_page();
+        let original = self.page_table_mut(guest_pa)?.0.entries[pt_index(guest_pa)];
+        let memory_type = original.memory_type();
+
+        let mut execute = Entry(0);
+        execute.set_executable(true);
+        execute.set_memory_type(memory_type);
+        execute.set_pfn(shadow_pa >> BASE_PAGE_SHIFT);
+
+        let mut read_write = Entry(0);
+        read_write.set_readable(true);
+        read_write.set_writable(true);
+        read_write.set_memory_type(memory_type);
+        read_write.set_pfn(guest_pa.as_u64() >> BASE_PAGE_SHIFT);
+
+        Ok(HookViews {
+            execute,
+            read_write,
+            original,
+        })
+    }
+
+    /// Replaces the entry of a 4KB page with a single 64-bit store, like a view of `hook_views`.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address of the page, within a split 2MB page.
+    /// * `entry` - The new entry.
+    ///
+    /// # Returns
+    ///
+    /// `HypervisorError::PageTableNotFound` if the page is still mapped with a large page.
+    pub fn set_page_entry(&mut self, guest_pa: u64, entry: Entry) -> Result<(), HypervisorError> {
+        let guest_pa = VAddr::from(guest_pa);
+        let pt_index = pt_index(guest_pa);
+        store_entry(&mut self.page_table_mut(guest_pa)?.0.entries[pt_index], entry);
         Ok(())
     }
 
@@ -316,12 +643,11 @@ impl Ept {
     ///
     /// * `guest_pa` - Guest physical address of the page whose permissions are to be changed.
     /// * `access_type` - The new access permissions to set for the page.
-    /// * `pt` - The page table to modify. This is required to update 4KB pages.
     ///
     /// # Returns
     ///
     /// A `Result<(), HypervisorError>` indicating if the operation was successful.
-    pub fn modify_page_permissions(&mut self, guest_pa: u64, access_type: AccessType, pt: &mut Pt) -> Result<(), HypervisorError> {
+    pub fn modify_page_permissions(&mut self, guest_pa: u64, access_type: AccessType) -> Result<(), HypervisorError> {
         trace!("Modifying permissions for GPA {:#x}", guest_pa);
 
         let guest_pa = VAddr::from(guest_pa);
@@ -345,7 +671,7 @@ impl Ept {
             pde.set_executable(access_type.contains(AccessType::EXECUTE));
         } else {
             trace!("Changing the permissions of a 4KB page");
-            let pte = &mut pt.0.entries[pt_index];
+            let pte = &mut self.page_table_mut(guest_pa)?.0.entries[pt_index];
             pte.set_readable(access_type.contains(AccessType::READ));
             pte.set_writable(access_type.contains(AccessType::WRITE));
             pte.set_executable(access_type.contains(AccessType::EXECUTE));
@@ -363,14 +689,13 @@ impl Ept {
     ///
     /// * `guest_pa` - The guest physical address that needs to be remapped.
     /// * `host_pa` - The new host physical address to map the guest physical address to.
-    /// * `pt` - The page table to modify. This is required to update 4KB pages.
     ///
     /// # Returns
     ///
     /// A `Result<u64, HypervisorError>` indicating if the operation was successful.
     /// On success, returns the old host physical address that was previously mapped to the guest physical address.
     /// In case of failure, a `HypervisorError` is returned, detailing the nature of the error.
-    pub fn remap_gpa_to_hpa(&mut self, guest_pa: u64, host_pa: u64, pt: &mut Pt) -> Result<u64, HypervisorError> {
+    pub fn remap_gpa_to_hpa(&mut self, guest_pa: u64, host_pa: u64) -> Result<u64, HypervisorError> {
         trace!("Remapping GPA {:#x} to HPA {:#x}", guest_pa, host_pa);
 
         let guest_pa = VAddr::from(guest_pa);
@@ -396,7 +721,7 @@ impl Ept {
         }
 
         // Access the corresponding PT entry
-        let pte = &mut pt.0.entries[pt_index];
+        let pte = &mut self.page_table_mut(guest_pa)?.0.entries[pt_index];
         let old_hpa = pte.pfn() << BASE_PAGE_SHIFT; // Calculate the old HPA from the Page Frame Number
 
         // Update the PTE to point to the new HPA
@@ -406,7 +731,7 @@ impl Ept {
         Ok(old_hpa)
     }
 
-    pub fn dump_ept_entries(&self, guest_pa: u64, pt: &Pt) {
+    pub fn dump_ept_entries(&self, guest_pa: u64) {
         let guest_pa = VAddr::from(guest_pa);
         let pdpt_index = pdpt_index(guest_pa);
         let pd_index = pd_index(guest_pa);
@@ -426,13 +751,15 @@ impl Ept {
 
         if pde.large() {
             trace!("This is a large page, no PT involved.");
+        } else if !pde.readable() {
+            trace!("The 2MB page is not mapped, no PT involved.");
         } else {
             // For non-large pages, calculate the physical address of the PT
             let pt_address = pde.pfn() << BASE_PAGE_SHIFT;
             trace!("PT located at physical address: {:#x}", pt_address);
 
             // Trace the PTE within the PT
-            let pte = pt.0.entries[pt_index];
+            let pte = unsafe { (*(pt_address as *const Pt)).0.entries[pt_index] };
             trace!("PTE at index {}: {:#x?}", pt_index, pte);
         }
     }
@@ -445,12 +772,11 @@ impl Ept {
     /// * `guest_pa` - The guest physical address to remap.
     /// * `host_pa` - The new host physical address to map to the guest physical address.
     /// * `access_type` - The access permissions to set for the mapped page.
-    /// * `pt` - The page table to use for the remap operation.
     ///
     /// # Returns
     ///
     /// * `Result<(), HypervisorError>` - The result of the operation, `Ok` if successful, otherwise a `HypervisorError`.
-    pub fn swap_page(&mut self, guest_pa: u64, host_pa: u64, access_type: AccessType, pt: &mut Pt) -> Result<(), HypervisorError> {
+    pub fn swap_page(&mut self, guest_pa: u64, host_pa: u64, access_type: AccessType) -> Result<(), HypervisorError> {
         let guest_pa = VAddr::from(guest_pa);
         let host_pa = VAddr::from(host_pa);
 
@@ -462,17 +788,14 @@ impl Ept {
 
         // Modify the permissions for the guest physical address.
         trace!("Modifying permissions for GPA {:#x} to {:?}", guest_pa, access_type);
-        self.modify_page_permissions(guest_pa.as_u64(), access_type, pt)?;
+        self.modify_page_permissions(guest_pa.as_u64(), access_type)?;
 
         // Remap the guest physical address to the new host physical address in the primary EPT.
         trace!("Remapping GPA {:#x} to HPA {:#x} in the primary EPT", guest_pa, host_pa);
-        self.remap_gpa_to_hpa(guest_pa.as_u64(), host_pa.as_u64(), pt)?;
+        self.remap_gpa_to_hpa(guest_pa.as_u64(), host_pa.as_u64())?;
 
-        // Invalidate the EPT cache for all contexts.
-        invept_all_contexts();
-
-        // Invalidate the VPID cache for all contexts.
-        invvpid_all_contexts();
+        // Invalidate the EPT caches, which covers the VPID-tagged translations as well.
+        shootdown::flush_ept(Scope::CurrentProcessor);
 
         Ok(())
     }
@@ -533,8 +856,15 @@ impl Ept {
         trace!("EPT PML4 (self) address: {:#x}", addr);
 
         // Get the physical address of the PML4 table for EPT.
-        let ept_pml4_base_addr = addr;
+        Self::eptp_for_pml4(addr)
+    }
 
+    /// Creates the EPTP of any PML4 with a Write-Back memory type and a 4-level page walk, like `create_eptp_with_wb_and_4lvl_walk`.
+    ///
+    /// # Arguments
+    ///
+    /// * `ept_pml4_base_addr` - The physical address of the PML4 table.
+    pub fn eptp_for_pml4(ept_pml4_base_addr: u64) -> Result<u64, HypervisorError> {
         // Represents the EPT page walk length for Intel VT-x, specifically for a 4-level page walk.
         // The value is 3 (encoded as '3 << 3' in EPTP) because the EPTP encoding requires "number of levels minus one".
         const EPT_PAGE_WALK_LENGTH_4: u64 = 3 << 3;
@@ -552,6 +882,26 @@ impl Ept {
     }
 }
 
+/// The EPT entries of a hooked 4KB page, built once and toggled on EPT violations.
+///
+/// Instruction fetches see the shadow page with the patched code, reads and writes see the pristine guest page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HookViews {
+    /// Execute-only, maps the shadow page.
+    pub execute: Entry,
+
+    /// Read-write, maps the guest page.
+    pub read_write: Entry,
+
+    /// The entry before the page was hooked, restored with its last hook.
+    pub original: Entry,
+}
+
+/// Replaces a paging-structure entry with a single 64-bit store, a processor walking the tables never sees half of it.
+fn store_entry(entry: &mut Entry, value: Entry) {
+    unsafe { AtomicU64::from_ptr(&mut entry.0) }.store(value.0, Ordering::Release);
+}
+
 /// Represents an EPT PML4 Entry (PML4E) that references a Page-Directory-Pointer Table.
 ///
 /// PML4 is the top level in the EPT paging hierarchy.
@@ -585,6 +935,18 @@ struct Pd(Table);
 #[derive(Debug, Clone, Copy)]
 pub struct Pt(Table);
 
+impl Pt {
+    /// Returns the entries of the table.
+    pub fn entries(&self) -> &[Entry; 512] {
+        &self.0.entries
+    }
+
+    /// Returns the entries of the table for changes nothing walks concurrently.
+    pub fn entries_mut(&mut self) -> &mut [Entry; 512] {
+        &mut self.0.entries
+    }
+}
+
 /// General struct to represent a table in the EPT paging structure.
 ///
 /// This struct is used as a basis for PML4, PDPT, PD, and PT. It contains an array of entries
@@ -611,9 +973,10 @@ bitfield! {
     /// * `pfn` - The Page Frame Number, indicating the physical address.
     /// * `verify_guest_paging` - Additional flag for guest paging verification.
     /// * `paging_write_access` - Additional flag for paging write access.
+    /// * `suppress_ve` - If set, EPT violations of this page always cause VM exits instead of #VE.
     ///
     /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 29.3.2 EPT Translation Mechanism
-    #[derive(Clone, Copy)]
+    #[derive(Clone, Copy, PartialEq, Eq)]
     pub struct Entry(u64);
     impl Debug;
 
@@ -626,6 +989,21 @@ bitfield! {
     pub pfn, set_pfn: 51, 12;
     pub verify_guest_paging, set_verify_guest_paging: 57;
     pub paging_write_access, set_paging_write_access: 58;
+    pub suppress_ve, set_suppress_ve: 63;
+}
+
+impl Entry {
+    /// Builds an entry referencing the paging structure of the next level at `table_pa`.
+    ///
+    /// Table 29-6. Format of an EPT Page-Directory Entry (PDE) that References an EPT Page Table: 6:3 Reserved (must be 0)
+    pub fn table(table_pa: u64) -> Self {
+        let mut table = Entry(0);
+        table.set_readable(true);
+        table.set_writable(true);
+        table.set_executable(true);
+        table.set_pfn(table_pa >> BASE_PAGE_SHIFT);
+        table
+    }
 }
 
 bitflags::bitflags! {
@@ -0,0 +1,394 @@
+//! EPT views the guest of one processor switches between with VMFUNC, or the hypervisor with `Vm::switch_view`.
+//!
+//! The primary EPT is view 0 and the only complete EPT of a processor. Every other view starts out as a PML4
+//! and a PDPT of its own referencing the paging structures of the primary EPT, and only copies the page
+//! directory of a 1GB page and the page table of a 2MB page where one of its 4KB pages is overridden, like
+//! the execute view of a hooked page. A view costs 8KB plus 4KB for every 1GB page and 2MB page with an
+//! override, so the memory is bounded by `MAX_EPT_VIEWS` and the number of hooked pages.
+//!
+//! Whoever changes the primary EPT calls `EptViewManager::sync` for the changed address afterwards. A page
+//! table copy is freed once the last override in its 2MB page is removed, a page directory copy once its 1GB
+//! page has no page table copy left. Like the primary EPT, the views are only used by their own processor.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.5.6.3 EPTP Switching
+
+use {
+    crate::{
+        allocator::box_zeroed,
+        error::HypervisorError,
+        intel::{
+            ept::{Entry, Ept, Pt, LOW_EPT_END},
+            shootdown::{self, Scope},
+            support::{vmread, vmwrite},
+            vcpu,
+            vm::Vm,
+        },
+    },
+    alloc::{boxed::Box, collections::BTreeMap},
+    core::ptr::addr_of,
+    x86::{
+        bits64::paging::{pd_index, pdpt_index, pml4_index, pt_index, VAddr, HUGE_PAGE_SIZE, LARGE_PAGE_SIZE},
+        vmx::vmcs,
+    },
+};
+
+/// The most EPT views a processor has, the primary EPT included.
+pub const MAX_EPT_VIEWS: usize = 4;
+
+/// The EPTPs VMFUNC switches between, indexed by view.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.5.6.3 EPTP Switching
+#[repr(C, align(4096))]
+#[derive(Debug, Clone, Copy)]
+pub struct EptpList(pub [u64; 512]);
+
+/// An EPT that maps like the primary EPT but for the pages it overrides.
+pub struct EptView {
+    /// The PML4 of the view, the entries above `LOW_EPT_END` are those of the primary EPT.
+    pml4: Box<Pt>,
+
+    /// The PDPT mapping the guest physical addresses below `LOW_EPT_END`.
+    pdpt: Box<Pt>,
+
+    /// The copied page directories, by PDPT index.
+    pds: BTreeMap<usize, Box<Pt>>,
+
+    /// The copied page tables, by the guest physical address of their 2MB page.
+    pts: BTreeMap<u64, Box<Pt>>,
+
+    /// The entries that differ from the primary EPT, by the guest physical address of their 4KB page.
+    overrides: BTreeMap<u64, Entry>,
+}
+
+impl EptView {
+    /// Creates a view mapping exactly like the primary EPT.
+    ///
+    /// # Arguments
+    ///
+    /// * `primary` - The primary EPT of the processor, built already.
+    pub fn new(primary: &Ept) -> Box<Self> {
+        let mut pml4 = unsafe { box_zeroed::<Pt>() };
+        let mut pdpt = unsafe { box_zeroed::<Pt>() };
+        *pml4.entries_mut() = *primary.pml4_entries();
+        *pdpt.entries_mut() = *primary.pdpt_entries();
+        pml4.entries_mut()[0] = Entry::table(addr_of!(*pdpt) as u64);
+
+        Box::new(Self {
+            pml4,
+            pdpt,
+            pds: BTreeMap::new(),
+            pts: BTreeMap::new(),
+            overrides: BTreeMap::new(),
+        })
+    }
+
+    /// Returns the EPTP of the view.
+    pub fn eptp(&self) -> Result<u64, HypervisorError> {
+        Ept::eptp_for_pml4(addr_of!(*self.pml4) as u64)
+    }
+
+    /// Returns the number of page directories and page tables the view copied.
+    pub fn copied_tables(&self) -> usize {
+        self.pds.len() + self.pts.len()
+    }
+
+    /// Maps a 4KB page with its own entry, the 2MB page of it must be split in the primary EPT.
+    ///
+    /// # Arguments
+    ///
+    /// * `primary` - The primary EPT of the processor.
+    /// * `guest_pa` - The guest physical address of the page, below `LOW_EPT_END`.
+    /// * `entry` - The entry of the page in this view.
+    pub fn set_override(&mut self, primary: &Ept, guest_pa: u64, entry: Entry) -> Result<(), HypervisorError> {
+        let guest_page_pa = VAddr::from(guest_pa).align_down_to_base_page().as_u64();
+        let previous = self.overrides.insert(guest_page_pa, entry);
+
+        if let Err(e) = self.sync(primary, guest_page_pa) {
+            match previous {
+                Some(previous) => self.overrides.insert(guest_page_pa, previous),
+                None => self.overrides.remove(&guest_page_pa),
+            };
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Maps a 4KB page like the primary EPT again.
+    ///
+    /// # Arguments
+    ///
+    /// * `primary` - The primary EPT of the processor.
+    /// * `guest_pa` - The guest physical address of the page.
+    pub fn remove_override(&mut self, primary: &Ept, guest_pa: u64) -> Result<(), HypervisorError> {
+        let guest_page_pa = VAddr::from(guest_pa).align_down_to_base_page().as_u64();
+        if self.overrides.remove(&guest_page_pa).is_none() {
+            return Ok(());
+        }
+        self.sync(primary, guest_page_pa)
+    }
+
+    /// Derives the entries of the view translating a guest physical address from the primary EPT and the overrides.
+    ///
+    /// # Arguments
+    ///
+    /// * `primary` - The primary EPT of the processor, after the change.
+    /// * `guest_pa` - A guest physical address the change affected.
+    pub fn sync(&mut self, primary: &Ept, guest_pa: u64) -> Result<(), HypervisorError> {
+        let guest_pa = VAddr::from(guest_pa);
+        if guest_pa.as_u64() >= LOW_EPT_END {
+            // The PDPTs above the first 512GB map with 1GB pages and are shared with the primary EPT.
+            let index = pml4_index(guest_pa);
+            self.pml4.entries_mut()[index] = primary.pml4_entries()[index];
+            return Ok(());
+        }
+
+        let large_page_pa = guest_pa.align_down_to_large_page().as_u64();
+        let huge_page_pa = guest_pa.align_down_to_huge_page().as_u64();
+        let pdpt_index = pdpt_index(guest_pa);
+        let pd_index = pd_index(guest_pa);
+
+        let overridden = self.overrides.range(large_page_pa..large_page_pa + LARGE_PAGE_SIZE as u64);
+        if overridden.clone().next().is_some() {
+            let mut entries = *primary.page_table_entries(large_page_pa)?;
+            for (&page_pa, &entry) in overridden {
+                entries[pt_index(VAddr::from(page_pa))] = entry;
+            }
+
+            let pt = self.pts.entry(large_page_pa).or_insert_with(|| unsafe { box_zeroed::<Pt>() });
+            *pt.entries_mut() = entries;
+            let table = Entry::table(addr_of!(**pt) as u64);
+
+            let pdpt = &mut self.pdpt;
+            let pd = self.pds.entry(pdpt_index).or_insert_with(|| {
+                let mut pd = unsafe { box_zeroed::<Pt>() };
+                *pd.entries_mut() = *primary.pd_entries(huge_page_pa);
+                pdpt.entries_mut()[pdpt_index] = Entry::table(addr_of!(*pd) as u64);
+                pd
+            });
+            pd.entries_mut()[pd_index] = table;
+        } else if let Some(pd) = self.pds.get_mut(&pdpt_index) {
+            pd.entries_mut()[pd_index] = primary.pd_entries(huge_page_pa)[pd_index];
+            self.pts.remove(&large_page_pa);
+
+            if self.pts.range(huge_page_pa..huge_page_pa + HUGE_PAGE_SIZE as u64).next().is_none() {
+                self.pdpt.entries_mut()[pdpt_index] = primary.pdpt_entries()[pdpt_index];
+                self.pds.remove(&pdpt_index);
+            }
+        } else {
+            return Ok(());
+        }
+
+        // Cached translations of the view must not outlive the change.
+        shootdown::flush_ept(Scope::CurrentProcessor);
+
+        Ok(())
+    }
+}
+
+/// The EPT views of one processor and the EPTP list naming them.
+///
+/// `Vm::zeroed` leaves every field `None`, which is a manager with the primary EPT as its only view.
+pub struct EptViewManager {
+    /// The views after the primary EPT, view `i + 1` is `views[i]`.
+    views: [Option<Box<EptView>>; MAX_EPT_VIEWS - 1],
+
+    /// The EPTP list of the VMCS, built with the views.
+    eptp_list: Option<Box<EptpList>>,
+
+    /// The view the guest ran with at the latest VM exit.
+    current: u16,
+}
+
+impl EptViewManager {
+    /// Builds the views and the EPTP list.
+    ///
+    /// # Arguments
+    ///
+    /// * `primary` - The primary EPT of the processor, built already.
+    /// * `primary_eptp` - The EPTP of `primary`.
+    /// * `count` - The number of views besides the primary EPT.
+    pub fn init(&mut self, primary: &Ept, primary_eptp: u64, count: usize) -> Result<(), HypervisorError> {
+        if count >= MAX_EPT_VIEWS {
+            return Err(HypervisorError::EptViewNotFound);
+        }
+
+        let mut eptp_list = unsafe { box_zeroed::<EptpList>() };
+        eptp_list.0[0] = primary_eptp;
+        for (index, slot) in self.views.iter_mut().enumerate().take(count) {
+            let view = EptView::new(primary);
+            eptp_list.0[index + 1] = view.eptp()?;
+            *slot = Some(view);
+        }
+
+        self.eptp_list = Some(eptp_list);
+        self.current = 0;
+        Ok(())
+    }
+
+    /// Returns the number of views, the primary EPT included.
+    pub fn view_count(&self) -> usize {
+        1 + self.views.iter().take_while(|view| view.is_some()).count()
+    }
+
+    /// Returns whether there are views besides the primary EPT.
+    pub fn has_views(&self) -> bool {
+        self.views[0].is_some()
+    }
+
+    /// Returns the physical address of the EPTP list, `None` without views.
+    pub fn eptp_list_pa(&self) -> Option<u64> {
+        self.eptp_list.as_deref().map(|list| list as *const EptpList as u64)
+    }
+
+    /// Returns the EPTP of a view, `None` if it doesn't exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The EPTP index of the view.
+    pub fn eptp(&self, index: u16) -> Option<u64> {
+        let list = self.eptp_list.as_deref()?;
+        (usize::from(index) < self.view_count()).then(|| list.0[usize::from(index)])
+    }
+
+    /// Returns a view after the primary EPT.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The EPTP index of the view, at least `1`.
+    pub fn view_mut(&mut self, index: u16) -> Result<&mut EptView, HypervisorError> {
+        usize::from(index)
+            .checked_sub(1)
+            .and_then(|slot| self.views.get_mut(slot))
+            .and_then(|view| view.as_deref_mut())
+            .ok_or(HypervisorError::EptViewNotFound)
+    }
+
+    /// Brings every view up to date after a change of the primary EPT, see `EptView::sync`.
+    ///
+    /// # Arguments
+    ///
+    /// * `primary` - The primary EPT of the processor, after the change.
+    /// * `guest_pa` - A guest physical address the change affected.
+    pub fn sync(&mut self, primary: &Ept, guest_pa: u64) -> Result<(), HypervisorError> {
+        for view in self.views.iter_mut().flatten() {
+            view.sync(primary, guest_pa)?;
+        }
+        Ok(())
+    }
+}
+
+impl Vm {
+    /// Switches the guest to an EPT view, like VMFUNC 0 with `index` in ECX would.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The EPTP index of the view, `0` for the primary EPT.
+    pub fn switch_view(&mut self, index: u16) -> Result<(), HypervisorError> {
+        let eptp = if index == 0 {
+            self.primary_eptp
+        } else {
+            self.ept_views.eptp(index).ok_or(HypervisorError::EptViewNotFound)?
+        };
+
+        vmwrite(vmcs::control::EPTP_FULL, eptp);
+        if self.ept_views.has_views() {
+            vmwrite(vmcs::control::EPTP_INDEX, index as u64);
+        }
+        self.record_ept_view(index);
+
+        Ok(())
+    }
+
+    /// Records the view the guest runs with for `Command::QueryEptViews`, the guest may have switched with VMFUNC.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The EPTP index of the view.
+    pub fn record_ept_view(&mut self, index: u16) {
+        if self.ept_views.current == index {
+            return;
+        }
+
+        self.ept_views.current = index;
+        if let Some(vcpu) = vcpu::current() {
+            vcpu.set_ept_view(index as u32);
+        }
+    }
+
+    /// Records the view the guest ran with until this VM exit, only processors with views switch at all.
+    pub fn record_ept_view_at_exit(&mut self) {
+        if self.ept_views.has_views() {
+            self.record_ept_view(vmread(vmcs::control::EPTP_INDEX) as u16);
+        }
+    }
+
+    /// Maps the page of a guest physical address the identity map left out, in every view of the processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address the guest accessed.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(true)` if the page is mapped now, see `Ept::map_on_demand`.
+    pub fn map_on_demand(&mut self, guest_pa: u64) -> Result<bool, HypervisorError> {
+        let mapped = self.primary_ept.map_on_demand(guest_pa)?;
+        if mapped {
+            self.ept_views.sync(&self.primary_ept, guest_pa)?;
+        }
+        Ok(mapped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn primary() -> Box<Ept> {
+        let mut primary = unsafe { box_zeroed::<Ept>() };
+        primary.init();
+        primary
+    }
+
+    #[test]
+    fn views_start_out_sharing_the_primary_ept() {
+        let primary = primary();
+        let mut manager = EptViewManager {
+            views: [const { None }; MAX_EPT_VIEWS - 1],
+            eptp_list: None,
+            current: 0,
+        };
+        assert_eq!((manager.view_count(), manager.eptp_list_pa(), manager.eptp(0)), (1, None, None));
+
+        manager.init(&primary, 0x1234_501E, 1).unwrap();
+        assert_eq!(manager.view_count(), 2);
+        assert_eq!(manager.eptp(0), Some(0x1234_501E));
+        assert_eq!(manager.eptp(2), None);
+
+        let view = manager.view_mut(1).unwrap();
+        let eptp = view.eptp().unwrap();
+        assert_eq!(eptp & 0xFFF, 0x1E);
+        assert_eq!(view.pml4.entries()[0].pfn() << 12, addr_of!(*view.pdpt) as u64);
+        assert_eq!(view.copied_tables(), 0);
+        assert_eq!(manager.eptp(1), Some(eptp));
+
+        assert!(manager.view_mut(0).is_err() && manager.view_mut(2).is_err());
+        assert!(manager.init(&primary, 0, MAX_EPT_VIEWS).is_err());
+    }
+
+    #[test]
+    fn overrides_need_a_split_primary_page() {
+        let primary = primary();
+        let mut view = EptView::new(&primary);
+
+        let mut execute = Entry(0);
+        execute.set_executable(true);
+        assert!(view.set_override(&primary, 0x20_3000, execute).is_err());
+        assert!(view.overrides.is_empty() && view.copied_tables() == 0);
+
+        // Unknown pages and pages without copies leave the view as it is.
+        view.remove_override(&primary, 0x20_3000).unwrap();
+        view.sync(&primary, 0x20_3000).unwrap();
+        assert_eq!(view.copied_tables(), 0);
+    }
+}
@@ -1,15 +1,32 @@
 //! This module provides utilities and structures to manage event injection in VMX.
 //! It handles the representation, manipulation, and injection of various types of events.
+//!
+//! VM exit handlers don't write the VM-entry interruption-information field themselves, they queue events on
+//! the `EventInjector` of their VM, which the VM exit loop flushes right before the VM entry. One event is
+//! injected per VM entry, the others wait in order. An exception raised while another one is pending follows
+//! the rules of the processor for exceptions during the delivery of an exception, and events interrupted by a
+//! VM exit in the middle of their delivery are queued again first. Maskable interrupts and NMIs the guest
+//! can't take yet wait for an interrupt-window or NMI-window exit.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 6.15 EXCEPTION AND INTERRUPT REFERENCE, Interrupt 8—Double Fault Exception (#DF)
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.6 EVENT INJECTION
 
 #![allow(dead_code)]
 
 use {
-    crate::intel::{
-        support::vmwrite,
-        vmerror::{ExceptionInterrupt, InterruptionType},
+    crate::{
+        intel::{
+            nmi,
+            state::GuestActivityState,
+            support::{vmread, vmwrite},
+            v86,
+            vmerror::{ExceptionInterrupt, InterruptionType},
+        },
+        stats,
     },
     bitfield::bitfield,
-    x86::vmx::vmcs,
+    log::*,
+    x86::vmx::vmcs::{self, control::PrimaryControls},
 };
 
 bitfield! {
@@ -47,106 +64,786 @@ bitfield! {
 const VALID: u32 = 1;
 const INVALID: u32 = 0;
 
-/// Provides methods for event injection in VMX.
-///
-/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.6 EVENT INJECTION
-impl EventInjection {
-    /// Inject General Protection (#GP) to the guest (Event Injection).
-    fn general_protection() -> u32 {
-        let mut event = EventInjection(0);
+/// The events waiting behind the pending one at most.
+pub const OVERFLOW_CAPACITY: usize = 4;
 
-        event.set_vector(ExceptionInterrupt::GeneralProtectionFault as u32);
-        event.set_type(InterruptionType::HardwareException as u32);
-        event.set_deliver_error_code(1);
-        event.set_valid(VALID);
+/// The length of INT3 and INTO, the instructions raising #BP and #OF.
+const SOFTWARE_EXCEPTION_LENGTH: u32 = 1;
 
-        event.0
+/// [Bit 0] Interruptibility state: blocking by STI.
+const BLOCKING_BY_STI: u64 = 1 << 0;
+
+/// [Bit 1] Interruptibility state: blocking by MOV SS.
+const BLOCKING_BY_MOV_SS: u64 = 1 << 1;
+
+/// [Bit 3] Interruptibility state: blocking by NMI, virtual-NMI blocking with "virtual NMIs".
+pub const BLOCKING_BY_NMI: u64 = 1 << 3;
+
+/// [Bit 9] RFLAGS: maskable interrupts are enabled.
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// [Bit 11] Interruption-information fields: an error code is delivered.
+const DELIVER_ERROR_CODE: u64 = 1 << 11;
+
+/// [Bit 31] Interruption-information fields: the field is valid.
+const INTERRUPTION_INFO_VALID: u64 = 1 << 31;
+
+/// An event to inject into the guest.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// The vector of the interrupt or exception.
+    pub vector: u8,
+
+    /// How the event is delivered.
+    pub interruption_type: InterruptionType,
+
+    /// The error code pushed with the exception, `None` if it pushes none.
+    pub error_code: Option<u32>,
+
+    /// The length of the instruction raising a software interrupt or exception, `0` for other events.
+    pub instruction_length: u32,
+}
+
+impl Event {
+    /// Builds an exception.
+    ///
+    /// #BP and #OF are software exceptions raised by INT3 and INTO, the guest resumes after the instruction
+    /// at RIP. The error code is delivered exactly for the exceptions that push one: `0` is used if none is
+    /// given for those, and one given for the others is ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `vector` - The vector of the exception.
+    /// * `error_code` - The error code of the exception.
+    pub fn exception(vector: u8, error_code: Option<u32>) -> Self {
+        let software = vector == ExceptionInterrupt::Breakpoint as u8 || vector == ExceptionInterrupt::Overflow as u8;
+
+        Self {
+            vector,
+            interruption_type: if software {
+                InterruptionType::SoftwareException
+            } else {
+                InterruptionType::HardwareException
+            },
+            error_code: has_error_code(vector).then(|| error_code.unwrap_or(0)),
+            instruction_length: if software { SOFTWARE_EXCEPTION_LENGTH } else { 0 },
+        }
     }
 
-    /// Inject Breakpoint (#BP) to the guest (Event Injection).
-    fn breakpoint() -> u32 {
-        let mut event = EventInjection(0);
+    /// Builds a non-maskable interrupt.
+    pub fn nmi() -> Self {
+        Self {
+            vector: ExceptionInterrupt::NonMaskableInterrupt as u8,
+            interruption_type: InterruptionType::NonMaskableInterrupt,
+            error_code: None,
+            instruction_length: 0,
+        }
+    }
 
-        event.set_vector(ExceptionInterrupt::Breakpoint as u32);
-        event.set_type(InterruptionType::HardwareException as u32);
-        event.set_valid(VALID);
+    /// Builds a maskable external interrupt.
+    ///
+    /// # Arguments
+    ///
+    /// * `vector` - The vector of the interrupt.
+    pub fn external(vector: u8) -> Self {
+        Self {
+            vector,
+            interruption_type: InterruptionType::ExternalInterrupt,
+            error_code: None,
+            instruction_length: 0,
+        }
+    }
 
-        event.0
+    /// Decodes the event a VM exit interrupted in the middle of its delivery.
+    ///
+    /// # Arguments
+    ///
+    /// * `info` - The IDT-vectoring information field.
+    /// * `error_code` - The IDT-vectoring error code field.
+    /// * `instruction_length` - The VM-exit instruction length, the length of INT n, INT3 or INTO for software events.
+    ///
+    /// # Returns
+    ///
+    /// The event, `None` if the VM exit didn't happen during the delivery of an event.
+    pub fn from_idt_vectoring(info: u64, error_code: u64, instruction_length: u64) -> Option<Self> {
+        if info & INTERRUPTION_INFO_VALID == 0 {
+            return None;
+        }
+
+        let event = EventInjection(info as u32);
+        let interruption_type = InterruptionType::from_bits(event.get_type() as u8)?;
+        let software = matches!(
+            interruption_type,
+            InterruptionType::SoftwareInterrupt | InterruptionType::PrivilegedSoftwareException | InterruptionType::SoftwareException
+        );
+
+        Some(Self {
+            vector: event.get_vector() as u8,
+            interruption_type,
+            error_code: (info & DELIVER_ERROR_CODE != 0).then_some(error_code as u32),
+            instruction_length: if software { instruction_length as u32 } else { 0 },
+        })
     }
 
-    /// Inject Page Fault (#PF) to the guest (Event Injection).
-    fn page_fault() -> u32 {
+    /// Encodes the event for the VM-entry interruption-information field.
+    pub fn encode(&self) -> u32 {
         let mut event = EventInjection(0);
 
-        event.set_vector(ExceptionInterrupt::PageFault as u32);
-        event.set_type(InterruptionType::HardwareException as u32);
+        event.set_vector(self.vector as u32);
+        event.set_type(self.interruption_type as u32);
+        event.set_deliver_error_code(self.error_code.is_some() as u32);
         event.set_valid(VALID);
 
         event.0
     }
 
-    /// Inject Undefined Opcode (#UD) to the guest (Event Injection).
-    fn undefined_opcode() -> u32 {
-        let mut event = EventInjection(0);
+    /// Returns whether the event is an exception, software exceptions included.
+    fn is_exception(&self) -> bool {
+        matches!(
+            self.interruption_type,
+            InterruptionType::HardwareException | InterruptionType::SoftwareException | InterruptionType::PrivilegedSoftwareException
+        )
+    }
+}
 
-        event.set_vector(ExceptionInterrupt::InvalidOpcode as u32);
-        event.set_type(InterruptionType::HardwareException as u32);
-        event.set_valid(VALID);
+/// Returns whether the exception `vector` pushes an error code.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 6-1. Protected-Mode Exceptions and Interrupts
+pub fn has_error_code(vector: u8) -> bool {
+    matches!(vector, 8 | 10..=14 | 17 | 21)
+}
 
-        event.0
+/// How an exception takes part in generating a double fault.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 6-4. Interrupt and Exception Classes
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExceptionClass {
+    /// Every other exception and every interrupt, delivered one after the other.
+    Benign,
+
+    /// #DE, #TS, #NP, #SS, #GP and #CP.
+    Contributory,
+
+    /// #PF.
+    PageFault,
+
+    /// #DF.
+    DoubleFault,
+}
+
+/// Returns the class of the exception `vector`.
+pub fn exception_class(vector: u8) -> ExceptionClass {
+    match vector {
+        0 | 10..=13 | 21 => ExceptionClass::Contributory,
+        14 => ExceptionClass::PageFault,
+        8 => ExceptionClass::DoubleFault,
+        _ => ExceptionClass::Benign,
+    }
+}
+
+/// What raising an exception during the delivery of another one leads to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Escalation {
+    /// The second exception is delivered, the first one is raised again once its instruction runs again.
+    Serial,
+
+    /// Both are replaced by a #DF.
+    DoubleFault,
+
+    /// The processor shuts down.
+    TripleFault,
+}
+
+/// Returns what raising the exception `second` during the delivery of the exception `first` leads to.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 6-5. Conditions for Generating a Double Fault
+///
+/// # Arguments
+///
+/// * `first` - The vector of the exception being delivered.
+/// * `second` - The vector of the exception raised.
+pub fn escalation(first: u8, second: u8) -> Escalation {
+    use ExceptionClass::*;
+
+    match (exception_class(first), exception_class(second)) {
+        (Contributory, Contributory) | (PageFault, Contributory) | (PageFault, PageFault) => Escalation::DoubleFault,
+        (DoubleFault, Contributory) | (DoubleFault, PageFault) | (DoubleFault, DoubleFault) => Escalation::TripleFault,
+        _ => Escalation::Serial,
+    }
+}
+
+/// The state of the guest that decides whether it can take an event.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GuestEventState {
+    /// The guest interruptibility state.
+    pub interruptibility: u64,
+
+    /// The guest RFLAGS.
+    pub rflags: u64,
+
+    /// The guest activity state.
+    pub activity_state: u64,
+
+    /// Whether the VMCS has "virtual NMIs", and with it NMI-window exiting.
+    pub virtual_nmis: bool,
+}
+
+impl GuestEventState {
+    /// Reads the state of the guest from the current VMCS.
+    pub fn read() -> Self {
+        Self {
+            interruptibility: vmread(vmcs::guest::INTERRUPTIBILITY_STATE),
+            rflags: vmread(vmcs::guest::RFLAGS),
+            activity_state: vmread(vmcs::guest::ACTIVITY_STATE),
+            virtual_nmis: nmi::virtual_nmis(),
+        }
+    }
+}
+
+/// What happens with the pending event at a VM entry.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Delivery {
+    /// The event is injected with this VM entry.
+    Inject,
+
+    /// The guest blocks maskable interrupts, "interrupt-window exiting" exits once it stops.
+    WaitForInterruptWindow,
+
+    /// The guest blocks NMIs, "NMI-window exiting" exits once it stops.
+    WaitForNmiWindow,
+
+    /// The guest is in the shutdown or wait-for-SIPI state, the event waits until it leaves it.
+    Wait,
+
+    /// The guest blocks NMIs and the processor has no NMI-window exiting, the NMI is lost.
+    Drop,
+}
+
+/// Chooses what happens with `event` at a VM entry.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.3.1.5 Checks on Guest Non-Register State
+///
+/// # Arguments
+///
+/// * `event` - The pending event.
+/// * `state` - The state of the guest.
+pub fn delivery(event: &Event, state: &GuestEventState) -> Delivery {
+    let awake = state.activity_state <= GuestActivityState::Hlt as u64;
+    let shadowed = state.interruptibility & (BLOCKING_BY_STI | BLOCKING_BY_MOV_SS) != 0;
+
+    match event.interruption_type {
+        InterruptionType::ExternalInterrupt => {
+            if !awake {
+                Delivery::Wait
+            } else if shadowed || state.rflags & RFLAGS_IF == 0 {
+                Delivery::WaitForInterruptWindow
+            } else {
+                Delivery::Inject
+            }
+        }
+        InterruptionType::NonMaskableInterrupt => {
+            if awake && !shadowed && state.interruptibility & BLOCKING_BY_NMI == 0 {
+                Delivery::Inject
+            } else if state.virtual_nmis {
+                Delivery::WaitForNmiWindow
+            } else {
+                Delivery::Drop
+            }
+        }
+        // Exceptions and software interrupts aren't blocked.
+        _ => {
+            if awake {
+                Delivery::Inject
+            } else {
+                Delivery::Wait
+            }
+        }
+    }
+}
+
+/// What a VM entry does with the events of an `EventInjector`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct EntryPlan {
+    /// The event to inject.
+    pub inject: Option<Event>,
+
+    /// Whether a maskable interrupt waits for an interrupt-window exit.
+    pub interrupt_window: bool,
+
+    /// Whether an NMI waits for an NMI-window exit.
+    pub nmi_window: bool,
+
+    /// Whether an exception during the delivery of a #DF shut the guest down.
+    pub shutdown: bool,
+}
+
+/// The events waiting to be injected into the guest of one processor.
+#[derive(Debug, Clone)]
+pub struct EventInjector {
+    /// The event injected with the next VM entry the guest can take it at.
+    pending: Option<Event>,
+
+    /// The events waiting behind `pending`, first one first.
+    overflow: [Option<Event>; OVERFLOW_CAPACITY],
+
+    /// Whether an exception during the delivery of a #DF shuts the guest down at the next VM entry.
+    triple_fault: bool,
+}
+
+impl EventInjector {
+    /// Creates an injector without events.
+    pub const fn new() -> Self {
+        Self {
+            pending: None,
+            overflow: [None; OVERFLOW_CAPACITY],
+            triple_fault: false,
+        }
+    }
+
+    /// Returns whether an event waits to be injected.
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Returns whether an NMI waits to be injected.
+    pub fn holds_nmi(&self) -> bool {
+        self.events()
+            .any(|event| event.interruption_type == InterruptionType::NonMaskableInterrupt)
     }
 
-    /// Injects a general protection fault into the guest.
+    /// Returns the waiting events, the one injected next first.
+    pub fn events(&self) -> impl Iterator<Item = &Event> {
+        self.pending.iter().chain(self.overflow.iter().flatten())
+    }
+
+    /// Raises an exception in the guest.
     ///
-    /// This function is used to signal to the guest that a protection violation
-    /// has occurred, typically due to accessing a reserved MSR.
+    /// The exception goes first. A pending exception is replaced by it, or together with it by a #DF if the
+    /// second one is raised during the delivery of the first one, see `escalation`. A pending software
+    /// interrupt is replaced too, its instruction runs again, and a pending NMI or maskable interrupt waits
+    /// behind it.
     ///
     /// # Arguments
     ///
-    /// * `error_code` - The error code to be associated with the fault.
-    ///
-    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.8.3 VM-Entry Controls for Event Injection
-    /// and Table 25-17. Format of the VM-Entry Interruption-Information Field.
-    pub fn vmentry_inject_gp(error_code: u32) {
-        vmwrite(vmcs::control::VMENTRY_EXCEPTION_ERR_CODE, error_code);
-        vmwrite(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD, EventInjection::general_protection());
+    /// * `vector` - The vector of the exception.
+    /// * `error_code` - The error code of the exception, see `Event::exception`.
+    pub fn inject_exception(&mut self, vector: u8, error_code: Option<u32>) {
+        let event = Event::exception(vector, error_code);
+
+        match self.pending {
+            Some(first) if first.interruption_type == InterruptionType::HardwareException => match escalation(first.vector, vector) {
+                Escalation::Serial => self.pending = Some(event),
+                Escalation::DoubleFault => {
+                    debug!("{:#x} during the delivery of {:#x} escalates to #DF", vector, first.vector);
+                    self.pending = Some(Event::exception(ExceptionInterrupt::DoubleFault as u8, Some(0)));
+                }
+                Escalation::TripleFault => {
+                    error!("{:#x} during the delivery of #DF, the guest triple faults", vector);
+                    self.pending = None;
+                    self.triple_fault = true;
+                }
+            },
+            Some(first) if first.is_exception() || first.interruption_type == InterruptionType::SoftwareInterrupt => {
+                self.pending = Some(event);
+            }
+            Some(first) => {
+                self.push_front(first);
+                self.pending = Some(event);
+            }
+            None => self.pending = Some(event),
+        }
     }
 
-    /// Injects a page fault into the guest.
-    ///
-    /// This function is used to signal to the guest that a page fault has occurred.
-    /// It's typically used in response to a memory access violation.
+    /// Sends an NMI to the guest, after the events already waiting.
+    pub fn inject_nmi(&mut self) {
+        self.push_back(Event::nmi());
+    }
+
+    /// Sends a maskable interrupt to the guest, after the events already waiting.
     ///
     /// # Arguments
     ///
-    /// * `error_code` - The error code to be associated with the page fault.
-    ///
-    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.8.3 VM-Entry Controls for Event Injection
-    /// and Table 25-17. Format of the VM-Entry Interruption-Information Field.
-    pub fn vmentry_inject_pf(error_code: u32) {
-        vmwrite(vmcs::control::VMENTRY_EXCEPTION_ERR_CODE, error_code);
-        vmwrite(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD, EventInjection::page_fault());
+    /// * `vector` - The vector of the interrupt.
+    pub fn inject_external(&mut self, vector: u8) {
+        self.push_back(Event::external(vector));
     }
 
-    /// Injects a breakpoint exception into the guest.
+    /// Queues the event a VM exit interrupted in the middle of its delivery, ahead of every other event.
     ///
-    /// This function is used to signal to the guest that a breakpoint exception
-    /// has occurred, typically used for debugging purposes.
+    /// An exception a handler raises afterwards is raised during its delivery.
     ///
-    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.8.3 VM-Entry Controls for Event Injection
-    /// and Table 25-17. Format of the VM-Entry Interruption-Information Field.
-    pub fn vmentry_inject_bp() {
-        vmwrite(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD, EventInjection::breakpoint());
+    /// # Arguments
+    ///
+    /// * `event` - The interrupted event.
+    pub fn requeue(&mut self, event: Event) {
+        if let Some(pending) = self.pending.replace(event) {
+            self.push_front(pending);
+        }
     }
 
-    /// Injects an undefined opcode exception into the guest.
+    /// Takes the events another VM entry delivers, right before it.
     ///
-    /// This function is used to signal to the guest that an invalid or undefined opcode
-    /// has been encountered, typically indicating an error in the guest's execution.
+    /// # Arguments
     ///
-    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.8.3 VM-Entry Controls for Event Injection
-    /// and Table 25-17. Format of the VM-Entry Interruption-Information Field.
-    pub fn vmentry_inject_ud() {
-        vmwrite(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD, EventInjection::undefined_opcode());
+    /// * `state` - The state of the guest.
+    pub fn plan(&mut self, state: &GuestEventState) -> EntryPlan {
+        let mut plan = EntryPlan::default();
+
+        if self.triple_fault {
+            self.triple_fault = false;
+            plan.shutdown = true;
+            return plan;
+        }
+
+        while let Some(event) = self.pending {
+            match delivery(&event, state) {
+                Delivery::Inject => {
+                    plan.inject = Some(event);
+                    self.pending = self.pop_front();
+                    break;
+                }
+                Delivery::Drop => {
+                    warn!("Dropping an NMI the guest blocks, the processor has no virtual NMIs");
+                    stats::record_nmi_dropped();
+                    self.pending = self.pop_front();
+                }
+                Delivery::WaitForInterruptWindow | Delivery::WaitForNmiWindow | Delivery::Wait => break,
+            }
+        }
+
+        // The events left exit as soon as the guest can take them, after the one injected now.
+        for event in self.events() {
+            match event.interruption_type {
+                InterruptionType::ExternalInterrupt => plan.interrupt_window = true,
+                InterruptionType::NonMaskableInterrupt => plan.nmi_window |= state.virtual_nmis,
+                _ => {}
+            }
+        }
+
+        plan
+    }
+
+    /// Queues the event the VM exit interrupted, right after a VM exit.
+    pub fn after_vm_exit(&mut self) {
+        let event = Event::from_idt_vectoring(
+            vmread(vmcs::ro::IDT_VECTORING_INFO),
+            vmread(vmcs::ro::IDT_VECTORING_ERR_CODE),
+            vmread(vmcs::ro::VMEXIT_INSTRUCTION_LEN),
+        );
+        if let Some(event) = event {
+            trace!("Reinjecting the interrupted {:?}", event);
+            self.requeue(event);
+        }
+    }
+
+    /// Injects the next event the guest can take and sets up the window exits of the others, right before a VM entry.
+    pub fn flush(&mut self) {
+        let state = GuestEventState::read();
+        let plan = self.plan(&state);
+
+        if plan.shutdown {
+            if GuestActivityState::Shutdown.is_supported() {
+                vmwrite(vmcs::guest::ACTIVITY_STATE, GuestActivityState::Shutdown as u32);
+            } else {
+                // The processor can't enter the shutdown state, the guest gets the #DF it can't handle once more.
+                write(&Event::exception(ExceptionInterrupt::DoubleFault as u8, Some(0)), &state);
+            }
+        }
+
+        if let Some(event) = plan.inject {
+            write(&event, &state);
+            if event.interruption_type == InterruptionType::NonMaskableInterrupt {
+                stats::record_nmi_reinjected();
+            }
+        }
+
+        set_window_exiting(PrimaryControls::INTERRUPT_WINDOW_EXITING, plan.interrupt_window);
+        if state.virtual_nmis {
+            set_window_exiting(PrimaryControls::NMI_WINDOW_EXITING, plan.nmi_window);
+        }
+    }
+
+    /// Queues `event` behind every waiting event, dropping it if the queue is full.
+    fn push_back(&mut self, event: Event) {
+        if self.pending.is_none() {
+            self.pending = Some(event);
+        } else if let Some(slot) = self.overflow.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(event);
+        } else {
+            dropped(&event);
+        }
+    }
+
+    /// Queues `event` right behind the pending event, dropping the last one if the queue is full.
+    fn push_front(&mut self, event: Event) {
+        if let Some(last) = self.overflow[OVERFLOW_CAPACITY - 1].take() {
+            dropped(&last);
+        }
+        self.overflow.rotate_right(1);
+        self.overflow[0] = Some(event);
+    }
+
+    /// Takes the first event behind the pending one.
+    fn pop_front(&mut self) -> Option<Event> {
+        let first = self.overflow[0].take();
+        self.overflow.rotate_left(1);
+        first
+    }
+}
+
+impl Default for EventInjector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reports an event lost because the queue is full.
+fn dropped(event: &Event) {
+    warn!("Dropping {:?}, {} events wait already", event, OVERFLOW_CAPACITY + 1);
+    if event.interruption_type == InterruptionType::NonMaskableInterrupt {
+        stats::record_nmi_dropped();
+    }
+}
+
+/// Writes `event` to the VM-entry fields.
+///
+/// # Arguments
+///
+/// * `event` - The event to inject.
+/// * `state` - The state of the guest, whose HLT state only lets some events be injected.
+fn write(event: &Event, state: &GuestEventState) {
+    if let Some(error_code) = event.error_code {
+        vmwrite(vmcs::control::VMENTRY_EXCEPTION_ERR_CODE, error_code);
+    }
+    if event.instruction_length != 0 {
+        vmwrite(vmcs::control::VMENTRY_INSTRUCTION_LEN, event.instruction_length);
+    }
+
+    // Delivering the event wakes the guest up, like it would bare metal.
+    if state.activity_state == GuestActivityState::Hlt as u64 {
+        vmwrite(vmcs::guest::ACTIVITY_STATE, GuestActivityState::Active as u32);
+    }
+
+    // Real mode emulated in virtual-8086 mode takes its events through the interrupt vector table.
+    if v86::emulated_real_mode() {
+        v86::deliver(event);
+        return;
+    }
+
+    vmwrite(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD, event.encode());
+}
+
+/// Enables or disables a window-exiting control.
+///
+/// # Arguments
+///
+/// * `control` - `INTERRUPT_WINDOW_EXITING` or `NMI_WINDOW_EXITING`.
+/// * `enable` - Whether a VM exit should happen as soon as the guest can take the event.
+fn set_window_exiting(control: PrimaryControls, enable: bool) {
+    let primary = vmread(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS);
+    let window = control.bits() as u64;
+    let updated = if enable { primary | window } else { primary & !window };
+    if updated != primary {
+        vmwrite(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS, updated);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ACTIVE: GuestEventState = GuestEventState {
+        interruptibility: 0,
+        rflags: RFLAGS_IF,
+        activity_state: 0,
+        virtual_nmis: true,
+    };
+
+    fn with(interruptibility: u64, rflags: u64, activity_state: u64, virtual_nmis: bool) -> GuestEventState {
+        GuestEventState {
+            interruptibility,
+            rflags,
+            activity_state,
+            virtual_nmis,
+        }
+    }
+
+    #[test]
+    fn exceptions_carry_their_error_codes_and_lengths() {
+        let gp = Event::exception(13, Some(0x10));
+        assert_eq!((gp.interruption_type, gp.error_code, gp.instruction_length), (InterruptionType::HardwareException, Some(0x10), 0));
+        assert_eq!(gp.encode(), 0x8000_0B0D);
+
+        // #DF always pushes one, #UD never does.
+        assert_eq!(Event::exception(8, None).error_code, Some(0));
+        assert_eq!(Event::exception(6, Some(1)).error_code, None);
+        assert_eq!(Event::exception(6, None).encode(), 0x8000_0306);
+
+        // #BP and #OF continue after INT3 and INTO.
+        let bp = Event::exception(3, None);
+        assert_eq!((bp.interruption_type, bp.instruction_length), (InterruptionType::SoftwareException, 1));
+        assert_eq!(bp.encode(), 0x8000_0603);
+        assert_eq!(Event::exception(4, None).interruption_type, InterruptionType::SoftwareException);
+
+        assert_eq!(Event::nmi().encode(), 0x8000_0202);
+        assert_eq!(Event::external(0x41).encode(), 0x8000_0041);
+    }
+
+    #[test]
+    fn interrupted_deliveries_decode() {
+        assert_eq!(Event::from_idt_vectoring(0x0B0E, 0x2, 3), None);
+        assert_eq!(Event::from_idt_vectoring(0x8000_0B0E, 0x2, 3), Some(Event::exception(14, Some(0x2))));
+        assert_eq!(Event::from_idt_vectoring(0x8000_0041, 0, 3), Some(Event::external(0x41)));
+
+        // INT 0x2E runs again from the start with its own length.
+        let software = Event::from_idt_vectoring(0x8000_042E, 0, 2).unwrap();
+        assert_eq!((software.interruption_type, software.instruction_length), (InterruptionType::SoftwareInterrupt, 2));
+    }
+
+    #[test]
+    fn faults_during_delivery_escalate() {
+        // Contributory, page fault, double fault, benign.
+        let cases = [
+            (13, 13, Escalation::DoubleFault),
+            (0, 11, Escalation::DoubleFault),
+            (13, 14, Escalation::Serial),
+            (14, 14, Escalation::DoubleFault),
+            (14, 13, Escalation::DoubleFault),
+            (14, 6, Escalation::Serial),
+            (8, 13, Escalation::TripleFault),
+            (8, 14, Escalation::TripleFault),
+            (8, 6, Escalation::Serial),
+            (6, 13, Escalation::Serial),
+            (3, 14, Escalation::Serial),
+            (17, 13, Escalation::Serial),
+        ];
+        for (first, second, expected) in cases {
+            assert_eq!(escalation(first, second), expected, "{} then {}", first, second);
+        }
+    }
+
+    #[test]
+    fn events_wait_for_the_guest() {
+        let external = Event::external(0x41);
+        let nmi = Event::nmi();
+        let gp = Event::exception(13, Some(0));
+
+        assert_eq!(delivery(&external, &ACTIVE), Delivery::Inject);
+        assert_eq!(delivery(&nmi, &ACTIVE), Delivery::Inject);
+        assert_eq!(delivery(&gp, &ACTIVE), Delivery::Inject);
+
+        // RFLAGS.IF, blocking by STI and by MOV SS hold interrupts back, not exceptions.
+        for state in [
+            with(0, 0, 0, true),
+            with(BLOCKING_BY_STI, RFLAGS_IF, 0, true),
+            with(BLOCKING_BY_MOV_SS, RFLAGS_IF, 0, true),
+        ] {
+            assert_eq!(delivery(&external, &state), Delivery::WaitForInterruptWindow);
+            assert_eq!(delivery(&gp, &state), Delivery::Inject);
+        }
+        assert_eq!(delivery(&nmi, &with(0, 0, 0, true)), Delivery::Inject);
+
+        // Blocking by STI, by MOV SS and by NMI hold NMIs back, lost without virtual NMIs.
+        for interruptibility in [BLOCKING_BY_STI, BLOCKING_BY_MOV_SS, BLOCKING_BY_NMI] {
+            assert_eq!(delivery(&nmi, &with(interruptibility, RFLAGS_IF, 0, true)), Delivery::WaitForNmiWindow);
+            assert_eq!(delivery(&nmi, &with(interruptibility, RFLAGS_IF, 0, false)), Delivery::Drop);
+        }
+        assert_eq!(delivery(&external, &with(BLOCKING_BY_NMI, RFLAGS_IF, 0, true)), Delivery::Inject);
+
+        // Blocking by SMI doesn't hold NMIs back.
+        assert_eq!(delivery(&nmi, &with(1 << 2, RFLAGS_IF, 0, true)), Delivery::Inject);
+
+        // HLT takes every event, shutdown and wait-for-SIPI none.
+        for event in [external, nmi, gp] {
+            assert_eq!(delivery(&event, &with(0, RFLAGS_IF, 1, true)), Delivery::Inject);
+        }
+        assert_eq!(delivery(&external, &with(0, RFLAGS_IF, 3, true)), Delivery::Wait);
+        assert_eq!(delivery(&gp, &with(0, RFLAGS_IF, 2, true)), Delivery::Wait);
+        assert_eq!(delivery(&nmi, &with(0, RFLAGS_IF, 2, true)), Delivery::WaitForNmiWindow);
+        assert_eq!(delivery(&nmi, &with(0, RFLAGS_IF, 3, false)), Delivery::Drop);
+    }
+
+    #[test]
+    fn handlers_no_longer_clobber_each_other() {
+        let mut injector = EventInjector::new();
+        injector.inject_external(0x41);
+        injector.inject_nmi();
+        injector.inject_exception(13, Some(0));
+
+        // The exception goes first, the interrupts wait behind it in order.
+        let plan = injector.plan(&ACTIVE);
+        assert_eq!(plan.inject, Some(Event::exception(13, Some(0))));
+        assert!(plan.interrupt_window && plan.nmi_window);
+        assert_eq!(injector.plan(&ACTIVE).inject, Some(Event::external(0x41)));
+        assert_eq!(injector.plan(&ACTIVE).inject, Some(Event::nmi()));
+        assert_eq!(injector.plan(&ACTIVE), EntryPlan::default());
+    }
+
+    #[test]
+    fn exceptions_on_exceptions_merge() {
+        let mut injector = EventInjector::new();
+        injector.inject_exception(14, Some(2));
+        injector.inject_exception(13, Some(0));
+        assert_eq!(injector.plan(&ACTIVE).inject, Some(Event::exception(8, Some(0))));
+
+        // A #PF during the delivery of a #DF shuts the guest down.
+        injector.inject_exception(8, None);
+        injector.inject_exception(14, Some(0));
+        assert!(injector.plan(&ACTIVE).shutdown);
+        assert!(!injector.is_pending());
+
+        // A benign second exception replaces the first one.
+        injector.inject_exception(13, Some(0));
+        injector.inject_exception(6, None);
+        assert_eq!(injector.plan(&ACTIVE).inject, Some(Event::exception(6, None)));
+    }
+
+    #[test]
+    fn interrupted_deliveries_go_first() {
+        let mut injector = EventInjector::new();
+        injector.inject_external(0x41);
+        injector.requeue(Event::exception(14, Some(0)));
+
+        // A #GP the handler raises is raised during the delivery of the #PF.
+        injector.inject_exception(13, Some(0));
+        assert_eq!(injector.plan(&ACTIVE).inject, Some(Event::exception(8, Some(0))));
+        assert_eq!(injector.plan(&ACTIVE).inject, Some(Event::external(0x41)));
+    }
+
+    #[test]
+    fn blocked_events_stay_pending() {
+        let mut injector = EventInjector::new();
+        injector.inject_external(0x41);
+        injector.inject_nmi();
+
+        // Interrupts disabled: the interrupt waits, the NMI behind it waits too.
+        let plan = injector.plan(&with(0, 0, 0, true));
+        assert_eq!(plan.inject, None);
+        assert!(plan.interrupt_window && plan.nmi_window);
+        assert!(injector.holds_nmi());
+
+        // Without virtual NMIs a blocked NMI is lost and the next event is tried.
+        let mut injector = EventInjector::new();
+        injector.inject_nmi();
+        injector.inject_exception(6, None);
+        injector.inject_nmi();
+        assert_eq!(injector.plan(&with(BLOCKING_BY_NMI, RFLAGS_IF, 0, false)).inject, Some(Event::exception(6, None)));
+        assert_eq!(injector.plan(&with(BLOCKING_BY_NMI, RFLAGS_IF, 0, false)), EntryPlan::default());
+        assert!(!injector.holds_nmi());
+    }
+
+    #[test]
+    fn full_queues_drop_the_last_event() {
+        let mut injector = EventInjector::new();
+        for vector in 0..=OVERFLOW_CAPACITY as u8 + 1 {
+            injector.inject_external(0x40 + vector);
+        }
+        assert_eq!(injector.events().count(), OVERFLOW_CAPACITY + 1);
+        assert_eq!(injector.events().last(), Some(&Event::external(0x40 + OVERFLOW_CAPACITY as u8)));
+
+        // An exception displaces the pending interrupt, pushing out the last one.
+        injector.inject_exception(6, None);
+        assert_eq!(injector.events().nth(1), Some(&Event::external(0x40)));
+        assert_eq!(injector.events().last(), Some(&Event::external(0x40 + OVERFLOW_CAPACITY as u8 - 1)));
     }
 }
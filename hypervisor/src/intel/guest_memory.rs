@@ -0,0 +1,413 @@
+//! Copies guest memory for the memory and process hypercalls of `shared::vmcall`.
+//!
+//! Guest virtual addresses are translated with `page_walk`, guest-physical ones through the EPT, so a copy never
+//! relies on the guest kernel and never faults in the host: it goes a piece at a time, no piece crossing a 4-KByte
+//! page on either side, and stops at the first page that isn't mapped. Writes follow the read/write bits of the
+//! guest's page tables unless they are forced, the bytes then go through the host mapping of the page. Only the
+//! guest-physical addresses in the first PML4 entry of the EPT whose host page is in the host's identity map are
+//! copied, the EPT walk ignores the bits above 47 and the host maps nothing past 512GB.
+
+use {
+    crate::intel::{
+        ept::{Ept, LOW_EPT_END},
+        page_walk::{walk, Paging, PhysicalMemory},
+        paging::IDENTITY_MAP_END,
+        support::vmread,
+    },
+    x86::{bits64::paging::BASE_PAGE_SIZE, vmx::vmcs},
+};
+
+/// Guest-physical memory the hypervisor can copy within.
+pub trait GuestMemory: PhysicalMemory {
+    /// Copies bytes between guest-physical addresses.
+    ///
+    /// # Arguments
+    ///
+    /// * `from` - The address of the first byte to read.
+    /// * `to` - The address of the first byte to write.
+    /// * `length` - The bytes to copy, neither range crossing a 4-KByte page.
+    ///
+    /// # Returns
+    ///
+    /// Whether the bytes were copied, `false` if either page isn't backed by memory.
+    fn copy(&mut self, from: u64, to: u64, length: u64) -> bool;
+
+    /// Reads bytes at a guest-physical address into a host buffer.
+    ///
+    /// # Arguments
+    ///
+    /// * `pa` - The address of the first byte.
+    /// * `buffer` - The buffer, not crossing a 4-KByte page at `pa`.
+    ///
+    /// # Returns
+    ///
+    /// Whether the bytes were read, `false` if the page isn't backed by memory.
+    fn read(&self, pa: u64, buffer: &mut [u8]) -> bool;
+
+    /// Writes bytes of the host to a guest-physical address.
+    ///
+    /// # Arguments
+    ///
+    /// * `pa` - The address of the first byte.
+    /// * `bytes` - The bytes, not crossing a 4-KByte page at `pa`.
+    ///
+    /// # Returns
+    ///
+    /// Whether the bytes were written, `false` if the page isn't backed by memory.
+    fn write(&mut self, pa: u64, bytes: &[u8]) -> bool;
+}
+
+/// The guest-physical memory the current processor runs the guest with, as the EPT maps it to the host.
+pub struct EptMemory {
+    /// The EPT PML4 of the current EPTP.
+    pml4: u64,
+}
+
+impl EptMemory {
+    /// Returns the memory of the EPT the current VMCS uses, `None` if its EPTP is invalid.
+    pub fn current() -> Option<Self> {
+        let (pml4, _, _) = Ept::decode_eptp(vmread(vmcs::control::EPTP_FULL)).ok()?;
+        Some(Self { pml4 })
+    }
+
+    /// Returns the host physical address of a guest-physical address, identity mapped in the host.
+    fn host_address(&self, pa: u64) -> Option<u64> {
+        if !within_host_map(pa) {
+            return None;
+        }
+
+        let host = unsafe { Ept::translate_guest_pa_to_host_pa(self.pml4, pa) }.ok()?;
+        within_host_map(host).then_some(host)
+    }
+}
+
+/// Returns whether a physical address is in the first PML4 entry of the EPT and in the host's identity map.
+fn within_host_map(pa: u64) -> bool {
+    pa < LOW_EPT_END.min(IDENTITY_MAP_END)
+}
+
+impl PhysicalMemory for EptMemory {
+    fn read_entry(&self, pa: u64) -> Option<u64> {
+        let host = self.host_address(pa)?;
+        Some(unsafe { (host as *const u64).read_volatile() })
+    }
+}
+
+impl GuestMemory for EptMemory {
+    fn copy(&mut self, from: u64, to: u64, length: u64) -> bool {
+        let (Some(from), Some(to)) = (self.host_address(from), self.host_address(to)) else {
+            return false;
+        };
+
+        // Safety: both ranges lie in a page the EPT maps, the guest may make them overlap.
+        unsafe { core::ptr::copy(from as *const u8, to as *mut u8, length as usize) };
+        true
+    }
+
+    fn read(&self, pa: u64, buffer: &mut [u8]) -> bool {
+        let Some(host) = self.host_address(pa) else {
+            return false;
+        };
+
+        // Safety: the page is mapped by the EPT, the guest may change it while it's read.
+        unsafe { core::ptr::copy(host as *const u8, buffer.as_mut_ptr(), buffer.len()) };
+        true
+    }
+
+    fn write(&mut self, pa: u64, bytes: &[u8]) -> bool {
+        let Some(host) = self.host_address(pa) else {
+            return false;
+        };
+
+        // Safety: the page is mapped by the EPT and not one of the hypervisor's host buffers.
+        unsafe { core::ptr::copy(bytes.as_ptr(), host as *mut u8, bytes.len()) };
+        true
+    }
+}
+
+/// One side of a copy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Span {
+    /// Guest virtual memory, translated with the page tables of `cr3`.
+    Virtual { cr3: u64, address: u64 },
+
+    /// Guest-physical memory.
+    Physical(u64),
+}
+
+impl Span {
+    /// Returns whether the span may hold `length` bytes: its addresses stay canonical or below 2^MAXPHYADDR.
+    ///
+    /// # Arguments
+    ///
+    /// * `paging` - The paging state of the guest.
+    /// * `length` - The bytes in the span.
+    pub fn holds(self, paging: &Paging, length: u64) -> bool {
+        match self {
+            Self::Virtual { address, .. } => length == 0 || paging.is_canonical_range(address, length),
+            Self::Physical(address) => address.checked_add(length).is_some_and(|end| end <= paging.physical_address_limit()),
+        }
+    }
+
+    /// Returns the guest-physical address of a byte of the span.
+    ///
+    /// # Arguments
+    ///
+    /// * `memory` - The memory the paging structures are in.
+    /// * `paging` - The paging state of the guest.
+    /// * `offset` - The offset of the byte in the span.
+    /// * `writable` - Whether the guest's page tables must allow writes to the byte.
+    ///
+    /// # Returns
+    ///
+    /// The address, `None` if the byte isn't mapped or not writable when it must be.
+    fn resolve(self, memory: &impl PhysicalMemory, paging: &Paging, offset: u64, writable: bool) -> Option<u64> {
+        match self {
+            Self::Virtual { cr3, address } => {
+                let walk = walk(memory, paging, cr3, address + offset).ok()?;
+                (walk.writable || !writable).then_some(walk.pa)
+            }
+            Self::Physical(address) => Some(address + offset),
+        }
+    }
+}
+
+/// Returns the bytes from a guest-physical address to the end of its 4-KByte page.
+fn page_remaining(pa: u64) -> u64 {
+    BASE_PAGE_SIZE as u64 - (pa & (BASE_PAGE_SIZE as u64 - 1))
+}
+
+/// Copies guest memory from one span to another.
+///
+/// # Arguments
+///
+/// * `memory` - The guest-physical memory.
+/// * `paging` - The paging state of the guest.
+/// * `source` - The span to read, both spans holding `length` bytes.
+/// * `destination` - The span to write.
+/// * `length` - The bytes to copy.
+/// * `force` - Whether to write the destination where the guest's page tables make it read-only.
+///
+/// # Returns
+///
+/// `Ok` once every byte is copied, else the bytes copied before the first page that isn't mapped or writable.
+pub fn copy(memory: &mut impl GuestMemory, paging: &Paging, source: Span, destination: Span, length: u64, force: bool) -> Result<(), u64> {
+    let mut copied = 0;
+
+    while copied < length {
+        let from = source.resolve(memory, paging, copied, false).ok_or(copied)?;
+        let to = destination.resolve(memory, paging, copied, !force).ok_or(copied)?;
+
+        let chunk = (length - copied).min(page_remaining(from)).min(page_remaining(to));
+        if !memory.copy(from, to, chunk) {
+            return Err(copied);
+        }
+        copied += chunk;
+    }
+
+    Ok(())
+}
+
+/// Reads guest memory into a host buffer.
+///
+/// # Arguments
+///
+/// * `memory` - The guest-physical memory.
+/// * `paging` - The paging state of the guest.
+/// * `source` - The span to read.
+/// * `buffer` - The buffer to fill.
+///
+/// # Returns
+///
+/// `Ok` once the buffer is filled, else the bytes read before the first page that isn't mapped.
+pub fn read(memory: &impl GuestMemory, paging: &Paging, source: Span, buffer: &mut [u8]) -> Result<(), u64> {
+    let mut done = 0;
+
+    while done < buffer.len() {
+        let from = source.resolve(memory, paging, done as u64, false).ok_or(done as u64)?;
+        let chunk = (buffer.len() - done).min(page_remaining(from) as usize);
+        if !memory.read(from, &mut buffer[done..done + chunk]) {
+            return Err(done as u64);
+        }
+        done += chunk;
+    }
+
+    Ok(())
+}
+
+/// Writes bytes of the host to guest memory, following the read/write bits of the guest's page tables.
+///
+/// # Arguments
+///
+/// * `memory` - The guest-physical memory.
+/// * `paging` - The paging state of the guest.
+/// * `destination` - The span to write.
+/// * `bytes` - The bytes to write.
+///
+/// # Returns
+///
+/// `Ok` once every byte is written, else the bytes written before the first page that isn't mapped or writable.
+pub fn write(memory: &mut impl GuestMemory, paging: &Paging, destination: Span, bytes: &[u8]) -> Result<(), u64> {
+    let mut done = 0;
+
+    while done < bytes.len() {
+        let to = destination.resolve(memory, paging, done as u64, true).ok_or(done as u64)?;
+        let chunk = (bytes.len() - done).min(page_remaining(to) as usize);
+        if !memory.write(to, &bytes[done..done + chunk]) {
+            return Err(done as u64);
+        }
+        done += chunk;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::intel::page_walk::{
+            tests::{FakeMemory, FOUR_LEVEL},
+            PRESENT, WRITABLE,
+        },
+    };
+
+    impl GuestMemory for FakeMemory {
+        fn copy(&mut self, from: u64, to: u64, length: u64) -> bool {
+            assert!(length <= page_remaining(from) && length <= page_remaining(to));
+            let (from, to, length) = (from as usize, to as usize, length as usize);
+            if from.max(to) + length > self.bytes.len() {
+                return false;
+            }
+            self.bytes.copy_within(from..from + length, to);
+            true
+        }
+
+        fn read(&self, pa: u64, buffer: &mut [u8]) -> bool {
+            assert!(buffer.len() as u64 <= page_remaining(pa));
+            let Some(bytes) = self.bytes.get(pa as usize..pa as usize + buffer.len()) else {
+                return false;
+            };
+            buffer.copy_from_slice(bytes);
+            true
+        }
+
+        fn write(&mut self, pa: u64, bytes: &[u8]) -> bool {
+            assert!(bytes.len() as u64 <= page_remaining(pa));
+            let Some(destination) = self.bytes.get_mut(pa as usize..pa as usize + bytes.len()) else {
+                return false;
+            };
+            destination.copy_from_slice(bytes);
+            true
+        }
+    }
+
+    const TABLES: [u64; 4] = [0x1000, 0x2000, 0x3000, 0x4000];
+
+    /// Page tables at `TABLES` mapping 0x10000 to the frame at 0x6000 and 0x11000 to the one at 0x5000, read-only.
+    fn two_pages() -> FakeMemory {
+        let mut memory = FakeMemory::new(16);
+        memory.map(&TABLES, 0x10000, 0x6000 | PRESENT | WRITABLE);
+        memory.map(&TABLES, 0x11000, 0x5000 | PRESENT);
+        memory
+    }
+
+    #[test]
+    fn copies_follow_pages_across_boundaries() {
+        let mut memory = two_pages();
+        memory.bytes[0x6F00..0x7000].fill(0xAA);
+        memory.bytes[0x5000..0x6000].fill(0xBB);
+
+        let source = Span::Virtual {
+            cr3: 0x1000,
+            address: 0x10F00,
+        };
+        assert_eq!(copy(&mut memory, &FOUR_LEVEL, source, Span::Physical(0x7000), 0x200, false), Ok(()));
+        assert!(memory.bytes[0x7000..0x7100].iter().all(|&byte| byte == 0xAA));
+        assert!(memory.bytes[0x7100..0x7200].iter().all(|&byte| byte == 0xBB));
+
+        // Past the second page nothing is mapped, the copy stops where the mapping does.
+        assert_eq!(copy(&mut memory, &FOUR_LEVEL, source, Span::Physical(0x8000), 0x1200, false), Err(0x1100));
+        assert!(memory.bytes[0x8100..0x9100].iter().all(|&byte| byte == 0xBB));
+        assert_eq!(copy(&mut memory, &FOUR_LEVEL, Span::Physical(0xFF00), Span::Physical(0x7000), 0x200, false), Err(0x100));
+    }
+
+    #[test]
+    fn read_only_pages_need_force() {
+        let mut memory = two_pages();
+        memory.bytes[0x7000..0x7200].fill(0xCC);
+
+        let destination = Span::Virtual {
+            cr3: 0x1000,
+            address: 0x10F00,
+        };
+        assert_eq!(copy(&mut memory, &FOUR_LEVEL, Span::Physical(0x7000), destination, 0x200, false), Err(0x100));
+        assert_eq!(memory.bytes[0x5000], 0);
+
+        assert_eq!(copy(&mut memory, &FOUR_LEVEL, Span::Physical(0x7000), destination, 0x200, true), Ok(()));
+        assert!(memory.bytes[0x5000..0x5100].iter().all(|&byte| byte == 0xCC));
+    }
+
+    #[test]
+    fn host_buffers_read_and_write_through_pages() {
+        let mut memory = two_pages();
+        memory.bytes[0x6FF0..0x7000].fill(0xAA);
+        memory.bytes[0x5000..0x5010].fill(0xBB);
+
+        let span = Span::Virtual {
+            cr3: 0x1000,
+            address: 0x10FF0,
+        };
+        let mut buffer = [0; 0x20];
+        assert_eq!(read(&memory, &FOUR_LEVEL, span, &mut buffer), Ok(()));
+        assert_eq!((buffer[0xF], buffer[0x10]), (0xAA, 0xBB));
+
+        // The second page is read-only, writes stop where it starts.
+        assert_eq!(write(&mut memory, &FOUR_LEVEL, span, &[0xCC; 0x20]), Err(0x10));
+        assert_eq!((memory.bytes[0x6FF0], memory.bytes[0x5000]), (0xCC, 0xBB));
+        assert_eq!(read(&memory, &FOUR_LEVEL, Span::Physical(0xFFF8), &mut buffer), Err(0x8));
+    }
+
+    #[test]
+    fn spans_reject_invalid_ranges() {
+        assert!(Span::Virtual {
+            cr3: 0,
+            address: 0x7FFF_FFFF_F000
+        }
+        .holds(&FOUR_LEVEL, 0x1000));
+        assert!(!Span::Virtual {
+            cr3: 0,
+            address: 0x7FFF_FFFF_F000
+        }
+        .holds(&FOUR_LEVEL, 0x1001));
+        assert!(Span::Virtual {
+            cr3: 0,
+            address: 0x8000_0000_0000
+        }
+        .holds(&FOUR_LEVEL, 0));
+
+        // Physical spans end at the MAXPHYADDR of the guest, never past 2^52.
+        let limit = FOUR_LEVEL.physical_address_limit();
+        assert_eq!(limit, 1 << 39);
+        assert!(Span::Physical(limit - 0x1000).holds(&FOUR_LEVEL, 0x1000));
+        assert!(!Span::Physical(limit - 0x1000).holds(&FOUR_LEVEL, 0x1001));
+        assert!(!Span::Physical(u64::MAX).holds(&FOUR_LEVEL, 2));
+        let wide = Paging {
+            physical_address_bits: 64,
+            ..FOUR_LEVEL
+        };
+        assert_eq!(wide.physical_address_limit(), 1 << 52);
+    }
+
+    #[test]
+    fn host_addresses_stay_in_the_host_map() {
+        assert!(within_host_map(0));
+        assert!(within_host_map(LOW_EPT_END - 0x1000));
+        assert!(!within_host_map(LOW_EPT_END));
+        assert!(!within_host_map(IDENTITY_MAP_END));
+
+        // The EPT walk would alias these to the first 512GB.
+        assert!(!within_host_map((1 << 48) | 0x1000));
+        assert!(!within_host_map(u64::MAX & !0xFFF));
+    }
+}
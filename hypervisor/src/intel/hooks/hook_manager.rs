@@ -3,18 +3,24 @@ use {
         error::HypervisorError,
         intel::{
             addresses::PhysicalAddress,
-            bitmap::{MsrAccessType, MsrBitmap, MsrOperation},
+            bitmap::{MsrBitmap, DEFAULT_MSR_POLICY},
             ept::AccessType,
+            guest_memory::EptMemory,
             hooks::{
+                hook_sync,
                 inline::{InlineHook, InlineHookType},
-                memory_manager::MemoryManager,
+                memory_manager::{HookInfo, MemoryManager},
+                patch_audit::{verify_prologue, BytePattern},
             },
-            invept::invept_all_contexts,
-            invvpid::invvpid_all_contexts,
+            page_walk::Paging,
+            regions::ContiguousPage,
+            shootdown::{self, Scope},
+            support::vmread,
             vm::Vm,
         },
         windows::{
-            nt::pe::{get_export_by_hash, get_image_base_address, get_size_of_image},
+            kernel::{locate, ExportCache, Kernel},
+            nt::pe::get_export_by_hash,
             ssdt::ssdt_hook::SsdtHook,
         },
     },
@@ -25,10 +31,13 @@ use {
     spin::Mutex,
     x86::{
         bits64::paging::{PAddr, BASE_PAGE_SIZE},
-        msr,
+        vmx::vmcs::guest,
     },
 };
 
+/// The most functions `install_inline_hook` hooks, each hooked page costs a shadow page and a 2MB page a page table.
+pub const MAX_INLINE_HOOKS: usize = 64;
+
 /// Enum representing different types of hooks that can be applied.
 #[derive(Debug, Clone, Copy)]
 pub enum EptHookType {
@@ -48,8 +57,8 @@ pub struct HookManager {
     /// The memory manager instance for the pre-allocated shadow pages and page tables.
     pub memory_manager: MemoryManager,
 
-    /// A bitmap for handling MSRs.
-    pub msr_bitmap: MsrBitmap,
+    /// A bitmap for handling MSRs, shared by the VMCS of every processor.
+    pub msr_bitmap: ContiguousPage<MsrBitmap>,
 
     /// The physical address of the dummy page used for hiding hypervisor memory.
     pub dummy_page_pa: u64,
@@ -63,6 +72,12 @@ pub struct HookManager {
     /// The size of ntoskrnl.exe.
     pub ntoskrnl_size: u64,
 
+    /// The CR3 the guest wrote LSTAR with, one mapping the kernel.
+    pub ntoskrnl_cr3: u64,
+
+    /// The exports of ntoskrnl.exe `resolve_kernel_export` resolved.
+    pub kernel_exports: ExportCache,
+
     /// A flag indicating whether the CPUID cache information has been called. This will be used to perform hooks at boot time when SSDT has been initialized.
     /// KiSetCacheInformation -> KiSetCacheInformationIntel -> KiSetStandardizedCacheInformation -> __cpuid(4, 0)
     pub has_cpuid_cache_info_been_called: bool,
@@ -82,14 +97,18 @@ lazy_static! {
     /// - `ntoskrnl_base_va`: Virtual address of the Windows kernel (ntoskrnl.exe).
     /// - `ntoskrnl_base_pa`: Physical address of the Windows kernel (ntoskrnl.exe).
     /// - `ntoskrnl_size`: Size of the Windows kernel (ntoskrnl.exe).
+    /// - `ntoskrnl_cr3`: CR3 of the kernel when the base was found.
+    /// - `kernel_exports`: Exports of the Windows kernel resolved so far.
     /// - `has_cpuid_cache_info_been_called`: Flag indicating whether the CPUID cache information has been called.
     pub static ref SHARED_HOOK_MANAGER: Mutex<HookManager> = Mutex::new(HookManager {
         memory_manager: MemoryManager::new(),
-        msr_bitmap: MsrBitmap::new(),
+        msr_bitmap: ContiguousPage::new(MsrBitmap::new()),
         dummy_page_pa: 0,
         ntoskrnl_base_va: 0,
         ntoskrnl_base_pa: 0,
         ntoskrnl_size: 0,
+        ntoskrnl_cr3: 0,
+        kernel_exports: ExportCache::new(),
         has_cpuid_cache_info_been_called: false,
         allocated_memory_ranges: Vec::with_capacity(128),
     });
@@ -108,14 +127,9 @@ impl HookManager {
     pub fn initialize_shared_hook_manager(dummy_page_pa: u64) {
         let mut hook_manager = SHARED_HOOK_MANAGER.lock();
         hook_manager.dummy_page_pa = dummy_page_pa;
-        trace!("Modifying MSR interception for LSTAR MSR write access");
-        hook_manager
-            .msr_bitmap
-            .modify_msr_interception(msr::IA32_LSTAR, MsrAccessType::Write, MsrOperation::Hook);
-
-        hook_manager
-            .msr_bitmap
-            .modify_msr_interception(msr::IA32_FEATURE_CONTROL, MsrAccessType::Read, MsrOperation::Hook);
+        hook_manager.msr_bitmap.init();
+        trace!("Applying the default MSR interception policy");
+        DEFAULT_MSR_POLICY.apply(&mut hook_manager.msr_bitmap);
     }
 
     /// Records a memory allocation for tracking purposes.
@@ -139,24 +153,62 @@ impl HookManager {
     ///
     /// # Arguments
     ///
-    /// * `guest_va` - The virtual address of the guest.
+    /// * `vm` - The virtual machine instance of the hypervisor.
+    /// * `guest_va` - A virtual address inside ntoskrnl.exe, the one the guest writes to LSTAR.
     ///
     /// # Returns
     ///
     /// * `Ok(())` - The kernel base and size were set successfully.
-    pub fn set_kernel_base_and_size(&mut self, guest_va: u64) -> Result<(), HypervisorError> {
-        // Get the base address of ntoskrnl.exe.
-        self.ntoskrnl_base_va = unsafe { get_image_base_address(guest_va)? };
+    pub fn set_kernel_base_and_size(&mut self, vm: &Vm, guest_va: u64) -> Result<(), HypervisorError> {
+        // Find the image of ntoskrnl.exe around the address, in the address space the kernel runs in.
+        let memory = EptMemory::current().ok_or(HypervisorError::GetKernelBaseFailed)?;
+        let kernel = locate(&memory, &Paging::current(vm), vmread(guest::CR3), guest_va).ok_or(HypervisorError::FailedToGetImageBaseAddress)?;
+
+        self.ntoskrnl_base_va = kernel.base;
+        self.ntoskrnl_size = kernel.size;
+
+        // Keep the address space the kernel wrote LSTAR in, user-mode CR3s may not map the kernel.
+        self.ntoskrnl_cr3 = kernel.cr3;
 
         // Get the physical address of ntoskrnl.exe using GUEST_CR3 and the virtual address.
         self.ntoskrnl_base_pa = PhysicalAddress::pa_from_va_with_current_cr3(self.ntoskrnl_base_va)?;
 
-        // Get the size of ntoskrnl.exe.
-        self.ntoskrnl_size = unsafe { get_size_of_image(self.ntoskrnl_base_pa as _).ok_or(HypervisorError::FailedToGetKernelSize)? } as u64;
+        // Exports resolved before are those of another kernel.
+        self.kernel_exports.flush();
 
         Ok(())
     }
 
+    /// Returns the address of an export of ntoskrnl.exe, once `set_kernel_base_and_size` found the kernel.
+    ///
+    /// The export directory is read through the page tables of the kernel with every offset checked, and the
+    /// address is cached for the next lookup.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the hypervisor.
+    /// * `name` - The name of the export, such as `NtCreateFile`.
+    ///
+    /// # Returns
+    ///
+    /// The virtual address of the export.
+    pub fn resolve_kernel_export(&mut self, vm: &Vm, name: &str) -> Result<u64, HypervisorError> {
+        if self.ntoskrnl_base_va == 0 {
+            return Err(HypervisorError::GetKernelBaseFailed);
+        }
+
+        let kernel = Kernel {
+            base: self.ntoskrnl_base_va,
+            size: self.ntoskrnl_size,
+            cr3: self.ntoskrnl_cr3,
+        };
+        let memory = EptMemory::current().ok_or(HypervisorError::FailedToGetExport)?;
+
+        self.kernel_exports
+            .resolve(&memory, &Paging::current(vm), kernel, name.as_bytes())
+            .map_err(|_| HypervisorError::FailedToGetExport)
+    }
+
     /// Manages an EPT hook for a kernel function, enabling or disabling it.
     ///
     /// # Arguments
@@ -260,27 +312,18 @@ impl HookManager {
 
         trace!("Dummy page PA: {:#x}", dummy_page_pa);
 
-        trace!("Mapping large page");
-        // Map the large page to the pre-allocated page table, if it hasn't been mapped already.
-        self.memory_manager.map_large_page_to_pt(guest_large_page_pa.as_u64())?;
-
-        let pre_alloc_pt = self
-            .memory_manager
-            .get_page_table_as_mut(guest_large_page_pa.as_u64())
-            .ok_or(HypervisorError::PageTableNotFound)?;
-
         // Check if a guest page has already been split.
         if vm.primary_ept.is_large_page(guest_page_pa.as_u64()) {
             trace!("Splitting 2MB page to 4KB pages for Primary EPT: {:#x}", guest_large_page_pa);
-            vm.primary_ept.split_2mb_to_4kb(guest_large_page_pa.as_u64(), pre_alloc_pt)?;
+            vm.primary_ept.split_2mb(guest_large_page_pa.as_u64())?;
         }
 
         trace!("Swapping guest page: {:#x} with dummy page: {:#x}", guest_page_pa.as_u64(), dummy_page_pa);
-        vm.primary_ept
-            .swap_page(guest_page_pa.as_u64(), dummy_page_pa, page_permissions, pre_alloc_pt)?;
+        vm.primary_ept.swap_page(guest_page_pa.as_u64(), dummy_page_pa, page_permissions)?;
+        vm.ept_views.sync(&vm.primary_ept, guest_page_pa.as_u64())?;
 
-        invept_all_contexts();
-        invvpid_all_contexts();
+        // Every processor hides the memory in its own EPT.
+        shootdown::flush_ept(Scope::CurrentProcessor);
 
         trace!("EPT hide hypervisor memory completed successfully");
 
@@ -289,45 +332,168 @@ impl HookManager {
 
     /// Installs an EPT hook for a function.
     ///
-    /// # Steps:
-    /// 1. Map the large page to the pre-allocated page table, if it hasn't been mapped already.
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the hypervisor.
+    /// * `guest_function_va` - The virtual address of the function or page to be hooked.
+    /// * `function_hash` - The hash of the function to be hooked.
+    /// * `ept_hook_type` - The type of EPT hook to be installed.
+    ///
+    /// # Returns
+    ///
+    /// * Returns `Ok(())` if the hook was successfully installed, `Err(HypervisorError)` otherwise.
+    pub fn ept_hook_function(
+        &mut self,
+        vm: &mut Vm,
+        guest_function_va: u64,
+        function_hash: u32,
+        ept_hook_type: EptHookType,
+    ) -> Result<(), HypervisorError> {
+        debug!("Creating EPT hook for function at VA: {:#x}", guest_function_va);
+
+        let guest_function_pa = PhysicalAddress::pa_from_va_with_current_cr3(guest_function_va)?;
+        self.install_ept_hook(vm, guest_function_va, guest_function_pa, function_hash, ept_hook_type)
+    }
+
+    /// Hooks a guest function with a jump to a handler, invisibly to code that reads the function.
+    ///
+    /// The jump is patched into a copy of the page only. Instruction fetches from the page see the copy,
+    /// reads and writes see the pristine guest page, so integrity checks comparing the bytes pass. The
+    /// handler is guest code that calls the original function through a trampoline of its own, which
+    /// executes the overwritten instructions and jumps back to `guest_va + inline::INLINE_JMP_SIZE`.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the hypervisor.
+    /// * `guest_va` - The virtual address of the function to hook.
+    /// * `cr3` - The guest CR3 mapping `guest_va`.
+    /// * `handler_trampoline` - The guest virtual address the function jumps to.
+    /// * `expected` - The pattern the function must start with, see `patch_audit`, `None` to hook any code.
+    ///
+    /// # Returns
+    ///
+    /// * `Err(HypervisorError::TooManyHooks)` - `MAX_INLINE_HOOKS` hooks are installed.
+    /// * `Err(HypervisorError::HookCrossesPageBoundary)` - The jump does not fit into the page of the function.
+    /// * `Err(HypervisorError::UnexpectedPrologue)` - The function does not match `expected`.
+    pub fn install_inline_hook(
+        &mut self,
+        vm: &mut Vm,
+        guest_va: u64,
+        cr3: u64,
+        handler_trampoline: u64,
+        expected: Option<&BytePattern>,
+    ) -> Result<(), HypervisorError> {
+        debug!("Installing inline hook at VA: {:#x} to handler: {:#x}", guest_va, handler_trampoline);
+
+        if self.memory_manager.hook_count() >= MAX_INLINE_HOOKS {
+            return Err(HypervisorError::TooManyHooks);
+        }
+
+        let ept_hook_type = EptHookType::Function(InlineHookType::Jmp(handler_trampoline));
+        if PAddr::from(guest_va).base_page_offset() as usize + Self::hook_size(ept_hook_type) > BASE_PAGE_SIZE {
+            return Err(HypervisorError::HookCrossesPageBoundary);
+        }
+
+        let guest_function_pa = PhysicalAddress::pa_from_va_with_explicit_cr3(guest_va, cr3)?;
+        if let Some(expected) = expected {
+            verify_prologue(guest_function_pa, expected)?;
+        }
+        self.install_ept_hook(vm, guest_va, guest_function_pa, 0, ept_hook_type)
+    }
+
+    /// Removes a hook `install_inline_hook` installed.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the hypervisor.
+    /// * `guest_va` - The virtual address of the hooked function.
+    /// * `cr3` - The guest CR3 mapping `guest_va`.
+    ///
+    /// # Returns
     ///
-    /// 2. Check if the large page has already been split. If not, split it into 4KB pages.
+    /// * `Err(HypervisorError::HookNotFound)` - The function is not hooked.
+    pub fn uninstall_inline_hook(&mut self, vm: &mut Vm, guest_va: u64, cr3: u64) -> Result<(), HypervisorError> {
+        debug!("Uninstalling inline hook at VA: {:#x}", guest_va);
+
+        let guest_function_pa = PhysicalAddress::pa_from_va_with_explicit_cr3(guest_va, cr3)?;
+        self.remove_ept_hook(vm, guest_function_pa)
+    }
+
+    /// Returns the installed hooks, in the order of the pages they are on.
+    pub fn inline_hooks(&self) -> impl Iterator<Item = &HookInfo> {
+        self.memory_manager.hooks()
+    }
+
+    /// Hooks a routine ntoskrnl.exe exports, once `set_kernel_base_and_size` found the kernel.
+    ///
+    /// For example, to redirect `NtQuerySystemInformation` to a handler the guest allocated:
+    ///
+    /// `hook_manager.hook_kernel_export(vm, djb2_hash("NtQuerySystemInformation".as_bytes()), handler_va)?`
+    ///
+    /// # Arguments
     ///
-    /// 3. Check if the guest page is already processed. If not, map the guest page to the shadow page.
+    /// * `vm` - The virtual machine instance of the hypervisor.
+    /// * `function_hash` - The `djb2_hash` of the export name.
+    /// * `handler_trampoline` - The guest virtual address the routine jumps to.
+    ///
+    /// # Returns
+    ///
+    /// The virtual address of the hooked routine.
+    pub fn hook_kernel_export(&mut self, vm: &mut Vm, function_hash: u32, handler_trampoline: u64) -> Result<u64, HypervisorError> {
+        if self.ntoskrnl_base_va == 0 {
+            return Err(HypervisorError::GetKernelBaseFailed);
+        }
+
+        let function_va = unsafe { get_export_by_hash(self.ntoskrnl_base_pa as _, self.ntoskrnl_base_va as _, function_hash) }
+            .ok_or(HypervisorError::FailedToGetExport)? as u64;
+        trace!("Export {:#x} found at VA: {:#x}", function_hash, function_va);
+
+        // The kernel is mapped in every address space, the current one will do.
+        self.install_inline_hook(vm, function_va, vmread(guest::CR3), handler_trampoline, None)?;
+
+        Ok(function_va)
+    }
+
+    /// Installs an EPT hook for a function at a known guest physical address.
+    ///
+    /// # Steps:
+    /// 1. Check if the large page has already been split. If not, split it into 4KB pages.
+    ///
+    /// 2. Check if the guest page is already processed. If not, map the guest page to the shadow page.
     ///    Ensure the memory manager maintains a set of processed guest pages to track this mapping.
     ///
-    /// 4. Copy the guest page to the shadow page if it hasn't been copied already, ensuring the
-    ///    shadow page contains the original function code.
+    /// 3. Copy the guest page to the shadow page if it hasn't been copied already, ensuring the
+    ///    shadow page contains the original function code. A clean copy is kept for `patch_audit`.
     ///
-    /// 5. Install the inline hook at the shadow function address if the hook type is `Function`.
+    /// 4. Install the inline hook at the shadow function address if the hook type is `Function`.
     ///
-    /// 6. Change the permissions of the guest page to read-write only.
+    /// 5. Build the execute and read-write views of the page, and publish them for every processor to map the
+    ///    read-write one.
     ///
-    /// 7. Invalidate the EPT and VPID contexts to ensure the changes take effect.
+    /// 6. Invalidate the EPT and VPID contexts to ensure the changes take effect.
     ///
-    /// These operations are performed only once per guest page to avoid overwriting existing hooks on the same page.
+    /// Further hooks on a processed page are patched into its shadow page, hooking the same function twice is a no-op.
     ///
     /// # Arguments
     ///
     /// * `vm` - The virtual machine instance of the hypervisor.
     /// * `guest_function_va` - The virtual address of the function or page to be hooked.
+    /// * `guest_function_pa` - The physical address of the function or page to be hooked.
     /// * `function_hash` - The hash of the function to be hooked.
     /// * `ept_hook_type` - The type of EPT hook to be installed.
     ///
     /// # Returns
     ///
     /// * Returns `Ok(())` if the hook was successfully installed, `Err(HypervisorError)` otherwise.
-    pub fn ept_hook_function(
+    fn install_ept_hook(
         &mut self,
         vm: &mut Vm,
         guest_function_va: u64,
+        guest_function_pa: u64,
         function_hash: u32,
         ept_hook_type: EptHookType,
     ) -> Result<(), HypervisorError> {
-        debug!("Creating EPT hook for function at VA: {:#x}", guest_function_va);
-
-        let guest_function_pa = PAddr::from(PhysicalAddress::pa_from_va_with_current_cr3(guest_function_va)?);
+        let guest_function_pa = PAddr::from(guest_function_pa);
         debug!("Guest function PA: {:#x}", guest_function_pa.as_u64());
 
         let guest_page_pa = guest_function_pa.align_down_to_base_page();
@@ -336,81 +502,84 @@ impl HookManager {
         let guest_large_page_pa = guest_function_pa.align_down_to_large_page();
         debug!("Guest large page PA: {:#x}", guest_large_page_pa.as_u64());
 
-        // 1. Map the large page to the pre-allocated page table, if it hasn't been mapped already.
-        // We must map the large page to the pre-allocated page table before accessing it.
-        debug!("Mapping large page");
-        self.memory_manager.map_large_page_to_pt(guest_large_page_pa.as_u64())?;
+        if self
+            .memory_manager
+            .get_hook_info_by_function_pa(guest_page_pa.as_u64(), guest_function_pa.as_u64())
+            .is_some()
+        {
+            debug!("Function already hooked, skipping hook installation.");
+            return Ok(());
+        }
 
-        // 2. Check if the large page has already been split. If not, split it into 4KB pages.
+        // 1. Check if the large page has already been split. If not, split it into 4KB pages.
+        // The first hook in a 2MB page splits it, later ones reuse the page table.
         debug!("Checking if large page has already been split");
         if vm.primary_ept.is_large_page(guest_page_pa.as_u64()) {
-            // We must map the large page to the pre-allocated page table before accessing it.
-            let pre_alloc_pt = self
-                .memory_manager
-                .get_page_table_as_mut(guest_large_page_pa.as_u64())
-                .ok_or(HypervisorError::PageTableNotFound)?;
-
             debug!("Splitting 2MB page to 4KB pages for Primary EPT: {:#x}", guest_large_page_pa);
-            vm.primary_ept.split_2mb_to_4kb(guest_large_page_pa.as_u64(), pre_alloc_pt)?;
+            vm.primary_ept.split_2mb(guest_large_page_pa.as_u64())?;
         }
 
-        // 3. Check if the guest page is already processed. If not, map the guest page to the shadow page.
+        // 2. Check if the guest page is already processed. If not, map the guest page to the shadow page.
         // Ensure the memory manager maintains a set of processed guest pages to track this mapping.
-        if !self.memory_manager.is_guest_page_processed(guest_page_pa.as_u64()) {
-            // We must map the guest page to the shadow page before accessing it.
-            debug!("Mapping guest page and shadow page");
-            self.memory_manager.map_guest_to_shadow_page(
-                guest_page_pa.as_u64(),
-                guest_function_va,
-                guest_function_pa.as_u64(),
-                ept_hook_type,
-                function_hash,
-            )?;
-
-            // We must map the guest page to the shadow page before accessing it.
-            let shadow_page_pa = PAddr::from(
-                self.memory_manager
-                    .get_shadow_page_as_ptr(guest_page_pa.as_u64())
-                    .ok_or(HypervisorError::ShadowPageNotFound)?,
-            );
-
-            // 4. Copy the guest page to the shadow page if it hasn't been copied already, ensuring the shadow page contains the original function code.
+        let first_hook_on_page = !self.memory_manager.is_guest_page_processed(guest_page_pa.as_u64());
+
+        // We must map the guest page to the shadow page before accessing it.
+        debug!("Mapping guest page and shadow page");
+        self.memory_manager.map_guest_to_shadow_page(
+            guest_page_pa.as_u64(),
+            guest_function_va,
+            guest_function_pa.as_u64(),
+            ept_hook_type,
+            function_hash,
+        )?;
+
+        let shadow_page_pa = PAddr::from(
+            self.memory_manager
+                .get_shadow_page_as_ptr(guest_page_pa.as_u64())
+                .ok_or(HypervisorError::ShadowPageNotFound)?,
+        );
+
+        // 3. Copy the guest page to the shadow page if it hasn't been copied already, ensuring the shadow page contains the original function code.
+        if first_hook_on_page {
             debug!("Copying guest page to shadow page: {:#x}", guest_page_pa.as_u64());
             Self::unsafe_copy_guest_to_shadow(guest_page_pa, shadow_page_pa);
 
-            // 5. Install the inline hook at the shadow function address if the hook type is `Function`.
-            match ept_hook_type {
-                EptHookType::Function(inline_hook_type) => {
-                    let shadow_function_pa = PAddr::from(Self::calculate_function_offset_in_host_shadow_page(shadow_page_pa, guest_function_pa));
-                    debug!("Shadow Function PA: {:#x}", shadow_function_pa);
-
-                    debug!("Installing inline hook at shadow function PA: {:#x}", shadow_function_pa.as_u64());
-                    InlineHook::new(shadow_function_pa.as_u64() as *mut u8, inline_hook_type).detour64();
-                }
-                EptHookType::Page => {
-                    unimplemented!("Page hooks are not yet implemented");
-                }
-            }
-
-            let pre_alloc_pt = self
+            let clean_page_pa = self
                 .memory_manager
-                .get_page_table_as_mut(guest_large_page_pa.as_u64())
-                .ok_or(HypervisorError::PageTableNotFound)?;
+                .get_clean_page_as_ptr(guest_page_pa.as_u64())
+                .ok_or(HypervisorError::ShadowPageNotFound)?;
+            Self::unsafe_copy_guest_to_shadow(guest_page_pa, PAddr::from(clean_page_pa));
+        }
 
-            // 6. Change the permissions of the guest page to read-write only.
-            debug!("Changing Primary EPT permissions for page to Read-Write (RW) only: {:#x}", guest_page_pa);
-            vm.primary_ept
-                .modify_page_permissions(guest_page_pa.as_u64(), AccessType::READ_WRITE, pre_alloc_pt)?;
+        // 4. Install the inline hook at the shadow function address if the hook type is `Function`.
+        match ept_hook_type {
+            EptHookType::Function(inline_hook_type) => {
+                let shadow_function_pa = PAddr::from(Self::calculate_function_offset_in_host_shadow_page(shadow_page_pa, guest_function_pa));
+                debug!("Shadow Function PA: {:#x}", shadow_function_pa);
 
-            // 7. Invalidate the EPT and VPID contexts to ensure the changes take effect.
-            invept_all_contexts();
-            invvpid_all_contexts();
+                debug!("Installing inline hook at shadow function PA: {:#x}", shadow_function_pa.as_u64());
+                InlineHook::new(shadow_function_pa.as_u64() as *mut u8, inline_hook_type).detour64();
+            }
+            EptHookType::Page => {
+                unimplemented!("Page hooks are not yet implemented");
+            }
+        }
 
-            debug!("EPT hook created and enabled successfully");
-        } else {
-            debug!("Guest page already processed, skipping hook installation and permission modification.");
+        if first_hook_on_page {
+            // 5. Build the execute and read-write views of the page, every processor maps the read-write one.
+            // The execute view the #VE handler switches to maps the execute one for good.
+            debug!("Changing EPT permissions for page to Read-Write (RW) only: {:#x}", guest_page_pa);
+            let views = vm.primary_ept.hook_views(guest_page_pa.as_u64(), shadow_page_pa.as_u64())?;
+            self.memory_manager.set_hook_views(guest_page_pa.as_u64(), views)?;
+            hook_sync::publish(&self.memory_manager);
+            hook_sync::sync(vm)?;
         }
 
+        // 6. Have the other processors apply the hook and invalidate their EPT contexts, which covers every VPID.
+        shootdown::flush_ept(Scope::AllProcessors);
+
+        debug!("EPT hook created and enabled successfully");
+
         Ok(())
     }
 
@@ -428,28 +597,67 @@ impl HookManager {
     pub fn ept_unhook_function(&mut self, vm: &mut Vm, guest_function_va: u64, _ept_hook_type: EptHookType) -> Result<(), HypervisorError> {
         debug!("Removing EPT hook for function at VA: {:#x}", guest_function_va);
 
-        let guest_function_pa = PAddr::from(PhysicalAddress::pa_from_va_with_current_cr3(guest_function_va)?);
+        let guest_function_pa = PhysicalAddress::pa_from_va_with_current_cr3(guest_function_va)?;
+        self.remove_ept_hook(vm, guest_function_pa)
+    }
+
+    /// Removes an EPT hook for a function at a known guest physical address.
+    ///
+    /// Other hooks on the page stay, only the bytes of this one are restored in the shadow page. The last
+    /// hook of a page maps the guest page again, and the last one of a 2MB page merges it back.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the hypervisor.
+    /// * `guest_function_pa` - The physical address of the function or page to be unhooked.
+    ///
+    /// # Returns
+    ///
+    /// * Returns `Ok(())` if the hook was successfully removed, `Err(HypervisorError)` otherwise.
+    fn remove_ept_hook(&mut self, vm: &mut Vm, guest_function_pa: u64) -> Result<(), HypervisorError> {
+        let guest_function_pa = PAddr::from(guest_function_pa);
         debug!("Guest function PA: {:#x}", guest_function_pa.as_u64());
 
         let guest_page_pa = guest_function_pa.align_down_to_base_page();
         debug!("Guest page PA: {:#x}", guest_page_pa.as_u64());
 
-        let guest_large_page_pa = guest_function_pa.align_down_to_large_page();
-        debug!("Guest large page PA: {:#x}", guest_large_page_pa.as_u64());
-
-        let pre_alloc_pt = self
+        let hook_info = self
             .memory_manager
-            .get_page_table_as_mut(guest_large_page_pa.as_u64())
-            .ok_or(HypervisorError::PageTableNotFound)?;
+            .get_hook_info_by_function_pa(guest_page_pa.as_u64(), guest_function_pa.as_u64())
+            .cloned()
+            .ok_or(HypervisorError::HookNotFound)?;
 
-        // Swap the page back and restore the original page permissions
-        vm.primary_ept
-            .swap_page(guest_page_pa.as_u64(), guest_page_pa.as_u64(), AccessType::READ_WRITE_EXECUTE, pre_alloc_pt)?;
+        if self
+            .memory_manager
+            .get_hook_info(guest_page_pa.as_u64())
+            .is_some_and(|hooks| hooks.len() > 1)
+        {
+            // Other hooks on the page stay, restore the original bytes of this one in the shadow page.
+            let shadow_page_pa = PAddr::from(
+                self.memory_manager
+                    .get_shadow_page_as_ptr(guest_page_pa.as_u64())
+                    .ok_or(HypervisorError::ShadowPageNotFound)?,
+            );
+            let shadow_function_pa = Self::calculate_function_offset_in_host_shadow_page(shadow_page_pa, guest_function_pa);
+            unsafe {
+                copy_nonoverlapping(guest_function_pa.as_u64() as *const u8, shadow_function_pa as *mut u8, Self::hook_size(hook_info.ept_hook_type))
+            };
+            self.memory_manager.remove_hook(guest_page_pa.as_u64(), guest_function_pa.as_u64())?;
+            return Ok(());
+        }
 
         // Update the memory manager to indicate that the guest page is no longer processed (unmapped/unhooked).
         // This will allow the page to be reprocessed/remapped/rehooked if needed.
         self.memory_manager.unmap_guest_from_shadow_page(guest_page_pa.as_u64())?;
 
+        // Every processor maps the guest page again with the entry it had before the first hook, and the 2MB page
+        // with a large page once its last hook is gone.
+        hook_sync::publish(&self.memory_manager);
+        hook_sync::sync(vm)?;
+
+        // The other processors may still run the guest with the shadow page.
+        shootdown::flush_ept(Scope::AllProcessors);
+
         Ok(())
     }
 
@@ -0,0 +1,326 @@
+//! Applies the EPT hooks of `SHARED_HOOK_MANAGER` to the EPTs of every processor.
+//!
+//! Every processor has its own primary EPT and views, see `Ept`. The hook manager changes its hooked pages on the
+//! processor a hook is installed or removed from, publishes the views of all of them here and requests a
+//! shootdown. Each processor applies the published pages to its own EPTs whenever it handles its mailbox, right
+//! after a VM exit and right before a VM entry, so once `shootdown::flush_ept` returns no processor runs the guest
+//! without the change. A processor started later applies every hook before its first VM entry.
+//!
+//! A page a processor fails to hook or unhook is left as it was and retried by its next `sync`, only the first
+//! failure for a publication is logged. The processor doesn't acknowledge the shootdown until every page applied.
+//!
+//! The published pages have a lock of their own, which is taken after `SHARED_HOOK_MANAGER` wherever both are. An
+//! initiator holds the hook manager while it waits for the other processors, they only take this one.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            ept::HookViews,
+            hooks::memory_manager::MemoryManager,
+            shootdown::{self, Scope},
+            ve::EXECUTE_VIEW,
+            vm::Vm,
+        },
+    },
+    alloc::{collections::BTreeMap, vec::Vec},
+    core::sync::atomic::{AtomicU64, Ordering},
+    log::*,
+    spin::Mutex,
+    x86::bits64::paging::{PAddr, BASE_PAGE_SHIFT, LARGE_PAGE_SIZE},
+};
+
+/// The views of all hooked pages by guest physical address, as the hook manager published them last.
+static PUBLISHED: Mutex<BTreeMap<u64, HookViews>> = Mutex::new(BTreeMap::new());
+
+/// The times the hooked pages were published, `0` before the first time.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// The hooked pages applied to the EPTs of one processor.
+#[derive(Debug, Clone)]
+pub struct AppliedHooks {
+    /// The publication the pages match.
+    generation: u64,
+
+    /// The publication a page failed to be applied for, its failures aren't logged again.
+    failed: u64,
+
+    /// The views of the pages in the EPTs of the processor, with the entries they had before.
+    pages: BTreeMap<u64, HookViews>,
+}
+
+impl AppliedHooks {
+    /// Creates the state of a processor that applied no hook yet.
+    pub const fn new() -> Self {
+        Self {
+            generation: 0,
+            failed: 0,
+            pages: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the views of a hooked page in the EPTs of the processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_page_pa` - The guest physical address of the page.
+    pub fn views(&self, guest_page_pa: u64) -> Option<HookViews> {
+        self.pages.get(&guest_page_pa).copied()
+    }
+
+    /// Returns the views of all hooked pages in the EPTs of the processor, with the address of the page.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, HookViews)> + '_ {
+        self.pages.iter().map(|(&guest_page_pa, &views)| (guest_page_pa, views))
+    }
+
+    /// Checks if any page within a 2MB page is hooked in the EPTs of the processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_large_page_pa` - The guest physical address of the 2MB page.
+    fn has_pages_in_large_page(&self, guest_large_page_pa: u64) -> bool {
+        let end = guest_large_page_pa + LARGE_PAGE_SIZE as u64;
+        self.pages.range(guest_large_page_pa..end).next().is_some()
+    }
+}
+
+impl Default for AppliedHooks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The pages a processor must unhook and hook to match the published ones.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Changes {
+    /// The pages no longer hooked, or hooked with another shadow page.
+    removed: Vec<u64>,
+
+    /// The pages newly hooked, or hooked with another shadow page, with their published views.
+    added: Vec<(u64, HookViews)>,
+}
+
+impl Changes {
+    /// Returns whether the processor already matches the published pages.
+    fn is_empty(&self) -> bool {
+        self.removed.is_empty() && self.added.is_empty()
+    }
+}
+
+/// Compares the pages applied to a processor with the published ones.
+///
+/// # Arguments
+///
+/// * `applied` - The pages applied to the processor.
+/// * `published` - The published pages.
+fn changes(applied: &BTreeMap<u64, HookViews>, published: &BTreeMap<u64, HookViews>) -> Changes {
+    let same = |local: &HookViews, views: &HookViews| local.execute == views.execute && local.read_write == views.read_write;
+
+    Changes {
+        removed: applied
+            .iter()
+            .filter(|(page, local)| !published.get(page).is_some_and(|views| same(local, views)))
+            .map(|(&page, _)| page)
+            .collect(),
+        added: published
+            .iter()
+            .filter(|(page, views)| !applied.get(page).is_some_and(|local| same(local, views)))
+            .map(|(&page, &views)| (page, views))
+            .collect(),
+    }
+}
+
+/// Publishes the views of all hooked pages of the hook manager, after it changed them.
+///
+/// # Arguments
+///
+/// * `memory_manager` - The memory manager of `SHARED_HOOK_MANAGER`, which the caller holds.
+pub fn publish(memory_manager: &MemoryManager) {
+    let mut published = PUBLISHED.lock();
+    *published = memory_manager.views().collect();
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Applies the published hooks to the EPTs of the current processor, if they changed since it last did.
+///
+/// Pages that fail are left as they were and retried by the next call, the others are applied anyway.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+///
+/// # Returns
+///
+/// `Ok(true)` if the EPTs changed, `Ok(false)` if they already matched, or the `HypervisorError` of the first page
+/// that couldn't be hooked or unhooked.
+pub fn sync(vm: &mut Vm) -> Result<bool, HypervisorError> {
+    if vm.applied_hooks.generation == GENERATION.load(Ordering::SeqCst) {
+        return Ok(false);
+    }
+
+    let published = PUBLISHED.lock();
+    let generation = GENERATION.load(Ordering::SeqCst);
+    let changes = changes(&vm.applied_hooks.pages, &published);
+    drop(published);
+
+    // Only the first failure for a publication is logged, the retries would repeat it before every VM entry.
+    let quiet = vm.applied_hooks.failed == generation;
+    let apic_id = vm.apic_id;
+    let mut error = None;
+    let mut fail = |guest_page_pa: u64, e: HypervisorError| {
+        if !quiet {
+            error!("Failed to apply the EPT hook of {:#x} on processor {}: {:?}", guest_page_pa, apic_id, e);
+        }
+        error.get_or_insert(e);
+    };
+
+    for &guest_page_pa in &changes.removed {
+        trace!("Unhooking {:#x} on processor {}", guest_page_pa, vm.apic_id);
+        if let Err(e) = unhook_page(vm, guest_page_pa) {
+            fail(guest_page_pa, e);
+        }
+    }
+
+    for &(guest_page_pa, views) in &changes.added {
+        trace!("Hooking {:#x} on processor {}", guest_page_pa, vm.apic_id);
+        match hook_page(vm, guest_page_pa, views) {
+            Ok(local) => {
+                vm.applied_hooks.pages.insert(guest_page_pa, local);
+            }
+            Err(e) => fail(guest_page_pa, e),
+        }
+    }
+
+    if !changes.is_empty() {
+        shootdown::flush_ept(Scope::CurrentProcessor);
+    }
+
+    match error {
+        None => {
+            vm.applied_hooks.generation = generation;
+            Ok(!changes.is_empty())
+        }
+        Some(e) => {
+            vm.applied_hooks.failed = generation;
+            Err(e)
+        }
+    }
+}
+
+/// Maps the read-write view of a hooked page in the primary EPT of the current processor, and the execute one in
+/// its execute view.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+/// * `guest_page_pa` - The guest physical address of the page.
+/// * `views` - The published views of the page.
+///
+/// # Returns
+///
+/// The views of the page with the entry it had before on this processor.
+fn hook_page(vm: &mut Vm, guest_page_pa: u64, views: HookViews) -> Result<HookViews, HypervisorError> {
+    if vm.primary_ept.is_large_page(guest_page_pa) {
+        vm.primary_ept.split_2mb(PAddr::from(guest_page_pa).align_down_to_large_page().as_u64())?;
+    }
+
+    // The entry before the hook is the one of this processor, the views are the same on all of them.
+    let local = HookViews {
+        original: vm.primary_ept.hook_views(guest_page_pa, views.execute.pfn() << BASE_PAGE_SHIFT)?.original,
+        ..views
+    };
+    vm.primary_ept.set_page_entry(guest_page_pa, local.read_write)?;
+    if vm.ept_views.has_views() {
+        // The next try takes the entry before the hook from the primary EPT again.
+        let overridden = vm
+            .ept_views
+            .view_mut(EXECUTE_VIEW)
+            .and_then(|view| view.set_override(&vm.primary_ept, guest_page_pa, local.execute));
+        if let Err(e) = overridden {
+            vm.primary_ept.set_page_entry(guest_page_pa, local.original)?;
+            return Err(e);
+        }
+    }
+
+    Ok(local)
+}
+
+/// Maps a page that is no longer hooked like before its first hook in the EPTs of the current processor.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+/// * `guest_page_pa` - The guest physical address of the page.
+fn unhook_page(vm: &mut Vm, guest_page_pa: u64) -> Result<(), HypervisorError> {
+    let Some(local) = vm.applied_hooks.views(guest_page_pa) else {
+        return Ok(());
+    };
+
+    // The execute view maps the page like the primary EPT again, and shares the 2MB page once nothing overrides it.
+    vm.primary_ept.set_page_entry(guest_page_pa, local.original)?;
+    if vm.ept_views.has_views() {
+        vm.ept_views.view_mut(EXECUTE_VIEW)?.remove_override(&vm.primary_ept, guest_page_pa)?;
+    }
+    vm.applied_hooks.pages.remove(&guest_page_pa);
+
+    // Map the 2MB page with a large page again once its last hook is gone.
+    let guest_large_page_pa = PAddr::from(guest_page_pa).align_down_to_large_page().as_u64();
+    if !vm.applied_hooks.has_pages_in_large_page(guest_large_page_pa) {
+        match vm.primary_ept.merge_back(guest_large_page_pa) {
+            // The views may reference the freed page table.
+            Ok(()) => vm.ept_views.sync(&vm.primary_ept, guest_large_page_pa)?,
+            Err(e) => debug!("Keeping the 4KB pages of {:#x}: {:?}", guest_large_page_pa, e),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::intel::ept::Entry};
+
+    fn views(shadow_pa: u64, guest_pa: u64) -> HookViews {
+        HookViews {
+            execute: Entry::table(shadow_pa),
+            read_write: Entry::table(guest_pa),
+            original: Entry::table(0),
+        }
+    }
+
+    #[test]
+    fn changes_unhook_and_hook_the_differing_pages() {
+        let applied = BTreeMap::from([
+            (0x1000, views(0xA000, 0x1000)),
+            (0x2000, views(0xB000, 0x2000)),
+            (0x3000, views(0xC000, 0x3000)),
+        ]);
+        let published = BTreeMap::from([
+            (0x2000, views(0xB000, 0x2000)),
+            (0x3000, views(0xD000, 0x3000)),
+            (0x4000, views(0xE000, 0x4000)),
+        ]);
+
+        let changes = changes(&applied, &published);
+        assert_eq!(changes.removed, [0x1000, 0x3000]);
+        assert_eq!(changes.added, [(0x3000, views(0xD000, 0x3000)), (0x4000, views(0xE000, 0x4000))]);
+    }
+
+    #[test]
+    fn changes_are_empty_for_the_published_pages() {
+        let pages = BTreeMap::from([(0x1000, views(0xA000, 0x1000))]);
+
+        assert!(changes(&pages, &pages.clone()).is_empty());
+        assert!(changes(&BTreeMap::new(), &BTreeMap::new()).is_empty());
+    }
+
+    #[test]
+    fn changes_ignore_the_entries_before_the_hooks() {
+        let mut local = views(0xA000, 0x1000);
+        local.original = Entry::table(0x1000);
+        let applied = BTreeMap::from([(0x1000, local)]);
+        let published = BTreeMap::from([(0x1000, views(0xA000, 0x1000))]);
+
+        assert!(changes(&applied, &published).is_empty());
+    }
+}
@@ -1,11 +1,16 @@
 use {core::ptr::copy_nonoverlapping, log::*};
 
+/// The size of `jmp qword ptr [rip]` followed by its 64-bit destination.
+pub const INLINE_JMP_SIZE: usize = 14;
+
 /// Enum to define the types of inline hooks we support.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum InlineHookType {
     Int3,
     Cpuid,
     Vmcall,
+    /// An absolute jump to a guest handler at the contained virtual address, which causes no VM exit.
+    Jmp(u64),
 }
 
 /// Structure representing our hook configuration.
@@ -38,10 +43,13 @@ impl InlineHook {
         }
     }
 
-    /// Performs a detour or hook, from the source to the destination function, by overwriting it with either int3, cpuid, or vmcall instructions.
+    /// Performs a detour or hook, from the source to the destination function, by overwriting it with either int3, cpuid, vmcall or jmp instructions.
     pub fn detour64(&mut self) {
         trace!("Hook Type: {:?}", self.hook_type);
 
+        // jmp qword ptr [rip+0], followed by the destination.
+        let mut jmp = [0xFF, 0x25, 0x00, 0x00, 0x00, 0x00, 0, 0, 0, 0, 0, 0, 0, 0];
+
         let shellcode: &mut [u8] = match self.hook_type {
             // int3 instruction
             InlineHookType::Int3 => &mut [0xCC],
@@ -51,6 +59,12 @@ impl InlineHook {
 
             // vmcall instruction
             InlineHookType::Vmcall => &mut [0x0F, 0x01, 0xC1],
+
+            // jmp instruction
+            InlineHookType::Jmp(destination) => {
+                jmp[6..].copy_from_slice(&destination.to_le_bytes());
+                &mut jmp
+            }
         };
 
         unsafe {
@@ -71,6 +85,23 @@ impl InlineHook {
             InlineHookType::Int3 => 1,   // int3 is 1 byte
             InlineHookType::Cpuid => 2,  // cpuid is 2 bytes
             InlineHookType::Vmcall => 3, // vmcall is 3 bytes
+            InlineHookType::Jmp(_) => INLINE_JMP_SIZE,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jumps_encode_their_destination() {
+        let mut code = [0x90u8; 16];
+        InlineHook::new(code.as_mut_ptr(), InlineHookType::Jmp(0xFFFF_F800_1234_5678)).detour64();
+
+        assert_eq!(code[..6], [0xFF, 0x25, 0, 0, 0, 0]);
+        assert_eq!(u64::from_le_bytes(code[6..14].try_into().unwrap()), 0xFFFF_F800_1234_5678);
+        assert_eq!(code[14..], [0x90, 0x90]);
+        assert_eq!(InlineHook::hook_size(InlineHookType::Jmp(0)), INLINE_JMP_SIZE);
+    }
+}
@@ -6,7 +6,7 @@ use {
     crate::{
         allocator::box_zeroed,
         error::HypervisorError,
-        intel::{ept::Pt, hooks::hook_manager::EptHookType, page::Page},
+        intel::{ept::HookViews, hooks::hook_manager::EptHookType, page::Page},
     },
     alloc::{boxed::Box, collections::BTreeMap, vec::Vec},
     log::trace,
@@ -30,18 +30,20 @@ pub struct HookInfo {
 pub struct HookMapping {
     /// The shadow page.
     pub shadow_page: Box<Page>,
+    /// The guest page as it was when the shadow page was taken, see `patch_audit`.
+    pub clean_page: Box<Page>,
     /// The list of hooks associated with this page.
     pub hooks: Vec<HookInfo>,
+    /// The EPT entries EPT violations toggle the page between, `None` until the hook manager built them.
+    pub views: Option<HookViews>,
 }
 
-/// Represents a memory management system that manages page tables and shadow pages
+/// Represents a memory management system that manages shadow pages
 /// for a hypervisor, allocating memory as needed at runtime.
 #[derive(Debug, Clone)]
 pub struct MemoryManager {
     /// Mappings of guest physical addresses to their respective hook mappings.
     guest_page_mappings: BTreeMap<u64, HookMapping>,
-    /// Mappings of large guest physical addresses to their respective page tables.
-    large_page_table_mappings: BTreeMap<u64, Box<Pt>>,
 }
 
 impl MemoryManager {
@@ -54,7 +56,6 @@ impl MemoryManager {
 
         Self {
             guest_page_mappings: BTreeMap::new(),
-            large_page_table_mappings: BTreeMap::new(),
         }
     }
 
@@ -111,39 +112,26 @@ impl MemoryManager {
             trace!("Mapping does not exist, creating new mapping");
             // Allocate a new shadow page
             let shadow_page = unsafe { box_zeroed::<Page>() };
+            let clean_page = unsafe { box_zeroed::<Page>() };
             let mut hooks = Vec::new();
             hooks.push(hook_info);
 
             // Insert new mapping into guest_page_mappings
-            self.guest_page_mappings.insert(guest_page_pa, HookMapping { shadow_page, hooks });
+            self.guest_page_mappings.insert(
+                guest_page_pa,
+                HookMapping {
+                    shadow_page,
+                    clean_page,
+                    hooks,
+                    views: None,
+                },
+            );
             trace!("Guest page mapped to shadow page successfully");
         }
 
         Ok(())
     }
 
-    /// Maps a free page table to a large guest physical address, allocating memory as needed.
-    ///
-    /// # Arguments
-    /// * `guest_large_page_pa` - The large guest physical address to map.
-    ///
-    /// # Returns
-    /// `Ok(())` if successful, or an error if no free page tables are available.
-    pub fn map_large_page_to_pt(&mut self, guest_large_page_pa: u64) -> Result<(), HypervisorError> {
-        // Check if the large page is already mapped
-        if !self.large_page_table_mappings.contains_key(&guest_large_page_pa) {
-            trace!("Large page not mapped to page table, mapping now");
-            // Allocate a new page table
-            let pt = unsafe { box_zeroed::<Pt>() };
-            self.large_page_table_mappings.insert(guest_large_page_pa, pt);
-            trace!("Large page mapped to page table successfully");
-        } else {
-            trace!("Large page PA: {:#x} is already mapped to a page table", guest_large_page_pa);
-        }
-
-        Ok(())
-    }
-
     /// Unmaps a shadow page from a guest physical address, removing the associated hooks.
     ///
     /// # Arguments
@@ -164,35 +152,74 @@ impl MemoryManager {
         }
     }
 
-    /// Unmaps a page table from a large guest physical address.
+    /// Removes one hook from a guest page, keeping the shadow page for the others.
     ///
     /// # Arguments
-    /// * `guest_large_page_pa` - The large guest physical address to unmap.
+    /// * `guest_page_pa` - The guest physical address of the page.
+    /// * `guest_function_pa` - The guest physical address of the hooked function.
     ///
     /// # Returns
-    /// `Ok(())` if successful, or an error if the page table was not mapped.
-    pub fn unmap_large_page_from_pt(&mut self, guest_large_page_pa: u64) -> Result<(), HypervisorError> {
-        trace!("Unmapping large page and page table for PA: {:#x}", guest_large_page_pa);
+    /// The number of hooks left on the page, or an error if the function was not hooked.
+    pub fn remove_hook(&mut self, guest_page_pa: u64, guest_function_pa: u64) -> Result<usize, HypervisorError> {
+        let mapping = self.guest_page_mappings.get_mut(&guest_page_pa).ok_or(HypervisorError::HookNotFound)?;
+        let index = mapping
+            .hooks
+            .iter()
+            .position(|hook| hook.guest_function_pa == guest_function_pa)
+            .ok_or(HypervisorError::HookNotFound)?;
+        mapping.hooks.remove(index);
 
-        // Remove the mapping if it exists
-        if self.large_page_table_mappings.remove(&guest_large_page_pa).is_some() {
-            trace!("Large page unmapped from page table successfully");
-            Ok(())
-        } else {
-            trace!("Large page PA: {:#x} was not mapped", guest_large_page_pa);
-            Err(HypervisorError::LargePageUnmapError)
-        }
+        Ok(mapping.hooks.len())
     }
 
-    /// Retrieves a mutable reference to the page table associated with a large guest physical address.
+    /// Records the EPT entries of a hooked guest page.
     ///
     /// # Arguments
-    /// * `guest_large_page_pa` - The large guest physical address.
+    /// * `guest_page_pa` - The guest physical address of the page.
+    /// * `views` - The execute and read-write entries of the page.
     ///
     /// # Returns
-    /// An `Option` containing a mutable reference to the `Pt` if found.
-    pub fn get_page_table_as_mut(&mut self, guest_large_page_pa: u64) -> Option<&mut Pt> {
-        self.large_page_table_mappings.get_mut(&guest_large_page_pa).map(|pt| &mut **pt)
+    /// `Ok(())` if successful, or an error if the page is not mapped to a shadow page.
+    pub fn set_hook_views(&mut self, guest_page_pa: u64, views: HookViews) -> Result<(), HypervisorError> {
+        let mapping = self
+            .guest_page_mappings
+            .get_mut(&guest_page_pa)
+            .ok_or(HypervisorError::ShadowPageNotFound)?;
+        mapping.views = Some(views);
+        Ok(())
+    }
+
+    /// Retrieves the EPT entries of a hooked guest page.
+    ///
+    /// # Arguments
+    /// * `guest_page_pa` - The guest physical address of the page.
+    ///
+    /// # Returns
+    /// An `Option` containing the views if the page is hooked and they were built.
+    pub fn get_hook_views(&self, guest_page_pa: u64) -> Option<HookViews> {
+        self.guest_page_mappings.get(&guest_page_pa)?.views
+    }
+
+    /// Returns the number of hooks on all guest pages.
+    pub fn hook_count(&self) -> usize {
+        self.guest_page_mappings.values().map(|mapping| mapping.hooks.len()).sum()
+    }
+
+    /// Returns the hooks of all guest pages, in ascending order of the pages.
+    pub fn hooks(&self) -> impl Iterator<Item = &HookInfo> {
+        self.guest_page_mappings.values().flat_map(|mapping| mapping.hooks.iter())
+    }
+
+    /// Returns the mappings of all hooked guest pages with the address of the page, in ascending order of the pages.
+    pub fn mappings(&self) -> impl Iterator<Item = (u64, &HookMapping)> {
+        self.guest_page_mappings.iter().map(|(&guest_page_pa, mapping)| (guest_page_pa, mapping))
+    }
+
+    /// Returns the views of all hooked guest pages that have them built, with the address of the page.
+    pub fn views(&self) -> impl Iterator<Item = (u64, HookViews)> + '_ {
+        self.guest_page_mappings
+            .iter()
+            .filter_map(|(&guest_page_pa, mapping)| Some((guest_page_pa, mapping.views?)))
     }
 
     /// Retrieves a pointer to the shadow page associated with a guest physical address.
@@ -208,6 +235,19 @@ impl MemoryManager {
             .map(|mapping| &*mapping.shadow_page as *const Page as u64)
     }
 
+    /// Retrieves a pointer to the clean copy of the guest page `map_guest_to_shadow_page` allocated with its shadow page.
+    ///
+    /// # Arguments
+    /// * `guest_page_pa` - The guest physical address.
+    ///
+    /// # Returns
+    /// An `Option` containing the memory address of the `Page` as a `u64` if found.
+    pub fn get_clean_page_as_ptr(&self, guest_page_pa: u64) -> Option<u64> {
+        self.guest_page_mappings
+            .get(&guest_page_pa)
+            .map(|mapping| &*mapping.clean_page as *const Page as u64)
+    }
+
     /// Retrieves a reference to the `HookInfo` associated with a guest physical address.
     ///
     /// # Arguments
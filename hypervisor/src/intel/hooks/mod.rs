@@ -1,4 +1,7 @@
 pub mod descriptor_manager;
 pub mod hook_manager;
+pub mod hook_sync;
 pub mod inline;
 pub mod memory_manager;
+pub mod patch_audit;
+pub mod syscall_watch;
@@ -0,0 +1,308 @@
+//! Checks the code EPT hooks are placed on, and that nobody else patched it since.
+//!
+//! Before a hook is installed, `verify_prologue` compares the first bytes of the target with a `BytePattern` the
+//! caller expects there, so a hook never lands on a function that was already patched or isn't the one meant.
+//!
+//! `HookManager::install_ept_hook` keeps a clean copy of every hooked page when it takes the shadow page, and
+//! `audit` compares the pages of all hooks with it:
+//!
+//! * Where the guest-visible page, the one reads and writes see, differs from the clean copy, someone else patched
+//!   the code after the hook was installed. The patch isn't executed, the shadow page is, and is logged to
+//!   `event_log` as an `EVENT_GUEST_PATCH`.
+//! * Where the shadow page differs from the clean copy outside the bytes of its hooks, the shadow page was
+//!   corrupted, logged as an `EVENT_SHADOW_DRIFT`.
+//!
+//! Each event holds the guest-physical address of the page, the offset of the modified bytes and their length.
+
+use {
+    crate::{
+        error::HypervisorError,
+        event_log,
+        intel::hooks::{hook_manager::HookManager, memory_manager::MemoryManager},
+    },
+    alloc::vec::Vec,
+    core::ops::Range,
+    log::*,
+    shared::vmcall::{EVENT_GUEST_PATCH, EVENT_SHADOW_DRIFT},
+    x86::bits64::paging::BASE_PAGE_SIZE,
+};
+
+/// The most events `audit` logs per page and kind, the report counts every discrepancy.
+const MAX_EVENTS_PER_PAGE: usize = 16;
+
+/// A byte pattern code is expected to match, with wildcards for the bytes that vary, such as displacements.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BytePattern {
+    /// The bytes, `None` for a wildcard.
+    bytes: Vec<Option<u8>>,
+}
+
+impl BytePattern {
+    /// Parses a pattern of hexadecimal bytes separated by spaces, `?` or `??` for a wildcard.
+    ///
+    /// For example, `"48 89 5C 24 ?? 57"` for `mov [rsp+?], rbx; push rdi`.
+    ///
+    /// # Returns
+    ///
+    /// The pattern, `None` if a byte isn't hexadecimal or the pattern is empty.
+    pub fn parse(pattern: &str) -> Option<Self> {
+        let bytes = pattern
+            .split_ascii_whitespace()
+            .map(|byte| match byte {
+                "?" | "??" => Some(None),
+                _ if byte.len() == 2 => u8::from_str_radix(byte, 16).ok().map(Some),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        (!bytes.is_empty()).then_some(Self { bytes })
+    }
+
+    /// Creates a pattern from bytes and a mask of the same length, `x` where a byte must match and `?` where any
+    /// byte does.
+    ///
+    /// # Returns
+    ///
+    /// The pattern, `None` if the lengths differ or the mask has another character.
+    pub fn from_masked(bytes: &[u8], mask: &str) -> Option<Self> {
+        if bytes.len() != mask.len() {
+            return None;
+        }
+
+        let bytes = bytes
+            .iter()
+            .zip(mask.bytes())
+            .map(|(&byte, mask)| match mask {
+                b'x' => Some(Some(byte)),
+                b'?' => Some(None),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        Some(Self { bytes })
+    }
+
+    /// Returns the bytes of the pattern, wildcards included.
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Returns whether the pattern has no bytes, only `from_masked` of no bytes creates one, which matches any code.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Returns the offset of the first byte of `code` that doesn't match, `None` if all of the pattern does.
+    ///
+    /// Code shorter than the pattern mismatches at its end.
+    pub fn mismatch(&self, code: &[u8]) -> Option<usize> {
+        self.bytes
+            .iter()
+            .enumerate()
+            .find(|&(offset, expected)| code.get(offset).is_none_or(|byte| expected.is_some_and(|expected| expected != *byte)))
+            .map(|(offset, _)| offset)
+    }
+}
+
+/// Checks the code at a guest-physical address matches the pattern a hook expects there.
+///
+/// # Arguments
+///
+/// * `guest_function_pa` - The guest-physical address of the function to hook.
+/// * `expected` - The pattern the first bytes of the function must match.
+///
+/// # Returns
+///
+/// * `Err(HypervisorError::UnexpectedPrologue)` - The function doesn't match, or the pattern leaves its page.
+pub fn verify_prologue(guest_function_pa: u64, expected: &BytePattern) -> Result<(), HypervisorError> {
+    let offset = guest_function_pa as usize % BASE_PAGE_SIZE;
+    let length = expected.len().min(BASE_PAGE_SIZE - offset);
+
+    // Guest-physical memory is identity-mapped by the hypervisor.
+    let code = unsafe { core::slice::from_raw_parts(guest_function_pa as *const u8, length) };
+
+    match expected.mismatch(code) {
+        None => Ok(()),
+        Some(mismatch) => {
+            warn!("Unexpected code at {:#x}, byte {} is {:x?}", guest_function_pa, mismatch, code.get(mismatch));
+            Err(HypervisorError::UnexpectedPrologue)
+        }
+    }
+}
+
+/// Returns the FNV-1a hash of a page.
+pub fn page_hash(page: &[u8]) -> u64 {
+    page.iter()
+        .fold(0xCBF2_9CE4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01B3))
+}
+
+/// Returns the runs of bytes two copies of a page differ in, outside the intended ones.
+///
+/// # Arguments
+///
+/// * `expected` - The clean copy of the page.
+/// * `actual` - The copy to check.
+/// * `intended` - The offsets `actual` is meant to differ at.
+pub fn discrepancies(expected: &[u8], actual: &[u8], intended: &[Range<usize>]) -> Vec<Range<usize>> {
+    let mut runs: Vec<Range<usize>> = Vec::new();
+
+    for (offset, (expected, actual)) in expected.iter().zip(actual).enumerate() {
+        if expected == actual || intended.iter().any(|range| range.contains(&offset)) {
+            continue;
+        }
+
+        match runs.last_mut() {
+            Some(run) if run.end == offset => run.end += 1,
+            _ => runs.push(offset..offset + 1),
+        }
+    }
+
+    runs
+}
+
+/// What `audit` found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AuditReport {
+    /// The hooked pages audited.
+    pub pages: u64,
+
+    /// The pages whose guest-visible copy differs from the clean copy.
+    pub patched_pages: u64,
+
+    /// The runs of modified bytes found, in guest-visible and shadow pages.
+    pub discrepancies: u64,
+}
+
+/// Audits one hooked page.
+///
+/// # Arguments
+///
+/// * `guest_page_pa` - The guest-physical address of the page.
+/// * `pages` - The clean copy of the page, the page reads and writes of the guest see and the shadow page the guest
+///   executes, with the hooks.
+/// * `hooks` - The offsets of the bytes of the hooks on the page.
+/// * `report` - The report to add the findings to.
+/// * `log` - Called with the `EVENT_*` kind and the values of every event to log.
+fn audit_page(guest_page_pa: u64, pages: [&[u8]; 3], hooks: &[Range<usize>], report: &mut AuditReport, mut log: impl FnMut(u32, [u64; 3])) {
+    let [clean, guest, shadow] = pages;
+    report.pages += 1;
+
+    // Rehashing first keeps the common case, an untouched page, to a pass over it.
+    if page_hash(guest) != page_hash(clean) {
+        let patches = discrepancies(clean, guest, &[]);
+        report.patched_pages += !patches.is_empty() as u64;
+        report.discrepancies += patches.len() as u64;
+        for run in patches.iter().take(MAX_EVENTS_PER_PAGE) {
+            log(EVENT_GUEST_PATCH, [guest_page_pa, run.start as u64, run.len() as u64]);
+        }
+    }
+
+    let drift = discrepancies(clean, shadow, hooks);
+    report.discrepancies += drift.len() as u64;
+    for run in drift.iter().take(MAX_EVENTS_PER_PAGE) {
+        log(EVENT_SHADOW_DRIFT, [guest_page_pa, run.start as u64, run.len() as u64]);
+    }
+}
+
+/// Audits the pages of all hooks, logging the discrepancies to `event_log`.
+///
+/// # Arguments
+///
+/// * `memory_manager` - The memory manager holding the hooked pages.
+pub fn audit(memory_manager: &MemoryManager) -> AuditReport {
+    let mut report = AuditReport::default();
+
+    for (guest_page_pa, mapping) in memory_manager.mappings() {
+        let hooks: Vec<Range<usize>> = mapping
+            .hooks
+            .iter()
+            .map(|hook| {
+                let offset = hook.guest_function_pa as usize % BASE_PAGE_SIZE;
+                offset..offset + HookManager::hook_size(hook.ept_hook_type)
+            })
+            .collect();
+
+        // Guest-physical memory is identity-mapped by the hypervisor.
+        let guest = unsafe { core::slice::from_raw_parts(guest_page_pa as *const u8, BASE_PAGE_SIZE) };
+        let pages = [&mapping.clean_page.0[..], guest, &mapping.shadow_page.0[..]];
+        audit_page(guest_page_pa, pages, &hooks, &mut report, event_log::log_event);
+    }
+
+    if report.discrepancies != 0 {
+        warn!("Hooked pages were modified: {:?}", report);
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, alloc::vec};
+
+    #[test]
+    fn patterns_match_with_wildcards() {
+        let pattern = BytePattern::parse("48 89 5C 24 ?? 57").unwrap();
+        assert_eq!(pattern.len(), 6);
+        assert_eq!(pattern.mismatch(&[0x48, 0x89, 0x5C, 0x24, 0x08, 0x57, 0xCC]), None);
+        assert_eq!(pattern.mismatch(&[0x48, 0x89, 0x5C, 0x24, 0x10, 0x56]), Some(5));
+        assert_eq!(pattern.mismatch(&[0xE9, 0x89]), Some(0));
+        assert_eq!(pattern.mismatch(&[0x48, 0x89, 0x5C]), Some(3));
+
+        assert_eq!(BytePattern::from_masked(&[0x48, 0x89, 0x5C, 0x24, 0, 0x57], "xxxx?x"), Some(pattern));
+        assert_eq!(BytePattern::parse("0F 01 F8").map(|pattern| pattern.mismatch(&[0x0F, 0x01, 0xF8])), Some(None));
+        assert_eq!(BytePattern::parse("48 8G"), None);
+        assert_eq!(BytePattern::parse("488B"), None);
+        assert_eq!(BytePattern::parse(" "), None);
+        assert_eq!(BytePattern::from_masked(&[0x48], "xx"), None);
+    }
+
+    #[test]
+    fn discrepancies_skip_intended_bytes() {
+        let clean = vec![0x90u8; 0x40];
+        let mut modified = clean.clone();
+        modified[0x10..0x13].copy_from_slice(&[0x0F, 0x01, 0xC1]);
+        modified[0x20] = 0xCC;
+        modified[0x22] = 0xCC;
+        modified[0x23] = 0xCC;
+
+        assert_eq!(discrepancies(&clean, &modified, core::slice::from_ref(&(0x10..0x13))), [0x20..0x21, 0x22..0x24]);
+        assert_eq!(discrepancies(&clean, &modified, &[]).len(), 3);
+        assert!(discrepancies(&clean, &clean, &[]).is_empty());
+    }
+
+    #[test]
+    fn audits_report_foreign_patches_and_drift() {
+        let clean = vec![0x90u8; BASE_PAGE_SIZE];
+        let mut shadow = clean.clone();
+        shadow[0x100..0x103].copy_from_slice(&[0x0F, 0x01, 0xC1]);
+        let hooks = core::slice::from_ref(&(0x100..0x103));
+
+        let mut events = Vec::new();
+        let mut report = AuditReport::default();
+        audit_page(0x5000, [&clean, &clean, &shadow], hooks, &mut report, |kind, values| events.push((kind, values)));
+        assert_eq!(
+            report,
+            AuditReport {
+                pages: 1,
+                ..Default::default()
+            }
+        );
+        assert!(events.is_empty());
+
+        // Someone else hooked the same function on the guest-visible page, and the shadow page lost a byte.
+        let mut guest = clean.clone();
+        guest[0x100..0x10E].fill(0xFF);
+        shadow[0x800] = 0;
+        audit_page(0x5000, [&clean, &guest, &shadow], hooks, &mut report, |kind, values| events.push((kind, values)));
+        assert_eq!(
+            report,
+            AuditReport {
+                pages: 2,
+                patched_pages: 1,
+                discrepancies: 2
+            }
+        );
+        assert_eq!(events, [(EVENT_GUEST_PATCH, [0x5000, 0x100, 0xE]), (EVENT_SHADOW_DRIFT, [0x5000, 0x800, 1])]);
+        assert_ne!(page_hash(&guest), page_hash(&clean));
+    }
+}
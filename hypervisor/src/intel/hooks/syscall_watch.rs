@@ -0,0 +1,262 @@
+//! Watches the syscalls of the guest for the syscall calls of `shared::vmcall`, without touching LSTAR, which
+//! PatchGuard checks.
+//!
+//! While a rule is set, the first instruction of the entry point LSTAR holds, KiSystemCall64 or
+//! KiSystemCall64Shadow, is hooked with a VMCALL in the shadow page of an EPT hook. The hypervisor is the trampoline
+//! of the hook: `vmexit::vmcall` hands the syscall to `record` and single-steps the displaced instruction on the
+//! original page before re-arming the hook. Every syscall then costs the VMCALL exit and an MTF exit per displaced
+//! instruction, one for the SWAPGS both entry points start with, whether a rule matches it or not. `stats` counts
+//! the syscalls and the exits taken for them, which `GetSyscallStats` returns. The entry point is only hooked if it
+//! starts with the SWAPGS, anything else there means someone else hooked it already, see `patch_audit`.
+//!
+//! A syscall a rule matches is logged to `event_log` as an `EVENT_SYSCALL` with its number from EAX, the CR3 and
+//! the return address in RCX. With KVA shadowing, the CR3 is the user directory table base of the process rather
+//! than the DirectoryTableBase `find_process` returns, a `SYSCALL_WATCH_ANY` rule for every address space finds it.
+
+use {
+    crate::{
+        error::HypervisorError,
+        event_log,
+        intel::{
+            addresses::PhysicalAddress,
+            hooks::{
+                hook_manager::{EptHookType, SHARED_HOOK_MANAGER},
+                inline::InlineHookType,
+                patch_audit::{verify_prologue, BytePattern},
+            },
+            page_walk::ADDRESS_MASK,
+            support::rdmsr,
+            vm::Vm,
+        },
+        stats,
+        windows::nt::pe::djb2_hash,
+    },
+    core::sync::atomic::{AtomicU64, Ordering},
+    log::*,
+    shared::vmcall::{SyscallWatchEntry, EVENT_SYSCALL, MAX_SYSCALL_WATCHES, SYSCALL_WATCH_ANY},
+    spin::Mutex,
+    x86::msr,
+};
+
+/// The code both syscall entry points start with, SWAPGS.
+const ENTRY_PROLOGUE: &str = "0F 01 F8";
+
+/// Why a rule couldn't be added.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchError {
+    /// The syscall number is neither 32 bits nor `SYSCALL_WATCH_ANY`.
+    InvalidNumber,
+
+    /// `MAX_SYSCALL_WATCHES` rules are set.
+    Full,
+}
+
+/// The rules of the syscall watch, with the syscalls each one matched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyscallWatches {
+    /// The rules, in the order they were added, `None` for the free ones.
+    rules: [Option<SyscallWatchEntry>; MAX_SYSCALL_WATCHES],
+}
+
+impl SyscallWatches {
+    /// Creates a watch without rules.
+    pub const fn new() -> Self {
+        Self {
+            rules: [None; MAX_SYSCALL_WATCHES],
+        }
+    }
+
+    /// Returns the rules set.
+    pub fn len(&self) -> usize {
+        self.entries().count()
+    }
+
+    /// Returns whether no rule is set.
+    pub fn is_empty(&self) -> bool {
+        self.rules.iter().all(Option::is_none)
+    }
+
+    /// Returns the rules and their hits.
+    pub fn entries(&self) -> impl Iterator<Item = &SyscallWatchEntry> {
+        self.rules.iter().flatten()
+    }
+
+    /// Adds a rule, adding one that is set is a no-op.
+    ///
+    /// # Arguments
+    ///
+    /// * `number` - The syscall number, `SYSCALL_WATCH_ANY` for every one.
+    /// * `cr3` - The CR3 the syscalls are made with, its PCID and flags are ignored, zero for every address space.
+    pub fn add(&mut self, number: u64, cr3: u64) -> Result<(), WatchError> {
+        if number > u32::MAX as u64 && number != SYSCALL_WATCH_ANY {
+            return Err(WatchError::InvalidNumber);
+        }
+
+        let cr3 = cr3 & ADDRESS_MASK;
+        if self.find(number, cr3).is_some() {
+            return Ok(());
+        }
+
+        let free = self.rules.iter_mut().find(|rule| rule.is_none()).ok_or(WatchError::Full)?;
+        *free = Some(SyscallWatchEntry { number, cr3, hits: 0 });
+        Ok(())
+    }
+
+    /// Removes a rule, returns whether it was set.
+    ///
+    /// # Arguments
+    ///
+    /// * `number` - The syscall number of the rule.
+    /// * `cr3` - The CR3 of the rule.
+    pub fn remove(&mut self, number: u64, cr3: u64) -> bool {
+        match self.find(number, cr3 & ADDRESS_MASK) {
+            Some(index) => {
+                self.rules[index] = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Counts a syscall for the rules it matches, returns whether any does.
+    ///
+    /// # Arguments
+    ///
+    /// * `number` - The syscall number, only EAX is compared.
+    /// * `cr3` - The CR3 the syscall was made with.
+    pub fn matches(&mut self, number: u64, cr3: u64) -> bool {
+        let (number, cr3) = (number & 0xFFFF_FFFF, cr3 & ADDRESS_MASK);
+        let mut matched = false;
+
+        for rule in self.rules.iter_mut().flatten() {
+            if (rule.number == SYSCALL_WATCH_ANY || rule.number == number) && (rule.cr3 == 0 || rule.cr3 == cr3) {
+                rule.hits += 1;
+                matched = true;
+            }
+        }
+
+        matched
+    }
+
+    /// Returns the index of a rule, whose CR3 is already masked.
+    fn find(&self, number: u64, cr3: u64) -> Option<usize> {
+        self.rules
+            .iter()
+            .position(|rule| rule.is_some_and(|rule| rule.number == number && rule.cr3 == cr3))
+    }
+}
+
+impl Default for SyscallWatches {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The rules of the syscall watch, taken after `SHARED_HOOK_MANAGER` wherever both are.
+pub static SYSCALL_WATCHES: Mutex<SyscallWatches> = Mutex::new(SyscallWatches::new());
+
+/// The virtual address of the hooked syscall entry point, zero while it isn't hooked.
+static HOOKED_ENTRY_VA: AtomicU64 = AtomicU64::new(0);
+
+/// The guest-physical address of the hooked syscall entry point, zero while it isn't hooked.
+static HOOKED_ENTRY_PA: AtomicU64 = AtomicU64::new(0);
+
+/// Returns whether the VMCALL of an EPT hook at a guest-physical address is the one of the syscall entry point.
+pub fn is_entry(guest_function_pa: u64) -> bool {
+    guest_function_pa != 0 && HOOKED_ENTRY_PA.load(Ordering::Relaxed) == guest_function_pa
+}
+
+/// Records a syscall the hook of the entry point intercepted.
+///
+/// # Arguments
+///
+/// * `number` - RAX, the syscall number.
+/// * `cr3` - The CR3 the syscall was made with.
+/// * `return_address` - RCX, the address the syscall returns to.
+/// * `exits` - The VM exits the hook takes for the syscall, its VMCALL and the MTF exits stepping over it.
+pub fn record(number: u64, cr3: u64, return_address: u64, exits: u64) {
+    let matched = SYSCALL_WATCHES.lock().matches(number, cr3);
+    stats::record_syscall(matched, exits);
+
+    if matched {
+        event_log::log_event(EVENT_SYSCALL, [number & 0xFFFF_FFFF, cr3, return_address]);
+    }
+}
+
+/// Hooks the syscall entry point while a rule is set and unhooks it once none is.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the hypervisor.
+///
+/// # Returns
+///
+/// * `Ok(())` once the hook matches the rules, `Err(HypervisorError)` if it couldn't be installed or removed.
+pub fn sync_hook(vm: &mut Vm) -> Result<(), HypervisorError> {
+    let mut hook_manager = SHARED_HOOK_MANAGER.lock();
+    let wanted = !SYSCALL_WATCHES.lock().is_empty();
+    let hooked_va = HOOKED_ENTRY_VA.load(Ordering::Relaxed);
+    let ept_hook_type = EptHookType::Function(InlineHookType::Vmcall);
+
+    if wanted && hooked_va == 0 {
+        // LSTAR isn't switched on VM exits, it holds the entry point of the guest here.
+        let entry_va = rdmsr(msr::IA32_LSTAR);
+        debug!("Hooking the syscall entry point at {:#x}", entry_va);
+
+        let entry_pa = PhysicalAddress::pa_from_va_with_current_cr3(entry_va)?;
+        verify_prologue(entry_pa, &BytePattern::parse(ENTRY_PROLOGUE).ok_or(HypervisorError::UnexpectedPrologue)?)?;
+        hook_manager.ept_hook_function(vm, entry_va, djb2_hash(b"KiSystemCall64"), ept_hook_type)?;
+        HOOKED_ENTRY_PA.store(entry_pa, Ordering::Relaxed);
+        HOOKED_ENTRY_VA.store(entry_va, Ordering::Relaxed);
+    } else if !wanted && hooked_va != 0 {
+        debug!("Unhooking the syscall entry point at {:#x}", hooked_va);
+
+        HOOKED_ENTRY_PA.store(0, Ordering::Relaxed);
+        HOOKED_ENTRY_VA.store(0, Ordering::Relaxed);
+        hook_manager.ept_unhook_function(vm, hooked_va, ept_hook_type)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules_filter_numbers_and_address_spaces() {
+        let mut watches = SyscallWatches::new();
+        assert_eq!(watches.add(0x55, 0x1AD000 | 0x2), Ok(()));
+        assert_eq!(watches.add(0x55, 0x1AD000), Ok(()));
+        assert_eq!(watches.add(SYSCALL_WATCH_ANY, 0x2000), Ok(()));
+        assert_eq!(watches.add(1 << 32, 0), Err(WatchError::InvalidNumber));
+        assert_eq!(watches.len(), 2);
+
+        // The PCID and bit 63 of CR3, and the high half of RAX, are ignored.
+        assert!(watches.matches(0xFFFF_FFFF_0000_0055, 1 << 63 | 0x1AD000 | 0x7));
+        assert!(watches.matches(0x1234, 0x2000));
+        assert!(!watches.matches(0x55, 0x3000));
+        assert!(!watches.matches(0x56, 0x1AD000));
+        assert_eq!(watches.entries().map(|entry| entry.hits).collect::<alloc::vec::Vec<_>>(), [1, 1]);
+
+        assert!(watches.remove(0x55, 0x1AD000));
+        assert!(!watches.remove(0x55, 0x1AD000));
+        assert!(!watches.matches(0x55, 0x1AD000));
+        assert!(watches.remove(SYSCALL_WATCH_ANY, 0x2000));
+        assert!(watches.is_empty());
+    }
+
+    #[test]
+    fn rules_are_bounded() {
+        let mut watches = SyscallWatches::new();
+        for number in 0..MAX_SYSCALL_WATCHES as u64 {
+            assert_eq!(watches.add(number, 0), Ok(()));
+        }
+        assert_eq!(watches.add(MAX_SYSCALL_WATCHES as u64, 0), Err(WatchError::Full));
+
+        // A freed rule is reused for the next one.
+        assert!(watches.remove(3, 0));
+        assert_eq!(watches.add(0x100, 0), Ok(()));
+        assert_eq!(watches.entries().nth(3).map(|entry| entry.number), Some(0x100));
+    }
+}
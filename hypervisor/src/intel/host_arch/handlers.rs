@@ -0,0 +1,302 @@
+//! The handlers behind the host IDT.
+//!
+//! Every gate points at a stub that completes the frame the processor pushed with the vector, a zero error
+//! code where the exception has none and the general-purpose registers, and calls `host_arch_exception`.
+//! Exceptions are reported on the serial port with the registers, the RIP relative to the hypervisor image,
+//! the VM exit being handled and the recent log lines, then the processor halts. NMIs return, the VM exit
+//! loop handles the shootdown of the ones `shootdown` sent and `nmi` delivers the others to the guest.
+
+use {
+    crate::{
+        intel::{
+            host_arch::{image_offset, DOUBLE_FAULT_IST, NMI_IST},
+            nmi, shootdown,
+            support::{cr0, cr3, cr4, vmread},
+            vcpu,
+            vmerror::VmxBasicExitReason,
+        },
+        logger,
+    },
+    core::{
+        arch::{asm, global_asm},
+        fmt::Write,
+        hint,
+        sync::atomic::{AtomicU32, Ordering},
+    },
+    x86::vmx::vmcs,
+};
+
+/// The vector of NMIs.
+const NMI_VECTOR: u64 = 2;
+
+/// The vector of #PF, which reports CR2 too.
+const PAGE_FAULT_VECTOR: u64 = 14;
+
+/// The APIC ID of the processor writing a report, `u32::MAX` for none.
+static REPORTING: AtomicU32 = AtomicU32::new(u32::MAX);
+
+/// The frame the stubs pass to `host_arch_exception`, lowest address first.
+#[repr(C)]
+#[derive(Debug)]
+pub struct ExceptionFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rax: u64,
+
+    /// The vector, pushed by the stub.
+    pub vector: u64,
+
+    /// The error code of the exception, `0` if it pushes none.
+    pub error_code: u64,
+
+    // Pushed by the processor.
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+extern "efiapi" {
+    fn host_arch_nmi();
+    fn host_arch_invalid_opcode();
+    fn host_arch_double_fault();
+    fn host_arch_general_protection();
+    fn host_arch_page_fault();
+}
+
+/// Returns the gates of the host IDT as vector, handler and interrupt stack table entry.
+pub fn gates() -> [(u8, u64, u8); 5] {
+    [
+        (NMI_VECTOR as u8, host_arch_nmi as usize as u64, NMI_IST),
+        (6, host_arch_invalid_opcode as usize as u64, 0),
+        (8, host_arch_double_fault as usize as u64, DOUBLE_FAULT_IST),
+        (13, host_arch_general_protection as usize as u64, 0),
+        (PAGE_FAULT_VECTOR as u8, host_arch_page_fault as usize as u64, 0),
+    ]
+}
+
+global_asm!(
+    r#"
+// Pushes the vector, after a zero error code for exceptions without one, and continues with the common part.
+.macro HOST_ARCH_STUB name, vector, has_error_code
+.global \name
+\name:
+.if \has_error_code == 0
+    push    0
+.endif
+    push    \vector
+    jmp     host_arch_common
+.endm
+
+HOST_ARCH_STUB host_arch_nmi, 2, 0
+HOST_ARCH_STUB host_arch_invalid_opcode, 6, 0
+HOST_ARCH_STUB host_arch_double_fault, 8, 1
+HOST_ARCH_STUB host_arch_general_protection, 13, 1
+HOST_ARCH_STUB host_arch_page_fault, 14, 1
+
+// The processor aligned RSP to 16 bytes before pushing its 5 values, with the error code, the vector and the
+// 15 registers the frame is 176 bytes, so RSP stays aligned for the call.
+//
+// extern "efiapi" fn host_arch_exception(frame: &mut ExceptionFrame)
+host_arch_common:
+    push    rax
+    push    rcx
+    push    rdx
+    push    rbx
+    push    rbp
+    push    rsi
+    push    rdi
+    push    r8
+    push    r9
+    push    r10
+    push    r11
+    push    r12
+    push    r13
+    push    r14
+    push    r15
+
+    mov     rcx, rsp
+    sub     rsp, 0x20
+    call    host_arch_exception
+    add     rsp, 0x20
+
+    pop     r15
+    pop     r14
+    pop     r13
+    pop     r12
+    pop     r11
+    pop     r10
+    pop     r9
+    pop     r8
+    pop     rdi
+    pop     rsi
+    pop     rbp
+    pop     rbx
+    pop     rdx
+    pop     rcx
+    pop     rax
+
+    // Skip the vector and the error code.
+    add     rsp, 0x10
+    iretq
+"#
+);
+
+/// Handles an exception or NMI of the host, called by the stubs.
+///
+/// # Arguments
+///
+/// * `frame` - The registers and the frame of the exception.
+#[no_mangle]
+extern "efiapi" fn host_arch_exception(frame: &mut ExceptionFrame) {
+    let apic_id = vcpu::current_apic_id();
+
+    if frame.vector == NMI_VECTOR {
+        if !shootdown::take_nmi(apic_id) {
+            nmi::queue_current();
+        }
+        return;
+    }
+
+    // One report at a time, a fault while reporting is only mentioned.
+    while let Err(owner) = REPORTING.compare_exchange(u32::MAX, apic_id, Ordering::Acquire, Ordering::Relaxed) {
+        if owner == apic_id {
+            let _ = writeln!(logger::crash_writer(), "vcpu-{} {} at {:#x} while reporting a host exception", apic_id, name(frame.vector), frame.rip);
+            halt();
+        }
+        hint::spin_loop();
+    }
+
+    report(apic_id, frame);
+    REPORTING.store(u32::MAX, Ordering::Release);
+    halt();
+}
+
+/// Writes the report of a host exception to the crash port.
+///
+/// # Arguments
+///
+/// * `apic_id` - The APIC ID of the current processor.
+/// * `frame` - The registers and the frame of the exception.
+fn report(apic_id: u32, frame: &ExceptionFrame) {
+    let mut serial = logger::crash_writer();
+
+    let _ = writeln!(
+        serial,
+        "==== vcpu-{} host exception {} (vector {}), error code {:#x} ====",
+        apic_id,
+        name(frame.vector),
+        frame.vector,
+        frame.error_code
+    );
+    match image_offset(frame.rip) {
+        Some(offset) => {
+            let _ = writeln!(serial, "rip: {:#018x} (image + {:#x}), cs: {:#x}, rflags: {:#x}", frame.rip, offset, frame.cs, frame.rflags);
+        }
+        None => {
+            let _ = writeln!(serial, "rip: {:#018x} (outside the image), cs: {:#x}, rflags: {:#x}", frame.rip, frame.cs, frame.rflags);
+        }
+    }
+    if frame.vector == PAGE_FAULT_VECTOR {
+        let _ = writeln!(serial, "cr2: {:#018x}", unsafe { x86::controlregs::cr2() });
+    }
+
+    let _ = writeln!(serial, "rax: {:#018x}, rcx: {:#018x}, rdx: {:#018x}, rbx: {:#018x}", frame.rax, frame.rcx, frame.rdx, frame.rbx);
+    let _ = writeln!(serial, "rsp: {:#018x}, rbp: {:#018x}, rsi: {:#018x}, rdi: {:#018x}", frame.rsp, frame.rbp, frame.rsi, frame.rdi);
+    let _ = writeln!(serial, "r8: {:#018x}, r9: {:#018x}, r10: {:#018x}, r11: {:#018x}", frame.r8, frame.r9, frame.r10, frame.r11);
+    let _ = writeln!(serial, "r12: {:#018x}, r13: {:#018x}, r14: {:#018x}, r15: {:#018x}", frame.r12, frame.r13, frame.r14, frame.r15);
+    let _ = writeln!(serial, "ss: {:#x}, cr0: {:#x}, cr3: {:#x}, cr4: {:#x}", frame.ss, cr0().bits(), cr3(), cr4());
+
+    // The host IDT is only loaded by VM exits, so the VMCS of the processor is current.
+    let exit_reason = vmread(vmcs::ro::EXIT_REASON) as u32;
+    let _ = writeln!(
+        serial,
+        "Handling VM exit {:?} ({:#x}), qualification {:#x}, guest rip {:#x}",
+        VmxBasicExitReason::from_u32(exit_reason),
+        exit_reason,
+        vmread(vmcs::ro::EXIT_QUALIFICATION),
+        vmread(vmcs::guest::RIP)
+    );
+
+    let _ = writeln!(serial, "Recent log lines:");
+    logger::write_recent_lines();
+    let _ = writeln!(serial, "==== vcpu-{} halted ====", apic_id);
+}
+
+/// Returns the mnemonic of an exception vector.
+///
+/// # Arguments
+///
+/// * `vector` - The vector of the exception.
+pub fn name(vector: u64) -> &'static str {
+    const NAMES: [&str; 21] = [
+        "#DE",
+        "#DB",
+        "NMI",
+        "#BP",
+        "#OF",
+        "#BR",
+        "#UD",
+        "#NM",
+        "#DF",
+        "Coprocessor Segment Overrun",
+        "#TS",
+        "#NP",
+        "#SS",
+        "#GP",
+        "#PF",
+        "Reserved",
+        "#MF",
+        "#AC",
+        "#MC",
+        "#XM",
+        "#VE",
+    ];
+    NAMES.get(vector as usize).copied().unwrap_or("Interrupt")
+}
+
+/// Halts the processor for good, NMIs only wake it up to halt again.
+fn halt() -> ! {
+    loop {
+        unsafe { asm!("cli", "hlt", options(nomem, nostack)) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        core::mem::{offset_of, size_of},
+    };
+
+    #[test]
+    fn frames_match_the_stubs() {
+        // 15 registers, the vector, the error code and the 5 values the processor pushes.
+        assert_eq!(size_of::<ExceptionFrame>(), 22 * 8);
+        assert_eq!(offset_of!(ExceptionFrame, rax), 14 * 8);
+        assert_eq!(offset_of!(ExceptionFrame, vector), 15 * 8);
+        assert_eq!(offset_of!(ExceptionFrame, rip), 17 * 8);
+        assert_eq!(size_of::<ExceptionFrame>() % 16, 0);
+    }
+
+    #[test]
+    fn vectors_have_names() {
+        assert_eq!(name(8), "#DF");
+        assert_eq!(name(14), "#PF");
+        assert_eq!(name(20), "#VE");
+        assert_eq!(name(0x41), "Interrupt");
+    }
+}
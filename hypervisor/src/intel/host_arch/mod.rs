@@ -0,0 +1,258 @@
+//! The GDT, TSS and IDT the host runs on after VM exits, one set per processor.
+//!
+//! A VM exit loads GDTR, IDTR and TR from the host-state area, so exceptions in VM exit handlers are delivered
+//! through these tables instead of whatever the firmware left behind. The IDT has gates for NMIs, #UD, #DF, #GP
+//! and #PF, see `handlers`. Every other vector finds no gate and raises a #GP naming it in the error code,
+//! which is reported as well. #DF and NMIs switch to stacks of their own through the interrupt stack table,
+//! so they are reported even after the host stack overflowed or RSP was corrupted.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 6.14 EXCEPTION AND INTERRUPT HANDLING IN 64-BIT MODE
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 8.7 TASK MANAGEMENT IN 64-BIT MODE
+
+pub mod handlers;
+
+use core::{
+    mem::size_of,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// The selector of the host code segment.
+pub const HOST_CS: u16 = 0x08;
+
+/// The selector of the host TSS.
+pub const HOST_TR: u16 = 0x10;
+
+/// The interrupt stack table entry of #DF.
+pub const DOUBLE_FAULT_IST: u8 = 1;
+
+/// The interrupt stack table entry of NMIs.
+pub const NMI_IST: u8 = 2;
+
+/// The size of each interrupt stack.
+const HOST_STACK_SIZE: usize = 0x3000;
+
+/// Present, DPL 0, 64-bit code segment, readable, accessed.
+const CODE_SEGMENT: u64 = 0x00AF_9B00_0000_FFFF;
+
+/// The base of the hypervisor image, `0` until `record_image`.
+static IMAGE_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// The size of the hypervisor image.
+static IMAGE_SIZE: AtomicU64 = AtomicU64::new(0);
+
+/// The 64-bit Task State Segment, only used for its interrupt stack table.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Figure 8-11. 64-Bit TSS Format
+#[repr(C, packed(4))]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Tss {
+    reserved0: u32,
+
+    /// The stacks of privilege levels 0 to 2.
+    pub rsp: [u64; 3],
+
+    reserved1: u64,
+
+    /// The interrupt stack table, entry 1 first.
+    pub ist: [u64; 7],
+
+    reserved2: u64,
+    reserved3: u16,
+
+    /// The offset of the I/O permission bitmap, the size of the TSS for none.
+    pub io_map_base: u16,
+}
+
+/// One interrupt stack.
+#[repr(C, align(16))]
+struct HostStack([u8; HOST_STACK_SIZE]);
+
+/// The descriptor tables and interrupt stacks of the host on one processor.
+///
+/// The tables point into themselves, the structure must not move once `init` ran.
+///
+/// # Size
+/// - IDT: 256 * 16 bytes (0x1000)
+/// - GDT: 4 * 8 bytes (0x20)
+/// - TSS: 104 bytes (0x68)
+/// - Interrupt stacks: 2 * 12288 bytes (0x6000)
+/// - Total, aligned to 4096 bytes: 32768 bytes (0x8000)
+#[repr(C, align(4096))]
+pub struct HostArch {
+    /// The interrupt gates by vector.
+    idt: [[u64; 2]; 256],
+
+    /// The null descriptor, the code segment and the TSS descriptor.
+    gdt: [u64; 4],
+
+    /// The TSS pointing at the interrupt stacks.
+    tss: Tss,
+
+    /// The stack of #DF.
+    double_fault_stack: HostStack,
+
+    /// The stack of NMIs.
+    nmi_stack: HostStack,
+}
+
+impl HostArch {
+    /// Builds the tables in place.
+    pub fn init(&mut self) {
+        let mut tss = Tss {
+            io_map_base: size_of::<Tss>() as u16,
+            ..Default::default()
+        };
+        tss.ist[DOUBLE_FAULT_IST as usize - 1] = stack_top(&self.double_fault_stack);
+        tss.ist[NMI_IST as usize - 1] = stack_top(&self.nmi_stack);
+        self.tss = tss;
+
+        let [tss_low, tss_high] = tss_descriptor(self.tss_base(), size_of::<Tss>() as u32 - 1);
+        self.gdt = [0, CODE_SEGMENT, tss_low, tss_high];
+
+        self.idt = [[0; 2]; 256];
+        for (vector, handler, ist) in handlers::gates() {
+            self.idt[vector as usize] = interrupt_gate(handler, HOST_CS, ist);
+        }
+    }
+
+    /// Returns the address of the GDT.
+    pub fn gdt_base(&self) -> u64 {
+        self.gdt.as_ptr() as u64
+    }
+
+    /// Returns the address of the IDT.
+    pub fn idt_base(&self) -> u64 {
+        self.idt.as_ptr() as u64
+    }
+
+    /// Returns the address of the TSS.
+    pub fn tss_base(&self) -> u64 {
+        &self.tss as *const Tss as u64
+    }
+}
+
+/// Returns the initial RSP of an interrupt stack.
+fn stack_top(stack: &HostStack) -> u64 {
+    stack.0.as_ptr() as u64 + HOST_STACK_SIZE as u64
+}
+
+/// Builds a 64-bit interrupt gate.
+///
+/// # Arguments
+///
+/// * `handler` - The address of the handler.
+/// * `selector` - The code segment of the handler.
+/// * `ist` - The interrupt stack table entry to switch to, `0` to stay on the current stack.
+pub fn interrupt_gate(handler: u64, selector: u16, ist: u8) -> [u64; 2] {
+    /// Present, DPL 0, 64-bit interrupt gate.
+    const INTERRUPT_GATE: u64 = 0x8E;
+
+    let low = (handler & 0xFFFF) | (selector as u64) << 16 | ((ist & 0b111) as u64) << 32 | INTERRUPT_GATE << 40 | ((handler >> 16) & 0xFFFF) << 48;
+    [low, handler >> 32]
+}
+
+/// Builds the descriptor of an available 64-bit TSS.
+///
+/// # Arguments
+///
+/// * `base` - The address of the TSS.
+/// * `limit` - The size of the TSS minus one.
+pub fn tss_descriptor(base: u64, limit: u32) -> [u64; 2] {
+    /// Present, DPL 0, available 64-bit TSS.
+    const AVAILABLE_TSS: u64 = 0x89;
+
+    let low =
+        (limit as u64 & 0xFFFF) | (base & 0xFF_FFFF) << 16 | AVAILABLE_TSS << 40 | ((limit as u64 >> 16) & 0xF) << 48 | ((base >> 24) & 0xFF) << 56;
+    [low, base >> 32]
+}
+
+/// Records where the hypervisor image is loaded, so crash reports show addresses relative to it.
+///
+/// # Arguments
+///
+/// * `base` - The base address of the image.
+/// * `size` - The size of the image in bytes.
+pub fn record_image(base: u64, size: u64) {
+    IMAGE_SIZE.store(size, Ordering::Relaxed);
+    IMAGE_BASE.store(base, Ordering::Relaxed);
+}
+
+/// Returns the offset of `address` in the hypervisor image, `None` if it is outside or the image is unknown.
+pub fn image_offset(address: u64) -> Option<u64> {
+    offset_in(address, IMAGE_BASE.load(Ordering::Relaxed), IMAGE_SIZE.load(Ordering::Relaxed))
+}
+
+/// Returns the offset of `address` in the range of `size` bytes at `base`.
+fn offset_in(address: u64, base: u64, size: u64) -> Option<u64> {
+    let offset = address.checked_sub(base)?;
+    (base != 0 && offset < size).then_some(offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        core::mem::{align_of, offset_of},
+    };
+
+    #[test]
+    fn tables_have_the_architectural_layout() {
+        assert_eq!(size_of::<Tss>(), 104);
+        assert_eq!(offset_of!(Tss, rsp), 0x04);
+        assert_eq!(offset_of!(Tss, ist), 0x24);
+        assert_eq!(offset_of!(Tss, io_map_base), 0x66);
+
+        assert_eq!(size_of::<HostArch>(), 0x8000);
+        assert_eq!(align_of::<HostArch>(), 0x1000);
+        assert_eq!(offset_of!(HostArch, idt), 0);
+        assert_eq!(offset_of!(HostArch, double_fault_stack) % 16, 0);
+        assert_eq!(offset_of!(HostArch, nmi_stack) % 16, 0);
+
+        // The GDT holds the selectors the host-state area names.
+        assert_eq!(HOST_CS as usize / 8, 1);
+        assert_eq!(HOST_TR as usize / 8, 2);
+    }
+
+    #[test]
+    fn gates_split_the_handler_address() {
+        let [low, high] = interrupt_gate(0xFFFF_F801_2345_6789, 0x10, 0);
+        assert_eq!(low, 0x2345_8E00_0010_6789);
+        assert_eq!(high, 0xFFFF_F801);
+
+        let [low, _] = interrupt_gate(0xFFFF_F801_2345_6789, 0x10, NMI_IST);
+        assert_eq!(low, 0x2345_8E02_0010_6789);
+    }
+
+    #[test]
+    fn tss_descriptors_split_the_base() {
+        let [low, high] = tss_descriptor(0x0000_7FFE_1234_5678, 0x67);
+        assert_eq!(low, 0x1200_8934_5678_0067);
+        assert_eq!(high, 0x7FFE);
+    }
+
+    #[test]
+    fn addresses_resolve_against_the_image() {
+        assert_eq!(offset_in(0x1_2345, 0x1_0000, 0x8000), Some(0x2345));
+        assert_eq!(offset_in(0x1_8000, 0x1_0000, 0x8000), None);
+        assert_eq!(offset_in(0xFFFF, 0x1_0000, 0x8000), None);
+        assert_eq!(offset_in(0x1234, 0, 0x8000), None);
+    }
+
+    #[test]
+    fn init_points_into_the_structure() {
+        let mut host_arch: alloc::boxed::Box<HostArch> = unsafe { alloc::boxed::Box::new_zeroed().assume_init() };
+        host_arch.init();
+
+        let tss = host_arch.tss;
+        let ist = tss.ist;
+        let base = &*host_arch as *const HostArch as u64;
+        assert_eq!(ist[DOUBLE_FAULT_IST as usize - 1], base + offset_of!(HostArch, double_fault_stack) as u64 + HOST_STACK_SIZE as u64);
+        assert_eq!(ist[NMI_IST as usize - 1], base + offset_of!(HostArch, nmi_stack) as u64 + HOST_STACK_SIZE as u64);
+        assert_eq!(host_arch.gdt[1], CODE_SEGMENT);
+        assert_eq!(host_arch.gdt[2..], tss_descriptor(host_arch.tss_base(), 103));
+
+        // The #DF gate switches stacks, absent vectors stay empty.
+        assert_eq!((host_arch.idt[8][0] >> 32) & 0b111, DOUBLE_FAULT_IST as u64);
+        assert_eq!(host_arch.idt[0], [0, 0]);
+    }
+}
@@ -0,0 +1,229 @@
+//! Masquerades as Hyper-V, see `shared::hyperv`.
+//!
+//! The hypervisor CPUID leaves report the Hyper-V vendor with the minimal feature set of a partition that may only
+//! access the hypercall MSRs. HV_X64_MSR_GUEST_OS_ID and HV_X64_MSR_HYPERCALL are partition-wide and kept in
+//! `SYNTHETIC_MSRS`: Windows writes its identity and then enables the hypercall page, which the hypervisor fills with
+//! `VMCALL; RET` the way KVM does. VMCALLs from that page at CPL 0 fail with `HV_STATUS_INVALID_HYPERCALL_CODE`,
+//! as no hypercall is implemented, and raise #UD at CPL 3. All other synthetic MSRs still raise #GP(0).
+//!
+//! The mode is chosen by `HvFeatureFlags::HYPERV_MASQUERADE` and yields to `HIDE_CPUID_LEAF`, hiding the
+//! hypervisor and claiming to be Hyper-V contradict each other.
+//!
+//! Reference: https://learn.microsoft.com/en-us/virtualization/hyper-v-on-windows/tlfs/hypercall-interface
+
+use {
+    crate::{config, intel::cpuid_policy::registers},
+    core::ptr::{copy_nonoverlapping, write_bytes},
+    shared::{
+        features::HvFeatureFlags,
+        hyperv::{
+            HV_ACCESS_HYPERCALL_MSRS, HV_HYPERCALL_ENABLE, HV_HYPERCALL_LOCKED, HV_HYPERCALL_PAGE_MASK, HV_X64_MSR_GUEST_OS_ID, HV_X64_MSR_HYPERCALL,
+            HYPERV_INTERFACE_SIGNATURE, HYPERV_MAX_LEAF, HYPERV_VENDOR_SIGNATURE,
+        },
+    },
+    spin::Mutex,
+    x86::{bits64::paging::BASE_PAGE_SIZE, cpuid::CpuIdResult},
+};
+
+/// The hypervisor leaves Hyper-V reports, the leaves up to 0x400000FF not listed here read as zero.
+///
+/// * 0x40000000: the highest leaf and the vendor.
+/// * 0x40000001: the interface, "Hv#1".
+/// * 0x40000002: the system identity, Windows 10 build 19041.
+/// * 0x40000003: the partition privileges, only access to the hypercall MSRs.
+/// * 0x40000004: no recommendations, the spinlock retry count disabled.
+/// * 0x40000005: no implementation limits reported.
+pub const LEAVES: [(u32, CpuIdResult); 6] = [
+    (0x4000_0000, registers(HYPERV_MAX_LEAF, HYPERV_VENDOR_SIGNATURE[0], HYPERV_VENDOR_SIGNATURE[1], HYPERV_VENDOR_SIGNATURE[2])),
+    (0x4000_0001, registers(HYPERV_INTERFACE_SIGNATURE, 0, 0, 0)),
+    (0x4000_0002, registers(19041, 0x000A_0000, 0, 0)),
+    (0x4000_0003, registers(HV_ACCESS_HYPERCALL_MSRS, 0, 0, 0)),
+    (0x4000_0004, registers(0, u32::MAX, 0, 0)),
+    (0x4000_0005, registers(0, 0, 0, 0)),
+];
+
+/// The code of the hypercall page, `VMCALL; RET`, the rest of the page is filled with `INT3`.
+pub const HYPERCALL_CODE: [u8; 4] = [0x0F, 0x01, 0xC1, 0xC3];
+
+/// [Bits 11:2] HV_X64_MSR_HYPERCALL: reserved.
+const HYPERCALL_RESERVED: u64 = !(HV_HYPERCALL_PAGE_MASK | HV_HYPERCALL_LOCKED | HV_HYPERCALL_ENABLE);
+
+/// Returns whether the features masquerade as Hyper-V.
+///
+/// # Arguments
+///
+/// * `features` - The features enabled for this boot.
+pub fn masquerades(features: HvFeatureFlags) -> bool {
+    features.contains(HvFeatureFlags::HYPERV_MASQUERADE) && !features.contains(HvFeatureFlags::HIDE_CPUID_LEAF)
+}
+
+/// Returns whether the hypervisor masquerades as Hyper-V on this boot.
+pub fn masquerading() -> bool {
+    masquerades(config::features())
+}
+
+/// What a write to a synthetic MSR asks the hypervisor to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyntheticWrite {
+    /// Nothing beyond storing the value.
+    Stored,
+
+    /// Fill the hypercall page at this guest physical address.
+    FillHypercallPage(u64),
+}
+
+/// The synthetic MSRs of the partition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyntheticMsrs {
+    /// HV_X64_MSR_GUEST_OS_ID, zero until the guest identifies itself.
+    pub guest_os_id: u64,
+
+    /// HV_X64_MSR_HYPERCALL.
+    pub hypercall: u64,
+}
+
+impl SyntheticMsrs {
+    /// The MSRs after reset.
+    pub const fn new() -> Self {
+        Self {
+            guest_os_id: 0,
+            hypercall: 0,
+        }
+    }
+
+    /// Returns the value RDMSR reads, `None` for the synthetic MSRs that aren't implemented.
+    ///
+    /// # Arguments
+    ///
+    /// * `msr` - The MSR the guest reads.
+    pub fn read(&self, msr: u32) -> Option<u64> {
+        match msr {
+            HV_X64_MSR_GUEST_OS_ID => Some(self.guest_os_id),
+            HV_X64_MSR_HYPERCALL => Some(self.hypercall),
+            _ => None,
+        }
+    }
+
+    /// Applies a value WRMSR writes.
+    ///
+    /// Clearing the guest OS ID disables the hypercall page, which can't be enabled without one. Writes to a
+    /// locked HV_X64_MSR_HYPERCALL are ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `msr` - The MSR the guest writes.
+    /// * `value` - EDX:EAX of WRMSR.
+    ///
+    /// # Returns
+    ///
+    /// What is left to do, `None` if the instruction raises #GP(0) for an MSR that isn't implemented or for
+    /// setting reserved bits.
+    pub fn write(&mut self, msr: u32, value: u64) -> Option<SyntheticWrite> {
+        match msr {
+            HV_X64_MSR_GUEST_OS_ID => {
+                self.guest_os_id = value;
+                if value == 0 {
+                    self.hypercall &= !HV_HYPERCALL_ENABLE;
+                }
+                Some(SyntheticWrite::Stored)
+            }
+            HV_X64_MSR_HYPERCALL if value & HYPERCALL_RESERVED != 0 => None,
+            HV_X64_MSR_HYPERCALL if self.hypercall & HV_HYPERCALL_LOCKED != 0 => Some(SyntheticWrite::Stored),
+            HV_X64_MSR_HYPERCALL => {
+                let value = if self.guest_os_id == 0 { value & !HV_HYPERCALL_ENABLE } else { value };
+                let previous = core::mem::replace(&mut self.hypercall, value);
+                match self.hypercall_page() {
+                    Some(page) if previous != value => Some(SyntheticWrite::FillHypercallPage(page)),
+                    _ => Some(SyntheticWrite::Stored),
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the guest physical address of the hypercall page, `None` while it is disabled.
+    pub fn hypercall_page(&self) -> Option<u64> {
+        (self.hypercall & HV_HYPERCALL_ENABLE != 0).then_some(self.hypercall & HV_HYPERCALL_PAGE_MASK)
+    }
+}
+
+impl Default for SyntheticMsrs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The synthetic MSRs of the guest, shared by all processors.
+pub static SYNTHETIC_MSRS: Mutex<SyntheticMsrs> = Mutex::new(SyntheticMsrs::new());
+
+/// Fills the hypercall page with `HYPERCALL_CODE`.
+///
+/// # Arguments
+///
+/// * `page` - The guest physical address of the page, identity mapped in the host.
+/// * `allocated` - The memory ranges of the hypervisor, as start and size, which the page must not overlap.
+///
+/// # Returns
+///
+/// Whether the page was filled, a page in hypervisor memory is left alone.
+pub fn fill_hypercall_page(page: u64, allocated: &[(usize, usize)]) -> bool {
+    let end = page + BASE_PAGE_SIZE as u64;
+    if allocated
+        .iter()
+        .any(|&(start, size)| (start as u64) < end && page < (start + size) as u64)
+    {
+        return false;
+    }
+
+    // Safety: the page is guest memory the guest gave up for the hypercall page, and not the hypervisor's.
+    unsafe {
+        write_bytes(page as *mut u8, 0xCC, BASE_PAGE_SIZE);
+        copy_nonoverlapping(HYPERCALL_CODE.as_ptr(), page as *mut u8, HYPERCALL_CODE.len());
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOWS_10: u64 = 0x0001_040A_0000_4A61;
+
+    #[test]
+    fn hypercall_page_needs_a_guest_os_id() {
+        let mut msrs = SyntheticMsrs::new();
+        assert_eq!(msrs.write(HV_X64_MSR_HYPERCALL, 0x1234_5000 | HV_HYPERCALL_ENABLE), Some(SyntheticWrite::Stored));
+        assert_eq!(msrs.hypercall_page(), None);
+        assert_eq!(msrs.read(HV_X64_MSR_HYPERCALL), Some(0x1234_5000));
+
+        assert_eq!(msrs.write(HV_X64_MSR_GUEST_OS_ID, WINDOWS_10), Some(SyntheticWrite::Stored));
+        assert_eq!(msrs.write(HV_X64_MSR_HYPERCALL, 0x1234_5000 | HV_HYPERCALL_ENABLE), Some(SyntheticWrite::FillHypercallPage(0x1234_5000)));
+        assert_eq!(msrs.read(HV_X64_MSR_GUEST_OS_ID), Some(WINDOWS_10));
+
+        // Clearing the guest OS ID disables the page.
+        msrs.write(HV_X64_MSR_GUEST_OS_ID, 0);
+        assert_eq!(msrs.hypercall_page(), None);
+    }
+
+    #[test]
+    fn hypercall_msr_follows_the_tlfs() {
+        let mut msrs = SyntheticMsrs::new();
+        msrs.write(HV_X64_MSR_GUEST_OS_ID, WINDOWS_10);
+
+        assert_eq!(msrs.write(HV_X64_MSR_HYPERCALL, 0x1234_5000 | 1 << 4), None);
+        assert_eq!(msrs.write(0x4000_0002, 0), None);
+        assert_eq!(msrs.read(0x4000_0002), None);
+
+        let locked = 0x1234_5000 | HV_HYPERCALL_LOCKED | HV_HYPERCALL_ENABLE;
+        assert_eq!(msrs.write(HV_X64_MSR_HYPERCALL, locked), Some(SyntheticWrite::FillHypercallPage(0x1234_5000)));
+        assert_eq!(msrs.write(HV_X64_MSR_HYPERCALL, 0x6789_0000 | HV_HYPERCALL_ENABLE), Some(SyntheticWrite::Stored));
+        assert_eq!(msrs.read(HV_X64_MSR_HYPERCALL), Some(locked));
+    }
+
+    #[test]
+    fn stealth_wins_over_masquerading() {
+        assert!(masquerades(HvFeatureFlags::HYPERV_MASQUERADE | HvFeatureFlags::EPT_HOOKS));
+        assert!(!masquerades(HvFeatureFlags::HYPERV_MASQUERADE | HvFeatureFlags::HIDE_CPUID_LEAF));
+        assert!(!masquerades(HvFeatureFlags::DEFAULT));
+    }
+}
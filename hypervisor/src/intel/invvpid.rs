@@ -3,8 +3,18 @@
 //! The INVVPID (Invalidate VPID) instruction is used to invalidate entries in the TLB and paging-structure caches
 //! that are associated with a specific Virtual Processor Identifier (VPID). This is essential in virtualization
 //! environments to maintain consistency of memory translations across different virtual processors.
+//!
+//! Every processor tags the translations of its guest with its own non-zero VPID, so VM entries and exits no longer
+//! flush the TLB. Whoever changes how the guest translates addresses calls `flush` with the narrowest `TlbScope` that
+//! is architecturally sufficient, and `flush` picks the narrowest INVVPID type the processor supports for it. The
+//! `CONFIG_FLAG_FULL_TLB_FLUSHES` chicken flag turns every flush into an all-context one for debugging.
+//!
+//! Changes to the EPT need no INVVPID at all, INVEPT already invalidates the combined mappings of every VPID.
 
-pub const VPID_TAG: u16 = 0x1;
+use {
+    crate::{config, intel::support::rdmsr},
+    x86::{msr, vmx::vmcs::control::SecondaryControls},
+};
 
 /// Represents the types of INVVPID operations.
 #[repr(u64)]
@@ -16,16 +26,16 @@ pub enum InvvpidType {
     IndividualAddress = 0,
 
     /// Invalidate mappings associated with a specific VPID.
-    /// This type invalidates all mappings—except global translations—associated with the specified VPID.
+    /// This type invalidates all mappings—including global translations—associated with the specified VPID.
     SingleContext = 1,
 
     /// Invalidate mappings—including global translations—associated with all VPIDs.
-    /// This type invalidates all mappings for all VPIDs.
-    AllContextsIncludingGlobals = 2,
+    /// This type invalidates all mappings for all VPIDs except VPID 0.
+    AllContexts = 2,
 
-    /// Invalidate mappings associated with all VPIDs except global translations.
-    /// This type invalidates all mappings except for global translations for all VPIDs.
-    AllContexts = 3,
+    /// Invalidate mappings associated with a specific VPID except global translations.
+    /// This type invalidates all mappings—except global translations—associated with the specified VPID.
+    SingleContextRetainingGlobals = 3,
 }
 
 /// Represents an INVVPID descriptor.
@@ -40,6 +50,152 @@ pub struct InvvpidDescriptor {
     pub linear_address: u64,
 }
 
+/// The translations a change of the guest paging state may have made stale.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TlbScope {
+    /// The non-global translations of one linear address, like INVLPG.
+    Address(u64),
+
+    /// The non-global translations of the processor, like MOV to CR3.
+    NonGlobal,
+
+    /// All translations of the processor, like toggling CR4.PGE.
+    Context,
+
+    /// All translations of every processor.
+    AllContexts,
+}
+
+/// The INVVPID types the processor supports.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: A.10 VPID AND EPT CAPABILITIES
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct InvvpidSupport {
+    /// The individual-address type, bit 40 of IA32_VMX_EPT_VPID_CAP.
+    pub individual_address: bool,
+
+    /// The single-context type, bit 41.
+    pub single_context: bool,
+
+    /// The all-context type, bit 42.
+    pub all_contexts: bool,
+
+    /// The single-context-retaining-globals type, bit 43.
+    pub single_context_retaining_globals: bool,
+}
+
+impl InvvpidSupport {
+    /// Decodes the capability MSRs, reporting no types unless the "enable VPID" control can be set.
+    ///
+    /// # Arguments
+    ///
+    /// * `ept_vpid_cap` - IA32_VMX_EPT_VPID_CAP.
+    /// * `procbased_ctls2` - IA32_VMX_PROCBASED_CTLS2, whose upper half holds the allowed 1-settings.
+    pub fn from_capabilities(ept_vpid_cap: u64, procbased_ctls2: u64) -> Self {
+        /// [Bit 32] When set to 1, the INVVPID instruction is supported.
+        const INVVPID: u64 = 1 << 32;
+
+        let vpid_allowed = (procbased_ctls2 >> 32) & SecondaryControls::ENABLE_VPID.bits() as u64 != 0;
+        if !vpid_allowed || ept_vpid_cap & INVVPID == 0 {
+            return Self::default();
+        }
+
+        Self {
+            individual_address: ept_vpid_cap & (1 << 40) != 0,
+            single_context: ept_vpid_cap & (1 << 41) != 0,
+            all_contexts: ept_vpid_cap & (1 << 42) != 0,
+            single_context_retaining_globals: ept_vpid_cap & (1 << 43) != 0,
+        }
+    }
+
+    /// Reads the capabilities of the current processor.
+    pub fn read() -> Self {
+        Self::from_capabilities(rdmsr(msr::IA32_VMX_EPT_VPID_CAP), rdmsr(msr::IA32_VMX_PROCBASED_CTLS2))
+    }
+
+    /// Returns whether VPIDs can be used, which takes a type able to flush everything of a VPID.
+    pub fn vpid_usable(&self) -> bool {
+        self.single_context || self.all_contexts
+    }
+
+    /// Returns the narrowest supported INVVPID type invalidating at least `scope`.
+    ///
+    /// # Arguments
+    ///
+    /// * `scope` - The translations that have to be invalidated.
+    /// * `full_flushes` - Invalidate all contexts where supported, `CONFIG_FLAG_FULL_TLB_FLUSHES`.
+    ///
+    /// # Returns
+    ///
+    /// `None` if the processor supports no type that is wide enough.
+    pub fn narrowest(&self, scope: TlbScope, full_flushes: bool) -> Option<InvvpidType> {
+        if full_flushes && self.all_contexts {
+            return Some(InvvpidType::AllContexts);
+        }
+
+        let candidates = [
+            (InvvpidType::IndividualAddress, self.individual_address, matches!(scope, TlbScope::Address(_))),
+            (
+                InvvpidType::SingleContextRetainingGlobals,
+                self.single_context_retaining_globals,
+                matches!(scope, TlbScope::Address(_) | TlbScope::NonGlobal),
+            ),
+            (InvvpidType::SingleContext, self.single_context, scope != TlbScope::AllContexts),
+            (InvvpidType::AllContexts, self.all_contexts, true),
+        ];
+
+        candidates
+            .into_iter()
+            .find(|(_, supported, sufficient)| *supported && *sufficient)
+            .map(|(invvpid_type, ..)| invvpid_type)
+    }
+}
+
+/// Returns the VPID of the processor with the initial APIC ID `apic_id`.
+///
+/// VPID 0 belongs to the host, so the VPIDs start at 1 and stay unique for every APIC ID below `u16::MAX`.
+pub fn vpid_for(apic_id: u32) -> u16 {
+    (apic_id as u16).wrapping_add(1).max(1)
+}
+
+/// Invalidates the translations of `scope` cached for `vpid`.
+///
+/// # Arguments
+///
+/// * `vpid` - The VPID of the current processor, `0` if VPIDs are disabled and every VM entry and exit flushes the TLBs anyway.
+/// * `scope` - The translations that have to be invalidated.
+pub fn flush(vpid: u16, scope: TlbScope) {
+    if vpid == 0 && scope != TlbScope::AllContexts {
+        return;
+    }
+
+    let Some(invvpid_type) = InvvpidSupport::read().narrowest(scope, config::full_tlb_flushes()) else {
+        return;
+    };
+
+    let linear_address = match scope {
+        TlbScope::Address(address) => address,
+        _ => 0,
+    };
+    invvpid(
+        invvpid_type,
+        &InvvpidDescriptor {
+            vpid,
+            reserved: [0; 3], // Reserved fields, must be zero
+            linear_address,
+        },
+    );
+}
+
+/// Invalidates the VPID-tagged translations an EPT change may have made stale.
+///
+/// INVEPT already invalidated the combined mappings of every VPID, so this only flushes with the chicken flag set.
+pub fn flush_for_ept_change() {
+    if config::full_tlb_flushes() {
+        flush(0, TlbScope::AllContexts);
+    }
+}
+
 /// Performs the INVVPID instruction.
 ///
 /// # Arguments
@@ -57,44 +213,51 @@ fn invvpid(invvpid_type: InvvpidType, descriptor: &InvvpidDescriptor) {
     }
 }
 
-/// Invalidates TLB and paging-structure cache entries associated with a specific linear address and VPID.
-///
-/// # Arguments
-/// * `vpid` - Virtual Processor Identifier.
-/// * `linear_address` - Specific linear address whose mappings are to be invalidated.
-pub fn invvpid_individual_address(vpid: u16, linear_address: u64) {
-    let descriptor = InvvpidDescriptor {
-        vpid,
-        reserved: [0; 3], // Reserved fields, must be zero
-        linear_address,
-    };
-    // Perform the INVVPID operation for an individual address.
-    invvpid(InvvpidType::IndividualAddress, &descriptor);
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-/// Invalidates TLB and paging-structure cache entries associated with a specific VPID.
-///
-/// # Arguments
-/// * `vpid` - Virtual Processor Identifier.
-pub fn invvpid_single_context(vpid: u16) {
-    let descriptor = InvvpidDescriptor {
-        vpid,              // VPID of the target context
-        reserved: [0; 3],  // Reserved fields, must be zero
-        linear_address: 0, // Irrelevant for SingleContext, but required for struct completeness
-    };
-    // Perform the INVVPID operation for a single context.
-    invvpid(InvvpidType::SingleContext, &descriptor);
-}
+    const VPID_ALLOWED: u64 = (SecondaryControls::ENABLE_VPID.bits() as u64) << 32;
 
-/// Invalidates TLB and paging-structure cache entries for all VPIDs.
-///
-/// This operation ignores the descriptor fields as they are irrelevant for the AllContexts type.
-pub fn invvpid_all_contexts() {
-    let descriptor = InvvpidDescriptor {
-        vpid: 0,           // Irrelevant for AllContexts
-        reserved: [0; 3],  // Reserved fields, must be zero
-        linear_address: 0, // Irrelevant for AllContexts
-    };
-    // Perform the INVVPID operation for all contexts.
-    invvpid(InvvpidType::AllContexts, &descriptor);
+    const ALL_TYPES: u64 = 1 << 32 | 0xF << 40;
+
+    #[test]
+    fn capabilities_require_the_vpid_control() {
+        assert_eq!(InvvpidSupport::from_capabilities(ALL_TYPES, 0), InvvpidSupport::default());
+        assert_eq!(InvvpidSupport::from_capabilities(0xF << 40, VPID_ALLOWED), InvvpidSupport::default());
+
+        let support = InvvpidSupport::from_capabilities(1 << 32 | 1 << 42, VPID_ALLOWED);
+        assert!(support.vpid_usable() && support.all_contexts && !support.single_context);
+        assert!(!InvvpidSupport::from_capabilities(1 << 32 | 1 << 40, VPID_ALLOWED).vpid_usable());
+    }
+
+    #[test]
+    fn flushes_use_the_narrowest_type() {
+        let all = InvvpidSupport::from_capabilities(ALL_TYPES, VPID_ALLOWED);
+        assert_eq!(all.narrowest(TlbScope::Address(0x1000), false), Some(InvvpidType::IndividualAddress));
+        assert_eq!(all.narrowest(TlbScope::NonGlobal, false), Some(InvvpidType::SingleContextRetainingGlobals));
+        assert_eq!(all.narrowest(TlbScope::Context, false), Some(InvvpidType::SingleContext));
+        assert_eq!(all.narrowest(TlbScope::AllContexts, false), Some(InvvpidType::AllContexts));
+        assert_eq!(all.narrowest(TlbScope::Address(0x1000), true), Some(InvvpidType::AllContexts));
+    }
+
+    #[test]
+    fn missing_types_widen_the_flush() {
+        let single = InvvpidSupport::from_capabilities(1 << 32 | 1 << 41, VPID_ALLOWED);
+        assert_eq!(single.narrowest(TlbScope::Address(0x1000), false), Some(InvvpidType::SingleContext));
+        assert_eq!(single.narrowest(TlbScope::NonGlobal, false), Some(InvvpidType::SingleContext));
+        assert_eq!(single.narrowest(TlbScope::AllContexts, false), None);
+        assert_eq!(single.narrowest(TlbScope::Context, true), Some(InvvpidType::SingleContext));
+
+        let all_only = InvvpidSupport::from_capabilities(1 << 32 | 1 << 42, VPID_ALLOWED);
+        assert_eq!(all_only.narrowest(TlbScope::Address(0x1000), false), Some(InvvpidType::AllContexts));
+        assert_eq!(InvvpidSupport::default().narrowest(TlbScope::Context, false), None);
+    }
+
+    #[test]
+    fn vpids_are_unique_and_non_zero() {
+        assert_eq!(vpid_for(0), 1);
+        assert_eq!(vpid_for(255), 256);
+        assert_ne!(vpid_for(u16::MAX as u32), 0);
+    }
 }
@@ -0,0 +1,180 @@
+//! The physical address ranges that exist on this machine, which the EPT identity map covers.
+//!
+//! The UEFI image captures the firmware memory map before the processors are virtualized, adds the low 4 GiB
+//! with the PCI hole and the APICs, and the uncacheable ranges of the MTRRs that PCI MMIO windows live in.
+//! `Ept::build_identity` maps every 2MB page this map touches and traps the others, which are mapped on
+//! demand if the guest accesses them after all.
+
+use {crate::intel::mtrr::Mtrr, core::ops::Range, spin::RwLock, x86::bits64::paging::LARGE_PAGE_SIZE};
+
+/// The most disjoint ranges the map holds, further ones are merged into their neighbours.
+pub const MAX_MEMORY_RANGES: usize = 128;
+
+/// The end of the low memory that is mapped unconditionally, for the legacy ranges, the 32-bit PCI hole,
+/// the I/O APIC, the HPET and the local APIC that firmware memory maps usually leave out.
+pub const ALWAYS_MAPPED_END: u64 = 0x1_0000_0000;
+
+/// The physical memory map of this boot, empty until the UEFI image captured it.
+pub static PHYSICAL_MEMORY_MAP: RwLock<PhysicalMemoryMap> = RwLock::new(PhysicalMemoryMap::new());
+
+/// Sorted, disjoint physical address ranges aligned to 2MB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhysicalMemoryMap {
+    /// The start and end of each range, the first `len` are valid.
+    ranges: [(u64, u64); MAX_MEMORY_RANGES],
+
+    /// The number of valid ranges.
+    len: usize,
+}
+
+impl PhysicalMemoryMap {
+    /// Creates an empty map, which the EPT takes as "map everything".
+    pub const fn new() -> Self {
+        Self {
+            ranges: [(0, 0); MAX_MEMORY_RANGES],
+            len: 0,
+        }
+    }
+
+    /// Returns whether no range was added, so the memory map wasn't captured.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the ranges in ascending order.
+    pub fn ranges(&self) -> impl Iterator<Item = Range<u64>> + '_ {
+        self.ranges[..self.len].iter().map(|&(start, end)| start..end)
+    }
+
+    /// Returns the end of the highest range, `0` for an empty map.
+    pub fn end(&self) -> u64 {
+        self.len.checked_sub(1).map_or(0, |last| self.ranges[last].1)
+    }
+
+    /// Adds a range, widened to 2MB boundaries and merged with the ranges it overlaps or touches.
+    ///
+    /// # Arguments
+    ///
+    /// * `range` - The physical addresses that exist.
+    pub fn add(&mut self, range: Range<u64>) {
+        if range.is_empty() {
+            return;
+        }
+
+        let large_page = LARGE_PAGE_SIZE as u64;
+        let mut start = range.start & !(large_page - 1);
+        let mut end = range.end.saturating_add(large_page - 1) & !(large_page - 1);
+
+        // The ranges before `first` end before `start`, the ones from `last` on start after `end`.
+        let first = self.ranges[..self.len].partition_point(|&(_, range_end)| range_end < start);
+        let last = self.ranges[..self.len].partition_point(|&(range_start, _)| range_start <= end);
+
+        if first < last {
+            start = start.min(self.ranges[first].0);
+            end = end.max(self.ranges[last - 1].1);
+        } else if self.len == MAX_MEMORY_RANGES {
+            // Mapping a gap is harmless, widen the neighbour instead of dropping the range.
+            let neighbour = first.min(self.len - 1);
+            let (neighbour_start, neighbour_end) = self.ranges[neighbour];
+            self.ranges[neighbour] = (neighbour_start.min(start), neighbour_end.max(end));
+            return;
+        }
+
+        // Replace the merged ranges `first..last` by the single new one.
+        self.ranges.copy_within(last..self.len, first + 1);
+        self.len = self.len + 1 - (last - first);
+        self.ranges[first] = (start, end);
+    }
+
+    /// Adds the ranges that exist on every machine or are implied by the MTRRs, see the module documentation.
+    ///
+    /// # Arguments
+    ///
+    /// * `mtrr` - The MTRRs of the processor.
+    pub fn add_platform_ranges(&mut self, mtrr: &Mtrr) {
+        self.add(0..ALWAYS_MAPPED_END);
+        for range in mtrr.mmio_ranges() {
+            self.add(range);
+        }
+    }
+
+    /// Returns whether the 2MB page at `pa` has to be mapped.
+    ///
+    /// # Arguments
+    ///
+    /// * `pa` - A physical address in the page.
+    pub fn contains(&self, pa: u64) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+
+        let index = self.ranges[..self.len].partition_point(|&(_, end)| end <= pa);
+        self.ranges[..self.len].get(index).is_some_and(|&(start, _)| start <= pa)
+    }
+}
+
+impl Default for PhysicalMemoryMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Replaces the memory map the EPT of every processor is built from.
+///
+/// # Arguments
+///
+/// * `map` - The captured memory map.
+pub fn set_physical_memory_map(map: PhysicalMemoryMap) {
+    *PHYSICAL_MEMORY_MAP.write() = map;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: u64 = 0x10_0000;
+
+    #[test]
+    fn ranges_are_widened_and_merged() {
+        let mut map = PhysicalMemoryMap::new();
+        assert!(map.is_empty() && map.contains(0x1234_5678_9000));
+
+        map.add(0x1000..0x9_F000);
+        map.add(10 * MB..11 * MB);
+        map.add(6 * MB..7 * MB);
+        assert_eq!(map.ranges().collect::<alloc::vec::Vec<_>>(), [0..2 * MB, 6 * MB..8 * MB, 10 * MB..12 * MB]);
+
+        // Touching ranges and ranges spanning others collapse into one.
+        map.add(2 * MB..3 * MB);
+        assert_eq!(map.ranges().count(), 3);
+        map.add(5 * MB..14 * MB);
+        assert_eq!(map.ranges().collect::<alloc::vec::Vec<_>>(), [0..14 * MB]);
+    }
+
+    #[test]
+    fn lookups_cover_exactly_the_ranges() {
+        let mut map = PhysicalMemoryMap::new();
+        map.add(0..ALWAYS_MAPPED_END);
+        map.add(0x80_0000_0000..0x80_4000_0000);
+
+        assert!(map.contains(0));
+        assert!(map.contains(ALWAYS_MAPPED_END - 1));
+        assert!(!map.contains(ALWAYS_MAPPED_END));
+        assert!(map.contains(0x80_3FFF_F000));
+        assert!(!map.contains(0x80_4000_0000));
+        assert_eq!(map.end(), 0x80_4000_0000);
+    }
+
+    #[test]
+    fn a_full_map_widens_a_neighbour() {
+        let mut map = PhysicalMemoryMap::new();
+        for i in 0..MAX_MEMORY_RANGES as u64 {
+            map.add(i * 8 * MB..i * 8 * MB + 2 * MB);
+        }
+
+        map.add(4 * MB..5 * MB);
+        assert_eq!(map.ranges().count(), MAX_MEMORY_RANGES);
+        assert!(map.contains(4 * MB) && map.contains(6 * MB));
+        assert!(!map.contains(2 * MB));
+    }
+}
@@ -1,22 +1,44 @@
 pub mod addresses;
+pub mod apic;
 pub mod bitmap;
 pub mod capture;
 pub mod controls;
+pub mod cpuid_policy;
+pub mod cr_shadow;
+pub mod debug_regs;
 pub mod descriptor;
+pub mod efer;
+pub mod entry_checks;
+pub mod entry_failure;
 pub mod ept;
+pub mod ept_views;
 pub mod events;
+pub mod guest_memory;
 pub mod hooks;
+pub mod host_arch;
+pub mod hyperv;
 pub mod invept;
 pub mod invvpid;
+pub mod memory_map;
+pub mod mode_watch;
 pub mod mtrr;
+pub mod nmi;
 pub mod page;
+pub mod page_walk;
 pub mod paging;
+pub mod regions;
 pub mod segmentation;
+pub mod shootdown;
 pub mod state;
 pub mod support;
+pub mod tsc;
+pub mod v86;
+pub mod vcpu;
+pub mod ve;
 pub mod vm;
 pub mod vmcs;
 pub mod vmerror;
 pub mod vmexit;
 pub mod vmlaunch;
 pub mod vmxon;
+pub mod xstate;
@@ -0,0 +1,587 @@
+//! Runs the guest through real mode and unpaged protected mode on processors without "unrestricted guest".
+//!
+//! Without "unrestricted guest" VMX operation fixes CR0.PE and CR0.PG to 1, a VM entry only accepts a guest in
+//! paged protected mode. The application processors the operating system starts with INIT-SIPI-SIPI begin in
+//! real mode, so the mode the guest intends is watched through the CR0 read shadow, whose CR0.PE and CR0.PG
+//! belong to the guest/host mask, and the guest runs in one the processor accepts until it enables paging:
+//!
+//! - Real mode runs in virtual-8086 mode. Privileged instructions raise #GP(0) there instead of exiting, so
+//!   every exception exits and `v86` emulates the instruction or delivers the exception through the interrupt
+//!   vector table, like every event injected meanwhile. INT n goes through the vector table natively, with
+//!   CR4.VME and the clear interrupt redirection bitmap of the TSS in `RealModePages`, and the clear I/O bitmap
+//!   behind it lets port I/O run natively too. The IDTR is loaded with a limit of 0, so an external interrupt
+//!   the processor would deliver through it exits as #GP and is delivered by `v86` instead.
+//! - Both modes are paged with the identity map of `RealModePages`, 4-MByte pages covering the 32-bit physical
+//!   address space. The CR3 the guest loads, its CR4.PAE and its EFER.LME wait aside until it enables paging.
+//!
+//! The guest's own CR3, CR4, IA32_EFER, IDTR and TR are only loaded, and the guest only fully virtualized, once
+//! it reaches paged protected mode. The segments are converted between the two modes, the selectors of real
+//! mode give the bases of virtual-8086 mode and lose their RPL in protected mode, and SIDT reads the IDTR the
+//! guest runs with in real mode. Processors with "unrestricted guest" run every mode natively and `ModeWatch`
+//! stays out of the way.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 20.2 VIRTUAL-8086 MODE
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 20.3.3 Class 3—Software Interrupt Handling in Virtual-8086 Mode
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.3.1.2 Checks on Guest Segment Registers
+
+use {
+    crate::intel::{
+        cr_shadow::CrShadow,
+        efer::{self, LME},
+        invvpid::TlbScope,
+        support::{rdmsr, read_effective_guest_cr0, read_effective_guest_cr4, vmread, vmwrite},
+        vm::Vm,
+    },
+    log::*,
+    x86::{
+        bits64::paging::BASE_PAGE_SIZE,
+        msr,
+        vmx::vmcs::{
+            self,
+            control::{PrimaryControls, SecondaryControls},
+        },
+    },
+    x86_64::registers::control::{Cr0Flags, Cr4Flags},
+};
+
+/// [Bit 17] RFLAGS: virtual-8086 mode.
+pub const RFLAGS_VM: u64 = 1 << 17;
+
+/// [Bits 13:12] RFLAGS: I/O privilege level.
+const RFLAGS_IOPL: u64 = 0b11 << 12;
+
+/// The access rights of segments in virtual-8086 mode: present, DPL 3, read/write data, accessed.
+const AR_V86: u64 = 0xF3;
+
+/// The access rights of CS leaving real mode: present, DPL 0, execute/read code, accessed.
+const AR_CODE: u64 = 0x9B;
+
+/// The access rights of the other segments leaving real mode: present, DPL 0, read/write data, accessed.
+const AR_DATA: u64 = 0x93;
+
+/// The access rights of the TSS in `RealModePages`: present, busy 32-bit TSS.
+const AR_TSS: u64 = 0x8B;
+
+/// The CR4 bits the identity map and virtual-8086 mode depend on, monitored while the guest is unpaged.
+const CR4_EMULATED: u64 =
+    Cr4Flags::PHYSICAL_ADDRESS_EXTENSION.bits() | Cr4Flags::PAGE_SIZE_EXTENSION.bits() | Cr4Flags::VIRTUAL_8086_MODE_EXTENSIONS.bits();
+
+/// The controls making MOV to and from CR3 exit while the guest is unpaged.
+const CR3_EXITING: u64 = (PrimaryControls::CR3_LOAD_EXITING.bits() | PrimaryControls::CR3_STORE_EXITING.bits()) as u64;
+
+/// [Bits 0, 1, 2, 7] Page directory entry: present, writable, user-accessible, 4-MByte page.
+const PDE_LARGE_PAGE: u32 = 0x87;
+
+/// The size of the 32-bit TSS itself.
+const TSS_HEADER_SIZE: usize = 104;
+
+/// The offset of the I/O map base address field in the TSS.
+const IO_MAP_BASE_FIELD: usize = 102;
+
+/// The offset of the I/O bitmap, the 32 bytes of the interrupt redirection bitmap lie right below it.
+const IO_MAP_BASE: usize = TSS_HEADER_SIZE + 32;
+
+/// The size of the TSS: the TSS itself, both bitmaps and the byte of 1s terminating the I/O bitmap.
+pub const TSS_SIZE: usize = IO_MAP_BASE + 0x2000 + 1;
+
+/// The guest-state fields of a segment register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentFields {
+    pub selector: u32,
+    pub base: u32,
+    pub limit: u32,
+    pub access_rights: u32,
+}
+
+/// The segment registers by the number instructions encode them with, ES, CS, SS, DS, FS and GS.
+pub const SEGMENTS: [SegmentFields; 6] = [
+    SegmentFields {
+        selector: vmcs::guest::ES_SELECTOR,
+        base: vmcs::guest::ES_BASE,
+        limit: vmcs::guest::ES_LIMIT,
+        access_rights: vmcs::guest::ES_ACCESS_RIGHTS,
+    },
+    SegmentFields {
+        selector: vmcs::guest::CS_SELECTOR,
+        base: vmcs::guest::CS_BASE,
+        limit: vmcs::guest::CS_LIMIT,
+        access_rights: vmcs::guest::CS_ACCESS_RIGHTS,
+    },
+    SegmentFields {
+        selector: vmcs::guest::SS_SELECTOR,
+        base: vmcs::guest::SS_BASE,
+        limit: vmcs::guest::SS_LIMIT,
+        access_rights: vmcs::guest::SS_ACCESS_RIGHTS,
+    },
+    SegmentFields {
+        selector: vmcs::guest::DS_SELECTOR,
+        base: vmcs::guest::DS_BASE,
+        limit: vmcs::guest::DS_LIMIT,
+        access_rights: vmcs::guest::DS_ACCESS_RIGHTS,
+    },
+    SegmentFields {
+        selector: vmcs::guest::FS_SELECTOR,
+        base: vmcs::guest::FS_BASE,
+        limit: vmcs::guest::FS_LIMIT,
+        access_rights: vmcs::guest::FS_ACCESS_RIGHTS,
+    },
+    SegmentFields {
+        selector: vmcs::guest::GS_SELECTOR,
+        base: vmcs::guest::GS_BASE,
+        limit: vmcs::guest::GS_LIMIT,
+        access_rights: vmcs::guest::GS_ACCESS_RIGHTS,
+    },
+];
+
+/// The number of CS in `SEGMENTS`.
+pub const CS: u8 = 1;
+
+/// The guest-state fields of TR.
+const TR: SegmentFields = SegmentFields {
+    selector: vmcs::guest::TR_SELECTOR,
+    base: vmcs::guest::TR_BASE,
+    limit: vmcs::guest::TR_LIMIT,
+    access_rights: vmcs::guest::TR_ACCESS_RIGHTS,
+};
+
+/// The mode the guest intends, by CR0.PE and CR0.PG.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestMode {
+    /// CR0.PE is clear.
+    Real,
+
+    /// CR0.PE is set, CR0.PG clear.
+    Protected,
+
+    /// CR0.PG is set, with EFER.LME in IA-32e mode.
+    Paged,
+}
+
+impl GuestMode {
+    /// Returns the mode of a guest with `cr0`.
+    ///
+    /// # Arguments
+    ///
+    /// * `cr0` - The CR0 the guest reads.
+    pub const fn of(cr0: u64) -> Self {
+        if cr0 & Cr0Flags::PROTECTED_MODE_ENABLE.bits() == 0 {
+            Self::Real
+        } else if cr0 & Cr0Flags::PAGING.bits() == 0 {
+            Self::Protected
+        } else {
+            Self::Paged
+        }
+    }
+}
+
+/// Returns the CR4 the guest runs with in `mode`: without CR4.PAE and with CR4.PSE for the identity map
+/// while it's unpaged, with CR4.VME in real mode.
+///
+/// # Arguments
+///
+/// * `mode` - The mode of the guest.
+/// * `cr4` - The CR4 VMX operation allows for the CR4 the guest intends.
+pub const fn emulated_cr4(mode: GuestMode, cr4: u64) -> u64 {
+    const PAE: u64 = Cr4Flags::PHYSICAL_ADDRESS_EXTENSION.bits();
+    const PSE: u64 = Cr4Flags::PAGE_SIZE_EXTENSION.bits();
+    const VME: u64 = Cr4Flags::VIRTUAL_8086_MODE_EXTENSIONS.bits();
+
+    match mode {
+        GuestMode::Real => cr4 & !PAE | PSE | VME,
+        GuestMode::Protected => cr4 & !PAE | PSE,
+        GuestMode::Paged => cr4,
+    }
+}
+
+/// Returns the base, limit and access rights of a segment register in virtual-8086 mode, as its selector gives them.
+///
+/// # Arguments
+///
+/// * `selector` - The selector of the segment register.
+pub const fn v86_segment(selector: u64) -> (u64, u64, u64) {
+    ((selector & 0xFFFF) << 4, 0xFFFF, AR_V86)
+}
+
+/// Returns the selector and access rights a segment register of real mode keeps in protected mode until it is
+/// loaded again, its base and limit are kept. VM entries require RPL 0 for CS and SS, they are all given DPL 0.
+///
+/// # Arguments
+///
+/// * `index` - The number of the segment register in `SEGMENTS`.
+/// * `selector` - The selector of the segment register.
+pub const fn protected_segment(index: u8, selector: u64) -> (u64, u64) {
+    (selector & !0b11, if index == CS { AR_CODE } else { AR_DATA })
+}
+
+/// Returns entry `index` of the page directory of the identity map.
+///
+/// # Arguments
+///
+/// * `index` - The number of the 4-MByte page.
+pub const fn identity_pde(index: usize) -> u32 {
+    (index as u32) << 22 | PDE_LARGE_PAGE
+}
+
+/// The identity map and the TSS a guest without "unrestricted guest" runs with until it enables paging.
+///
+/// The guest reaches both through the identity-mapped primary EPT, so they must lie below 4 GiB.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct RealModePages {
+    /// The page directory of the identity map, given to the guest CR3.
+    pub page_directory: [u32; 1024],
+
+    /// The TSS, with the interrupt redirection and I/O bitmaps, padded to whole pages.
+    pub tss: [u8; 3 * BASE_PAGE_SIZE],
+}
+
+impl RealModePages {
+    /// Builds the identity map and the TSS, whose bitmaps stay clear.
+    pub fn build(&mut self) {
+        for (index, entry) in self.page_directory.iter_mut().enumerate() {
+            *entry = identity_pde(index);
+        }
+
+        self.tss[IO_MAP_BASE_FIELD..IO_MAP_BASE_FIELD + 2].copy_from_slice(&(IO_MAP_BASE as u16).to_le_bytes());
+        self.tss[TSS_SIZE - 1] = 0xFF;
+    }
+}
+
+/// Returns whether the processor supports "unrestricted guest".
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: A.3.3 Secondary Processor-Based VM-Execution Controls
+pub fn unrestricted_guest_supported() -> bool {
+    (rdmsr(msr::IA32_VMX_PROCBASED_CTLS2) >> 32) & SecondaryControls::UNRESTRICTED_GUEST.bits() as u64 != 0
+}
+
+/// The mode the guest of one processor runs in, and the state it intends while it runs in another one.
+#[derive(Debug, Clone, Copy)]
+pub struct ModeWatch {
+    /// Whether the processor lacks "unrestricted guest", so that real mode and unpaged protected mode are emulated.
+    pub emulated: bool,
+
+    /// The mode the VMCS runs the guest in.
+    pub mode: GuestMode,
+
+    /// The physical address of the `RealModePages` of the VM.
+    pages: u64,
+
+    /// The CR3 the guest loaded while it's unpaged.
+    cr3: u64,
+
+    /// Whether the guest set EFER.LME while it's unpaged.
+    lme: bool,
+
+    /// The base and limit of the IDTR the guest loaded while it's in real mode.
+    idtr: (u64, u64),
+
+    /// The selector, base, limit and access rights of the guest's TR while it's in real mode.
+    tr: [u64; 4],
+
+    /// RFLAGS.IOPL of the guest while it's in real mode.
+    iopl: u64,
+
+    /// The exception bitmap while the guest is in real mode.
+    exception_bitmap: u64,
+
+    /// The CR3-load and CR3-store exiting controls while the guest is unpaged.
+    cr3_exiting: u64,
+}
+
+impl ModeWatch {
+    /// Creates the watch of a guest in paged protected mode, the mode the hypervisor takes it over in.
+    ///
+    /// # Arguments
+    ///
+    /// * `pages` - The physical address of the `RealModePages` of the VM.
+    pub fn new(pages: u64) -> Self {
+        let emulated = !unrestricted_guest_supported();
+        if emulated {
+            info!("The processor lacks unrestricted guest, real mode and unpaged protected mode are emulated");
+            if pages + size_of::<RealModePages>() as u64 > 1 << 32 {
+                error!("The real-mode pages at {:#x} lie above 4 GiB, application processors will fail to start", pages);
+            }
+        }
+
+        Self {
+            emulated,
+            mode: GuestMode::Paged,
+            pages,
+            cr3: 0,
+            lme: false,
+            idtr: (0, 0),
+            tr: [0; 4],
+            iopl: 0,
+            exception_bitmap: 0,
+            cr3_exiting: 0,
+        }
+    }
+
+    /// Returns whether the guest runs in virtual-8086 mode for the real mode it intends.
+    pub fn in_real_mode(&self) -> bool {
+        self.emulated && self.mode == GuestMode::Real
+    }
+
+    /// Returns whether the guest runs on the identity map for the unpaged mode it intends.
+    fn unpaged(&self) -> bool {
+        self.emulated && self.mode != GuestMode::Paged
+    }
+
+    /// Loads the identity map and keeps the CR3 and EFER.LME of the guest aside.
+    fn leave_paging(&mut self) {
+        self.cr3 = vmread(vmcs::guest::CR3);
+        vmwrite(vmcs::guest::CR3, self.pages);
+
+        let primary = vmread(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS);
+        self.cr3_exiting = primary & CR3_EXITING;
+        vmwrite(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS, primary | CR3_EXITING);
+
+        // With CR0.PG set in the guest CR0, VM entries require EFER.LME to match EFER.LMA.
+        let efer = efer::read_guest();
+        self.lme = efer & LME != 0;
+        efer::write_guest(efer & !LME);
+    }
+
+    /// Loads the CR3 and EFER.LME of the guest.
+    fn enter_paging(&mut self) {
+        vmwrite(vmcs::guest::CR3, self.cr3);
+
+        let primary = vmread(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS);
+        vmwrite(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS, primary & !CR3_EXITING | self.cr3_exiting);
+
+        if self.lme {
+            efer::write_guest(efer::read_guest() | LME);
+        }
+    }
+
+    /// Switches the guest to virtual-8086 mode.
+    fn enter_real_mode(&mut self) {
+        let rflags = vmread(vmcs::guest::RFLAGS);
+        self.iopl = rflags & RFLAGS_IOPL;
+        vmwrite(vmcs::guest::RFLAGS, rflags | RFLAGS_VM | RFLAGS_IOPL);
+
+        for segment in SEGMENTS {
+            let (base, limit, access_rights) = v86_segment(vmread(segment.selector));
+            vmwrite(segment.base, base);
+            vmwrite(segment.limit, limit);
+            vmwrite(segment.access_rights, access_rights);
+        }
+
+        self.tr = [vmread(TR.selector), vmread(TR.base), vmread(TR.limit), vmread(TR.access_rights)];
+        vmwrite(TR.selector, 0u64);
+        vmwrite(TR.base, self.pages + size_of::<[u32; 1024]>() as u64);
+        vmwrite(TR.limit, TSS_SIZE as u64 - 1);
+        vmwrite(TR.access_rights, AR_TSS);
+
+        self.idtr = (vmread(vmcs::guest::IDTR_BASE), vmread(vmcs::guest::IDTR_LIMIT));
+        vmwrite(vmcs::guest::IDTR_BASE, 0u64);
+        vmwrite(vmcs::guest::IDTR_LIMIT, 0u64);
+
+        self.exception_bitmap = vmread(vmcs::control::EXCEPTION_BITMAP);
+        vmwrite(vmcs::control::EXCEPTION_BITMAP, u32::MAX);
+    }
+
+    /// Switches the guest from virtual-8086 mode to protected mode.
+    fn leave_real_mode(&mut self) {
+        let rflags = vmread(vmcs::guest::RFLAGS);
+        vmwrite(vmcs::guest::RFLAGS, rflags & !(RFLAGS_VM | RFLAGS_IOPL) | self.iopl);
+
+        for (index, segment) in SEGMENTS.iter().enumerate() {
+            let (selector, access_rights) = protected_segment(index as u8, vmread(segment.selector));
+            vmwrite(segment.selector, selector);
+            vmwrite(segment.access_rights, access_rights);
+        }
+
+        let [selector, base, limit, access_rights] = self.tr;
+        vmwrite(TR.selector, selector);
+        vmwrite(TR.base, base);
+        vmwrite(TR.limit, limit);
+        vmwrite(TR.access_rights, access_rights);
+
+        vmwrite(vmcs::guest::IDTR_BASE, self.idtr.0);
+        vmwrite(vmcs::guest::IDTR_LIMIT, self.idtr.1);
+
+        vmwrite(vmcs::control::EXCEPTION_BITMAP, self.exception_bitmap);
+    }
+
+    /// Writes the guest/host mask, read shadow and guest CR4 for the mode the guest runs in.
+    fn apply_cr4(&self) {
+        let shadow = CrShadow::cr4();
+        let cr4 = read_effective_guest_cr4();
+        let mask = if self.unpaged() { shadow.mask | CR4_EMULATED } else { shadow.mask };
+
+        vmwrite(vmcs::control::CR4_GUEST_HOST_MASK, mask);
+        vmwrite(vmcs::control::CR4_READ_SHADOW, cr4);
+        vmwrite(vmcs::guest::CR4, emulated_cr4(self.mode, shadow.guest_value(cr4)));
+    }
+}
+
+impl Vm {
+    /// Runs the guest in the mode its CR0 asks for, right after it changed CR0 or CR4 or INIT reset them.
+    pub fn sync_guest_mode(&mut self) {
+        if !self.mode_watch.emulated {
+            return;
+        }
+
+        let watch = &mut self.mode_watch;
+        let previous = watch.mode;
+        let mode = GuestMode::of(read_effective_guest_cr0());
+
+        if mode != previous {
+            debug!("The guest switches from {:?} to {:?} mode", previous, mode);
+
+            if previous == GuestMode::Real {
+                watch.leave_real_mode();
+            }
+            if previous == GuestMode::Paged {
+                watch.leave_paging();
+            }
+            if mode == GuestMode::Paged {
+                watch.enter_paging();
+            }
+            if mode == GuestMode::Real {
+                watch.enter_real_mode();
+            }
+            watch.mode = mode;
+
+            // Translations of the identity map and of the guest's page tables share the VPID.
+            if (previous == GuestMode::Paged) != (mode == GuestMode::Paged) {
+                self.flush_translations(TlbScope::Context);
+            }
+        }
+
+        self.mode_watch.apply_cr4();
+    }
+
+    /// Returns the guest to the state it runs with in paged protected mode, right before INIT resets it.
+    pub fn reset_guest_mode(&mut self) {
+        let watch = &mut self.mode_watch;
+        if watch.mode == GuestMode::Real {
+            watch.leave_real_mode();
+        }
+        if watch.unpaged() {
+            watch.enter_paging();
+        }
+        watch.mode = GuestMode::Paged;
+    }
+
+    /// Returns the CR3 of the guest, the one it loaded while it runs on the identity map.
+    pub fn guest_cr3(&self) -> u64 {
+        if self.mode_watch.unpaged() {
+            self.mode_watch.cr3
+        } else {
+            vmread(vmcs::guest::CR3)
+        }
+    }
+
+    /// Sets the CR3 of the guest, kept aside while it runs on the identity map.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The value MOV to CR3 loads.
+    pub fn set_guest_cr3(&mut self, value: u64) {
+        if self.mode_watch.unpaged() {
+            self.mode_watch.cr3 = value;
+        } else {
+            vmwrite(vmcs::guest::CR3, value);
+        }
+    }
+
+    /// Returns the IA32_EFER of the guest, with the EFER.LME kept aside while it runs on the identity map.
+    pub fn guest_efer(&self) -> u64 {
+        if self.mode_watch.unpaged() && self.mode_watch.lme {
+            efer::read_guest() | LME
+        } else {
+            efer::read_guest()
+        }
+    }
+
+    /// Sets the IA32_EFER of the guest, keeping EFER.LME aside while it runs on the identity map.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The value, checked by `efer::checked_efer_write`.
+    pub fn set_guest_efer(&mut self, value: u64) {
+        if self.mode_watch.unpaged() {
+            self.mode_watch.lme = value & LME != 0;
+            efer::write_guest(value & !LME);
+        } else {
+            efer::write_guest(value);
+        }
+    }
+
+    /// Sets the IDTR of the guest, kept aside while it runs in virtual-8086 mode.
+    ///
+    /// # Arguments
+    ///
+    /// * `base` - The base LIDT loads.
+    /// * `limit` - The limit LIDT loads.
+    pub fn set_guest_idtr(&mut self, base: u64, limit: u64) {
+        if self.mode_watch.in_real_mode() {
+            self.mode_watch.idtr = (base, limit);
+        } else {
+            vmwrite(vmcs::guest::IDTR_BASE, base);
+            vmwrite(vmcs::guest::IDTR_LIMIT, limit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PE: u64 = Cr0Flags::PROTECTED_MODE_ENABLE.bits();
+    const PG: u64 = Cr0Flags::PAGING.bits();
+
+    #[test]
+    fn cr0_selects_the_mode() {
+        assert_eq!(GuestMode::of(0x6000_0010), GuestMode::Real);
+        assert_eq!(GuestMode::of(0x10 | PE), GuestMode::Protected);
+        assert_eq!(GuestMode::of(0x8005_0033), GuestMode::Paged);
+        assert_eq!(GuestMode::of(0x10 | PE | PG), GuestMode::Paged);
+    }
+
+    #[test]
+    fn unpaged_guests_run_on_the_identity_map() {
+        const PAE: u64 = Cr4Flags::PHYSICAL_ADDRESS_EXTENSION.bits();
+        const PSE: u64 = Cr4Flags::PAGE_SIZE_EXTENSION.bits();
+        const VME: u64 = Cr4Flags::VIRTUAL_8086_MODE_EXTENSIONS.bits();
+        const VMXE: u64 = Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS.bits();
+
+        // The trampoline of an application processor sets CR4.PAE before paging.
+        assert_eq!(emulated_cr4(GuestMode::Real, VMXE | PAE), VMXE | PSE | VME);
+        assert_eq!(emulated_cr4(GuestMode::Protected, VMXE | PAE), VMXE | PSE);
+        assert_eq!(emulated_cr4(GuestMode::Paged, VMXE | PAE), VMXE | PAE);
+
+        assert_eq!(identity_pde(0), 0x87);
+        assert_eq!(identity_pde(1023), 0xFFC0_0087);
+    }
+
+    #[test]
+    fn segments_convert_between_the_modes() {
+        // The SIPI vector 0x9A starts the guest at 9A00:0000.
+        assert_eq!(v86_segment(0x9A00), (0x9_A000, 0xFFFF, AR_V86));
+        assert_eq!(v86_segment(0xF000), (0xF_0000, 0xFFFF, AR_V86));
+
+        assert_eq!(protected_segment(CS, 0x9A00), (0x9A00, AR_CODE));
+        assert_eq!(protected_segment(2, 0x0103), (0x0100, AR_DATA));
+    }
+
+    #[test]
+    fn the_tss_redirects_every_interrupt_and_opens_every_port() {
+        let mut pages = RealModePages {
+            page_directory: [0; 1024],
+            tss: [0; 3 * BASE_PAGE_SIZE],
+        };
+        pages.build();
+
+        assert_eq!(pages.page_directory[2], 0x0080_0087);
+        assert_eq!(u16::from_le_bytes([pages.tss[IO_MAP_BASE_FIELD], pages.tss[IO_MAP_BASE_FIELD + 1]]), 136);
+        assert!(pages.tss[..TSS_SIZE - 1]
+            .iter()
+            .enumerate()
+            .all(|(offset, &byte)| byte == 0 || offset == IO_MAP_BASE_FIELD));
+        assert_eq!(pages.tss[TSS_SIZE - 1], 0xFF);
+        assert!(TSS_SIZE <= pages.tss.len());
+    }
+}
@@ -2,12 +2,22 @@
 //! It provides functionality to build a map of MTRRs and their corresponding memory ranges
 //! and types, following the specifications of the Intel® 64 and IA-32 Architectures Software Developer's Manual: 12.11 MEMORY TYPE RANGE REGISTERS (MTRRS)
 //!
+//! The registers are read once when the EPT is built. `Mtrr::memory_type` then resolves the type of any physical
+//! address the way the processor does, and `Mtrr::page_type` tells whether a whole EPT page has a single type or
+//! has to be mapped with smaller pages.
+//!
 //! Credits to Neri https://github.com/neri/maystorm/blob/develop/system/src/arch/x64/cpu.rs
 
 use {
     crate::intel::support::rdmsr,
     alloc::vec::Vec,
-    x86::msr::{IA32_MTRRCAP, IA32_MTRR_PHYSBASE0, IA32_MTRR_PHYSMASK0},
+    x86::{
+        bits64::paging::BASE_PAGE_SIZE,
+        msr::{
+            IA32_MTRRCAP, IA32_MTRR_DEF_TYPE, IA32_MTRR_FIX16K_80000, IA32_MTRR_FIX16K_A0000, IA32_MTRR_FIX4K_C0000, IA32_MTRR_FIX64K_00000,
+            IA32_MTRR_PHYSBASE0, IA32_MTRR_PHYSMASK0,
+        },
+    },
 };
 
 /// Represents the different types of memory as defined by MTRRs.
@@ -25,105 +35,183 @@ pub enum MemoryType {
     WriteBack = 6,
 }
 
-/// Represents a Mttr range descriptor.
+/// The end of the low memory the fixed-range MTRRs cover.
+pub const FIXED_RANGES_END: u64 = 0x10_0000;
+
+/// The number of fixed-range MTRRs, each holding the types of eight ranges.
+pub const FIXED_RANGE_MSRS: usize = 11;
+
+/// The fixed-range MTRRs in the order of the ranges they cover.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 12-9. Address Mapping for Fixed-Range MTRRs
+const FIXED_RANGE_MSR_ADDRESSES: [u32; FIXED_RANGE_MSRS] = [
+    IA32_MTRR_FIX64K_00000,
+    IA32_MTRR_FIX16K_80000,
+    IA32_MTRR_FIX16K_A0000,
+    IA32_MTRR_FIX4K_C0000,
+    IA32_MTRR_FIX4K_C0000 + 1,
+    IA32_MTRR_FIX4K_C0000 + 2,
+    IA32_MTRR_FIX4K_C0000 + 3,
+    IA32_MTRR_FIX4K_C0000 + 4,
+    IA32_MTRR_FIX4K_C0000 + 5,
+    IA32_MTRR_FIX4K_C0000 + 6,
+    IA32_MTRR_FIX4K_C0000 + 7,
+];
+
+/// [Bit 8] IA32_MTRRCAP.FIX: The fixed-range MTRRs are supported.
+const MTRRCAP_FIXED: u64 = 1 << 8;
+
+/// [Bit 10] IA32_MTRR_DEF_TYPE.FE: The fixed-range MTRRs are enabled.
+const DEF_TYPE_FIXED_ENABLE: u64 = 1 << 10;
+
+/// [Bit 11] IA32_MTRR_DEF_TYPE.E: The MTRRs are enabled.
+const DEF_TYPE_ENABLE: u64 = 1 << 11;
+
+/// The memory type map built from the MTRRs of the processor.
 #[derive(Debug, Clone)]
 pub struct Mtrr {
-    descriptors: Vec<MtrrRangeDescriptor>,
+    /// Whether the MTRRs are enabled at all, every address is UC otherwise.
+    enabled: bool,
+
+    /// The type of the addresses no MTRR covers.
+    default_type: MemoryType,
+
+    /// The raw fixed-range MTRRs, `None` if they are unsupported or disabled.
+    fixed: Option<[u64; FIXED_RANGE_MSRS]>,
+
+    /// The enabled variable-range MTRRs.
+    variable: Vec<MtrrItem>,
 }
 
 impl Mtrr {
-    /// Builds a map of the MTRR memory ranges currently in use.
-    ///
-    /// # Returns
-    /// A vector of `MtrrRangeDescriptor` representing each enabled memory range.
+    /// Reads the MTRRs of the current processor.
     pub fn new() -> Self {
-        let mut descriptors = Vec::new();
-
-        for index in Self::indexes() {
-            let item = Self::get(index);
+        let fixed = if rdmsr(IA32_MTRRCAP) & MTRRCAP_FIXED != 0 {
+            Some(FIXED_RANGE_MSR_ADDRESSES.map(rdmsr))
+        } else {
+            None
+        };
+        let variable = Self::indexes().map(Self::get).collect::<Vec<_>>();
 
-            // Skip Write Back type as it's the default memory type.
-            if item.is_enabled && item.mem_type != MemoryType::WriteBack {
-                let end_address = Self::calculate_end_address(item.base, item.mask);
-
-                let descriptor = MtrrRangeDescriptor {
-                    base_address: item.base,
-                    end_address,
-                    memory_type: item.mem_type,
-                };
+        Self::from_registers(rdmsr(IA32_MTRR_DEF_TYPE), fixed, &variable)
+    }
 
-                descriptors.push(descriptor);
-            }
+    /// Builds the map from the contents of the MTRRs.
+    ///
+    /// # Arguments
+    /// * `def_type` - IA32_MTRR_DEF_TYPE.
+    /// * `fixed` - The fixed-range MTRRs in the order of `FIXED_RANGE_MSR_ADDRESSES`, `None` if they are unsupported.
+    /// * `variable` - The variable-range MTRRs, disabled ones are ignored.
+    pub fn from_registers(def_type: u64, fixed: Option<[u64; FIXED_RANGE_MSRS]>, variable: &[MtrrItem]) -> Self {
+        Self {
+            enabled: def_type & DEF_TYPE_ENABLE != 0,
+            default_type: Self::from_raw(def_type as u8),
+            fixed: fixed.filter(|_| def_type & DEF_TYPE_FIXED_ENABLE != 0),
+            variable: variable.iter().copied().filter(|item| item.is_enabled).collect(),
         }
-
-        Self { descriptors }
     }
 
-    /// Finds the memory type for a given physical address range based on the MTRR map.
+    /// Resolves the memory type of a physical address.
+    ///
+    /// Below 1 MiB the enabled fixed-range MTRRs take precedence. Otherwise the variable-range MTRRs that match
+    /// decide, where UC beats every other type and WT beats WB. Other overlaps are undefined and resolve to UC.
     ///
-    /// This method examines the MTRR map to find the appropriate memory type for the
-    /// specified physical address range. It respects the precedence of different memory
-    /// types, with Uncacheable (UC) having the highest precedence.
-    /// If no matching range is found, it defaults to WriteBack.
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 12.11.4.1 MTRR Precedences
     ///
     /// # Arguments
-    /// * `mtrr_map` - The MTRR map to search within.
-    /// * `range` - The physical address range for which to find the memory type.
+    /// * `pa` - The physical address.
     ///
     /// # Returns
-    /// The memory type for the given address range, or a default of WriteBack if no matching range is found.
-    pub fn find(&mut self, range: core::ops::Range<u64>) -> Option<MemoryType> {
-        // Initialize a variable to store the memory type, initially set to None.
-        let mut memory_type: Option<MemoryType> = None;
-
-        // Iterate through each MTRR range descriptor in the map.
-        for descriptor in self.descriptors.iter_mut() {
-            // Check if the provided range falls within the current descriptor's range.
-            if range.start >= descriptor.base_address && range.end <= descriptor.end_address {
-                // Based on the memory type of the descriptor, set the memory type.
-                match descriptor.memory_type {
-                    // If Uncacheable, return immediately as it has the highest precedence.
-                    MemoryType::Uncacheable => return Some(MemoryType::Uncacheable),
-
-                    // For other types, set the memory type if it is not already set.
-                    // Or if it's a less strict type compared to the existing one.
-                    MemoryType::WriteCombining => memory_type = Some(MemoryType::WriteCombining),
-                    MemoryType::WriteThrough => memory_type = Some(MemoryType::WriteThrough),
-                    MemoryType::WriteProtected => memory_type = Some(MemoryType::WriteProtected),
-                    MemoryType::WriteBack => memory_type = Some(MemoryType::WriteBack),
-                }
-            }
+    /// The memory type the processor uses for `pa`.
+    pub fn memory_type(&self, pa: u64) -> MemoryType {
+        if !self.enabled {
+            return MemoryType::Uncacheable;
+        }
+
+        if let Some(fixed) = self.fixed.as_ref().filter(|_| pa < FIXED_RANGES_END) {
+            return Self::fixed_type(fixed, pa);
         }
 
-        // Return the found memory type or default to WriteBack if no specific type was found.
-        memory_type.or(Some(MemoryType::WriteBack))
+        let mut matching = self.variable.iter().filter(|item| item.contains(pa)).map(|item| item.mem_type);
+        let Some(first) = matching.next() else {
+            return self.default_type;
+        };
+
+        matching.fold(first, |resolved, memory_type| match (resolved, memory_type) {
+            (a, b) if a == b => a,
+            (MemoryType::WriteThrough, MemoryType::WriteBack) | (MemoryType::WriteBack, MemoryType::WriteThrough) => MemoryType::WriteThrough,
+            _ => MemoryType::Uncacheable,
+        })
     }
 
-    /// Calculates the end address of an MTRR memory range.
+    /// Resolves the memory type of a naturally aligned page, if the whole page has one.
     ///
     /// # Arguments
-    /// * `base` - The base address of the memory range.
-    /// * `mask` - The mask defining the size of the range.
+    /// * `pa` - The physical address of the page, aligned to `page_size`.
+    /// * `page_size` - The size of the page, a power of two of at least 4 KiB.
     ///
     /// # Returns
-    /// The end address of the memory range.
-    fn calculate_end_address(base: u64, mask: u64) -> u64 {
-        let first_set_bit = Self::bit_scan_forward(mask);
-        let size = 1 << first_set_bit;
-        base + size - 1
+    /// The memory type of the page, or `None` if an MTRR boundary falls inside it and it has to be split.
+    pub fn page_type(&self, pa: u64, page_size: u64) -> Option<MemoryType> {
+        let memory_type = self.memory_type(pa);
+
+        if self.is_uniform(pa, page_size) {
+            return Some(memory_type);
+        }
+
+        (pa..pa + page_size)
+            .step_by(BASE_PAGE_SIZE)
+            .all(|page| self.memory_type(page) == memory_type)
+            .then_some(memory_type)
     }
 
-    /// Performs a Bit Scan Forward (BSF) operation to find the index of the first set bit.
+    /// Returns the ranges of the variable-range MTRRs that make memory UC or WC, where firmware places MMIO windows.
     ///
-    /// # Arguments
-    /// * `value` - The value to scan.
+    /// MTRRs with a non-contiguous mask match scattered addresses and are left out.
+    pub fn mmio_ranges(&self) -> impl Iterator<Item = core::ops::Range<u64>> + '_ {
+        self.variable
+            .iter()
+            .filter(|item| matches!(item.mem_type, MemoryType::Uncacheable | MemoryType::WriteCombining))
+            .filter(|item| item.mask != 0 && ((item.mask >> item.mask.trailing_zeros()) + 1).is_power_of_two())
+            .map(|item| item.base..item.base + (1 << item.mask.trailing_zeros()))
+    }
+
+    /// Returns whether no MTRR can assign different types within a naturally aligned page.
     ///
-    /// # Returns
-    /// The index of the first set bit.
-    fn bit_scan_forward(value: u64) -> u64 {
-        let result: u64;
-        unsafe { core::arch::asm!("bsf {}, {}", out(reg) result, in(reg) value) };
-        result
+    /// A variable-range MTRR with a mask clearing every offset bit of the page matches all of it or none of it.
+    fn is_uniform(&self, pa: u64, page_size: u64) -> bool {
+        if !self.enabled {
+            return true;
+        }
+
+        if self.fixed.is_some() && pa < FIXED_RANGES_END {
+            return false;
+        }
+
+        let offset_bits = page_size - 1;
+        self.variable
+            .iter()
+            .all(|item| item.mask & offset_bits == 0 || (pa & item.mask & !offset_bits) != (item.base & item.mask & !offset_bits))
+    }
+
+    /// Looks up the type a fixed-range MTRR assigns to an address below 1 MiB.
+    ///
+    /// The first MTRR covers eight 64-KByte ranges from 0, the next two sixteen 16-KByte ranges from 0x80000
+    /// and the last eight sixty-four 4-KByte ranges from 0xC0000, one byte per range.
+    fn fixed_type(fixed: &[u64; FIXED_RANGE_MSRS], pa: u64) -> MemoryType {
+        let (msr_index, range_index) = match pa {
+            0..0x8_0000 => (0, pa / 0x1_0000),
+            0x8_0000..0xC_0000 => {
+                let range = (pa - 0x8_0000) / 0x4000;
+                (1 + range / 8, range % 8)
+            }
+            _ => {
+                let range = (pa - 0xC_0000) / 0x1000;
+                (3 + range / 8, range % 8)
+            }
+        };
+
+        Self::from_raw((fixed[msr_index as usize] >> (range_index * 8)) as u8)
     }
 
     /// Retrieves the count of variable range MTRRs.
@@ -197,13 +285,15 @@ impl Mtrr {
     /// * `value` - The raw memory type value.
     ///
     /// # Returns
-    /// The corresponding `MemoryType` enum variant.
-    ///
-    /// # Safety
-    /// This function is unsafe because it uses `transmute` which can lead to undefined behavior
-    /// if `value` does not correspond to a valid variant of `MemoryType`.
+    /// The corresponding `MemoryType` enum variant, UC for the reserved encodings.
     pub const fn from_raw(value: u8) -> MemoryType {
-        unsafe { core::mem::transmute(value) }
+        match value {
+            1 => MemoryType::WriteCombining,
+            4 => MemoryType::WriteThrough,
+            5 => MemoryType::WriteProtected,
+            6 => MemoryType::WriteBack,
+            _ => MemoryType::Uncacheable,
+        }
     }
 }
 
@@ -211,17 +301,6 @@ impl Mtrr {
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct MtrrIndex(pub u8);
 
-/// Describes a specific MTRR memory range.
-#[derive(Debug, Clone, Copy)]
-pub struct MtrrRangeDescriptor {
-    /// The base address of the memory range.
-    pub base_address: u64,
-    /// The end address of the memory range.
-    pub end_address: u64,
-    /// The memory type associated with this range.
-    pub memory_type: MemoryType,
-}
-
 /// Represents the configuration of a single MTRR.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct MtrrItem {
@@ -257,4 +336,132 @@ impl MtrrItem {
             is_enabled,
         }
     }
+
+    /// Returns whether `pa` lies in the range of this MTRR, which is the case if it matches the base in every bit the mask sets.
+    pub fn contains(&self, pa: u64) -> bool {
+        self.is_enabled && pa & self.mask == self.base & self.mask
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENABLED: u64 = DEF_TYPE_ENABLE | DEF_TYPE_FIXED_ENABLE;
+
+    const LARGE_PAGE: u64 = 0x20_0000;
+
+    /// A variable-range MTRR as firmware programs it on a processor with 39 physical address bits.
+    fn variable(base: u64, size: u64, memory_type: MemoryType) -> MtrrItem {
+        MtrrItem::from_raw(base | memory_type as u64, !(size - 1) & ((1 << 39) - 1) | 0x800)
+    }
+
+    /// Fixed ranges with WB below 640 KiB, UC for the legacy video memory and WP for the option ROMs and the BIOS.
+    fn low_memory() -> [u64; FIXED_RANGE_MSRS] {
+        let mut fixed = [0x0505_0505_0505_0505; FIXED_RANGE_MSRS];
+        fixed[0] = 0x0606_0606_0606_0606;
+        fixed[1] = 0x0606_0606_0606_0606;
+        fixed[2] = 0;
+        fixed
+    }
+
+    #[test]
+    fn fixed_ranges_cover_low_memory() {
+        let mtrr = Mtrr::from_registers(ENABLED | MemoryType::WriteBack as u64, Some(low_memory()), &[]);
+
+        assert_eq!(mtrr.memory_type(0), MemoryType::WriteBack);
+        assert_eq!(mtrr.memory_type(0x9_F000), MemoryType::WriteBack);
+        assert_eq!(mtrr.memory_type(0xA_0000), MemoryType::Uncacheable);
+        assert_eq!(mtrr.memory_type(0xB_F000), MemoryType::Uncacheable);
+        assert_eq!(mtrr.memory_type(0xC_0000), MemoryType::WriteProtected);
+        assert_eq!(mtrr.memory_type(0xF_F000), MemoryType::WriteProtected);
+        assert_eq!(mtrr.memory_type(FIXED_RANGES_END), MemoryType::WriteBack);
+        assert_eq!(mtrr.page_type(0, LARGE_PAGE), None);
+
+        // Without FE the variable ranges and the default type apply below 1 MiB as well.
+        let mtrr = Mtrr::from_registers(DEF_TYPE_ENABLE | MemoryType::WriteBack as u64, Some(low_memory()), &[]);
+        assert_eq!(mtrr.memory_type(0xA_0000), MemoryType::WriteBack);
+        assert_eq!(mtrr.page_type(0, LARGE_PAGE), Some(MemoryType::WriteBack));
+    }
+
+    #[test]
+    fn fixed_range_lookup_uses_the_right_byte() {
+        let mut fixed = [0; FIXED_RANGE_MSRS];
+        fixed[0] = 0x06 << 56;
+        fixed[2] = 0x04 << 8;
+        fixed[10] = 0x01 << 56;
+        let mtrr = Mtrr::from_registers(ENABLED, Some(fixed), &[]);
+
+        assert_eq!(mtrr.memory_type(0x6_F000), MemoryType::Uncacheable);
+        assert_eq!(mtrr.memory_type(0x7_0000), MemoryType::WriteBack);
+        assert_eq!(mtrr.memory_type(0xA_4000), MemoryType::WriteThrough);
+        assert_eq!(mtrr.memory_type(0xA_8000), MemoryType::Uncacheable);
+        assert_eq!(mtrr.memory_type(0xF_F000), MemoryType::WriteCombining);
+    }
+
+    #[test]
+    fn overlapping_variable_ranges_follow_the_precedence_rules() {
+        let mtrr = Mtrr::from_registers(
+            ENABLED | MemoryType::Uncacheable as u64,
+            None,
+            &[
+                variable(0, 0x8000_0000, MemoryType::WriteBack),
+                variable(0x4000_0000, 0x4000_0000, MemoryType::WriteThrough),
+                variable(0x7000_0000, 0x1000_0000, MemoryType::Uncacheable),
+                variable(0x6000_0000, 0x1000_0000, MemoryType::WriteCombining),
+            ],
+        );
+
+        assert_eq!(mtrr.memory_type(0x1000_0000), MemoryType::WriteBack);
+        assert_eq!(mtrr.memory_type(0x4000_0000), MemoryType::WriteThrough);
+        assert_eq!(mtrr.memory_type(0x7800_0000), MemoryType::Uncacheable);
+        // WC over WB and WT is undefined, UC is the safe choice.
+        assert_eq!(mtrr.memory_type(0x6000_0000), MemoryType::Uncacheable);
+        assert_eq!(mtrr.memory_type(0x8000_0000), MemoryType::Uncacheable);
+    }
+
+    #[test]
+    fn pages_split_where_a_boundary_falls_inside() {
+        let mtrr = Mtrr::from_registers(
+            ENABLED | MemoryType::WriteBack as u64,
+            None,
+            &[
+                variable(0xC000_0000, 0x4000_0000, MemoryType::Uncacheable),
+                variable(0xBFF0_0000, 0x1_0000, MemoryType::WriteCombining),
+            ],
+        );
+
+        assert_eq!(mtrr.page_type(0xC000_0000, LARGE_PAGE), Some(MemoryType::Uncacheable));
+        assert_eq!(mtrr.page_type(0xBFE0_0000, LARGE_PAGE), None);
+        assert_eq!(mtrr.page_type(0xBFF0_0000, 0x1000), Some(MemoryType::WriteCombining));
+        assert_eq!(mtrr.page_type(0xBFF1_0000, 0x1000), Some(MemoryType::WriteBack));
+        assert_eq!(mtrr.page_type(0x1_0000_0000, LARGE_PAGE), Some(MemoryType::WriteBack));
+    }
+
+    #[test]
+    fn uncacheable_ranges_are_mmio() {
+        let mtrr = Mtrr::from_registers(
+            ENABLED | MemoryType::WriteBack as u64,
+            None,
+            &[
+                variable(0, 0x8000_0000, MemoryType::WriteBack),
+                variable(0xC000_0000, 0x4000_0000, MemoryType::Uncacheable),
+                variable(0x40_0000_0000, 0x10_0000_0000, MemoryType::WriteCombining),
+            ],
+        );
+
+        assert_eq!(mtrr.mmio_ranges().collect::<Vec<_>>(), [0xC000_0000..0x1_0000_0000, 0x40_0000_0000..0x50_0000_0000]);
+    }
+
+    #[test]
+    fn disabled_mtrrs_make_everything_uncacheable() {
+        let mtrr = Mtrr::from_registers(MemoryType::WriteBack as u64, Some(low_memory()), &[variable(0, 0x8000_0000, MemoryType::WriteBack)]);
+        assert_eq!(mtrr.memory_type(0x1000), MemoryType::Uncacheable);
+        assert_eq!(mtrr.page_type(0, LARGE_PAGE), Some(MemoryType::Uncacheable));
+
+        let mut disabled = variable(0, 0x8000_0000, MemoryType::Uncacheable);
+        disabled.is_enabled = false;
+        let mtrr = Mtrr::from_registers(ENABLED | MemoryType::WriteBack as u64, None, &[disabled]);
+        assert_eq!(mtrr.memory_type(0x1000), MemoryType::WriteBack);
+    }
 }
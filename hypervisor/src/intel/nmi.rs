@@ -0,0 +1,157 @@
+//! Delivers the NMIs of the guest, which the hypervisor takes with "NMI exiting".
+//!
+//! NMIs that exit, or arrive in the host IDT while the processor is in VMX root operation, are queued on the
+//! `Vcpu` of the processor unless `shootdown` sent them. Right before every VM entry a queued NMI is handed to
+//! the `EventInjector` of the VM, which injects it if the guest can take it. Otherwise "NMI-window exiting"
+//! exits as soon as it can, which is right after the IRET of its NMI handler for a guest blocking NMIs. Like
+//! the processor, one NMI is held pending at most, NMIs arriving while one is pending are dropped.
+//!
+//! With "virtual NMIs" the processor tracks blocking by NMI of the guest, an injected NMI blocks further NMIs
+//! until the guest executes IRET. A VM exit in the middle of an IRET unblocked NMIs already, and the IRET runs
+//! again after the VM entry, so blocking by NMI is restored first when the exit says so.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.3 CHANGES TO INSTRUCTION BEHAVIOR IN VMX NON-ROOT OPERATION
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 28.2.3 Information About NMI Unblocking Due to IRET
+
+use {
+    crate::{
+        intel::{
+            events::EventInjector,
+            support::{vmread, vmwrite},
+            vcpu::{self, Vcpu},
+            vmerror::VmxBasicExitReason,
+        },
+        stats,
+    },
+    x86::vmx::vmcs::{self, control::PinbasedControls},
+};
+
+/// The NMIs held pending at most, like the processor.
+pub const MAX_PENDING_NMIS: u32 = 1;
+
+/// [Bit 3] Interruptibility state: blocking by NMI, virtual-NMI blocking with "virtual NMIs".
+const BLOCKING_BY_NMI: u64 = 1 << 3;
+
+/// [Bit 12] Exit qualification of EPT violations and VM-exit interruption information: NMI unblocking due to IRET.
+const NMI_UNBLOCKING_DUE_TO_IRET: u64 = 1 << 12;
+
+/// [Bit 31] Valid bit of the interruption-information fields.
+const INTERRUPTION_INFO_VALID: u64 = 1 << 31;
+
+/// Returns whether blocking by NMI must be restored after a VM exit, because it interrupted an IRET that
+/// unblocked NMIs and runs again.
+///
+/// # Arguments
+///
+/// * `exit_reason` - The basic exit reason.
+/// * `qualification` - The exit qualification.
+/// * `interruption_info` - The VM-exit interruption information.
+/// * `idt_vectoring_info` - The IDT-vectoring information, valid if the exit interrupted the delivery of an event.
+pub fn restores_blocking(exit_reason: VmxBasicExitReason, qualification: u64, interruption_info: u64, idt_vectoring_info: u64) -> bool {
+    // An exit during event delivery didn't come from the IRET, the event is delivered again.
+    if idt_vectoring_info & INTERRUPTION_INFO_VALID != 0 {
+        return false;
+    }
+
+    match exit_reason {
+        VmxBasicExitReason::EptViolation => qualification & NMI_UNBLOCKING_DUE_TO_IRET != 0,
+        VmxBasicExitReason::ExceptionOrNmi => {
+            // A #DF can't be followed by an IRET that runs again.
+            const DOUBLE_FAULT: u64 = 8;
+            interruption_info & INTERRUPTION_INFO_VALID != 0
+                && interruption_info & NMI_UNBLOCKING_DUE_TO_IRET != 0
+                && interruption_info & 0xFF != DOUBLE_FAULT
+        }
+        _ => false,
+    }
+}
+
+/// Queues an NMI of the guest on `vcpu`, counting it as dropped if one is pending already.
+///
+/// # Arguments
+///
+/// * `vcpu` - The processor that took the NMI.
+pub fn queue(vcpu: &Vcpu) {
+    if vcpu.queue_nmi(MAX_PENDING_NMIS) {
+        stats::record_nmi_queued();
+    } else {
+        stats::record_nmi_dropped();
+    }
+}
+
+/// Queues an NMI of the guest the current processor took.
+pub fn queue_current() {
+    match vcpu::current() {
+        Some(vcpu) => queue(vcpu),
+        None => stats::record_nmi_dropped(),
+    }
+}
+
+/// Restores blocking by NMI if the VM exit interrupted an IRET, right after the VM exit.
+///
+/// # Arguments
+///
+/// * `exit_reason` - The basic exit reason.
+pub fn after_vm_exit(exit_reason: VmxBasicExitReason) {
+    if !matches!(exit_reason, VmxBasicExitReason::EptViolation | VmxBasicExitReason::ExceptionOrNmi) {
+        return;
+    }
+
+    let restore = restores_blocking(
+        exit_reason,
+        vmread(vmcs::ro::EXIT_QUALIFICATION),
+        vmread(vmcs::ro::VMEXIT_INTERRUPTION_INFO),
+        vmread(vmcs::ro::IDT_VECTORING_INFO),
+    );
+    if restore && virtual_nmis() {
+        let interruptibility = vmread(vmcs::guest::INTERRUPTIBILITY_STATE);
+        vmwrite(vmcs::guest::INTERRUPTIBILITY_STATE, interruptibility | BLOCKING_BY_NMI);
+    }
+}
+
+/// Hands the queued NMI to the event injector of the VM, right before a VM entry.
+///
+/// # Arguments
+///
+/// * `events` - The event injector of the VM of the current processor.
+pub fn before_vm_entry(events: &mut EventInjector) {
+    let Some(vcpu) = vcpu::current() else {
+        return;
+    };
+
+    if !vcpu.take_pending_nmi() {
+        return;
+    }
+
+    // The NMI the injector holds is the pending one already.
+    if events.holds_nmi() {
+        stats::record_nmi_dropped();
+    } else {
+        events.inject_nmi();
+    }
+}
+
+/// Returns whether the VMCS has "virtual NMIs", and with it NMI-window exiting.
+pub fn virtual_nmis() -> bool {
+    vmread(vmcs::control::PINBASED_EXEC_CONTROLS) & PinbasedControls::VIRTUAL_NMIS.bits() as u64 != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interrupted_irets_restore_blocking() {
+        let unblocked = NMI_UNBLOCKING_DUE_TO_IRET;
+        assert!(restores_blocking(VmxBasicExitReason::EptViolation, unblocked | 0b001, 0, 0));
+        assert!(!restores_blocking(VmxBasicExitReason::EptViolation, 0b001, 0, 0));
+        assert!(!restores_blocking(VmxBasicExitReason::EptViolation, unblocked, 0, INTERRUPTION_INFO_VALID | 0x30E));
+
+        // #PF during IRET restores blocking, a #DF doesn't.
+        assert!(restores_blocking(VmxBasicExitReason::ExceptionOrNmi, 0, INTERRUPTION_INFO_VALID | unblocked | 0xB0E, 0));
+        assert!(!restores_blocking(VmxBasicExitReason::ExceptionOrNmi, 0, INTERRUPTION_INFO_VALID | unblocked | 0xB08, 0));
+        assert!(!restores_blocking(VmxBasicExitReason::ExceptionOrNmi, 0, unblocked | 0xB0E, 0));
+
+        assert!(!restores_blocking(VmxBasicExitReason::Cpuid, unblocked, unblocked, 0));
+    }
+}
@@ -0,0 +1,760 @@
+//! A software walk of the guest's 4-level and 5-level page tables, over any `PhysicalMemory`.
+//!
+//! The hypervisor reads guest memory on behalf of hypercalls, with the guest's page tables but without the
+//! processor's help. The walk only reads the paging structures through `PhysicalMemory`, so it runs on
+//! synthetic tables in unit tests and on guest-physical memory through the EPT in the hypervisor.
+//!
+//! `walk` finds the page an address is in and the rights every level grants it, `translate_gva` then checks an
+//! access against them the way the processor would, SMEP and SMAP included. Failures name the level of the entry
+//! and the reason, like the error code of the #PF the access would raise.
+//!
+//! With `HvFeatureFlags::TRANSLATION_CACHE`, every processor keeps its recent walks in a `TranslationCache`. It is
+//! invalidated with the TLB by `Vm::flush_translations`: INVLPG and INVPCID exit for it, and so does MOV to CR3,
+//! see `vmexit::invlpg`.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 4.5 4-LEVEL PAGING AND 5-LEVEL PAGING
+//! and 4.6 ACCESS RIGHTS
+
+use {
+    crate::intel::{
+        cpuid_policy::seen,
+        guest_memory::EptMemory,
+        invvpid::{self, TlbScope},
+        support::{read_effective_guest_cr0, read_effective_guest_cr4, vmread},
+        vm::Vm,
+    },
+    x86::vmx::vmcs,
+    x86_64::registers::control::{Cr0Flags, Cr4Flags},
+};
+
+/// [Bit 0] Paging-structure entry: present.
+pub const PRESENT: u64 = 1 << 0;
+
+/// [Bit 1] Paging-structure entry: read/write, writes are allowed to the region the entry controls.
+pub const WRITABLE: u64 = 1 << 1;
+
+/// [Bit 2] Paging-structure entry: user/supervisor, user-mode accesses are allowed to the region.
+pub const USER: u64 = 1 << 2;
+
+/// [Bit 7] PDPTE and PDE: page size, the entry maps a 1-GByte or 2-MByte page. Reserved in PML5Es and PML4Es.
+pub const PAGE_SIZE: u64 = 1 << 7;
+
+/// [Bit 63] Paging-structure entry: execute-disable with IA32_EFER.NXE, reserved without.
+pub const EXECUTE_DISABLE: u64 = 1 << 63;
+
+/// [Bits 51:12] Paging-structure entry: the physical address of the next table or of a 4-KByte page, also
+/// of CR3 once its flags and bit 63 are cleared.
+pub const ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// [Bit 18] RFLAGS: alignment check, allows supervisor data accesses to user pages despite SMAP.
+const RFLAGS_AC: u64 = 1 << 18;
+
+/// [Bit 11] IA32_EFER: execute disable bit enable.
+const EFER_NXE: u64 = 1 << 11;
+
+/// The most bits of physical addresses, MAXPHYADDR never exceeds 52.
+const MAX_PHYSICAL_ADDRESS_BITS: u32 = 52;
+
+/// The entries `TranslationCache` holds.
+const CACHED_WALKS: usize = 8;
+
+/// Reads the guest-physical memory the paging structures are in.
+pub trait PhysicalMemory {
+    /// Reads the paging-structure entry at a guest-physical address, `None` if nothing is mapped there.
+    ///
+    /// # Arguments
+    ///
+    /// * `pa` - The 8-byte aligned guest-physical address.
+    fn read_entry(&self, pa: u64) -> Option<u64>;
+}
+
+/// The state of the guest processor deciding how addresses translate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Paging {
+    /// CR4.LA57, 5-level paging and 57-bit linear addresses.
+    pub five_level: bool,
+
+    /// IA32_EFER.NXE, bit 63 of the entries is execute-disable.
+    pub nxe: bool,
+
+    /// CR0.WP, supervisor-mode writes honor read-only pages.
+    pub wp: bool,
+
+    /// CR4.SMEP, no supervisor-mode instruction fetches from user pages.
+    pub smep: bool,
+
+    /// CR4.SMAP, no supervisor-mode data accesses to user pages.
+    pub smap: bool,
+
+    /// RFLAGS.AC, explicit supervisor-mode data accesses to user pages are allowed despite SMAP.
+    pub ac: bool,
+
+    /// MAXPHYADDR, the bits of physical addresses CPUID.80000008H:EAX[7:0] reports to the guest.
+    pub physical_address_bits: u32,
+}
+
+impl Paging {
+    /// Returns the paging state of the guest on the current processor.
+    pub fn current(vm: &Vm) -> Self {
+        let cr0 = Cr0Flags::from_bits_retain(read_effective_guest_cr0());
+        let cr4 = Cr4Flags::from_bits_retain(read_effective_guest_cr4());
+        Self {
+            five_level: cr4.contains(Cr4Flags::L5_PAGING),
+            nxe: vm.guest_efer() & EFER_NXE != 0,
+            wp: cr0.contains(Cr0Flags::WRITE_PROTECT),
+            smep: cr4.contains(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION),
+            smap: cr4.contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION),
+            ac: vmread(vmcs::guest::RFLAGS) & RFLAGS_AC != 0,
+            physical_address_bits: seen(0x8000_0008, 0).eax & 0xFF,
+        }
+    }
+
+    /// Returns the end of the guest-physical address space, `2^MAXPHYADDR` with MAXPHYADDR at most 52.
+    pub fn physical_address_limit(&self) -> u64 {
+        1 << self.physical_address_bits.min(MAX_PHYSICAL_ADDRESS_BITS)
+    }
+
+    /// Returns the bits of linear addresses, 48 or 57.
+    fn linear_address_bits(&self) -> u32 {
+        if self.five_level {
+            57
+        } else {
+            48
+        }
+    }
+
+    /// Returns whether an address is canonical, the bits above the linear address all equal to its top bit.
+    pub fn is_canonical(&self, va: u64) -> bool {
+        let upper = va >> (self.linear_address_bits() - 1);
+        upper == 0 || upper == u64::MAX >> (self.linear_address_bits() - 1)
+    }
+
+    /// Returns whether every address of a range is canonical.
+    ///
+    /// # Arguments
+    ///
+    /// * `va` - The first address.
+    /// * `length` - The bytes in the range, at least 1.
+    pub fn is_canonical_range(&self, va: u64, length: u64) -> bool {
+        // Both canonical halves are contiguous, a range stays in one if both its ends are in it.
+        let half = |address: u64| address >> (self.linear_address_bits() - 1);
+        va.checked_add(length - 1)
+            .is_some_and(|last| self.is_canonical(va) && self.is_canonical(last) && half(va) == half(last))
+    }
+
+    /// Checks an access against the rights of a page.
+    ///
+    /// # Arguments
+    ///
+    /// * `walk` - The page.
+    /// * `access` - The access.
+    ///
+    /// # Returns
+    ///
+    /// `Ok` if the processor allows the access, else why it doesn't.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 4.6.1 Determination of Access Rights
+    pub fn check(&self, walk: &Walk, access: Access) -> Result<(), Fault> {
+        if access.user && !walk.user {
+            return Err(Fault::Supervisor);
+        }
+
+        match access.kind {
+            AccessKind::Execute if !walk.executable => Err(Fault::NoExecute),
+            AccessKind::Execute if !access.user && walk.user && self.smep => Err(Fault::Smep),
+            AccessKind::Execute => Ok(()),
+            AccessKind::Read | AccessKind::Write if !access.user && walk.user && self.smap && !self.ac => Err(Fault::Smap),
+            AccessKind::Write if !walk.writable && (access.user || self.wp) => Err(Fault::ReadOnly),
+            AccessKind::Read | AccessKind::Write => Ok(()),
+        }
+    }
+}
+
+/// What an access does with the memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    /// A data read.
+    Read,
+
+    /// A data write.
+    Write,
+
+    /// An instruction fetch.
+    Execute,
+}
+
+/// An access to check against the rights of a page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    /// What the access does.
+    pub kind: AccessKind,
+
+    /// Whether it is a user-mode access, made at CPL 3.
+    pub user: bool,
+}
+
+/// The page a guest virtual address is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Walk {
+    /// The guest-physical address.
+    pub pa: u64,
+
+    /// The size of the page mapping it, 4 KiB, 2 MiB or 1 GiB.
+    pub page_size: u64,
+
+    /// The level of the entry mapping the page, 1 for a PTE up to 3 for a PDPTE.
+    pub level: u8,
+
+    /// Whether every level allows writes.
+    pub writable: bool,
+
+    /// Whether every level allows user-mode accesses.
+    pub user: bool,
+
+    /// Whether no level disables instruction fetches.
+    pub executable: bool,
+}
+
+/// Why a guest virtual address doesn't translate for an access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The address isn't canonical.
+    NonCanonical,
+
+    /// The entry isn't present.
+    NotPresent,
+
+    /// The entry lies outside the guest-physical memory.
+    Unbacked,
+
+    /// The entry sets a reserved bit.
+    Reserved,
+
+    /// A write to a read-only page.
+    ReadOnly,
+
+    /// A user-mode access to a supervisor page.
+    Supervisor,
+
+    /// An instruction fetch from an execute-disabled page.
+    NoExecute,
+
+    /// A supervisor-mode instruction fetch from a user page with SMEP.
+    Smep,
+
+    /// A supervisor-mode data access to a user page with SMAP and RFLAGS.AC clear.
+    Smap,
+}
+
+/// A failed translation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WalkError {
+    /// The level of the entry at fault, 5 for a PML5E down to 1 for a PTE, 0 for a non-canonical address.
+    pub level: u8,
+
+    /// What is wrong with it.
+    pub fault: Fault,
+}
+
+/// Returns whether an entry sets bits that are reserved at its level.
+///
+/// The bits from MAXPHYADDR to 51 aren't checked, addresses beyond the guest-physical memory fail as `Unbacked`.
+///
+/// # Arguments
+///
+/// * `entry` - The present entry.
+/// * `level` - Its level, 5 for a PML5E down to 1 for a PTE.
+/// * `nxe` - IA32_EFER.NXE.
+fn sets_reserved_bits(entry: u64, level: u8, nxe: bool) -> bool {
+    if !nxe && entry & EXECUTE_DISABLE != 0 {
+        return true;
+    }
+
+    match level {
+        4 | 5 => entry & PAGE_SIZE != 0,
+        // Bit 12 of large page entries is PAT, the bits above it up to the page address are reserved.
+        2 | 3 if entry & PAGE_SIZE != 0 => entry & ADDRESS_MASK & ((1 << (12 + 9 * (level - 1))) - 1) & !0x1FFF != 0,
+        _ => false,
+    }
+}
+
+/// Walks the guest's page tables to the page an address is in.
+///
+/// # Arguments
+///
+/// * `memory` - The memory the paging structures are in.
+/// * `paging` - The paging state of the guest.
+/// * `cr3` - The CR3 of the address space, PCID and flags included.
+/// * `va` - The guest virtual address.
+///
+/// # Returns
+///
+/// The page and the rights every level grants it, or the entry ending the walk.
+pub fn walk(memory: &impl PhysicalMemory, paging: &Paging, cr3: u64, va: u64) -> Result<Walk, WalkError> {
+    if !paging.is_canonical(va) {
+        return Err(WalkError {
+            level: 0,
+            fault: Fault::NonCanonical,
+        });
+    }
+
+    let mut table = cr3 & ADDRESS_MASK;
+    let (mut writable, mut user, mut executable) = (true, true, true);
+    let top = if paging.five_level { 5 } else { 4 };
+
+    // PML5, PML4, PDPT, PD and PT, each indexed by 9 bits of the address.
+    for level in (1..=top).rev() {
+        let shift = 12 + 9 * (level as u32 - 1);
+        let fail = |fault| Err(WalkError { level, fault });
+
+        let Some(entry) = memory.read_entry(table + ((va >> shift) & 0x1FF) * 8) else {
+            return fail(Fault::Unbacked);
+        };
+        if entry & PRESENT == 0 {
+            return fail(Fault::NotPresent);
+        }
+        if sets_reserved_bits(entry, level, paging.nxe) {
+            return fail(Fault::Reserved);
+        }
+
+        writable &= entry & WRITABLE != 0;
+        user &= entry & USER != 0;
+        executable &= entry & EXECUTE_DISABLE == 0;
+
+        // Only PDPTEs, PDEs and PTEs map pages, the PT only 4-KByte ones.
+        if level == 1 || (level <= 3 && entry & PAGE_SIZE != 0) {
+            let page_size = 1u64 << shift;
+            let base = entry & ADDRESS_MASK & !(page_size - 1);
+            return Ok(Walk {
+                pa: base | va & (page_size - 1),
+                page_size,
+                level,
+                writable,
+                user,
+                executable,
+            });
+        }
+
+        table = entry & ADDRESS_MASK;
+    }
+
+    unreachable!("the PT maps 4-KByte pages");
+}
+
+/// Translates a guest virtual address for an access, with the access rights the processor would check.
+///
+/// # Arguments
+///
+/// * `memory` - The memory the paging structures are in.
+/// * `paging` - The paging state of the guest.
+/// * `cr3` - The CR3 of the address space, PCID and flags included.
+/// * `gva` - The guest virtual address.
+/// * `access` - The access.
+///
+/// # Returns
+///
+/// The page, or the entry and the reason the access would fault.
+pub fn translate_gva(memory: &impl PhysicalMemory, paging: &Paging, cr3: u64, gva: u64, access: Access) -> Result<Walk, WalkError> {
+    let walk = walk(memory, paging, cr3, gva)?;
+    paging.check(&walk, access).map_err(|fault| WalkError { level: walk.level, fault })?;
+    Ok(walk)
+}
+
+/// A walk kept by `TranslationCache`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CachedWalk {
+    /// The address of the PML4 or PML5 table, from CR3.
+    table: u64,
+
+    /// The 4-KByte page of the guest virtual address.
+    page: u64,
+
+    /// The walk to the page.
+    walk: Walk,
+}
+
+/// The recent walks of one processor, like a TLB without PCIDs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TranslationCache {
+    /// Whether walks are cached, only with `HvFeatureFlags::TRANSLATION_CACHE` as invalidations exit with it.
+    enabled: bool,
+
+    /// The walks, replaced round-robin.
+    entries: [Option<CachedWalk>; CACHED_WALKS],
+
+    /// The entry replaced next.
+    next: usize,
+}
+
+impl TranslationCache {
+    /// Creates an empty cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether walks are cached at all.
+    pub const fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            entries: [None; CACHED_WALKS],
+            next: 0,
+        }
+    }
+
+    /// Returns the cached walk of an address.
+    ///
+    /// # Arguments
+    ///
+    /// * `cr3` - The CR3 of the address space.
+    /// * `va` - The guest virtual address.
+    pub fn lookup(&self, cr3: u64, va: u64) -> Option<Walk> {
+        let cached = self
+            .entries
+            .iter()
+            .flatten()
+            .find(|cached| cached.table == cr3 & ADDRESS_MASK && cached.page == va & !0xFFF)?;
+        Some(Walk {
+            pa: cached.walk.pa & !0xFFF | va & 0xFFF,
+            ..cached.walk
+        })
+    }
+
+    /// Keeps a walk, if walks are cached.
+    ///
+    /// # Arguments
+    ///
+    /// * `cr3` - The CR3 of the address space.
+    /// * `va` - The guest virtual address walked.
+    /// * `walk` - The walk.
+    pub fn insert(&mut self, cr3: u64, va: u64, walk: Walk) {
+        if !self.enabled {
+            return;
+        }
+
+        self.entries[self.next] = Some(CachedWalk {
+            table: cr3 & ADDRESS_MASK,
+            page: va & !0xFFF,
+            walk,
+        });
+        self.next = (self.next + 1) % CACHED_WALKS;
+    }
+
+    /// Drops the walks of the page an address is in, in every address space, like INVLPG.
+    pub fn invalidate(&mut self, va: u64) {
+        for entry in &mut self.entries {
+            if entry.is_some_and(|cached| (cached.page ^ va) & !(cached.walk.page_size - 1) == 0) {
+                *entry = None;
+            }
+        }
+    }
+
+    /// Drops every walk.
+    pub fn flush(&mut self) {
+        self.entries = [None; CACHED_WALKS];
+    }
+}
+
+impl Vm {
+    /// Invalidates translations of the guest, in the TLB and in the translation cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `scope` - The translations a change of the guest paging state may have made stale.
+    pub fn flush_translations(&mut self, scope: TlbScope) {
+        invvpid::flush(self.vpid, scope);
+        match scope {
+            TlbScope::Address(address) => self.translations.invalidate(address),
+            _ => self.translations.flush(),
+        }
+    }
+
+    /// Translates a guest virtual address for an access of the guest, through the translation cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `cr3` - The CR3 of the address space.
+    /// * `gva` - The guest virtual address.
+    /// * `access` - The access.
+    ///
+    /// # Returns
+    ///
+    /// The page, or the entry and the reason the access would fault.
+    pub fn translate_gva(&mut self, cr3: u64, gva: u64, access: Access) -> Result<Walk, WalkError> {
+        let paging = Paging::current(self);
+
+        let walk = match self.translations.lookup(cr3, gva) {
+            Some(walk) => walk,
+            None => {
+                let Some(memory) = EptMemory::current() else {
+                    return Err(WalkError {
+                        level: if paging.five_level { 5 } else { 4 },
+                        fault: Fault::Unbacked,
+                    });
+                };
+                let walk = walk(&memory, &paging, cr3, gva)?;
+                self.translations.insert(cr3, gva, walk);
+                walk
+            }
+        };
+
+        paging.check(&walk, access).map_err(|fault| WalkError { level: walk.level, fault })?;
+        Ok(walk)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use {
+        super::*,
+        alloc::{vec, vec::Vec},
+        x86::bits64::paging::BASE_PAGE_SIZE,
+    };
+
+    /// Guest-physical memory made of zeroed 4-KByte frames from address 0.
+    pub struct FakeMemory {
+        pub bytes: Vec<u8>,
+    }
+
+    impl FakeMemory {
+        pub fn new(frames: usize) -> Self {
+            Self {
+                bytes: vec![0; frames * BASE_PAGE_SIZE],
+            }
+        }
+
+        pub fn set_entry(&mut self, pa: u64, entry: u64) {
+            self.bytes[pa as usize..pa as usize + 8].copy_from_slice(&entry.to_le_bytes());
+        }
+
+        /// Maps `va` to `leaf` through the tables, the PML4 or PML5 first and the PT last, every level above
+        /// the PT allowing everything.
+        pub fn map(&mut self, tables: &[u64], va: u64, leaf: u64) {
+            let top = tables.len() as u32;
+            for (index, table) in tables.iter().enumerate() {
+                let shift = 12 + 9 * (top - 1 - index as u32);
+                let entry = tables.get(index + 1).map_or(leaf, |next| next | PRESENT | WRITABLE | USER);
+                self.set_entry(table + ((va >> shift) & 0x1FF) * 8, entry);
+            }
+        }
+    }
+
+    impl PhysicalMemory for FakeMemory {
+        fn read_entry(&self, pa: u64) -> Option<u64> {
+            let bytes = self.bytes.get(pa as usize..pa as usize + 8)?;
+            Some(u64::from_le_bytes(bytes.try_into().unwrap()))
+        }
+    }
+
+    const TABLES: [u64; 4] = [0x1000, 0x2000, 0x3000, 0x4000];
+
+    pub const FOUR_LEVEL: Paging = Paging {
+        five_level: false,
+        nxe: true,
+        wp: true,
+        smep: true,
+        smap: true,
+        ac: false,
+        physical_address_bits: 39,
+    };
+
+    const fn access(kind: AccessKind, user: bool) -> Access {
+        Access { kind, user }
+    }
+
+    fn page(pa: u64, page_size: u64, level: u8, writable: bool) -> Walk {
+        Walk {
+            pa,
+            page_size,
+            level,
+            writable,
+            user: false,
+            executable: true,
+        }
+    }
+
+    #[test]
+    fn four_kbyte_pages_translate() {
+        let mut memory = FakeMemory::new(8);
+        let va = 0xFFFF_F801_2345_6789;
+        memory.map(&TABLES, va, 0x7000 | PRESENT | WRITABLE);
+
+        assert_eq!(walk(&memory, &FOUR_LEVEL, 0x1000 | 0x5 | 1 << 63, va), Ok(page(0x7789, 0x1000, 1, true)));
+
+        // The neighbouring page isn't mapped, and the address without its sign extension isn't canonical.
+        assert_eq!(
+            walk(&memory, &FOUR_LEVEL, 0x1000, va + 0x1000),
+            Err(WalkError {
+                level: 1,
+                fault: Fault::NotPresent
+            })
+        );
+        assert_eq!(
+            walk(&memory, &FOUR_LEVEL, 0x1000, 0x0000_F801_2345_6789),
+            Err(WalkError {
+                level: 0,
+                fault: Fault::NonCanonical
+            })
+        );
+    }
+
+    #[test]
+    fn large_pages_end_the_walk() {
+        let mut memory = FakeMemory::new(8);
+        memory.set_entry(0x1000, 0x2000 | PRESENT | WRITABLE);
+        // A read-only 1-GByte page at 3 GiB, PAT set, and a 2-MByte page through a PD.
+        memory.set_entry(0x2000, 0xC000_1000 | PAGE_SIZE | PRESENT);
+        memory.set_entry(0x2008, 0x3000 | PRESENT | WRITABLE);
+        memory.set_entry(0x3000 + 8 * 5, 0x60_0000 | PAGE_SIZE | PRESENT | WRITABLE);
+
+        assert_eq!(walk(&memory, &FOUR_LEVEL, 0x1000, 0x1234_5678), Ok(page(0xD234_5678, 0x4000_0000, 3, false)));
+        assert_eq!(walk(&memory, &FOUR_LEVEL, 0x1000, 0x4000_0000 + 5 * 0x20_0000 + 0x1234), Ok(page(0x60_1234, 0x20_0000, 2, true)));
+
+        // Bits between PAT and the address of a large page are reserved, as is the page size bit of a PML4E.
+        memory.set_entry(0x3000 + 8 * 5, 0x60_2000 | PAGE_SIZE | PRESENT);
+        assert_eq!(
+            walk(&memory, &FOUR_LEVEL, 0x1000, 0x4000_0000 + 5 * 0x20_0000),
+            Err(WalkError {
+                level: 2,
+                fault: Fault::Reserved
+            })
+        );
+        memory.set_entry(0x1000, 0x2000 | PAGE_SIZE | PRESENT);
+        assert_eq!(
+            walk(&memory, &FOUR_LEVEL, 0x1000, 0),
+            Err(WalkError {
+                level: 4,
+                fault: Fault::Reserved
+            })
+        );
+    }
+
+    #[test]
+    fn walks_stop_at_missing_levels() {
+        let mut memory = FakeMemory::new(8);
+        let va = 0x7FF0_0000_0000;
+        memory.map(&TABLES, va, 0x7000 | PRESENT | EXECUTE_DISABLE);
+        assert!(!walk(&memory, &FOUR_LEVEL, 0x1000, va).unwrap().writable);
+
+        // Without IA32_EFER.NXE, execute-disable is a reserved bit.
+        let without_nxe = Paging { nxe: false, ..FOUR_LEVEL };
+        assert_eq!(
+            walk(&memory, &without_nxe, 0x1000, va),
+            Err(WalkError {
+                level: 1,
+                fault: Fault::Reserved
+            })
+        );
+
+        // A table outside physical memory ends the walk as well as a clear present bit.
+        memory.set_entry(0x3000 + ((va >> 21) & 0x1FF) * 8, 0x10_0000 | PRESENT);
+        assert_eq!(
+            walk(&memory, &FOUR_LEVEL, 0x1000, va),
+            Err(WalkError {
+                level: 1,
+                fault: Fault::Unbacked
+            })
+        );
+        memory.set_entry(0x1000 + ((va >> 39) & 0x1FF) * 8, 0x2000);
+        assert_eq!(
+            walk(&memory, &FOUR_LEVEL, 0x1000, va),
+            Err(WalkError {
+                level: 4,
+                fault: Fault::NotPresent
+            })
+        );
+    }
+
+    #[test]
+    fn five_level_paging_widens_addresses() {
+        let mut memory = FakeMemory::new(8);
+        let five_level = Paging {
+            five_level: true,
+            ..FOUR_LEVEL
+        };
+        let va = 0x00FF_0000_1234_5678;
+        memory.map(&[0x1000, 0x2000, 0x3000, 0x4000, 0x5000], va, 0x7000 | PRESENT | USER);
+
+        assert_eq!(walk(&memory, &five_level, 0x1000, va).map(|walk| walk.pa), Ok(0x7678));
+        assert_eq!(
+            walk(&memory, &FOUR_LEVEL, 0x1000, va),
+            Err(WalkError {
+                level: 0,
+                fault: Fault::NonCanonical
+            })
+        );
+
+        assert!(five_level.is_canonical(0xFF00_0000_0000_0000) && !five_level.is_canonical(0xFE00_0000_0000_0000));
+        assert!(five_level.is_canonical_range(0xFF_FFFF_FFFF_F000, 0x1000));
+        assert!(!five_level.is_canonical_range(0xFF_FFFF_FFFF_F000, 0x1001));
+    }
+
+    #[test]
+    fn canonical_ranges_stay_in_one_half() {
+        assert!(FOUR_LEVEL.is_canonical(0x7FFF_FFFF_FFFF) && FOUR_LEVEL.is_canonical(0xFFFF_8000_0000_0000));
+        assert!(!FOUR_LEVEL.is_canonical(0x8000_0000_0000) && !FOUR_LEVEL.is_canonical(0xFFFF_7FFF_FFFF_FFFF));
+
+        assert!(FOUR_LEVEL.is_canonical_range(0x7FFF_FFFF_F000, 0x1000));
+        assert!(!FOUR_LEVEL.is_canonical_range(0x7FFF_FFFF_F000, 0x1001));
+        assert!(FOUR_LEVEL.is_canonical_range(0xFFFF_FFFF_FFFF_F000, 0x1000));
+        assert!(!FOUR_LEVEL.is_canonical_range(0xFFFF_FFFF_FFFF_F000, 0x1001));
+    }
+
+    #[test]
+    fn access_rights_follow_the_mode() {
+        let mut memory = FakeMemory::new(8);
+        memory.map(&TABLES, 0x1000_0000, 0x6000 | PRESENT | USER);
+        memory.map(&TABLES, 0x1000_1000, 0x7000 | PRESENT | EXECUTE_DISABLE);
+        let check = |paging: &Paging, va, access| {
+            translate_gva(&memory, paging, 0x1000, va, access)
+                .map(|_| ())
+                .map_err(|error| error.fault)
+        };
+
+        // A read-only user page: user reads and fetches pass, writes don't. The kernel meets SMEP and SMAP.
+        let user = 0x1000_0000;
+        assert_eq!(check(&FOUR_LEVEL, user, access(AccessKind::Read, true)), Ok(()));
+        assert_eq!(check(&FOUR_LEVEL, user, access(AccessKind::Execute, true)), Ok(()));
+        assert_eq!(check(&FOUR_LEVEL, user, access(AccessKind::Write, true)), Err(Fault::ReadOnly));
+        assert_eq!(check(&FOUR_LEVEL, user, access(AccessKind::Execute, false)), Err(Fault::Smep));
+        assert_eq!(check(&FOUR_LEVEL, user, access(AccessKind::Read, false)), Err(Fault::Smap));
+        assert_eq!(check(&Paging { ac: true, ..FOUR_LEVEL }, user, access(AccessKind::Read, false)), Ok(()));
+
+        // Without CR0.WP the kernel writes read-only pages, user mode still doesn't.
+        let legacy = Paging {
+            wp: false,
+            smap: false,
+            ..FOUR_LEVEL
+        };
+        assert_eq!(check(&legacy, user, access(AccessKind::Write, false)), Ok(()));
+        assert_eq!(check(&legacy, user, access(AccessKind::Write, true)), Err(Fault::ReadOnly));
+
+        // An execute-disabled supervisor page.
+        let kernel = 0x1000_1000;
+        assert_eq!(check(&FOUR_LEVEL, kernel, access(AccessKind::Read, true)), Err(Fault::Supervisor));
+        assert_eq!(check(&FOUR_LEVEL, kernel, access(AccessKind::Execute, false)), Err(Fault::NoExecute));
+        assert_eq!(check(&FOUR_LEVEL, kernel, access(AccessKind::Read, false)), Ok(()));
+    }
+
+    #[test]
+    fn cache_drops_invalidated_pages() {
+        let mut cache = TranslationCache::new(true);
+        let small = page(0x7000, 0x1000, 1, true);
+        let large = page(0x40_0000, 0x20_0000, 2, true);
+        cache.insert(0x1005, 0x1000_0123, small);
+        cache.insert(0x1000, 0x2000_3456, Walk { pa: 0x40_3456, ..large });
+
+        assert_eq!(cache.lookup(0x1000, 0x1000_0FFF), Some(Walk { pa: 0x7FFF, ..small }));
+        assert_eq!(cache.lookup(0x1000, 0x2000_3000), Some(Walk { pa: 0x40_3000, ..large }));
+        assert_eq!(cache.lookup(0x2000, 0x1000_0000), None);
+
+        // INVLPG of any address in the 2-MByte page drops its walks, but leaves the other page.
+        cache.invalidate(0x201F_F000);
+        assert_eq!(cache.lookup(0x1000, 0x2000_3000), None);
+        assert!(cache.lookup(0x1000, 0x1000_0000).is_some());
+        cache.flush();
+        assert_eq!(cache.lookup(0x1000, 0x1000_0000), None);
+
+        let mut disabled = TranslationCache::new(false);
+        disabled.insert(0x1000, 0x1000_0000, small);
+        assert_eq!(disabled.lookup(0x1000, 0x1000_0000), None);
+    }
+}
@@ -13,6 +13,9 @@ use {
     x86::bits64::paging::{pd_index, pdpt_index, pml4_index, pt_index, VAddr, BASE_PAGE_SHIFT, BASE_PAGE_SIZE, HUGE_PAGE_SIZE, LARGE_PAGE_SIZE},
 };
 
+/// The end of the physical addresses `PageTables::build_identity` maps, the first 512GB.
+pub const IDENTITY_MAP_END: u64 = 512 * HUGE_PAGE_SIZE as u64;
+
 /// Represents the entire Page Tables structure for the hypervisor.
 ///
 /// The Page Tables mechanism is crucial for virtual memory management in x86-64 architecture.
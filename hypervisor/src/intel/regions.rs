@@ -0,0 +1,184 @@
+//! Page-aligned regions handed to the processor by physical address.
+//!
+//! The VMXON region, the VMCS and the MSR bitmap must each start on a 4 KiB boundary and must not cross
+//! the page they start in, and the host stack must be page-granular for the host page tables. Relying on
+//! the alignment of whatever struct happens to contain them broke once already, so every such region is
+//! wrapped in a `ContiguousPage`, which checks the layout at compile time, zeroes the region and returns
+//! the physical address given to `vmxon`, `vmptrld` and the VMCS.
+//!
+//! Debug builds also check that no two regions of the same kind overlap, e.g. the VMCS of two processors.
+
+use {
+    crate::intel::{bitmap::MsrBitmap, mode_watch::RealModePages, page::Page, support::rdmsr, ve::VePages, vmcs::Vmcs, vmxon::Vmxon},
+    core::{
+        mem::size_of,
+        ops::{Deref, DerefMut},
+        ptr,
+    },
+    x86::{bits64::paging::BASE_PAGE_SIZE, msr},
+};
+
+/// Contents of a `ContiguousPage`.
+///
+/// # Safety
+///
+/// All-zero bytes must be a valid value of the type, and its size must be a non-zero multiple of 4 KiB.
+pub unsafe trait RegionContents {
+    /// The name of the region in logs and assertions.
+    const NAME: &'static str;
+}
+
+/// Regions starting with the VMCS revision identifier.
+pub trait VmxRegion: RegionContents {
+    /// Returns the revision identifier field.
+    fn revision_id_mut(&mut self) -> &mut u32;
+}
+
+/// A zeroed, page-aligned region given to the processor by physical address.
+///
+/// The hypervisor runs on identity-mapped page tables, so the address of the region is its physical address.
+/// Nothing else is stored next to the region, the wrapper is exactly as large as its contents.
+#[repr(C, align(4096))]
+#[derive(Debug, Clone, Copy)]
+pub struct ContiguousPage<T> {
+    /// The region itself, at the start of the wrapper and therefore page-aligned.
+    page: T,
+}
+
+impl<T: RegionContents> ContiguousPage<T> {
+    /// Evaluated by `init`, fails the build for contents that can't be a region.
+    const LAYOUT_CHECK: () = assert!(size_of::<T>() != 0 && size_of::<T>() % BASE_PAGE_SIZE == 0, "regions must be page-granular");
+
+    /// Wraps `page`, which `init` zeroes before the region is used.
+    pub const fn new(page: T) -> Self {
+        Self { page }
+    }
+
+    /// Zeroes the region.
+    pub fn init(&mut self) {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::LAYOUT_CHECK;
+
+        // Safety: `RegionContents` guarantees that zeroes are a valid `T`.
+        unsafe { ptr::write_bytes(&mut self.page as *mut T as *mut u8, 0, size_of::<T>()) };
+
+        debug_assert_eq!(self.physical_address() % BASE_PAGE_SIZE as u64, 0, "{} is not page-aligned", T::NAME);
+        #[cfg(debug_assertions)]
+        registry::record(T::NAME, self.physical_address(), size_of::<T>());
+    }
+
+    /// Returns the physical address of the region, its identity-mapped address.
+    pub fn physical_address(&self) -> u64 {
+        &self.page as *const T as u64
+    }
+}
+
+impl<T: VmxRegion> ContiguousPage<T> {
+    /// Zeroes the region and writes the VMCS revision identifier of `IA32_VMX_BASIC` to it.
+    pub fn init_with_revision_id(&mut self) {
+        self.init();
+
+        // Bit 31 of the identifier is the shadow-VMCS indicator, which stays clear for ordinary regions.
+        *self.page.revision_id_mut() = rdmsr(msr::IA32_VMX_BASIC) as u32 & !(1 << 31);
+    }
+}
+
+impl<T> Deref for ContiguousPage<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.page
+    }
+}
+
+impl<T> DerefMut for ContiguousPage<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.page
+    }
+}
+
+unsafe impl RegionContents for Vmxon {
+    const NAME: &'static str = "VMXON region";
+}
+
+impl VmxRegion for Vmxon {
+    fn revision_id_mut(&mut self) -> &mut u32 {
+        &mut self.revision_id
+    }
+}
+
+unsafe impl RegionContents for Vmcs {
+    const NAME: &'static str = "VMCS";
+}
+
+impl VmxRegion for Vmcs {
+    fn revision_id_mut(&mut self) -> &mut u32 {
+        &mut self.revision_id
+    }
+}
+
+unsafe impl RegionContents for MsrBitmap {
+    const NAME: &'static str = "MSR bitmap";
+}
+
+unsafe impl RegionContents for VePages {
+    const NAME: &'static str = "#VE pages";
+}
+
+unsafe impl RegionContents for RealModePages {
+    const NAME: &'static str = "real-mode pages";
+}
+
+unsafe impl<const N: usize> RegionContents for [Page; N] {
+    const NAME: &'static str = "host stack";
+}
+
+/// The regions initialized so far, checked for overlaps in debug builds.
+#[cfg(debug_assertions)]
+mod registry {
+    use {alloc::vec::Vec, spin::Mutex};
+
+    /// The name, start and size of every initialized region.
+    static REGIONS: Mutex<Vec<(&'static str, u64, usize)>> = Mutex::new(Vec::new());
+
+    /// Records a region, asserting that it doesn't overlap another region of the same kind.
+    ///
+    /// A region initialized twice at the same address, e.g. the MSR bitmap after a reload, is recorded once.
+    pub fn record(name: &'static str, start: u64, size: usize) {
+        let mut regions = REGIONS.lock();
+        let end = start + size as u64;
+
+        for &(other_name, other_start, other_size) in regions.iter().filter(|(other_name, ..)| *other_name == name) {
+            if other_start == start && other_size == size {
+                return;
+            }
+
+            let other_end = other_start + other_size as u64;
+            assert!(
+                end <= other_start || other_end <= start,
+                "{} at {:#x}..{:#x} overlaps the {} at {:#x}..{:#x}",
+                name,
+                start,
+                end,
+                other_name,
+                other_start,
+                other_end
+            );
+        }
+
+        regions.push((name, start, size));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::intel::page::Page};
+
+    #[test]
+    fn regions_are_as_large_as_their_contents() {
+        assert_eq!(size_of::<ContiguousPage<Vmxon>>(), BASE_PAGE_SIZE);
+        assert_eq!(size_of::<ContiguousPage<Vmcs>>(), BASE_PAGE_SIZE);
+        assert_eq!(size_of::<ContiguousPage<MsrBitmap>>(), size_of::<MsrBitmap>());
+        assert_eq!(size_of::<ContiguousPage<[Page; 4]>>(), 4 * BASE_PAGE_SIZE);
+    }
+}
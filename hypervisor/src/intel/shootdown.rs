@@ -0,0 +1,255 @@
+//! Flushes the EPT caches of the other virtualized processors after a change they may have cached (TLB shootdown).
+//!
+//! Every processor has a mailbox holding the latest shootdown requested from it and the latest one it
+//! acknowledged. A processor handles its mailbox at the safe points of its VM exit loop, right after a VM
+//! exit and right before the next VM entry: it applies the hooks `hook_sync` published, and flushes with
+//! INVEPT single-context for each of its EPTs. Processors in VMX root operation handle the request before
+//! they run the guest again, so the initiator only kicks the ones running the guest, with an NMI that exits
+//! through the "NMI exiting" control, and waits for their acknowledgements. A processor that fails to apply
+//! a hook doesn't acknowledge and retries at its next safe point. A processor that doesn't acknowledge in
+//! time is reported with the state of every mailbox and the initiator goes on.
+//!
+//! A processor marks itself as running the guest before it reads its mailbox, and the initiator posts the
+//! request before it reads the mark, so at least one of them sees the other and no processor enters the
+//! guest with stale translations. Initiators keep handling their own mailbox while they wait, two
+//! concurrent shootdowns never wait for each other.
+//!
+//! A processor entering the guest in the wait-for-SIPI state, an application processor the operating system
+//! reset with INIT, counts as in VMX root operation: the state blocks NMIs, and the SIPI that ends it exits
+//! and handles the mailbox before the guest executes an instruction.
+//!
+//! Every processor changes only its own EPTs. Changes all of them make, like hooks, are requested with
+//! `Scope::AllProcessors`; changes of the current processor alone, like the permission flips of hooks in VM exits,
+//! only flush it with `Scope::CurrentProcessor`.
+
+use {
+    crate::intel::{
+        apic,
+        hooks::hook_sync,
+        invept::{invept_all_contexts, invept_single_context},
+        invvpid,
+        state::GuestActivityState,
+        support::{rdtsc, vmread},
+        vcpu,
+        vm::Vm,
+    },
+    core::sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    log::*,
+    shared::hvstatus::{CPU_STATE_VIRTUALIZED, MAX_PROCESSORS},
+    x86::vmx::vmcs,
+};
+
+/// The TSC ticks an initiator waits for the acknowledgements, about a second at common frequencies.
+pub const TIMEOUT_TSC_TICKS: u64 = 1 << 31;
+
+/// The processors whose EPT caches a change made stale.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Scope {
+    /// Only the current processor, which changed its own EPT.
+    CurrentProcessor,
+
+    /// Every virtualized processor, which applies the hooks `hook_sync` published to its own EPTs.
+    AllProcessors,
+}
+
+/// The shootdown state of one processor.
+struct Mailbox {
+    /// The latest shootdown requested from the processor.
+    requested: AtomicU64,
+
+    /// The latest shootdown the processor flushed for.
+    acknowledged: AtomicU64,
+
+    /// Whether the processor runs the guest, or is about to.
+    in_guest: AtomicBool,
+
+    /// The NMIs initiators sent to the processor.
+    kicks: AtomicU64,
+
+    /// The NMIs of `kicks` the processor took, the others are NMIs of the guest.
+    kicks_taken: AtomicU64,
+}
+
+/// The mailboxes by initial APIC ID.
+static MAILBOXES: [Mailbox; MAX_PROCESSORS] = [const {
+    Mailbox {
+        requested: AtomicU64::new(0),
+        acknowledged: AtomicU64::new(0),
+        in_guest: AtomicBool::new(false),
+        kicks: AtomicU64::new(0),
+        kicks_taken: AtomicU64::new(0),
+    }
+}; MAX_PROCESSORS];
+
+/// The latest shootdown, `0` before the first one.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Flushes the EPT-derived translations a change of EPT entries made stale, see the module documentation.
+///
+/// # Arguments
+///
+/// * `scope` - The processors that may have cached the changed entries.
+pub fn flush_ept(scope: Scope) {
+    invept_all_contexts();
+    invvpid::flush_for_ept_change();
+
+    if scope == Scope::AllProcessors {
+        broadcast();
+    }
+}
+
+/// Requests a shootdown from every other virtualized processor and waits for the ones running the guest.
+fn broadcast() {
+    let current = vcpu::current_apic_id();
+    let generation = GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+
+    let mut waiting = [false; MAX_PROCESSORS];
+    for (apic_id, vcpu) in (0..vcpu::started()).filter_map(vcpu::by_start_order) {
+        let Some(mailbox) = MAILBOXES.get(apic_id as usize) else {
+            continue;
+        };
+        if apic_id == current || vcpu.state() != CPU_STATE_VIRTUALIZED {
+            continue;
+        }
+
+        mailbox.requested.fetch_max(generation, Ordering::SeqCst);
+        if mailbox.in_guest.load(Ordering::SeqCst) {
+            mailbox.kicks.fetch_add(1, Ordering::SeqCst);
+            apic::send_nmi(apic_id);
+            waiting[apic_id as usize] = true;
+        }
+    }
+
+    let start = rdtsc();
+    loop {
+        let acknowledged = MAILBOXES
+            .iter()
+            .zip(waiting.iter())
+            .all(|(mailbox, &waiting)| !waiting || mailbox.acknowledged.load(Ordering::Acquire) >= generation);
+        if acknowledged {
+            return;
+        }
+
+        if rdtsc().wrapping_sub(start) > TIMEOUT_TSC_TICKS {
+            dump(generation);
+            return;
+        }
+
+        // Another initiator may be waiting for this processor.
+        if let Some(mailbox) = MAILBOXES.get(current as usize) {
+            if mailbox.requested.load(Ordering::Acquire) > mailbox.acknowledged.load(Ordering::Acquire) {
+                invept_all_contexts();
+                invvpid::flush_for_ept_change();
+                mailbox
+                    .acknowledged
+                    .fetch_max(mailbox.requested.load(Ordering::Acquire), Ordering::Release);
+            }
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Logs every mailbox after a shootdown timed out.
+///
+/// # Arguments
+///
+/// * `generation` - The shootdown that timed out.
+fn dump(generation: u64) {
+    error!("EPT shootdown {} timed out on processor {}", generation, vcpu::current_apic_id());
+    for (apic_id, vcpu) in (0..vcpu::started()).filter_map(vcpu::by_start_order) {
+        let Some(mailbox) = MAILBOXES.get(apic_id as usize) else {
+            continue;
+        };
+        error!(
+            "Processor {}: state {}, requested {}, acknowledged {}, in guest {}, NMIs {} taken {}",
+            apic_id,
+            vcpu.state(),
+            mailbox.requested.load(Ordering::Relaxed),
+            mailbox.acknowledged.load(Ordering::Relaxed),
+            mailbox.in_guest.load(Ordering::Relaxed),
+            mailbox.kicks.load(Ordering::Relaxed),
+            mailbox.kicks_taken.load(Ordering::Relaxed),
+        );
+    }
+}
+
+/// Applies the hooks that changed and flushes the EPTs of the processor if a shootdown was requested from it since
+/// it last flushed.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+/// * `mailbox` - The mailbox of the current processor.
+fn acknowledge(vm: &mut Vm, mailbox: &Mailbox) {
+    // The hooks are published before the request is posted, so they are read after it.
+    let requested = mailbox.requested.load(Ordering::SeqCst);
+
+    // `hook_sync` logged the failure, the initiator reports the missing acknowledgement.
+    if hook_sync::sync(vm).is_err() {
+        return;
+    }
+
+    if requested <= mailbox.acknowledged.load(Ordering::Acquire) {
+        return;
+    }
+
+    invept_single_context(vm.primary_eptp);
+    for index in 1..vm.ept_views.view_count() as u16 {
+        if let Some(eptp) = vm.ept_views.eptp(index) {
+            invept_single_context(eptp);
+        }
+    }
+    invvpid::flush_for_ept_change();
+
+    mailbox.acknowledged.fetch_max(requested, Ordering::Release);
+}
+
+/// Marks the processor as in VMX root operation and handles its mailbox, right after a VM exit.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+pub fn after_vm_exit(vm: &mut Vm) {
+    if let Some(mailbox) = MAILBOXES.get(vm.apic_id as usize) {
+        mailbox.in_guest.store(false, Ordering::SeqCst);
+        acknowledge(vm, mailbox);
+    }
+}
+
+/// Marks the processor as running the guest and handles its mailbox, right before a VM entry.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+pub fn before_vm_entry(vm: &mut Vm) {
+    if let Some(mailbox) = MAILBOXES.get(vm.apic_id as usize) {
+        let waits_for_sipi = vmread(vmcs::guest::ACTIVITY_STATE) == GuestActivityState::WaitForSipi as u64;
+        mailbox.in_guest.store(!waits_for_sipi, Ordering::SeqCst);
+        acknowledge(vm, mailbox);
+    }
+}
+
+/// Takes an NMI of the processor, which exited for it or took it in VMX root operation.
+///
+/// # Arguments
+///
+/// * `apic_id` - The APIC ID of the current processor.
+///
+/// # Returns
+///
+/// `true` if an initiator sent the NMI, whose request the VM exit loop handles, `false` for an NMI of the guest.
+pub fn take_nmi(apic_id: u32) -> bool {
+    let Some(mailbox) = MAILBOXES.get(apic_id as usize) else {
+        return false;
+    };
+
+    let kicks = mailbox.kicks.load(Ordering::SeqCst);
+    let taken = mailbox.kicks_taken.load(Ordering::Relaxed);
+    if taken >= kicks {
+        return false;
+    }
+
+    // NMIs that arrive while one is pending collapse into one, take every kick sent so far.
+    mailbox.kicks_taken.store(kicks, Ordering::Relaxed);
+    true
+}
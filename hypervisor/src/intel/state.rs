@@ -1,3 +1,5 @@
+use {crate::intel::support::rdmsr, x86::msr};
+
 /// Represents the activity state of a logical processor in VMX operation.
 #[repr(u32)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -15,3 +17,42 @@ pub enum GuestActivityState {
     /// The logical processor is inactive because it is waiting for a startup-IPI (SIPI).
     WaitForSipi = 0x00000003,
 }
+
+impl GuestActivityState {
+    /// Returns whether VM entries can put the guest in this state according to `IA32_VMX_MISC`.
+    ///
+    /// Bits 8:6 report support for the HLT, shutdown and wait-for-SIPI states, the active state is always supported.
+    ///
+    /// # Arguments
+    ///
+    /// * `vmx_misc` - The value of `IA32_VMX_MISC`.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: A.6 MISCELLANEOUS DATA
+    pub const fn allowed_by(self, vmx_misc: u64) -> bool {
+        match self {
+            Self::Active => true,
+            state => vmx_misc & 1 << (state as u32 + 5) != 0,
+        }
+    }
+
+    /// Returns whether VM entries can put the guest in this state on the current processor.
+    pub fn is_supported(self) -> bool {
+        self.allowed_by(rdmsr(msr::IA32_VMX_MISC))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vmx_misc_reports_the_inactive_states() {
+        // HLT and wait-for-SIPI, not shutdown.
+        let vmx_misc = 0x1_4000_0000 | 1 << 8 | 1 << 6 | 0x5;
+        assert!(GuestActivityState::Active.allowed_by(0));
+        assert!(GuestActivityState::Hlt.allowed_by(vmx_misc));
+        assert!(!GuestActivityState::Shutdown.allowed_by(vmx_misc));
+        assert!(GuestActivityState::WaitForSipi.allowed_by(vmx_misc));
+        assert!(!GuestActivityState::WaitForSipi.allowed_by(1 << 7 | 1 << 6));
+    }
+}
@@ -46,6 +46,11 @@ where
     unsafe { x86::bits64::vmx::vmwrite(field, u64::from(val)) }.unwrap();
 }
 
+/// Read the Extended Control Register XCR0. Only supported if CR4_ENABLE_OS_XSAVE is set.
+pub fn xgetbv() -> u64 {
+    x86_64::registers::xcontrol::XCr0::read_raw()
+}
+
 /// Write to Extended Control Register XCR0. Only supported if CR4_ENABLE_OS_XSAVE is set.
 pub fn xsetbv(val: u64) {
     unsafe {
@@ -113,11 +118,28 @@ pub fn read_effective_guest_cr4() -> u64 {
     vmread(x86::vmx::vmcs::control::CR4_READ_SHADOW) & mask | vmread(x86::vmx::vmcs::guest::CR4) & !mask
 }
 
+/// Returns the current privilege level of the guest, the DPL of its SS.
+pub fn guest_cpl() -> u8 {
+    ((vmread(x86::vmx::vmcs::guest::SS_ACCESS_RIGHTS) >> 5) & 0b11) as u8
+}
+
 /// Writes a value to the Cr2 register.
 pub fn cr2_write(val: u64) {
     unsafe { x86::controlregs::cr2_write(val) };
 }
 
+/// Reads the CR8 register, the task-priority class of the local APIC.
+pub fn cr8() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov {}, cr8", out(reg) value, options(nomem, nostack, preserves_flags)) };
+    value
+}
+
+/// Writes a value to the CR8 register.
+pub fn cr8_write(val: u64) {
+    unsafe { asm!("mov cr8, {}", in(reg) val, options(nomem, nostack, preserves_flags)) };
+}
+
 /// Writes a value to the DR0 register.
 pub fn dr0_write(val: u64) {
     unsafe { x86::debugregs::dr0_write(val as _) };
@@ -174,6 +196,11 @@ pub fn dr7_read() -> u64 {
     unsafe { x86::debugregs::dr7().0 as u64 }
 }
 
+/// Writes a value to the DR7 register.
+pub fn dr7_write(val: u64) {
+    unsafe { x86::debugregs::dr7_write(x86::debugregs::Dr7(val as _)) };
+}
+
 /// Disables maskable interrupts.
 pub fn cli() {
     unsafe { x86::irq::disable() };
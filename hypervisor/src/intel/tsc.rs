@@ -0,0 +1,174 @@
+//! Hides the time spent in the hypervisor from the time stamp counter the guest reads.
+//!
+//! With the "use TSC offsetting" control, RDTSC, RDTSCP and RDMSR of IA32_TIME_STAMP_COUNTER return the
+//! host counter plus the TSC offset of the VMCS without a VM exit. Every exit the host measures the cycles
+//! between the exit and the next entry and lowers the offset by all of them but `VISIBLE_EXIT_CYCLES`, so
+//! the guest sees even a CPUID cost about as much as it does on bare metal.
+//!
+//! The offset decreases by at most the cycles that passed, so the counter the guest reads never goes
+//! backwards, and the guest counter of a busy processor falls behind the real one by all the time it spent
+//! in the hypervisor. The skew limit bounds that lag, and with it how far two processors drift apart: an exit
+//! that would take the offset below minus the limit resynchronizes the processor with the real counter
+//! instead, which the guest sees as a single long exit, like an SMI, before the compensation goes on.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.3 CHANGES TO INSTRUCTION BEHAVIOR IN VMX NON-ROOT OPERATION
+
+/// The cycles an exit appears to take in the guest, about what CPUID takes without a hypervisor.
+pub const VISIBLE_EXIT_CYCLES: u64 = 150;
+
+/// The TSC offset of one processor.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TscCompensation {
+    /// Whether the offset is maintained, `HvFeatureFlags::RDTSC_COMPENSATION`.
+    pub enabled: bool,
+
+    /// The TSC offset of the VMCS, never positive.
+    pub offset: i64,
+
+    /// The times the offset was reset to zero at the skew limit.
+    pub resyncs: u64,
+
+    /// The host counter right after the last VM exit, `None` before the first one.
+    pub exit_tsc: Option<u64>,
+}
+
+impl TscCompensation {
+    /// Creates the state of a processor that wasn't compensated yet.
+    pub const fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            offset: 0,
+            resyncs: 0,
+            exit_tsc: None,
+        }
+    }
+
+    /// Records the host counter read right after a VM exit.
+    pub fn exited(&mut self, host_tsc: u64) {
+        if self.enabled {
+            self.exit_tsc = Some(host_tsc);
+        }
+    }
+
+    /// Returns the offset to write to the VMCS before the next VM entry, if it changed.
+    ///
+    /// # Arguments
+    ///
+    /// * `host_tsc` - The host counter read right before the entry.
+    /// * `skew_limit` - The most cycles the offset may hold the guest counter back.
+    pub fn entering(&mut self, host_tsc: u64, skew_limit: u64) -> Option<i64> {
+        let exit_tsc = self.exit_tsc.take()?;
+
+        let hidden = host_tsc.saturating_sub(exit_tsc).saturating_sub(VISIBLE_EXIT_CYCLES);
+        let floor = -(skew_limit.min(i64::MAX as u64) as i64);
+        let mut offset = self.offset.saturating_sub(hidden.min(i64::MAX as u64) as i64);
+
+        if offset < floor {
+            offset = 0;
+            self.resyncs += 1;
+        }
+
+        if offset == self.offset {
+            return None;
+        }
+
+        self.offset = offset;
+        Some(offset)
+    }
+
+    /// Returns the counter the guest reads at the host counter `host_tsc`.
+    pub fn guest_tsc(&self, host_tsc: u64) -> u64 {
+        host_tsc.wrapping_add_signed(self.offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exits_look_nearly_free() {
+        let mut tsc = TscCompensation::new(true);
+        assert_eq!(tsc.entering(1_000, u64::MAX), None);
+
+        tsc.exited(10_000);
+        let before_exit = tsc.guest_tsc(10_000);
+        assert_eq!(tsc.entering(14_000, u64::MAX), Some(-(4_000 - VISIBLE_EXIT_CYCLES as i64)));
+        assert_eq!(tsc.guest_tsc(14_000) - before_exit, VISIBLE_EXIT_CYCLES);
+
+        // Every exit is compensated once.
+        assert_eq!(tsc.entering(20_000, u64::MAX), None);
+    }
+
+    #[test]
+    fn guest_time_never_goes_backwards() {
+        let mut tsc = TscCompensation::new(true);
+        let mut host = 1_000_000u64;
+        let mut last_seen = tsc.guest_tsc(host);
+
+        for elapsed in [0, 1, VISIBLE_EXIT_CYCLES, 2_000, 50_000, 3] {
+            tsc.exited(host);
+            assert!(tsc.guest_tsc(host) >= last_seen);
+            host += elapsed;
+            tsc.entering(host, u64::MAX);
+            last_seen = tsc.guest_tsc(host);
+            host += 500;
+        }
+        assert_eq!(tsc.offset, -(2_000 + 50_000 - 2 * VISIBLE_EXIT_CYCLES as i64));
+    }
+
+    #[test]
+    fn offsets_resync_at_the_skew_limit() {
+        let mut tsc = TscCompensation::new(true);
+        tsc.exited(0);
+        assert_eq!(tsc.entering(4_150, 10_000), Some(-4_000));
+        tsc.exited(10_000);
+        assert_eq!(tsc.entering(14_150, 10_000), Some(-8_000));
+
+        // The next exit would hold the guest back more than the limit, the guest catches up with the host.
+        tsc.exited(20_000);
+        assert_eq!(tsc.entering(24_150, 10_000), Some(0));
+        assert_eq!(tsc.guest_tsc(24_150), 24_150);
+        assert_eq!(tsc.resyncs, 1);
+
+        tsc.exited(30_000);
+        assert_eq!(tsc.entering(34_150, 10_000), Some(-4_000));
+    }
+
+    #[test]
+    fn compensation_continues_past_the_skew_limit() {
+        let skew_limit = 1_000_000;
+        let mut tsc = TscCompensation::new(true);
+        let mut host = 0u64;
+        let mut last_seen = 0;
+        let mut hidden_exits = 0;
+
+        // 100,000 exits of 2,000 cycles hide 185 times the skew limit.
+        for _ in 0..100_000 {
+            host += 50_000;
+            tsc.exited(host);
+            let before_exit = tsc.guest_tsc(host);
+            assert!(before_exit >= last_seen);
+
+            host += 2_000;
+            tsc.entering(host, skew_limit);
+            last_seen = tsc.guest_tsc(host);
+            hidden_exits += u64::from(last_seen - before_exit == VISIBLE_EXIT_CYCLES);
+
+            assert!(tsc.offset >= -(skew_limit as i64) && tsc.offset <= 0);
+            assert!(host - last_seen <= skew_limit);
+        }
+
+        // Every exit is hidden but the one resynchronizing the processor after each 540 of them.
+        assert_eq!(tsc.resyncs, 100_000 / 541);
+        assert_eq!(hidden_exits, 100_000 - tsc.resyncs);
+    }
+
+    #[test]
+    fn disabled_processors_keep_the_host_counter() {
+        let mut tsc = TscCompensation::new(false);
+        tsc.exited(0);
+        assert_eq!(tsc.entering(1_000_000, u64::MAX), None);
+        assert_eq!(tsc.guest_tsc(1_000_000), 1_000_000);
+    }
+}
@@ -0,0 +1,700 @@
+//! Emulates what virtual-8086 mode can't do for a guest in real mode, see `mode_watch`.
+//!
+//! The guest runs at CPL 3 in virtual-8086 mode, so the privileged instructions of its real-mode code raise
+//! #GP(0) instead of running or exiting, which the exception bitmap turns into VM exits. The instructions the
+//! startup code of application processors and boot loaders use are decoded from guest memory and emulated
+//! with the VM exit handlers of their protected-mode counterparts: HLT, CLTS, INVD and WBINVD, RDMSR and WRMSR,
+//! MOV to and from CR0, CR3 and CR4, LGDT, LIDT and LMSW. Every other exception, and every event the
+//! `EventInjector` injects, is delivered the way real mode does it: through the interrupt vector table at
+//! linear address 0, with FLAGS, CS and IP pushed on the stack and no error code. An external interrupt or
+//! exception the processor tries to deliver through the IDT limit of 0 exits as #GP with its delivery in the
+//! IDT-vectoring information, which the `EventInjector` queues again and delivers here.
+//!
+//! The identity map makes the linear addresses of the guest its guest-physical addresses, which the EPT in
+//! use translates.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 6.4 ADDRESSING MODES AND ENCODING
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 20.1.4 Interrupt and Exception Handling
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 20.2.7 Sensitive Instructions
+
+use {
+    crate::intel::{
+        bitmap::MsrAccessType,
+        ept::Ept,
+        events::{Event, BLOCKING_BY_NMI},
+        mode_watch::{CS, RFLAGS_VM, SEGMENTS},
+        support::{read_effective_guest_cr0, vmread, vmwrite},
+        vm::Vm,
+        vmerror::{CrAccessReg, ExceptionInterrupt, InterruptionType},
+        vmexit::{
+            cr::{handle_clts, handle_lmsw, handle_mov_from_cr, handle_mov_to_cr0, handle_mov_to_cr3, handle_mov_to_cr4},
+            halt::handle_halt,
+            invd::handle_invd,
+            msr::handle_msr_access,
+            ExitType,
+        },
+    },
+    log::*,
+    x86::vmx::vmcs,
+    x86_64::registers::control::Cr0Flags,
+};
+
+/// The maximum length of an instruction.
+const MAX_INSTRUCTION_LENGTH: usize = 15;
+
+/// [Bit 8] RFLAGS: trap flag.
+const RFLAGS_TF: u64 = 1 << 8;
+
+/// [Bit 9] RFLAGS: interrupt enable flag.
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// [Bits 13:12] RFLAGS: I/O privilege level.
+const RFLAGS_IOPL: u64 = 0b11 << 12;
+
+/// [Bit 18] RFLAGS: alignment check.
+const RFLAGS_AC: u64 = 1 << 18;
+
+/// [Bit 31] IDT-vectoring information: the field is valid.
+const IDT_VECTORING_VALID: u64 = 1 << 31;
+
+/// The number of SS in `SEGMENTS`, the default segment of BP, ESP and EBP.
+const SS: u8 = 2;
+
+/// The number of DS in `SEGMENTS`, the default segment of the other memory operands.
+const DS: u8 = 3;
+
+/// The operand of LMSW.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    /// The general-purpose register with this number.
+    Register(u8),
+
+    /// The memory at this linear address.
+    Memory(u64),
+}
+
+/// A privileged instruction of real mode the hypervisor emulates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Privileged {
+    Hlt,
+    Clts,
+
+    /// INVD or WBINVD, both write back and invalidate the caches.
+    Wbinvd,
+    Rdmsr,
+    Wrmsr,
+    MovToCr {
+        cr: u8,
+        gpr: u8,
+    },
+    MovFromCr {
+        cr: u8,
+        gpr: u8,
+    },
+
+    /// LGDT of the pseudo-descriptor at `address`, whose base only counts 24 bits with a 16-bit operand size.
+    Lgdt {
+        address: u64,
+        operand_32bit: bool,
+    },
+
+    /// LIDT of the pseudo-descriptor at `address`, whose base only counts 24 bits with a 16-bit operand size.
+    Lidt {
+        address: u64,
+        operand_32bit: bool,
+    },
+    Lmsw(Operand),
+}
+
+/// A decoded instruction and its length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decoded {
+    pub instruction: Privileged,
+    pub length: u64,
+}
+
+/// Reads the bytes of an instruction.
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Cursor<'_> {
+    /// Returns the next byte, `None` past the bytes read from memory or the maximum instruction length.
+    fn byte(&mut self) -> Option<u8> {
+        if self.position == MAX_INSTRUCTION_LENGTH {
+            return None;
+        }
+        let byte = *self.bytes.get(self.position)?;
+        self.position += 1;
+        Some(byte)
+    }
+
+    /// Returns the next `size` bytes as a little-endian value.
+    fn immediate(&mut self, size: usize) -> Option<u64> {
+        (0..size).try_fold(0, |value, index| Some(value | u64::from(self.byte()?) << (index * 8)))
+    }
+
+    /// Returns a displacement of `size` bytes, sign-extended.
+    fn displacement(&mut self, size: usize) -> Option<u64> {
+        let value = self.immediate(size)?;
+        let shift = 64 - size * 8;
+        Some(((value << shift) as i64 >> shift) as u64)
+    }
+}
+
+/// Decodes the real-mode instruction in `bytes`.
+///
+/// # Arguments
+///
+/// * `bytes` - The bytes at CS:IP, at most `MAX_INSTRUCTION_LENGTH`.
+/// * `gpr` - Returns the general-purpose register with a number, for memory operands.
+/// * `segment_base` - Returns the base of the segment register with a number of `SEGMENTS`, for memory operands.
+///
+/// # Returns
+///
+/// The instruction, `None` if it isn't one of the emulated ones.
+pub fn decode(bytes: &[u8], gpr: impl Fn(u8) -> u64, segment_base: impl Fn(u8) -> u64) -> Option<Decoded> {
+    let mut cursor = Cursor { bytes, position: 0 };
+    let mut operand_32bit = false;
+    let mut address_32bit = false;
+    let mut segment = None;
+
+    let opcode = loop {
+        match cursor.byte()? {
+            0x66 => operand_32bit = true,
+            0x67 => address_32bit = true,
+            0x26 => segment = Some(0),
+            0x2E => segment = Some(1),
+            0x36 => segment = Some(2),
+            0x3E => segment = Some(3),
+            0x64 => segment = Some(4),
+            0x65 => segment = Some(5),
+            0xF0 | 0xF2 | 0xF3 => {}
+            opcode => break opcode,
+        }
+    };
+
+    let instruction = match opcode {
+        0xF4 => Privileged::Hlt,
+        0x0F => match cursor.byte()? {
+            0x06 => Privileged::Clts,
+            0x08 | 0x09 => Privileged::Wbinvd,
+            0x30 => Privileged::Wrmsr,
+            0x32 => Privileged::Rdmsr,
+            opcode @ (0x20 | 0x22) => {
+                // The operand is a register whatever the mod field encodes.
+                let modrm = cursor.byte()?;
+                let (cr, gpr) = (modrm >> 3 & 0b111, modrm & 0b111);
+                if !matches!(cr, 0 | 3 | 4) {
+                    return None;
+                }
+                if opcode == 0x22 {
+                    Privileged::MovToCr { cr, gpr }
+                } else {
+                    Privileged::MovFromCr { cr, gpr }
+                }
+            }
+            0x01 => {
+                let modrm = cursor.byte()?;
+                let register = modrm >> 6 == 0b11;
+                let operand = if register {
+                    Operand::Register(modrm & 0b111)
+                } else {
+                    Operand::Memory(memory_operand(&mut cursor, modrm, address_32bit, segment, &gpr, &segment_base)?)
+                };
+
+                match (modrm >> 3 & 0b111, operand) {
+                    (2, Operand::Memory(address)) => Privileged::Lgdt { address, operand_32bit },
+                    (3, Operand::Memory(address)) => Privileged::Lidt { address, operand_32bit },
+                    (6, operand) => Privileged::Lmsw(operand),
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    Some(Decoded {
+        instruction,
+        length: cursor.position as u64,
+    })
+}
+
+/// Decodes the memory operand of `modrm` and returns its linear address.
+///
+/// # Arguments
+///
+/// * `cursor` - The cursor right after the ModR/M byte.
+/// * `modrm` - The ModR/M byte, whose mod field isn't `0b11`.
+/// * `address_32bit` - Whether the address-size prefix selects 32-bit addressing.
+/// * `segment` - The segment of a segment-override prefix.
+/// * `gpr` - Returns the general-purpose register with a number.
+/// * `segment_base` - Returns the base of the segment register with a number.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 2-1. 16-Bit Addressing Forms with the ModR/M Byte
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 2-2. 32-Bit Addressing Forms with the ModR/M Byte
+fn memory_operand(
+    cursor: &mut Cursor,
+    modrm: u8,
+    address_32bit: bool,
+    segment: Option<u8>,
+    gpr: &impl Fn(u8) -> u64,
+    segment_base: &impl Fn(u8) -> u64,
+) -> Option<u64> {
+    const BX: u8 = 3;
+    const SP: u8 = 4;
+    const BP: u8 = 5;
+    const SI: u8 = 6;
+    const DI: u8 = 7;
+
+    let mode = modrm >> 6;
+    let rm = modrm & 0b111;
+
+    let (offset, stack) = if address_32bit {
+        let register = |index: u8| gpr(index) & 0xFFFF_FFFF;
+        let (mut offset, mut stack) = (0, false);
+
+        if rm == SP {
+            let sib = cursor.byte()?;
+            let (scale, index, base) = (sib >> 6, sib >> 3 & 0b111, sib & 0b111);
+            if index != SP {
+                offset = register(index) << scale;
+            }
+            if base == BP && mode == 0 {
+                offset += cursor.displacement(4)?;
+            } else {
+                offset += register(base);
+                stack = matches!(base, SP | BP);
+            }
+        } else if rm == BP && mode == 0 {
+            offset = cursor.displacement(4)?;
+        } else {
+            offset = register(rm);
+            stack = rm == BP;
+        }
+
+        let displacement = match mode {
+            1 => cursor.displacement(1)?,
+            2 => cursor.displacement(4)?,
+            _ => 0,
+        };
+        (offset.wrapping_add(displacement) & 0xFFFF_FFFF, stack)
+    } else {
+        let register = |index: u8| gpr(index) & 0xFFFF;
+        let (offset, stack) = match rm {
+            0 => (register(BX) + register(SI), false),
+            1 => (register(BX) + register(DI), false),
+            2 => (register(BP) + register(SI), true),
+            3 => (register(BP) + register(DI), true),
+            4 => (register(SI), false),
+            5 => (register(DI), false),
+            6 if mode == 0 => (cursor.immediate(2)?, false),
+            6 => (register(BP), true),
+            _ => (register(BX), false),
+        };
+
+        let displacement = match mode {
+            1 => cursor.displacement(1)?,
+            2 => cursor.displacement(2)?,
+            _ => 0,
+        };
+        (offset.wrapping_add(displacement) & 0xFFFF, stack)
+    };
+
+    let segment = segment.unwrap_or(if stack { SS } else { DS });
+    Some(segment_base(segment).wrapping_add(offset) & 0xFFFF_FFFF)
+}
+
+/// Returns the bytes real mode pushes delivering an event, IP, CS and FLAGS from the lowest address up, and the new SP.
+///
+/// # Arguments
+///
+/// * `flags` - The FLAGS of the guest, pushed with IOPL 0 as the guest's real mode has it.
+/// * `cs` - The CS selector of the guest.
+/// * `ip` - The IP the handler's IRET returns to.
+/// * `sp` - The SP of the guest.
+pub fn real_mode_frame(flags: u64, cs: u64, ip: u64, sp: u64) -> ([u8; 6], u64) {
+    let mut frame = [0; 6];
+    frame[0..2].copy_from_slice(&(ip as u16).to_le_bytes());
+    frame[2..4].copy_from_slice(&(cs as u16).to_le_bytes());
+    frame[4..6].copy_from_slice(&((flags & !RFLAGS_IOPL) as u16).to_le_bytes());
+    (frame, sp.wrapping_sub(6) & 0xFFFF)
+}
+
+/// Returns whether the guest runs in virtual-8086 mode for the real mode it intends.
+///
+/// A guest in protected mode may use virtual-8086 mode itself, its CR0.PE is set then.
+pub fn emulated_real_mode() -> bool {
+    vmread(vmcs::guest::RFLAGS) & RFLAGS_VM != 0 && read_effective_guest_cr0() & Cr0Flags::PROTECTED_MODE_ENABLE.bits() == 0
+}
+
+/// Returns the host physical address of the guest's linear address `linear`.
+///
+/// # Arguments
+///
+/// * `linear` - The linear address, its guest-physical address through the identity map.
+fn host_address(linear: u64) -> Option<u64> {
+    let (pml4, _, _) = Ept::decode_eptp(vmread(vmcs::control::EPTP_FULL)).ok()?;
+    unsafe { Ept::translate_guest_pa_to_host_pa(pml4, linear & 0xFFFF_FFFF) }.ok()
+}
+
+/// Reads guest memory, byte by byte as the bytes may lie in different pages.
+///
+/// # Arguments
+///
+/// * `linear` - The linear address of the byte with an index.
+/// * `buffer` - The bytes read.
+fn read_guest(linear: impl Fn(u64) -> u64, buffer: &mut [u8]) -> Option<()> {
+    for (index, byte) in buffer.iter_mut().enumerate() {
+        *byte = unsafe { (host_address(linear(index as u64))? as *const u8).read_volatile() };
+    }
+    Some(())
+}
+
+/// Writes guest memory, byte by byte as the bytes may lie in different pages.
+///
+/// # Arguments
+///
+/// * `linear` - The linear address of the byte with an index.
+/// * `bytes` - The bytes to write.
+fn write_guest(linear: impl Fn(u64) -> u64, bytes: &[u8]) -> Option<()> {
+    for (index, &byte) in bytes.iter().enumerate() {
+        unsafe { (host_address(linear(index as u64))? as *mut u8).write_volatile(byte) };
+    }
+    Some(())
+}
+
+/// Delivers `event` through the interrupt vector table, right before a VM entry instead of injecting it.
+///
+/// # Arguments
+///
+/// * `event` - The event the `EventInjector` injects.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 6.15 EXCEPTION AND INTERRUPT REFERENCE
+pub fn deliver(event: &Event) {
+    let software = matches!(
+        event.interruption_type,
+        InterruptionType::SoftwareInterrupt | InterruptionType::SoftwareException | InterruptionType::PrivilegedSoftwareException
+    );
+    let rip = vmread(vmcs::guest::RIP);
+    let ip = if software { rip + u64::from(event.instruction_length) } else { rip } & 0xFFFF;
+
+    let mut vector = [0; 4];
+    if read_guest(|index| u64::from(event.vector) * 4 + index, &mut vector).is_none() {
+        error!("The interrupt vector table is unreachable, dropping {:?}", event);
+        return;
+    }
+    let handler = u32::from_le_bytes(vector);
+
+    let rflags = vmread(vmcs::guest::RFLAGS);
+    let rsp = vmread(vmcs::guest::RSP);
+    let ss_base = vmread(vmcs::guest::SS_BASE);
+    let (frame, sp) = real_mode_frame(rflags, vmread(vmcs::guest::CS_SELECTOR), ip, rsp);
+    if write_guest(|index| ss_base + ((sp + index) & 0xFFFF), &frame).is_none() {
+        error!("The stack at {:#x}:{:#x} is unreachable, dropping {:?}", ss_base >> 4, sp, event);
+        return;
+    }
+
+    trace!("Delivering {:?} to {:04x}:{:04x}", event, handler >> 16, handler & 0xFFFF);
+
+    vmwrite(vmcs::guest::RSP, rsp & !0xFFFF | sp);
+    vmwrite(vmcs::guest::RFLAGS, rflags & !(RFLAGS_IF | RFLAGS_TF | RFLAGS_AC));
+    vmwrite(vmcs::guest::CS_SELECTOR, handler >> 16);
+    vmwrite(vmcs::guest::CS_BASE, (handler >> 16) << 4);
+    vmwrite(vmcs::guest::RIP, handler & 0xFFFF);
+
+    if event.interruption_type == InterruptionType::NonMaskableInterrupt {
+        vmwrite(vmcs::guest::INTERRUPTIBILITY_STATE, vmread(vmcs::guest::INTERRUPTIBILITY_STATE) | BLOCKING_BY_NMI);
+    }
+}
+
+/// Handles an exception of the guest in virtual-8086 mode, which exits for every vector.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+/// * `vector` - The vector of the exception.
+/// * `error_code` - The error code of the exception, `0` for the ones that push none.
+///
+/// # Returns
+///
+/// `ExitType::Continue`, the emulated instructions advance RIP themselves.
+pub fn handle_exception(vm: &mut Vm, vector: u8, error_code: u32) -> ExitType {
+    // The processor couldn't deliver an event through the IDT limit of 0, the `EventInjector` queued it again.
+    if vmread(vmcs::ro::IDT_VECTORING_INFO) & IDT_VECTORING_VALID != 0 {
+        return ExitType::Continue;
+    }
+
+    if vector == ExceptionInterrupt::GeneralProtectionFault as u8 && error_code == 0 {
+        if let Some(exit_type) = emulate(vm) {
+            return exit_type;
+        }
+    }
+
+    vm.events.inject_exception(vector, Some(error_code));
+
+    ExitType::Continue
+}
+
+/// Emulates the privileged instruction at CS:IP that raised #GP(0).
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+///
+/// # Returns
+///
+/// The exit type, `None` if the instruction isn't emulated and the #GP is delivered.
+fn emulate(vm: &mut Vm) -> Option<ExitType> {
+    let ip = vmread(vmcs::guest::RIP) & 0xFFFF;
+    let cs_base = vmread(vmcs::guest::CS_BASE);
+
+    let mut bytes = [0; MAX_INSTRUCTION_LENGTH];
+    read_guest(|index| cs_base + ((ip + index) & 0xFFFF), &mut bytes)?;
+
+    let registers = vm.guest_registers;
+    let Some(decoded) = decode(&bytes, |index| registers.gpr(index.into()), |index| vmread(SEGMENTS[index as usize].base)) else {
+        debug!("Delivering #GP(0) of {:02x?} at {:04x}:{:04x}", bytes, vmread(SEGMENTS[CS as usize].selector), ip);
+        return None;
+    };
+    trace!("Emulating {:?}", decoded);
+
+    let exit_type = match decoded.instruction {
+        Privileged::Hlt => handle_halt(),
+        Privileged::Clts => handle_clts(),
+        Privileged::Wbinvd => handle_invd(&mut vm.guest_registers),
+        Privileged::Rdmsr => handle_msr_access(vm, MsrAccessType::Read).expect("Failed to handle RDMSR"),
+        Privileged::Wrmsr => handle_msr_access(vm, MsrAccessType::Write).expect("Failed to handle WRMSR"),
+        Privileged::MovToCr { cr, gpr } => match cr {
+            0 => handle_mov_to_cr0(vm, gpr.into()),
+            3 => handle_mov_to_cr3(vm, gpr.into()),
+            _ => handle_mov_to_cr4(vm, gpr.into()).expect("Failed to handle MOV to CR4"),
+        },
+        Privileged::MovFromCr { cr, gpr } => {
+            let control_reg = match cr {
+                0 => CrAccessReg::Cr0,
+                3 => CrAccessReg::Cr3,
+                _ => CrAccessReg::Cr4,
+            };
+            handle_mov_from_cr(vm, control_reg, gpr.into()).expect("Failed to handle MOV from CR")
+        }
+        Privileged::Lgdt { address, operand_32bit } | Privileged::Lidt { address, operand_32bit } => {
+            let mut descriptor = [0; 6];
+            read_guest(|index| address + index, &mut descriptor)?;
+            let limit = u64::from(u16::from_le_bytes([descriptor[0], descriptor[1]]));
+            let base = u64::from(u32::from_le_bytes([descriptor[2], descriptor[3], descriptor[4], descriptor[5]]));
+            let base = if operand_32bit { base } else { base & 0xFF_FFFF };
+
+            if matches!(decoded.instruction, Privileged::Lgdt { .. }) {
+                vmwrite(vmcs::guest::GDTR_BASE, base);
+                vmwrite(vmcs::guest::GDTR_LIMIT, limit);
+            } else {
+                vm.set_guest_idtr(base, limit);
+            }
+            ExitType::IncrementRIP
+        }
+        Privileged::Lmsw(operand) => {
+            let source = match operand {
+                Operand::Register(index) => registers.gpr(index.into()) as u16,
+                Operand::Memory(address) => {
+                    let mut word = [0; 2];
+                    read_guest(|index| address + index, &mut word)?;
+                    u16::from_le_bytes(word)
+                }
+            };
+            handle_lmsw(vm, source)
+        }
+    };
+
+    if exit_type == ExitType::IncrementRIP {
+        let rip = (ip + decoded.length) & 0xFFFF;
+        vm.guest_registers.rip = rip;
+        vmwrite(vmcs::guest::RIP, rip);
+    }
+
+    Some(ExitType::Continue)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// BX 0x1000, BP 0x2000, SI 0x10, DI 0x20, the others are their own number.
+    fn gpr(index: u8) -> u64 {
+        match index {
+            3 => 0x1000,
+            5 => 0x2000,
+            6 => 0x10,
+            7 => 0x20,
+            _ => u64::from(index),
+        }
+    }
+
+    /// The base of every segment register is its number times 0x10000.
+    fn segment_base(index: u8) -> u64 {
+        u64::from(index) << 16
+    }
+
+    fn decoded(bytes: &[u8]) -> Option<Decoded> {
+        decode(bytes, gpr, segment_base)
+    }
+
+    #[test]
+    fn simple_instructions_decode() {
+        assert_eq!(
+            decoded(&[0xF4]),
+            Some(Decoded {
+                instruction: Privileged::Hlt,
+                length: 1
+            })
+        );
+        assert_eq!(
+            decoded(&[0x0F, 0x06]),
+            Some(Decoded {
+                instruction: Privileged::Clts,
+                length: 2
+            })
+        );
+        assert_eq!(
+            decoded(&[0x0F, 0x09]),
+            Some(Decoded {
+                instruction: Privileged::Wbinvd,
+                length: 2
+            })
+        );
+        assert_eq!(
+            decoded(&[0x66, 0x0F, 0x32]),
+            Some(Decoded {
+                instruction: Privileged::Rdmsr,
+                length: 3
+            })
+        );
+        assert_eq!(
+            decoded(&[0x0F, 0x30]),
+            Some(Decoded {
+                instruction: Privileged::Wrmsr,
+                length: 2
+            })
+        );
+
+        // mov cr0, eax and mov eax, cr4, the mod field ignored
+        assert_eq!(
+            decoded(&[0x0F, 0x22, 0xC0]),
+            Some(Decoded {
+                instruction: Privileged::MovToCr { cr: 0, gpr: 0 },
+                length: 3
+            })
+        );
+        assert_eq!(
+            decoded(&[0x0F, 0x20, 0x21]),
+            Some(Decoded {
+                instruction: Privileged::MovFromCr { cr: 4, gpr: 1 },
+                length: 3
+            })
+        );
+
+        // Other instructions, MOV to CR2 and truncated ones aren't emulated.
+        assert_eq!(decoded(&[0xFA]), None);
+        assert_eq!(decoded(&[0x0F, 0x22, 0xD0]), None);
+        assert_eq!(decoded(&[0x0F, 0x01]), None);
+        assert_eq!(decoded(&[0x66; 15]), None);
+    }
+
+    #[test]
+    fn memory_operands_use_16bit_addressing() {
+        // lgdt [bx+si+0x10]: DS:0x1020
+        assert_eq!(
+            decoded(&[0x0F, 0x01, 0x50, 0x10]),
+            Some(Decoded {
+                instruction: Privileged::Lgdt {
+                    address: 0x3_1020,
+                    operand_32bit: false
+                },
+                length: 4
+            })
+        );
+
+        // o32 lidt cs:[0x7f00]
+        assert_eq!(
+            decoded(&[0x2E, 0x66, 0x0F, 0x01, 0x1E, 0x00, 0x7F]),
+            Some(Decoded {
+                instruction: Privileged::Lidt {
+                    address: 0x1_7F00,
+                    operand_32bit: true
+                },
+                length: 7
+            })
+        );
+
+        // lgdt [bp-2]: SS:0x1FFE, and offsets wrap at 64 KiB
+        assert_eq!(
+            decoded(&[0x0F, 0x01, 0x56, 0xFE]).map(|decoded| decoded.instruction),
+            Some(Privileged::Lgdt {
+                address: 0x2_1FFE,
+                operand_32bit: false
+            })
+        );
+        assert_eq!(
+            decoded(&[0x0F, 0x01, 0x97, 0x00, 0xF0]).map(|decoded| decoded.instruction),
+            Some(Privileged::Lgdt {
+                address: 0x3_0000,
+                operand_32bit: false
+            })
+        );
+
+        // lmsw ax and lmsw [di]
+        assert_eq!(decoded(&[0x0F, 0x01, 0xF0]).map(|decoded| decoded.instruction), Some(Privileged::Lmsw(Operand::Register(0))));
+        assert_eq!(decoded(&[0x0F, 0x01, 0x35]).map(|decoded| decoded.instruction), Some(Privileged::Lmsw(Operand::Memory(0x3_0020))));
+
+        // LGDT of a register is invalid.
+        assert_eq!(decoded(&[0x0F, 0x01, 0xD0]), None);
+    }
+
+    #[test]
+    fn memory_operands_use_32bit_addressing() {
+        // a32 lgdt [0x00007f00]
+        assert_eq!(
+            decoded(&[0x67, 0x0F, 0x01, 0x15, 0x00, 0x7F, 0x00, 0x00]),
+            Some(Decoded {
+                instruction: Privileged::Lgdt {
+                    address: 0x3_7F00,
+                    operand_32bit: false
+                },
+                length: 8
+            })
+        );
+
+        // a32 lidt [ebp+esi*4+8]: SS:0x2048
+        assert_eq!(
+            decoded(&[0x67, 0x0F, 0x01, 0x5C, 0xB5, 0x08]),
+            Some(Decoded {
+                instruction: Privileged::Lidt {
+                    address: 0x2_2048,
+                    operand_32bit: false
+                },
+                length: 6
+            })
+        );
+
+        // a32 lidt fs:[esi*2+0x100]: no base
+        assert_eq!(
+            decoded(&[0x64, 0x67, 0x0F, 0x01, 0x1C, 0x75, 0x00, 0x01, 0x00, 0x00]).map(|decoded| decoded.instruction),
+            Some(Privileged::Lidt {
+                address: 0x4_0120,
+                operand_32bit: false
+            })
+        );
+    }
+
+    #[test]
+    fn events_push_flags_cs_and_ip() {
+        let (frame, sp) = real_mode_frame(0x3202, 0x9A00, 0x0123, 0x1000);
+        assert_eq!(frame, [0x23, 0x01, 0x00, 0x9A, 0x02, 0x02]);
+        assert_eq!(sp, 0x0FFA);
+
+        // SP wraps at 64 KiB.
+        assert_eq!(real_mode_frame(0x2, 0, 0, 0x4).1, 0xFFFE);
+    }
+}
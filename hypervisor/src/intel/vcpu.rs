@@ -0,0 +1,169 @@
+//! Per-processor state of the hypervisor, indexed by initial APIC ID.
+//!
+//! Every processor marks itself as starting before it captures the registers the guest resumes with, so
+//! the guest side finds out whether the hypervisor took the processor or gave up on it. The entries are
+//! atomics, a processor only ever writes its own entry and any processor may read all of them.
+//!
+//! The initial APIC ID of CPUID leaf 1 has 8 bits, which covers every processor outside of x2APIC
+//! systems with more than 256 of them.
+
+use {
+    crate::intel::vm::Vm,
+    core::{
+        ptr,
+        sync::atomic::{AtomicPtr, AtomicU32, Ordering},
+    },
+    shared::hvstatus::{CPU_STATE_STARTING, MAX_PROCESSORS},
+    x86::cpuid::CpuId,
+};
+
+/// The state of one processor.
+pub struct Vcpu {
+    /// `shared::hvstatus::CPU_STATE_*`, `0` before the processor started.
+    state: AtomicU32,
+
+    /// The VM of the processor once its VMCS is active, null before.
+    vm: AtomicPtr<Vm>,
+
+    /// The EPTP index of the EPT view the guest ran with at the latest VM exit, `0` for the primary EPT.
+    ept_view: AtomicU32,
+
+    /// The number of EPT views of the processor, the primary EPT included, `0` before its VM was built.
+    ept_views: AtomicU32,
+
+    /// The NMIs of the guest waiting to be injected, see `nmi`.
+    pending_nmis: AtomicU32,
+}
+
+/// The processors by initial APIC ID.
+static VCPUS: [Vcpu; MAX_PROCESSORS] = [const {
+    Vcpu {
+        state: AtomicU32::new(0),
+        vm: AtomicPtr::new(ptr::null_mut()),
+        ept_view: AtomicU32::new(0),
+        ept_views: AtomicU32::new(0),
+        pending_nmis: AtomicU32::new(0),
+    }
+}; MAX_PROCESSORS];
+
+/// The APIC IDs in the order the processors started.
+static START_ORDER: [AtomicU32; MAX_PROCESSORS] = [const { AtomicU32::new(0) }; MAX_PROCESSORS];
+
+/// The number of processors that started.
+static STARTED: AtomicU32 = AtomicU32::new(0);
+
+impl Vcpu {
+    /// Returns the `CPU_STATE_*` of the processor.
+    pub fn state(&self) -> u32 {
+        self.state.load(Ordering::Relaxed)
+    }
+
+    /// Replaces the `CPU_STATE_*` of the processor.
+    pub fn set_state(&self, state: u32) {
+        self.state.store(state, Ordering::Relaxed);
+    }
+
+    /// Returns the VM of the processor, null until its VMCS is active.
+    pub fn vm(&self) -> *mut Vm {
+        self.vm.load(Ordering::Acquire)
+    }
+
+    /// Records the VM of the processor, which lives on its host stack for as long as it is virtualized.
+    pub fn set_vm(&self, vm: *mut Vm) {
+        self.vm.store(vm, Ordering::Release);
+    }
+
+    /// Returns the EPTP index of the EPT view the guest ran with at the latest VM exit.
+    pub fn ept_view(&self) -> u32 {
+        self.ept_view.load(Ordering::Relaxed)
+    }
+
+    /// Records the EPT view the guest runs with.
+    pub fn set_ept_view(&self, index: u32) {
+        self.ept_view.store(index, Ordering::Relaxed);
+    }
+
+    /// Returns the number of EPT views of the processor, the primary EPT included.
+    pub fn ept_views(&self) -> u32 {
+        self.ept_views.load(Ordering::Relaxed)
+    }
+
+    /// Records the number of EPT views of the processor once its VM built them.
+    pub fn set_ept_views(&self, count: u32) {
+        self.ept_views.store(count, Ordering::Relaxed);
+    }
+
+    /// Queues an NMI of the guest, `false` if `limit` NMIs are pending already.
+    pub fn queue_nmi(&self, limit: u32) -> bool {
+        self.pending_nmis
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |pending| (pending < limit).then_some(pending + 1))
+            .is_ok()
+    }
+
+    /// Returns whether an NMI of the guest is waiting to be injected.
+    pub fn has_pending_nmi(&self) -> bool {
+        self.pending_nmis.load(Ordering::Acquire) != 0
+    }
+
+    /// Takes a pending NMI of the guest, `false` if none is pending.
+    pub fn take_pending_nmi(&self) -> bool {
+        self.pending_nmis
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |pending| pending.checked_sub(1))
+            .is_ok()
+    }
+}
+
+/// Returns the initial APIC ID of the current processor.
+pub fn current_apic_id() -> u32 {
+    CpuId::new().get_feature_info().map_or(0, |info| u32::from(info.initial_local_apic_id()))
+}
+
+/// Returns the entry of the current processor.
+pub fn current() -> Option<&'static Vcpu> {
+    get(current_apic_id())
+}
+
+/// Returns the entry of the processor with the initial APIC ID `apic_id`.
+pub fn get(apic_id: u32) -> Option<&'static Vcpu> {
+    VCPUS.get(apic_id as usize)
+}
+
+/// Marks the current processor as starting to enter VMX operation.
+///
+/// # Returns
+///
+/// The entry of the processor, `None` if its APIC ID is out of range.
+pub fn start() -> Option<&'static Vcpu> {
+    let apic_id = current_apic_id();
+    let vcpu = get(apic_id)?;
+    vcpu.set_state(CPU_STATE_STARTING);
+
+    let index = STARTED.fetch_add(1, Ordering::Relaxed) as usize;
+    if let Some(slot) = START_ORDER.get(index) {
+        slot.store(apic_id, Ordering::Relaxed);
+    }
+    Some(vcpu)
+}
+
+/// Returns the number of processors that started.
+pub fn started() -> usize {
+    (STARTED.load(Ordering::Relaxed) as usize).min(MAX_PROCESSORS)
+}
+
+/// Returns the APIC ID and the entry of the `index`-th processor to start, `None` past the last one.
+pub fn by_start_order(index: usize) -> Option<(u32, &'static Vcpu)> {
+    if index >= started() {
+        return None;
+    }
+
+    let apic_id = START_ORDER[index].load(Ordering::Relaxed);
+    Some((apic_id, get(apic_id)?))
+}
+
+/// Returns how many of the processors that started are in `state`.
+pub fn count(state: u32) -> usize {
+    (0..started())
+        .filter_map(by_start_order)
+        .filter(|(_, vcpu)| vcpu.state() == state)
+        .count()
+}
@@ -0,0 +1,365 @@
+//! Switches the views of EPT hooks in the guest with virtualization exceptions (#VE) and VMFUNC.
+//!
+//! Every switch between the execute and the read-write view of a hooked page costs an EPT violation VM exit.
+//! With the "EPT-violation #VE" control, violations of entries whose suppress-#VE bit is clear are delivered
+//! to the guest as #VE instead, and with EPTP switching the guest changes the EPT with VMFUNC on its own.
+//!
+//! `Ept` suppresses #VE for every entry but the views of hooked pages. The primary EPT (EPTP index 0) maps
+//! hooked pages read-write, the execute view of `ept_views` (index 1) maps their shadow pages execute-only
+//! and shares everything else with the primary EPT. A handler of a
+//! few instructions in the guest switches to the other EPT and returns. A data access from code on the same
+//! page can't be served by either view, the handler leaves the information area busy and returns, so the
+//! retried access exits to the hypervisor, which single-steps it like without #VE.
+//!
+//! The hypervisor has no way to map pages into the guest on its own. `VE_HOOK_SWAPS` selects the mode at
+//! setup where the processor supports both controls, but #VE are only delivered once `Vm::install_ve_handler`
+//! learned where the guest mapped the information area and the handler. Until then, and on processors or
+//! boots without the mode, the views are switched in VM exits.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.5.7 Virtualization Exceptions
+
+use {
+    crate::{
+        config,
+        error::HypervisorError,
+        intel::{
+            addresses::PhysicalAddress,
+            host_arch::interrupt_gate,
+            shootdown::{self, Scope},
+            support::{rdmsr, vmread, vmwrite},
+            vcpu,
+            vm::Vm,
+        },
+        stats,
+    },
+    core::ptr,
+    log::*,
+    shared::{
+        features::HvFeatureFlags,
+        hvstatus::{HOOK_SWAP_VIRTUALIZATION_EXCEPTION, HOOK_SWAP_VM_EXIT},
+    },
+    x86::{
+        bits64::paging::BASE_PAGE_SIZE,
+        msr,
+        vmx::vmcs::{self, control::SecondaryControls},
+    },
+};
+
+/// The EPTP index of the primary EPT, which maps hooked pages read-write.
+pub const PRIMARY_VIEW: u16 = 0;
+
+/// The EPTP index of the execute view, which maps the shadow pages of hooked pages execute-only.
+pub const EXECUTE_VIEW: u16 = 1;
+
+/// The vector virtualization exceptions are delivered through.
+const VE_VECTOR: u64 = 20;
+
+/// [Bit 0] IA32_VMX_VMFUNC: VM function 0, EPTP switching.
+const VMFUNC_EPTP_SWITCHING: u64 = 1 << 0;
+
+/// The offset of the immediate holding the guest address of the information area in `handler_code`.
+const HANDLER_INFORMATION_OFFSET: usize = 5;
+
+/// The size of the code `handler_code` builds.
+pub const HANDLER_SIZE: usize = 57;
+
+/// The controls #VE offloading takes.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: A.3.3 Secondary Processor-Based VM-Execution Controls
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct VeSupport {
+    /// The "EPT-violation #VE" control can be set, bit 18 of the secondary controls.
+    pub ept_violation_ve: bool,
+
+    /// The "enable VM functions" control can be set and VM function 0 is EPTP switching.
+    pub eptp_switching: bool,
+}
+
+impl VeSupport {
+    /// Decodes the capability MSRs.
+    ///
+    /// # Arguments
+    ///
+    /// * `procbased_ctls2` - IA32_VMX_PROCBASED_CTLS2, whose upper half holds the allowed 1-settings.
+    /// * `vmfunc` - IA32_VMX_VMFUNC, `0` where it doesn't exist.
+    pub fn from_capabilities(procbased_ctls2: u64, vmfunc: u64) -> Self {
+        let allowed1 = procbased_ctls2 >> 32;
+        Self {
+            ept_violation_ve: allowed1 & SecondaryControls::EPT_VIOLATION_VE.bits() as u64 != 0,
+            eptp_switching: allowed1 & SecondaryControls::ENABLE_VM_FUNCTIONS.bits() as u64 != 0 && vmfunc & VMFUNC_EPTP_SWITCHING != 0,
+        }
+    }
+
+    /// Reads the capabilities of the current processor, IA32_VMX_VMFUNC only exists with the VM functions control.
+    pub fn read() -> Self {
+        let procbased_ctls2 = rdmsr(msr::IA32_VMX_PROCBASED_CTLS2);
+        let vmfunc_allowed = (procbased_ctls2 >> 32) & SecondaryControls::ENABLE_VM_FUNCTIONS.bits() as u64 != 0;
+        let vmfunc = if vmfunc_allowed { rdmsr(msr::IA32_VMX_VMFUNC) } else { 0 };
+        Self::from_capabilities(procbased_ctls2, vmfunc)
+    }
+
+    /// Returns whether the guest can take over the switches.
+    pub fn usable(&self) -> bool {
+        self.ept_violation_ve && self.eptp_switching
+    }
+}
+
+/// How the views of EPT hooks are switched.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum HookSwapMode {
+    /// In EPT violation VM exits, on every processor.
+    #[default]
+    VmExit,
+
+    /// By the guest #VE handler once it is installed, in VM exits before.
+    VirtualizationException,
+}
+
+impl HookSwapMode {
+    /// Selects the mode of a processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `support` - The controls the processor supports.
+    /// * `features` - The features of this boot, `VE_HOOK_SWAPS` asks for #VE.
+    pub fn select(support: VeSupport, features: HvFeatureFlags) -> Self {
+        if features.contains(HvFeatureFlags::VE_HOOK_SWAPS) && support.usable() {
+            Self::VirtualizationException
+        } else {
+            Self::VmExit
+        }
+    }
+
+    /// Returns the `HOOK_SWAP_*` the status protocol reports the mode as.
+    pub fn status(self) -> u32 {
+        match self {
+            Self::VmExit => HOOK_SWAP_VM_EXIT,
+            Self::VirtualizationException => HOOK_SWAP_VIRTUALIZATION_EXCEPTION,
+        }
+    }
+}
+
+/// The virtualization-exception information area the processor writes before delivering a #VE.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 26-1. Format of the Virtualization-Exception Information Area
+#[repr(C, align(4096))]
+#[derive(Debug, Clone, Copy)]
+pub struct VeInformation {
+    /// The basic exit reason an EPT violation VM exit would have, 48.
+    pub exit_reason: u32,
+
+    /// Set to `0xFFFF_FFFF` on delivery, violations exit instead of causing another #VE until it is cleared.
+    pub busy: u32,
+
+    /// The exit qualification of an EPT violation VM exit.
+    pub exit_qualification: u64,
+
+    /// The guest-linear address of the access.
+    pub guest_linear_address: u64,
+
+    /// The guest-physical address of the access.
+    pub guest_physical_address: u64,
+
+    /// The EPTP index of the EPT the access violated.
+    pub eptp_index: u16,
+}
+
+/// The pages of a processor taking part in #VE offloading, the guest maps both.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct VePages {
+    /// The information area of the processor, given to the VMCS.
+    pub information: VeInformation,
+
+    /// The guest handler of the processor, built by `handler_code`.
+    pub handler: [u8; BASE_PAGE_SIZE],
+}
+
+/// Builds the #VE handler of one processor.
+///
+/// It compares the page of the access with the page of the interrupted instruction. A different page means
+/// an instruction fetch or a data access the other view serves, so it clears `busy` and switches the EPT with
+/// VMFUNC. The same page is left to the hypervisor. In both cases it returns with IRETQ, retrying the access.
+///
+/// # Arguments
+///
+/// * `information_va` - The guest address of the information area of the processor.
+pub fn handler_code(information_va: u64) -> [u8; HANDLER_SIZE] {
+    #[rustfmt::skip]
+    let mut code = [
+        0x50,                                           // push rax
+        0x51,                                           // push rcx
+        0x52,                                           // push rdx
+        0x48, 0xBA, 0, 0, 0, 0, 0, 0, 0, 0,             // mov rdx, information_va
+        0x48, 0x8B, 0x42, 0x10,                         // mov rax, [rdx + guest_linear_address]
+        0x48, 0x33, 0x44, 0x24, 0x18,                   // xor rax, [rsp + 0x18], the interrupted RIP
+        0x48, 0xC1, 0xE8, 0x0C,                         // shr rax, 12
+        0x74, 0x18,                                     // jz same_page
+        0x0F, 0xB7, 0x4A, 0x20,                         // movzx ecx, word [rdx + eptp_index]
+        0x83, 0xF1, 0x01,                               // xor ecx, 1
+        0xC7, 0x42, 0x04, 0x00, 0x00, 0x00, 0x00,       // mov dword [rdx + busy], 0
+        0x31, 0xC0,                                     // xor eax, eax
+        0x0F, 0x01, 0xD4,                               // vmfunc
+        0x5A, 0x59, 0x58,                               // pop rdx, pop rcx, pop rax
+        0x48, 0xCF,                                     // iretq
+        0x5A, 0x59, 0x58,                               // same_page: pop rdx, pop rcx, pop rax
+        0x48, 0xCF,                                     // iretq
+    ];
+
+    code[HANDLER_INFORMATION_OFFSET..HANDLER_INFORMATION_OFFSET + 8].copy_from_slice(&information_va.to_le_bytes());
+    code
+}
+
+impl Vm {
+    /// Selects how this processor switches the views of hooks and builds the execute view for #VE.
+    pub fn init_hook_swaps(&mut self) -> Result<(), HypervisorError> {
+        self.hook_swap_mode = HookSwapMode::select(VeSupport::read(), config::features());
+        self.ve_handler_installed = false;
+        stats::record_hook_swap_mode(self.hook_swap_mode.status());
+
+        if self.hook_swap_mode == HookSwapMode::VirtualizationException {
+            trace!("Building the Execute View");
+            self.ept_views.init(&self.primary_ept, self.primary_eptp, EXECUTE_VIEW as usize)?;
+            self.ve_pages.init();
+        }
+
+        if let Some(vcpu) = vcpu::current() {
+            vcpu.set_ept_views(self.ept_views.view_count() as u32);
+        }
+
+        Ok(())
+    }
+
+    /// Enables EPTP switching in the current VMCS, #VE stay off until the guest handler is installed.
+    pub fn setup_hook_swap_controls(&self) {
+        let Some(eptp_list_pa) = self.ept_views.eptp_list_pa() else {
+            return;
+        };
+
+        let secondary = vmread(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS) | SecondaryControls::ENABLE_VM_FUNCTIONS.bits() as u64;
+        vmwrite(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS, secondary);
+        vmwrite(vmcs::control::VM_FUNCTION_CONTROLS_FULL, VMFUNC_EPTP_SWITCHING);
+        vmwrite(vmcs::control::EPTP_LIST_ADDR_FULL, eptp_list_pa);
+        vmwrite(vmcs::control::EPTP_INDEX, PRIMARY_VIEW as u64);
+        vmwrite(vmcs::control::VIRT_EXCEPTION_INFO_ADDR_FULL, self.ve_pages.physical_address());
+    }
+
+    /// Returns whether the guest switches the views of hooks on this processor.
+    pub fn ve_active(&self) -> bool {
+        self.ve_handler_installed
+    }
+
+    /// Installs the #VE handler in the guest and starts delivering #VE on this processor.
+    ///
+    /// The guest must have mapped both pages of `ve_pages`, the information area and the handler,
+    /// to consecutive addresses of the current address space. The #VE vector of the guest IDT is pointed at the
+    /// handler, PatchGuard checks the IDT, so this only suits guests without it. Hooked pages currently in
+    /// their execute view are switched back to the read-write one first, the primary EPT must never map the
+    /// execute view once the guest switches on its own. Locks the hook manager.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_va` - The guest address the information area is mapped at, the handler follows it.
+    pub fn install_ve_handler(&mut self, guest_va: u64) -> Result<(), HypervisorError> {
+        if self.hook_swap_mode != HookSwapMode::VirtualizationException {
+            return Err(HypervisorError::VirtualizationExceptionsUnavailable);
+        }
+
+        let pages_pa = self.ve_pages.physical_address();
+        let mapped = PhysicalAddress::pa_from_va_with_current_cr3(guest_va).ok() == Some(pages_pa)
+            && PhysicalAddress::pa_from_va_with_current_cr3(guest_va + BASE_PAGE_SIZE as u64).ok() == Some(pages_pa + BASE_PAGE_SIZE as u64);
+        let selector = vmread(vmcs::guest::CS_SELECTOR) as u16;
+        if !mapped || selector & 0b11 != 0 {
+            return Err(HypervisorError::VeHandlerNotMapped);
+        }
+
+        let idt_base = vmread(vmcs::guest::IDTR_BASE);
+        if vmread(vmcs::guest::IDTR_LIMIT) < (VE_VECTOR + 1) * 16 - 1 {
+            return Err(HypervisorError::VeHandlerNotMapped);
+        }
+        let gate_pa = PhysicalAddress::pa_from_va_with_current_cr3(idt_base + VE_VECTOR * 16)?;
+
+        self.ve_pages.handler[..HANDLER_SIZE].copy_from_slice(&handler_code(guest_va));
+        self.ve_pages.information.busy = 0;
+
+        // The gate is aligned to 16 bytes and never crosses a page.
+        unsafe { ptr::write_volatile(gate_pa as *mut [u64; 2], interrupt_gate(guest_va + BASE_PAGE_SIZE as u64, selector, 0)) };
+
+        for (guest_page_pa, views) in self.applied_hooks.iter() {
+            self.primary_ept.set_page_entry(guest_page_pa, views.read_write)?;
+        }
+
+        let secondary = vmread(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS) | SecondaryControls::EPT_VIOLATION_VE.bits() as u64;
+        vmwrite(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS, secondary);
+
+        shootdown::flush_ept(Scope::CurrentProcessor);
+
+        self.ve_handler_installed = true;
+        stats::record_ve_processor();
+        debug!("Installed the #VE handler at {:#x}", guest_va + BASE_PAGE_SIZE as u64);
+
+        Ok(())
+    }
+
+    /// Returns the guest to the primary EPT and re-arms #VE, before an exit handler looks at or changes the views.
+    ///
+    /// The handler leaves the information area busy for the accesses it hands to the hypervisor, and the EPT
+    /// violations of those exit with the EPT of whichever view the guest was in.
+    pub fn enter_primary_view(&mut self) -> Result<(), HypervisorError> {
+        if !self.ve_active() {
+            return Ok(());
+        }
+
+        if vmread(vmcs::control::EPTP_INDEX) as u16 != PRIMARY_VIEW {
+            self.switch_view(PRIMARY_VIEW)?;
+        }
+
+        // Safety: the information area is read by the processor and the guest concurrently.
+        unsafe { ptr::write_volatile(&mut self.ve_pages.information.busy, 0) };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, core::mem::offset_of};
+
+    const ALLOWED: u64 = ((SecondaryControls::EPT_VIOLATION_VE.bits() | SecondaryControls::ENABLE_VM_FUNCTIONS.bits()) as u64) << 32;
+
+    #[test]
+    fn both_controls_are_required() {
+        assert!(VeSupport::from_capabilities(ALLOWED, VMFUNC_EPTP_SWITCHING).usable());
+        assert!(!VeSupport::from_capabilities(ALLOWED, 0).usable());
+        assert!(!VeSupport::from_capabilities(ALLOWED >> 32, VMFUNC_EPTP_SWITCHING).usable());
+
+        let ve_only = VeSupport::from_capabilities((SecondaryControls::EPT_VIOLATION_VE.bits() as u64) << 32, VMFUNC_EPTP_SWITCHING);
+        assert!(ve_only.ept_violation_ve && !ve_only.eptp_switching);
+    }
+
+    #[test]
+    fn the_mode_takes_the_feature_and_the_controls() {
+        let usable = VeSupport::from_capabilities(ALLOWED, VMFUNC_EPTP_SWITCHING);
+        assert_eq!(HookSwapMode::select(usable, HvFeatureFlags::VE_HOOK_SWAPS), HookSwapMode::VirtualizationException);
+        assert_eq!(HookSwapMode::select(usable, HvFeatureFlags::DEFAULT), HookSwapMode::VmExit);
+        assert_eq!(HookSwapMode::select(VeSupport::default(), HvFeatureFlags::VE_HOOK_SWAPS), HookSwapMode::VmExit);
+        assert_eq!(HookSwapMode::default().status(), HOOK_SWAP_VM_EXIT);
+    }
+
+    #[test]
+    fn the_handler_matches_the_information_area() {
+        assert_eq!(offset_of!(VeInformation, busy), 4);
+        assert_eq!(offset_of!(VeInformation, guest_linear_address), 0x10);
+        assert_eq!(offset_of!(VeInformation, eptp_index), 0x20);
+        assert_eq!(offset_of!(VePages, handler), BASE_PAGE_SIZE);
+        assert_eq!(core::mem::size_of::<VePages>(), 2 * BASE_PAGE_SIZE);
+
+        let code = handler_code(0xFFFF_8000_1234_5000);
+        assert_eq!(code[HANDLER_INFORMATION_OFFSET..HANDLER_INFORMATION_OFFSET + 8], 0xFFFF_8000_1234_5000u64.to_le_bytes());
+
+        // The jump skips the switch, both paths restore the registers and return.
+        let jz = code.iter().position(|&byte| byte == 0x74).unwrap();
+        let same_page = jz + 2 + code[jz + 1] as usize;
+        assert_eq!(code[same_page..], [0x5A, 0x59, 0x58, 0x48, 0xCF]);
+        assert_eq!(code[same_page - 5..same_page], [0x5A, 0x59, 0x58, 0x48, 0xCF]);
+    }
+}
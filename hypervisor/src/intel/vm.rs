@@ -7,24 +7,40 @@
 
 use {
     crate::{
+        config,
         error::HypervisorError,
         intel::{
             capture::GuestRegisters,
+            debug_regs::DebugState,
+            entry_failure,
             ept::Ept,
-            hooks::{descriptor_manager::SHARED_DESCRIPTOR_MANAGER, hook_manager::SHARED_HOOK_MANAGER},
+            ept_views::EptViewManager,
+            events::EventInjector,
+            hooks::{descriptor_manager::SHARED_DESCRIPTOR_MANAGER, hook_manager::SHARED_HOOK_MANAGER, hook_sync::AppliedHooks},
+            host_arch::HostArch,
+            invvpid::{vpid_for, InvvpidSupport},
+            mode_watch::{ModeWatch, RealModePages},
+            page_walk::TranslationCache,
             paging::PageTables,
-            support::{vmclear, vmptrld, vmread, vmxon},
+            regions::ContiguousPage,
+            support::{rdtsc, vmclear, vmptrld, vmread, vmwrite, vmxon},
+            tsc::TscCompensation,
+            vcpu,
+            ve::{HookSwapMode, VePages},
             vmcs::Vmcs,
             vmerror::{VmInstructionError, VmxBasicExitReason},
+            vmexit::mtf::SingleStep,
             vmlaunch::launch_vm,
             vmxon::Vmxon,
+            xstate::XState,
         },
     },
     core::mem::MaybeUninit,
     log::*,
+    shared::features::HvFeatureFlags,
     x86::{
         bits64::rflags::RFlags,
-        cpuid::{cpuid, CpuId, FeatureInfo},
+        cpuid::{CpuId, FeatureInfo},
         vmx::vmcs,
     },
 };
@@ -36,16 +52,23 @@ use {
 /// and the state of guest registers. Additionally, it tracks whether the VM has been launched.
 ///
 /// # Size
-/// - Total size in bytes: 4,204,969 bytes (0x4010B9)
-/// - Total size in pages: 1027 pages (0x403)
+/// - Total size in bytes: 4,329,472 bytes (0x421000)
+/// - Total size in pages: 1057 pages (0x421)
 pub struct Vm {
     /// The VMXON (Virtual Machine Extensions On) region for the VM.
     /// - Aligned to 4096 bytes (0x1000)
-    pub vmxon_region: Vmxon,
+    /// - Size: 4096 bytes (0x1000)
+    pub vmxon_region: ContiguousPage<Vmxon>,
 
     /// The VMCS (Virtual Machine Control Structure) for the VM.
     /// - Aligned to 4096 bytes (0x1000)
-    pub vmcs_region: Vmcs,
+    /// - Size: 4096 bytes (0x1000)
+    pub vmcs_region: ContiguousPage<Vmcs>,
+
+    /// The GDT, TSS and IDT the host runs on, with the interrupt stacks of #DF and NMIs.
+    /// - Aligned to 4096 bytes (0x1000)
+    /// - Size: 32768 bytes (0x8000)
+    pub host_arch: HostArch,
 
     /// Paging tables for the host.
     /// - Pml4: 4096 bytes (0x1000)
@@ -58,14 +81,34 @@ pub struct Vm {
     /// - Pml4: 4096 bytes (0x1000)
     /// - Pdpt: 4096 bytes (0x1000)
     /// - Pd: 512 * 4096 bytes (0x200000)
-    /// - Pt: 4096 bytes (0x1000)
-    /// - Total: 4096 + 4096 + (512 * 4096) + 4096 = 2,100,224 bytes (0x201000)
+    /// - Pt: 8 * 4096 bytes (0x8000)
+    /// - High Pdpt: 4 * 4096 bytes (0x4000)
+    /// - Total: 4096 + 4096 + (512 * 4096) + (8 * 4096) + (4 * 4096) = 2,146,304 bytes (0x20C000)
     pub primary_ept: Ept,
 
     /// The primary EPTP (Extended Page Tables Pointer) for the VM.
     /// - Size: 8 bytes (0x8)
     pub primary_eptp: u64,
 
+    /// The EPT views besides the primary EPT, like the execute view the guest #VE handler switches to with VMFUNC.
+    /// Only built if `hook_swap_mode` is `HookSwapMode::VirtualizationException`, the tables live on the heap.
+    /// - Size: 40 bytes (0x28)
+    pub ept_views: EptViewManager,
+
+    /// The EPT hooks of `SHARED_HOOK_MANAGER` applied to the EPTs of the VM, see `hook_sync`.
+    /// - Size: 40 bytes (0x28)
+    pub applied_hooks: AppliedHooks,
+
+    /// The #VE information area and the guest #VE handler of the VM.
+    /// - Aligned to 4096 bytes (0x1000)
+    /// - Size: 8192 bytes (0x2000)
+    pub ve_pages: ContiguousPage<VePages>,
+
+    /// The identity map and the TSS a guest without "unrestricted guest" runs with until it enables paging.
+    /// - Aligned to 4096 bytes (0x1000)
+    /// - Size: 16384 bytes (0x4000)
+    pub real_mode_pages: ContiguousPage<RealModePages>,
+
     /// State of guest general-purpose registers.
     /// - Size: 400 bytes (0x190)
     pub guest_registers: GuestRegisters,
@@ -74,20 +117,50 @@ pub struct Vm {
     /// - Size: 1 byte (0x1)
     pub has_launched: bool,
 
-    /// The old RFLAGS value before turning off the interrupt flag.
-    /// Used for restoring the RFLAGS register after handling the Monitor Trap Flag (MTF) VM exit.
-    /// - Size: 8 bytes (Option<u64>) (0x8)
-    pub old_rflags: Option<u64>,
+    /// The single-step in progress with the MTF (Monitor Trap Flag), see `Vm::single_step`.
+    /// - Size: 32 bytes (Option<SingleStep>) (0x20)
+    pub single_step: Option<SingleStep>,
+
+    /// The events waiting to be injected into the guest, flushed right before every VM entry.
+    /// - Size: 84 bytes (pending event and 4 waiting ones) (0x54)
+    pub events: EventInjector,
 
-    /// The number of times the MTF (Monitor Trap Flag) should be triggered before disabling it for restoring overwritten instructions.
-    /// - Size: 8 bytes (Option<u64>) (0x8)
-    pub mtf_counter: Option<u64>,
+    /// The debug registers of the guest, and of the host while the guest runs.
+    /// - Size: 88 bytes (0x58)
+    pub debug_regs: DebugState,
 
     /// The CPUID feature information for the VM.
     pub cpuid_feature_info: FeatureInfo,
 
-    /// The CPUID extended feature information for the VM.
-    pub xcr0_unsupported_mask: u64,
+    /// The XCR0 of the guest, and of the host when it doesn't share it.
+    /// - Size: 16 bytes (0x10)
+    pub xstate: XState,
+
+    /// The TSC offset hiding the time spent in the hypervisor from the guest.
+    pub tsc: TscCompensation,
+
+    /// The VPID tagging the translations of the guest, `0` if the processor doesn't support VPIDs.
+    pub vpid: u16,
+
+    /// The initial APIC ID of the processor running the VM, which finds its shootdown mailbox.
+    /// - Size: 4 bytes (0x4)
+    pub apic_id: u32,
+
+    /// How the views of EPT hooks are switched, selected at setup.
+    /// - Size: 1 byte (0x1)
+    pub hook_swap_mode: HookSwapMode,
+
+    /// Flag indicating if the guest #VE handler was installed and #VE are delivered.
+    /// - Size: 1 byte (0x1)
+    pub ve_handler_installed: bool,
+
+    /// The mode the guest runs in without "unrestricted guest", see `mode_watch`.
+    /// - Size: 96 bytes (0x60)
+    pub mode_watch: ModeWatch,
+
+    /// The recent guest page walks of the hypervisor, see `page_walk`.
+    /// - Size: 336 bytes (0x150)
+    pub translations: TranslationCache,
 }
 
 impl Vm {
@@ -113,10 +186,13 @@ impl Vm {
         trace!("Creating VM");
 
         trace!("Initializing VMXON region");
-        self.vmxon_region.init();
+        self.vmxon_region.init_with_revision_id();
 
         trace!("Initializing VMCS region");
-        self.vmcs_region.init();
+        self.vmcs_region.init_with_revision_id();
+
+        trace!("Building Host Descriptor Tables");
+        self.host_arch.init();
 
         trace!("Initializing Host Paging Tables");
         self.host_paging.init();
@@ -133,20 +209,51 @@ impl Vm {
         trace!("Creating primary EPTP with WB and 4-level walk");
         self.primary_eptp = self.primary_ept.create_eptp_with_wb_and_4lvl_walk()?;
 
+        trace!("Initializing Applied Hooks");
+        self.applied_hooks = AppliedHooks::new();
+
+        trace!("Selecting How Hook Views Are Switched");
+        self.init_hook_swaps()?;
+
         trace!("Initializing Guest Registers");
         self.guest_registers = guest_registers.clone();
 
         trace!("Initializing Launch State");
         self.has_launched = false;
 
-        trace!("Initializing Old RFLAGS and MTF Counter");
-        self.old_rflags = None;
-        self.mtf_counter = None;
+        trace!("Initializing Single-Step State");
+        self.single_step = None;
+
+        trace!("Initializing Event Injector");
+        self.events = EventInjector::new();
 
-        trace!("Getting and Setting CPUID Feature Information and XCR0 Unsupported Mask");
-        let cpuid_ext_state_info = cpuid!(0x0d, 0x00);
+        trace!("Initializing Debug Registers");
+        self.debug_regs = DebugState::capture();
+
+        trace!("Getting and Setting CPUID Feature Information");
         self.cpuid_feature_info = CpuId::new().get_feature_info().ok_or(HypervisorError::CPUUnsupported)?;
-        self.xcr0_unsupported_mask = !((cpuid_ext_state_info.edx as u64) << 32 | cpuid_ext_state_info.eax as u64);
+
+        trace!("Capturing XCR0");
+        self.xstate = XState::capture();
+
+        trace!("Assigning VPID");
+        self.apic_id = vcpu::current_apic_id();
+        self.vpid = if InvvpidSupport::read().vpid_usable() {
+            vpid_for(self.apic_id)
+        } else {
+            0
+        };
+
+        trace!("Initializing TSC Compensation");
+        self.tsc = TscCompensation::new(config::has_feature(HvFeatureFlags::RDTSC_COMPENSATION));
+
+        trace!("Building Real-Mode Pages");
+        self.real_mode_pages.init();
+        self.real_mode_pages.build();
+        self.mode_watch = ModeWatch::new(self.real_mode_pages.physical_address());
+
+        trace!("Initializing Translation Cache");
+        self.translations = TranslationCache::new(config::has_feature(HvFeatureFlags::TRANSLATION_CACHE));
 
         trace!("VM created");
 
@@ -168,7 +275,7 @@ impl Vm {
         trace!("VMXON region setup successfully!");
 
         trace!("Executing VMXON instruction");
-        vmxon(&self.vmxon_region as *const _ as _);
+        vmxon(self.vmxon_region.physical_address());
         trace!("VMXON executed successfully!");
 
         Ok(())
@@ -214,11 +321,11 @@ impl Vm {
     pub fn activate_vmcs(&mut self) -> Result<(), HypervisorError> {
         trace!("Activating VMCS");
         // Clear the VMCS region.
-        vmclear(&self.vmcs_region as *const _ as _);
+        vmclear(self.vmcs_region.physical_address());
         trace!("VMCLEAR successful!");
 
         // Load current VMCS pointer.
-        vmptrld(&self.vmcs_region as *const _ as _);
+        vmptrld(self.vmcs_region.physical_address());
         trace!("VMPTRLD successful!");
 
         self.setup_vmcs()?;
@@ -241,19 +348,19 @@ impl Vm {
         // Lock the shared hook manager
         let hook_manager = SHARED_HOOK_MANAGER.lock();
 
-        let msr_bitmap = &hook_manager.msr_bitmap as *const _ as u64;
+        let msr_bitmap = hook_manager.msr_bitmap.physical_address();
 
         // Lock the descriptor manager
         let descriptor_manager = SHARED_DESCRIPTOR_MANAGER.lock();
 
         let guest_descriptors = &descriptor_manager.guest_descriptor;
-        let host_descriptors = &descriptor_manager.host_descriptor;
 
         let pml4_pa = self.host_paging.get_pml4_pa()?;
 
         Vmcs::setup_guest_registers_state(guest_descriptors, &self.guest_registers);
-        Vmcs::setup_host_registers_state(&host_descriptors, pml4_pa)?;
-        Vmcs::setup_vmcs_control_fields(primary_eptp, msr_bitmap)?;
+        Vmcs::setup_host_registers_state(&self.host_arch, pml4_pa)?;
+        Vmcs::setup_vmcs_control_fields(primary_eptp, msr_bitmap, self.vpid)?;
+        self.setup_hook_swap_controls();
 
         trace!("VMCS setup successfully!");
 
@@ -270,10 +377,26 @@ impl Vm {
     /// Returns `Ok(VmxBasicExitReason)` indicating the reason for the VM-exit, or an `Err(HypervisorError)`
     /// if the VM fails to launch or an unknown exit reason is encountered.
     pub fn run(&mut self) -> Result<VmxBasicExitReason, HypervisorError> {
+        // Hide the time spent handling the previous VM exit, as late as possible before the entry.
+        if let Some(offset) = self.tsc.entering(rdtsc(), config::tsc_skew_limit()) {
+            vmwrite(vmcs::control::TSC_OFFSET_FULL, offset as u64);
+        }
+
         // Run the VM until the VM-exit occurs.
         let flags = unsafe { launch_vm(&mut self.guest_registers, u64::from(self.has_launched)) };
+        let exit_tsc = rdtsc();
         Self::vm_succeed(RFlags::from_raw(flags))?;
+
+        // Bit 31 of the exit reason is set if the processor rejected the guest state after the entry
+        // instruction itself succeeded, the guest never ran and the VMCS is still not launched.
+        let exit_reason = vmread(vmcs::ro::EXIT_REASON) as u32;
+        if exit_reason & (1 << 31) != 0 {
+            entry_failure::report(VmxBasicExitReason::from_u32(exit_reason).ok_or(exit_reason));
+            return Err(HypervisorError::VmEntryFailed);
+        }
+
         self.has_launched = true;
+        self.tsc.exited(exit_tsc);
         // trace!("VM-exit occurred!");
 
         // VM-exit occurred. Copy the guest register values from VMCS so that
@@ -282,8 +405,6 @@ impl Vm {
         self.guest_registers.rsp = vmread(vmcs::guest::RSP);
         self.guest_registers.rflags = vmread(vmcs::guest::RFLAGS);
 
-        let exit_reason = vmread(vmcs::ro::EXIT_REASON) as u32;
-
         let Some(basic_exit_reason) = VmxBasicExitReason::from_u32(exit_reason) else {
             error!("Unknown exit reason: {:#x}", exit_reason);
             return Err(HypervisorError::UnknownVMExitReason);
@@ -292,6 +413,23 @@ impl Vm {
         return Ok(basic_exit_reason);
     }
 
+    /// Writes a general-purpose register of the guest by the number exit qualifications use, and the
+    /// guest-state area for RSP, which VM entries load from there.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The number of the register, `0` for RAX to `15` for R15.
+    /// * `value` - The new value of the register.
+    pub fn write_guest_gpr(&mut self, index: u64, value: u64) {
+        /// The number of RSP.
+        const RSP: u64 = 4;
+
+        self.guest_registers.set_gpr(index, value);
+        if index & 0xF == RSP {
+            vmwrite(vmcs::guest::RSP, value);
+        }
+    }
+
     /// Verifies that the `launch_vm` function executed successfully.
     ///
     /// This method checks the RFlags for indications of failure from the `launch_vm` function.
@@ -310,6 +448,9 @@ impl Vm {
             return match VmInstructionError::from_u32(instruction_error) {
                 Some(error) => {
                     error!("VM instruction error: {:?}", error);
+                    if matches!(error, VmInstructionError::VmEntryInvalidControlFields | VmInstructionError::VmEntryInvalidHostState) {
+                        entry_failure::report(error);
+                    }
                     Err(HypervisorError::VmInstructionError)
                 }
                 None => {
@@ -6,19 +6,22 @@
 
 use {
     crate::{
+        config,
         error::HypervisorError,
         intel::{
             capture::GuestRegisters,
             controls::{adjust_vmx_controls, VmxControl},
+            cr_shadow,
             descriptor::Descriptors,
+            host_arch::{HostArch, HOST_CS, HOST_TR},
             invept::invept_single_context,
-            invvpid::{invvpid_single_context, VPID_TAG},
+            invvpid::{self, TlbScope},
             segmentation::{access_rights_from_native, lar, lsl},
             support::{cr3, rdmsr, sidt, vmread, vmwrite},
         },
     },
-    bit_field::BitField,
     core::fmt,
+    shared::features::HvFeatureFlags,
     x86::{
         bits64::{paging::BASE_PAGE_SIZE, rflags},
         debugregs::dr7,
@@ -26,7 +29,7 @@ use {
         segmentation::{cs, ds, es, fs, gs, ss},
         vmx::vmcs,
     },
-    x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+    x86_64::registers::control::{Cr0, Cr4, Cr4Flags},
 };
 
 /// Represents the VMCS region in memory.
@@ -44,12 +47,6 @@ pub struct Vmcs {
 }
 
 impl Vmcs {
-    /// Initializes the VMCS region.
-    pub fn init(&mut self) {
-        self.revision_id = rdmsr(msr::IA32_VMX_BASIC) as u32;
-        self.revision_id.set_bit(31, false);
-    }
-
     /// Initialize the guest state for the currently loaded VMCS.
     ///
     /// The method sets up various guest state fields in the VMCS as per the
@@ -68,6 +65,7 @@ impl Vmcs {
         vmwrite(vmcs::guest::CR4, Cr4::read_raw());
 
         vmwrite(vmcs::guest::DR7, unsafe { dr7().0 as u64 });
+        vmwrite(vmcs::guest::IA32_EFER_FULL, rdmsr(msr::IA32_EFER));
 
         vmwrite(vmcs::guest::RSP, guest_registers.rsp);
         vmwrite(vmcs::guest::RIP, guest_registers.rip);
@@ -121,21 +119,24 @@ impl Vmcs {
     /// Intel® 64 and IA-32 Architectures Software Developer's Manual 25.5 HOST-STATE AREA.
     ///
     /// # Arguments
-    /// * `host_descriptor` - Descriptor tables for the host.
-    /// * `host_paging` - Paging tables for the host.
-    pub fn setup_host_registers_state(host_descriptor: &Descriptors, pml4_pa: u64) -> Result<(), HypervisorError> {
+    /// * `host_arch` - Descriptor tables for the host.
+    /// * `pml4_pa` - The physical address of the PML4 of the host.
+    pub fn setup_host_registers_state(host_arch: &HostArch, pml4_pa: u64) -> Result<(), HypervisorError> {
         log::debug!("Setting up Host Registers State");
 
         vmwrite(vmcs::host::CR0, Cr0::read_raw());
         vmwrite(vmcs::host::CR3, pml4_pa);
         vmwrite(vmcs::host::CR4, Cr4::read_raw());
 
-        vmwrite(vmcs::host::CS_SELECTOR, host_descriptor.cs.bits());
-        vmwrite(vmcs::host::TR_SELECTOR, host_descriptor.tr.bits());
+        // The guest's IA32_EFER is switched at VM entries and exits, see `intel::efer`.
+        vmwrite(vmcs::host::IA32_EFER_FULL, rdmsr(msr::IA32_EFER));
+
+        vmwrite(vmcs::host::CS_SELECTOR, HOST_CS);
+        vmwrite(vmcs::host::TR_SELECTOR, HOST_TR);
 
-        vmwrite(vmcs::host::TR_BASE, host_descriptor.tss.base);
-        vmwrite(vmcs::host::GDTR_BASE, host_descriptor.gdtr.base as u64);
-        vmwrite(vmcs::host::IDTR_BASE, host_descriptor.idtr.base as u64);
+        vmwrite(vmcs::host::TR_BASE, host_arch.tss_base());
+        vmwrite(vmcs::host::GDTR_BASE, host_arch.gdt_base());
+        vmwrite(vmcs::host::IDTR_BASE, host_arch.idt_base());
 
         log::debug!("Host Registers State setup successfully!");
 
@@ -154,60 +155,84 @@ impl Vmcs {
     ///
     /// * `primary_eptp` - The EPTP value for the primary EPT.
     /// * `msr_bitmap` - The physical address of the MSR bitmap.
+    /// * `vpid` - The VPID of the processor, `0` to leave VPIDs disabled.
     ///
     /// # Returns
     ///
     /// * `Result<(), HypervisorError>` - A result indicating the success or failure of the operation.
-    pub fn setup_vmcs_control_fields(primary_eptp: u64, msr_bitmap: u64) -> Result<(), HypervisorError> {
+    pub fn setup_vmcs_control_fields(primary_eptp: u64, msr_bitmap: u64, vpid: u16) -> Result<(), HypervisorError> {
         log::debug!("Setting up VMCS Control Fields");
 
-        const PRIMARY_CTL: u64 =
-            (vmcs::control::PrimaryControls::SECONDARY_CONTROLS.bits() | vmcs::control::PrimaryControls::USE_MSR_BITMAPS.bits()) as u64;
+        // MOV DR exits, so the guest's debug registers are served from `intel::debug_regs`.
+        const PRIMARY_CTL: u64 = (vmcs::control::PrimaryControls::SECONDARY_CONTROLS.bits()
+            | vmcs::control::PrimaryControls::USE_MSR_BITMAPS.bits()
+            | vmcs::control::PrimaryControls::MOV_DR_EXITING.bits()) as u64;
         const SECONDARY_CTL: u64 = (vmcs::control::SecondaryControls::ENABLE_RDTSCP.bits()
             | vmcs::control::SecondaryControls::ENABLE_XSAVES_XRSTORS.bits()
             | vmcs::control::SecondaryControls::ENABLE_INVPCID.bits()
-            | vmcs::control::SecondaryControls::ENABLE_VPID.bits()
             | vmcs::control::SecondaryControls::ENABLE_EPT.bits()
             | vmcs::control::SecondaryControls::CONCEAL_VMX_FROM_PT.bits()
             | vmcs::control::SecondaryControls::UNRESTRICTED_GUEST.bits()) as u64;
         const ENTRY_CTL: u64 = (vmcs::control::EntryControls::IA32E_MODE_GUEST.bits()
             | vmcs::control::EntryControls::LOAD_DEBUG_CONTROLS.bits()
+            | vmcs::control::EntryControls::LOAD_IA32_EFER.bits()
             | vmcs::control::EntryControls::CONCEAL_VMX_FROM_PT.bits()) as u64;
         const EXIT_CTL: u64 = (vmcs::control::ExitControls::HOST_ADDRESS_SPACE_SIZE.bits()
             | vmcs::control::ExitControls::SAVE_DEBUG_CONTROLS.bits()
+            | vmcs::control::ExitControls::SAVE_IA32_EFER.bits()
+            | vmcs::control::ExitControls::LOAD_IA32_EFER.bits()
             | vmcs::control::ExitControls::CONCEAL_VMX_FROM_PT.bits()) as u64;
-        const PINBASED_CTL: u64 = 0;
+        // NMIs exit, so `shootdown` can kick processors out of the guest, and the guest's are delivered by `nmi`.
+        const PINBASED_CTL: u64 = (vmcs::control::PinbasedControls::NMI_EXITING.bits() | vmcs::control::PinbasedControls::VIRTUAL_NMIS.bits()) as u64;
+
+        // RDTSC compensation works by offsetting the counter, see `intel::tsc`.
+        let tsc_offsetting = if config::has_feature(HvFeatureFlags::RDTSC_COMPENSATION) {
+            vmcs::control::PrimaryControls::USE_TSC_OFFSETTING.bits() as u64
+        } else {
+            0
+        };
+        // The translation cache drops walks when the guest invalidates its TLB, see `vmexit::invlpg`.
+        let invalidation_exiting = if config::has_feature(HvFeatureFlags::TRANSLATION_CACHE) {
+            (vmcs::control::PrimaryControls::INVLPG_EXITING.bits() | vmcs::control::PrimaryControls::CR3_LOAD_EXITING.bits()) as u64
+        } else {
+            0
+        };
+        // HLT runs natively unless the guest's idle time is measured, see `vmexit::halt`.
+        let hlt_exiting = if config::has_feature(HvFeatureFlags::HLT_EXITING) {
+            vmcs::control::PrimaryControls::HLT_EXITING.bits() as u64
+        } else {
+            0
+        };
 
-        vmwrite(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS, adjust_vmx_controls(VmxControl::ProcessorBased, PRIMARY_CTL));
-        vmwrite(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS, adjust_vmx_controls(VmxControl::ProcessorBased2, SECONDARY_CTL));
+        vmwrite(
+            vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS,
+            adjust_vmx_controls(VmxControl::ProcessorBased, PRIMARY_CTL | tsc_offsetting | hlt_exiting | invalidation_exiting),
+        );
+        // Without VPIDs every VM entry and exit flushes the TLBs, see `intel::invvpid`.
+        let enable_vpid = if vpid != 0 {
+            vmcs::control::SecondaryControls::ENABLE_VPID.bits() as u64
+        } else {
+            0
+        };
+
+        vmwrite(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS, adjust_vmx_controls(VmxControl::ProcessorBased2, SECONDARY_CTL | enable_vpid));
         vmwrite(vmcs::control::VMENTRY_CONTROLS, adjust_vmx_controls(VmxControl::VmEntry, ENTRY_CTL));
         vmwrite(vmcs::control::VMEXIT_CONTROLS, adjust_vmx_controls(VmxControl::VmExit, EXIT_CTL));
         vmwrite(vmcs::control::PINBASED_EXEC_CONTROLS, adjust_vmx_controls(VmxControl::PinBased, PINBASED_CTL));
 
-        let vmx_cr0_fixed0 = unsafe { msr::rdmsr(msr::IA32_VMX_CR0_FIXED0) };
-        let vmx_cr0_fixed1 = unsafe { msr::rdmsr(msr::IA32_VMX_CR0_FIXED1) };
-
-        let vmx_cr4_fixed0 = unsafe { msr::rdmsr(msr::IA32_VMX_CR4_FIXED0) };
-        let vmx_cr4_fixed1 = unsafe { msr::rdmsr(msr::IA32_VMX_CR4_FIXED1) };
-
-        // Credits to @vmctx
-        vmwrite(
-            vmcs::control::CR0_GUEST_HOST_MASK,
-            vmx_cr0_fixed0 | !vmx_cr0_fixed1 | Cr0Flags::CACHE_DISABLE.bits() | Cr0Flags::WRITE_PROTECT.bits(),
-        );
-        vmwrite(vmcs::control::CR4_GUEST_HOST_MASK, vmx_cr4_fixed0 | !vmx_cr4_fixed1);
-
-        vmwrite(vmcs::control::CR0_READ_SHADOW, Cr0::read_raw());
-        vmwrite(vmcs::control::CR4_READ_SHADOW, Cr4::read_raw() & !Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS.bits());
+        cr_shadow::setup(Cr0::read_raw(), Cr4::read_raw() & !Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS.bits());
 
         vmwrite(vmcs::control::MSR_BITMAPS_ADDR_FULL, msr_bitmap);
+        vmwrite(vmcs::control::TSC_OFFSET_FULL, 0u64);
         //vmwrite(vmcs::control::EXCEPTION_BITMAP, 1u64 << (ExceptionInterrupt::Breakpoint as u32));
 
         vmwrite(vmcs::control::EPTP_FULL, primary_eptp);
-        vmwrite(vmcs::control::VPID, VPID_TAG);
+        if vpid != 0 {
+            vmwrite(vmcs::control::VPID, vpid);
+        }
 
         invept_single_context(primary_eptp);
-        invvpid_single_context(VPID_TAG);
+        invvpid::flush(vpid, TlbScope::Context);
 
         log::debug!("VMCS Control Fields setup successfully!");
 
@@ -374,28 +374,48 @@ impl core::fmt::Display for VmInstructionError {
 ///
 /// This struct interprets the exit qualification for Control-Register Accesses as described in
 /// Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 28-3. Exit Qualification for Control-Register Accesses
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct ControlRegAccessExitQualification {
+    /// [Bits 3:0] The number of the control register.
     pub control_reg: CrAccessReg,
+
+    /// [Bits 5:4] The instruction that exited.
     pub access_type: CrAccessType,
+
+    /// [Bit 6] The operand type of LMSW.
     pub lmsw_op_type: LmswOperandType,
+
+    /// [Bits 11:8] The general-purpose register of MOV CR, in the order of `GuestRegisters`.
     pub gpr_mov_cr: u64,
-    // 31:16 not implemented for now
+
+    /// [Bits 31:16] The source operand of LMSW.
+    pub lmsw_source_data: u16,
 }
 
 impl ControlRegAccessExitQualification {
+    /// The fields of the exit qualification as first bit and width, in the order of the struct.
+    pub const FIELDS: [(usize, usize); 5] = [(0, 4), (4, 2), (6, 1), (8, 4), (16, 16)];
+
     /// Constructs an `ControlRegAccessExitQualification` from the raw 64-bit exit qualification value.
-    pub fn from_exit_qualification(value: u64) -> Self {
-        ControlRegAccessExitQualification {
-            control_reg: CrAccessReg::from_u64(value.get_bits(0..4)).unwrap(),
-            access_type: CrAccessType::from_u64(value.get_bits(4..6)).unwrap(),
-            lmsw_op_type: LmswOperandType::from_u64(value.get_bit(6) as u64).unwrap(),
-            gpr_mov_cr: value.get_bits(8..12),
-        }
+    ///
+    /// # Returns
+    ///
+    /// `None` if bits 3:0 name a control register that doesn't exit.
+    pub fn from_exit_qualification(value: u64) -> Option<Self> {
+        let [control_reg, access_type, lmsw_op_type, gpr_mov_cr, lmsw_source_data] =
+            Self::FIELDS.map(|(bit, width)| value.get_bits(bit..bit + width));
+
+        Some(ControlRegAccessExitQualification {
+            control_reg: CrAccessReg::from_u64(control_reg)?,
+            access_type: CrAccessType::from_u64(access_type)?,
+            lmsw_op_type: LmswOperandType::from_u64(lmsw_op_type)?,
+            gpr_mov_cr,
+            lmsw_source_data: lmsw_source_data as u16,
+        })
     }
 }
 
-#[derive(FromPrimitive, Clone, Copy, Debug)]
+#[derive(FromPrimitive, Clone, Copy, Debug, PartialEq, Eq)]
 pub enum CrAccessReg {
     Cr0 = 0,
     Cr2 = 2,
@@ -404,7 +424,7 @@ pub enum CrAccessReg {
     Cr8 = 8,
 }
 
-#[derive(FromPrimitive, Clone, Copy, Debug)]
+#[derive(FromPrimitive, Clone, Copy, Debug, PartialEq, Eq)]
 pub enum CrAccessType {
     MovToCr = 0,
     MovFromCr = 1,
@@ -412,12 +432,54 @@ pub enum CrAccessType {
     Lmsw = 3,
 }
 
-#[derive(FromPrimitive, Clone, Copy, Debug)]
+#[derive(FromPrimitive, Clone, Copy, Debug, PartialEq, Eq)]
 pub enum LmswOperandType {
     Register = 0,
     Memory = 1,
 }
 
+/// Represents the exit qualification for MOV DR.
+///
+/// This struct interprets the exit qualification for MOV DR as described in
+/// Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 28-4. Exit Qualification for MOV DR
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DebugRegAccessExitQualification {
+    /// [Bits 2:0] The number of the debug register.
+    pub debug_reg: u8,
+
+    /// [Bit 4] The direction of the access.
+    pub direction: DrAccessDirection,
+
+    /// [Bits 11:8] The general-purpose register, in the order of `GuestRegisters`.
+    pub gpr_mov_dr: u64,
+}
+
+impl DebugRegAccessExitQualification {
+    /// The fields of the exit qualification as first bit and width, in the order of the struct.
+    pub const FIELDS: [(usize, usize); 3] = [(0, 3), (4, 1), (8, 4)];
+
+    /// Constructs a `DebugRegAccessExitQualification` from the raw 64-bit exit qualification value.
+    pub fn from_exit_qualification(value: u64) -> Self {
+        let [debug_reg, direction, gpr_mov_dr] = Self::FIELDS.map(|(bit, width)| value.get_bits(bit..bit + width));
+
+        DebugRegAccessExitQualification {
+            debug_reg: debug_reg as u8,
+            direction: if direction == 0 {
+                DrAccessDirection::MovToDr
+            } else {
+                DrAccessDirection::MovFromDr
+            },
+            gpr_mov_dr,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DrAccessDirection {
+    MovToDr = 0,
+    MovFromDr = 1,
+}
+
 /// Represents the exit qualification for EPT Violations.
 ///
 /// This struct interprets the exit qualification for EPT Violations as described in
@@ -633,6 +695,7 @@ impl InterruptionType {
             0 => Some(Self::ExternalInterrupt),
             2 => Some(Self::NonMaskableInterrupt),
             3 => Some(Self::HardwareException),
+            4 => Some(Self::SoftwareInterrupt),
             5 => Some(Self::PrivilegedSoftwareException),
             6 => Some(Self::SoftwareException),
             _ => None, // Return None if the bits do not correspond to a known interruption type.
@@ -673,3 +736,97 @@ impl VmExitInterruptionInformation {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_register_accesses_decode_the_sdm_encodings() {
+        use {CrAccessReg::*, CrAccessType::*, LmswOperandType::*};
+
+        // Qualification, control register, access type, LMSW operand type, GPR, LMSW source data.
+        let table = [
+            // mov cr0, rbx
+            (0x0000_0000_0000_0300, Cr0, MovToCr, Register, 3, 0),
+            // mov rax, cr3
+            (0x0000_0000_0000_0013, Cr3, MovFromCr, Register, 0, 0),
+            // mov cr4, r15
+            (0x0000_0000_0000_0F04, Cr4, MovToCr, Register, 15, 0),
+            // mov cr8, rsp
+            (0x0000_0000_0000_0408, Cr8, MovToCr, Register, 4, 0),
+            // mov r9, cr8
+            (0x0000_0000_0000_0918, Cr8, MovFromCr, Register, 9, 0),
+            // clts
+            (0x0000_0000_0000_0020, Cr0, Clts, Register, 0, 0),
+            // lmsw ax, with ax = 0x0031
+            (0x0000_0000_0031_0030, Cr0, Lmsw, Register, 0, 0x31),
+            // lmsw [rcx], a memory operand holding 0xFFFF
+            (0x0000_0000_FFFF_0070, Cr0, Lmsw, Memory, 0, 0xFFFF),
+        ];
+
+        for (qualification, control_reg, access_type, lmsw_op_type, gpr_mov_cr, lmsw_source_data) in table {
+            assert_eq!(
+                ControlRegAccessExitQualification::from_exit_qualification(qualification),
+                Some(ControlRegAccessExitQualification {
+                    control_reg,
+                    access_type,
+                    lmsw_op_type,
+                    gpr_mov_cr,
+                    lmsw_source_data,
+                }),
+                "{:#x}",
+                qualification
+            );
+        }
+
+        // CR1 and the others don't exist, the reserved bits 7 and 15:12 are ignored.
+        assert_eq!(ControlRegAccessExitQualification::from_exit_qualification(0x01), None);
+        assert_eq!(ControlRegAccessExitQualification::from_exit_qualification(0x0F), None);
+        assert_eq!(ControlRegAccessExitQualification::from_exit_qualification(0xF080).map(|cr| cr.gpr_mov_cr), Some(0));
+    }
+
+    #[test]
+    fn debug_register_accesses_decode_the_sdm_encodings() {
+        use DrAccessDirection::*;
+
+        // Qualification, debug register, direction, GPR.
+        let table = [
+            // mov dr7, rax
+            (0x0000_0000_0000_0007, 7, MovToDr, 0),
+            // mov rcx, dr6
+            (0x0000_0000_0000_0116, 6, MovFromDr, 1),
+            // mov dr0, r12
+            (0x0000_0000_0000_0C00, 0, MovToDr, 12),
+            // mov rsp, dr3
+            (0x0000_0000_0000_0413, 3, MovFromDr, 4),
+            // mov dr5, r15, reserved bits 3 and 7:5 set
+            (0x0000_0000_0000_0FED, 5, MovToDr, 15),
+        ];
+
+        for (qualification, debug_reg, direction, gpr_mov_dr) in table {
+            assert_eq!(
+                DebugRegAccessExitQualification::from_exit_qualification(qualification),
+                DebugRegAccessExitQualification {
+                    debug_reg,
+                    direction,
+                    gpr_mov_dr
+                },
+                "{:#x}",
+                qualification
+            );
+        }
+    }
+
+    #[test]
+    fn control_register_fields_tile_the_low_half() {
+        let mut covered = 0u64;
+        for (bit, width) in ControlRegAccessExitQualification::FIELDS {
+            let field = ((1u64 << width) - 1) << bit;
+            assert_eq!(covered & field, 0);
+            covered |= field;
+        }
+        // Bits 7 and 15:12 are reserved.
+        assert_eq!(covered, 0xFFFF_0F7F);
+    }
+}
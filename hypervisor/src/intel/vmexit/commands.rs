@@ -1,17 +1,24 @@
 use {
     crate::{
+        config,
         intel::{
             addresses::PhysicalAddress,
             hooks::{
                 hook_manager::{EptHookType, SHARED_HOOK_MANAGER},
                 inline::InlineHookType,
             },
+            vcpu,
             vm::Vm,
         },
+        stats,
         windows::eprocess::ProcessInformation,
     },
     log::{debug, error},
-    shared::{ClientCommand, ClientDataPayload, Command, HookData, ProcessMemoryOperation},
+    shared::{
+        features::HvFeatureFlags,
+        hypercall::{EptViewReport, HypercallRequest, StatsReport},
+        ClientDataPayload, Command, HookData, ProcessMemoryOperation,
+    },
 };
 
 /// Handles guest commands sent to the hypervisor.
@@ -29,9 +36,12 @@ use {
 pub fn handle_guest_commands(vm: &mut Vm) -> Option<()> {
     debug!("Handling commands");
 
-    // Convert guest RCX register value to a physical address pointer to `ClientCommand`.
-    let client_command_ptr = PhysicalAddress::pa_from_va_with_current_cr3(vm.guest_registers.rcx).ok()?;
-    let client_command = ClientCommand::from_ptr(client_command_ptr);
+    // Convert guest RCX register value to a physical address pointer to `HypercallRequest`.
+    let request_ptr = PhysicalAddress::pa_from_va_with_current_cr3(vm.guest_registers.rcx).ok()?;
+    let Some(client_command) = HypercallRequest::from_ptr(request_ptr).decode() else {
+        error!("Unknown command code in hypercall request.");
+        return None;
+    };
 
     // Match the command and handle accordingly
     match client_command.command {
@@ -59,8 +69,27 @@ pub fn handle_guest_commands(vm: &mut Vm) -> Option<()> {
                 None
             }
         }
+        Command::QueryEptViews => {
+            if let ClientDataPayload::Memory(memory) = client_command.payload {
+                handle_query_ept_views(vm, memory)
+            } else {
+                error!("Expected Memory for QueryEptViews command.");
+                None
+            }
+        }
+        Command::QueryStats => {
+            if let ClientDataPayload::Memory(memory) = client_command.payload {
+                handle_query_stats(vm, memory)
+            } else {
+                error!("Expected Memory for QueryStats command.");
+                None
+            }
+        }
         Command::EnableKernelEptHook | Command::DisableKernelEptHook => {
-            if let ClientDataPayload::Hook(hook) = client_command.payload {
+            if !config::has_feature(HvFeatureFlags::EPT_HOOKS) {
+                error!("EPT hooks are disabled for this boot.");
+                None
+            } else if let ClientDataPayload::Hook(hook) = client_command.payload {
                 handle_hook_command(vm, client_command.command, hook)
             } else {
                 error!("Expected HookData for hook command.");
@@ -149,6 +178,59 @@ fn handle_write_memory(_vm: &mut Vm, memory: ProcessMemoryOperation) -> Option<(
     Some(())
 }
 
+/// Handles the `QueryEptViews` command.
+///
+/// This function writes one `EptViewReport` per processor that started to the buffer provided by the user mode
+/// client, in the order the processors started, as many as fit. The views of other processors are those of
+/// their latest VM exit.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `memory` - The `ProcessMemoryOperation` holding the buffer to fill.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the reports were written successfully, or `None` if an error occurred.
+fn handle_query_ept_views(_vm: &mut Vm, memory: ProcessMemoryOperation) -> Option<()> {
+    let capacity = memory.buffer_size as usize / size_of::<EptViewReport>();
+    debug!("Reporting the EPT views of up to {} processors", capacity);
+
+    let reports = memory.buffer as *mut EptViewReport;
+    for (index, (apic_id, vcpu)) in (0..vcpu::started().min(capacity)).filter_map(vcpu::by_start_order).enumerate() {
+        let report = EptViewReport {
+            apic_id,
+            state: vcpu.state(),
+            view: vcpu.ept_view(),
+            views: vcpu.ept_views(),
+        };
+        PhysicalAddress::write_guest_virt_with_current_cr3(reports.wrapping_add(index), report)?;
+    }
+
+    Some(())
+}
+
+/// Handles the `QueryStats` command.
+///
+/// This function writes one `StatsReport` to the buffer provided by the user mode client.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `memory` - The `ProcessMemoryOperation` holding the buffer to fill.
+///
+/// # Returns
+///
+/// * `Option<()>` - Returns `Some(())` if the report was written successfully, or `None` if an error occurred.
+fn handle_query_stats(_vm: &mut Vm, memory: ProcessMemoryOperation) -> Option<()> {
+    if (memory.buffer_size as usize) < size_of::<StatsReport>() {
+        error!("The buffer of QueryStats is too small: {:#x}", memory.buffer_size);
+        return None;
+    }
+
+    PhysicalAddress::write_guest_virt_with_current_cr3(memory.buffer as *mut StatsReport, stats::stats_report())
+}
+
 /// Handles commands related to enabling or disabling kernel EPT hooks.
 ///
 /// This function manages the setup or removal of kernel EPT hooks based on the provided command.
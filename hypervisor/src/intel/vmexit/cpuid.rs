@@ -3,15 +3,18 @@
 
 use {
     crate::{
+        config,
         error::HypervisorError,
         intel::{
+            cpuid_policy::CPUID_POLICY,
             vm::Vm,
             vmexit::{commands::handle_guest_commands, ExitType},
+            xstate,
         },
+        stats,
     },
-    bitfield::BitMut,
     log::*,
-    shared::CommandStatus,
+    shared::{hypercall::HypercallResponse, CommandStatus, PASSWORD, PRESENCE_LEAF, PRESENCE_SIGNATURE},
     x86::cpuid::cpuid,
 };
 
@@ -32,6 +35,9 @@ pub enum CpuidLeaf {
     /// CPUID function for extended feature information.
     ExtendedFeatureInformation = 0x7,
 
+    /// CPUID function for processor extended state enumeration.
+    ExtendedStateEnumeration = 0xD,
+
     /// Hypervisor vendor information leaf.
     HypervisorVendor = 0x40000000,
 
@@ -60,25 +66,12 @@ pub enum CpuidLeaf {
     HypervisorNestedVirtualizationFeatures = 0x4000000A,
 }
 
-/// Enumerates specific feature bits in the ECX register for CPUID instruction results.
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-#[allow(dead_code)]
-enum FeatureBits {
-    /// Bit 5 of ECX for CPUID with EAX=1, indicating VMX support.
-    HypervisorVmxSupportBit = 5,
-    /// Bit 31 of ECX for CPUID with EAX=1, indicating hypervisor presence.
-    HypervisorPresentBit = 31,
-}
-
-/// The password used for authentication with the hypervisor.
-const PASSWORD: u64 = 0xDEADBEEF;
-
 /// Handles the `CPUID` VM-exit.
 ///
 /// This function is invoked when the guest executes the `CPUID` instruction.
 /// The handler retrieves the results of the `CPUID` instruction executed on
-/// the host and then modifies or masks certain bits, if necessary, before
-/// returning the results to the guest.
+/// the host and then applies the action `CPUID_POLICY` has for the leaf, if any,
+/// before returning the results to the guest.
 ///
 /// # Arguments
 ///
@@ -97,17 +90,22 @@ pub fn handle_cpuid(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
     let leaf = vm.guest_registers.rax as u32;
     let sub_leaf = vm.guest_registers.rcx as u32;
 
-    if vm.guest_registers.rax == PASSWORD {
+    if vm.guest_registers.rax == config::vmcall_key() {
+        stats::record_hypercall();
+
         // Handle the guest command and update the CPUID result accordingly
-        vm.guest_registers.rax = match handle_guest_commands(vm) {
-            Some(_) => CommandStatus::Success.to_u64(), // Command handled successfully
-            None => CommandStatus::Failure.to_u64(),    // Command handling failed
+        let response = match handle_guest_commands(vm) {
+            Some(_) => HypercallResponse::new(CommandStatus::Success, 0), // Command handled successfully
+            None => HypercallResponse::new(CommandStatus::Failure, 0),    // Command handling failed
         };
+        vm.guest_registers.rax = response.status;
+        vm.guest_registers.rbx = response.value;
 
         trace!("Command executed successfully with leaf {:#x}", leaf);
     } else {
         // Execute CPUID instruction on the host and retrieve the result
         let mut cpuid_result = cpuid!(leaf, sub_leaf);
+        let mut presence_query = false;
         trace!("CpuidLeaf: {:#x}", leaf);
 
         match leaf {
@@ -116,12 +114,6 @@ pub fn handle_cpuid(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
             }
             leaf if leaf == CpuidLeaf::FeatureInformation as u32 => {
                 trace!("CPUID leaf 1 detected (Standard Feature Information).");
-
-                // Hide hypervisor presence by setting the appropriate bit in ECX.
-                cpuid_result.ecx.set_bit(FeatureBits::HypervisorPresentBit as usize, false);
-
-                // Hide VMX support by setting the appropriate bit in ECX.
-                // cpuid_result.ecx.set_bit(FeatureBits::HypervisorVmxSupportBit as usize, false);
             }
             leaf if leaf == CpuidLeaf::CacheInformation as u32 => {
                 trace!("CPUID leaf 0x2 detected (Cache Information).");
@@ -168,27 +160,39 @@ pub fn handle_cpuid(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
             leaf if leaf == CpuidLeaf::ExtendedFeatureInformation as u32 => {
                 trace!("CPUID leaf 0x7 detected (Extended Feature Information).");
             }
+            leaf if leaf == CpuidLeaf::ExtendedStateEnumeration as u32 => {
+                trace!("CPUID leaf 0xD detected (Processor Extended State Enumeration).");
+            }
+            leaf if leaf == PRESENCE_LEAF && sub_leaf == PASSWORD as u32 => {
+                trace!("CPUID presence leaf queried with password, reporting Illusion signature.");
+                cpuid_result.ebx = PRESENCE_SIGNATURE[0];
+                cpuid_result.ecx = PRESENCE_SIGNATURE[1];
+                cpuid_result.edx = PRESENCE_SIGNATURE[2];
+                presence_query = true;
+            }
             leaf if leaf == CpuidLeaf::HypervisorVendor as u32 => {
                 trace!("CPUID leaf 0x40000000 detected (Hypervisor Vendor Information).");
-                // Set the CPUID response to provide the hypervisor's vendor ID signature.
-                // We use the signature "Illusion" encoded in a little-endian format.
-                // cpuid_result.eax = CpuidLeaf::HypervisorInterface as u32; // Maximum supported CPUID leaf range.
-                // cpuid_result.ebx = 0x756c6c49; // "ullI", part of "Illusion" (in reverse order due to little-endian storage).
-                // cpuid_result.ecx = 0x6e6f6973; // "nois", part of "Illusion" (in reverse order due to little-endian storage).
-                // cpuid_result.edx = 0x00000000; // Filled with null bytes as there are no more characters to encode.
             }
             leaf if leaf == CpuidLeaf::HypervisorInterface as u32 => {
                 trace!("CPUID leaf 0x40000001 detected (Hypervisor Interface Identification).");
-                // Return information indicating the hypervisor's interface.
-                // Here, we specify that our hypervisor does not conform to the Microsoft hypervisor interface ("Hv#1").
-                // cpuid_result.eax = 0x00000000; // Interface signature indicating non-conformance to Microsoft interface.
-                // cpuid_result.ebx = 0x00000000; // Reserved field set to zero.
-                // cpuid_result.ecx = 0x00000000; // Reserved field set to zero.
-                // cpuid_result.edx = 0x00000000; // Reserved field set to zero.
             }
             _ => trace!("CPUID leaf 0x{leaf:X}."),
         }
 
+        // Apply the policy for the leaf, leaves without a rule are passed through. The presence leaf shares
+        // 0x40000000 with the hypervisor leaves and must not be hidden by them.
+        if !presence_query {
+            if let Some(action) = CPUID_POLICY.read().lookup(leaf, sub_leaf) {
+                trace!("Applying CPUID policy {:?} to leaf {:#x}:{:#x}", action, leaf, sub_leaf);
+                action.apply(leaf, sub_leaf, &mut cpuid_result);
+            }
+        }
+
+        // Leaf 0xD must not report the components of features the policy hides, nor count them in the sizes.
+        if leaf == CpuidLeaf::ExtendedStateEnumeration as u32 {
+            xstate::fixup_leaf(sub_leaf, &mut cpuid_result, xstate::guest_components(), vm.xstate.guest_xcr0, xstate::component_layout);
+        }
+
         // Update the guest registers with the results
         vm.guest_registers.rax = cpuid_result.eax as u64;
         vm.guest_registers.rbx = cpuid_result.ebx as u64;
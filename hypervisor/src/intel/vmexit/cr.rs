@@ -3,21 +3,19 @@ use {
     crate::{
         error::HypervisorError,
         intel::{
-            events::EventInjection,
-            invvpid::{invvpid_single_context, VPID_TAG},
-            support::{rdmsr, read_effective_guest_cr0, read_effective_guest_cr4, vmread, vmwrite},
+            cr_shadow::{write_guest_cr0, write_guest_cr4},
+            efer::{self, mode_switch, ModeSwitch},
+            invvpid::TlbScope,
+            support::{cr8, cr8_write, read_effective_guest_cr0, read_effective_guest_cr4, vmread},
             vm::Vm,
-            vmerror::{ControlRegAccessExitQualification, CrAccessReg, CrAccessType},
+            vmerror::{ControlRegAccessExitQualification, CrAccessReg, CrAccessType, ExceptionInterrupt},
             vmexit::ExitType,
         },
     },
     bit_field::BitField,
-    core::{ops::Range, ptr::addr_of},
+    core::ops::Range,
     log::trace,
-    x86::vmx::{
-        vmcs,
-        vmcs::{control, guest},
-    },
+    x86::vmx::vmcs,
     x86_64::registers::control::{Cr0Flags, Cr4Flags},
 };
 
@@ -39,41 +37,47 @@ pub fn handle_cr_reg_access(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
     trace!("Handling ControlRegisterAccess VM exit...");
 
     let qual = vmread(vmcs::ro::EXIT_QUALIFICATION);
-    let cr = ControlRegAccessExitQualification::from_exit_qualification(qual);
+    let Some(cr) = ControlRegAccessExitQualification::from_exit_qualification(qual) else {
+        return Err(HypervisorError::UnhandledVmExit);
+    };
+
     match cr.access_type {
         CrAccessType::MovToCr => match cr.control_reg {
-            CrAccessReg::Cr2 | CrAccessReg::Cr3 | CrAccessReg::Cr8 => Err(HypervisorError::UnhandledVmExit),
+            CrAccessReg::Cr2 => Err(HypervisorError::UnhandledVmExit),
             CrAccessReg::Cr0 => Ok(handle_mov_to_cr0(vm, cr.gpr_mov_cr)),
+            CrAccessReg::Cr3 => Ok(handle_mov_to_cr3(vm, cr.gpr_mov_cr)),
             CrAccessReg::Cr4 => Ok(handle_mov_to_cr4(vm, cr.gpr_mov_cr)?),
+            CrAccessReg::Cr8 => Ok(handle_mov_to_cr8(vm, cr.gpr_mov_cr)),
         },
-        CrAccessType::MovFromCr | CrAccessType::Clts | CrAccessType::Lmsw => Err(HypervisorError::UnhandledVmExit),
+        CrAccessType::MovFromCr => handle_mov_from_cr(vm, cr.control_reg, cr.gpr_mov_cr),
+        CrAccessType::Clts => Ok(handle_clts()),
+        CrAccessType::Lmsw => Ok(handle_lmsw(vm, cr.lmsw_source_data)),
     }
 }
 
-/// The MOV to CR0 instruction causes a VM exit unless the value of its source operand matches, for
-/// the position of each bit set in the CR0 guest/host mask, the corresponding bit in the CR0 read shadow. (If every
-/// bit is clear in the CR0 guest/host mask, MOV to CR0 cannot cause a VM exit.)
+/// Checks the source operand of MOV to CR0 the way the instruction does.
+///
+/// The reserved bits of CR0[31:0] are ignored and CR0.ET always reads 1, so both are normalized.
 ///
 /// # Arguments
 ///
-/// * `vm`: A mutable reference to the VM.
-/// * `gpr`: The general-purpose register index.
+/// * `value` - The source operand.
+/// * `cr4` - The CR4 of the guest.
+/// * `code_64bit` - Whether the guest runs 64-bit code, IA-32e mode with CS.L set.
 ///
 /// # Returns
 ///
-/// * `ExitType`: The appropriate exit type.
+/// The value CR0 takes, `None` if the instruction raises #GP(0). Switches into and out of IA-32e mode are
+/// checked by `efer::mode_switch`.
 ///
-/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.1.3 Instructions That Cause VM Exits Conditionally
-/// Reference: Table 28-3. Exit Qualification for Control-Register Accesses
-fn handle_mov_to_cr0(vm: &mut Vm, gpr: u64) -> ExitType {
-    trace!("Handling MOV to CR0 VM exit...");
-
-    let mut new_cr0 = unsafe { Cr0Flags::from_bits_retain(addr_of!(vm.guest_registers).cast::<u64>().add(gpr as usize).read_unaligned()) };
-
-    let curr_cr0 = Cr0Flags::from_bits_retain(read_effective_guest_cr0());
-    let curr_cr4 = Cr4Flags::from_bits_retain(read_effective_guest_cr4());
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: MOV—Move to/from Control Registers
+pub fn checked_cr0(value: u64, cr4: u64, code_64bit: bool) -> Option<u64> {
+    // #GP(0) if setting any reserved bits in CR0[63:32]
+    if value.get_bits(32..64) != 0 {
+        return None;
+    }
 
-    let mut new_cr0_raw = new_cr0.bits();
+    let mut new_cr0_raw = value;
 
     // CR0[15:6] is always 0
     new_cr0_raw.set_bits(6..16, 0);
@@ -84,68 +88,100 @@ fn handle_mov_to_cr0(vm: &mut Vm, gpr: u64) -> ExitType {
     // CR0[28:19] is always 0
     new_cr0_raw.set_bits(19..29, 0);
 
-    new_cr0 = Cr0Flags::from_bits_retain(new_cr0_raw);
+    let mut new_cr0 = Cr0Flags::from_bits_retain(new_cr0_raw);
+    let cr4 = Cr4Flags::from_bits_retain(cr4);
 
     // CR0.ET is always 1
     new_cr0.set(Cr0Flags::EXTENSION_TYPE, true);
 
-    // #GP(0) if setting any reserved bits in CR0[63:32]
-    if new_cr0.bits().get_bits(32..64) != 0 {
-        EventInjection::vmentry_inject_gp(0);
-        return ExitType::Continue;
-    }
-
     // #GP(0) if setting CR0.PG while CR0.PE is clear
     if new_cr0.contains(Cr0Flags::PAGING) && !new_cr0.contains(Cr0Flags::PROTECTED_MODE_ENABLE) {
-        EventInjection::vmentry_inject_gp(0);
-        return ExitType::Continue;
+        return None;
     }
 
     // #GP(0) if invalid bit combination
     if !new_cr0.contains(Cr0Flags::CACHE_DISABLE) && new_cr0.contains(Cr0Flags::NOT_WRITE_THROUGH) {
-        EventInjection::vmentry_inject_gp(0);
-        return ExitType::Continue;
+        return None;
     }
 
-    // #GP(0) if an attempt is made to clear CR0.PG
-    if !new_cr0.contains(Cr0Flags::PAGING) {
-        EventInjection::vmentry_inject_gp(0);
-        return ExitType::Continue;
+    // #GP(0) if an attempt is made to clear CR0.PG in 64-bit mode, compatibility mode leaves IA-32e mode that way
+    if code_64bit && !new_cr0.contains(Cr0Flags::PAGING) {
+        return None;
     }
 
     // #GP(0) if an attempt is made to clear CR0.WP while CR4.CET is set
-    if !new_cr0.contains(Cr0Flags::WRITE_PROTECT) && curr_cr4.contains(Cr4Flags::CONTROL_FLOW_ENFORCEMENT) {
-        EventInjection::vmentry_inject_gp(0);
-        return ExitType::Continue;
+    if !new_cr0.contains(Cr0Flags::WRITE_PROTECT) && cr4.contains(Cr4Flags::CONTROL_FLOW_ENFORCEMENT) {
+        return None;
     }
 
-    if new_cr0.contains(Cr0Flags::CACHE_DISABLE) != new_cr0.contains(Cr0Flags::CACHE_DISABLE)
-        || new_cr0.contains(Cr0Flags::NOT_WRITE_THROUGH) != curr_cr0.contains(Cr0Flags::NOT_WRITE_THROUGH)
-    {
-        // https://github.com/jonomango/hv/blob/cd4d4022351b5d762045a02108973c697a79bb34/hv/exit-handlers.cpp#L284
+    Some(new_cr0.bits())
+}
+
+/// Checks the source operand of MOV to CR4 the way the instruction does.
+///
+/// # Arguments
+///
+/// * `value` - The source operand.
+/// * `cr4` - The current CR4 of the guest.
+/// * `cr0` - The CR0 of the guest.
+/// * `cr3` - The CR3 of the guest.
+/// * `long_mode` - Whether the guest runs in IA-32e mode, EFER.LMA.
+/// * `has_smx` - Whether the processor supports SMX.
+///
+/// # Returns
+///
+/// The value CR4 takes, `None` if the instruction raises #GP(0).
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: MOV—Move to/from Control Registers
+pub fn checked_cr4(value: u64, cr4: u64, cr0: u64, cr3: u64, long_mode: bool, has_smx: bool) -> Option<u64> {
+    const CR4_RESERVED_1: usize = 15;
+    const CR4_RESERVED_2: Range<usize> = 32..64;
+
+    let new_cr4 = Cr4Flags::from_bits_retain(value);
+    let curr_cr4 = Cr4Flags::from_bits_retain(cr4);
+    let curr_cr0 = Cr0Flags::from_bits_retain(cr0);
 
-        unimplemented!()
-        //invept_all_contexts();
+    // #GP(0) if an attempt is made to set CR4.SMXE when SMX is not supported
+    if !has_smx && new_cr4.contains(Cr4Flags::SAFER_MODE_EXTENSIONS) {
+        return None;
     }
 
-    vmwrite(control::CR0_READ_SHADOW, new_cr0.bits());
+    // #GP(0) if an attempt is made to write to any reserved bits
+    if value.get_bit(CR4_RESERVED_1) || value.get_bits(CR4_RESERVED_2) != 0 {
+        return None;
+    }
 
-    let vmx_cr0_fixed0 = rdmsr(x86::msr::IA32_VMX_CR0_FIXED0);
-    let vmx_cr0_fixed1 = rdmsr(x86::msr::IA32_VMX_CR0_FIXED1);
+    // #GP(0) if an attempt is made to change CR4.PCIDE from 0 to 1 outside IA-32e mode or while CR3[11:0] != 000H
+    if new_cr4.contains(Cr4Flags::PCID) && !curr_cr4.contains(Cr4Flags::PCID) && (!long_mode || cr3.get_bits(0..12) != 0) {
+        return None;
+    }
 
-    // make sure to account for VMX reserved bits when setting the real CR0
-    new_cr0 |= Cr0Flags::from_bits_retain(vmx_cr0_fixed0);
-    new_cr0 &= Cr0Flags::from_bits_retain(vmx_cr0_fixed1);
+    // #GP(0) if CR4.PAE is cleared in IA-32e mode
+    if long_mode && !new_cr4.contains(Cr4Flags::PHYSICAL_ADDRESS_EXTENSION) {
+        return None;
+    }
 
-    vmwrite(guest::CR0, new_cr0.bits());
+    // #GP(0) if CR4.LA57 is changed in IA-32e mode
+    if long_mode && new_cr4.contains(Cr4Flags::L5_PAGING) != curr_cr4.contains(Cr4Flags::L5_PAGING) {
+        return None;
+    }
 
-    trace!("Handled MOV to CR0 successfully!");
+    // #GP(0) if CR4.CET == 1 and CR0.WP == 0
+    if new_cr4.contains(Cr4Flags::CONTROL_FLOW_ENFORCEMENT) && !curr_cr0.contains(Cr0Flags::WRITE_PROTECT) {
+        return None;
+    }
 
-    ExitType::IncrementRIP
+    Some(value)
 }
 
-/// The MOV to CR4 instruction causes a VM exit unless the value of its source operand matches, for
-/// the position of each bit set in the CR4 guest/host mask, the corresponding bit in the CR4 read shadow.
+/// Returns whether the guest runs in IA-32e mode, which VM exits store in the "IA-32e mode guest" entry control.
+fn long_mode() -> bool {
+    vmread(vmcs::control::VMENTRY_CONTROLS) & vmcs::control::EntryControls::IA32E_MODE_GUEST.bits() as u64 != 0
+}
+
+/// The MOV to CR0 instruction causes a VM exit unless the value of its source operand matches, for
+/// the position of each bit set in the CR0 guest/host mask, the corresponding bit in the CR0 read shadow. (If every
+/// bit is clear in the CR0 guest/host mask, MOV to CR0 cannot cause a VM exit.)
 ///
 /// # Arguments
 ///
@@ -154,78 +190,293 @@ fn handle_mov_to_cr0(vm: &mut Vm, gpr: u64) -> ExitType {
 ///
 /// # Returns
 ///
-/// * `Result<ExitType, HypervisorError>`: Ok with the appropriate exit type or an error.
+/// * `ExitType`: The appropriate exit type.
 ///
 /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.1.3 Instructions That Cause VM Exits Conditionally
-fn handle_mov_to_cr4(vm: &mut Vm, gpr: u64) -> Result<ExitType, HypervisorError> {
-    trace!("Handling MOV to CR4 VM exit...");
+/// Reference: Table 28-3. Exit Qualification for Control-Register Accesses
+pub fn handle_mov_to_cr0(vm: &mut Vm, gpr: u64) -> ExitType {
+    trace!("Handling MOV to CR0 VM exit...");
 
-    const CR4_RESERVED_1: usize = 15;
-    const CR4_RESERVED_2: Range<usize> = 32..64;
+    let curr_cr0 = read_effective_guest_cr0();
+    let cr4 = read_effective_guest_cr4();
+    let code_64bit = efer::code_64bit();
+
+    let checked = checked_cr0(vm.guest_registers.gpr(gpr), cr4, code_64bit)
+        .and_then(|new_cr0| Some((new_cr0, mode_switch(curr_cr0, new_cr0, cr4, vm.guest_efer(), code_64bit)?)));
+    let Some((new_cr0, switch)) = checked else {
+        vm.events.inject_exception(ExceptionInterrupt::GeneralProtectionFault as u8, Some(0));
+        return ExitType::Continue;
+    };
+
+    // Turning paging off invalidates every translation, including the global ones.
+    if curr_cr0 & Cr0Flags::PAGING.bits() != 0 && new_cr0 & Cr0Flags::PAGING.bits() == 0 {
+        vm.flush_translations(TlbScope::Context);
+    }
+
+    // Setting or clearing CR0.PG with EFER.LME set enters or leaves IA-32e mode.
+    if switch != ModeSwitch::Unchanged {
+        trace!("MOV to CR0 {:?} IA-32e mode", switch);
+    }
+    efer::switch_mode(switch);
+    write_guest_cr0(new_cr0);
+    vm.sync_guest_mode();
+
+    trace!("Handled MOV to CR0 successfully!");
+
+    ExitType::IncrementRIP
+}
 
-    let mut new_cr4 = unsafe { Cr4Flags::from_bits_retain(addr_of!(vm.guest_registers).cast::<u64>().add(gpr as usize).read_unaligned()) };
+/// The MOV to CR3 instruction causes a VM exit if the "CR3-load exiting" control is 1, unless the source
+/// operand matches one of the CR3-target values.
+///
+/// The write is performed on the guest CR3, followed by the TLB invalidation the instruction does natively: the
+/// non-global translations, unless CR4.PCIDE is set and bit 63 of the operand asks to keep them.
+///
+/// # Arguments
+///
+/// * `vm`: A mutable reference to the VM.
+/// * `gpr`: The general-purpose register index.
+///
+/// # Returns
+///
+/// * `ExitType`: The appropriate exit type.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 4.10.4.1 Operations that Invalidate TLBs and Paging-Structure Caches
+pub fn handle_mov_to_cr3(vm: &mut Vm, gpr: u64) -> ExitType {
+    trace!("Handling MOV to CR3 VM exit...");
 
-    let curr_cr3 = vmread(guest::CR3);
+    const CR3_NO_FLUSH: u64 = 1 << 63;
 
-    let curr_cr0 = Cr0Flags::from_bits_retain(read_effective_guest_cr0());
+    let new_cr3 = vm.guest_registers.gpr(gpr);
     let curr_cr4 = Cr4Flags::from_bits_retain(read_effective_guest_cr4());
 
-    // #GP(0) if an attempt is made to set CR4.SMXE when SMX is not supported
-    if vm.cpuid_feature_info.has_smx() && new_cr4.contains(Cr4Flags::SAFER_MODE_EXTENSIONS) {
-        EventInjection::vmentry_inject_gp(0);
-        return Ok(ExitType::Continue);
-    }
+    // Bit 63 only controls the invalidation, CR3 itself never holds it.
+    vm.set_guest_cr3(new_cr3 & !CR3_NO_FLUSH);
 
-    // #GP(0) if an attempt is made to write to any reserved bits
-    if new_cr4.bits().get_bit(CR4_RESERVED_1) || new_cr4.bits().get_bits(CR4_RESERVED_2) != 0 {
-        EventInjection::vmentry_inject_gp(0);
-        return Ok(ExitType::Continue);
+    if !(curr_cr4.contains(Cr4Flags::PCID) && new_cr3 & CR3_NO_FLUSH != 0) {
+        vm.flush_translations(TlbScope::NonGlobal);
     }
 
-    // #GP(0) if an attempt is made to change CR4.PCIDE from 0 to 1 while CR3[11:0] != 000H
-    if new_cr4.contains(Cr4Flags::PCID) && !curr_cr4.contains(Cr4Flags::PCID) && curr_cr3.get_bits(0..12) != 0 {
-        EventInjection::vmentry_inject_gp(0);
-        return Ok(ExitType::Continue);
-    }
+    trace!("Handled MOV to CR3 successfully!");
 
-    // #GP(0) if CR4.PAE is cleared
-    if !new_cr4.contains(Cr4Flags::PHYSICAL_ADDRESS_EXTENSION) {
-        EventInjection::vmentry_inject_gp(0);
-        return Ok(ExitType::Continue);
-    }
+    ExitType::IncrementRIP
+}
 
-    // #GP(0) if CR4.LA57 is enabled
-    if new_cr4.contains(Cr4Flags::L5_PAGING) {
-        EventInjection::vmentry_inject_gp(0);
-        return Ok(ExitType::Continue);
-    }
+/// The MOV to CR4 instruction causes a VM exit unless the value of its source operand matches, for
+/// the position of each bit set in the CR4 guest/host mask, the corresponding bit in the CR4 read shadow.
+///
+/// # Arguments
+///
+/// * `vm`: A mutable reference to the VM.
+/// * `gpr`: The general-purpose register index.
+///
+/// # Returns
+///
+/// * `Result<ExitType, HypervisorError>`: Ok with the appropriate exit type or an error.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.1.3 Instructions That Cause VM Exits Conditionally
+pub fn handle_mov_to_cr4(vm: &mut Vm, gpr: u64) -> Result<ExitType, HypervisorError> {
+    trace!("Handling MOV to CR4 VM exit...");
 
-    // #GP(0) if CR4.CET == 1 and CR0.WP == 0
-    if new_cr4.contains(Cr4Flags::CONTROL_FLOW_ENFORCEMENT) && !curr_cr0.contains(Cr0Flags::WRITE_PROTECT) {
-        EventInjection::vmentry_inject_gp(0);
+    let curr_cr4_raw = read_effective_guest_cr4();
+    let checked = checked_cr4(
+        vm.guest_registers.gpr(gpr),
+        curr_cr4_raw,
+        read_effective_guest_cr0(),
+        vm.guest_cr3(),
+        long_mode(),
+        vm.cpuid_feature_info.has_smx(),
+    );
+    let Some(new_cr4_raw) = checked else {
+        vm.events.inject_exception(ExceptionInterrupt::GeneralProtectionFault as u8, Some(0));
         return Ok(ExitType::Continue);
-    }
+    };
+
+    let new_cr4 = Cr4Flags::from_bits_retain(new_cr4_raw);
+    let curr_cr4 = Cr4Flags::from_bits_retain(curr_cr4_raw);
 
     // invalidate TLB entries if required
     if (new_cr4.contains(Cr4Flags::PAGE_GLOBAL) != curr_cr4.contains(Cr4Flags::PAGE_GLOBAL))
         || !new_cr4.contains(Cr4Flags::PCID) && curr_cr4.contains(Cr4Flags::PCID)
         || new_cr4.contains(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION) && !curr_cr4.contains(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION)
     {
-        invvpid_single_context(VPID_TAG);
+        vm.flush_translations(TlbScope::Context);
     }
 
-    vmwrite(control::CR4_READ_SHADOW, new_cr4.bits());
+    write_guest_cr4(new_cr4_raw);
+    vm.sync_guest_mode();
+
+    trace!("Handled MOV to CR4 successfully!");
+
+    Ok(ExitType::IncrementRIP)
+}
+
+/// The MOV to CR8 instruction causes a VM exit if the "CR8-load exiting" control is 1.
+///
+/// Without a TPR shadow the guest owns the task-priority register, so the write goes to the real CR8.
+///
+/// # Arguments
+///
+/// * `vm`: A mutable reference to the VM.
+/// * `gpr`: The general-purpose register index.
+///
+/// # Returns
+///
+/// * `ExitType`: The appropriate exit type.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.1.3 Instructions That Cause VM Exits Conditionally
+fn handle_mov_to_cr8(vm: &mut Vm, gpr: u64) -> ExitType {
+    trace!("Handling MOV to CR8 VM exit...");
 
-    let vmx_cr4_fixed0 = rdmsr(x86::msr::IA32_VMX_CR4_FIXED0);
-    let vmx_cr4_fixed1 = rdmsr(x86::msr::IA32_VMX_CR4_FIXED1);
+    let new_cr8 = vm.guest_registers.gpr(gpr);
 
-    // make sure to account for VMX reserved bits when setting the real CR4
-    new_cr4 |= Cr4Flags::from_bits_retain(vmx_cr4_fixed0);
-    new_cr4 &= Cr4Flags::from_bits_retain(vmx_cr4_fixed1);
+    // #GP(0) if an attempt is made to write a 1 to any reserved bit in CR8
+    if new_cr8.get_bits(4..64) != 0 {
+        vm.events.inject_exception(ExceptionInterrupt::GeneralProtectionFault as u8, Some(0));
+        return ExitType::Continue;
+    }
 
-    vmwrite(guest::CR4, new_cr4.bits());
+    cr8_write(new_cr8);
 
-    trace!("Handled MOV to CR4 successfully!");
+    ExitType::IncrementRIP
+}
+
+/// The MOV from CR3 and CR8 instructions cause a VM exit if the "CR3-store exiting" and "CR8-store exiting"
+/// controls are 1. MOV from CR0 and CR4 read the read shadows for the bits of the guest/host masks and
+/// don't exit, they are still answered with the values the guest reads.
+///
+/// # Arguments
+///
+/// * `vm`: A mutable reference to the VM.
+/// * `control_reg`: The control register read.
+/// * `gpr`: The general-purpose register index.
+///
+/// # Returns
+///
+/// * `Result<ExitType, HypervisorError>`: Ok with the appropriate exit type or an error.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.1.3 Instructions That Cause VM Exits Conditionally
+pub fn handle_mov_from_cr(vm: &mut Vm, control_reg: CrAccessReg, gpr: u64) -> Result<ExitType, HypervisorError> {
+    trace!("Handling MOV from {:?} VM exit...", control_reg);
+
+    let value = match control_reg {
+        CrAccessReg::Cr0 => read_effective_guest_cr0(),
+        CrAccessReg::Cr2 => return Err(HypervisorError::UnhandledVmExit),
+        CrAccessReg::Cr3 => vm.guest_cr3(),
+        CrAccessReg::Cr4 => read_effective_guest_cr4(),
+        CrAccessReg::Cr8 => cr8(),
+    };
+    vm.write_guest_gpr(gpr, value);
 
     Ok(ExitType::IncrementRIP)
 }
+
+/// The CLTS instruction causes a VM exit if the bits in position 3 (corresponding to CR0.TS) are set in both the
+/// CR0 guest/host mask and the CR0 read shadow. It clears CR0.TS in both the read shadow and the guest CR0.
+///
+/// # Returns
+///
+/// * `ExitType`: The appropriate exit type.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.1.3 Instructions That Cause VM Exits Conditionally
+pub fn handle_clts() -> ExitType {
+    trace!("Handling CLTS VM exit...");
+
+    write_guest_cr0(read_effective_guest_cr0() & !Cr0Flags::TASK_SWITCHED.bits());
+
+    ExitType::IncrementRIP
+}
+
+/// Returns the CR0 LMSW writes: the source operand replaces CR0[3:0], except that LMSW never clears CR0.PE.
+///
+/// # Arguments
+///
+/// * `cr0` - The CR0 of the guest.
+/// * `source` - The source operand.
+pub fn lmsw_cr0(cr0: u64, source: u16) -> u64 {
+    const MSW_BITS: u64 = 0xF;
+    cr0 & !MSW_BITS | u64::from(source) & MSW_BITS | cr0 & Cr0Flags::PROTECTED_MODE_ENABLE.bits()
+}
+
+/// The LMSW instruction causes a VM exit if it would change a bit of CR0[3:0] set in the CR0 guest/host mask
+/// from its value in the CR0 read shadow. The exit qualification holds the source operand, for memory operands too.
+///
+/// # Arguments
+///
+/// * `vm`: A mutable reference to the VM.
+/// * `source`: The source operand of LMSW.
+///
+/// # Returns
+///
+/// * `ExitType`: The appropriate exit type.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.1.3 Instructions That Cause VM Exits Conditionally
+pub fn handle_lmsw(vm: &mut Vm, source: u16) -> ExitType {
+    trace!("Handling LMSW VM exit...");
+
+    write_guest_cr0(lmsw_cr0(read_effective_guest_cr0(), source));
+    vm.sync_guest_mode();
+
+    ExitType::IncrementRIP
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PE: u64 = Cr0Flags::PROTECTED_MODE_ENABLE.bits();
+    const ET: u64 = Cr0Flags::EXTENSION_TYPE.bits();
+    const WP: u64 = Cr0Flags::WRITE_PROTECT.bits();
+    const PG: u64 = Cr0Flags::PAGING.bits();
+
+    /// CR0 of a 64-bit Windows guest: PG, WP, NE, ET, MP and PE.
+    const CR0: u64 = 0x8005_0033;
+
+    #[test]
+    fn illegal_cr0_writes_raise_gp() {
+        // Clearing PG in IA-32e mode faults, outside of it it goes through.
+        assert_eq!(checked_cr0(CR0 & !PG, 0, true), None);
+        assert_eq!(checked_cr0(CR0 & !PG, 0, false), Some(CR0 & !PG));
+
+        assert_eq!(checked_cr0(CR0 | 1 << 32, 0, true), None);
+        assert_eq!(checked_cr0(PG, 0, false), None);
+        assert_eq!(checked_cr0(CR0 | Cr0Flags::NOT_WRITE_THROUGH.bits(), 0, true), None);
+        assert_eq!(checked_cr0(CR0 & !WP, Cr4Flags::CONTROL_FLOW_ENFORCEMENT.bits(), true), None);
+        assert_eq!(checked_cr0(CR0 & !WP, 0, true), Some(CR0 & !WP));
+    }
+
+    #[test]
+    fn cr0_writes_ignore_the_reserved_low_bits() {
+        assert_eq!(checked_cr0(CR0 | 0xFFC0 | 1 << 17 | 0x1FF8_0000, 0, true), Some(CR0));
+        assert_eq!(checked_cr0(CR0 & !ET, 0, true), Some(CR0));
+        assert_eq!(checked_cr0(PE, 0, false), Some(PE | ET));
+    }
+
+    #[test]
+    fn illegal_cr4_writes_raise_gp() {
+        const PAE: u64 = Cr4Flags::PHYSICAL_ADDRESS_EXTENSION.bits();
+        const PCIDE: u64 = Cr4Flags::PCID.bits();
+        const LA57: u64 = Cr4Flags::L5_PAGING.bits();
+        const SMXE: u64 = Cr4Flags::SAFER_MODE_EXTENSIONS.bits();
+        const CET: u64 = Cr4Flags::CONTROL_FLOW_ENFORCEMENT.bits();
+        let cr4 = 0x0035_06F8;
+
+        assert_eq!(checked_cr4(cr4 | PCIDE, cr4, CR0, 0x1A_B000, true, false), Some(cr4 | PCIDE));
+        assert_eq!(checked_cr4(cr4 | PCIDE, cr4, CR0, 0x1A_B001, true, false), None);
+        assert_eq!(checked_cr4(cr4 | PCIDE, cr4, CR0, 0x1A_B000, false, false), None);
+        assert_eq!(checked_cr4(cr4 & !PAE, cr4, CR0, 0, true, false), None);
+        assert_eq!(checked_cr4(cr4 | LA57, cr4, CR0, 0, true, false), None);
+        assert_eq!(checked_cr4(cr4 | LA57, cr4 | LA57, CR0, 0, true, false), Some(cr4 | LA57));
+        assert_eq!(checked_cr4(cr4 | 1 << 15, cr4, CR0, 0, true, false), None);
+        assert_eq!(checked_cr4(cr4 | SMXE, cr4, CR0, 0, true, false), None);
+        assert_eq!(checked_cr4(cr4 | SMXE, cr4, CR0, 0, true, true), Some(cr4 | SMXE));
+        assert_eq!(checked_cr4(cr4 | CET, cr4, CR0 & !WP, 0, true, false), None);
+    }
+
+    #[test]
+    fn lmsw_never_clears_pe() {
+        assert_eq!(lmsw_cr0(CR0, 0x0000), CR0 & !0xF | PE);
+        assert_eq!(lmsw_cr0(0x10, 0xFFF1), 0x11);
+        assert_eq!(lmsw_cr0(CR0 & !0xF, 0x000A), CR0 & !0xF | 0xA);
+    }
+}
@@ -0,0 +1,84 @@
+//! Handles the VM exits of MOV DR, serving the debug registers of the guest from `intel::debug_regs`.
+
+use {
+    crate::{
+        intel::{
+            debug_regs::{checked_dr_write, resolve, DrAccess, DR6_BD, DR7_GD},
+            support::{guest_cpl, read_effective_guest_cr4, vmread, vmwrite},
+            vm::Vm,
+            vmerror::{DebugRegAccessExitQualification, DrAccessDirection, ExceptionInterrupt},
+            vmexit::ExitType,
+        },
+        stats,
+    },
+    log::trace,
+    x86::vmx::vmcs,
+    x86_64::registers::control::Cr4Flags,
+};
+
+/// Handles the `MovDr` VM exit.
+///
+/// The MOV DR instruction causes a VM exit if the "MOV-DR exiting" VM-execution control is 1. Such VM exits
+/// represent an exception to the principles identified in Section 26.1.1 in that they take priority over the
+/// following: general-protection exceptions based on privilege level; and invalid-opcode exceptions due to
+/// attempts to access DR4 and DR5 with CR4.DE = 1. Both are raised here, as is the #DB of DR7.GD.
+///
+/// # Arguments
+///
+/// * `vm`: A mutable reference to the VM.
+///
+/// # Returns
+///
+/// * `ExitType`: The appropriate exit type.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.1.3 Instructions That Cause VM Exits Conditionally
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 28-4. Exit Qualification for MOV DR
+pub fn handle_mov_dr(vm: &mut Vm) -> ExitType {
+    trace!("Handling MOV DR VM exit...");
+
+    let dr = DebugRegAccessExitQualification::from_exit_qualification(vmread(vmcs::ro::EXIT_QUALIFICATION));
+    match dr.direction {
+        DrAccessDirection::MovToDr => stats::record_debug_register_write(),
+        DrAccessDirection::MovFromDr => stats::record_debug_register_read(),
+    }
+
+    // #GP(0) if the current privilege level is not 0
+    if guest_cpl() != 0 {
+        vm.events.inject_exception(ExceptionInterrupt::GeneralProtectionFault as u8, Some(0));
+        return ExitType::Continue;
+    }
+
+    let cr4 = Cr4Flags::from_bits_retain(read_effective_guest_cr4());
+    let dr7 = vmread(vmcs::guest::DR7);
+    let index = match resolve(dr.debug_reg, cr4.contains(Cr4Flags::DEBUGGING_EXTENSIONS), dr7) {
+        DrAccess::Register(index) => index,
+        DrAccess::InvalidOpcode => {
+            vm.events.inject_exception(ExceptionInterrupt::InvalidOpcode as u8, None);
+            return ExitType::Continue;
+        }
+        DrAccess::GeneralDetect => {
+            // The processor clears DR7.GD when it delivers the #DB, so the handler can use the debug registers.
+            vm.debug_regs.guest.dr6 |= DR6_BD;
+            vmwrite(vmcs::guest::DR7, dr7 & !DR7_GD);
+            vm.events.inject_exception(ExceptionInterrupt::Debug as u8, None);
+            return ExitType::Continue;
+        }
+    };
+
+    match dr.direction {
+        DrAccessDirection::MovFromDr => {
+            let value = vm.debug_regs.read(index);
+            vm.write_guest_gpr(dr.gpr_mov_dr, value);
+        }
+        DrAccessDirection::MovToDr => {
+            // #GP(0) if an attempt is made to write a 1 to any of bits 63:32 in DR6 or DR7
+            let Some(value) = checked_dr_write(index, vm.guest_registers.gpr(dr.gpr_mov_dr)) else {
+                vm.events.inject_exception(ExceptionInterrupt::GeneralProtectionFault as u8, Some(0));
+                return ExitType::Continue;
+            };
+            vm.debug_regs.write(index, value);
+        }
+    }
+
+    ExitType::IncrementRIP
+}
@@ -1,9 +1,14 @@
 use {
     crate::{
         error::HypervisorError,
-        intel::{ept::Pt, hooks::hook_manager::SHARED_HOOK_MANAGER, support::vmread, vm::Vm, vmexit::ExitType},
+        intel::{
+            shootdown::{self, Scope},
+            support::vmread,
+            vm::Vm,
+            vmexit::ExitType,
+        },
     },
-    log::trace,
+    log::{trace, warn},
     x86::{bits64::paging::PAddr, vmx::vmcs},
 };
 
@@ -13,6 +18,9 @@ use {
 /// an issue with the Extended Page Tables (EPT) setup. It logs the faulting
 /// guest physical address and returns a `HypervisorError` for immediate debugging.
 ///
+/// The misconfiguration the identity map deliberately leaves where the memory map has nothing is no error,
+/// the page is logged and mapped on demand instead.
+///
 /// # Arguments
 ///
 /// * `vm` - A mutable reference to the virtual machine (VM) instance.
@@ -26,20 +34,21 @@ pub fn handle_ept_misconfiguration(vm: &mut Vm) -> Result<ExitType, HypervisorEr
     // Retrieve the guest physical address that caused the EPT misconfiguration.
     let guest_physical_address = PAddr::from(vmread(vmcs::ro::GUEST_PHYSICAL_ADDR_FULL));
 
+    // The trap may be in a view, which shares the page directory of the trap with the primary EPT.
+    vm.enter_primary_view()?;
+
+    if vm.primary_ept.is_misconfiguration_trap(guest_physical_address.as_u64()) && vm.map_on_demand(guest_physical_address.as_u64())? {
+        warn!("Mapped {:#x} on demand, it is missing from the memory map", guest_physical_address.as_u64());
+        shootdown::flush_ept(Scope::CurrentProcessor);
+        return Ok(ExitType::Continue);
+    }
+
     trace!(
         "EPT Misconfiguration: Faulting guest address: {:#x}. This is a critical error that cannot be safely ignored.",
         guest_physical_address.as_u64()
     );
 
-    // Lock the shared hook manager
-    let mut hook_manager = SHARED_HOOK_MANAGER.lock();
-
-    let pre_alloc_pt = hook_manager
-        .memory_manager
-        .get_page_table_as_mut(guest_physical_address.align_down_to_large_page().as_u64())
-        .ok_or(HypervisorError::PageTableNotFound)?;
-
-    dump_primary_ept_entries(vm, guest_physical_address.as_u64(), pre_alloc_pt)?;
+    dump_primary_ept_entries(vm, guest_physical_address.as_u64())?;
 
     // Return a HypervisorError indicating a critical issue with the EPT configuration.
     Err(HypervisorError::EptMisconfiguration)
@@ -53,8 +62,7 @@ pub fn handle_ept_misconfiguration(vm: &mut Vm) -> Result<ExitType, HypervisorEr
 ///
 /// * `vm` - The virtual machine instance.
 /// * `faulting_guest_pa` - The faulting guest physical address that caused the EPT misconfiguration or violation.
-/// * `pre_alloc_pt` - The pre-allocated page table to be used for the entries.
-pub fn dump_primary_ept_entries(vm: &mut Vm, faulting_guest_pa: u64, pre_alloc_pt: &mut Pt) -> Result<(), HypervisorError> {
+pub fn dump_primary_ept_entries(vm: &mut Vm, faulting_guest_pa: u64) -> Result<(), HypervisorError> {
     // Log the critical error information.
     trace!("Faulting guest address: {:#x}", faulting_guest_pa);
 
@@ -69,7 +77,7 @@ pub fn dump_primary_ept_entries(vm: &mut Vm, faulting_guest_pa: u64, pre_alloc_p
     let primary_ept = &mut vm.primary_ept;
 
     trace!("Dumping Primary EPT entries for guest physical address: {:#x}", faulting_guest_pa);
-    primary_ept.dump_ept_entries(faulting_guest_pa, pre_alloc_pt);
+    primary_ept.dump_ept_entries(faulting_guest_pa);
 
     Ok(())
 }
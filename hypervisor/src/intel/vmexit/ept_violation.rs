@@ -3,18 +3,20 @@ use {
         error::HypervisorError,
         intel::{
             ept::AccessType,
-            hooks::hook_manager::SHARED_HOOK_MANAGER,
+            hooks::{hook_manager::SHARED_HOOK_MANAGER, hook_sync},
+            shootdown::{self, Scope},
             support::vmread,
+            ve::EXECUTE_VIEW,
             vm::Vm,
             vmerror::EptViolationExitQualification,
-            vmexit::{
-                mtf::{set_monitor_trap_flag, update_guest_interrupt_flag},
-                ExitType,
-            },
+            vmexit::{mtf::PostStepAction, ExitType},
         },
     },
     log::*,
-    x86::{bits64::paging::PAddr, vmx::vmcs},
+    x86::{
+        bits64::paging::{PAddr, BASE_PAGE_SIZE},
+        vmx::vmcs,
+    },
 };
 
 /// Handles VM exits for EPT violations.
@@ -22,7 +24,9 @@ use {
 ///
 /// This function addresses the EPT violation by either swapping the page to a shadow page
 /// or restoring the original page based on the exit qualification. It also sets up the monitor trap flag
-/// if necessary.
+/// if necessary. Pages hooked with pre-built views are toggled with a single entry store, or with a switch of the EPTP
+/// where the guest #VE handler switches the views itself and handed this access over. Accesses to pages without a hook that the identity map left out are logged and the page is
+/// mapped on demand.
 ///
 /// # Arguments
 ///
@@ -43,8 +47,23 @@ pub fn handle_ept_violation(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
     let guest_large_page_pa = guest_page_pa.align_down_to_large_page();
     trace!("Faulting Guest Large Page PA: {:#x}", guest_large_page_pa);
 
+    // The violation may come from the execute view, the views of hooks are handled in the primary EPT.
+    vm.enter_primary_view()?;
+
     // Lock the shared hook manager
-    let mut hook_manager = SHARED_HOOK_MANAGER.lock();
+    let hook_manager = SHARED_HOOK_MANAGER.lock();
+
+    // A hook changed since the VM exit, the guest retries the access with the EPT of the hook manager.
+    if matches!(hook_sync::sync(vm), Ok(true)) {
+        return Ok(ExitType::Continue);
+    }
+
+    // Not a hook, the guest found memory outside the memory map, like hot-plugged MMIO.
+    if hook_manager.memory_manager.get_shadow_page_as_ptr(guest_page_pa.as_u64()).is_none() && vm.map_on_demand(guest_pa)? {
+        warn!("Mapped {:#x} on demand, it is missing from the memory map", guest_pa);
+        shootdown::flush_ept(Scope::CurrentProcessor);
+        return Ok(ExitType::Continue);
+    }
 
     let shadow_page_pa = PAddr::from(
         hook_manager
@@ -54,18 +73,39 @@ pub fn handle_ept_violation(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
     );
     trace!("Shadow Page PA: {:#x}", shadow_page_pa.as_u64());
 
-    let pre_alloc_pt = hook_manager
-        .memory_manager
-        .get_page_table_as_mut(guest_large_page_pa.as_u64())
-        .ok_or(HypervisorError::PageTableNotFound)?;
-
-    // dump_primary_ept_entries(vm, guest_pa, pre_alloc_pt)?;
+    // dump_primary_ept_entries(vm, guest_pa)?;
 
     let exit_qualification_value = vmread(vmcs::ro::EXIT_QUALIFICATION);
     let ept_violation_qualification = EptViolationExitQualification::from_exit_qualification(exit_qualification_value);
     trace!("Exit Qualification for EPT Violations: {:#?}", ept_violation_qualification);
     trace!("Faulting Guest RIP: {:#x}", vm.guest_registers.rip);
 
+    // Fast path, toggle between the pre-built views of the page. A data access from code on the same page
+    // would toggle forever, so it takes the single-step path below instead.
+    if let Some(views) = vm.applied_hooks.views(guest_page_pa.as_u64()) {
+        if ept_violation_qualification.instruction_fetch {
+            trace!("Execution attempt on the read-write view, switching to the execute view.");
+            if vm.ve_active() {
+                // The primary EPT keeps the read-write view, or the #VE handler would switch back and forth.
+                vm.switch_view(EXECUTE_VIEW)?;
+            } else {
+                vm.primary_ept.set_page_entry(guest_page_pa.as_u64(), views.execute)?;
+            }
+            return Ok(ExitType::Continue);
+        }
+
+        let guest_linear_address = vmread(vmcs::ro::GUEST_LINEAR_ADDR);
+        let same_page =
+            ept_violation_qualification.guest_linear_address_valid && (guest_linear_address ^ vm.guest_registers.rip) < BASE_PAGE_SIZE as u64;
+        if !same_page {
+            trace!("Read/Write attempt on the execute view, switching to the read-write view.");
+            if !vm.ve_active() {
+                vm.primary_ept.set_page_entry(guest_page_pa.as_u64(), views.read_write)?;
+            }
+            return Ok(ExitType::Continue);
+        }
+    }
+
     if ept_violation_qualification.readable && ept_violation_qualification.writable && !ept_violation_qualification.executable {
         // if the instruction fetch is true and the page is not executable, we need to swap the page to a shadow page.
         //   Instruction Fetch: true,
@@ -73,7 +113,7 @@ pub fn handle_ept_violation(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
         trace!("Page Permissions: R:true, W:true, X:false (readable, writable, but non-executable).");
         trace!("Execution attempt on non-executable page, switching to hooked shadow-copy page.");
         vm.primary_ept
-            .swap_page(guest_page_pa.as_u64(), shadow_page_pa.as_u64(), AccessType::EXECUTE, pre_alloc_pt)?;
+            .swap_page(guest_page_pa.as_u64(), shadow_page_pa.as_u64(), AccessType::EXECUTE)?;
         trace!("Page swapped successfully!");
     } else if ept_violation_qualification.executable && !ept_violation_qualification.readable && !ept_violation_qualification.writable {
         // if the instruction fetch is false and the page is executable, we need to swap the page to a shadow page.
@@ -81,19 +121,16 @@ pub fn handle_ept_violation(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
         //   Page Permissions: R:false, W:false, X:true (non-readable, non-writable, but executable).
         trace!("Read/Write attempt on execute-only page, restoring original page.");
         trace!("Page Permissions: R:false, W:false, X:true (non-readable, non-writable, but executable).");
-        vm.primary_ept
-            .swap_page(guest_page_pa.as_u64(), guest_page_pa.as_u64(), AccessType::READ_WRITE_EXECUTE, pre_alloc_pt)?;
-
         // We make this read-write-execute to allow the instruction performing a read-write
-        // operation and then switch back to execute-only shadow page from handle_mtf vmexit
-        vm.mtf_counter = Some(1);
-
-        // Set the monitor trap flag and initialize counter to the number of overwritten instructions
-        set_monitor_trap_flag(true);
+        // operation and then switch back to execute-only shadow page from handle_mtf vmexit.
+        // Arming first completes a pending step, which must not undo the swap below.
+        vm.single_step(PostStepAction::RearmHook {
+            guest_page_pa: guest_page_pa.as_u64(),
+            shadow_page_pa: shadow_page_pa.as_u64(),
+        })?;
 
-        // Ensure all data mutations to vm are done before calling this.
-        // This function will update the guest interrupt flag to prevent interrupts while single-stepping
-        update_guest_interrupt_flag(vm, false)?;
+        vm.primary_ept
+            .swap_page(guest_page_pa.as_u64(), guest_page_pa.as_u64(), AccessType::READ_WRITE_EXECUTE)?;
     }
 
     trace!("EPT Violation handled successfully!");
@@ -4,10 +4,11 @@
 
 use {
     crate::intel::{
-        events::EventInjection,
+        nmi, shootdown,
         support::vmread,
+        v86,
         vm::Vm,
-        vmerror::{EptViolationExitQualification, ExceptionInterrupt, VmExitInterruptionInformation},
+        vmerror::{EptViolationExitQualification, ExceptionInterrupt, InterruptionType, VmExitInterruptionInformation},
         vmexit::ExitType,
     },
     x86::vmx::vmcs,
@@ -27,30 +28,42 @@ use {
 /// # Returns
 ///
 /// * `ExitType::Continue` - Indicating that VM execution should continue after handling the exception
-pub fn handle_exception(_vm: &mut Vm) -> ExitType {
+pub fn handle_exception(vm: &mut Vm) -> ExitType {
     log::debug!("Handling ExceptionOrNmi VM exit...");
 
     let interruption_info_value = vmread(vmcs::ro::VMEXIT_INTERRUPTION_INFO);
     let interruption_error_code_value = vmread(vmcs::ro::VMEXIT_INTERRUPTION_ERR_CODE);
 
     if let Some(interruption_info) = VmExitInterruptionInformation::from_u32(interruption_info_value as u32) {
+        if interruption_info.interruption_type == InterruptionType::NonMaskableInterrupt {
+            handle_nmi(vm);
+            return ExitType::Continue;
+        }
+
+        // Without unrestricted guest every exception of real mode exits, see `v86`.
+        if vm.mode_watch.in_real_mode() {
+            return v86::handle_exception(vm, interruption_info.vector, interruption_error_code_value as u32);
+        }
+
         if let Some(exception_interrupt) = ExceptionInterrupt::from_u32(interruption_info.vector.into()) {
             match exception_interrupt {
                 ExceptionInterrupt::PageFault => {
                     let exit_qualification_value = vmread(vmcs::ro::EXIT_QUALIFICATION);
                     let ept_violation_qualification = EptViolationExitQualification::from_exit_qualification(exit_qualification_value);
                     log::trace!("Exit Qualification for EPT Violations: {:#?}", ept_violation_qualification);
-                    EventInjection::vmentry_inject_pf(interruption_error_code_value as u32);
+                    vm.events
+                        .inject_exception(ExceptionInterrupt::PageFault as u8, Some(interruption_error_code_value as u32));
                 }
                 ExceptionInterrupt::GeneralProtectionFault => {
-                    EventInjection::vmentry_inject_gp(interruption_error_code_value as u32);
+                    vm.events
+                        .inject_exception(ExceptionInterrupt::GeneralProtectionFault as u8, Some(interruption_error_code_value as u32));
                 }
                 ExceptionInterrupt::Breakpoint => {
                     //handle_breakpoint_exception(guest_registers, vm);
-                    EventInjection::vmentry_inject_bp();
+                    vm.events.inject_exception(ExceptionInterrupt::Breakpoint as u8, None);
                 }
                 ExceptionInterrupt::InvalidOpcode => {
-                    EventInjection::vmentry_inject_ud();
+                    vm.events.inject_exception(ExceptionInterrupt::InvalidOpcode as u8, None);
                 }
                 _ => {
                     panic!("Unhandled exception: {:?}", exception_interrupt);
@@ -68,6 +81,23 @@ pub fn handle_exception(_vm: &mut Vm) -> ExitType {
     ExitType::Continue
 }
 
+/// Handles an NMI that exited because of the "NMI exiting" control.
+///
+/// NMIs `shootdown` sent were already handled at the VM exit, the others belong to the guest and are
+/// queued, `nmi::before_vm_entry` injects them once the guest can take them.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+fn handle_nmi(vm: &mut Vm) {
+    if shootdown::take_nmi(vm.apic_id) {
+        log::trace!("Shootdown NMI taken");
+        return;
+    }
+
+    nmi::queue_current();
+}
+
 /*
 /// Handles breakpoint (`#BP`) exceptions specifically.
 ///
@@ -110,7 +140,7 @@ fn handle_breakpoint_exception(guest_registers: &mut GuestRegisters, vm: &mut Vm
 
         log::debug!("Breakpoint (int3) hook handled successfully!");
     } else {
-        EventInjection::vmentry_inject_bp();
+        vm.events.inject_exception(ExceptionInterrupt::Breakpoint as u8, None);
         log::debug!("Breakpoint exception handled successfully!");
     };
 }
@@ -121,13 +151,17 @@ fn handle_breakpoint_exception(guest_registers: &mut GuestRegisters, vm: &mut Vm
 /// This function is invoked when the VM attempts to execute an invalid or undefined
 /// opcode. It injects an undefined opcode exception into the VM.
 ///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the VM.
+///
 /// # Returns
 ///
 /// * `ExitType::Continue` - Indicating that VM execution should continue.
-pub fn handle_undefined_opcode_exception() -> ExitType {
+pub fn handle_undefined_opcode_exception(vm: &mut Vm) -> ExitType {
     log::debug!("Undefined Opcode Exception");
 
-    EventInjection::vmentry_inject_ud();
+    vm.events.inject_exception(ExceptionInterrupt::InvalidOpcode as u8, None);
 
     log::debug!("Undefined Opcode Exception handled successfully!");
 
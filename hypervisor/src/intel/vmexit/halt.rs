@@ -3,20 +3,42 @@
 //! This crate provides functionality to handle VM exits caused by specific instructions
 //! like `HLT`, facilitating appropriate responses and actions in a virtualized environment.
 //! Essential for managing VM execution flow and state in response to guest actions.
+//!
+//! HLT only exits with the `hlt_exiting` feature, for measurements of the guest's idle time. The guest
+//! is then entered in the HLT activity state after the instruction, and stays halted until an event
+//! wakes it the way it would bare metal: an external interrupt it takes natively, or an event the
+//! `EventInjector` injects, which it leaves the HLT state for. Interrupt-window and NMI-window exits
+//! end the HLT state too, so a queued interrupt waiting for the guest to set RFLAGS.IF only resumes it
+//! once the interrupt can be delivered.
 
-use {crate::intel::vmexit::ExitType, log::trace};
+use {
+    crate::intel::{state::GuestActivityState, support::vmwrite, vmexit::ExitType},
+    log::trace,
+    x86::vmx::vmcs,
+};
 
 /// Handles the VM exit caused by a `HLT` instruction.
 ///
 /// Responds to a `HLT` instruction executed by the guest by incrementing the instruction
-/// pointer (RIP) to continue execution after the `HLT`. This ensures the virtual machine
-/// does not halt and continues processing subsequent instructions.
+/// pointer (RIP) past the `HLT` and entering the guest in the HLT activity state, so it
+/// halts until the next interrupt. A processor that can't enter the HLT state resumes the
+/// guest right away, its idle loop spins instead.
+///
+/// The #GP of a `HLT` outside ring 0 takes priority over the VM exit.
 ///
 /// # Returns
 ///
 /// Returns `ExitType::IncrementRIP` to indicate that the VM's instruction pointer should
-/// be incremented to continue execution.
+/// be incremented past the `HLT`.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.7.2 Activity State
 pub fn handle_halt() -> ExitType {
     trace!("Handling HLT VM exit...");
+
+    // Without support for the HLT state the guest continues after the HLT, like it did before HLT exiting.
+    if GuestActivityState::Hlt.is_supported() {
+        vmwrite(vmcs::guest::ACTIVITY_STATE, GuestActivityState::Hlt as u32);
+    }
+
     ExitType::IncrementRIP
 }
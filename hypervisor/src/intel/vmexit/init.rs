@@ -6,22 +6,28 @@
 //! Credits to Satoshi Tanada: https://github.com/tandasat/MiniVisorPkg/blob/master/Sources/HostMain.c
 
 use {
-    crate::intel::{
-        capture::GuestRegisters,
-        invvpid::invvpid_single_context,
-        segmentation::VmxSegmentAccessRights,
-        state::GuestActivityState,
-        support::{cr2_write, dr0_write, dr1_write, dr2_write, dr3_write, dr6_write, rdmsr, vmread, vmwrite},
-        vmexit::ExitType,
+    crate::{
+        error::HypervisorError,
+        intel::{
+            cr_shadow,
+            debug_regs::{DebugRegisters, DR7_INIT},
+            events::EventInjector,
+            invvpid::TlbScope,
+            segmentation::VmxSegmentAccessRights,
+            state::GuestActivityState,
+            support::{cr2_write, read_effective_guest_cr0, vmread, vmwrite},
+            vm::Vm,
+            vmexit::ExitType,
+        },
+        stats,
     },
+    log::error,
     x86::{
         bits64::rflags,
-        controlregs::Cr0,
-        msr::{IA32_VMX_CR0_FIXED0, IA32_VMX_CR0_FIXED1, IA32_VMX_CR4_FIXED0, IA32_VMX_CR4_FIXED1},
         segmentation::{CodeSegmentType, DataSegmentType, SystemDescriptorTypes64},
-        vmx::vmcs::{self, control::SecondaryControls},
+        vmx::vmcs,
     },
-    x86_64::registers::control::Cr4Flags,
+    x86_64::registers::control::Cr0Flags,
 };
 
 /// Handles the INIT signal by initializing processor state according to Intel SDM.
@@ -30,14 +36,41 @@ use {
 /// setting registers and segment selectors to their startup values. This ensures the guest VM is correctly
 /// initialized in line with the MP initialization protocol.
 ///
+/// The guest is then left in the wait-for-SIPI state, whose SIPIs exit to `handle_sipi_signal`. This
+/// is how the operating system starts the application processors virtualized before it took over:
+/// INIT and SIPI always exit in VMX non-root operation, so the processor stays in VMX operation with
+/// its VMCS, EPTs and hooks, only the guest state is reset.
+///
 /// # Arguments
 ///
-/// - `guest_registers`: A mutable reference to the guest's general-purpose registers.
+/// - `vm`: A mutable reference to the VM.
 ///
 /// # Returns
 ///
-/// Returns `ExitType::Continue` to indicate the VM should continue execution post-initialization.
-pub fn handle_init_signal(guest_registers: &mut GuestRegisters) -> ExitType {
+/// Returns `ExitType::Continue` to indicate the VM should continue execution post-initialization,
+/// or the error of completing a pending single-step.
+pub fn handle_init_signal(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
+    stats::record_init_signal();
+
+    //
+    // The stepped code is gone, its hook is re-armed now instead of after the next instruction.
+    //
+    vm.finish_single_step()?;
+
+    //
+    // INIT discards the events waiting for the guest and any blocking of them.
+    //
+    vm.events = EventInjector::new();
+    vmwrite(vmcs::guest::INTERRUPTIBILITY_STATE, 0u64);
+    vmwrite(vmcs::guest::PENDING_DBG_EXCEPTIONS, 0u64);
+
+    //
+    // Without unrestricted guest, the state a guest in real or unpaged protected mode runs with is undone first.
+    //
+    vm.reset_guest_mode();
+
+    let guest_registers = &mut vm.guest_registers;
+
     //
     // Initializes the processor to the state after INIT as described in the Intel SDM.
     //
@@ -49,16 +82,16 @@ pub fn handle_init_signal(guest_registers: &mut GuestRegisters) -> ExitType {
     vmwrite(vmcs::guest::RFLAGS, guest_registers.rflags);
     guest_registers.rip = 0xfff0u64;
     vmwrite(vmcs::guest::RIP, guest_registers.rip);
-    vmwrite(vmcs::control::CR0_READ_SHADOW, 0u64);
     cr2_write(0);
     vmwrite(vmcs::guest::CR3, 0u64);
-    vmwrite(vmcs::control::CR4_READ_SHADOW, 0u64);
 
     //
-    // Actual guest CR0 and CR4 must fulfill requirements for VMX. Apply those.
+    // CR0 is 60000010H after reset, INIT keeps CD and NW. Actual guest CR0 and CR4 must fulfill
+    // requirements for VMX, `cr_shadow` applies those and keeps the values reset in the read shadows.
     //
-    vmwrite(vmcs::guest::CR0, adjust_guest_cr0(Cr0::CR0_EXTENSION_TYPE));
-    vmwrite(vmcs::guest::CR4, adjust_cr4());
+    let caching = read_effective_guest_cr0() & (Cr0Flags::CACHE_DISABLE.bits() | Cr0Flags::NOT_WRITE_THROUGH.bits());
+    cr_shadow::write_guest_cr0(Cr0Flags::EXTENSION_TYPE.bits() | caching);
+    cr_shadow::write_guest_cr4(0);
 
     //
     // Set the CS segment registers to their initial state (ExecuteReadAccessed).
@@ -158,14 +191,10 @@ pub fn handle_init_signal(guest_registers: &mut GuestRegisters) -> ExitType {
     vmwrite(vmcs::guest::TR_ACCESS_RIGHTS, access_rights.0);
 
     //
-    // DR0, DR1, DR2, DR3, DR6, DR7
+    // DR0, DR1, DR2, DR3, DR6, DR7. The guest's DR0-DR3 and DR6 are loaded from `debug_regs` at the VM entry.
     //
-    dr0_write(0u64);
-    dr1_write(0u64);
-    dr2_write(0u64);
-    dr3_write(0u64);
-    dr6_write(0xffff0ff0u64);
-    vmwrite(vmcs::guest::DR7, 0x400u64);
+    vm.debug_regs.guest = DebugRegisters::INIT;
+    vmwrite(vmcs::guest::DR7, DR7_INIT);
 
     //
     // Set the guest registers r8-r15 to 0.
@@ -203,77 +232,22 @@ pub fn handle_init_signal(guest_registers: &mut GuestRegisters) -> ExitType {
     //
     // Invalidate TLB for current VPID
     //
-    invvpid_single_context(vmread(vmcs::control::VPID) as _);
+    vm.flush_translations(TlbScope::Context);
 
     //
-    // Set the activity state to "Wait for SIPI".
+    // Set the activity state to "Wait for SIPI". A processor without it fails the VM entry, which logs the state.
     //
-    vmwrite(vmcs::guest::ACTIVITY_STATE, GuestActivityState::WaitForSipi as u32);
-
-    ExitType::Continue
-}
-
-/// Adjusts guest CR0 considering UnrestrictedGuest feature and fixed MSRs.
-///
-/// Modifies the guest's CR0 register to ensure it meets VMX operation constraints, particularly
-/// when the UnrestrictedGuest feature is enabled. Adjusts for protection and paging enable bits.
-///
-/// # Arguments
-///
-/// - `cr0`: The original CR0 register value from the guest.
-///
-/// # Returns
-///
-/// Returns the adjusted CR0 value as a `u64`.
-fn adjust_guest_cr0(cr0: Cr0) -> u64 {
-    // Adjust the CR0 register according to the fixed0 and fixed1 MSR values.
-    let mut new_cr0 = adjust_cr0(cr0);
-
-    // Read the secondary processor-based VM-execution controls to check for UnrestrictedGuest support.
-    let secondary_proc_based_ctls2 = vmread(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS);
-    let unrestricted_guest = secondary_proc_based_ctls2 as u32 & SecondaryControls::UNRESTRICTED_GUEST.bits() != 0;
-
-    if unrestricted_guest {
-        // if the guest is unrestricted, only set these bits if the guest requested them to be set
-        new_cr0 &= !(Cr0::CR0_PROTECTED_MODE | Cr0::CR0_ENABLE_PAGING);
-        new_cr0 |= cr0 & (Cr0::CR0_PROTECTED_MODE | Cr0::CR0_ENABLE_PAGING);
+    if !GuestActivityState::WaitForSipi.is_supported() {
+        error!("The processor can't enter the wait-for-SIPI activity state, the AP can't be started");
     }
+    vmwrite(vmcs::guest::ACTIVITY_STATE, GuestActivityState::WaitForSipi as u32);
 
-    new_cr0.bits() as u64
-}
-
-/// Adjusts guest CR0 considering UnrestrictedGuest feature and fixed MSRs.
-///
-/// Modifies the guest's CR0 register to ensure it meets VMX operation constraints, particularly
-/// when the UnrestrictedGuest feature is enabled. Adjusts for protection and paging enable bits.
-///
-/// # Arguments
-///
-/// - `cr0`: The original CR0 register value from the guest.
-///
-/// # Returns
-///
-/// Returns the adjusted CR0 value as a `u64`.
-fn adjust_cr0(cr0: Cr0) -> Cr0 {
-    let fixed0_cr0 = Cr0::from_bits_truncate(rdmsr(IA32_VMX_CR0_FIXED0) as usize);
-    let fixed1_cr0 = Cr0::from_bits_truncate(rdmsr(IA32_VMX_CR0_FIXED1) as usize);
-    let new_cr0 = (cr0 & fixed1_cr0) | fixed0_cr0;
-    new_cr0
-}
+    //
+    // Without unrestricted guest, the real mode INIT left the guest in runs in virtual-8086 mode.
+    //
+    vm.sync_guest_mode();
 
-/// Adjusts CR4 for VMX operation, considering fixed bit requirements.
-///
-/// Sets or clears CR4 bits based on the IA32_VMX_CR4_FIXED0/1 MSRs to ensure the register
-/// meets VMX operation constraints.
-///
-/// # Returns
-///
-/// Returns the adjusted CR4 value as a `u64`.
-fn adjust_cr4() -> u64 {
-    let fixed0_cr4 = Cr4Flags::from_bits_truncate(rdmsr(IA32_VMX_CR4_FIXED0));
-    let zero_cr4 = Cr4Flags::empty();
-    let new_cr4 = (zero_cr4 & Cr4Flags::from_bits_truncate(rdmsr(IA32_VMX_CR4_FIXED1))) | fixed0_cr4;
-    new_cr4.bits()
+    Ok(ExitType::Continue)
 }
 
 /// Retrieves CPU feature information using the CPUID instruction.
@@ -0,0 +1,16 @@
+//! Handles interrupt-window VM exits, which the event injector asks for while a maskable interrupt waits.
+
+use {crate::intel::vmexit::ExitType, log::trace};
+
+/// Handles the VM exit caused by "interrupt-window exiting".
+///
+/// The guest can take a maskable interrupt now, `EventInjector::flush` injects the waiting one. A guest
+/// halted by `handle_halt` leaves the HLT state for it.
+///
+/// # Returns
+///
+/// Returns `ExitType::Continue`, the exit happened before an instruction.
+pub fn handle_interrupt_window() -> ExitType {
+    trace!("Handling interrupt-window VM exit...");
+    ExitType::Continue
+}
@@ -0,0 +1,89 @@
+//! Handles the INVLPG and INVPCID VM exits of `HvFeatureFlags::TRANSLATION_CACHE`.
+//!
+//! The translation cache of `page_walk` has to drop walks whenever the guest invalidates its TLB. With the feature,
+//! INVLPG exits, which with "enable INVPCID" makes INVPCID exit as well, and so does MOV to CR3, see `vmexit::cr`.
+//! Both instructions are then performed on behalf of the guest with `Vm::flush_translations`, which invalidates the
+//! translations of its VPID. Their privilege checks come before the VM exit, the guest runs at CPL 0 here.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.1.3 Instructions That Cause VM Exits Conditionally
+
+use {
+    crate::intel::{efer::code_64bit, invvpid::TlbScope, support::vmread, vm::Vm, vmerror::ExceptionInterrupt, vmexit::ExitType},
+    log::trace,
+    x86::vmx::vmcs,
+};
+
+/// Handles the VM exit caused by an `INVLPG` instruction, whose exit qualification is the linear address.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - To move past the `INVLPG` instruction.
+pub fn handle_invlpg(vm: &mut Vm) -> ExitType {
+    let address = vmread(vmcs::ro::EXIT_QUALIFICATION);
+    trace!("Handling INVLPG of {:#x}", address);
+
+    vm.flush_translations(TlbScope::Address(address));
+    ExitType::IncrementRIP
+}
+
+/// Handles the VM exit caused by an `INVPCID` instruction.
+///
+/// The INVPCID type is in the register encoded in bits 31:28 of the VM-exit instruction information. The descriptor
+/// in memory is never read, every type is served by invalidating at least as much as it asks for.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - To move past the `INVPCID` instruction, `ExitType::Continue` after injecting #GP(0)
+///   for an invalid type.
+pub fn handle_invpcid(vm: &mut Vm) -> ExitType {
+    let register = (vmread(vmcs::ro::VMEXIT_INSTRUCTION_INFO) >> 28) & 0xF;
+    let value = vm.guest_registers.gpr(register);
+    let invpcid_type = if code_64bit() { value } else { value & 0xFFFF_FFFF };
+    trace!("Handling INVPCID of type {}", invpcid_type);
+
+    let Some(scope) = invpcid_scope(invpcid_type) else {
+        vm.events.inject_exception(ExceptionInterrupt::GeneralProtectionFault as u8, Some(0));
+        return ExitType::Continue;
+    };
+
+    vm.flush_translations(scope);
+    ExitType::IncrementRIP
+}
+
+/// Returns the translations an INVPCID type invalidates, `None` if the type raises #GP(0).
+///
+/// The individual-address and single-context types only invalidate non-global translations of the PCID in the
+/// descriptor. Without reading it, they invalidate the non-global translations of every PCID.
+///
+/// # Arguments
+///
+/// * `invpcid_type` - The INVPCID type.
+fn invpcid_scope(invpcid_type: u64) -> Option<TlbScope> {
+    match invpcid_type {
+        0 | 1 | 3 => Some(TlbScope::NonGlobal),
+        2 => Some(TlbScope::Context),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invpcid_types_map_to_scopes() {
+        assert_eq!(invpcid_scope(0), Some(TlbScope::NonGlobal));
+        assert_eq!(invpcid_scope(2), Some(TlbScope::Context));
+        assert_eq!(invpcid_scope(3), Some(TlbScope::NonGlobal));
+        assert_eq!(invpcid_scope(4), None);
+        assert_eq!(invpcid_scope(1 << 32), None);
+    }
+}
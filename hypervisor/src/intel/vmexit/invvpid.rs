@@ -1,6 +1,9 @@
 //! Manages VM exits related to Virtual Processor Identifier (VPID) operations in Intel VT-x technology.
 
-use crate::intel::{invvpid::invvpid_all_contexts, vmexit::ExitType};
+use crate::intel::{
+    invvpid::{self, TlbScope},
+    vmexit::ExitType,
+};
 
 /// Handles the INVVPID VM exit.
 ///
@@ -13,7 +16,7 @@ pub fn handle_invvpid() -> ExitType {
     log::debug!("Handling INVVPID VM exit...");
 
     // Invalidate all VPID contexts to ensure consistency of TLB entries with the current VM state.
-    invvpid_all_contexts();
+    invvpid::flush(0, TlbScope::AllContexts);
 
     log::debug!("INVVPID VMEXIT handled successfully!");
 
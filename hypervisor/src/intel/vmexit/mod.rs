@@ -1,19 +1,24 @@
 pub mod commands;
 pub mod cpuid;
 pub mod cr;
+pub mod dr;
 pub mod ept_misconfiguration;
 pub mod ept_violation;
 pub mod exception;
 pub mod halt;
 pub mod init;
+pub mod interrupt_window;
 pub mod invd;
 pub mod invept;
+pub mod invlpg;
 pub mod invvpid;
 pub mod msr;
 pub mod mtf;
+pub mod nmi_window;
 pub mod rdtsc;
 pub mod sipi;
 pub mod vmcall;
+pub mod vmcall_dispatch;
 pub mod vmxon;
 pub mod xsetbv;
 
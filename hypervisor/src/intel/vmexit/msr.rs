@@ -11,27 +11,28 @@ use {
     crate::{
         error::HypervisorError,
         intel::{
-            bitmap::{MsrAccessType, MsrOperation},
-            events::EventInjection,
+            bitmap::{MsrAccessType, MsrAction, MsrOperation, DEFAULT_MSR_POLICY, HYPERV_MSRS},
+            cpuid_policy::{VmxExposure, VMX_CAPABILITY_MSRS},
+            efer::{checked_efer_write, EferFeatures},
             hooks::hook_manager::SHARED_HOOK_MANAGER,
-            support::{rdmsr, wrmsr},
+            hyperv::{self, fill_hypercall_page, SyntheticWrite, SYNTHETIC_MSRS},
+            support::{rdmsr, read_effective_guest_cr0, wrmsr},
             vm::Vm,
+            vmerror::ExceptionInterrupt,
             vmexit::ExitType,
         },
     },
-    bit_field::BitField,
-    core::ops::RangeInclusive,
     log::*,
     x86::msr,
+    x86_64::registers::control::Cr0Flags,
 };
 
 /// Handles MSR access based on the provided access type.
 ///
-/// This function checks if the requested MSR address is within a valid
-/// range, a reserved range, or a synthetic MSR range used by Hyper-V.
-/// For valid MSRs, the function will either read or write to the MSR based
-/// on the access type. For reserved or synthetic MSRs, a general protection
-/// fault is injected.
+/// The action comes from `DEFAULT_MSR_POLICY`, the table the MSR bitmap was built from. MSRs the policy
+/// rejects, e.g. reserved or synthetic ones and writes to the VMX capability MSRs, get a general protection
+/// fault, except for the synthetic MSRs implemented while masquerading as Hyper-V. All other MSRs are read or
+/// written based on the access type.
 ///
 /// # Arguments
 ///
@@ -50,36 +51,20 @@ pub fn handle_msr_access(vm: &mut Vm, access_type: MsrAccessType) -> Result<Exit
     // Define the mask for the low 32-bits of the MSR value
     const MSR_MASK_LOW: u64 = u32::MAX as u64;
 
-    // Define the VMX lock bit for IA32_FEATURE_CONTROL MSR
-    const VMX_LOCK_BIT: u64 = 0;
-    const VMXON_OUTSIDE_SMX: u64 = 2;
-
     let msr_id = vm.guest_registers.rcx as u32;
     let msr_value = (vm.guest_registers.rdx << 32) | (vm.guest_registers.rax & MSR_MASK_LOW);
 
-    // Determine if the MSR address is valid, reserved, or synthetic (EasyAntiCheat and Battleye invalid MSR checks)
-    // by checking if the MSR address is in the Hyper-V range or outside other valid ranges
-
-    // Define the range for valid MSR access and Hyper-V MSRs
-    const MSR_VALID_RANGE_LOW: RangeInclusive<u32> = 0x00000000..=0x00001FFF;
-    const MSR_VALID_RANGE_HIGH: RangeInclusive<u32> = 0xC0000000..=0xC0001FFF;
-    const MSR_HYPERV_RANGE: RangeInclusive<u32> = 0x40000000..=0x400000FF;
-
     trace!("MSR access attempted: {:#x}", msr_id);
 
-    #[cfg(feature = "vmware")]
-    if !MSR_VALID_RANGE_LOW.contains(&msr_id) && !MSR_VALID_RANGE_HIGH.contains(&msr_id) && MSR_HYPERV_RANGE.contains(&msr_id) {
-        // In VMware, do not inject #GP for MSRs within the Hyper-V range
-        trace!("Invalid MSR access attempted: {:#x}", msr_id);
-        EventInjection::vmentry_inject_gp(0);
-        return Ok(ExitType::Continue);
+    // Masquerading as Hyper-V, its synthetic MSRs are served before the policy rejects them.
+    if hyperv::masquerading() && HYPERV_MSRS.contains(&msr_id) {
+        return Ok(handle_synthetic_msr(vm, msr_id, msr_value, access_type));
     }
 
-    #[cfg(not(feature = "vmware"))]
-    if !(MSR_VALID_RANGE_LOW.contains(&msr_id) || MSR_VALID_RANGE_HIGH.contains(&msr_id)) || MSR_HYPERV_RANGE.contains(&msr_id) {
-        // On real hardware, inject #GP if MSR is in the Hyper-V range or outside the valid ranges
+    // Reserved and synthetic MSRs are rejected as on hardware (EasyAntiCheat and Battleye invalid MSR checks).
+    if DEFAULT_MSR_POLICY.action(msr_id, access_type) == MsrAction::InjectGp {
         trace!("Invalid MSR access attempted: {:#x}", msr_id);
-        EventInjection::vmentry_inject_gp(0);
+        vm.events.inject_exception(ExceptionInterrupt::GeneralProtectionFault as u8, Some(0));
         return Ok(ExitType::Continue);
     }
 
@@ -97,16 +82,25 @@ pub fn handle_msr_access(vm: &mut Vm, access_type: MsrAccessType) -> Result<Exit
                     vm.guest_registers.original_lstar
                 }
 
-                // Simulate IA32_FEATURE_CONTROL as locked: VMX locked bit set, VMX outside SMX clear.
-                // Set lock bit, indicating that feature control is locked.
+                // Simulate IA32_FEATURE_CONTROL as locked, with VMX outside SMX as the CPUID policy reports VMX.
                 // Credits to @vmctx
                 msr::IA32_FEATURE_CONTROL => {
                     trace!("IA32_FEATURE_CONTROL read attempted with MSR value: {:#x}", msr_value);
-                    let mut result_value = rdmsr(msr_id as _);
-                    result_value.set_bit(VMX_LOCK_BIT as usize, true);
-                    result_value.set_bit(VMXON_OUTSIDE_SMX as usize, false);
-                    result_value
+                    VmxExposure::current().feature_control(rdmsr(msr_id))
                 }
+
+                // The VMX capability MSRs only exist as far as the VMX the CPUID policy reports enumerates them.
+                msr if VMX_CAPABILITY_MSRS.contains(&msr) => {
+                    let Some(value) = VmxExposure::current().capability(msr, rdmsr) else {
+                        trace!("VMX capability MSR read the guest capabilities do not enumerate: {:#x}", msr);
+                        vm.events.inject_exception(ExceptionInterrupt::GeneralProtectionFault as u8, Some(0));
+                        return Ok(ExitType::Continue);
+                    };
+                    value
+                }
+
+                // The guest's IA32_EFER is kept apart from the host's, see `intel::efer`.
+                msr::IA32_EFER => vm.guest_efer(),
                 _ => rdmsr(msr_id),
             };
 
@@ -115,7 +109,16 @@ pub fn handle_msr_access(vm: &mut Vm, access_type: MsrAccessType) -> Result<Exit
         }
         // Credits: jessiep_ and https://revers.engineering/patchguard-detection-of-hypervisor-based-instrospection-p2/
         MsrAccessType::Write => {
-            if msr_id == msr::IA32_LSTAR {
+            if msr_id == msr::IA32_EFER {
+                // Only the guest-state field is written, the switch into IA-32e mode waits for CR0.PG.
+                let paging = read_effective_guest_cr0() & Cr0Flags::PAGING.bits() != 0;
+                let Some(value) = checked_efer_write(msr_value, vm.guest_efer(), paging, EferFeatures::read()) else {
+                    trace!("Invalid IA32_EFER write attempted with MSR value: {:#x}", msr_value);
+                    vm.events.inject_exception(ExceptionInterrupt::GeneralProtectionFault as u8, Some(0));
+                    return Ok(ExitType::Continue);
+                };
+                vm.set_guest_efer(value);
+            } else if msr_id == msr::IA32_LSTAR {
                 trace!("IA32_LSTAR write attempted with MSR value: {:#x}", msr_value);
                 // trace!("GuestRegisters Original LSTAR value: {:#x}", vm.guest_registers.original_lstar);
                 // trace!("GuestRegisters Hook LSTAR value: {:#x}", vm.guest_registers.hook_lstar);
@@ -129,7 +132,7 @@ pub fn handle_msr_access(vm: &mut Vm, access_type: MsrAccessType) -> Result<Exit
                 trace!("Unhooked MSR_IA32_LSTAR");
 
                 // Get and set the ntoskrnl.exe base address and size, to be used for hooking later in `CpuidLeaf::CacheInformation` or by the guest client.
-                hook_manager.set_kernel_base_and_size(msr_value)?;
+                hook_manager.set_kernel_base_and_size(vm, msr_value)?;
 
                 // Check if it's the first time we're intercepting a write to LSTAR.
                 // If so, store the value being written as the original LSTAR value.
@@ -184,3 +187,46 @@ pub fn handle_msr_access(vm: &mut Vm, access_type: MsrAccessType) -> Result<Exit
     debug!("MSR VMEXIT handled successfully.");
     Ok(ExitType::IncrementRIP)
 }
+
+/// Handles an access to a synthetic MSR of Hyper-V while masquerading as it, see `intel::hyperv`.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance.
+/// * `msr_id` - The MSR in `HYPERV_MSRS`.
+/// * `msr_value` - EDX:EAX of WRMSR.
+/// * `access_type` - The type of MSR access (read or write).
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - To move past the `rdmsr` or `wrmsr` instruction in the VM.
+/// * `ExitType::Continue` - The access raised #GP(0).
+fn handle_synthetic_msr(vm: &mut Vm, msr_id: u32, msr_value: u64, access_type: MsrAccessType) -> ExitType {
+    const MSR_MASK_LOW: u64 = u32::MAX as u64;
+
+    let mut synthetic_msrs = SYNTHETIC_MSRS.lock();
+    let handled = match access_type {
+        MsrAccessType::Read => synthetic_msrs.read(msr_id).map(|value| {
+            vm.guest_registers.rax = value & MSR_MASK_LOW;
+            vm.guest_registers.rdx = value >> 32;
+        }),
+        MsrAccessType::Write => synthetic_msrs.write(msr_id, msr_value).map(|write| {
+            if let SyntheticWrite::FillHypercallPage(page) = write {
+                let hook_manager = SHARED_HOOK_MANAGER.lock();
+                if fill_hypercall_page(page, &hook_manager.allocated_memory_ranges) {
+                    debug!("Hypercall page enabled at {:#x}", page);
+                } else {
+                    warn!("Not filling the hypercall page at {:#x} in hypervisor memory", page);
+                }
+            }
+        }),
+    };
+
+    if handled.is_none() {
+        trace!("Unimplemented synthetic MSR access attempted: {:#x}", msr_id);
+        vm.events.inject_exception(ExceptionInterrupt::GeneralProtectionFault as u8, Some(0));
+        return ExitType::Continue;
+    }
+
+    ExitType::IncrementRIP
+}
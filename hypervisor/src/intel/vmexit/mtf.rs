@@ -1,24 +1,149 @@
+//! Single-steps guest instructions with the monitor trap flag (MTF) and acts once they executed.
+//!
+//! `Vm::single_step` sets the "monitor trap flag" VM-execution control and records a `PostStepAction`, like
+//! re-arming a hook whose page was mapped readable for one instruction. The MTF VM exit after the step, or
+//! the VM exit of an instruction the hypervisor emulated instead, performs the action and clears the control.
+//!
+//! Interrupts would run their handler with the step still pending, so every stepped instruction is entered
+//! with blocking by STI in the guest interruptibility state instead of clearing RFLAGS.IF, which the guest
+//! could observe. Pending interrupts are delivered right after the step, with the action performed.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.5.2 Monitor Trap Flag
+
 use {
     crate::{
         error::HypervisorError,
         intel::{
-            addresses::PhysicalAddress,
             ept::AccessType,
-            hooks::hook_manager::SHARED_HOOK_MANAGER,
+            shootdown::{self, Scope},
             support::{vmread, vmwrite},
             vm::Vm,
             vmexit::ExitType,
         },
     },
     log::*,
-    x86::{current::paging::PAddr, vmx::vmcs},
-    x86_64::registers::rflags::RFlags,
+    x86::vmx::vmcs,
 };
 
+/// [Bit 0] Blocking by STI, the next instruction cannot be interrupted.
+const INTERRUPTIBILITY_STI: u64 = 1 << 0;
+
+/// [Bit 1] Blocking by MOV SS, which must not be combined with blocking by STI.
+const INTERRUPTIBILITY_MOV_SS: u64 = 1 << 1;
+
+/// [Bit 9] RFLAGS.IF, blocking by STI requires it to be set.
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// What to do once the single-stepped instructions executed.
+#[derive(Debug, Clone, Copy)]
+pub enum PostStepAction {
+    /// Map the shadow page of a hooked page execute-only again, re-enabling its hooks.
+    RearmHook {
+        /// The guest physical address of the hooked page.
+        guest_page_pa: u64,
+        /// The host physical address of its shadow page.
+        shadow_page_pa: u64,
+    },
+
+    /// Restore the EPT permissions of a 4KB page.
+    RestorePermissions {
+        /// The guest physical address of the page.
+        guest_pa: u64,
+        /// The permissions to restore.
+        access_type: AccessType,
+    },
+
+    /// Write a byte of guest memory, like the original byte of a breakpoint to clear it.
+    WriteByte {
+        /// The guest physical address of the byte.
+        guest_pa: u64,
+        /// The byte to write.
+        byte: u8,
+    },
+}
+
+/// A single-step in progress.
+#[derive(Debug, Clone, Copy)]
+pub struct SingleStep {
+    /// The instructions that still have to execute.
+    pub remaining: u64,
+
+    /// The action once they did.
+    pub then: PostStepAction,
+}
+
+impl SingleStep {
+    /// Creates a step over `instructions` instructions, at least one.
+    pub fn new(instructions: u64, then: PostStepAction) -> Self {
+        Self {
+            remaining: instructions.max(1),
+            then,
+        }
+    }
+
+    /// Counts one executed instruction.
+    ///
+    /// # Returns
+    ///
+    /// The action once the last instruction executed, `None` while more are left.
+    pub fn executed(&mut self) -> Option<PostStepAction> {
+        self.remaining = self.remaining.saturating_sub(1);
+        if self.remaining == 0 {
+            Some(self.then)
+        } else {
+            None
+        }
+    }
+}
+
+impl Vm {
+    /// Lets exactly one guest instruction execute before performing `then`.
+    ///
+    /// # Arguments
+    ///
+    /// * `then` - The action once the instruction executed.
+    pub fn single_step(&mut self, then: PostStepAction) -> Result<(), HypervisorError> {
+        self.single_step_over(1, then)
+    }
+
+    /// Lets `instructions` guest instructions execute before performing `then`.
+    ///
+    /// A step that is still pending is completed first, its action performed early is merely a spurious exit later.
+    ///
+    /// # Arguments
+    ///
+    /// * `instructions` - The number of instructions to step, at least one.
+    /// * `then` - The action once they executed.
+    pub fn single_step_over(&mut self, instructions: u64, then: PostStepAction) -> Result<(), HypervisorError> {
+        if let Some(pending) = self.single_step.take() {
+            trace!("Completing the pending single-step early: {:?}", pending);
+            perform(self, pending.then)?;
+        }
+
+        trace!("Single-stepping {} instructions, then {:?}", instructions, then);
+        self.single_step = Some(SingleStep::new(instructions, then));
+        set_monitor_trap_flag(true);
+        block_interrupts_for_one_instruction(self.events.is_pending());
+
+        Ok(())
+    }
+
+    /// Completes a pending single-step right away, performing its action, for an INIT that ends the stepped code.
+    pub fn finish_single_step(&mut self) -> Result<(), HypervisorError> {
+        let Some(pending) = self.single_step.take() else {
+            return Ok(());
+        };
+
+        trace!("Completing the pending single-step early: {:?}", pending);
+        set_monitor_trap_flag(false);
+        perform(self, pending.then)
+    }
+}
+
 /// Handles the Monitor Trap Flag (MTF) VM exit.
 ///
-/// This function ensures single stepping through overwritten instructions on a hooked function
-/// and restores the original execution flow once all instructions have been executed.
+/// This function counts the single-stepped instruction and performs the pending action once all
+/// instructions have been executed.
 ///
 /// # Parameters
 /// * `vm`: A mutable reference to the virtual machine instance.
@@ -27,55 +152,79 @@ use {
 /// * `Result<ExitType, HypervisorError>`: Ok with the appropriate exit type or an error.
 pub fn handle_monitor_trap_flag(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
     trace!("Handling Monitor Trap Flag exit.");
+    trace!("Guest RIP: {:#x}", vm.guest_registers.rip);
 
-    if let Some(counter) = vm.mtf_counter.as_mut() {
-        trace!("Guest RIP: {:#x}", vm.guest_registers.rip);
-        trace!("MTF counter before decrement: {}", *counter);
-        *counter = counter.saturating_sub(1); // Safely decrement the counter
-        trace!("MTF counter after decremented: {}", *counter);
-
-        // If the counter is zero, we have completed the single-stepping process, restore the hook.
-        if *counter == 0 {
-            set_monitor_trap_flag(false);
-
-            let guest_pa = PAddr::from(PhysicalAddress::pa_from_va_with_current_cr3(vm.guest_registers.rip)?);
-            trace!("Guest PA: {:#x}", guest_pa.as_u64());
-
-            let guest_page_pa = guest_pa.align_down_to_base_page();
-            trace!("Guest Page PA: {:#x}", guest_page_pa.as_u64());
+    if vm.single_step.is_none() {
+        error!("No active single-step found, possibly an error in state management.");
+        set_monitor_trap_flag(false);
+        return Err(HypervisorError::MtfCounterNotSet);
+    }
 
-            let guest_large_page_pa = guest_page_pa.align_down_to_large_page();
-            trace!("Guest Large Page PA: {:#x}", guest_large_page_pa.as_u64());
+    instruction_completed(vm)?;
 
-            let mut hook_manager = SHARED_HOOK_MANAGER.lock();
+    Ok(ExitType::Continue)
+}
 
-            let shadow_page_pa = PAddr::from(
-                hook_manager
-                    .memory_manager
-                    .get_shadow_page_as_ptr(guest_page_pa.as_u64())
-                    .ok_or(HypervisorError::ShadowPageNotFound)?,
-            );
-            trace!("Shadow Page PA: {:#x}", shadow_page_pa);
+/// Counts an instruction that completed while single-stepping, either executed by the guest or emulated by the
+/// handler of its VM exit, and performs the pending action after the last one.
+///
+/// Without counting emulated instructions, the guest would execute one more instruction before the MTF VM exit.
+///
+/// # Parameters
+/// * `vm`: A mutable reference to the virtual machine instance.
+pub fn instruction_completed(vm: &mut Vm) -> Result<(), HypervisorError> {
+    let Some(step) = vm.single_step.as_mut() else {
+        return Ok(());
+    };
 
-            let pre_alloc_pt = hook_manager
-                .memory_manager
-                .get_page_table_as_mut(guest_large_page_pa.as_u64())
-                .ok_or(HypervisorError::PageTableNotFound)?;
+    trace!("Single-step instructions left before this one: {}", step.remaining);
+    match step.executed() {
+        Some(then) => {
+            vm.single_step = None;
+            set_monitor_trap_flag(false);
+            perform(vm, then)
+        }
+        None => {
+            block_interrupts_for_one_instruction(vm.events.is_pending());
+            Ok(())
+        }
+    }
+}
 
-            // Restore the hook to continue monitoring
-            vm.primary_ept
-                .swap_page(guest_pa.align_down_to_base_page().as_u64(), shadow_page_pa.as_u64(), AccessType::EXECUTE, pre_alloc_pt)?;
+/// Performs the action of a completed single-step.
+///
+/// # Parameters
+/// * `vm`: A mutable reference to the virtual machine instance.
+/// * `action`: The action to perform.
+fn perform(vm: &mut Vm, action: PostStepAction) -> Result<(), HypervisorError> {
+    trace!("Performing the post-step action: {:?}", action);
 
-            restore_guest_interrupt_flag(vm)?;
-        } else {
-            set_monitor_trap_flag(true); // Keep MTF enabled if there are more steps
+    match action {
+        PostStepAction::RearmHook {
+            guest_page_pa,
+            shadow_page_pa,
+        } => {
+            if vm.ve_active() {
+                // The execute view serves the next fetch, the primary EPT keeps the read-write view.
+                vm.primary_ept.swap_page(guest_page_pa, guest_page_pa, AccessType::READ_WRITE)?;
+            } else {
+                vm.primary_ept.swap_page(guest_page_pa, shadow_page_pa, AccessType::EXECUTE)?;
+            }
+        }
+        PostStepAction::RestorePermissions { guest_pa, access_type } => {
+            vm.primary_ept.modify_page_permissions(guest_pa, access_type)?;
+            vm.ept_views.sync(&vm.primary_ept, guest_pa)?;
+        }
+        PostStepAction::WriteByte { guest_pa, byte } => {
+            // The host identity maps the guest physical memory.
+            unsafe { (guest_pa as *mut u8).write_volatile(byte) };
         }
-    } else {
-        error!("No active MTF counter found, possibly an error in state management.");
-        return Err(HypervisorError::MtfCounterNotSet);
     }
 
-    Ok(ExitType::Continue)
+    // The stepped instructions may have cached the wider permissions.
+    shootdown::flush_ept(Scope::CurrentProcessor);
+
+    Ok(())
 }
 
 /// Set the monitor trap flag
@@ -99,61 +248,86 @@ pub fn set_monitor_trap_flag(set: bool) {
     trace!("Monitor Trap Flag set to: {}", set);
 }
 
-/// Sets or clears the Interrupt Flag (IF) in the guest's RFLAGS register based on the specified state,
-/// and stores the old RFLAGS value for potential restoration.
+/// Keeps maskable interrupts from being delivered before the next guest instruction.
 ///
-/// # Parameters
-/// * `vm`: A mutable reference to the virtual machine instance.
-/// * `enable`: If `true`, sets the Interrupt Flag; if `false`, clears the Interrupt Flag and optionally restores from old RFLAGS.
+/// With RFLAGS.IF clear the guest blocks them itself, and blocking by MOV SS or an injected event rule out
+/// blocking by STI, see 27.3.1.5 Checks on Guest Non-Register State.
 ///
-/// # Returns
-/// * `Result<(), HypervisorError>`: Ok if successful, Err if an error occurred during VMCS read/write operations.
-pub fn update_guest_interrupt_flag(vm: &mut Vm, enable: bool) -> Result<(), HypervisorError> {
-    trace!("Updating guest interrupt flag...");
+/// # Arguments
+///
+/// * `event_pending` - Whether the event injector holds an event, which the next VM entry may inject.
+fn block_interrupts_for_one_instruction(event_pending: bool) {
+    let interruptibility = vmread(vmcs::guest::INTERRUPTIBILITY_STATE);
+    if vmread(vmcs::guest::RFLAGS) & RFLAGS_IF == 0 || interruptibility & INTERRUPTIBILITY_MOV_SS != 0 || event_pending {
+        return;
+    }
 
-    // Retrieve the current RFLAGS from the VMCS guest state area
-    let current_rflags_bits = vmread(vmcs::guest::RFLAGS);
-    let mut current_rflags = RFlags::from_bits_retain(current_rflags_bits);
-    trace!("Current guest RFLAGS before update: {:#x}", current_rflags_bits);
+    vmwrite(vmcs::guest::INTERRUPTIBILITY_STATE, interruptibility | INTERRUPTIBILITY_STI);
+}
 
-    // Optionally save the current RFLAGS to old_rflags before modification
-    vm.old_rflags = Some(current_rflags_bits);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // Set or clear the Interrupt Flag based on the 'enable' parameter
-    if enable {
-        current_rflags.insert(RFlags::INTERRUPT_FLAG);
-    } else {
-        current_rflags.remove(RFlags::INTERRUPT_FLAG);
+    const REARM: PostStepAction = PostStepAction::RearmHook {
+        guest_page_pa: 0x1000,
+        shadow_page_pa: 0x2000,
+    };
+
+    #[test]
+    fn one_instruction_steps_complete_at_once() {
+        let mut step = SingleStep::new(1, REARM);
+        assert!(matches!(step.executed(), Some(PostStepAction::RearmHook { guest_page_pa: 0x1000, .. })));
+
+        // Stepping zero instructions still lets one execute.
+        assert!(SingleStep::new(0, REARM).executed().is_some());
     }
 
-    // Update the guest's RFLAGS register in the VMCS
-    vmwrite(vmcs::guest::RFLAGS, current_rflags.bits());
-    trace!("Updated guest RFLAGS: {:#x}", current_rflags.bits());
+    #[test]
+    fn longer_steps_count_every_instruction() {
+        let mut step = SingleStep::new(
+            3,
+            PostStepAction::WriteByte {
+                guest_pa: 0x4000,
+                byte: 0xCC,
+            },
+        );
+        assert!(step.executed().is_none());
+        assert!(step.executed().is_none());
+        assert!(matches!(step.executed(), Some(PostStepAction::WriteByte { byte: 0xCC, .. })));
+    }
 
-    // Synchronize the local VM state if necessary
-    vm.guest_registers.rflags = current_rflags.bits();
+    #[test]
+    fn rearming_in_a_tight_loop_never_loses_an_action() {
+        // A guest loop calling a hooked function: every call steps over the overwritten instructions, and
+        // every seventh call hits another hook before the previous step completed.
+        let mut pending: Option<SingleStep> = None;
+        let mut armed = 0u64;
+        let mut performed = 0u64;
 
-    Ok(())
-}
+        for hit in 0..100_000u64 {
+            if pending.take().is_some() {
+                performed += 1;
+            }
+            pending = Some(SingleStep::new(hit % 3 + 1, REARM));
+            armed += 1;
 
-/// Restores the guest's RFLAGS register from the saved old RFLAGS.
-///
-/// # Parameters
-/// * `vm`: A mutable reference to the virtual machine instance.
-///
-/// # Returns
-/// * `Result<(), HypervisorError>`: Ok if successful, Err if an error occurred during VMCS read/write operations.
-pub fn restore_guest_interrupt_flag(vm: &mut Vm) -> Result<(), HypervisorError> {
-    if let Some(old_rflags_bits) = vm.old_rflags {
-        trace!("Restoring guest RFLAGS to old value: {:#x}", old_rflags_bits);
+            if hit % 7 == 0 {
+                continue;
+            }
 
-        // Update VM register state first
-        vm.guest_registers.rflags = old_rflags_bits;
+            let mut instructions = 0;
+            while let Some(step) = pending.as_mut() {
+                instructions += 1;
+                if step.executed().is_some() {
+                    pending = None;
+                    performed += 1;
+                }
+            }
+            assert_eq!(instructions, hit % 3 + 1);
+        }
 
-        // Then write to VMCS
-        vmwrite(vmcs::guest::RFLAGS, old_rflags_bits);
-        Ok(())
-    } else {
-        Err(HypervisorError::OldRflagsNotSet)
+        assert!(pending.is_none());
+        assert_eq!(performed, armed);
     }
 }
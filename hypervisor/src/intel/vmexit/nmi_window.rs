@@ -0,0 +1,15 @@
+//! Handles NMI-window VM exits, which `nmi` asks for while an NMI of the guest is pending.
+
+use {crate::intel::vmexit::ExitType, log::trace};
+
+/// Handles the VM exit caused by "NMI-window exiting".
+///
+/// The guest can take an NMI now, `EventInjector::flush` injects the pending one and clears the control.
+///
+/// # Returns
+///
+/// Returns `ExitType::Continue`, the exit happened before an instruction.
+pub fn handle_nmi_window() -> ExitType {
+    trace!("Handling NMI-window VM exit...");
+    ExitType::Continue
+}
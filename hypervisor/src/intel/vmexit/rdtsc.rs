@@ -3,8 +3,12 @@
 //! information is provided to the guest while maintaining the integrity of the hypervisor.
 
 use {
-    crate::intel::{capture::GuestRegisters, vmexit::ExitType},
-    x86::time::rdtsc,
+    crate::intel::{
+        support::{rdmsr, rdtsc},
+        vm::Vm,
+        vmexit::ExitType,
+    },
+    x86::msr,
 };
 
 /*
@@ -16,29 +20,55 @@ User can add the following later:
 /// Handles the `RDTSC` VM-exit.
 ///
 /// This function is invoked when the guest executes the `RDTSC` instruction.
-/// It reads the current value of the host's time-stamp counter and updates the guest's
-/// RAX and RDX registers with the low and high 32-bits of the counter, respectively.
+/// It reads the current value of the host's time-stamp counter, applies the TSC offset
+/// of the VM and updates the guest's RAX and RDX registers with the low and high
+/// 32-bits of the counter, respectively.
 ///
 /// # Arguments
 ///
-/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
 ///
 /// # Returns
 ///
 /// * `ExitType::IncrementRIP` - To move past the `RDTSC` instruction in the VM.
 ///
-/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual, Table C-1. Basic Exit Reasons 10.
-pub fn handle_rdtsc(guest_registers: &mut GuestRegisters) -> ExitType {
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual, Table C-1. Basic Exit Reasons 16.
+pub fn handle_rdtsc(vm: &mut Vm) -> ExitType {
     log::debug!("Handling RDTSC VM exit...");
 
-    // Read the time stamp counter.
-    let rdtsc_value: u64 = unsafe { rdtsc() };
+    // Read the time stamp counter the guest would have read without the VM exit.
+    let rdtsc_value = vm.tsc.guest_tsc(rdtsc());
 
     // Update the guest's RAX and RDX registers.
-    guest_registers.rax = rdtsc_value & 0xFFFFFFFF; // Low 32 bits
-    guest_registers.rdx = rdtsc_value >> 32; // High 32 bits
+    vm.guest_registers.rax = rdtsc_value & 0xFFFFFFFF; // Low 32 bits
+    vm.guest_registers.rdx = rdtsc_value >> 32; // High 32 bits
 
     log::debug!("RDTSC VMEXIT handled successfully!");
 
     ExitType::IncrementRIP
 }
+
+/// Handles the `RDTSCP` VM-exit.
+///
+/// Like `handle_rdtsc`, and additionally loads ECX with IA32_TSC_AUX, which the guest
+/// writes itself as the MSR is passed through.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - To move past the `RDTSCP` instruction in the VM.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual, Table C-1. Basic Exit Reasons 51.
+pub fn handle_rdtscp(vm: &mut Vm) -> ExitType {
+    log::debug!("Handling RDTSCP VM exit...");
+
+    handle_rdtsc(vm);
+    vm.guest_registers.rcx = rdmsr(msr::IA32_TSC_AUX) & 0xFFFFFFFF;
+
+    log::debug!("RDTSCP VMEXIT handled successfully!");
+
+    ExitType::IncrementRIP
+}
@@ -6,11 +6,14 @@
 //! Credits to Satoshi Tanada: https://github.com/tandasat/MiniVisorPkg/blob/master/Sources/HostMain.c
 
 use {
-    crate::intel::{
-        capture::GuestRegisters,
-        state::GuestActivityState,
-        support::{vmread, vmwrite},
-        vmexit::ExitType,
+    crate::{
+        intel::{
+            capture::GuestRegisters,
+            state::GuestActivityState,
+            support::{vmread, vmwrite},
+            vmexit::ExitType,
+        },
+        stats,
     },
     x86::vmx::vmcs,
 };
@@ -20,7 +23,10 @@ use {
 /// Upon receiving a SIPI, this function adjusts the guest's code segment selector,
 /// base, and instruction pointer to reflect the startup vector indicated by the SIPI.
 /// It ensures that subsequent SIPI signals, if any, are ignored once the AP is out of
-/// the wait-for-SIPI state, following VMX and MP initialization protocols.
+/// the wait-for-SIPI state, following VMX and MP initialization protocols: only a processor in
+/// the wait-for-SIPI state `handle_init_signal` left it in exits for a SIPI.
+///
+/// The guest starts in real mode at the vector's page, with the rest of the state INIT reset.
 ///
 /// # Arguments
 ///
@@ -30,7 +36,10 @@ use {
 ///
 /// Returns `ExitType::Continue` to indicate the VM should continue execution.
 pub fn handle_sipi_signal(guest_registers: &mut GuestRegisters) -> ExitType {
-    let vector = vmread(vmcs::ro::EXIT_QUALIFICATION);
+    stats::record_startup_ipi();
+
+    // Bits 7:0 of the exit qualification hold the SIPI vector, the others are cleared.
+    let vector = vmread(vmcs::ro::EXIT_QUALIFICATION) & 0xff;
 
     vmwrite(vmcs::guest::CS_SELECTOR, vector << 8);
     vmwrite(vmcs::guest::CS_BASE, vector << 12);
@@ -3,25 +3,34 @@
 
 use {
     crate::{
+        config,
         error::HypervisorError,
         intel::{
             addresses::PhysicalAddress,
             ept::AccessType,
-            events::EventInjection,
-            hooks::hook_manager::{HookManager, SHARED_HOOK_MANAGER},
-            vm::Vm,
-            vmexit::{
-                mtf::{set_monitor_trap_flag, update_guest_interrupt_flag},
-                ExitType,
+            hooks::{
+                hook_manager::{HookManager, SHARED_HOOK_MANAGER},
+                syscall_watch,
             },
+            hyperv::{self, SYNTHETIC_MSRS},
+            support::{guest_cpl, vmread},
+            vm::Vm,
+            vmerror::ExceptionInterrupt,
+            vmexit::{mtf::PostStepAction, vmcall_dispatch, ExitType},
         },
     },
     log::*,
-    x86::bits64::paging::PAddr,
+    shared::hyperv::HV_STATUS_INVALID_HYPERCALL_CODE,
+    x86::{bits64::paging::PAddr, vmx::vmcs},
 };
 
 /// Handles a VMCALL VM exit by executing the corresponding action based on the VMCALL command.
 ///
+/// Hypercalls carrying the magic and the key of this boot are served by `vmcall_dispatch`. While masquerading as
+/// Hyper-V, VMCALLs from its hypercall page fail with `HV_STATUS_INVALID_HYPERCALL_CODE`, see `intel::hyperv`.
+/// Other VMCALLs outside EPT hooks raise #UD. The EPT hook of the syscall entry point records the syscall with
+/// `syscall_watch` before it is stepped over like any other.
+///
 /// # Parameters
 ///
 /// * `vm`: A mutable reference to the virtual machine instance encountering the VMCALL exit.
@@ -38,6 +47,12 @@ pub fn handle_vmcall(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
     trace!("Handling VMCALL VM exit...");
     trace!("Register state before handling VM exit: {:?}", vm.guest_registers);
 
+    // Hypercalls carrying the magic and the key of this boot, see `vmcall_dispatch`.
+    if let Some(request) = vmcall_dispatch::authenticate(vm) {
+        vmcall_dispatch::dispatch(vm, request, guest_cpl());
+        return Ok(ExitType::IncrementRIP);
+    }
+
     let vmcall_number = vm.guest_registers.rax;
     trace!("Guest RAX - VMCALL command number: {:#x}", vmcall_number);
     trace!("Guest RIP: {:#x}", vm.guest_registers.rip);
@@ -51,7 +66,7 @@ pub fn handle_vmcall(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
     let guest_large_page_pa = guest_page_pa.align_down_to_large_page();
     trace!("Guest Large Page PA: {:#x}", guest_large_page_pa.as_u64());
 
-    let mut hook_manager = SHARED_HOOK_MANAGER.lock();
+    let hook_manager = SHARED_HOOK_MANAGER.lock();
 
     // Set the current hook to the EPT hook for handling MTF exit
     let exit_type = if let Some(shadow_page_pa) = hook_manager.memory_manager.get_shadow_page_as_ptr(guest_page_pa.as_u64()) {
@@ -59,39 +74,50 @@ pub fn handle_vmcall(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
 
         trace!("Executing VMCALL hook on shadow page for EPT hook at PA: {:#x} with VA: {:#x}", guest_function_pa, vm.guest_registers.rip);
 
-        let pre_alloc_pt = hook_manager
-            .memory_manager
-            .get_page_table_as_mut(guest_large_page_pa.as_u64())
-            .ok_or(HypervisorError::PageTableNotFound)?;
-
-        // Perform swap_page before the mutable borrow for update_guest_interrupt_flag
-        vm.primary_ept
-            .swap_page(guest_page_pa.as_u64(), guest_page_pa.as_u64(), AccessType::READ_WRITE_EXECUTE, pre_alloc_pt)?;
-
         let hook_info = hook_manager
             .memory_manager
             .get_hook_info_by_function_pa(guest_page_pa.as_u64(), guest_function_pa.as_u64())
             .ok_or(HypervisorError::HookInfoNotFound)?;
 
-        debug!("Hook info: {:#x?}", hook_info);
+        match config::log_ept_hooks() {
+            true => info!("EPT hook hit at {:#x}: {:#x?}", vm.guest_registers.rip, hook_info),
+            false => debug!("Hook info: {:#x?}", hook_info),
+        }
 
         // Calculate the number of instructions in the function to set the MTF counter for restoring overwritten instructions by single-stepping.
         let instruction_count =
             unsafe { HookManager::calculate_instruction_count(guest_function_pa.as_u64(), HookManager::hook_size(hook_info.ept_hook_type)) as u64 };
-        vm.mtf_counter = Some(instruction_count);
 
-        // Set the monitor trap flag and initialize counter to the number of overwritten instructions
-        set_monitor_trap_flag(true);
+        // The VMCALL exit and an MTF exit per instruction stepped over are all a syscall costs.
+        if syscall_watch::is_entry(guest_function_pa.as_u64()) {
+            syscall_watch::record(vm.guest_registers.rax, vmread(vmcs::guest::CR3), vm.guest_registers.rcx, 1 + instruction_count);
+        }
+
+        // Step over the overwritten instructions on the original page, then re-arm the hook.
+        // Arming first completes a pending step, which must not undo the swap below. With #VE the
+        // hook hit in the execute view, the step runs in the primary EPT.
+        vm.enter_primary_view()?;
+        vm.single_step_over(
+            instruction_count,
+            PostStepAction::RearmHook {
+                guest_page_pa: guest_page_pa.as_u64(),
+                shadow_page_pa,
+            },
+        )?;
 
-        // Ensure all data mutations to vm are done before calling this.
-        // This function will update the guest interrupt flag to prevent interrupts while single-stepping
-        update_guest_interrupt_flag(vm, false)?;
+        vm.primary_ept
+            .swap_page(guest_page_pa.as_u64(), guest_page_pa.as_u64(), AccessType::READ_WRITE_EXECUTE)?;
 
         Ok(ExitType::Continue)
+    } else if hyperv::masquerading() && SYNTHETIC_MSRS.lock().hypercall_page() == Some(guest_page_pa.as_u64()) && guest_cpl() == 0 {
+        // A hypercall through the page of the Hyper-V interface, none of which is implemented.
+        trace!("Hyper-V hypercall {:#x} is not implemented", vm.guest_registers.rcx & 0xFFFF);
+        vm.guest_registers.rax = HV_STATUS_INVALID_HYPERCALL_CODE;
+        Ok(ExitType::IncrementRIP)
     } else {
         // https://www.felixcloutier.com/x86/vmcall
         // #UD: If executed outside VMX operation.
-        EventInjection::vmentry_inject_ud();
+        vm.events.inject_exception(ExceptionInterrupt::InvalidOpcode as u8, None);
         Ok(ExitType::Continue)
     };
 
@@ -0,0 +1,547 @@
+//! Serves the VMCALL hypercalls of `shared::vmcall`.
+//!
+//! `handle_vmcall` hands every VMCALL carrying the magic and the hypercall key of this boot to `dispatch` before
+//! it looks for EPT hooks, everything else about the guest's VMCALLs stays as it was. Each range of calls states
+//! whether user mode may issue them, calls from CPL 3 into a kernel-only range fail with `AccessDenied` before
+//! their number is looked at. The memory calls copy through `guest_memory`, the process calls read the kernel's
+//! process list with `windows::processes` and its exports with `windows::kernel`, and the hook range is reserved
+//! and fails with `NotImplemented`. The syscall calls program the rules of `syscall_watch`, `dispatch` then hooks
+//! or unhooks the syscall entry point to match them, and are only served with `HvFeatureFlags::EPT_HOOKS`.
+//! `audit_hooks` runs `patch_audit` over the pages of all EPT hooks.
+
+use {
+    crate::{
+        config, event_log,
+        intel::{
+            guest_memory::{copy, read, write, EptMemory, GuestMemory, Span},
+            hooks::{
+                hook_manager::SHARED_HOOK_MANAGER,
+                patch_audit,
+                syscall_watch::{self, WatchError, SYSCALL_WATCHES},
+            },
+            page_walk::Paging,
+            support::vmread,
+            vm::Vm,
+        },
+        stats,
+        windows::{
+            kernel::{resolve_export, Kernel, KernelError},
+            processes::{find_process, for_each_process, ProcessError},
+        },
+    },
+    alloc::vec::Vec,
+    log::*,
+    shared::{
+        features::HvFeatureFlags,
+        vmcall::{
+            version_compatible, EventRecord, ProcessEntry, SyscallWatchEntry, VmcallNumber, VmcallRange, VmcallRegisters, VmcallRequest,
+            VmcallResponse, VmcallStatus, CAPABILITY_BUILTIN, CAPABILITY_MEMORY, CAPABILITY_PROCESSES, CAPABILITY_SYSCALLS, MAX_EXPORT_NAME_LENGTH,
+            MAX_MEMORY_LENGTH, MEMORY_FORCE_WRITE, PROCESS_NAME_LENGTH, VMCALL_VERSION,
+        },
+    },
+    x86::vmx::vmcs,
+};
+
+/// Returns the registers of the guest the convention uses.
+fn registers(vm: &Vm) -> VmcallRegisters {
+    VmcallRegisters {
+        rax: vm.guest_registers.rax,
+        rcx: vm.guest_registers.rcx,
+        rdx: vm.guest_registers.rdx,
+        r8: vm.guest_registers.r8,
+        r9: vm.guest_registers.r9,
+        r10: vm.guest_registers.r10,
+        r11: vm.guest_registers.r11,
+        r12: vm.guest_registers.r12,
+    }
+}
+
+/// Decodes an authenticated VMCALL of the guest.
+///
+/// # Returns
+///
+/// The request, `None` if the VMCALL doesn't carry the magic and the hypercall key of this boot.
+pub fn authenticate(vm: &Vm) -> Option<VmcallRequest> {
+    VmcallRequest::decode(&registers(vm), config::vmcall_key())
+}
+
+/// Serves an authenticated VMCALL and writes the answer to the registers of the guest.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance.
+/// * `request` - The request `authenticate` decoded.
+/// * `cpl` - The current privilege level of the guest.
+pub fn dispatch(vm: &mut Vm, request: VmcallRequest, cpl: u8) {
+    stats::record_hypercall();
+
+    let mut response = match EptMemory::current() {
+        Some(mut memory) => answer(request, cpl, vmread(vmcs::guest::CR3), &Paging::current(vm), &mut memory, Kernel::current()),
+        None => VmcallResponse::status(VmcallStatus::Failure),
+    };
+
+    // The rules changed, the hook of the syscall entry point follows them.
+    let number = VmcallNumber::from_u64(request.number);
+    if response.status == VmcallStatus::Success && matches!(number, Some(VmcallNumber::SyscallWatchAdd | VmcallNumber::SyscallWatchRemove)) {
+        if let Err(error) = syscall_watch::sync_hook(vm) {
+            warn!("Failed to hook the syscall entry point for the syscall watch: {:?}", error);
+            if number == Some(VmcallNumber::SyscallWatchAdd) {
+                SYSCALL_WATCHES.lock().remove(request.args[0], request.args[1]);
+            }
+            response = VmcallResponse::status(VmcallStatus::Failure);
+        }
+    }
+    trace!("VMCALL hypercall {:#x} at CPL {}: {:?}", request.number, cpl, response);
+
+    let mut registers = registers(vm);
+    response.encode(&mut registers);
+    vm.guest_registers.rax = registers.rax;
+    vm.guest_registers.rdx = registers.rdx;
+    vm.guest_registers.r8 = registers.r8;
+    vm.guest_registers.r9 = registers.r9;
+}
+
+/// Returns the answer to a request.
+///
+/// # Arguments
+///
+/// * `request` - The request.
+/// * `cpl` - The current privilege level of the caller.
+/// * `caller_cr3` - The CR3 the caller runs with.
+/// * `paging` - The paging state of the guest.
+/// * `memory` - The guest-physical memory.
+/// * `kernel` - The kernel of a Windows guest, `None` before it was found.
+fn answer(
+    request: VmcallRequest,
+    cpl: u8,
+    caller_cr3: u64,
+    paging: &Paging,
+    memory: &mut impl GuestMemory,
+    kernel: Option<Kernel>,
+) -> VmcallResponse {
+    let Some(range) = VmcallRange::of(request.number) else {
+        return VmcallResponse::status(VmcallStatus::UnknownCall);
+    };
+
+    if cpl != 0 && !range.allows_user_mode() {
+        return VmcallResponse::status(VmcallStatus::AccessDenied);
+    }
+
+    if range == VmcallRange::Hook || (range == VmcallRange::Syscall && !config::has_feature(HvFeatureFlags::EPT_HOOKS)) {
+        return VmcallResponse::status(VmcallStatus::NotImplemented);
+    }
+
+    match VmcallNumber::from_u64(request.number) {
+        Some(VmcallNumber::Ping) if !version_compatible(request.args[0] as u32) => VmcallResponse {
+            status: VmcallStatus::VersionMismatch,
+            values: [VMCALL_VERSION as u64, 0, 0],
+        },
+        Some(VmcallNumber::Ping) => VmcallResponse::success([VMCALL_VERSION as u64, config::features().bits(), 0]),
+        Some(VmcallNumber::GetCapabilities) => VmcallResponse::success([capabilities(), 0, 0]),
+        Some(VmcallNumber::GetStats) => {
+            let stats = stats::stats();
+            VmcallResponse::success([stats.vm_exits, stats.hypercalls, stats.virtualized as u64])
+        }
+        Some(VmcallNumber::ReadEvents) => read_events(request.args, caller_cr3, paging, memory),
+        Some(VmcallNumber::AuditHooks) => {
+            let report = patch_audit::audit(&SHARED_HOOK_MANAGER.lock().memory_manager);
+            VmcallResponse::success([report.pages, report.patched_pages, report.discrepancies])
+        }
+        Some(number @ (VmcallNumber::ReadGva | VmcallNumber::WriteGva | VmcallNumber::ReadGpa | VmcallNumber::WriteGpa)) => {
+            copy_memory(number, request.args, caller_cr3, paging, memory)
+        }
+        Some(number @ (VmcallNumber::FindProcess | VmcallNumber::EnumerateProcesses | VmcallNumber::ResolveKernelExport)) => match kernel {
+            Some(kernel) if number == VmcallNumber::FindProcess => find(request.args, caller_cr3, paging, memory, kernel),
+            Some(kernel) if number == VmcallNumber::EnumerateProcesses => enumerate(request.args, caller_cr3, paging, memory, kernel),
+            Some(kernel) => export(request.args, caller_cr3, paging, memory, kernel),
+            None => VmcallResponse::status(VmcallStatus::UnsupportedGuest),
+        },
+        Some(VmcallNumber::SyscallWatchAdd) => match SYSCALL_WATCHES.lock().add(request.args[0], request.args[1]) {
+            Ok(()) => VmcallResponse::success([0; 3]),
+            Err(WatchError::InvalidNumber) => VmcallResponse::status(VmcallStatus::InvalidParameter),
+            Err(WatchError::Full) => VmcallResponse::status(VmcallStatus::Exhausted),
+        },
+        Some(VmcallNumber::SyscallWatchRemove) => match SYSCALL_WATCHES.lock().remove(request.args[0], request.args[1]) {
+            true => VmcallResponse::success([0; 3]),
+            false => VmcallResponse::status(VmcallStatus::NotFound),
+        },
+        Some(VmcallNumber::SyscallWatchList) => list_watches(request.args, caller_cr3, paging, memory),
+        Some(VmcallNumber::GetSyscallStats) => VmcallResponse::success(stats::syscall_stats()),
+        None => VmcallResponse::status(VmcallStatus::UnknownCall),
+    }
+}
+
+/// Serves a memory call, see `shared::vmcall` for its arguments.
+///
+/// # Arguments
+///
+/// * `number` - The memory call.
+/// * `args` - The CR3, the address, the buffer, the length and the flags.
+/// * `caller_cr3` - The CR3 the caller runs with, the one of the buffer.
+/// * `paging` - The paging state of the guest.
+/// * `memory` - The guest-physical memory.
+fn copy_memory(number: VmcallNumber, args: [u64; 5], caller_cr3: u64, paging: &Paging, memory: &mut impl GuestMemory) -> VmcallResponse {
+    let [cr3, address, buffer, length, flags] = args;
+    if length > MAX_MEMORY_LENGTH || flags & !MEMORY_FORCE_WRITE != 0 {
+        return VmcallResponse::status(VmcallStatus::InvalidParameter);
+    }
+
+    let cr3 = if cr3 == 0 { caller_cr3 } else { cr3 };
+    let target = match number {
+        VmcallNumber::ReadGva | VmcallNumber::WriteGva => Span::Virtual { cr3, address },
+        _ => Span::Physical(address),
+    };
+    let buffer = Span::Virtual {
+        cr3: caller_cr3,
+        address: buffer,
+    };
+    if !target.holds(paging, length) || !buffer.holds(paging, length) {
+        return VmcallResponse::status(VmcallStatus::InvalidParameter);
+    }
+
+    // The buffer of the caller is only ever written through its own page tables.
+    let result = match number {
+        VmcallNumber::ReadGva | VmcallNumber::ReadGpa => copy(memory, paging, target, buffer, length, false),
+        _ => copy(memory, paging, buffer, target, length, flags & MEMORY_FORCE_WRITE != 0),
+    };
+
+    match result {
+        Ok(()) => VmcallResponse::success([length, 0, 0]),
+        Err(copied) => VmcallResponse {
+            status: VmcallStatus::PartialCopy,
+            values: [copied, 0, 0],
+        },
+    }
+}
+
+/// Serves `find_process`, see `shared::vmcall` for its arguments.
+///
+/// # Arguments
+///
+/// * `args` - The address and the length of the name.
+/// * `caller_cr3` - The CR3 the caller runs with, the one of the name.
+/// * `paging` - The paging state of the guest.
+/// * `memory` - The guest-physical memory.
+/// * `kernel` - The kernel of the guest.
+fn find(args: [u64; 5], caller_cr3: u64, paging: &Paging, memory: &impl GuestMemory, kernel: Kernel) -> VmcallResponse {
+    let [address, length, ..] = args;
+    let mut name = [0; PROCESS_NAME_LENGTH];
+    let name = &mut name[..length.min(PROCESS_NAME_LENGTH as u64) as usize];
+    if name.is_empty() || !read_caller(memory, paging, caller_cr3, address, name) {
+        return VmcallResponse::status(VmcallStatus::InvalidParameter);
+    }
+
+    match find_process(memory, paging, kernel, name) {
+        Ok(Some(entry)) => VmcallResponse::success([entry.eprocess, entry.directory_table_base, entry.process_id]),
+        Ok(None) => VmcallResponse::status(VmcallStatus::NotFound),
+        Err(error) => process_failure(error),
+    }
+}
+
+/// Serves `resolve_kernel_export`, see `shared::vmcall` for its arguments.
+///
+/// # Arguments
+///
+/// * `args` - The address and the length of the name.
+/// * `caller_cr3` - The CR3 the caller runs with, the one of the name.
+/// * `paging` - The paging state of the guest.
+/// * `memory` - The guest-physical memory.
+/// * `kernel` - The kernel of the guest.
+fn export(args: [u64; 5], caller_cr3: u64, paging: &Paging, memory: &impl GuestMemory, kernel: Kernel) -> VmcallResponse {
+    let [address, length, ..] = args;
+    let mut name = [0; MAX_EXPORT_NAME_LENGTH];
+    if length == 0 || length > MAX_EXPORT_NAME_LENGTH as u64 || !read_caller(memory, paging, caller_cr3, address, &mut name[..length as usize]) {
+        return VmcallResponse::status(VmcallStatus::InvalidParameter);
+    }
+
+    match resolve_export(memory, paging, kernel, &name[..length as usize]) {
+        Ok(export) => VmcallResponse::success([export, kernel.base, 0]),
+        Err(KernelError::NotExported) => VmcallResponse::status(VmcallStatus::NotFound),
+        Err(error) => process_failure(error.into()),
+    }
+}
+
+/// Reads bytes of the caller's address space, returns whether they're all mapped.
+fn read_caller(memory: &impl GuestMemory, paging: &Paging, caller_cr3: u64, address: u64, buffer: &mut [u8]) -> bool {
+    let span = Span::Virtual { cr3: caller_cr3, address };
+    span.holds(paging, buffer.len() as u64) && read(memory, paging, span, buffer).is_ok()
+}
+
+/// Serves `enumerate_processes`, see `shared::vmcall` for its arguments.
+///
+/// # Arguments
+///
+/// * `args` - The array and the entries it has room for.
+/// * `caller_cr3` - The CR3 the caller runs with, the one of the array.
+/// * `paging` - The paging state of the guest.
+/// * `memory` - The guest-physical memory.
+/// * `kernel` - The kernel of the guest.
+fn enumerate(args: [u64; 5], caller_cr3: u64, paging: &Paging, memory: &mut impl GuestMemory, kernel: Kernel) -> VmcallResponse {
+    let [address, capacity, ..] = args;
+    let Some(span) = caller_array(address, capacity, ProcessEntry::SIZE, caller_cr3, paging) else {
+        return VmcallResponse::status(VmcallStatus::InvalidParameter);
+    };
+
+    let mut entries = Vec::new();
+    if let Err(error) = for_each_process(memory, paging, kernel, |entry| {
+        entries.push(*entry);
+        true
+    }) {
+        return process_failure(error);
+    }
+
+    let written = entries.len().min(capacity as usize);
+    let bytes: Vec<u8> = entries[..written].iter().flat_map(ProcessEntry::to_bytes).collect();
+    match write(memory, paging, span, &bytes) {
+        Ok(()) => VmcallResponse::success([entries.len() as u64, written as u64, 0]),
+        Err(done) => VmcallResponse {
+            status: VmcallStatus::PartialCopy,
+            values: [done, 0, 0],
+        },
+    }
+}
+
+/// Returns the span of an array of the caller, `None` unless all of it is mapped.
+///
+/// # Arguments
+///
+/// * `address` - The address of the array.
+/// * `capacity` - The elements the array has room for.
+/// * `size` - The bytes of an element.
+/// * `caller_cr3` - The CR3 the caller runs with, the one of the array.
+/// * `paging` - The paging state of the guest.
+fn caller_array(address: u64, capacity: u64, size: usize, caller_cr3: u64, paging: &Paging) -> Option<Span> {
+    let span = Span::Virtual { cr3: caller_cr3, address };
+    capacity
+        .checked_mul(size as u64)
+        .is_some_and(|length| span.holds(paging, length))
+        .then_some(span)
+}
+
+/// Serves `read_events`, see `shared::vmcall` for its arguments.
+///
+/// The events are taken from the log before they are written, those the caller's array couldn't take are lost.
+///
+/// # Arguments
+///
+/// * `args` - The array and the records it has room for.
+/// * `caller_cr3` - The CR3 the caller runs with, the one of the array.
+/// * `paging` - The paging state of the guest.
+/// * `memory` - The guest-physical memory.
+fn read_events(args: [u64; 5], caller_cr3: u64, paging: &Paging, memory: &mut impl GuestMemory) -> VmcallResponse {
+    let [address, capacity, ..] = args;
+    let Some(span) = caller_array(address, capacity, EventRecord::SIZE, caller_cr3, paging) else {
+        return VmcallResponse::status(VmcallStatus::InvalidParameter);
+    };
+
+    let (records, lost) = event_log::drain(capacity as usize);
+    let bytes: Vec<u8> = records.iter().flat_map(EventRecord::to_bytes).collect();
+    match write(memory, paging, span, &bytes) {
+        Ok(()) => VmcallResponse::success([records.len() as u64, lost, 0]),
+        Err(done) => VmcallResponse {
+            status: VmcallStatus::PartialCopy,
+            values: [done, lost, 0],
+        },
+    }
+}
+
+/// Serves `syscall_watch_list`, see `shared::vmcall` for its arguments.
+///
+/// # Arguments
+///
+/// * `args` - The array and the entries it has room for.
+/// * `caller_cr3` - The CR3 the caller runs with, the one of the array.
+/// * `paging` - The paging state of the guest.
+/// * `memory` - The guest-physical memory.
+fn list_watches(args: [u64; 5], caller_cr3: u64, paging: &Paging, memory: &mut impl GuestMemory) -> VmcallResponse {
+    let [address, capacity, ..] = args;
+    let Some(span) = caller_array(address, capacity, SyscallWatchEntry::SIZE, caller_cr3, paging) else {
+        return VmcallResponse::status(VmcallStatus::InvalidParameter);
+    };
+
+    let entries: Vec<SyscallWatchEntry> = SYSCALL_WATCHES.lock().entries().copied().collect();
+    let written = entries.len().min(capacity as usize);
+    let bytes: Vec<u8> = entries[..written].iter().flat_map(SyscallWatchEntry::to_bytes).collect();
+    match write(memory, paging, span, &bytes) {
+        Ok(()) => VmcallResponse::success([entries.len() as u64, written as u64, 0]),
+        Err(done) => VmcallResponse {
+            status: VmcallStatus::PartialCopy,
+            values: [done, 0, 0],
+        },
+    }
+}
+
+/// Returns the answer to a process call that couldn't read the process list.
+fn process_failure(error: ProcessError) -> VmcallResponse {
+    warn!("Failed to read the processes of the guest: {:?}", error);
+    match error {
+        ProcessError::NoKernel | ProcessError::UnknownBuild(_) => VmcallResponse::status(VmcallStatus::UnsupportedGuest),
+        _ => VmcallResponse::status(VmcallStatus::Failure),
+    }
+}
+
+/// Returns the `CAPABILITY_*` bits of the ranges the hypervisor serves.
+fn capabilities() -> u64 {
+    let syscalls = if config::has_feature(HvFeatureFlags::EPT_HOOKS) {
+        CAPABILITY_SYSCALLS
+    } else {
+        0
+    };
+    CAPABILITY_BUILTIN | CAPABILITY_MEMORY | CAPABILITY_PROCESSES | syscalls
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            intel::page_walk::{
+                tests::{FakeMemory, FOUR_LEVEL},
+                PRESENT, WRITABLE,
+            },
+            windows::processes::tests::fake_kernel,
+        },
+        shared::vmcall::CAPABILITY_HOOKS,
+    };
+
+    /// The page tables of the caller, at 0x1000.
+    const CALLER_CR3: u64 = 0x1000;
+
+    fn call_with(memory: &mut FakeMemory, number: u64, args: [u64; 5], cpl: u8) -> VmcallResponse {
+        answer(VmcallRequest { number, args }, cpl, CALLER_CR3, &FOUR_LEVEL, memory, None)
+    }
+
+    fn call(number: u64, args: [u64; 5], cpl: u8) -> VmcallResponse {
+        call_with(&mut FakeMemory::new(8), number, args, cpl)
+    }
+
+    #[test]
+    fn ranges_are_checked_before_calls() {
+        assert_eq!(call(0x500, [0; 5], 0).status, VmcallStatus::UnknownCall);
+        assert_eq!(call(0x4FF, [0; 5], 3).status, VmcallStatus::UnknownCall);
+        assert_eq!(call(0x0FF, [0; 5], 3).status, VmcallStatus::UnknownCall);
+        assert_eq!(call(0x200, [0; 5], 3).status, VmcallStatus::AccessDenied);
+        assert_eq!(call(0x200, [0; 5], 0).status, VmcallStatus::NotImplemented);
+        assert_eq!(call(0x1FF, [0; 5], 3).status, VmcallStatus::UnknownCall);
+        assert_eq!(capabilities() & CAPABILITY_HOOKS, 0);
+    }
+
+    #[test]
+    fn memory_calls_copy_through_the_buffer() {
+        // The caller's buffer at 0x10000 is the frame at 0x5000, a read-only page of it at 0x20000 the one at 0x6000.
+        let mut memory = FakeMemory::new(8);
+        memory.map(&[0x1000, 0x2000, 0x3000, 0x4000], 0x10000, 0x5000 | PRESENT | WRITABLE);
+        memory.map(&[0x1000, 0x2000, 0x3000, 0x4000], 0x20000, 0x6000 | PRESENT);
+        memory.bytes[0x7000..0x7010].fill(0xAA);
+
+        let read = call_with(&mut memory, VmcallNumber::ReadGpa as u64, [0, 0x7000, 0x10000, 0x10, 0], 3);
+        assert_eq!(read, VmcallResponse::success([0x10, 0, 0]));
+        assert_eq!(memory.bytes[0x5000..0x5010], [0xAA; 0x10]);
+
+        // The read-only page takes the bytes only when forced, reads never write through it.
+        let denied = call_with(&mut memory, VmcallNumber::WriteGva as u64, [0, 0x20000, 0x10000, 0x10, 0], 0);
+        assert_eq!(
+            denied,
+            VmcallResponse {
+                status: VmcallStatus::PartialCopy,
+                values: [0; 3]
+            }
+        );
+        let forced = call_with(&mut memory, VmcallNumber::WriteGva as u64, [0, 0x20000, 0x10000, 0x10, MEMORY_FORCE_WRITE], 0);
+        assert_eq!(forced.status, VmcallStatus::Success);
+        assert_eq!(memory.bytes[0x6000..0x6010], [0xAA; 0x10]);
+        let into_read_only = call_with(&mut memory, VmcallNumber::ReadGva as u64, [CALLER_CR3, 0x10000, 0x20000, 0x10, MEMORY_FORCE_WRITE], 0);
+        assert_eq!(into_read_only.status, VmcallStatus::PartialCopy);
+
+        // Lengths are capped, flags checked and addresses canonical.
+        for args in [
+            [0, 0x7000, 0x10000, MAX_MEMORY_LENGTH + 1, 0],
+            [0, 0x7000, 0x10000, 0x10, 1 << 1],
+            [0, 0x8000_0000_0000, 0x10000, 0x10, 0],
+        ] {
+            assert_eq!(call_with(&mut memory, VmcallNumber::ReadGva as u64, args, 3).status, VmcallStatus::InvalidParameter);
+        }
+    }
+
+    fn call_kernel(memory: &mut FakeMemory, kernel: Kernel, number: VmcallNumber, args: [u64; 5]) -> VmcallResponse {
+        answer(VmcallRequest { number: number as u64, args }, 3, CALLER_CR3, &FOUR_LEVEL, memory, Some(kernel))
+    }
+
+    #[test]
+    fn process_calls_read_the_kernel() {
+        assert_eq!(call(VmcallNumber::FindProcess as u64, [0; 5], 3).status, VmcallStatus::UnsupportedGuest);
+        assert_eq!(call(VmcallNumber::ResolveKernelExport as u64, [0; 5], 3).status, VmcallStatus::UnsupportedGuest);
+
+        // The caller's name and array at 0xA000, the frame at 0xE000.
+        let (mut memory, kernel) = fake_kernel(22631);
+        memory.map(&[0x1000, 0x2000, 0x3000, 0x4000], 0xA000, 0xE000 | PRESENT | WRITABLE);
+        memory.bytes[0xE000..0xE00D].copy_from_slice(b"NtBuildNumber");
+
+        let export = call_kernel(&mut memory, kernel, VmcallNumber::ResolveKernelExport, [0xA000, 13, 0, 0, 0]);
+        assert_eq!(export, VmcallResponse::success([kernel.base + 0x800, kernel.base, 0]));
+        let missing = call_kernel(&mut memory, kernel, VmcallNumber::ResolveKernelExport, [0xA000, 12, 0, 0, 0]);
+        assert_eq!(missing.status, VmcallStatus::NotFound);
+        let too_long = call_kernel(&mut memory, kernel, VmcallNumber::ResolveKernelExport, [0xA000, MAX_EXPORT_NAME_LENGTH as u64 + 1, 0, 0, 0]);
+        assert_eq!(too_long.status, VmcallStatus::InvalidParameter);
+
+        memory.bytes[0xE000..0xE00B].copy_from_slice(b"smss.exe\0\0\0");
+        let mut call = |number: VmcallNumber, args: [u64; 5]| call_kernel(&mut memory, kernel, number, args);
+        assert_eq!(call(VmcallNumber::FindProcess, [0xA000, 8, 0, 0, 0]), VmcallResponse::success([0x7000, 0x20_0000, 0x1C0]));
+        assert_eq!(call(VmcallNumber::FindProcess, [0xA000, 4, 0, 0, 0]).status, VmcallStatus::NotFound);
+        assert_eq!(call(VmcallNumber::FindProcess, [0xA000, 0, 0, 0, 0]).status, VmcallStatus::InvalidParameter);
+
+        // Two of the three processes fit, the count tells the caller to retry with more room.
+        assert_eq!(call(VmcallNumber::EnumerateProcesses, [0xA000, 2, 0, 0, 0]), VmcallResponse::success([3, 2, 0]));
+        assert_eq!(call(VmcallNumber::EnumerateProcesses, [0xB000, 3, 0, 0, 0]).status, VmcallStatus::PartialCopy);
+        assert_eq!(memory.bytes[0xE000 + 16], 4);
+        assert_eq!(&memory.bytes[0xE000 + ProcessEntry::SIZE + 24..][..8], b"smss.exe");
+    }
+
+    #[test]
+    fn syscall_calls_program_the_watch() {
+        // The caller's array at 0xA000, the frame at 0xE000.
+        let mut memory = FakeMemory::new(16);
+        memory.map(&[0x1000, 0x2000, 0x3000, 0x4000], 0xA000, 0xE000 | PRESENT | WRITABLE);
+        let mut call = |number: VmcallNumber, args: [u64; 5]| call_with(&mut memory, number as u64, args, 3);
+
+        assert_eq!(call(VmcallNumber::SyscallWatchAdd, [0x55, 0x1AD000, 0, 0, 0]), VmcallResponse::success([0; 3]));
+        assert_eq!(call(VmcallNumber::SyscallWatchAdd, [1 << 40, 0, 0, 0, 0]).status, VmcallStatus::InvalidParameter);
+        SYSCALL_WATCHES.lock().matches(0x55, 0x1AD000);
+
+        assert_eq!(call(VmcallNumber::SyscallWatchList, [0xA000, 4, 0, 0, 0]), VmcallResponse::success([1, 1, 0]));
+        assert_eq!(call(VmcallNumber::SyscallWatchList, [0xA000, u64::MAX / 8, 0, 0, 0]).status, VmcallStatus::InvalidParameter);
+        assert_eq!(call(VmcallNumber::SyscallWatchRemove, [0x55, 0x1AD000, 0, 0, 0]), VmcallResponse::success([0; 3]));
+        assert_eq!(call(VmcallNumber::SyscallWatchRemove, [0x55, 0x1AD000, 0, 0, 0]).status, VmcallStatus::NotFound);
+        assert!(SYSCALL_WATCHES.lock().is_empty());
+
+        let entry = SyscallWatchEntry {
+            number: 0x55,
+            cr3: 0x1AD000,
+            hits: 1,
+        };
+        assert_eq!(&memory.bytes[0xE000..0xE000 + SyscallWatchEntry::SIZE], &entry.to_bytes());
+        assert_ne!(capabilities() & CAPABILITY_SYSCALLS, 0);
+
+        let events = call_with(&mut memory, VmcallNumber::ReadEvents as u64, [0xA000, 0x80, 0, 0, 0], 3);
+        assert_eq!(events.status, VmcallStatus::Success);
+        assert_eq!(
+            call_with(&mut memory, VmcallNumber::ReadEvents as u64, [0x7FFF_FFFF_F000, 0x81, 0, 0, 0], 3).status,
+            VmcallStatus::InvalidParameter
+        );
+    }
+
+    #[test]
+    fn ping_negotiates_the_version() {
+        let ping = call(VmcallNumber::Ping as u64, [VMCALL_VERSION as u64, 0, 0, 0, 0], 3);
+        assert_eq!((ping.status, ping.values[0]), (VmcallStatus::Success, VMCALL_VERSION as u64));
+
+        let mismatch = call(VmcallNumber::Ping as u64, [2 << 16, 0, 0, 0, 0], 3);
+        assert_eq!(
+            mismatch,
+            VmcallResponse {
+                status: VmcallStatus::VersionMismatch,
+                values: [VMCALL_VERSION as u64, 0, 0]
+            }
+        );
+    }
+}
@@ -1,5 +1,5 @@
 use {
-    crate::intel::{events::EventInjection, support::read_effective_guest_cr4, vmexit::ExitType},
+    crate::intel::{support::read_effective_guest_cr4, vm::Vm, vmerror::ExceptionInterrupt, vmexit::ExitType},
     log::trace,
     x86_64::registers::control::Cr4Flags,
 };
@@ -8,18 +8,22 @@ use {
 ///
 /// This function is called when the VM exits due to a VMXON instruction.
 ///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the VM.
+///
 /// # Returns
 ///
 /// * `ExitType::Continue` - Indicating that VM execution should continue after handling the VMXON instruction.
-pub fn handle_vmxon() -> ExitType {
+pub fn handle_vmxon(vm: &mut Vm) -> ExitType {
     trace!("Handling VMXON VM exit...");
 
     let curr_cr4 = Cr4Flags::from_bits_retain(read_effective_guest_cr4());
 
     if !curr_cr4.contains(Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS) {
-        EventInjection::vmentry_inject_ud();
+        vm.events.inject_exception(ExceptionInterrupt::InvalidOpcode as u8, None);
     } else {
-        EventInjection::vmentry_inject_gp(0);
+        vm.events.inject_exception(ExceptionInterrupt::GeneralProtectionFault as u8, Some(0));
     }
 
     trace!("VMXON VMEXIT handled successfully!");
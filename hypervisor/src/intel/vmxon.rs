@@ -4,10 +4,7 @@
 //! It covers setting up the VMXON region, adjusting necessary control registers, and handling model-specific registers to meet Intel's virtualization requirements.
 
 use {
-    crate::{
-        error::HypervisorError,
-        intel::support::{cr0_write, rdmsr},
-    },
+    crate::{error::HypervisorError, intel::support::cr0_write},
     bitfield::BitMut,
     x86::{current::paging::BASE_PAGE_SIZE, msr},
     x86_64::registers::control::{Cr0, Cr4},
@@ -30,12 +27,6 @@ pub struct Vmxon {
 }
 
 impl Vmxon {
-    /// Initializes the VMXON region.
-    pub fn init(&mut self) {
-        self.revision_id = rdmsr(msr::IA32_VMX_BASIC) as u32;
-        self.revision_id.set_bit(31, false);
-    }
-
     /// Enables VMX operation by setting the VMX-enable bit in CR4.
     ///
     /// Sets the CR4_VMX_ENABLE_BIT to enable VMX operations, preparing the processor to enter VMX operation mode.
@@ -0,0 +1,426 @@
+//! The state components the guest enables in XCR0 with XSETBV, and the CPUID leaf describing them.
+//!
+//! XSETBV of the guest always exits. It is checked against the components the guest sees, the ones
+//! CPUID.(EAX=0DH,ECX=0):EDX:EAX reports after `CPUID_POLICY` whose features the policy left visible, so the
+//! guest never enables a component the policy hid, e.g. the AVX-512 state once AVX512F is cleared in leaf 7.
+//! Leaf 0xD answers consistently: hidden components are unsupported in subleaf 0, their subleaves are empty and
+//! the XSAVE area sizes only cover the visible components.
+//!
+//! VM entries and exits don't switch XCR0, the guest's value stays loaded in VMX root operation as long as it
+//! enables every component the host had enabled, which any XCR0 Windows sets does. Otherwise the host's XCR0 is
+//! restored right after every VM exit and the guest's loaded again right before the VM entry.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 13.3 ENABLING THE XSAVE FEATURE SET AND XSAVE-ENABLED FEATURES
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: XSETBV—Set Extended Control Register
+
+use {
+    crate::intel::{
+        cpuid_policy::seen,
+        support::{cr4, cr4_write, xgetbv, xsetbv},
+    },
+    x86::cpuid::{cpuid, CpuIdResult},
+    x86_64::registers::control::Cr4Flags,
+};
+
+/// [Bit 0] XCR0: x87 state, always enabled.
+pub const X87: u64 = 1 << 0;
+
+/// [Bit 1] XCR0: SSE state.
+pub const SSE: u64 = 1 << 1;
+
+/// [Bit 2] XCR0: AVX state, the upper halves of YMM0–YMM15.
+pub const AVX: u64 = 1 << 2;
+
+/// [Bits 4:3] XCR0: MPX state, BNDREGS and BNDCSR.
+pub const MPX: u64 = 0b11 << 3;
+
+/// [Bits 7:5] XCR0: AVX-512 state, opmask, ZMM_Hi256 and Hi16_ZMM.
+pub const AVX512: u64 = 0b111 << 5;
+
+/// [Bit 9] XCR0: PKRU state.
+pub const PKRU: u64 = 1 << 9;
+
+/// [Bits 18:17] XCR0: AMX state, XTILECFG and XTILEDATA.
+pub const AMX: u64 = 0b11 << 17;
+
+/// The size of the legacy region and the XSAVE header, which hold the x87 and SSE state.
+const LEGACY_AREA_SIZE: u32 = 512 + 64;
+
+/// The features of CPUID leaf 1 and 7 the components depend on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ComponentFeatures {
+    /// CPUID.1:ECX.
+    pub leaf1_ecx: u32,
+
+    /// CPUID.(EAX=07H,ECX=0):EBX.
+    pub leaf7_ebx: u32,
+
+    /// CPUID.(EAX=07H,ECX=0):ECX.
+    pub leaf7_ecx: u32,
+
+    /// CPUID.(EAX=07H,ECX=0):EDX.
+    pub leaf7_edx: u32,
+}
+
+/// Returns the components XCR0 supports according to leaf 0xD, cleared of the ones whose features are hidden.
+///
+/// # Arguments
+///
+/// * `supported` - CPUID.(EAX=0DH,ECX=0):EDX:EAX.
+/// * `features` - The feature bits the components depend on.
+pub fn visible_components(supported: u64, features: ComponentFeatures) -> u64 {
+    /// CPUID.1:ECX.AVX[bit 28].
+    const AVX_FEATURE: u32 = 1 << 28;
+    /// CPUID.(EAX=07H,ECX=0):EBX.MPX[bit 14].
+    const MPX_FEATURE: u32 = 1 << 14;
+    /// CPUID.(EAX=07H,ECX=0):EBX.AVX512F[bit 16].
+    const AVX512F_FEATURE: u32 = 1 << 16;
+    /// CPUID.(EAX=07H,ECX=0):ECX.PKU[bit 3].
+    const PKU_FEATURE: u32 = 1 << 3;
+    /// CPUID.(EAX=07H,ECX=0):EDX.AMX-TILE[bit 24].
+    const AMX_TILE_FEATURE: u32 = 1 << 24;
+
+    let mut visible = supported | X87;
+    if features.leaf1_ecx & AVX_FEATURE == 0 {
+        visible &= !(AVX | AVX512);
+    }
+    if features.leaf7_ebx & MPX_FEATURE == 0 {
+        visible &= !MPX;
+    }
+    if features.leaf7_ebx & AVX512F_FEATURE == 0 {
+        visible &= !AVX512;
+    }
+    if features.leaf7_ecx & PKU_FEATURE == 0 {
+        visible &= !PKRU;
+    }
+    if features.leaf7_edx & AMX_TILE_FEATURE == 0 {
+        visible &= !AMX;
+    }
+    visible
+}
+
+/// Checks the value XSETBV writes to XCR0 the way the instruction does.
+///
+/// # Arguments
+///
+/// * `value` - EDX:EAX of XSETBV.
+/// * `visible` - The components the guest sees, see `visible_components`.
+///
+/// # Returns
+///
+/// The value XCR0 takes, `None` if the instruction raises #GP(0).
+pub fn checked_xcr0(value: u64, visible: u64) -> Option<u64> {
+    // #GP(0) if setting a bit the guest doesn't see as supported
+    if value & !visible != 0 {
+        return None;
+    }
+
+    // #GP(0) if clearing XCR0.X87
+    if value & X87 == 0 {
+        return None;
+    }
+
+    // #GP(0) if XCR0.AVX is 1 while XCR0.SSE is cleared
+    if value & AVX != 0 && value & SSE == 0 {
+        return None;
+    }
+
+    // #GP(0) if XCR0[7:5] is neither 000b nor 111b, or not 000b while XCR0[2:1] is not 11b
+    let avx512 = value & AVX512;
+    if avx512 != 0 && (avx512 != AVX512 || value & (SSE | AVX) != SSE | AVX) {
+        return None;
+    }
+
+    // #GP(0) if XCR0[4:3] is neither 00b nor 11b
+    if !matches!(value & MPX, 0 | MPX) {
+        return None;
+    }
+
+    // #GP(0) if XCR0[18:17] is neither 00b nor 11b
+    if !matches!(value & AMX, 0 | AMX) {
+        return None;
+    }
+
+    Some(value)
+}
+
+/// Returns the size of the standard-format XSAVE area holding `components`.
+///
+/// # Arguments
+///
+/// * `components` - The XCR0 components.
+/// * `layout` - Returns the size and offset of component `i` for `i` from 2 to 62, CPUID.(EAX=0DH,ECX=i):EAX and EBX.
+pub fn xsave_area_size(components: u64, layout: impl Fn(u32) -> (u32, u32)) -> u32 {
+    (2..63)
+        .filter(|component| components & 1 << component != 0)
+        .map(|component| {
+            let (size, offset) = layout(component);
+            offset + size
+        })
+        .fold(LEGACY_AREA_SIZE, u32::max)
+}
+
+/// Makes a result of leaf 0xD consistent with the components the guest sees.
+///
+/// # Arguments
+///
+/// * `subleaf` - The subleaf the guest queried.
+/// * `result` - The result after `CPUID_POLICY`.
+/// * `visible` - The components the guest sees, see `visible_components`.
+/// * `xcr0` - The XCR0 of the guest.
+/// * `layout` - The layout of the components, see `xsave_area_size`.
+pub fn fixup_leaf(subleaf: u32, result: &mut CpuIdResult, visible: u64, xcr0: u64, layout: impl Fn(u32) -> (u32, u32)) {
+    match subleaf {
+        0 => {
+            let supported = (result.edx as u64) << 32 | result.eax as u64;
+            let supported = supported & visible;
+            result.eax = supported as u32;
+            result.edx = (supported >> 32) as u32;
+            result.ebx = xsave_area_size(xcr0 & supported, &layout);
+            result.ecx = xsave_area_size(supported, &layout);
+        }
+        // Components managed through XCR0 report ECX bit 0 clear, the ones of IA32_XSS are left alone.
+        2..=62 if visible & 1 << subleaf == 0 && result.ecx & 1 == 0 && result.eax != 0 => {
+            *result = CpuIdResult {
+                eax: 0,
+                ebx: 0,
+                ecx: 0,
+                edx: 0,
+            };
+        }
+        _ => {}
+    }
+}
+
+/// Returns the components the guest sees on the current processor.
+pub fn guest_components() -> u64 {
+    let leaf_0xd = seen(0xD, 0);
+    let leaf7 = seen(7, 0);
+    let features = ComponentFeatures {
+        leaf1_ecx: seen(1, 0).ecx,
+        leaf7_ebx: leaf7.ebx,
+        leaf7_ecx: leaf7.ecx,
+        leaf7_edx: leaf7.edx,
+    };
+    visible_components((leaf_0xd.edx as u64) << 32 | leaf_0xd.eax as u64, features)
+}
+
+/// Returns the size and offset of a component on the current processor.
+///
+/// # Arguments
+///
+/// * `component` - The component, from 2 to 62.
+pub fn component_layout(component: u32) -> (u32, u32) {
+    let result = cpuid!(0xD, component);
+    (result.eax, result.ebx)
+}
+
+/// Writes XCR0, enabling CR4.OSXSAVE in VMX root operation first, which XSETBV requires.
+///
+/// # Arguments
+///
+/// * `value` - The new value of XCR0.
+fn write_xcr0(value: u64) {
+    let cr4 = cr4();
+    if cr4 & Cr4Flags::OSXSAVE.bits() == 0 {
+        cr4_write(cr4 | Cr4Flags::OSXSAVE.bits());
+    }
+    xsetbv(value);
+}
+
+/// The XCR0 of the guest and of the host on one processor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct XState {
+    /// The XCR0 the guest set.
+    pub guest_xcr0: u64,
+
+    /// The XCR0 of the host, the one the processor had when it was virtualized.
+    pub host_xcr0: u64,
+}
+
+impl XState {
+    /// Captures the XCR0 of the current processor, which the guest starts with.
+    pub fn capture() -> Self {
+        // Without CR4.OSXSAVE XCR0 keeps its value after reset.
+        let xcr0 = if cr4() & Cr4Flags::OSXSAVE.bits() != 0 { xgetbv() } else { X87 };
+        Self {
+            guest_xcr0: xcr0,
+            host_xcr0: xcr0,
+        }
+    }
+
+    /// Returns whether the host runs with the guest's XCR0, which enables every component the host enabled.
+    pub const fn shares_guest_xcr0(&self) -> bool {
+        self.host_xcr0 & !self.guest_xcr0 == 0
+    }
+
+    /// Sets the XCR0 of the guest after a checked XSETBV.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The value, checked by `checked_xcr0`.
+    pub fn set_guest_xcr0(&mut self, value: u64) {
+        let shared = self.shares_guest_xcr0();
+        self.guest_xcr0 = value;
+        if self.shares_guest_xcr0() {
+            write_xcr0(value);
+        } else if shared {
+            write_xcr0(self.host_xcr0);
+        }
+    }
+
+    /// Loads the guest's XCR0 if the host doesn't share it, right before a VM entry.
+    pub fn before_vm_entry(&self) {
+        if !self.shares_guest_xcr0() {
+            write_xcr0(self.guest_xcr0);
+        }
+    }
+
+    /// Restores the host's XCR0 if it doesn't share the guest's, right after a VM exit.
+    pub fn after_vm_exit(&self) {
+        if !self.shares_guest_xcr0() {
+            write_xcr0(self.host_xcr0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The features of a processor with AVX, AVX-512, MPX, PKU and AMX.
+    const ALL_FEATURES: ComponentFeatures = ComponentFeatures {
+        leaf1_ecx: 1 << 28,
+        leaf7_ebx: 1 << 16 | 1 << 14,
+        leaf7_ecx: 1 << 3,
+        leaf7_edx: 1 << 24,
+    };
+
+    /// The components of Sapphire Rapids: x87, SSE, AVX, AVX-512, PKRU and AMX.
+    const SUPPORTED: u64 = 0x6_02E7;
+
+    /// The sizes and offsets of the standard format on Sapphire Rapids.
+    fn layout(component: u32) -> (u32, u32) {
+        match component {
+            2 => (256, 576),
+            5 => (64, 1088),
+            6 => (512, 1152),
+            7 => (1024, 1664),
+            9 => (8, 2688),
+            17 => (64, 2752),
+            18 => (8192, 2816),
+            _ => (0, 0),
+        }
+    }
+
+    #[test]
+    fn xcr0_follows_the_sdm_rules() {
+        assert_eq!(checked_xcr0(0x7, SUPPORTED), Some(0x7));
+        assert_eq!(checked_xcr0(0x2E7, SUPPORTED), Some(0x2E7));
+        assert_eq!(checked_xcr0(0x6_02E7, SUPPORTED), Some(0x6_02E7));
+
+        assert_eq!(checked_xcr0(0x6, SUPPORTED), None);
+        assert_eq!(checked_xcr0(0x5, SUPPORTED), None);
+        assert_eq!(checked_xcr0(0x67, SUPPORTED), None);
+        assert_eq!(checked_xcr0(0xE3, SUPPORTED), None);
+        assert_eq!(checked_xcr0(0x2_0007, SUPPORTED), None);
+        assert_eq!(checked_xcr0(0x4_0007, SUPPORTED), None);
+
+        // MPX isn't supported, and never was in pairs of different values.
+        assert_eq!(checked_xcr0(0x1F, SUPPORTED), None);
+        assert_eq!(checked_xcr0(0x0F, SUPPORTED | MPX), None);
+        assert_eq!(checked_xcr0(0x1F, SUPPORTED | MPX), Some(0x1F));
+    }
+
+    #[test]
+    fn hidden_features_hide_their_components() {
+        assert_eq!(visible_components(SUPPORTED, ALL_FEATURES), SUPPORTED);
+
+        let no_avx512 = ComponentFeatures {
+            leaf7_ebx: 1 << 14,
+            ..ALL_FEATURES
+        };
+        assert_eq!(visible_components(SUPPORTED, no_avx512), SUPPORTED & !AVX512);
+        assert_eq!(checked_xcr0(0x2E7, visible_components(SUPPORTED, no_avx512)), None);
+
+        let no_avx = ComponentFeatures {
+            leaf1_ecx: 0,
+            ..ALL_FEATURES
+        };
+        assert_eq!(visible_components(SUPPORTED, no_avx), X87 | SSE | PKRU | AMX);
+
+        let no_amx = ComponentFeatures {
+            leaf7_edx: 0,
+            ..ALL_FEATURES
+        };
+        assert_eq!(visible_components(SUPPORTED, no_amx), 0x2E7);
+    }
+
+    #[test]
+    fn leaf_0xd_reports_the_visible_components() {
+        let host = CpuIdResult {
+            eax: SUPPORTED as u32,
+            ebx: 2696,
+            ecx: 11008,
+            edx: 0,
+        };
+
+        let mut result = host;
+        fixup_leaf(0, &mut result, SUPPORTED, 0x2E7, layout);
+        assert_eq!(result, CpuIdResult { ebx: 2696, ..host });
+
+        // Without AMX the area ends after PKRU.
+        let mut result = host;
+        fixup_leaf(0, &mut result, 0x2E7, 0x7, layout);
+        assert_eq!(
+            result,
+            CpuIdResult {
+                eax: 0x2E7,
+                ebx: 832,
+                ecx: 2696,
+                edx: 0
+            }
+        );
+
+        let mut result = CpuIdResult {
+            eax: 8192,
+            ebx: 2816,
+            ecx: 0b110,
+            edx: 0,
+        };
+        fixup_leaf(18, &mut result, 0x2E7, 0x7, layout);
+        assert_eq!(
+            result,
+            CpuIdResult {
+                eax: 0,
+                ebx: 0,
+                ecx: 0,
+                edx: 0
+            }
+        );
+
+        // Supervisor components of IA32_XSS are left alone.
+        let pt = CpuIdResult {
+            eax: 128,
+            ebx: 0,
+            ecx: 1,
+            edx: 0,
+        };
+        let mut result = pt;
+        fixup_leaf(8, &mut result, 0x2E7, 0x7, layout);
+        assert_eq!(result, pt);
+    }
+
+    #[test]
+    fn hosts_share_supersets_of_their_xcr0() {
+        let mut state = XState {
+            guest_xcr0: 0x7,
+            host_xcr0: 0x7,
+        };
+        assert!(state.shares_guest_xcr0());
+        state.guest_xcr0 = 0x2E7;
+        assert!(state.shares_guest_xcr0());
+        state.guest_xcr0 = 0x3;
+        assert!(!state.shares_guest_xcr0());
+    }
+}
@@ -13,9 +13,12 @@ extern crate alloc;
 extern crate static_assertions;
 
 pub mod allocator;
+pub mod config;
 pub mod error;
+pub mod event_log;
 pub mod global_const;
 pub mod intel;
 pub mod logger;
+pub mod stats;
 pub mod vmm;
 pub mod windows;
@@ -4,18 +4,39 @@
 //! to a serial console. This is particularly useful for debugging hypervisor and kernel-level
 //! development where traditional logging mechanisms might not be available.
 //!
+//! The latest lines logged through this logger or the logger of the UEFI image are also kept in a small
+//! ring, which the host exception handlers repeat in their crash reports, see `intel::host_arch`.
+//!
 //! Credits to Satoshi Tanda: https://github.com/tandasat/Hello-VT-rp/blob/main/hypervisor/src/serial_logger.rs
 //!
 
 use {
     crate::intel::support::{inb, outb},
-    core::{fmt, fmt::Write},
+    core::{
+        fmt,
+        fmt::Write,
+        ptr,
+        sync::atomic::{AtomicU16, Ordering},
+    },
+    shared::logring::{LogRing, MAX_LINE_SIZE},
     spin::Mutex,
 };
 
 /// The global serial port logger instance.
 static mut SERIAL_LOGGER: Option<SerialLogger> = None;
 
+/// The serial port crash reports are written to, the one of `init` or COM1.
+static CRASH_PORT: AtomicU16 = AtomicU16::new(SerialPort::COM1 as u16);
+
+/// The size of the memory of the recent lines, a few dozen lines.
+const RECENT_LINES_SIZE: usize = 0x1000;
+
+/// The memory of `RECENT_LINES`, only borrowed by the ring.
+static mut RECENT_LINES_MEMORY: [u8; RECENT_LINES_SIZE] = [0; RECENT_LINES_SIZE];
+
+/// The latest lines logged, the ring is created with the first line.
+static RECENT_LINES: Mutex<Option<LogRing<'static>>> = Mutex::new(None);
+
 /// Enum representing available serial ports.
 #[repr(u16)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -37,6 +58,7 @@ pub enum SerialPort {
 /// - `level`: The maximum log level filter. Messages with a level higher than this will not be logged.
 ///
 pub fn init(port: SerialPort, level: log::LevelFilter) {
+    CRASH_PORT.store(port as u16, Ordering::Relaxed);
     unsafe { SERIAL_LOGGER = Some(SerialLogger::new(port)) };
     let serial_logger = unsafe { SERIAL_LOGGER.as_ref().unwrap() };
 
@@ -122,6 +144,10 @@ impl log::Log for SerialLogger {
 
             // Format and print the log message with APIC ID, log level, and log message
             let _ = writeln!(serial, "vcpu-{} {}: {}", vcpu_id, record.level(), record.args());
+
+            let mut line = LineBuffer::new();
+            let _ = write!(line, "vcpu-{} {}: {}", vcpu_id, record.level(), record.args());
+            record_recent_line(line.as_bytes());
         }
     }
 
@@ -167,6 +193,91 @@ impl Write for Serial {
     }
 }
 
+/// Keeps `line` in the ring of recent lines, dropping the oldest lines if it is full.
+///
+/// # Arguments
+///
+/// * `line` - The formatted line, without the line break.
+pub fn record_recent_line(line: &[u8]) {
+    let mut recent_lines = RECENT_LINES.lock();
+    if recent_lines.is_none() {
+        // Only the ring borrows the memory, and it is created once.
+        *recent_lines = LogRing::create(unsafe { &mut *ptr::addr_of_mut!(RECENT_LINES_MEMORY) }).ok();
+    }
+
+    if let Some(ring) = recent_lines.as_mut() {
+        ring.push(line);
+    }
+}
+
+/// Writes the recent lines to the crash port, oldest first, and empties the ring.
+///
+/// The lock is only tried, the crash may have happened while a line was recorded.
+pub fn write_recent_lines() {
+    let mut serial = crash_writer();
+    let Some(mut recent_lines) = RECENT_LINES.try_lock() else {
+        let _ = writeln!(serial, "Recent log lines are unavailable, the ring is locked");
+        return;
+    };
+    let Some(ring) = recent_lines.as_mut() else {
+        return;
+    };
+
+    let mut buffer = [0u8; MAX_LINE_SIZE];
+    while let Some(line) = ring.pop(&mut buffer) {
+        let text = core::str::from_utf8(&buffer[..line.length]).unwrap_or("<invalid UTF-8>");
+        let _ = writeln!(serial, "  | {}{}", text, if line.truncated { "..." } else { "" });
+    }
+}
+
+/// Returns a writer to the crash port that doesn't take the lock of the logger.
+///
+/// Crash reports are written from exception handlers, which may have interrupted a line being logged.
+pub fn crash_writer() -> impl Write {
+    let port = if CRASH_PORT.load(Ordering::Relaxed) == SerialPort::COM2 as u16 {
+        SerialPort::COM2
+    } else {
+        SerialPort::COM1
+    };
+    Serial { port }
+}
+
+/// A line formatted without allocating, one byte longer than the ring keeps so `push` marks it truncated.
+pub struct LineBuffer {
+    bytes: [u8; MAX_LINE_SIZE + 1],
+    length: usize,
+}
+
+impl LineBuffer {
+    /// Creates an empty line.
+    pub fn new() -> Self {
+        Self {
+            bytes: [0; MAX_LINE_SIZE + 1],
+            length: 0,
+        }
+    }
+
+    /// Returns the bytes written so far.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.length]
+    }
+}
+
+impl Default for LineBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Write for LineBuffer {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        let count = text.len().min(self.bytes.len() - self.length);
+        self.bytes[self.length..self.length + count].copy_from_slice(&text.as_bytes()[..count]);
+        self.length += count;
+        Ok(())
+    }
+}
+
 /// Gets an APIC ID.
 ///
 /// # Returns
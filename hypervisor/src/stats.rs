@@ -0,0 +1,176 @@
+//! Counters and per-processor states read through `shared::hvstatus` and `Command::QueryStats`.
+//!
+//! Everything is an atomic updated with relaxed ordering, so readers running at any TPL on any processor
+//! see consistent values without taking a lock. The processors themselves are tracked by `intel::vcpu`.
+
+use {
+    crate::intel::vcpu,
+    core::sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    shared::{
+        hvstatus::{HvCpuStatus, HvHookSwapStatus, HvStats, CPU_STATE_VIRTUALIZED, HOOK_SWAP_VM_EXIT},
+        hypercall::StatsReport,
+    },
+};
+
+/// The VM exits handled on all processors.
+static VM_EXITS: AtomicU64 = AtomicU64::new(0);
+
+/// The hypercalls handled on all processors.
+static HYPERCALLS: AtomicU64 = AtomicU64::new(0);
+
+/// The NMIs of the guest queued on all processors.
+static NMIS_QUEUED: AtomicU64 = AtomicU64::new(0);
+
+/// The queued NMIs injected into the guest.
+static NMIS_REINJECTED: AtomicU64 = AtomicU64::new(0);
+
+/// The NMIs of the guest lost on all processors.
+static NMIS_DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// The MOVs from debug registers the guest executed on all processors.
+static DEBUG_REGISTER_READS: AtomicU64 = AtomicU64::new(0);
+
+/// The MOVs to debug registers the guest executed on all processors.
+static DEBUG_REGISTER_WRITES: AtomicU64 = AtomicU64::new(0);
+
+/// The INIT signals taken on all processors.
+static INIT_SIGNALS: AtomicU64 = AtomicU64::new(0);
+
+/// The SIPIs taken on all processors.
+static STARTUP_IPIS: AtomicU64 = AtomicU64::new(0);
+
+/// The `HOOK_SWAP_*` mode the processors selected when they were virtualized.
+static HOOK_SWAP_MODE: AtomicU32 = AtomicU32::new(HOOK_SWAP_VM_EXIT);
+
+/// The processors whose guest handles #VE.
+static VE_PROCESSORS: AtomicU32 = AtomicU32::new(0);
+
+/// The syscalls the hook of `syscall_watch` intercepted.
+static SYSCALLS_INTERCEPTED: AtomicU64 = AtomicU64::new(0);
+
+/// The intercepted syscalls a rule of `syscall_watch` matched.
+static SYSCALLS_WATCHED: AtomicU64 = AtomicU64::new(0);
+
+/// The VM exits the hook of `syscall_watch` took.
+static SYSCALL_EXITS: AtomicU64 = AtomicU64::new(0);
+
+/// Counts a VM exit.
+pub fn record_vm_exit() {
+    VM_EXITS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a hypercall.
+pub fn record_hypercall() {
+    HYPERCALLS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts an NMI of the guest queued for reinjection.
+pub fn record_nmi_queued() {
+    NMIS_QUEUED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a queued NMI injected into the guest.
+pub fn record_nmi_reinjected() {
+    NMIS_REINJECTED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts an NMI of the guest that was lost.
+pub fn record_nmi_dropped() {
+    NMIS_DROPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a MOV from a debug register of the guest.
+pub fn record_debug_register_read() {
+    DEBUG_REGISTER_READS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a MOV to a debug register of the guest.
+pub fn record_debug_register_write() {
+    DEBUG_REGISTER_WRITES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts an INIT signal that reset the guest of a processor.
+pub fn record_init_signal() {
+    INIT_SIGNALS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a SIPI that started the guest of a processor.
+pub fn record_startup_ipi() {
+    STARTUP_IPIS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records the `HOOK_SWAP_*` mode selected at setup.
+pub fn record_hook_swap_mode(mode: u32) {
+    HOOK_SWAP_MODE.store(mode, Ordering::Relaxed);
+}
+
+/// Counts a processor whose guest #VE handler was installed.
+pub fn record_ve_processor() {
+    VE_PROCESSORS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a syscall `syscall_watch` intercepted.
+///
+/// # Arguments
+///
+/// * `watched` - Whether a rule matched the syscall.
+/// * `exits` - The VM exits taken for the syscall.
+pub fn record_syscall(watched: bool, exits: u64) {
+    SYSCALLS_INTERCEPTED.fetch_add(1, Ordering::Relaxed);
+    SYSCALLS_WATCHED.fetch_add(watched as u64, Ordering::Relaxed);
+    SYSCALL_EXITS.fetch_add(exits, Ordering::Relaxed);
+}
+
+/// Returns the syscalls intercepted, those a rule matched and the VM exits taken for them.
+pub fn syscall_stats() -> [u64; 3] {
+    [
+        SYSCALLS_INTERCEPTED.load(Ordering::Relaxed),
+        SYSCALLS_WATCHED.load(Ordering::Relaxed),
+        SYSCALL_EXITS.load(Ordering::Relaxed),
+    ]
+}
+
+/// Returns the status of the `index`-th processor to start, `None` past the last one.
+pub fn cpu_status(index: usize) -> Option<HvCpuStatus> {
+    let (apic_id, vcpu) = vcpu::by_start_order(index)?;
+    Some(HvCpuStatus {
+        apic_id,
+        state: vcpu.state(),
+    })
+}
+
+/// Returns the counters.
+pub fn stats() -> HvStats {
+    HvStats {
+        processors: vcpu::started() as u32,
+        virtualized: vcpu::count(CPU_STATE_VIRTUALIZED) as u32,
+        vm_exits: VM_EXITS.load(Ordering::Relaxed),
+        hypercalls: HYPERCALLS.load(Ordering::Relaxed),
+    }
+}
+
+/// Returns the counters for `Command::QueryStats`, the NMI, debug register and INIT/SIPI counters included.
+pub fn stats_report() -> StatsReport {
+    let stats = stats();
+    StatsReport {
+        processors: stats.processors,
+        virtualized: stats.virtualized,
+        vm_exits: stats.vm_exits,
+        hypercalls: stats.hypercalls,
+        nmis_queued: NMIS_QUEUED.load(Ordering::Relaxed),
+        nmis_reinjected: NMIS_REINJECTED.load(Ordering::Relaxed),
+        nmis_dropped: NMIS_DROPPED.load(Ordering::Relaxed),
+        debug_register_reads: DEBUG_REGISTER_READS.load(Ordering::Relaxed),
+        debug_register_writes: DEBUG_REGISTER_WRITES.load(Ordering::Relaxed),
+        init_signals: INIT_SIGNALS.load(Ordering::Relaxed),
+        startup_ipis: STARTUP_IPIS.load(Ordering::Relaxed),
+    }
+}
+
+/// Returns how the views of EPT hooks are switched.
+pub fn hook_swap_status() -> HvHookSwapStatus {
+    HvHookSwapStatus {
+        mode: HOOK_SWAP_MODE.load(Ordering::Relaxed),
+        ve_processors: VE_PROCESSORS.load(Ordering::Relaxed),
+    }
+}
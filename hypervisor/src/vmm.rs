@@ -6,27 +6,34 @@
 
 use {
     crate::{
+        config,
         error::HypervisorError,
         intel::{
             bitmap::MsrAccessType,
-            capture::GuestRegisters,
-            support::{rdmsr, vmread, vmwrite},
+            capture::{restore_registers, GuestRegisters},
+            nmi, shootdown,
+            support::{cr4, cr4_write, rdmsr, vmread, vmwrite, vmxoff},
+            vcpu,
             vm::Vm,
             vmerror::VmxBasicExitReason,
             vmexit::{
                 cpuid::handle_cpuid,
                 cr::handle_cr_reg_access,
+                dr::handle_mov_dr,
                 ept_misconfiguration::handle_ept_misconfiguration,
                 ept_violation::handle_ept_violation,
                 exception::{handle_exception, handle_undefined_opcode_exception},
                 halt::handle_halt,
                 init::handle_init_signal,
+                interrupt_window::handle_interrupt_window,
                 invd::handle_invd,
                 invept::handle_invept,
+                invlpg::{handle_invlpg, handle_invpcid},
                 invvpid::handle_invvpid,
                 msr::handle_msr_access,
-                mtf::handle_monitor_trap_flag,
-                rdtsc::handle_rdtsc,
+                mtf::{self, handle_monitor_trap_flag},
+                nmi_window::handle_nmi_window,
+                rdtsc::{handle_rdtsc, handle_rdtscp},
                 sipi::handle_sipi_signal,
                 vmcall::handle_vmcall,
                 vmxon::handle_vmxon,
@@ -34,11 +41,13 @@ use {
                 ExitType,
             },
         },
+        stats,
         windows::eprocess::ProcessInformation,
     },
     log::*,
+    shared::{features::HvFeatureFlags, hvstatus::CPU_STATE_FAILED},
     x86::{
-        msr::IA32_VMX_EPT_VPID_CAP,
+        msr::{IA32_FEATURE_CONTROL, IA32_VMX_EPT_VPID_CAP},
         vmx::vmcs::{guest, ro},
     },
 };
@@ -52,32 +61,39 @@ use {
 ///
 /// - `guest_registers`: The initial state of the guest's general-purpose registers.
 ///
+/// Failures before the first VM entry succeeded leave VMX operation and return to the guest bare metal,
+/// with the processor marked as failed in `vcpu`, instead of halting it.
+///
 /// # Panics
 ///
-/// Panics if the CPU is not supported, VMX cannot be enabled, VM or VMCS activation fails,
-/// or an unhandled VM exit reason is encountered.
+/// Panics if an unhandled VM exit reason is encountered once the guest runs.
 pub fn start_hypervisor(guest_registers: &GuestRegisters) -> ! {
     debug!("Starting hypervisor");
 
-    match check_supported_cpu() {
-        Ok(_) => debug!("CPU is supported"),
-        Err(e) => panic!("CPU is not supported: {:?}", e),
-    };
+    if let Err(e) = check_supported_cpu() {
+        abandon(guest_registers, false, "CPU is not supported", e);
+    }
+    debug!("CPU is supported");
 
     let mut vm = unsafe { Vm::zeroed().assume_init() };
-    match vm.init(guest_registers) {
-        Ok(_) => debug!("VM initialized"),
-        Err(e) => panic!("Failed to initialize VM: {:?}", e),
+    if let Err(e) = vm.init(guest_registers) {
+        abandon(guest_registers, false, "Failed to initialize VM", e);
     }
+    debug!("VM initialized");
 
-    match vm.activate_vmxon() {
-        Ok(_) => debug!("VMX enabled"),
-        Err(e) => panic!("Failed to enable VMX: {:?}", e),
+    if let Err(e) = vm.activate_vmxon() {
+        abandon(guest_registers, false, "Failed to enable VMX", e);
     }
+    debug!("VMX enabled");
 
-    match vm.activate_vmcs() {
-        Ok(_) => debug!("VMCS activated"),
-        Err(e) => panic!("Failed to activate VMCS: {:?}", e),
+    if let Err(e) = vm.activate_vmcs() {
+        abandon(guest_registers, true, "Failed to activate VMCS", e);
+    }
+    debug!("VMCS activated");
+
+    // The VM lives on the host stack of this processor, which this function never returns from.
+    if let Some(vcpu) = vcpu::current() {
+        vcpu.set_vm(&mut vm);
     }
 
     trace!("VMCS Dump: {:#x?}", vm.vmcs_region);
@@ -96,16 +112,38 @@ pub fn start_hypervisor(guest_registers: &GuestRegisters) -> ! {
     info!("Launching the VM until a vmexit occurs...");
 
     loop {
-        if let Ok(basic_exit_reason) = vm.run() {
-            // Log the VM exit reason along with the current process information, only if available
-            if let Some(p) = ProcessInformation::get_current_process_info() {
-                debug!(
-                    "VM exit reason: {:?}, ImageFileName: {}, UniqueProcessId: {}, DirectoryTableBase: {:#x}",
-                    basic_exit_reason, p.file_name, p.unique_process_id, p.directory_table_base
-                );
-            } else {
-                if basic_exit_reason != VmxBasicExitReason::Cpuid {
-                    debug!("VM exit reason: {:?}", basic_exit_reason);
+        shootdown::before_vm_entry(&mut vm);
+        nmi::before_vm_entry(&mut vm.events);
+        vm.events.flush();
+        vm.debug_regs.before_vm_entry();
+        vm.xstate.before_vm_entry();
+        let result = match vm.run() {
+            // The guest never ran yet, so it can still continue bare metal.
+            Err(e) if !vm.has_launched => abandon(guest_registers, true, "Failed to launch the VM", e),
+            result => result,
+        };
+
+        if let Ok(basic_exit_reason) = result {
+            vm.debug_regs.after_vm_exit();
+            vm.xstate.after_vm_exit();
+            shootdown::after_vm_exit(&mut vm);
+            vm.events.after_vm_exit();
+            nmi::after_vm_exit(basic_exit_reason);
+            stats::record_vm_exit();
+            vm.record_ept_view_at_exit();
+
+            // Log the VM exit reason along with the current process information, only if available.
+            // Looking up the process on every exit is only worth it when the exit is logged.
+            if config::has_feature(HvFeatureFlags::LOG_VMEXITS) {
+                if let Some(p) = ProcessInformation::get_current_process_info() {
+                    debug!(
+                        "VM exit reason: {:?}, ImageFileName: {}, UniqueProcessId: {}, DirectoryTableBase: {:#x}",
+                        basic_exit_reason, p.file_name, p.unique_process_id, p.directory_table_base
+                    );
+                } else {
+                    if basic_exit_reason != VmxBasicExitReason::Cpuid {
+                        debug!("VM exit reason: {:?}", basic_exit_reason);
+                    }
                 }
             }
 
@@ -113,39 +151,49 @@ pub fn start_hypervisor(guest_registers: &GuestRegisters) -> ! {
                 // 0
                 VmxBasicExitReason::ExceptionOrNmi => handle_exception(&mut vm),
                 // 3
-                VmxBasicExitReason::InitSignal => handle_init_signal(&mut vm.guest_registers),
+                VmxBasicExitReason::InitSignal => handle_init_signal(&mut vm).expect("Failed to handle INIT"),
                 // 4
                 VmxBasicExitReason::StartupIpi => handle_sipi_signal(&mut vm.guest_registers),
+                // 7
+                VmxBasicExitReason::InterruptWindow => handle_interrupt_window(),
+                // 8
+                VmxBasicExitReason::NmiWindow => handle_nmi_window(),
                 // 10
                 VmxBasicExitReason::Cpuid => handle_cpuid(&mut vm).expect("Failed to handle CPUID"),
                 // 11
-                VmxBasicExitReason::Getsec => handle_undefined_opcode_exception(),
+                VmxBasicExitReason::Getsec => handle_undefined_opcode_exception(&mut vm),
                 // 12
                 VmxBasicExitReason::Hlt => handle_halt(),
                 // 13
                 VmxBasicExitReason::Invd => handle_invd(&mut vm.guest_registers),
+                // 14
+                VmxBasicExitReason::Invlpg => handle_invlpg(&mut vm),
+                // 16
+                VmxBasicExitReason::Rdtsc => handle_rdtsc(&mut vm),
                 // 18
                 VmxBasicExitReason::Vmcall => handle_vmcall(&mut vm).expect("Failed to handle VMCALL"),
                 // 19
-                VmxBasicExitReason::Vmclear => handle_undefined_opcode_exception(),
+                VmxBasicExitReason::Vmclear => handle_undefined_opcode_exception(&mut vm),
                 // 20
-                VmxBasicExitReason::Vmlaunch => handle_undefined_opcode_exception(),
+                VmxBasicExitReason::Vmlaunch => handle_undefined_opcode_exception(&mut vm),
                 // 21
-                VmxBasicExitReason::Vmptrld => handle_undefined_opcode_exception(),
+                VmxBasicExitReason::Vmptrld => handle_undefined_opcode_exception(&mut vm),
                 // 22
-                VmxBasicExitReason::Vmptrst => handle_undefined_opcode_exception(),
+                VmxBasicExitReason::Vmptrst => handle_undefined_opcode_exception(&mut vm),
                 // 23
-                VmxBasicExitReason::Vmread => handle_undefined_opcode_exception(),
+                VmxBasicExitReason::Vmread => handle_undefined_opcode_exception(&mut vm),
                 // 24
-                VmxBasicExitReason::Vmresume => handle_undefined_opcode_exception(),
+                VmxBasicExitReason::Vmresume => handle_undefined_opcode_exception(&mut vm),
                 // 25
-                VmxBasicExitReason::Vmwrite => handle_undefined_opcode_exception(),
+                VmxBasicExitReason::Vmwrite => handle_undefined_opcode_exception(&mut vm),
                 // 26
-                VmxBasicExitReason::Vmxoff => handle_undefined_opcode_exception(),
+                VmxBasicExitReason::Vmxoff => handle_undefined_opcode_exception(&mut vm),
                 // 27
-                VmxBasicExitReason::Vmxon => handle_vmxon(),
+                VmxBasicExitReason::Vmxon => handle_vmxon(&mut vm),
                 // 28
                 VmxBasicExitReason::ControlRegisterAccesses => handle_cr_reg_access(&mut vm).expect("Failed to handle CR access"),
+                // 29
+                VmxBasicExitReason::MovDr => handle_mov_dr(&mut vm),
                 // 31
                 VmxBasicExitReason::Rdmsr => handle_msr_access(&mut vm, MsrAccessType::Read).expect("Failed to handle RDMSR"),
                 // 32
@@ -159,16 +207,21 @@ pub fn start_hypervisor(guest_registers: &GuestRegisters) -> ! {
                 // 50
                 VmxBasicExitReason::Invept => handle_invept(),
                 // 51
-                VmxBasicExitReason::Rdtsc => handle_rdtsc(&mut vm.guest_registers),
+                VmxBasicExitReason::Rdtscp => handle_rdtscp(&mut vm),
                 // 53
                 VmxBasicExitReason::Invvpid => handle_invvpid(),
                 // 55
                 VmxBasicExitReason::Xsetbv => handle_xsetbv(&mut vm),
+                // 58
+                VmxBasicExitReason::Invpcid => handle_invpcid(&mut vm),
                 _ => panic!("Unhandled VM exit reason: {:?}", basic_exit_reason),
             };
 
             if exit_type == ExitType::IncrementRIP {
                 advance_guest_rip(&mut vm.guest_registers);
+
+                // The emulated instruction completed, it counts as a single-stepped one.
+                mtf::instruction_completed(&mut vm).expect("Failed to complete the single-step");
             }
         } else {
             panic!("Failed to run the VM");
@@ -176,6 +229,42 @@ pub fn start_hypervisor(guest_registers: &GuestRegisters) -> ! {
     }
 }
 
+/// Gives up virtualizing the current processor and returns to the guest bare metal.
+///
+/// Leaves VMX operation if it was entered, marks the processor as failed and continues at the registers the
+/// guest captured, which see `capture_registers` return `true` once more. The host stack is not freed, the
+/// guest may still be running on memory allocated next to it.
+///
+/// # Arguments
+///
+/// - `guest_registers`: The state captured before the processor started virtualizing itself.
+/// - `vmx_on`: Whether VMXON succeeded.
+/// - `stage`: What failed, for the log.
+/// - `error`: Why it failed.
+fn abandon(guest_registers: &GuestRegisters, vmx_on: bool, stage: &str, error: HypervisorError) -> ! {
+    /// [Bit 13] CR4.VMXE, set by `Vmxon::enable_vmx_operation`.
+    const CR4_VMXE: u64 = 1 << 13;
+
+    error!("{} on processor {}: {:?}, continuing without the hypervisor", stage, vcpu::current_apic_id(), error);
+
+    if vmx_on {
+        if let Err(e) = vmxoff() {
+            error!("Failed to leave VMX operation: {:?}", e);
+        }
+    }
+    cr4_write(cr4() & !CR4_VMXE);
+
+    if let Some(vcpu) = vcpu::current() {
+        vcpu.set_vm(core::ptr::null_mut());
+        vcpu.set_state(CPU_STATE_FAILED);
+    }
+
+    // A non-zero RAX tells the caller of `capture_registers` that it returned a second time.
+    let mut registers = *guest_registers;
+    registers.rax = 1;
+    unsafe { restore_registers(&registers) }
+}
+
 /// Advances the guest's instruction pointer after handling a VM exit.
 ///
 /// Ensures the guest VM does not re-execute the instruction causing the VM exit
@@ -192,6 +281,31 @@ fn advance_guest_rip(guest_registers: &mut GuestRegisters) {
     // trace!("Guest RIP advanced to: {:#x}", vmread(guest::RIP));
 }
 
+/// Checks whether the current processor can run the hypervisor before any processor is virtualized.
+///
+/// Runs the checks `start_hypervisor` repeats on every processor, plus a read-only look at
+/// IA32_FEATURE_CONTROL, so the image can decline the platform instead of panicking halfway through.
+///
+/// # Returns
+///
+/// Returns `Ok(())` if the processor meets all requirements, otherwise returns `Err(HypervisorError)`.
+pub fn check_platform() -> Result<(), HypervisorError> {
+    /// [Bit 0] Once set, the MSR can't be written until the next reset.
+    const LOCK: u64 = 1 << 0;
+
+    /// [Bit 2] VMXON is allowed outside SMX operation.
+    const VMXON_OUTSIDE_SMX: u64 = 1 << 2;
+
+    check_supported_cpu()?;
+
+    let feature_control = rdmsr(IA32_FEATURE_CONTROL);
+    if feature_control & LOCK != 0 && feature_control & VMXON_OUTSIDE_SMX == 0 {
+        return Err(HypervisorError::VMXBIOSLock);
+    }
+
+    Ok(())
+}
+
 /// Checks if the CPU is supported for hypervisor operation.
 ///
 /// Verifies the CPU is Intel with VMX support and Memory Type Range Registers (MTRRs) support.
@@ -250,7 +364,8 @@ fn has_vmx_support() -> Result<(), HypervisorError> {
 ///
 /// # Returns
 ///
-/// Returns `Ok(())` if EPT is supported, otherwise `Err(HypervisorError::EPTUnsupported)`.
+/// Returns `Ok(())` if EPT is supported, otherwise `Err(HypervisorError::EPTUnsupported)`. VPIDs are optional,
+/// see `intel::invvpid`.
 ///
 /// Credits Satoshi Tanda: https://github.com/tandasat/MiniVisorPkg/blob/master/Sources/MiniVisor.c#L534-L550
 fn check_ept_support() -> Result<(), HypervisorError> {
@@ -272,27 +387,10 @@ fn check_ept_support() -> Result<(), HypervisorError> {
     /// [Bit 26] When set to 1, the all-context INVEPT type is supported.
     const INVEPT_ALL_CONTEXTS: u64 = 1 << 26;
 
-    /// [Bit 32] When set to 1, the INVVPID instruction is supported.
-    const INVVPID: u64 = 1 << 32;
-
-    /// [Bit 41] When set to 1, the single-context INVVPID type is supported.
-    const INVVPID_SINGLE_CONTEXT: u64 = 1 << 41;
-
-    /// [Bit 42] When set to 1, the all-context INVVPID type is supported.
-    const INVVPID_ALL_CONTEXTS: u64 = 1 << 42;
-
     let ept_vpid_cap = rdmsr(IA32_VMX_EPT_VPID_CAP);
 
     // Construct a combined mask for all required features for simplicity
-    let required_features = PAGE_WALK_LENGTH_4
-        | MEMORY_TYPE_WRITE_BACK
-        | PDE_2MB_PAGES
-        | INVEPT
-        | INVEPT_SINGLE_CONTEXT
-        | INVEPT_ALL_CONTEXTS
-        | INVVPID
-        | INVVPID_SINGLE_CONTEXT
-        | INVVPID_ALL_CONTEXTS;
+    let required_features = PAGE_WALK_LENGTH_4 | MEMORY_TYPE_WRITE_BACK | PDE_2MB_PAGES | INVEPT | INVEPT_SINGLE_CONTEXT | INVEPT_ALL_CONTEXTS;
 
     if ept_vpid_cap & required_features != required_features {
         return Err(HypervisorError::EPTUnsupported);
@@ -0,0 +1,656 @@
+//! Finds the image of ntoskrnl.exe in a Windows guest and resolves its exports, without asking the guest.
+//!
+//! The kernel is found from the address LSTAR holds, KiSystemCall64 or its KVA shadow twin, both inside the
+//! image: `locate` goes back a page at a time to the first MZ header whose image covers the address. Everything
+//! is read with `guest_memory` through the page tables of the kernel, and every field of the headers and of the
+//! export directory is checked against the size of the image before it's followed, so a corrupt or hostile image
+//! fails with `KernelError::Malformed` instead of sending the hypervisor elsewhere in guest memory.
+//!
+//! `ExportCache` keeps the exports already resolved, for the kernel it resolved them in: another base, after a
+//! reboot with KASLR, or another CR3 drops them.
+
+use {
+    crate::{
+        intel::{
+            guest_memory::{read, GuestMemory, Span},
+            hooks::hook_manager::SHARED_HOOK_MANAGER,
+            page_walk::{walk, Paging},
+        },
+        windows::nt::{
+            pe::djb2_hash,
+            types::{
+                IMAGE_DIRECTORY_ENTRY_EXPORT, IMAGE_DOS_HEADER, IMAGE_DOS_SIGNATURE, IMAGE_EXPORT_DIRECTORY, IMAGE_FILE_MACHINE_AMD64,
+                IMAGE_NT_HEADERS64, IMAGE_NT_OPTIONAL_HDR64_MAGIC, IMAGE_NT_SIGNATURE,
+            },
+        },
+    },
+    core::mem::{offset_of, size_of},
+    shared::vmcall::MAX_EXPORT_NAME_LENGTH,
+    x86::bits64::paging::BASE_PAGE_SIZE,
+};
+
+/// The bytes the headers of the image must fit in, the first page.
+const HEADERS_SIZE: u64 = BASE_PAGE_SIZE as u64;
+
+/// The largest image taken for ntoskrnl.exe, also how far `locate` goes back.
+const MAX_KERNEL_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The most exports an export directory is taken to have.
+const MAX_EXPORTS: u64 = 0x10000;
+
+/// The exports `ExportCache` keeps.
+const CACHED_EXPORTS: usize = 32;
+
+/// Why the kernel or one of its exports couldn't be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelError {
+    /// A guest virtual address isn't mapped.
+    Unreadable(u64),
+
+    /// The headers or the export directory aren't those of a 64-bit image, or point outside of it.
+    Malformed,
+
+    /// The kernel doesn't export the name, or forwards it to another image.
+    NotExported,
+}
+
+/// The image of ntoskrnl.exe and the address space it's read in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Kernel {
+    /// The virtual address of the image.
+    pub base: u64,
+
+    /// The bytes of the image.
+    pub size: u64,
+
+    /// A CR3 mapping the kernel.
+    pub cr3: u64,
+}
+
+impl Kernel {
+    /// Returns the kernel the guest wrote LSTAR with, `None` before it did.
+    pub fn current() -> Option<Self> {
+        let hook_manager = SHARED_HOOK_MANAGER.lock();
+        (hook_manager.ntoskrnl_base_va != 0).then_some(Self {
+            base: hook_manager.ntoskrnl_base_va,
+            size: hook_manager.ntoskrnl_size,
+            cr3: hook_manager.ntoskrnl_cr3,
+        })
+    }
+
+    /// Returns whether a virtual address is inside the image.
+    pub fn contains(&self, va: u64) -> bool {
+        va.wrapping_sub(self.base) < self.size
+    }
+}
+
+/// Guest virtual memory of an address space, read through its page tables.
+pub struct KernelMemory<'a, M: GuestMemory> {
+    memory: &'a M,
+    paging: &'a Paging,
+    cr3: u64,
+}
+
+impl<'a, M: GuestMemory> KernelMemory<'a, M> {
+    /// Creates a reader of the address space of `cr3`.
+    pub fn new(memory: &'a M, paging: &'a Paging, cr3: u64) -> Self {
+        Self { memory, paging, cr3 }
+    }
+
+    /// Fills a buffer with the bytes at a virtual address.
+    pub fn read_into(&self, address: u64, buffer: &mut [u8]) -> Result<(), KernelError> {
+        let span = Span::Virtual { cr3: self.cr3, address };
+        if !span.holds(self.paging, buffer.len() as u64) {
+            return Err(KernelError::Unreadable(address));
+        }
+
+        read(self.memory, self.paging, span, buffer).map_err(|done| KernelError::Unreadable(address + done))
+    }
+
+    /// Returns the bytes at a virtual address.
+    fn bytes<const N: usize>(&self, address: u64) -> Result<[u8; N], KernelError> {
+        let mut bytes = [0; N];
+        self.read_into(address, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn read_u16(&self, address: u64) -> Result<u16, KernelError> {
+        self.bytes(address).map(u16::from_le_bytes)
+    }
+
+    pub fn read_u32(&self, address: u64) -> Result<u32, KernelError> {
+        self.bytes(address).map(u32::from_le_bytes)
+    }
+
+    pub fn read_u64(&self, address: u64) -> Result<u64, KernelError> {
+        self.bytes(address).map(u64::from_le_bytes)
+    }
+
+    /// Returns the guest-physical address of a virtual address.
+    pub fn physical(&self, address: u64) -> Result<u64, KernelError> {
+        walk(self.memory, self.paging, self.cr3, address)
+            .map(|walk| walk.pa)
+            .map_err(|_| KernelError::Unreadable(address))
+    }
+}
+
+/// Returns whether `length` bytes at an RVA stay inside an image of `size` bytes.
+fn within(rva: u64, length: u64, size: u64) -> bool {
+    rva.checked_add(length).is_some_and(|end| end <= size)
+}
+
+/// What the headers of an image tell about it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ImageHeaders {
+    /// `SizeOfImage`.
+    size: u64,
+
+    /// The RVA and the size of the export directory, zero without one.
+    exports: (u64, u64),
+}
+
+/// Reads and checks the headers of a 64-bit image.
+///
+/// # Arguments
+///
+/// * `memory` - The address space the image is in.
+/// * `base` - The virtual address of the image.
+fn image_headers(memory: &KernelMemory<impl GuestMemory>, base: u64) -> Result<ImageHeaders, KernelError> {
+    if memory.read_u16(base)? != IMAGE_DOS_SIGNATURE {
+        return Err(KernelError::Malformed);
+    }
+
+    let e_lfanew = memory.read_u32(base + offset_of!(IMAGE_DOS_HEADER, e_lfanew) as u64)? as u64;
+    if !within(e_lfanew, size_of::<IMAGE_NT_HEADERS64>() as u64, HEADERS_SIZE) {
+        return Err(KernelError::Malformed);
+    }
+
+    let nt_headers = base + e_lfanew;
+    let field = |offset: usize| nt_headers + offset as u64;
+    if memory.read_u32(nt_headers)? != IMAGE_NT_SIGNATURE
+        || memory.read_u16(field(offset_of!(IMAGE_NT_HEADERS64, FileHeader.Machine)))? != IMAGE_FILE_MACHINE_AMD64
+        || memory.read_u16(field(offset_of!(IMAGE_NT_HEADERS64, OptionalHeader.Magic)))? != IMAGE_NT_OPTIONAL_HDR64_MAGIC
+    {
+        return Err(KernelError::Malformed);
+    }
+
+    let size = memory.read_u32(field(offset_of!(IMAGE_NT_HEADERS64, OptionalHeader.SizeOfImage)))? as u64;
+    if !(HEADERS_SIZE..=MAX_KERNEL_SIZE).contains(&size) {
+        return Err(KernelError::Malformed);
+    }
+
+    let directories = memory.read_u32(field(offset_of!(IMAGE_NT_HEADERS64, OptionalHeader.NumberOfRvaAndSizes)))?;
+    let exports = if directories > IMAGE_DIRECTORY_ENTRY_EXPORT as u32 {
+        let directory = field(offset_of!(IMAGE_NT_HEADERS64, OptionalHeader.DataDirectory));
+        (memory.read_u32(directory)? as u64, memory.read_u32(directory + 4)? as u64)
+    } else {
+        (0, 0)
+    };
+
+    Ok(ImageHeaders { size, exports })
+}
+
+/// Finds the image of ntoskrnl.exe around an address inside it.
+///
+/// # Arguments
+///
+/// * `memory` - The guest-physical memory.
+/// * `paging` - The paging state of the guest.
+/// * `cr3` - A CR3 mapping the kernel.
+/// * `address` - An address inside the image, the one LSTAR holds.
+///
+/// # Returns
+///
+/// The kernel, `None` if no image within `MAX_KERNEL_SIZE` below the address covers it.
+pub fn locate(memory: &impl GuestMemory, paging: &Paging, cr3: u64, address: u64) -> Option<Kernel> {
+    let reader = KernelMemory::new(memory, paging, cr3);
+    let mut base = address & !(BASE_PAGE_SIZE as u64 - 1);
+
+    for _ in 0..MAX_KERNEL_SIZE / BASE_PAGE_SIZE as u64 {
+        // Unmapped pages and MZ bytes that aren't headers are passed over alike.
+        if let Ok(headers) = image_headers(&reader, base) {
+            if address - base < headers.size {
+                return Some(Kernel {
+                    base,
+                    size: headers.size,
+                    cr3,
+                });
+            }
+        }
+        base = base.checked_sub(BASE_PAGE_SIZE as u64)?;
+    }
+
+    None
+}
+
+/// The export directory of an image, its arrays checked to be inside the image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exports {
+    base: u64,
+    size: u64,
+    directory: (u64, u64),
+    functions: u64,
+    names: u64,
+    ordinals: u64,
+    function_count: u64,
+    name_count: u64,
+}
+
+impl Exports {
+    /// Reads the export directory of the kernel.
+    ///
+    /// # Arguments
+    ///
+    /// * `memory` - The address space of the kernel.
+    /// * `base` - The virtual address of the image.
+    pub fn parse(memory: &KernelMemory<impl GuestMemory>, base: u64) -> Result<Self, KernelError> {
+        let headers = image_headers(memory, base)?;
+        let (rva, length) = headers.exports;
+        if length < size_of::<IMAGE_EXPORT_DIRECTORY>() as u64 || !within(rva, length, headers.size) {
+            return Err(KernelError::Malformed);
+        }
+
+        let field = |offset: usize| memory.read_u32(base + rva + offset as u64).map(|value| value as u64);
+        let exports = Self {
+            base,
+            size: headers.size,
+            directory: (rva, length),
+            functions: field(offset_of!(IMAGE_EXPORT_DIRECTORY, AddressOfFunctions))?,
+            names: field(offset_of!(IMAGE_EXPORT_DIRECTORY, AddressOfNames))?,
+            ordinals: field(offset_of!(IMAGE_EXPORT_DIRECTORY, AddressOfNameOrdinals))?,
+            function_count: field(offset_of!(IMAGE_EXPORT_DIRECTORY, NumberOfFunctions))?,
+            name_count: field(offset_of!(IMAGE_EXPORT_DIRECTORY, NumberOfNames))?,
+        };
+
+        if exports.function_count > MAX_EXPORTS
+            || exports.name_count > exports.function_count
+            || !within(exports.functions, exports.function_count * 4, exports.size)
+            || !within(exports.names, exports.name_count * 4, exports.size)
+            || !within(exports.ordinals, exports.name_count * 2, exports.size)
+        {
+            return Err(KernelError::Malformed);
+        }
+
+        Ok(exports)
+    }
+
+    /// Returns the address of an export.
+    ///
+    /// The names of an export directory are sorted, so the lookup is a binary search reading a few of them.
+    ///
+    /// # Arguments
+    ///
+    /// * `memory` - The address space of the kernel.
+    /// * `name` - The name of the export, at most `MAX_EXPORT_NAME_LENGTH` bytes.
+    pub fn resolve(&self, memory: &KernelMemory<impl GuestMemory>, name: &[u8]) -> Result<u64, KernelError> {
+        if name.is_empty() || name.len() > MAX_EXPORT_NAME_LENGTH {
+            return Err(KernelError::NotExported);
+        }
+
+        // A candidate is read one byte past the name, enough to order it against the name.
+        let mut buffer = [0; MAX_EXPORT_NAME_LENGTH + 1];
+        let (mut low, mut high) = (0, self.name_count);
+
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let rva = memory.read_u32(self.base + self.names + middle * 4)? as u64;
+            if rva >= self.size {
+                return Err(KernelError::Malformed);
+            }
+
+            let candidate = &mut buffer[..(name.len() as u64 + 1).min(self.size - rva) as usize];
+            memory.read_into(self.base + rva, candidate)?;
+            let length = candidate.iter().position(|&byte| byte == 0).unwrap_or(candidate.len());
+
+            match candidate[..length].cmp(name) {
+                core::cmp::Ordering::Less => low = middle + 1,
+                core::cmp::Ordering::Greater => high = middle,
+                core::cmp::Ordering::Equal => return self.function(memory, middle),
+            }
+        }
+
+        Err(KernelError::NotExported)
+    }
+
+    /// Returns the address of the function of the name at an index of the names.
+    fn function(&self, memory: &KernelMemory<impl GuestMemory>, index: u64) -> Result<u64, KernelError> {
+        let ordinal = memory.read_u16(self.base + self.ordinals + index * 2)? as u64;
+        if ordinal >= self.function_count {
+            return Err(KernelError::Malformed);
+        }
+
+        let rva = memory.read_u32(self.base + self.functions + ordinal * 4)? as u64;
+        let (directory, length) = self.directory;
+        match rva {
+            0 => Err(KernelError::NotExported),
+            // A forwarder, the name of an export of another image.
+            rva if rva >= directory && rva < directory + length => Err(KernelError::NotExported),
+            rva if rva >= self.size => Err(KernelError::Malformed),
+            rva => Ok(self.base + rva),
+        }
+    }
+}
+
+/// Returns the address of an export of the kernel.
+///
+/// # Arguments
+///
+/// * `memory` - The guest-physical memory.
+/// * `paging` - The paging state of the guest.
+/// * `kernel` - The kernel of the guest.
+/// * `name` - The name of the export.
+pub fn resolve_export(memory: &impl GuestMemory, paging: &Paging, kernel: Kernel, name: &[u8]) -> Result<u64, KernelError> {
+    let reader = KernelMemory::new(memory, paging, kernel.cr3);
+    Exports::parse(&reader, kernel.base)?.resolve(&reader, name)
+}
+
+/// An export `ExportCache` keeps.
+#[derive(Debug, Clone, Copy)]
+struct CachedExport {
+    /// The `djb2_hash` of the name, compared before the name.
+    hash: u32,
+
+    /// The name, its first `length` bytes.
+    name: [u8; MAX_EXPORT_NAME_LENGTH],
+
+    /// The bytes of the name.
+    length: usize,
+
+    /// The address of the export.
+    address: u64,
+}
+
+impl CachedExport {
+    /// Returns whether the export has a name, of the given hash.
+    fn is(&self, hash: u32, name: &[u8]) -> bool {
+        self.hash == hash && &self.name[..self.length] == name
+    }
+}
+
+/// The exports of the kernel resolved so far, by their name.
+#[derive(Debug, Clone)]
+pub struct ExportCache {
+    /// The kernel the exports were resolved in.
+    kernel: Option<Kernel>,
+
+    /// The exports, replaced round-robin.
+    entries: [Option<CachedExport>; CACHED_EXPORTS],
+
+    /// The entry replaced next.
+    next: usize,
+}
+
+impl ExportCache {
+    /// Creates an empty cache.
+    pub const fn new() -> Self {
+        Self {
+            kernel: None,
+            entries: [None; CACHED_EXPORTS],
+            next: 0,
+        }
+    }
+
+    /// Returns the address of an export of the kernel, resolving it only the first time.
+    ///
+    /// # Arguments
+    ///
+    /// * `memory` - The guest-physical memory.
+    /// * `paging` - The paging state of the guest.
+    /// * `kernel` - The kernel of the guest, the cached exports are dropped when it's another one.
+    /// * `name` - The name of the export.
+    pub fn resolve(&mut self, memory: &impl GuestMemory, paging: &Paging, kernel: Kernel, name: &[u8]) -> Result<u64, KernelError> {
+        if self.kernel != Some(kernel) {
+            self.flush();
+            self.kernel = Some(kernel);
+        }
+
+        let hash = djb2_hash(name);
+        if let Some(cached) = self.entries.iter().flatten().find(|cached| cached.is(hash, name)) {
+            return Ok(cached.address);
+        }
+
+        // Only names of at most `MAX_EXPORT_NAME_LENGTH` bytes resolve.
+        let address = resolve_export(memory, paging, kernel, name)?;
+        let mut cached = CachedExport {
+            hash,
+            name: [0; MAX_EXPORT_NAME_LENGTH],
+            length: name.len(),
+            address,
+        };
+        cached.name[..name.len()].copy_from_slice(name);
+        self.entries[self.next] = Some(cached);
+        self.next = (self.next + 1) % CACHED_EXPORTS;
+        Ok(address)
+    }
+
+    /// Drops every cached export.
+    pub fn flush(&mut self) {
+        *self = Self::new();
+    }
+}
+
+impl Default for ExportCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use {
+        super::*,
+        crate::intel::page_walk::{
+            tests::{FakeMemory, FOUR_LEVEL},
+            PRESENT,
+        },
+        alloc::{vec, vec::Vec},
+    };
+
+    pub const TABLES: [u64; 4] = [0x1000, 0x2000, 0x3000, 0x4000];
+
+    /// Where the images of the tests are, virtually.
+    pub const KERNEL_BASE: u64 = 0xFFFF_F800_0000_0000;
+
+    /// The offset of the export directory `image` writes.
+    const DIRECTORY: usize = 0x200;
+
+    /// A 32KB image laid out like a mapped ntoskrnl.exe: its headers, sections up to `.data`, and in `.rdata` an
+    /// export directory of 53 functions, the first four exported by ordinal only, and 49 of its export names.
+    const NTOSKRNL: &[u8] = include_bytes!("../../tests/fixtures/ntoskrnl_exports.bin");
+
+    /// Exports of `NTOSKRNL` and their RVAs, in `.text`, `PAGE` and `.data`.
+    const NTOSKRNL_RVAS: [(&[u8], u64); 8] = [
+        (b"ExAllocatePool2", 0x1040),
+        (b"KdDebuggerEnabled", 0x7010),
+        (b"MmCopyVirtualMemory", 0x4010),
+        (b"NtBuildNumber", 0x7028),
+        (b"PsLookupProcessByProcessId", 0x1620),
+        (b"ZwQuerySystemInformation", 0x1780),
+        (b"_stricmp", 0x17C0),
+        (b"wcslen", 0x1900),
+    ];
+
+    /// A sample of the export names of ntoskrnl.exe, in the order of its export directory: sorted by byte, so
+    /// capitals before `_` before lowercase letters, with the RVAs of their code or data.
+    pub const NTOSKRNL_EXPORTS: [(&[u8], u32); 16] = [
+        (b"ExAllocatePool2", 0x1100),
+        (b"IoCreateDevice", 0x1120),
+        (b"KeBugCheckEx", 0x1140),
+        (b"KeServiceDescriptorTable", 0x2000),
+        (b"MmGetSystemRoutineAddress", 0x1160),
+        (b"NtBuildNumber", 0x2010),
+        (b"NtCreateFile", 0x1180),
+        (b"NtOpenProcess", 0x11A0),
+        (b"PsGetCurrentProcessId", 0x11C0),
+        (b"PsInitialSystemProcess", 0x2018),
+        (b"PsLookupProcessByProcessId", 0x11E0),
+        (b"RtlInitUnicodeString", 0x1200),
+        (b"ZwQuerySystemInformation", 0x1220),
+        (b"_stricmp", 0x1240),
+        (b"memcpy", 0x1260),
+        (b"wcslen", 0x1280),
+    ];
+
+    fn put(bytes: &mut [u8], offset: usize, value: &[u8]) {
+        bytes[offset..offset + value.len()].copy_from_slice(value);
+    }
+
+    /// Returns a 64-bit image of `size` bytes exporting the sorted names at their RVAs, the export directory in
+    /// the first page as the linker lays it out: the directory, the functions, the names, the ordinals, then the
+    /// strings of the names.
+    pub fn image(size: usize, exports: &[(&[u8], u32)]) -> Vec<u8> {
+        let mut bytes = vec![0; size];
+        let nt = 0x40;
+        put(&mut bytes, 0, &IMAGE_DOS_SIGNATURE.to_le_bytes());
+        put(&mut bytes, offset_of!(IMAGE_DOS_HEADER, e_lfanew), &(nt as u32).to_le_bytes());
+        put(&mut bytes, nt, &IMAGE_NT_SIGNATURE.to_le_bytes());
+        put(&mut bytes, nt + offset_of!(IMAGE_NT_HEADERS64, FileHeader.Machine), &IMAGE_FILE_MACHINE_AMD64.to_le_bytes());
+        put(&mut bytes, nt + offset_of!(IMAGE_NT_HEADERS64, OptionalHeader.Magic), &IMAGE_NT_OPTIONAL_HDR64_MAGIC.to_le_bytes());
+        put(&mut bytes, nt + offset_of!(IMAGE_NT_HEADERS64, OptionalHeader.SizeOfImage), &(size as u32).to_le_bytes());
+        put(&mut bytes, nt + offset_of!(IMAGE_NT_HEADERS64, OptionalHeader.NumberOfRvaAndSizes), &16u32.to_le_bytes());
+
+        let count = exports.len();
+        let functions = DIRECTORY + size_of::<IMAGE_EXPORT_DIRECTORY>();
+        let (names, ordinals) = (functions + count * 4, functions + count * 8);
+        let mut strings = ordinals + count * 2;
+        for (offset, value) in [
+            (offset_of!(IMAGE_EXPORT_DIRECTORY, NumberOfFunctions), count),
+            (offset_of!(IMAGE_EXPORT_DIRECTORY, NumberOfNames), count),
+            (offset_of!(IMAGE_EXPORT_DIRECTORY, AddressOfFunctions), functions),
+            (offset_of!(IMAGE_EXPORT_DIRECTORY, AddressOfNames), names),
+            (offset_of!(IMAGE_EXPORT_DIRECTORY, AddressOfNameOrdinals), ordinals),
+        ] {
+            put(&mut bytes, DIRECTORY + offset, &(value as u32).to_le_bytes());
+        }
+
+        for (index, (name, rva)) in exports.iter().enumerate() {
+            put(&mut bytes, functions + index * 4, &rva.to_le_bytes());
+            put(&mut bytes, names + index * 4, &(strings as u32).to_le_bytes());
+            put(&mut bytes, ordinals + index * 2, &(index as u16).to_le_bytes());
+            put(&mut bytes, strings, name);
+            strings += name.len() + 1;
+        }
+
+        let directory = nt + offset_of!(IMAGE_NT_HEADERS64, OptionalHeader.DataDirectory);
+        put(&mut bytes, directory, &(DIRECTORY as u32).to_le_bytes());
+        put(&mut bytes, directory + 4, &((strings - DIRECTORY) as u32).to_le_bytes());
+        bytes
+    }
+
+    /// Copies an image to the frames from `pa` and maps it read-only at `KERNEL_BASE`.
+    pub fn load(memory: &mut FakeMemory, pa: u64, image: &[u8]) {
+        memory.bytes[pa as usize..pa as usize + image.len()].copy_from_slice(image);
+        for page in (0..image.len() as u64).step_by(BASE_PAGE_SIZE) {
+            memory.map(&TABLES, KERNEL_BASE + page, (pa + page) | PRESENT);
+        }
+    }
+
+    fn kernel() -> Kernel {
+        Kernel {
+            base: KERNEL_BASE,
+            size: 0x3000,
+            cr3: TABLES[0],
+        }
+    }
+
+    fn ntoskrnl() -> FakeMemory {
+        let mut memory = FakeMemory::new(16);
+        load(&mut memory, 0x5000, &image(0x3000, &NTOSKRNL_EXPORTS));
+        memory
+    }
+
+    /// Loads `NTOSKRNL` at `KERNEL_BASE`.
+    fn captured() -> (FakeMemory, Kernel) {
+        let mut memory = FakeMemory::new(16);
+        load(&mut memory, 0x5000, NTOSKRNL);
+        let kernel = locate(&memory, &FOUR_LEVEL, TABLES[0], KERNEL_BASE + 0x1840).unwrap();
+        (memory, kernel)
+    }
+
+    #[test]
+    fn the_kernel_is_found_from_inside_it() {
+        let memory = ntoskrnl();
+        assert_eq!(locate(&memory, &FOUR_LEVEL, TABLES[0], KERNEL_BASE + 0x2ABC), Some(kernel()));
+
+        // An address past the end of the image isn't in it, and nothing else below is mapped.
+        assert_eq!(locate(&memory, &FOUR_LEVEL, TABLES[0], KERNEL_BASE + 0x3000), None);
+    }
+
+    #[test]
+    fn exports_resolve_by_name() {
+        let memory = ntoskrnl();
+        for (name, rva) in NTOSKRNL_EXPORTS {
+            assert_eq!(resolve_export(&memory, &FOUR_LEVEL, kernel(), name), Ok(KERNEL_BASE + rva as u64));
+        }
+
+        for name in [&b"NtCreate"[..], b"NtCreateFileEx", b"AAA", b"zzz", b""] {
+            assert_eq!(resolve_export(&memory, &FOUR_LEVEL, kernel(), name), Err(KernelError::NotExported));
+        }
+    }
+
+    #[test]
+    fn captured_exports_resolve_at_their_rvas() {
+        let (memory, kernel) = captured();
+        assert_eq!((kernel.base, kernel.size), (KERNEL_BASE, NTOSKRNL.len() as u64));
+
+        let reader = KernelMemory::new(&memory, &FOUR_LEVEL, kernel.cr3);
+        let exports = Exports::parse(&reader, kernel.base).unwrap();
+        assert_eq!((exports.directory, exports.function_count, exports.name_count), ((0x4A00, 0x578), 53, 49));
+
+        for (name, rva) in NTOSKRNL_RVAS {
+            assert_eq!(resolve_export(&memory, &FOUR_LEVEL, kernel, name), Ok(KERNEL_BASE + rva));
+        }
+
+        // The name of the image is in the directory but isn't an export, names are compared with their case.
+        for name in [&b"ntoskrnl.exe"[..], b"_STRICMP", b"ZwQuerySystemInformationEx", b"ExAllocatePool"] {
+            assert_eq!(resolve_export(&memory, &FOUR_LEVEL, kernel, name), Err(KernelError::NotExported));
+        }
+    }
+
+    #[test]
+    fn corrupt_images_are_rejected() {
+        let field = |offset: usize| 0x5000 + DIRECTORY + offset;
+        let corrupt = |offset: usize, value: u32| {
+            let mut memory = ntoskrnl();
+            memory.bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+            resolve_export(&memory, &FOUR_LEVEL, kernel(), b"NtCreateFile")
+        };
+
+        assert_eq!(corrupt(0x503C, 0xFF8), Err(KernelError::Malformed));
+        assert_eq!(corrupt(field(offset_of!(IMAGE_EXPORT_DIRECTORY, NumberOfNames)), u32::MAX), Err(KernelError::Malformed));
+        assert_eq!(corrupt(field(offset_of!(IMAGE_EXPORT_DIRECTORY, AddressOfNames)), 0x2FF0), Err(KernelError::Malformed));
+
+        // The name the search reads first, the ninth, then the function of NtCreateFile.
+        let functions = DIRECTORY + size_of::<IMAGE_EXPORT_DIRECTORY>();
+        assert_eq!(corrupt(0x5000 + functions + 16 * 4 + 8 * 4, 0x10_0000), Err(KernelError::Malformed));
+        assert_eq!(corrupt(0x5000 + functions + 6 * 4, 0x4000), Err(KernelError::Malformed));
+        assert_eq!(corrupt(0x5000 + functions + 6 * 4, DIRECTORY as u32 + 8), Err(KernelError::NotExported));
+    }
+
+    #[test]
+    fn cached_exports_follow_the_kernel() {
+        let mut memory = ntoskrnl();
+        let mut cache = ExportCache::new();
+        assert_eq!(cache.resolve(&memory, &FOUR_LEVEL, kernel(), b"NtOpenProcess"), Ok(KERNEL_BASE + 0x11A0));
+
+        // Resolved once, the export is no longer read from the image.
+        memory.bytes.fill(0);
+        assert_eq!(cache.resolve(&memory, &FOUR_LEVEL, kernel(), b"NtOpenProcess"), Ok(KERNEL_BASE + 0x11A0));
+
+        // Another CR3 is another kernel as far as the cache knows.
+        let elsewhere = Kernel { cr3: 0x9000, ..kernel() };
+        assert_eq!(cache.resolve(&memory, &FOUR_LEVEL, elsewhere, b"NtOpenProcess"), Err(KernelError::Unreadable(KERNEL_BASE)));
+    }
+
+    #[test]
+    fn cached_exports_compare_their_names() {
+        let (memory, kernel) = captured();
+        let mut cache = ExportCache::new();
+        assert_eq!(cache.resolve(&memory, &FOUR_LEVEL, kernel, b"_stricmp"), Ok(KERNEL_BASE + 0x17C0));
+
+        // The hash ignores the case, the cached export isn't taken for another name of the same hash.
+        assert_eq!(djb2_hash(b"_stricmp"), djb2_hash(b"_STRICMP"));
+        assert_eq!(cache.resolve(&memory, &FOUR_LEVEL, kernel, b"_STRICMP"), Err(KernelError::NotExported));
+        assert_eq!(cache.resolve(&memory, &FOUR_LEVEL, kernel, b"_stricmp"), Ok(KERNEL_BASE + 0x17C0));
+    }
+}
@@ -1,4 +1,6 @@
 pub mod eprocess;
+pub mod kernel;
 pub mod log;
 pub mod nt;
+pub mod processes;
 pub mod ssdt;
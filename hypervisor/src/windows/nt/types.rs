@@ -6,6 +6,8 @@ use core::ffi::c_void;
 pub const IMAGE_DOS_SIGNATURE: u16 = 23117u16;
 pub const IMAGE_NT_SIGNATURE: u32 = 17744u32;
 pub const IMAGE_DIRECTORY_ENTRY_EXPORT: IMAGE_DIRECTORY_ENTRY = 0u16;
+pub const IMAGE_FILE_MACHINE_AMD64: IMAGE_FILE_MACHINE = 34404u16;
+pub const IMAGE_NT_OPTIONAL_HDR64_MAGIC: IMAGE_OPTIONAL_HEADER_MAGIC = 523u16;
 pub const SYSTEM_MODULE_INFORMATION: SYSTEM_INFORMATION_CLASS = 11;
 
 pub type PIMAGE_DOS_HEADER = *mut IMAGE_DOS_HEADER;
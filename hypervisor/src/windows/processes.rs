@@ -0,0 +1,274 @@
+//! Finds the processes of a Windows guest for the process hypercalls of `shared::vmcall`.
+//!
+//! Everything is read through the page tables the kernel wrote LSTAR with, when `windows::kernel` found the
+//! image of ntoskrnl.exe: the CR3 of a user-mode caller may not map the kernel, and the guest is never asked to
+//! translate. The layout of EPROCESS changes between builds of Windows, the NtBuildNumber export picks its offsets
+//! from `offsets`, and a build missing there fails with `ProcessError::UnknownBuild` rather than reading the wrong
+//! fields.
+//!
+//! The PsInitialSystemProcess export is the EPROCESS of the System process, whose ActiveProcessLinks is in the ring
+//! of every process. The one link of the ring inside the image of ntoskrnl.exe is PsActiveProcessHead, which isn't
+//! an EPROCESS and is skipped.
+//!
+//! # References
+//!
+//! https://www.vergiliusproject.com/kernels/x64
+
+use {
+    crate::{
+        intel::{guest_memory::GuestMemory, page_walk::Paging},
+        windows::kernel::{resolve_export, Kernel, KernelError, KernelMemory},
+    },
+    shared::vmcall::{ProcessEntry, PROCESS_NAME_LENGTH},
+};
+
+/// The offset of DirectoryTableBase in KPROCESS, the first member of EPROCESS, the same on every build.
+const DIRECTORY_TABLE_BASE_OFFSET: u64 = 0x28;
+
+/// The most links followed before the ring of processes is taken to be corrupt.
+const MAX_PROCESSES: usize = 0x10000;
+
+/// Why the processes of the guest couldn't be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    /// The base of ntoskrnl.exe hasn't been found, the guest never wrote LSTAR.
+    NoKernel,
+
+    /// The NT build number of the kernel isn't one `offsets` knows.
+    UnknownBuild(u32),
+
+    /// The kernel or its exports couldn't be read.
+    Kernel(KernelError),
+
+    /// The ring of processes doesn't lead back to where it started.
+    CorruptList,
+}
+
+impl From<KernelError> for ProcessError {
+    fn from(error: KernelError) -> Self {
+        Self::Kernel(error)
+    }
+}
+
+/// The offsets of the EPROCESS members a build of Windows keeps the process list in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EprocessOffsets {
+    /// `UniqueProcessId`, the process ID.
+    pub unique_process_id: u64,
+
+    /// `ActiveProcessLinks`, the LIST_ENTRY of the process list.
+    pub active_process_links: u64,
+
+    /// `ImageFileName`, the first 15 bytes of the name of the image and a NUL.
+    pub image_file_name: u64,
+}
+
+/// Returns the EPROCESS offsets of a build of 64-bit Windows 10 or 11, `None` for an unknown build.
+///
+/// # Arguments
+///
+/// * `build` - The NT build number, without the flags NtBuildNumber keeps in its high bits.
+pub fn offsets(build: u32) -> Option<EprocessOffsets> {
+    let (unique_process_id, active_process_links, image_file_name) = match build {
+        // 1507.
+        10240 => (0x2E8, 0x2F0, 0x448),
+        // 1511 and 1607.
+        10586 | 14393 => (0x2E8, 0x2F0, 0x450),
+        // 1703 through 1809.
+        15063 | 16299 | 17134 | 17763 => (0x2E0, 0x2E8, 0x450),
+        // 1903 and 1909.
+        18362 | 18363 => (0x2E8, 0x2F0, 0x450),
+        // Windows 10 2004 through 22H2, Windows 11 21H2 through 23H2.
+        19041..=19045 | 22000 | 22621 | 22631 => (0x440, 0x448, 0x5A8),
+        // Windows 11 24H2.
+        26100 => (0x1D0, 0x1D8, 0x338),
+        _ => return None,
+    };
+
+    Some(EprocessOffsets {
+        unique_process_id,
+        active_process_links,
+        image_file_name,
+    })
+}
+
+/// Returns the entry of the process whose EPROCESS is at a virtual address.
+fn entry(memory: &KernelMemory<impl GuestMemory>, offsets: &EprocessOffsets, eprocess: u64) -> Result<ProcessEntry, KernelError> {
+    let mut entry = ProcessEntry {
+        eprocess: memory.physical(eprocess)?,
+        directory_table_base: memory.read_u64(eprocess + DIRECTORY_TABLE_BASE_OFFSET)?,
+        process_id: memory.read_u64(eprocess + offsets.unique_process_id)?,
+        ..Default::default()
+    };
+    memory.read_into(eprocess + offsets.image_file_name, &mut entry.image_file_name[..PROCESS_NAME_LENGTH])?;
+    Ok(entry)
+}
+
+/// Visits the processes of the guest, the System process first.
+///
+/// # Arguments
+///
+/// * `memory` - The guest-physical memory.
+/// * `paging` - The paging state of the guest.
+/// * `kernel` - The kernel of the guest.
+/// * `visit` - Called with every process, returns `false` to stop.
+///
+/// # Returns
+///
+/// `Ok` once every process was visited or `visit` stopped, else why the list couldn't be read.
+pub fn for_each_process(
+    memory: &impl GuestMemory,
+    paging: &Paging,
+    kernel: Kernel,
+    mut visit: impl FnMut(&ProcessEntry) -> bool,
+) -> Result<(), ProcessError> {
+    let build = resolve_export(memory, paging, kernel, b"NtBuildNumber")?;
+    let system = resolve_export(memory, paging, kernel, b"PsInitialSystemProcess")?;
+    let memory = KernelMemory::new(memory, paging, kernel.cr3);
+
+    let build = memory.read_u32(build)? & 0xFFFF;
+    let offsets = offsets(build).ok_or(ProcessError::UnknownBuild(build))?;
+
+    let system = memory.read_u64(system)?;
+    let first = system + offsets.active_process_links;
+    let mut link = first;
+
+    for _ in 0..MAX_PROCESSES {
+        if !kernel.contains(link) && !visit(&entry(&memory, &offsets, link - offsets.active_process_links)?) {
+            return Ok(());
+        }
+
+        // Flink, the first member of LIST_ENTRY.
+        link = memory.read_u64(link)?;
+        if link == first {
+            return Ok(());
+        }
+    }
+
+    Err(ProcessError::CorruptList)
+}
+
+/// Returns the process with a name, ignoring ASCII case.
+///
+/// # Arguments
+///
+/// * `memory` - The guest-physical memory.
+/// * `paging` - The paging state of the guest.
+/// * `kernel` - The kernel of the guest.
+/// * `name` - The name, only its first `PROCESS_NAME_LENGTH` bytes are compared as Windows keeps no more.
+///
+/// # Returns
+///
+/// The first process with the name, `Ok(None)` if none has it.
+pub fn find_process(memory: &impl GuestMemory, paging: &Paging, kernel: Kernel, name: &[u8]) -> Result<Option<ProcessEntry>, ProcessError> {
+    let name = &name[..name.len().min(PROCESS_NAME_LENGTH)];
+    let mut found = None;
+
+    for_each_process(memory, paging, kernel, |entry| {
+        if entry.name().eq_ignore_ascii_case(name) {
+            found = Some(*entry);
+        }
+        found.is_none()
+    })?;
+
+    Ok(found)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use {
+        super::*,
+        crate::{
+            intel::page_walk::{
+                tests::{FakeMemory, FOUR_LEVEL},
+                PRESENT, WRITABLE,
+            },
+            windows::kernel::tests::{image, load, KERNEL_BASE, TABLES},
+        },
+    };
+
+    /// The EPROCESS of the System process, the others follow a page apart.
+    pub const SYSTEM: u64 = 0xFFFF_A000_0001_0000;
+
+    fn put(memory: &mut FakeMemory, pa: u64, bytes: &[u8]) {
+        memory.bytes[pa as usize..pa as usize + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// A kernel of `build` at the frame at 0x5000, exporting NtBuildNumber and PsInitialSystemProcess, and the
+    /// processes System, smss.exe and notepad.exe at the frames from 0x6000.
+    pub fn fake_kernel(build: u32) -> (FakeMemory, Kernel) {
+        let mut memory = FakeMemory::new(16);
+        load(&mut memory, 0x5000, &image(0x1000, &[(b"NtBuildNumber", 0x800), (b"PsInitialSystemProcess", 0x808)]));
+        put(&mut memory, 0x5800, &(0xF000_0000 | build).to_le_bytes());
+        put(&mut memory, 0x5808, &SYSTEM.to_le_bytes());
+
+        // PsActiveProcessHead at 0x810, then the ring of the three processes.
+        let offsets = offsets(build).or(offsets(22631)).unwrap();
+        let head = KERNEL_BASE + 0x810;
+        let links = |index: u64| SYSTEM + index * 0x1000 + offsets.active_process_links;
+        for (index, (pid, name)) in [(4u64, &b"System"[..]), (0x1C0, b"smss.exe"), (0x2A4, b"notepad.exe")]
+            .into_iter()
+            .enumerate()
+        {
+            let index = index as u64;
+            let frame = 0x6000 + index * 0x1000;
+            memory.map(&TABLES, SYSTEM + index * 0x1000, frame | PRESENT | WRITABLE);
+            put(&mut memory, frame + DIRECTORY_TABLE_BASE_OFFSET, &(0x10_0000 * (index + 1)).to_le_bytes());
+            put(&mut memory, frame + offsets.unique_process_id, &pid.to_le_bytes());
+            put(&mut memory, frame + offsets.image_file_name, name);
+
+            let next = if index == 2 { head } else { links(index + 1) };
+            put(&mut memory, frame + offsets.active_process_links, &next.to_le_bytes());
+        }
+        put(&mut memory, 0x5810, &links(0).to_le_bytes());
+
+        let kernel = Kernel {
+            base: KERNEL_BASE,
+            size: 0x1000,
+            cr3: TABLES[0],
+        };
+        (memory, kernel)
+    }
+
+    #[test]
+    fn builds_select_offsets() {
+        assert_eq!(offsets(19044).map(|offsets| offsets.active_process_links), Some(0x448));
+        assert_eq!(offsets(26100).map(|offsets| offsets.image_file_name), Some(0x338));
+        assert_eq!(offsets(17763).map(|offsets| offsets.unique_process_id), Some(0x2E0));
+        assert_eq!(offsets(19046), None);
+        assert_eq!(offsets(9600), None);
+    }
+
+    #[test]
+    fn processes_are_walked_past_the_list_head() {
+        let (memory, kernel) = fake_kernel(19045);
+
+        let mut seen = alloc::vec::Vec::new();
+        assert_eq!(
+            for_each_process(&memory, &FOUR_LEVEL, kernel, |entry| {
+                seen.push(*entry);
+                true
+            }),
+            Ok(())
+        );
+        assert_eq!(seen.iter().map(|entry| entry.process_id).collect::<alloc::vec::Vec<_>>(), [4, 0x1C0, 0x2A4]);
+        assert_eq!((seen[1].eprocess, seen[1].directory_table_base, seen[1].name()), (0x7000, 0x20_0000, &b"smss.exe"[..]));
+
+        let notepad = find_process(&memory, &FOUR_LEVEL, kernel, b"NOTEPAD.EXE").unwrap();
+        assert_eq!(notepad.map(|entry| entry.process_id), Some(0x2A4));
+        assert_eq!(find_process(&memory, &FOUR_LEVEL, kernel, b"explorer.exe"), Ok(None));
+    }
+
+    #[test]
+    fn bad_kernels_fail_cleanly() {
+        let (memory, kernel) = fake_kernel(9600);
+        assert_eq!(for_each_process(&memory, &FOUR_LEVEL, kernel, |_| true), Err(ProcessError::UnknownBuild(9600)));
+
+        let (memory, kernel) = fake_kernel(22631);
+        let moved = Kernel {
+            base: KERNEL_BASE + 0x1000,
+            ..kernel
+        };
+        assert_eq!(for_each_process(&memory, &FOUR_LEVEL, moved, |_| true), Err(ProcessError::Kernel(KernelError::Unreadable(moved.base))));
+    }
+}
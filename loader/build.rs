@@ -0,0 +1,27 @@
+//! Embeds the git revision and the profile the loader is built with, see `build_info.rs`.
+
+use std::{path::Path, process::Command};
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    // Rebuild when the checked out commit changes. Missing files, e.g. in a source tarball, are not
+    // watched, as cargo would then rerun the script on every build.
+    for path in ["../.git/HEAD", "../.git/refs/heads", "../.git/packed-refs"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+
+    // `debug_assertions` is disabled in the workspace's dev profile, so the profile is taken from cargo.
+    println!("cargo:rustc-env=ILLUSION_BUILD_PROFILE={}", std::env::var("PROFILE").unwrap_or_else(|_| String::from("unknown")));
+
+    // Without git or outside of a checkout the variable stays unset and the loader reports `unknown`.
+    let output = Command::new("git").args(["rev-parse", "--short=8", "HEAD"]).output().ok();
+    if let Some(output) = output.filter(|output| output.status.success()) {
+        let hash = String::from_utf8_lossy(&output.stdout);
+        if !hash.trim().is_empty() {
+            println!("cargo:rustc-env=ILLUSION_GIT_HASH={}", hash.trim());
+        }
+    }
+}
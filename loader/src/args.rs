@@ -0,0 +1,141 @@
+//! Command-line style arguments passed to the loader through its `LoadedImage` load options.
+//!
+//! Firmware passes the optional data of a `Boot####` entry as load options, and the EFI shell passes
+//! the command line. Arguments override the values read from `illusion.cfg`.
+
+extern crate alloc;
+
+use {
+    crate::config::LoaderConfig,
+    alloc::{string::String, vec::Vec},
+    uefi::{prelude::*, proto::loaded_image::LoadedImage},
+};
+
+/// Flags taking a value, mapped to the configuration key they override.
+const VALUE_FLAGS: [(&str, &str); 5] = [
+    ("--timeout", "selection_timeout_ms"),
+    ("--hv-path", "hypervisor_path"),
+    ("--candidate", "default_candidate"),
+    ("--serial", "serial"),
+    ("--log-level", "log_level"),
+];
+
+/// Reads the loader's own load options and applies the recognized arguments to `config`.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+/// * `config` - The configuration to override.
+pub(crate) fn apply_load_options(boot_services: &BootServices, config: &mut LoaderConfig) {
+    let options = match boot_services.open_protocol_exclusive::<LoadedImage>(boot_services.image_handle()) {
+        Ok(loaded_image) => match loaded_image.load_options_as_bytes() {
+            Some(bytes) => decode_load_options(bytes),
+            None => {
+                log::debug!("No load options passed to the loader");
+                return;
+            }
+        },
+        Err(error) => {
+            log::warn!("Failed to open LoadedImage to read load options ({:?})", error);
+            return;
+        }
+    };
+
+    log::info!("Load options: {}", options);
+    apply_arguments(&options, config);
+}
+
+/// Decodes raw load options as either a UCS-2 or an ASCII string.
+///
+/// Load options are usually a null-terminated UCS-2 string, but `Boot####` entries created by some tools
+/// carry plain ASCII. The encoding is guessed from the position of the zero bytes.
+///
+/// # Arguments
+///
+/// * `bytes` - The raw load options.
+///
+/// # Returns
+///
+/// The decoded string, up to the first null character.
+pub(crate) fn decode_load_options(bytes: &[u8]) -> String {
+    let is_ucs2 = bytes.len() >= 2 && bytes.len() % 2 == 0 && bytes[1] == 0 && bytes[0] != 0;
+
+    if is_ucs2 {
+        let units = bytes
+            .chunks_exact(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+            .take_while(|&unit| unit != 0);
+        char::decode_utf16(units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect()
+    } else {
+        bytes
+            .iter()
+            .take_while(|&&byte| byte != 0)
+            .map(|&byte| if byte.is_ascii() { byte as char } else { char::REPLACEMENT_CHARACTER })
+            .collect()
+    }
+}
+
+/// Encodes load options as the null-terminated UCS-2 string images usually expect.
+///
+/// # Arguments
+///
+/// * `options` - The load options as text.
+///
+/// # Returns
+///
+/// The raw load options, including the terminating null character.
+pub(crate) fn encode_load_options(options: &str) -> Vec<u8> {
+    options.encode_utf16().chain([0]).flat_map(u16::to_le_bytes).collect()
+}
+
+/// Applies whitespace separated arguments to `config`.
+///
+/// A leading token that is not a flag is treated as the image name (the EFI shell passes the full
+/// command line) and skipped. Unknown flags are reported and otherwise ignored.
+///
+/// # Arguments
+///
+/// * `options` - The decoded load options.
+/// * `config` - The configuration to override.
+pub(crate) fn apply_arguments(options: &str, config: &mut LoaderConfig) {
+    for (index, argument) in options.split_whitespace().enumerate() {
+        if !argument.starts_with("--") {
+            if index == 0 {
+                log::debug!("Skipping image name {} in load options", argument);
+            } else {
+                log::warn!("Ignoring unexpected load option argument {}", argument);
+            }
+            continue;
+        }
+
+        if argument == "--no-hypervisor" {
+            config.skip_hypervisor = true;
+            log::info!("Config: skip_hypervisor = true (from load options)");
+            continue;
+        }
+
+        if argument == "--no-verify" {
+            config.skip_verify = true;
+            log::info!("Config: skip_verify = true (from load options)");
+            continue;
+        }
+
+        if argument == "--force-load" {
+            config.force_load = true;
+            log::info!("Config: force_load = true (from load options)");
+            continue;
+        }
+
+        let (flag, value) = argument.split_once('=').unwrap_or((argument, ""));
+
+        let Some((_, key)) = VALUE_FLAGS.iter().find(|(name, _)| *name == flag) else {
+            log::warn!("Ignoring unknown load option {}", argument);
+            continue;
+        };
+
+        match config.apply(key, value) {
+            Ok(key) => log::info!("Config: {} = {} (from load options)", key, value),
+            Err(reason) => log::warn!("Ignoring load option {}: {}", argument, reason),
+        }
+    }
+}
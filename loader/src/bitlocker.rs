@@ -0,0 +1,149 @@
+//! Detection of BitLocker volumes before Windows is started behind the hypervisor.
+//!
+//! BitLocker seals its key against PCR values that cover the boot applications started before the
+//! Windows boot manager. Chainloading it behind the hypervisor changes them, and Windows asks for the
+//! recovery key. Encrypted volumes are recognized by the `-FVE-FS-` signature that replaces the NTFS OEM
+//! ID in their boot sector.
+
+extern crate alloc;
+
+use {
+    crate::devpath,
+    alloc::{string::String, vec::Vec},
+    uefi::{
+        prelude::*,
+        proto::media::block::BlockIO,
+        table::boot::{OpenProtocolAttributes, OpenProtocolParams, SearchType},
+        Identify,
+    },
+};
+
+/// Signature of BitLocker volumes, found at offset 3 of the boot sector and at the start of the metadata blocks.
+const FVE_SIGNATURE: &[u8; 8] = b"-FVE-FS-";
+
+/// Number of bytes at the start of a partition searched for the signature.
+const SCAN_BYTES: usize = 8 * 1024;
+
+/// Returns the offset of the BitLocker signature in sectors read from the start of a partition.
+pub(crate) fn find_signature(sectors: &[u8]) -> Option<usize> {
+    sectors.windows(FVE_SIGNATURE.len()).position(|window| window == FVE_SIGNATURE)
+}
+
+/// Returns how many blocks of `block_size` bytes cover the scanned area, limited to the `blocks` of the partition.
+pub(crate) fn scan_blocks(block_size: usize, blocks: u64) -> usize {
+    let needed = SCAN_BYTES.div_ceil(block_size.max(1));
+    needed.min(usize::try_from(blocks).unwrap_or(usize::MAX))
+}
+
+/// Finds the fixed partitions carrying the BitLocker signature.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+///
+/// # Returns
+///
+/// The device paths of the encrypted partitions as text.
+pub(crate) fn find_encrypted_volumes(boot_services: &BootServices) -> Vec<String> {
+    let handles = match boot_services.locate_handle_buffer(SearchType::ByProtocol(&BlockIO::GUID)) {
+        Ok(handles) => handles,
+        Err(error) => {
+            log::debug!("Failed to locate BlockIO handles for the BitLocker check ({:?})", error.status());
+            return Vec::new();
+        }
+    };
+
+    handles
+        .iter()
+        .filter(|handle| is_encrypted(boot_services, **handle))
+        .map(|handle| devpath::handle_to_text(boot_services, *handle))
+        .collect()
+}
+
+/// Reads the first sectors of a fixed partition and checks them for the BitLocker signature.
+fn is_encrypted(boot_services: &BootServices, handle: Handle) -> bool {
+    let params = OpenProtocolParams {
+        handle,
+        agent: boot_services.image_handle(),
+        controller: None,
+    };
+
+    // SAFETY: The protocol is only used while the handle is still installed, before any image is started.
+    // An exclusive open would disconnect the drivers stacked on top of the partition.
+    let Ok(block_io) = (unsafe { boot_services.open_protocol::<BlockIO>(params, OpenProtocolAttributes::GetProtocol) }) else {
+        return false;
+    };
+
+    let media = block_io.media();
+    if !media.is_media_present() || media.is_removable_media() || !media.is_logical_partition() {
+        return false;
+    }
+
+    let block_size = media.block_size() as usize;
+    let length = scan_blocks(block_size, media.last_block().saturating_add(1)) * block_size;
+
+    // The buffer has to satisfy the alignment the device asks for.
+    let align = (media.io_align() as usize).max(1);
+    let mut buffer = alloc::vec![0u8; length + align];
+    let offset = buffer.as_ptr().align_offset(align);
+    let sectors = &mut buffer[offset..offset + length];
+
+    match block_io.read_blocks(media.media_id(), 0, sectors) {
+        Ok(()) => find_signature(sectors).is_some(),
+        Err(error) => {
+            log::debug!("Failed to read the first sectors of a partition ({:?})", error.status());
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Start of the boot sector of a BitLocker encrypted NTFS partition.
+    const BITLOCKER_BOOT_SECTOR: [u8; 48] = [
+        0xeb, 0x58, 0x90, 0x2d, 0x46, 0x56, 0x45, 0x2d, 0x46, 0x53, 0x2d, 0x00, 0x02, 0x08, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, 0x00, 0xf8, 0x00, 0x00, 0x3f, 0x00, 0xff, 0x00, 0x00, 0x08, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, 0xe0, 0x1f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+    ];
+
+    /// Start of the boot sector of a plain NTFS partition.
+    const NTFS_BOOT_SECTOR: [u8; 48] = [
+        0xeb, 0x52, 0x90, 0x4e, 0x54, 0x46, 0x53, 0x20, 0x20, 0x20, 0x20, 0x00, 0x02, 0x08, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, 0x00, 0xf8, 0x00, 0x00, 0x3f, 0x00, 0xff, 0x00, 0x00, 0x08, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, 0x80, 0x00, 0x80, 0x00, 0xff, 0x97, 0x5d, 0x3a, 0x00, 0x00, 0x00, 0x00, //
+    ];
+
+    /// Builds `count` sectors of 512 bytes starting with `start`, each ending with the boot signature.
+    fn sectors(start: &[u8], count: usize) -> Vec<u8> {
+        let mut bytes = alloc::vec![0u8; 512 * count];
+        bytes[..start.len()].copy_from_slice(start);
+        for sector in bytes.chunks_mut(512) {
+            sector[510..].copy_from_slice(&[0x55, 0xaa]);
+        }
+        bytes
+    }
+
+    #[test]
+    fn bitlocker_boot_sectors_are_recognized() {
+        assert_eq!(find_signature(&sectors(&BITLOCKER_BOOT_SECTOR, 16)), Some(3));
+        assert_eq!(find_signature(&sectors(&NTFS_BOOT_SECTOR, 16)), None);
+        assert_eq!(find_signature(&sectors(&[], 16)), None);
+    }
+
+    #[test]
+    fn signatures_spanning_sectors_are_found() {
+        let mut bytes = sectors(&NTFS_BOOT_SECTOR, 2);
+        bytes[508..516].copy_from_slice(FVE_SIGNATURE);
+        assert_eq!(find_signature(&bytes), Some(508));
+    }
+
+    #[test]
+    fn scan_covers_the_first_sectors() {
+        assert_eq!(scan_blocks(512, 1 << 20), 16);
+        assert_eq!(scan_blocks(4096, 1 << 20), 2);
+        assert_eq!(scan_blocks(512, 4), 4);
+        assert_eq!(scan_blocks(0, 100), 100);
+    }
+}
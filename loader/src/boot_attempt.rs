@@ -0,0 +1,71 @@
+//! Counter of boots the hypervisor was started in, used to recover from a hypervisor that hangs Windows.
+//!
+//! The loader increments the non-volatile `IllusionBootAttempt` variable right before it starts the
+//! hypervisor, and the hypervisor deletes it when Windows exits boot services. A count that reaches the
+//! configured limit therefore means the previous boots never got that far, and the loader engages safe
+//! mode by skipping the hypervisor until the counter is reset.
+
+use {
+    crate::last_boot::ILLUSION_VENDOR,
+    uefi::{prelude::*, table::runtime::VariableAttributes, CStr16},
+};
+
+/// Name of the variable holding the number of unfinished boots. Also used by the hypervisor.
+const BOOT_ATTEMPT_VARIABLE: &CStr16 = cstr16!("IllusionBootAttempt");
+
+/// Consecutive unfinished boots after which the hypervisor is skipped by default.
+pub(crate) const DEFAULT_BOOT_ATTEMPT_LIMIT: u32 = 3;
+
+/// Reads the number of boots that started the hypervisor but did not reach Windows.
+///
+/// # Arguments
+///
+/// * `runtime_services` - A reference to the UEFI runtime services.
+///
+/// # Returns
+///
+/// The stored count, `0` if the variable is absent, unreadable or malformed.
+pub(crate) fn load(runtime_services: &RuntimeServices) -> u32 {
+    let mut buffer = [0u8; 4];
+
+    match runtime_services.get_variable(BOOT_ATTEMPT_VARIABLE, &ILLUSION_VENDOR, &mut buffer) {
+        Ok((data, _)) if data.len() == 4 => u32::from_le_bytes(buffer),
+        Ok((data, _)) => {
+            log::warn!("Ignoring {} with unexpected size {}", BOOT_ATTEMPT_VARIABLE, data.len());
+            0
+        }
+        Err(error) if error.status() == Status::NOT_FOUND => 0,
+        Err(error) => {
+            log::warn!("Failed to read {} ({:?})", BOOT_ATTEMPT_VARIABLE, error.status());
+            0
+        }
+    }
+}
+
+/// Records that the hypervisor is about to be started once more.
+///
+/// # Arguments
+///
+/// * `runtime_services` - A reference to the UEFI runtime services.
+/// * `count` - The number of unfinished boots including the current one.
+pub(crate) fn store(runtime_services: &RuntimeServices, count: u32) {
+    let attributes = VariableAttributes::NON_VOLATILE | VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS;
+
+    match runtime_services.set_variable(BOOT_ATTEMPT_VARIABLE, &ILLUSION_VENDOR, attributes, &count.to_le_bytes()) {
+        Ok(()) => log::debug!("Recorded boot attempt {} in {}", count, BOOT_ATTEMPT_VARIABLE),
+        Err(error) => log::warn!("Failed to record boot attempt in {} ({:?})", BOOT_ATTEMPT_VARIABLE, error.status()),
+    }
+}
+
+/// Clears the counter, leaving safe mode.
+///
+/// # Arguments
+///
+/// * `runtime_services` - A reference to the UEFI runtime services.
+pub(crate) fn reset(runtime_services: &RuntimeServices) {
+    match runtime_services.delete_variable(BOOT_ATTEMPT_VARIABLE, &ILLUSION_VENDOR) {
+        Ok(()) => log::info!("Reset the boot attempt counter as requested by the configuration"),
+        Err(error) if error.status() == Status::NOT_FOUND => {}
+        Err(error) => log::warn!("Failed to reset {} ({:?})", BOOT_ATTEMPT_VARIABLE, error.status()),
+    }
+}
@@ -0,0 +1,489 @@
+//! Chainload candidates taken from the firmware boot entries in `BootOrder` and `Boot####`.
+//!
+//! Every `Boot####` variable holds an `EFI_LOAD_OPTION`: attributes, a UCS-2 description, a list of device
+//! paths and optional data that the firmware passes to the image as load options. Entries pointing at a file
+//! are resolved to a volume like the configured chainload paths. The entry that started the loader itself is
+//! dropped, chainloading it would only start the loader again.
+
+extern crate alloc;
+
+use {
+    crate::images::{self, BootTarget, ImageError, SearchOptions, VolumeKey},
+    alloc::{
+        format,
+        string::{String, ToString},
+        vec::Vec,
+    },
+    core::fmt::{self, Display},
+    uefi::{prelude::*, proto::loaded_image::LoadedImage, table::runtime::VariableVendor, CStr16, CString16},
+};
+
+/// Name of the variable listing the boot entries in the order the firmware tries them.
+const BOOT_ORDER_VARIABLE: &CStr16 = cstr16!("BootOrder");
+
+/// `LOAD_OPTION_ACTIVE`, set for entries the firmware boot manager may start.
+const LOAD_OPTION_ACTIVE: u32 = 0x0000_0001;
+
+/// Size of the attributes and file path list length preceding the description.
+const LOAD_OPTION_HEADER_SIZE: usize = 6;
+
+/// Device path node types and subtypes needed to split the file path list.
+const MEDIA_DEVICE_PATH: u8 = 0x04;
+const MEDIA_FILE_PATH: u8 = 0x04;
+const END_DEVICE_PATH: u8 = 0x7f;
+const END_ENTIRE: u8 = 0xff;
+
+/// A decoded `EFI_LOAD_OPTION`.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct LoadOption {
+    /// The `LOAD_OPTION_*` attributes.
+    pub attributes: u32,
+
+    /// The description shown by the firmware boot menu.
+    pub description: String,
+
+    /// The raw device path list, the first instance locating the image.
+    pub file_path_list: Vec<u8>,
+
+    /// The data passed to the image as load options, may be empty.
+    pub optional_data: Vec<u8>,
+}
+
+/// A boot entry together with the number of the `Boot####` variable it was read from.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct BootEntry {
+    /// The `####` of the variable name.
+    pub number: u16,
+
+    /// The decoded variable content.
+    pub option: LoadOption,
+}
+
+/// Reasons why a `Boot####` variable can't be decoded.
+#[derive(Debug, PartialEq)]
+pub(crate) enum LoadOptionError {
+    /// The variable is shorter than the fixed header.
+    Truncated,
+
+    /// The description is not terminated by a null character.
+    UnterminatedDescription,
+
+    /// The file path list extends beyond the end of the variable.
+    FilePathListOverflow(usize),
+}
+
+impl Display for LoadOptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadOptionError::Truncated => write!(f, "variable is shorter than the load option header"),
+            LoadOptionError::UnterminatedDescription => write!(f, "description is not null-terminated"),
+            LoadOptionError::FilePathListOverflow(length) => write!(f, "file path list of {} bytes extends beyond the variable", length),
+        }
+    }
+}
+
+impl LoadOption {
+    /// Returns whether the firmware boot manager considers the entry bootable.
+    pub(crate) fn is_active(&self) -> bool {
+        self.attributes & LOAD_OPTION_ACTIVE != 0
+    }
+}
+
+impl BootEntry {
+    /// Returns the name of the variable the entry was read from, e.g. `Boot0003`.
+    pub(crate) fn name(&self) -> String {
+        format!("Boot{:04X}", self.number)
+    }
+}
+
+/// Decodes the content of a `BootOrder` variable.
+///
+/// # Arguments
+///
+/// * `bytes` - The raw variable content, an array of 16-bit entry numbers.
+///
+/// # Returns
+///
+/// The entry numbers in boot order. A trailing odd byte is ignored.
+pub(crate) fn parse_boot_order(bytes: &[u8]) -> Vec<u16> {
+    bytes.chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])).collect()
+}
+
+/// Decodes the content of a `Boot####` variable.
+///
+/// # Arguments
+///
+/// * `bytes` - The raw variable content.
+///
+/// # Returns
+///
+/// The decoded load option, or why it is malformed.
+pub(crate) fn parse_load_option(bytes: &[u8]) -> Result<LoadOption, LoadOptionError> {
+    if bytes.len() < LOAD_OPTION_HEADER_SIZE {
+        return Err(LoadOptionError::Truncated);
+    }
+
+    let attributes = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+    let file_path_list_length = u16::from_le_bytes([bytes[4], bytes[5]]) as usize;
+
+    let rest = &bytes[LOAD_OPTION_HEADER_SIZE..];
+    let terminator = rest
+        .chunks_exact(2)
+        .position(|pair| pair == [0, 0])
+        .ok_or(LoadOptionError::UnterminatedDescription)?;
+    let description = char::decode_utf16(rest[..terminator * 2].chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])))
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect();
+
+    let rest = &rest[(terminator + 1) * 2..];
+    if rest.len() < file_path_list_length {
+        return Err(LoadOptionError::FilePathListOverflow(file_path_list_length));
+    }
+    let (file_path_list, optional_data) = rest.split_at(file_path_list_length);
+
+    Ok(LoadOption {
+        attributes,
+        description,
+        file_path_list: file_path_list.to_vec(),
+        optional_data: optional_data.to_vec(),
+    })
+}
+
+/// Returns the first device path instance of a file path list, terminated by an end entire node.
+///
+/// # Arguments
+///
+/// * `bytes` - The raw device path list, as a sequence of nodes with a 4 byte header each.
+///
+/// # Returns
+///
+/// The nodes up to the first end node followed by an end entire node, or `None` if the list is malformed.
+pub(crate) fn first_instance(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut instance = Vec::new();
+    let mut rest = bytes;
+
+    while rest.len() >= 4 {
+        let length = u16::from_le_bytes([rest[2], rest[3]]) as usize;
+        if length < 4 || length > rest.len() {
+            return None;
+        }
+
+        let (node, next) = rest.split_at(length);
+        if node[0] == END_DEVICE_PATH {
+            instance.extend_from_slice(&[END_DEVICE_PATH, END_ENTIRE, 4, 0]);
+            return Some(instance);
+        }
+
+        instance.extend_from_slice(node);
+        rest = next;
+    }
+
+    None
+}
+
+/// Extracts the file path from the file path nodes of a device path.
+///
+/// # Arguments
+///
+/// * `bytes` - The raw device path, as a sequence of nodes with a 4 byte header each.
+///
+/// # Returns
+///
+/// The path of all file path nodes joined with backslashes, or `None` if the device path has none.
+pub(crate) fn file_path_text(bytes: &[u8]) -> Option<String> {
+    let mut path = String::new();
+    let mut rest = bytes;
+
+    while rest.len() >= 4 {
+        let length = u16::from_le_bytes([rest[2], rest[3]]) as usize;
+        if length < 4 || length > rest.len() {
+            break;
+        }
+
+        let (node, next) = rest.split_at(length);
+        rest = next;
+
+        match (node[0], node[1]) {
+            (END_DEVICE_PATH, _) => break,
+            (MEDIA_DEVICE_PATH, MEDIA_FILE_PATH) => {
+                let component: String = char::decode_utf16(node[4..].chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])))
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .take_while(|&c| c != '\0')
+                    .collect();
+
+                if !path.is_empty() && !path.ends_with('\\') && !component.starts_with('\\') {
+                    path.push('\\');
+                }
+                path.push_str(&component);
+            }
+            _ => {}
+        }
+    }
+
+    Some(path).filter(|path| !path.is_empty())
+}
+
+/// Reads the boot entries listed in `BootOrder`.
+///
+/// # Arguments
+///
+/// * `runtime_services` - A reference to the UEFI runtime services.
+///
+/// # Returns
+///
+/// The entries that could be read and decoded, in boot order. Missing or malformed entries are skipped.
+pub(crate) fn read_boot_entries(runtime_services: &RuntimeServices) -> Vec<BootEntry> {
+    let order = match runtime_services.get_variable_boxed(BOOT_ORDER_VARIABLE, &VariableVendor::GLOBAL_VARIABLE) {
+        Ok((bytes, _)) => parse_boot_order(&bytes),
+        Err(error) => {
+            log::warn!("Failed to read {} ({:?})", BOOT_ORDER_VARIABLE, error.status());
+            return Vec::new();
+        }
+    };
+
+    log::debug!("{} lists {} boot entries", BOOT_ORDER_VARIABLE, order.len());
+
+    order
+        .into_iter()
+        .filter_map(|number| {
+            let name = CString16::try_from(format!("Boot{:04X}", number).as_str()).ok()?;
+            let bytes = match runtime_services.get_variable_boxed(&name, &VariableVendor::GLOBAL_VARIABLE) {
+                Ok((bytes, _)) => bytes,
+                Err(error) => {
+                    log::warn!("Failed to read {} listed in {} ({:?})", name, BOOT_ORDER_VARIABLE, error.status());
+                    return None;
+                }
+            };
+
+            match parse_load_option(&bytes) {
+                Ok(option) => Some(BootEntry { number, option }),
+                Err(error) => {
+                    log::warn!("Ignoring malformed {}: {}", name, error);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Finds a chainload candidate for every active boot entry that points at a file.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+/// * `runtime_services` - A reference to the UEFI runtime services.
+/// * `options` - How the volumes are probed.
+///
+/// # Returns
+///
+/// The candidates in boot order, each carrying the entry it was built from, or why a search failed.
+pub(crate) fn find_boot_order_targets(
+    boot_services: &BootServices,
+    runtime_services: &RuntimeServices,
+    options: SearchOptions,
+) -> Result<Vec<BootTarget>, ImageError> {
+    let loader = loader_location(boot_services);
+    let mut targets = Vec::new();
+
+    for entry in read_boot_entries(runtime_services) {
+        let name = entry.name();
+
+        if !entry.option.is_active() {
+            log::debug!("Skipping inactive {} ({})", name, entry.option.description);
+            continue;
+        }
+
+        let Some(device_path) = first_instance(&entry.option.file_path_list) else {
+            log::warn!("Skipping {} ({}), its device path is malformed", name, entry.option.description);
+            continue;
+        };
+
+        let Some(path) = file_path_text(&device_path) else {
+            log::debug!("Skipping {} ({}), it does not point at a file", name, entry.option.description);
+            continue;
+        };
+
+        // Short-form paths consisting of only a file path node are resolved on any volume, as the firmware does.
+        let key = images::volume_key(&device_path);
+        let any_volume = key == VolumeKey::Path(Vec::new());
+
+        if let Some((loader_key, loader_path)) = &loader {
+            if (any_volume || key == *loader_key) && path.eq_ignore_ascii_case(loader_path) {
+                log::info!("Skipping {} ({}), it starts the loader itself", name, entry.option.description);
+                continue;
+            }
+        }
+
+        let Ok(path) = CString16::try_from(path.as_str()) else {
+            log::warn!("Skipping {} ({}), its file path can't be encoded as UCS-2", name, entry.option.description);
+            continue;
+        };
+
+        let found = images::enumerate_device_paths(boot_services, options, &path)?
+            .into_iter()
+            .find(|target| any_volume || target.volume_key() == key);
+
+        match found {
+            Some(mut target) => {
+                log::info!("{} ({}) found on handle {}: {}", name, entry.option.description, target.handle_index, path);
+                target.boot_entry = Some(entry);
+                targets.push(target);
+            }
+            None => log::info!("Skipping {} ({}), {} is not present on its volume", name, entry.option.description, path),
+        }
+    }
+
+    Ok(targets)
+}
+
+/// Finds the boot entry that starts the same file as a target found through a chainload path.
+///
+/// # Arguments
+///
+/// * `runtime_services` - A reference to the UEFI runtime services.
+/// * `target` - The chainload target.
+///
+/// # Returns
+///
+/// The first active entry in boot order whose device path points at the target's file on its volume.
+pub(crate) fn find_matching_entry(runtime_services: &RuntimeServices, target: &BootTarget) -> Option<BootEntry> {
+    let target_key = target.volume_key();
+    let target_path = target.path.to_string();
+
+    read_boot_entries(runtime_services).into_iter().find(|entry| {
+        let Some(device_path) = first_instance(&entry.option.file_path_list) else {
+            return false;
+        };
+
+        let key = images::volume_key(&device_path);
+        entry.option.is_active()
+            && (key == target_key || key == VolumeKey::Path(Vec::new()))
+            && file_path_text(&device_path).is_some_and(|path| path.eq_ignore_ascii_case(&target_path))
+    })
+}
+
+/// Returns the volume and file path the loader was started from.
+fn loader_location(boot_services: &BootServices) -> Option<(VolumeKey, String)> {
+    let volume = images::loader_device_path(boot_services)?;
+    let loaded_image = boot_services.open_protocol_exclusive::<LoadedImage>(boot_services.image_handle()).ok()?;
+    let path = file_path_text(loaded_image.file_path()?.as_bytes())?;
+
+    Some((images::volume_key(volume.as_bytes()), path))
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, alloc::vec::Vec};
+
+    /// `Windows Boot Manager` on a GPT partition, with the `WINDOWS` / `BCDOBJECT=` optional data bootmgfw expects.
+    const WINDOWS_BOOT_MANAGER: [u8; 294] = [
+        0x01, 0x00, 0x00, 0x00, 0x74, 0x00, 0x57, 0x00, 0x69, 0x00, 0x6e, 0x00, 0x64, 0x00, 0x6f, 0x00, 0x77, 0x00, 0x73, 0x00, 0x20, 0x00, 0x42,
+        0x00, 0x6f, 0x00, 0x6f, 0x00, 0x74, 0x00, 0x20, 0x00, 0x4d, 0x00, 0x61, 0x00, 0x6e, 0x00, 0x61, 0x00, 0x67, 0x00, 0x65, 0x00, 0x72, 0x00,
+        0x00, 0x00, 0x04, 0x01, 0x2a, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x20, 0x08, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0xd1, 0x27, 0x04, 0x6f, 0xa6, 0x04, 0x35, 0x4b, 0xa3, 0xf4, 0x9d, 0x58, 0xe1, 0xc4, 0xb0, 0xa2, 0x02, 0x02, 0x04, 0x04,
+        0x46, 0x00, 0x5c, 0x00, 0x45, 0x00, 0x46, 0x00, 0x49, 0x00, 0x5c, 0x00, 0x4d, 0x00, 0x69, 0x00, 0x63, 0x00, 0x72, 0x00, 0x6f, 0x00, 0x73,
+        0x00, 0x6f, 0x00, 0x66, 0x00, 0x74, 0x00, 0x5c, 0x00, 0x42, 0x00, 0x6f, 0x00, 0x6f, 0x00, 0x74, 0x00, 0x5c, 0x00, 0x62, 0x00, 0x6f, 0x00,
+        0x6f, 0x00, 0x74, 0x00, 0x6d, 0x00, 0x67, 0x00, 0x66, 0x00, 0x77, 0x00, 0x2e, 0x00, 0x65, 0x00, 0x66, 0x00, 0x69, 0x00, 0x00, 0x00, 0x7f,
+        0xff, 0x04, 0x00, 0x57, 0x49, 0x4e, 0x44, 0x4f, 0x57, 0x53, 0x00, 0x01, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x14, 0x00, 0x00, 0x00, 0x42, 0x00, 0x43, 0x00, 0x44, 0x00, 0x4f, 0x00, 0x42, 0x00, 0x4a,
+        0x00, 0x45, 0x00, 0x43, 0x00, 0x54, 0x00, 0x3d, 0x00, 0x7b, 0x00, 0x39, 0x00, 0x64, 0x00, 0x65, 0x00, 0x61, 0x00, 0x38, 0x00, 0x36, 0x00,
+        0x32, 0x00, 0x63, 0x00, 0x2d, 0x00, 0x35, 0x00, 0x63, 0x00, 0x64, 0x00, 0x64, 0x00, 0x2d, 0x00, 0x34, 0x00, 0x65, 0x00, 0x37, 0x00, 0x30,
+        0x00, 0x2d, 0x00, 0x61, 0x00, 0x63, 0x00, 0x63, 0x00, 0x31, 0x00, 0x2d, 0x00, 0x66, 0x00, 0x33, 0x00, 0x32, 0x00, 0x62, 0x00, 0x33, 0x00,
+        0x34, 0x00, 0x34, 0x00, 0x64, 0x00, 0x34, 0x00, 0x37, 0x00, 0x39, 0x00, 0x35, 0x00, 0x7d, 0x00, 0x00, 0x00,
+    ];
+
+    /// A shim entry passing the second stage loader path as UCS-2 load options.
+    const DEBIAN_SHIM: [u8; 166] = [
+        0x01, 0x00, 0x00, 0x00, 0x62, 0x00, 0x64, 0x00, 0x65, 0x00, 0x62, 0x00, 0x69, 0x00, 0x61, 0x00, 0x6e, 0x00, 0x00, 0x00, 0x04, 0x01, 0x2a,
+        0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x28, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1e, 0x8a,
+        0x5f, 0x0c, 0x7d, 0x3b, 0x29, 0x4e, 0x8f, 0x61, 0xa4, 0xd2, 0xc7, 0xe9, 0xb1, 0x35, 0x02, 0x02, 0x04, 0x04, 0x34, 0x00, 0x5c, 0x00, 0x45,
+        0x00, 0x46, 0x00, 0x49, 0x00, 0x5c, 0x00, 0x64, 0x00, 0x65, 0x00, 0x62, 0x00, 0x69, 0x00, 0x61, 0x00, 0x6e, 0x00, 0x5c, 0x00, 0x73, 0x00,
+        0x68, 0x00, 0x69, 0x00, 0x6d, 0x00, 0x78, 0x00, 0x36, 0x00, 0x34, 0x00, 0x2e, 0x00, 0x65, 0x00, 0x66, 0x00, 0x69, 0x00, 0x00, 0x00, 0x7f,
+        0xff, 0x04, 0x00, 0x5c, 0x00, 0x45, 0x00, 0x46, 0x00, 0x49, 0x00, 0x5c, 0x00, 0x64, 0x00, 0x65, 0x00, 0x62, 0x00, 0x69, 0x00, 0x61, 0x00,
+        0x6e, 0x00, 0x5c, 0x00, 0x67, 0x00, 0x72, 0x00, 0x75, 0x00, 0x62, 0x00, 0x78, 0x00, 0x36, 0x00, 0x34, 0x00, 0x2e, 0x00, 0x65, 0x00, 0x66,
+        0x00, 0x69, 0x00, 0x00, 0x00,
+    ];
+
+    /// An inactive network boot entry without a file path node.
+    const PXE: [u8; 87] = [
+        0x00, 0x00, 0x00, 0x00, 0x3b, 0x00, 0x55, 0x00, 0x45, 0x00, 0x46, 0x00, 0x49, 0x00, 0x20, 0x00, 0x50, 0x00, 0x58, 0x00, 0x45, 0x00, 0x76,
+        0x00, 0x34, 0x00, 0x00, 0x00, 0x02, 0x01, 0x0c, 0x00, 0xd0, 0x41, 0x03, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x06, 0x00, 0x00, 0x03,
+        0x03, 0x0b, 0x25, 0x00, 0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x7f, 0xff, 0x04, 0x00,
+    ];
+
+    fn ucs2(text: &str) -> Vec<u8> {
+        text.encode_utf16().chain([0]).flat_map(u16::to_le_bytes).collect()
+    }
+
+    #[test]
+    fn windows_boot_manager() {
+        let option = parse_load_option(&WINDOWS_BOOT_MANAGER).unwrap();
+        assert!(option.is_active());
+        assert_eq!(option.description, "Windows Boot Manager");
+        assert_eq!(option.file_path_list.len(), 116);
+        assert_eq!(&option.optional_data[..8], b"WINDOWS\0");
+        assert!(option.optional_data.ends_with(&ucs2("BCDOBJECT={9dea862c-5cdd-4e70-acc1-f32b344d4795}")));
+
+        let device_path = first_instance(&option.file_path_list).unwrap();
+        assert_eq!(device_path, option.file_path_list);
+        assert_eq!(file_path_text(&device_path).as_deref(), Some(r"\EFI\Microsoft\Boot\bootmgfw.efi"));
+        assert!(matches!(images::volume_key(&device_path), VolumeKey::Gpt(_)));
+    }
+
+    #[test]
+    fn shim_optional_data() {
+        let option = parse_load_option(&DEBIAN_SHIM).unwrap();
+        assert_eq!(option.description, "debian");
+        assert_eq!(file_path_text(&option.file_path_list).as_deref(), Some(r"\EFI\debian\shimx64.efi"));
+        assert_eq!(option.optional_data, ucs2(r"\EFI\debian\grubx64.efi"));
+    }
+
+    #[test]
+    fn entry_without_file_path() {
+        let option = parse_load_option(&PXE).unwrap();
+        assert!(!option.is_active());
+        assert_eq!(option.description, "UEFI PXEv4");
+        assert!(option.optional_data.is_empty());
+        assert_eq!(file_path_text(&option.file_path_list), None);
+    }
+
+    #[test]
+    fn malformed_load_options() {
+        assert_eq!(parse_load_option(&WINDOWS_BOOT_MANAGER[..4]), Err(LoadOptionError::Truncated));
+        assert_eq!(parse_load_option(&WINDOWS_BOOT_MANAGER[..20]), Err(LoadOptionError::UnterminatedDescription));
+        assert_eq!(parse_load_option(&WINDOWS_BOOT_MANAGER[..100]), Err(LoadOptionError::FilePathListOverflow(116)));
+    }
+
+    #[test]
+    fn boot_order() {
+        assert_eq!(parse_boot_order(&[0x03, 0x00, 0x00, 0x00, 0x0a, 0x10, 0xff]), [0x0003, 0x0000, 0x100a]);
+        assert_eq!(
+            BootEntry {
+                number: 0x1a,
+                option: parse_load_option(&PXE).unwrap()
+            }
+            .name(),
+            "Boot001A"
+        );
+    }
+
+    #[test]
+    fn multiple_instances_and_file_nodes() {
+        let mut list = Vec::new();
+        list.extend_from_slice(&[0x04, 0x04, 0x0c, 0x00]);
+        list.extend_from_slice(&ucs2(r"\EFI")[..8]);
+        list.extend_from_slice(&[0x04, 0x04, 0x10, 0x00]);
+        list.extend_from_slice(&ucs2("a.efi"));
+        list.extend_from_slice(&[
+            0x7f, 0x01, 0x04, 0x00, 0x04, 0x04, 0x08, 0x00, 0x62, 0x00, 0x00, 0x00, 0x7f, 0xff, 0x04, 0x00,
+        ]);
+
+        let instance = first_instance(&list).unwrap();
+        assert_eq!(instance.len(), 12 + 16 + 4);
+        assert_eq!(&instance[28..], &[0x7f, 0xff, 0x04, 0x00]);
+        assert_eq!(file_path_text(&instance).as_deref(), Some(r"\EFI\a.efi"));
+
+        assert_eq!(first_instance(&[0x04, 0x04, 0x02, 0x00]), None);
+    }
+}
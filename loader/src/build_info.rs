@@ -0,0 +1,61 @@
+//! Identification of the loader build, printed at startup and written to the log file.
+
+use {core::fmt, uefi::prelude::*};
+
+/// Version, revision and profile of the loader build.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct BuildInfo {
+    /// The crate version.
+    pub version: &'static str,
+
+    /// The short git hash of the commit the loader was built from, `unknown` outside of a git checkout.
+    pub git_hash: &'static str,
+
+    /// `debug` or `release`.
+    pub profile: &'static str,
+}
+
+/// The build of the running loader.
+pub(crate) const LOADER_BUILD_INFO: BuildInfo = BuildInfo {
+    version: env!("CARGO_PKG_VERSION"),
+    git_hash: match option_env!("ILLUSION_GIT_HASH") {
+        Some(hash) => hash,
+        None => "unknown",
+    },
+    profile: env!("ILLUSION_BUILD_PROFILE"),
+};
+
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}, {} build)", self.version, self.git_hash, self.profile)
+    }
+}
+
+/// Logs the loader build and the firmware it runs on.
+///
+/// # Arguments
+///
+/// * `system_table` - The UEFI system table, the source of the firmware details.
+pub(crate) fn log_banner(system_table: &SystemTable<Boot>) {
+    log::info!("[1/8] Illusion loader {}", LOADER_BUILD_INFO);
+    log::info!(
+        "[1/8] Firmware: {} (revision {:#x}), UEFI {}",
+        system_table.firmware_vendor(),
+        system_table.firmware_revision(),
+        system_table.uefi_revision()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::LOADER_BUILD_INFO, alloc::format};
+
+    #[test]
+    fn build_info_is_complete() {
+        let info = LOADER_BUILD_INFO;
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert!(!info.git_hash.is_empty());
+        assert!(["debug", "release"].contains(&info.profile));
+        assert_eq!(format!("{}", info), format!("{} ({}, {} build)", info.version, info.git_hash, info.profile));
+    }
+}
@@ -0,0 +1,251 @@
+//! Decompression of LZ4 compressed hypervisor images.
+//!
+//! A compressed image is an LZ4 block prefixed with an 8 byte header: the magic `ILZ4` followed by the
+//! decompressed size as a little-endian `u32`. The block can be produced with `lz4 -l -9`, by replacing
+//! the 4 byte legacy frame magic and the 4 byte block size with the header.
+
+extern crate alloc;
+
+use {
+    alloc::vec::Vec,
+    core::fmt::{self, Display},
+};
+
+/// Extension appended to the hypervisor path to locate the compressed image.
+pub(crate) const COMPRESSED_EXTENSION: &str = ".lz4";
+
+/// Magic bytes at the start of a compressed image.
+const MAGIC: [u8; 4] = *b"ILZ4";
+
+/// Size of the header preceding the LZ4 block.
+const HEADER_SIZE: usize = 8;
+
+/// Upper bound for the decompressed size, far above any hypervisor image.
+const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+
+/// Length of the shortest match, added to the match length stored in a sequence.
+const MIN_MATCH: usize = 4;
+
+/// Reasons why a compressed image is rejected.
+#[derive(Debug, PartialEq)]
+pub(crate) enum CompressError {
+    /// The file is too short to hold the header.
+    TruncatedHeader,
+
+    /// The header announces more than `MAX_DECOMPRESSED_SIZE` bytes.
+    TooLarge(usize),
+
+    /// The block ends in the middle of a sequence.
+    TruncatedBlock,
+
+    /// A match refers to data before the start of the output.
+    InvalidOffset(usize),
+
+    /// The block decompresses to a different size than the header announces.
+    SizeMismatch { expected: usize, actual: usize },
+}
+
+impl Display for CompressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompressError::TruncatedHeader => write!(f, "file truncated in the header"),
+            CompressError::TooLarge(size) => write!(f, "decompressed size {} exceeds the {} bytes limit", size, MAX_DECOMPRESSED_SIZE),
+            CompressError::TruncatedBlock => write!(f, "compressed data is truncated"),
+            CompressError::InvalidOffset(offset) => write!(f, "match offset {} points before the start of the data", offset),
+            CompressError::SizeMismatch { expected, actual } => {
+                write!(f, "data decompresses to {} bytes, but the header announces {}", actual, expected)
+            }
+        }
+    }
+}
+
+/// Returns whether `data` starts with the magic bytes of a compressed image.
+pub(crate) fn is_compressed(data: &[u8]) -> bool {
+    data.starts_with(&MAGIC)
+}
+
+/// Decompresses a compressed image including its header.
+///
+/// # Arguments
+///
+/// * `data` - The contents of the compressed file.
+///
+/// # Returns
+///
+/// The decompressed image, or why the file is not a valid compressed image.
+pub(crate) fn decompress(data: &[u8]) -> Result<Vec<u8>, CompressError> {
+    if data.len() < HEADER_SIZE || !is_compressed(data) {
+        return Err(CompressError::TruncatedHeader);
+    }
+
+    let size = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+    if size > MAX_DECOMPRESSED_SIZE {
+        return Err(CompressError::TooLarge(size));
+    }
+
+    decompress_block(&data[HEADER_SIZE..], size)
+}
+
+/// Decompresses a raw LZ4 block.
+///
+/// # Arguments
+///
+/// * `block` - The LZ4 block, a sequence of literal runs and back references.
+/// * `size` - The expected decompressed size. Output beyond it is rejected.
+///
+/// # Returns
+///
+/// The decompressed data, exactly `size` bytes long.
+fn decompress_block(block: &[u8], size: usize) -> Result<Vec<u8>, CompressError> {
+    let mut output = Vec::with_capacity(size);
+    let mut input = block;
+
+    loop {
+        let (&token, rest) = input.split_first().ok_or(CompressError::TruncatedBlock)?;
+        input = rest;
+
+        let literal_length = read_length((token >> 4) as usize, &mut input)?;
+        if input.len() < literal_length || output.len() + literal_length > size {
+            return Err(length_error(&output, literal_length, size));
+        }
+        output.extend_from_slice(&input[..literal_length]);
+        input = &input[literal_length..];
+
+        // The last sequence only consists of literals.
+        if input.is_empty() {
+            break;
+        }
+
+        if input.len() < 2 {
+            return Err(CompressError::TruncatedBlock);
+        }
+        let offset = u16::from_le_bytes([input[0], input[1]]) as usize;
+        input = &input[2..];
+
+        if offset == 0 || offset > output.len() {
+            return Err(CompressError::InvalidOffset(offset));
+        }
+
+        let match_length = read_length((token & 0x0f) as usize, &mut input)? + MIN_MATCH;
+        if output.len() + match_length > size {
+            return Err(CompressError::SizeMismatch {
+                expected: size,
+                actual: output.len() + match_length,
+            });
+        }
+
+        // Matches may overlap the bytes they produce, so copy one byte at a time.
+        let start = output.len() - offset;
+        for index in 0..match_length {
+            output.push(output[start + index]);
+        }
+    }
+
+    if output.len() != size {
+        return Err(CompressError::SizeMismatch {
+            expected: size,
+            actual: output.len(),
+        });
+    }
+
+    Ok(output)
+}
+
+/// Reads a length whose 4 bit value in the token is extended by following bytes while it is saturated.
+fn read_length(mut length: usize, input: &mut &[u8]) -> Result<usize, CompressError> {
+    if length != 0x0f {
+        return Ok(length);
+    }
+
+    loop {
+        let (&byte, rest) = input.split_first().ok_or(CompressError::TruncatedBlock)?;
+        *input = rest;
+        length += byte as usize;
+
+        if byte != 0xff {
+            return Ok(length);
+        }
+    }
+}
+
+/// Picks the error for a literal run that doesn't fit the input or the output.
+fn length_error(output: &[u8], literal_length: usize, size: usize) -> CompressError {
+    match output.len() + literal_length > size {
+        true => CompressError::SizeMismatch {
+            expected: size,
+            actual: output.len() + literal_length,
+        },
+        false => CompressError::TruncatedBlock,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decompress, decompress_block, is_compressed, CompressError};
+
+    const TEXT: &[u8] = include_bytes!("../tests/fixtures/text.bin");
+    const TEXT_LZ4: &[u8] = include_bytes!("../tests/fixtures/text.bin.lz4");
+    const BINARY: &[u8] = include_bytes!("../tests/fixtures/binary.bin");
+    const BINARY_LZ4: &[u8] = include_bytes!("../tests/fixtures/binary.bin.lz4");
+
+    #[test]
+    fn round_trips_text_fixture() {
+        assert!(is_compressed(TEXT_LZ4));
+        assert_eq!(decompress(TEXT_LZ4).unwrap(), TEXT);
+    }
+
+    #[test]
+    fn round_trips_binary_fixture() {
+        assert!(is_compressed(BINARY_LZ4));
+        assert_eq!(decompress(BINARY_LZ4).unwrap(), BINARY);
+    }
+
+    #[test]
+    fn uncompressed_images_are_not_detected() {
+        assert!(!is_compressed(b"MZ\x90\x00"));
+        assert!(!is_compressed(TEXT));
+    }
+
+    #[test]
+    fn overlapping_match() {
+        // One literal `a`, then a match of 7 bytes at offset 1.
+        assert_eq!(decompress_block(&[0x13, b'a', 0x01, 0x00, 0x00], 8).unwrap(), b"aaaaaaaa");
+    }
+
+    #[test]
+    fn rejects_truncated_header() {
+        assert_eq!(decompress(b"ILZ4\x10"), Err(CompressError::TruncatedHeader));
+    }
+
+    #[test]
+    fn rejects_oversized_header() {
+        assert_eq!(decompress(b"ILZ4\x00\x00\x00\x10"), Err(CompressError::TooLarge(0x1000_0000)));
+    }
+
+    #[test]
+    fn rejects_truncated_block() {
+        let truncated = &TEXT_LZ4[..TEXT_LZ4.len() - 10];
+        assert!(decompress(truncated).is_err());
+    }
+
+    #[test]
+    fn rejects_size_mismatch() {
+        let mut data = TEXT_LZ4.to_vec();
+        data[4..8].copy_from_slice(&(TEXT.len() as u32 - 1).to_le_bytes());
+        assert!(matches!(decompress(&data), Err(CompressError::SizeMismatch { .. })));
+
+        data[4..8].copy_from_slice(&(TEXT.len() as u32 + 1).to_le_bytes());
+        assert_eq!(
+            decompress(&data),
+            Err(CompressError::SizeMismatch {
+                expected: TEXT.len() + 1,
+                actual: TEXT.len(),
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_offset_before_start() {
+        assert_eq!(decompress_block(&[0x10, b'a', 0x02, 0x00, 0x00], 8), Err(CompressError::InvalidOffset(2)));
+    }
+}
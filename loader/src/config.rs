@@ -0,0 +1,1070 @@
+//! Loader configuration read from `\EFI\Boot\illusion.cfg` on the volume the loader was started from.
+//!
+//! The file is a plain list of `key=value` lines. Empty lines and lines starting with `#` are ignored.
+//! Unknown keys and malformed values only produce a warning, the affected setting keeps its default.
+
+extern crate alloc;
+
+use {
+    crate::{
+        boot_attempt::DEFAULT_BOOT_ATTEMPT_LIMIT,
+        console::ConsoleMode,
+        exclusion::VolumePattern,
+        images::{SearchOptions, DEFAULT_CHAINLOAD_PATHS, DEFAULT_PROBE_TIMEOUT_MS},
+        logging::{self, COM1, DEFAULT_LOG_LEVEL},
+        measure::{DEFAULT_MEASURE_PCR, MAX_PCR},
+        memmap,
+        netboot::{self, NetSource, DEFAULT_NET_TIMEOUT_MS},
+        retry,
+        sha256::DIGEST_SIZE,
+        verify,
+    },
+    alloc::{format, string::String, vec::Vec},
+    log::LevelFilter,
+    shared::features::HvFeatureFlags,
+    uefi::{
+        prelude::*,
+        proto::media::file::{File, FileAttribute, FileInfo, FileMode},
+        CStr16, CString16,
+    },
+};
+
+/// Path of the configuration file, relative to the root of the loader's own volume.
+pub(crate) const CONFIG_PATH: &CStr16 = cstr16!(r"\EFI\Boot\illusion.cfg");
+
+/// Upper bound for the configuration file size, anything larger is certainly not a config file.
+const MAX_CONFIG_SIZE: u64 = 64 * 1024;
+
+/// Default time to wait for a selection in the boot manager menu.
+const DEFAULT_SELECTION_TIMEOUT_MS: u64 = 5_000;
+
+/// Default time a single boot manager candidate is shown before it is booted.
+const DEFAULT_SINGLE_CANDIDATE_TIMEOUT_MS: u64 = 2_000;
+
+/// Default time to stall before handing off to the boot manager.
+const DEFAULT_HANDOFF_STALL_MS: u64 = 3_000;
+
+/// What to do when the hypervisor can't be started.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum FailurePolicy {
+    /// Stop booting.
+    Abort,
+
+    /// Boot Windows without the hypervisor.
+    Continue,
+
+    /// Ask the user whether to boot Windows without the hypervisor.
+    Prompt,
+}
+
+impl FailurePolicy {
+    fn parse(value: &str) -> Result<Self, &'static str> {
+        match value {
+            "abort" => Ok(FailurePolicy::Abort),
+            "continue" => Ok(FailurePolicy::Continue),
+            "prompt" => Ok(FailurePolicy::Prompt),
+            _ => Err("expected abort, continue or prompt"),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            FailurePolicy::Abort => "abort",
+            FailurePolicy::Continue => "continue",
+            FailurePolicy::Prompt => "prompt",
+        }
+    }
+}
+
+/// Where the boot manager candidates come from.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum ChainloadSource {
+    /// Search the configured chainload paths on all filesystems.
+    Paths,
+
+    /// Use the firmware boot entries listed in `BootOrder`.
+    BootOrder,
+
+    /// Don't chainload, return to the firmware after the hypervisor phase.
+    Firmware,
+}
+
+/// What to do when Secure Boot is enforced and the hypervisor image is unsigned.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum SecureBootPolicy {
+    /// Boot Windows without the hypervisor.
+    Continue,
+
+    /// Stop booting.
+    Abort,
+
+    /// Load the image anyway, e.g. because it was signed with a key the loader can't see.
+    Attempt,
+}
+
+impl SecureBootPolicy {
+    fn parse(value: &str) -> Result<Self, &'static str> {
+        match value {
+            "continue" => Ok(SecureBootPolicy::Continue),
+            "abort" => Ok(SecureBootPolicy::Abort),
+            "attempt" => Ok(SecureBootPolicy::Attempt),
+            _ => Err("expected continue, abort or attempt"),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            SecureBootPolicy::Continue => "continue",
+            SecureBootPolicy::Abort => "abort",
+            SecureBootPolicy::Attempt => "attempt",
+        }
+    }
+}
+
+/// What to do when the loader fails and would return to the firmware with an error.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum FatalPolicy {
+    /// Ask whether to reboot, shut down or return to the firmware.
+    Prompt,
+
+    /// Reboot the machine.
+    Reboot,
+
+    /// Return to the firmware with the error status.
+    Firmware,
+}
+
+impl FatalPolicy {
+    fn parse(value: &str) -> Result<Self, &'static str> {
+        match value {
+            "prompt" => Ok(FatalPolicy::Prompt),
+            "reboot" => Ok(FatalPolicy::Reboot),
+            "firmware" => Ok(FatalPolicy::Firmware),
+            _ => Err("expected prompt, reboot or firmware"),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            FatalPolicy::Prompt => "prompt",
+            FatalPolicy::Reboot => "reboot",
+            FatalPolicy::Firmware => "firmware",
+        }
+    }
+}
+
+/// Settings consumed by the loader, with defaults for everything the config file does not specify.
+pub(crate) struct LoaderConfig {
+    /// Paths of the hypervisor images searched on all filesystems. If more than one is found, the user picks
+    /// one. If unset, the image matching the processor vendor is used.
+    pub hypervisor_paths: Vec<CString16>,
+
+    /// TFTP server the hypervisor image is downloaded from. If unset or the download fails, the image is
+    /// searched on the local filesystems.
+    pub hypervisor_source: Option<NetSource>,
+
+    /// How long downloading the hypervisor image may take, `0` disables the check.
+    pub net_timeout_ms: u64,
+
+    /// Paths of the images to chainload, searched on all filesystems in order of preference.
+    pub chainload: Vec<CString16>,
+
+    /// Where the boot manager candidates come from, set by `chainload`.
+    pub chainload_source: ChainloadSource,
+
+    /// Load options passed to the boot manager, overriding the optional data of its `Boot####` entry.
+    pub chainload_options: Option<String>,
+
+    /// Also search every `\EFI\<vendor>` directory for the file names of the chainload paths.
+    pub deep_scan: bool,
+
+    /// How long probing a single volume may take before it is skipped, `0` disables the check.
+    pub probe_timeout_ms: u64,
+
+    /// Don't search removable media for the hypervisor image.
+    pub hypervisor_skip_removable: bool,
+
+    /// How long the selection menu waits for input before picking the default candidate, `0` waits forever.
+    pub selection_timeout_ms: u64,
+
+    /// How long a single candidate is shown, with ESC to abort and `r` to rescan, before it is booted.
+    /// `0` boots it right away.
+    pub single_candidate_timeout_ms: u64,
+
+    /// Draw the selection menu on the framebuffer instead of the text console, if the screen allows it.
+    pub graphical_menu: bool,
+
+    /// Log every key pressed in the selection menu, to report keys the menu doesn't recognize.
+    pub log_keys: bool,
+
+    /// How long to stall before handing off to the boot manager.
+    pub handoff_stall_ms: u64,
+
+    /// Start Windows behind the hypervisor without asking when a BitLocker volume is found.
+    pub acknowledge_bitlocker: bool,
+
+    /// Volumes whose boot manager candidates are left out of the menu, by partition GUID or volume label,
+    /// e.g. the Windows recovery partition.
+    pub exclude_volumes: Vec<VolumePattern>,
+
+    /// If not empty, the only volumes whose boot manager candidates are offered in the menu.
+    pub include_volumes: Vec<VolumePattern>,
+
+    /// List the excluded candidates greyed out at the end of the menu, selectable after pressing `x`.
+    pub show_excluded: bool,
+
+    /// The menu entry (1-based) selected by default. If unset, the loader picks the previously selected volume
+    /// or the one on the same disk as the loader.
+    pub default_candidate: Option<usize>,
+
+    /// Skip the hypervisor phase and go straight to the boot manager (set by `--no-hypervisor`).
+    pub skip_hypervisor: bool,
+
+    /// Expected SHA-256 digest of the hypervisor image. If unset, the sidecar file is consulted.
+    pub hypervisor_sha256: Option<[u8; DIGEST_SIZE]>,
+
+    /// Load the hypervisor from the bytes read by the loader instead of letting the firmware read the file.
+    /// Always done when the integrity check verified the image.
+    pub load_from_buffer: bool,
+
+    /// Skip the hypervisor integrity check (set by `--no-verify`).
+    pub skip_verify: bool,
+
+    /// Measure the hypervisor image and the boot manager selection into the TPM.
+    pub measure: bool,
+
+    /// PCR the measurements are extended into.
+    pub measure_pcr: u32,
+
+    /// How often loading an image or probing a volume is attempted when the device reports it is not ready.
+    pub load_attempts: u32,
+
+    /// How often the hypervisor and the boot manager are searched for before giving up, for controllers that
+    /// expose their filesystems late.
+    pub rescan_attempts: u32,
+
+    /// Time to wait before searching again, see `rescan_attempts`.
+    pub rescan_interval_ms: u64,
+
+    /// Load the hypervisor even if it is already running (set by `--force-load`).
+    pub force_load: bool,
+
+    /// What to do when the virtualization pre-flight check fails.
+    pub on_unsupported_cpu: FailurePolicy,
+
+    /// What to do when the hypervisor can't be found, verified, loaded or started.
+    pub on_hypervisor_failure: FailurePolicy,
+
+    /// What to do when Secure Boot is enforced and the hypervisor image is unsigned.
+    pub on_unsigned_secure_boot: SecureBootPolicy,
+
+    /// What to do when the loader fails.
+    pub on_fatal: FatalPolicy,
+
+    /// Consecutive boots the hypervisor was started in without reaching Windows, after which it is skipped.
+    /// `0` disables the check.
+    pub boot_attempt_limit: u32,
+
+    /// Clear the boot attempt counter, leaving safe mode.
+    pub reset_boot_attempts: bool,
+
+    /// Base I/O port of the UART the log is mirrored to, `None` to only log to the console.
+    pub serial: Option<u16>,
+
+    /// Highest level of messages that are logged.
+    pub log_level: LevelFilter,
+
+    /// Save the log to `\EFI\Boot\illusion-loader.log` before every handoff and when the loader fails.
+    pub log_file: bool,
+
+    /// Log a summary of the memory map before starting the boot manager.
+    pub log_memory_map: bool,
+
+    /// Runtime services regions at least this many MiB large are listed in the memory map summary.
+    pub log_memory_map_min_mib: u64,
+
+    /// Text mode the console is switched to at startup.
+    pub console_mode: ConsoleMode,
+
+    /// Clear the loader's file path and padding before starting the boot manager. Off by default, as it
+    /// hides the loader from firmware debugging tools as well.
+    pub scrub_loader_image: bool,
+
+    /// Highest level of messages the hypervisor logs, `None` to keep the level the image was built with.
+    pub hv_log_level: Option<LevelFilter>,
+
+    /// Make the hypervisor log every EPT hook the guest runs into, `None` to keep the image's setting.
+    pub hv_log_ept_hooks: Option<bool>,
+
+    /// Make the hypervisor invalidate the TLB entries of all VPIDs on every flush, for debugging. `None` to
+    /// keep the image's setting.
+    pub hv_full_tlb_flushes: Option<bool>,
+
+    /// The value RAX has to hold for a CPUID to be taken as a hypercall, `None` to generate a new key
+    /// every boot.
+    pub hv_vmcall_key: Option<u64>,
+
+    /// The most cycles RDTSC compensation may hold the time stamp counter of a processor back, `None` to
+    /// keep the image's setting.
+    pub hv_tsc_skew_limit: Option<u32>,
+
+    /// The features the hypervisor enables for this boot, passed in the handoff.
+    pub hv_features: HvFeatureFlags,
+}
+
+impl Default for LoaderConfig {
+    fn default() -> Self {
+        Self {
+            hypervisor_paths: Vec::new(),
+            hypervisor_source: None,
+            net_timeout_ms: DEFAULT_NET_TIMEOUT_MS,
+            chainload: DEFAULT_CHAINLOAD_PATHS.iter().map(|path| CString16::from(*path)).collect(),
+            chainload_source: ChainloadSource::Paths,
+            chainload_options: None,
+            deep_scan: false,
+            probe_timeout_ms: DEFAULT_PROBE_TIMEOUT_MS,
+            hypervisor_skip_removable: false,
+            selection_timeout_ms: DEFAULT_SELECTION_TIMEOUT_MS,
+            single_candidate_timeout_ms: DEFAULT_SINGLE_CANDIDATE_TIMEOUT_MS,
+            graphical_menu: false,
+            log_keys: false,
+            handoff_stall_ms: DEFAULT_HANDOFF_STALL_MS,
+            acknowledge_bitlocker: false,
+            exclude_volumes: Vec::new(),
+            include_volumes: Vec::new(),
+            show_excluded: false,
+            default_candidate: None,
+            skip_hypervisor: false,
+            hypervisor_sha256: None,
+            load_from_buffer: false,
+            skip_verify: false,
+            measure: true,
+            measure_pcr: DEFAULT_MEASURE_PCR,
+            load_attempts: retry::DEFAULT_ATTEMPTS,
+            rescan_attempts: retry::DEFAULT_RESCAN_ATTEMPTS,
+            rescan_interval_ms: retry::DEFAULT_RESCAN_INTERVAL_MS,
+            force_load: false,
+            on_unsupported_cpu: FailurePolicy::Abort,
+            on_hypervisor_failure: FailurePolicy::Abort,
+            on_unsigned_secure_boot: SecureBootPolicy::Continue,
+            on_fatal: FatalPolicy::Prompt,
+            boot_attempt_limit: DEFAULT_BOOT_ATTEMPT_LIMIT,
+            reset_boot_attempts: false,
+            serial: Some(COM1),
+            log_level: DEFAULT_LOG_LEVEL,
+            log_file: false,
+            log_memory_map: false,
+            log_memory_map_min_mib: memmap::DEFAULT_RUNTIME_REGION_MIB,
+            console_mode: ConsoleMode::Auto,
+            scrub_loader_image: false,
+            hv_log_level: None,
+            hv_log_ept_hooks: None,
+            hv_full_tlb_flushes: None,
+            hv_vmcall_key: None,
+            hv_tsc_skew_limit: None,
+            hv_features: HvFeatureFlags::DEFAULT,
+        }
+    }
+}
+
+/// All keys understood by the parser, used to report which settings kept their defaults.
+const KEYS: [&str; 44] = [
+    "hypervisor_path",
+    "hypervisor_source",
+    "net_timeout_ms",
+    "chainload",
+    "chainload_options",
+    "deep_scan",
+    "probe_timeout_ms",
+    "hypervisor_skip_removable",
+    "selection_timeout_ms",
+    "single_candidate_timeout_ms",
+    "graphical_menu",
+    "log_keys",
+    "handoff_stall_ms",
+    "acknowledge_bitlocker",
+    "exclude_volumes",
+    "include_volumes",
+    "show_excluded",
+    "default_candidate",
+    "hypervisor_sha256",
+    "load_from_buffer",
+    "measure",
+    "measure_pcr",
+    "load_attempts",
+    "rescan_attempts",
+    "rescan_interval_ms",
+    "on_unsupported_cpu",
+    "on_hypervisor_failure",
+    "on_unsigned_secure_boot",
+    "on_fatal",
+    "boot_attempt_limit",
+    "reset_boot_attempts",
+    "serial",
+    "log_level",
+    "log_file",
+    "log_memory_map",
+    "log_memory_map_min_mib",
+    "console_mode",
+    "scrub_loader_image",
+    "hv_log_level",
+    "hv_log_ept_hooks",
+    "hv_full_tlb_flushes",
+    "hv_vmcall_key",
+    "hv_tsc_skew_limit",
+    "hv_features",
+];
+
+impl LoaderConfig {
+    /// Loads the configuration from the volume the loader image was started from.
+    ///
+    /// # Arguments
+    ///
+    /// * `boot_services` - A reference to the UEFI boot services.
+    ///
+    /// # Returns
+    ///
+    /// The parsed configuration. If the file is missing or unreadable, the defaults are returned.
+    pub(crate) fn load(boot_services: &BootServices) -> Self {
+        Self::from_file(read_config_file(boot_services))
+    }
+
+    /// Builds the configuration from the outcome of reading the configuration file.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The contents of the file, or why it couldn't be read.
+    fn from_file(file: uefi::Result<Vec<u8>>) -> Self {
+        match file {
+            Ok(bytes) => {
+                log::info!("Loaded configuration file {} ({} bytes)", CONFIG_PATH, bytes.len());
+                Self::parse(&bytes)
+            }
+            Err(error) => {
+                log::info!("No configuration file {} ({:?}), using defaults", CONFIG_PATH, error.status());
+                let config = Self::default();
+                config.log_defaults(&[]);
+                config
+            }
+        }
+    }
+
+    /// Parses the raw contents of a configuration file.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The contents of the configuration file.
+    ///
+    /// # Returns
+    ///
+    /// The parsed configuration. Invalid lines are reported and skipped.
+    pub(crate) fn parse(bytes: &[u8]) -> Self {
+        let mut config = Self::default();
+        let mut applied: Vec<&'static str> = Vec::new();
+
+        let text = String::from_utf8_lossy(bytes);
+        let text = text.strip_prefix('\u{feff}').unwrap_or(&text);
+
+        for (line_index, raw_line) in text.lines().enumerate() {
+            let line_number = line_index + 1;
+            let line = raw_line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                log::warn!("illusion.cfg:{}: expected key=value, ignoring line", line_number);
+                continue;
+            };
+
+            let (key, value) = (key.trim(), value.trim());
+
+            match config.apply(key, value) {
+                Ok(key) => {
+                    log::info!("Config: {} = {} (from illusion.cfg)", key, value);
+                    applied.push(key);
+                }
+                Err(reason) => log::warn!("illusion.cfg:{}: {} ({}), ignoring line", line_number, reason, key),
+            }
+        }
+
+        config.log_defaults(&applied);
+        config
+    }
+
+    /// Applies a single setting.
+    ///
+    /// # Returns
+    ///
+    /// The canonical name of the key that was set, or a description of why the value was rejected.
+    pub(crate) fn apply(&mut self, key: &str, value: &str) -> Result<&'static str, &'static str> {
+        match key {
+            "hypervisor_path" => {
+                let paths = value
+                    .split(';')
+                    .map(str::trim)
+                    .filter(|path| !path.is_empty())
+                    .map(parse_path)
+                    .collect::<Result<Vec<_>, _>>()?;
+                if paths.is_empty() {
+                    return Err("expected at least one path");
+                }
+                self.hypervisor_paths = paths;
+                Ok("hypervisor_path")
+            }
+            "hypervisor_source" => {
+                self.hypervisor_source = match value {
+                    "local" => None,
+                    _ => Some(netboot::parse_source(value)?),
+                };
+                Ok("hypervisor_source")
+            }
+            "net_timeout_ms" => {
+                self.net_timeout_ms = value.parse().map_err(|_| "invalid number")?;
+                Ok("net_timeout_ms")
+            }
+            "chainload" if value == "firmware" => {
+                self.chainload_source = ChainloadSource::Firmware;
+                Ok("chainload")
+            }
+            "chainload" if value == "boot_order" => {
+                self.chainload_source = ChainloadSource::BootOrder;
+                Ok("chainload")
+            }
+            "chainload" => {
+                let paths = value
+                    .split(';')
+                    .map(str::trim)
+                    .filter(|path| !path.is_empty())
+                    .map(parse_path)
+                    .collect::<Result<Vec<_>, _>>()?;
+                if paths.is_empty() {
+                    return Err("expected at least one path");
+                }
+                self.chainload = paths;
+                self.chainload_source = ChainloadSource::Paths;
+                Ok("chainload")
+            }
+            "chainload_options" => {
+                self.chainload_options = Some(String::from(value));
+                Ok("chainload_options")
+            }
+            "deep_scan" => {
+                self.deep_scan = parse_bool(value)?;
+                Ok("deep_scan")
+            }
+            "probe_timeout_ms" => {
+                self.probe_timeout_ms = value.parse().map_err(|_| "invalid number")?;
+                Ok("probe_timeout_ms")
+            }
+            "hypervisor_skip_removable" => {
+                self.hypervisor_skip_removable = parse_bool(value)?;
+                Ok("hypervisor_skip_removable")
+            }
+            "selection_timeout_ms" => {
+                self.selection_timeout_ms = value.parse().map_err(|_| "invalid number")?;
+                Ok("selection_timeout_ms")
+            }
+            "single_candidate_timeout_ms" => {
+                self.single_candidate_timeout_ms = value.parse().map_err(|_| "invalid number")?;
+                Ok("single_candidate_timeout_ms")
+            }
+            "graphical_menu" => {
+                self.graphical_menu = parse_bool(value)?;
+                Ok("graphical_menu")
+            }
+            "log_keys" => {
+                self.log_keys = parse_bool(value)?;
+                Ok("log_keys")
+            }
+            "handoff_stall_ms" => {
+                self.handoff_stall_ms = value.parse().map_err(|_| "invalid number")?;
+                Ok("handoff_stall_ms")
+            }
+            "acknowledge_bitlocker" => {
+                self.acknowledge_bitlocker = parse_bool(value)?;
+                Ok("acknowledge_bitlocker")
+            }
+            "exclude_volumes" => {
+                self.exclude_volumes = parse_volumes(value);
+                Ok("exclude_volumes")
+            }
+            "include_volumes" => {
+                self.include_volumes = parse_volumes(value);
+                Ok("include_volumes")
+            }
+            "show_excluded" => {
+                self.show_excluded = parse_bool(value)?;
+                Ok("show_excluded")
+            }
+            "default_candidate" => {
+                self.default_candidate = match value.parse() {
+                    Ok(0) | Err(_) => return Err("expected a candidate number starting at 1"),
+                    Ok(candidate) => Some(candidate),
+                };
+                Ok("default_candidate")
+            }
+            "hypervisor_sha256" => {
+                self.hypervisor_sha256 = Some(verify::parse_digest(value).ok_or("expected 64 hexadecimal digits")?);
+                Ok("hypervisor_sha256")
+            }
+            "load_from_buffer" => {
+                self.load_from_buffer = parse_bool(value)?;
+                Ok("load_from_buffer")
+            }
+            "measure" => {
+                self.measure = parse_bool(value)?;
+                Ok("measure")
+            }
+            "measure_pcr" => {
+                self.measure_pcr = match value.parse() {
+                    Ok(pcr) if pcr <= MAX_PCR => pcr,
+                    _ => return Err("expected a PCR index from 0 to 23"),
+                };
+                Ok("measure_pcr")
+            }
+            "load_attempts" => {
+                self.load_attempts = match value.parse() {
+                    Ok(0) | Err(_) => return Err("expected a number of at least 1"),
+                    Ok(attempts) => attempts,
+                };
+                Ok("load_attempts")
+            }
+            "rescan_attempts" => {
+                self.rescan_attempts = match value.parse() {
+                    Ok(0) | Err(_) => return Err("expected a number of at least 1"),
+                    Ok(attempts) => attempts,
+                };
+                Ok("rescan_attempts")
+            }
+            "rescan_interval_ms" => {
+                self.rescan_interval_ms = value.parse().map_err(|_| "invalid number")?;
+                Ok("rescan_interval_ms")
+            }
+            "on_unsupported_cpu" => {
+                self.on_unsupported_cpu = FailurePolicy::parse(value)?;
+                Ok("on_unsupported_cpu")
+            }
+            "on_hypervisor_failure" => {
+                self.on_hypervisor_failure = FailurePolicy::parse(value)?;
+                Ok("on_hypervisor_failure")
+            }
+            "on_unsigned_secure_boot" => {
+                self.on_unsigned_secure_boot = SecureBootPolicy::parse(value)?;
+                Ok("on_unsigned_secure_boot")
+            }
+            "on_fatal" => {
+                self.on_fatal = FatalPolicy::parse(value)?;
+                Ok("on_fatal")
+            }
+            "boot_attempt_limit" => {
+                self.boot_attempt_limit = value.parse().map_err(|_| "invalid number")?;
+                Ok("boot_attempt_limit")
+            }
+            "reset_boot_attempts" => {
+                self.reset_boot_attempts = parse_bool(value)?;
+                Ok("reset_boot_attempts")
+            }
+            "serial" => {
+                self.serial = logging::parse_serial_port(value)?;
+                Ok("serial")
+            }
+            "log_level" => {
+                self.log_level = logging::parse_level(value)?;
+                Ok("log_level")
+            }
+            "log_file" => {
+                self.log_file = parse_bool(value)?;
+                Ok("log_file")
+            }
+            "log_memory_map" => {
+                self.log_memory_map = parse_bool(value)?;
+                Ok("log_memory_map")
+            }
+            "log_memory_map_min_mib" => {
+                self.log_memory_map_min_mib = value.parse().map_err(|_| "invalid number")?;
+                Ok("log_memory_map_min_mib")
+            }
+            "console_mode" => {
+                self.console_mode = ConsoleMode::parse(value)?;
+                Ok("console_mode")
+            }
+            "scrub_loader_image" => {
+                self.scrub_loader_image = parse_bool(value)?;
+                Ok("scrub_loader_image")
+            }
+            "hv_log_level" => {
+                self.hv_log_level = Some(match value {
+                    "off" => LevelFilter::Off,
+                    value => logging::parse_level(value).map_err(|_| "expected off, error, warn, info, debug or trace")?,
+                });
+                Ok("hv_log_level")
+            }
+            "hv_log_ept_hooks" => {
+                self.hv_log_ept_hooks = Some(parse_bool(value)?);
+                Ok("hv_log_ept_hooks")
+            }
+            "hv_full_tlb_flushes" => {
+                self.hv_full_tlb_flushes = Some(parse_bool(value)?);
+                Ok("hv_full_tlb_flushes")
+            }
+            "hv_vmcall_key" => {
+                let digits = value.strip_prefix("0x").ok_or("expected a hexadecimal key like 0x1234abcd")?;
+                self.hv_vmcall_key = Some(u64::from_str_radix(digits, 16).map_err(|_| "expected a hexadecimal key like 0x1234abcd")?);
+                Ok("hv_vmcall_key")
+            }
+            "hv_tsc_skew_limit" => {
+                self.hv_tsc_skew_limit = match value.parse() {
+                    Ok(0) | Err(_) => return Err("expected a number of cycles of at least 1"),
+                    Ok(cycles) => Some(cycles),
+                };
+                Ok("hv_tsc_skew_limit")
+            }
+            "hv_features" => {
+                self.hv_features = parse_features(value);
+                Ok("hv_features")
+            }
+            _ => Err("unknown key"),
+        }
+    }
+
+    /// Returns how volumes are probed while searching for the hypervisor or the boot manager.
+    ///
+    /// # Arguments
+    ///
+    /// * `hypervisor` - Whether the hypervisor image is searched, which honors `hypervisor_skip_removable`.
+    pub(crate) fn search_options(&self, hypervisor: bool) -> SearchOptions {
+        SearchOptions {
+            probe_timeout_ms: self.probe_timeout_ms,
+            skip_removable: hypervisor && self.hypervisor_skip_removable,
+            attempts: self.load_attempts,
+        }
+    }
+
+    /// Logs every setting that was not provided by the configuration file.
+    fn log_defaults(&self, applied: &[&str]) {
+        for key in KEYS.iter().filter(|key| !applied.contains(key)) {
+            log::info!("Config: {} = {} (default)", key, self.value_of(key));
+        }
+    }
+
+    /// Formats the current value of a setting for logging.
+    fn value_of(&self, key: &str) -> String {
+        match key {
+            "hypervisor_path" => match self.hypervisor_paths.is_empty() {
+                true => String::from("per-vendor image"),
+                false => self.hypervisor_paths.iter().map(|path| format!("{}", path)).collect::<Vec<_>>().join(";"),
+            },
+            "hypervisor_source" => match &self.hypervisor_source {
+                Some(source) => format!("{}", source),
+                None => String::from("local"),
+            },
+            "net_timeout_ms" => format!("{}", self.net_timeout_ms),
+            "chainload" => match self.chainload_source {
+                ChainloadSource::Firmware => String::from("firmware"),
+                ChainloadSource::BootOrder => String::from("boot_order"),
+                ChainloadSource::Paths => self.chainload.iter().map(|path| format!("{}", path)).collect::<Vec<_>>().join(";"),
+            },
+            "chainload_options" => match &self.chainload_options {
+                Some(options) => format!("\"{}\"", options),
+                None => String::from("from boot entry"),
+            },
+            "deep_scan" => format!("{}", self.deep_scan),
+            "probe_timeout_ms" => format!("{}", self.probe_timeout_ms),
+            "hypervisor_skip_removable" => format!("{}", self.hypervisor_skip_removable),
+            "selection_timeout_ms" => format!("{}", self.selection_timeout_ms),
+            "single_candidate_timeout_ms" => format!("{}", self.single_candidate_timeout_ms),
+            "graphical_menu" => format!("{}", self.graphical_menu),
+            "log_keys" => format!("{}", self.log_keys),
+            "handoff_stall_ms" => format!("{}", self.handoff_stall_ms),
+            "acknowledge_bitlocker" => format!("{}", self.acknowledge_bitlocker),
+            "exclude_volumes" => format_volumes(&self.exclude_volumes, "none"),
+            "include_volumes" => format_volumes(&self.include_volumes, "all"),
+            "show_excluded" => format!("{}", self.show_excluded),
+            "default_candidate" => match self.default_candidate {
+                Some(candidate) => format!("{}", candidate),
+                None => String::from("auto"),
+            },
+            "hypervisor_sha256" => match &self.hypervisor_sha256 {
+                Some(digest) => verify::to_hex(digest),
+                None => String::from("none"),
+            },
+            "load_from_buffer" => format!("{}", self.load_from_buffer),
+            "measure" => format!("{}", self.measure),
+            "measure_pcr" => format!("{}", self.measure_pcr),
+            "load_attempts" => format!("{}", self.load_attempts),
+            "rescan_attempts" => format!("{}", self.rescan_attempts),
+            "rescan_interval_ms" => format!("{}", self.rescan_interval_ms),
+            "on_unsupported_cpu" => String::from(self.on_unsupported_cpu.name()),
+            "on_hypervisor_failure" => String::from(self.on_hypervisor_failure.name()),
+            "on_unsigned_secure_boot" => String::from(self.on_unsigned_secure_boot.name()),
+            "on_fatal" => String::from(self.on_fatal.name()),
+            "boot_attempt_limit" => format!("{}", self.boot_attempt_limit),
+            "reset_boot_attempts" => format!("{}", self.reset_boot_attempts),
+            "serial" => logging::serial_port_name(self.serial),
+            "log_level" => format!("{}", self.log_level).to_lowercase(),
+            "log_file" => format!("{}", self.log_file),
+            "log_memory_map" => format!("{}", self.log_memory_map),
+            "log_memory_map_min_mib" => format!("{}", self.log_memory_map_min_mib),
+            "console_mode" => self.console_mode.name(),
+            "scrub_loader_image" => format!("{}", self.scrub_loader_image),
+            "hv_log_level" => match self.hv_log_level {
+                Some(level) => format!("{}", level).to_lowercase(),
+                None => String::from("from image"),
+            },
+            "hv_log_ept_hooks" => match self.hv_log_ept_hooks {
+                Some(enabled) => format!("{}", enabled),
+                None => String::from("from image"),
+            },
+            "hv_full_tlb_flushes" => match self.hv_full_tlb_flushes {
+                Some(enabled) => format!("{}", enabled),
+                None => String::from("from image"),
+            },
+            // The key authenticates hypercalls, so it never ends up in the log.
+            "hv_vmcall_key" => String::from(if self.hv_vmcall_key.is_some() { "set" } else { "random per boot" }),
+            "hv_tsc_skew_limit" => match self.hv_tsc_skew_limit {
+                Some(cycles) => format!("{} cycles", cycles),
+                None => String::from("from image"),
+            },
+            "hv_features" => format_features(self.hv_features),
+            _ => String::new(),
+        }
+    }
+}
+
+/// Parses a boolean setting.
+fn parse_bool(value: &str) -> Result<bool, &'static str> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err("expected true or false"),
+    }
+}
+
+/// Parses a `;` separated list of hypervisor features, applied to the defaults.
+///
+/// A name enables a feature, a name prefixed with `-` disables it and `none` disables the features listed
+/// before. Enabling a feature disables those it excludes, e.g. `hyperv_masquerade` disables `hide_cpuid_leaf`.
+/// Unknown names are reported and skipped, so that a file written for a newer hypervisor still boots.
+fn parse_features(value: &str) -> HvFeatureFlags {
+    let mut features = HvFeatureFlags::DEFAULT;
+    for item in value.split(';').map(str::trim).filter(|item| !item.is_empty()) {
+        let (enable, name) = match item.strip_prefix('-') {
+            Some(name) => (false, name.trim()),
+            None => (true, item),
+        };
+
+        match HvFeatureFlags::from_name(name) {
+            Some(flag) if enable => {
+                let excluded = HvFeatureFlags::from_bits(features.bits() & flag.exclusive_with().bits());
+                if excluded.bits() != 0 {
+                    log::info!("illusion.cfg: hypervisor feature {} disables {}", name, format_features(excluded));
+                }
+                features.remove(excluded);
+                features.insert(flag);
+            }
+            Some(flag) => features.remove(flag),
+            None if enable && name == "none" => features = HvFeatureFlags::empty(),
+            None => log::warn!("illusion.cfg: unknown hypervisor feature {}, ignoring it", name),
+        }
+    }
+    features
+}
+
+/// Formats hypervisor features for logging, `none` standing for the empty set.
+pub(crate) fn format_features(features: HvFeatureFlags) -> String {
+    match features.bits() {
+        0 => String::from("none"),
+        _ => features.names().collect::<Vec<_>>().join(";"),
+    }
+}
+
+/// Parses a `;` separated list of volumes, an empty list clears it.
+fn parse_volumes(value: &str) -> Vec<VolumePattern> {
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|volume| !volume.is_empty())
+        .map(VolumePattern::parse)
+        .collect()
+}
+
+/// Formats a list of volumes for logging, `empty` standing for an empty list.
+fn format_volumes(volumes: &[VolumePattern], empty: &str) -> String {
+    match volumes.is_empty() {
+        true => String::from(empty),
+        false => volumes.iter().map(|volume| format!("{}", volume)).collect::<Vec<_>>().join(";"),
+    }
+}
+
+/// Converts a path value into a UCS-2 string usable with the UEFI file protocols.
+fn parse_path(value: &str) -> Result<CString16, &'static str> {
+    let value = normalize_path(value);
+    if !value.starts_with('\\') {
+        return Err("path must be absolute and start with a backslash");
+    }
+
+    CString16::try_from(value.as_str()).map_err(|_| "path contains characters that cannot be encoded as UCS-2")
+}
+
+/// Converts forward slashes to backslashes and collapses repeated separators.
+pub(crate) fn normalize_path(value: &str) -> String {
+    let mut normalized = String::with_capacity(value.len());
+    for c in value.chars().map(|c| if c == '/' { '\\' } else { c }) {
+        if !(c == '\\' && normalized.ends_with('\\')) {
+            normalized.push(c);
+        }
+    }
+    normalized
+}
+
+/// Reads the configuration file from the volume the loader image was started from.
+fn read_config_file(boot_services: &BootServices) -> uefi::Result<Vec<u8>> {
+    let mut file_system = boot_services.get_image_file_system(boot_services.image_handle())?;
+    let mut root = file_system.open_volume()?;
+
+    let mut file = root
+        .open(CONFIG_PATH, FileMode::Read, FileAttribute::READ_ONLY)?
+        .into_regular_file()
+        .ok_or(uefi::Error::from(Status::INVALID_PARAMETER))?;
+
+    let size = file.get_boxed_info::<FileInfo>()?.file_size();
+    if size > MAX_CONFIG_SIZE {
+        return Err(Status::BAD_BUFFER_SIZE.into());
+    }
+
+    let mut bytes = alloc::vec![0u8; size as usize];
+    let read = file.read(&mut bytes)?;
+    bytes.truncate(read);
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{normalize_path, parse_path, LoaderConfig, DEFAULT_HANDOFF_STALL_MS, DEFAULT_SELECTION_TIMEOUT_MS, KEYS},
+        crate::images::DEFAULT_CHAINLOAD_PATHS,
+        log::LevelFilter,
+        shared::features::HvFeatureFlags,
+        uefi::Status,
+    };
+
+    #[test]
+    fn forward_slashes_become_backslashes() {
+        assert_eq!(normalize_path("/EFI/Microsoft/Boot/bootmgfw.efi"), r"\EFI\Microsoft\Boot\bootmgfw.efi");
+        assert_eq!(normalize_path(r"\EFI/Boot\illusion.efi"), r"\EFI\Boot\illusion.efi");
+    }
+
+    #[test]
+    fn repeated_separators_collapse() {
+        assert_eq!(normalize_path(r"\\EFI//Boot\/bootx64.efi"), r"\EFI\Boot\bootx64.efi");
+    }
+
+    #[test]
+    fn relative_paths_are_rejected() {
+        assert!(parse_path("EFI/Boot/illusion.efi").is_err());
+        assert!(parse_path("/EFI/Boot/illusion.efi").is_ok());
+    }
+
+    #[test]
+    fn hypervisor_paths_are_a_list() {
+        let config = LoaderConfig::parse(b"hypervisor_path = \\EFI\\Boot\\illusion.efi; /EFI/Boot/illusion-debug.efi\n");
+        let paths: Vec<String> = config.hypervisor_paths.iter().map(|path| path.to_string()).collect();
+        assert_eq!(paths, [r"\EFI\Boot\illusion.efi", r"\EFI\Boot\illusion-debug.efi"]);
+        assert!(LoaderConfig::default().hypervisor_paths.is_empty());
+    }
+
+    #[test]
+    fn boot_manager_paths_are_read_with_the_hypervisor_paths() {
+        let config = LoaderConfig::parse(
+            b"chainload = /EFI/Microsoft/Boot/bootmgfw.efi; \\EFI\\Boot\\bootx64.efi\nhypervisor_path = /EFI/Boot/illusion.efi\n",
+        );
+        let chainload: Vec<String> = config.chainload.iter().map(|path| path.to_string()).collect();
+        assert_eq!(chainload, [r"\EFI\Microsoft\Boot\bootmgfw.efi", r"\EFI\Boot\bootx64.efi"]);
+        assert_eq!(config.value_of("hypervisor_path"), r"\EFI\Boot\illusion.efi");
+
+        let defaults: Vec<String> = LoaderConfig::default().chainload.iter().map(|path| path.to_string()).collect();
+        assert_eq!(defaults, DEFAULT_CHAINLOAD_PATHS.iter().map(|path| path.to_string()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn menu_timings_and_default_candidate_are_read() {
+        let config = LoaderConfig::parse(b"selection_timeout_ms = 0\nhandoff_stall_ms = 1500\ndefault_candidate = 2\n");
+        assert_eq!((config.selection_timeout_ms, config.handoff_stall_ms, config.default_candidate), (0, 1500, Some(2)));
+        assert_eq!(config.value_of("default_candidate"), "2");
+
+        // Candidates are numbered from 1 like the menu, 0 keeps the automatic choice.
+        let config = LoaderConfig::parse(b"default_candidate = 0\n");
+        assert_eq!(config.default_candidate, None);
+        assert_eq!(config.value_of("default_candidate"), "auto");
+        assert!(LoaderConfig::default().apply("default_candidate", "0").is_err());
+    }
+
+    #[test]
+    fn malformed_values_keep_the_defaults() {
+        let config =
+            LoaderConfig::parse(b"selection_timeout_ms = soon\nhandoff_stall_ms = -1\ndefault_candidate = first\nlog_file\ndeep_scan = true\n");
+        assert_eq!(config.selection_timeout_ms, DEFAULT_SELECTION_TIMEOUT_MS);
+        assert_eq!(config.handoff_stall_ms, DEFAULT_HANDOFF_STALL_MS);
+        assert_eq!(config.default_candidate, None);
+
+        // A rejected line doesn't stop the ones after it.
+        assert!(config.deep_scan);
+    }
+
+    #[test]
+    fn a_missing_file_keeps_every_default() {
+        let config = LoaderConfig::from_file(Err(Status::NOT_FOUND.into()));
+        let defaults = LoaderConfig::default();
+        for key in KEYS {
+            assert_eq!(config.value_of(key), defaults.value_of(key), "{}", key);
+        }
+        assert_eq!(config.hv_features, HvFeatureFlags::DEFAULT);
+    }
+
+    #[test]
+    fn excluded_volumes_are_a_list() {
+        let config = LoaderConfig::parse(b"exclude_volumes = Recovery; WINRE;\n");
+        assert_eq!(config.value_of("exclude_volumes"), "Recovery;WINRE");
+        assert_eq!(config.value_of("include_volumes"), "all");
+    }
+
+    #[test]
+    fn hypervisor_settings_default_to_the_image() {
+        let config = LoaderConfig::parse(b"hv_log_level = off\nhv_vmcall_key = 0x1234abcd\n");
+        assert_eq!((config.hv_log_level, config.hv_vmcall_key), (Some(LevelFilter::Off), Some(0x1234abcd)));
+        assert_eq!(config.value_of("hv_vmcall_key"), "set");
+        assert_eq!(config.value_of("hv_log_ept_hooks"), "from image");
+        assert_eq!(LoaderConfig::parse(b"hv_vmcall_key = 1234\n").hv_vmcall_key, None);
+
+        assert_eq!(config.value_of("hv_tsc_skew_limit"), "from image");
+        assert_eq!(LoaderConfig::parse(b"hv_tsc_skew_limit = 2000000\n").value_of("hv_tsc_skew_limit"), "2000000 cycles");
+        assert_eq!(LoaderConfig::parse(b"hv_tsc_skew_limit = 0\n").hv_tsc_skew_limit, None);
+    }
+
+    #[test]
+    fn hypervisor_features_apply_to_the_defaults() {
+        assert_eq!(LoaderConfig::default().hv_features, HvFeatureFlags::DEFAULT);
+        assert_eq!(LoaderConfig::default().value_of("hv_features"), "log_vmexits;hide_cpuid_leaf;ept_hooks");
+
+        let config = LoaderConfig::parse(b"hv_features = -log_vmexits; rdtsc_compensation\n");
+        assert_eq!(config.value_of("hv_features"), "hide_cpuid_leaf;rdtsc_compensation;ept_hooks");
+
+        let config = LoaderConfig::parse(b"hv_features = none; ept_hooks\n");
+        assert_eq!(config.hv_features, HvFeatureFlags::EPT_HOOKS);
+        assert_eq!(LoaderConfig::parse(b"hv_features = none\n").value_of("hv_features"), "none");
+
+        // Unknown names only skip themselves, not the line.
+        let config = LoaderConfig::parse(b"hv_features = stealth; -hide_cpuid_leaf\n");
+        assert!(!config.hv_features.contains(HvFeatureFlags::HIDE_CPUID_LEAF));
+        assert!(config.hv_features.contains(HvFeatureFlags::LOG_VMEXITS | HvFeatureFlags::EPT_HOOKS));
+
+        // Masquerading as Hyper-V and hiding the hypervisor leaves exclude each other, the later one wins.
+        let config = LoaderConfig::parse(b"hv_features = hyperv_masquerade\n");
+        assert_eq!(config.value_of("hv_features"), "log_vmexits;ept_hooks;hyperv_masquerade");
+        let config = LoaderConfig::parse(b"hv_features = hyperv_masquerade; hide_cpuid_leaf\n");
+        assert_eq!(config.value_of("hv_features"), "log_vmexits;hide_cpuid_leaf;ept_hooks");
+    }
+}
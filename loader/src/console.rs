@@ -0,0 +1,106 @@
+//! Selection of the console text mode.
+//!
+//! Firmware starts the console in 80x25 even on high resolution screens, where the menu and long device
+//! paths then wrap. The loader switches to the largest text mode by default, or to the one pinned in the
+//! configuration for firmware whose larger modes don't work.
+
+extern crate alloc;
+
+use {
+    alloc::{format, string::String, vec::Vec},
+    uefi::proto::console::text::{Output, OutputMode},
+};
+
+/// Which text mode the console is switched to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ConsoleMode {
+    /// The mode with the most cells.
+    Auto,
+
+    /// The mode the firmware set up.
+    Keep,
+
+    /// The mode with the given number of columns and rows.
+    Size(usize, usize),
+}
+
+impl ConsoleMode {
+    /// Parses a `console_mode` setting: `auto`, `keep` or `<columns>x<rows>` like `128x40`.
+    pub(crate) fn parse(value: &str) -> Result<Self, &'static str> {
+        match value {
+            "auto" => Ok(ConsoleMode::Auto),
+            "keep" => Ok(ConsoleMode::Keep),
+            _ => {
+                let (columns, rows) = value.split_once('x').ok_or("expected auto, keep or a size like 128x40")?;
+                match (columns.parse(), rows.parse()) {
+                    (Ok(columns), Ok(rows)) if columns != 0 && rows != 0 => Ok(ConsoleMode::Size(columns, rows)),
+                    _ => Err("expected auto, keep or a size like 128x40"),
+                }
+            }
+        }
+    }
+
+    /// Returns the setting value of the mode.
+    pub(crate) fn name(self) -> String {
+        match self {
+            ConsoleMode::Auto => String::from("auto"),
+            ConsoleMode::Keep => String::from("keep"),
+            ConsoleMode::Size(columns, rows) => format!("{}x{}", columns, rows),
+        }
+    }
+}
+
+/// Switches the console to the configured text mode and clears it.
+///
+/// Failures are logged and leave the console in its current mode.
+///
+/// # Arguments
+///
+/// * `stdout` - The console output.
+/// * `mode` - The configured mode.
+pub(crate) fn apply(stdout: &mut Output, mode: ConsoleMode) {
+    let current = stdout.current_mode().ok().flatten();
+    let modes: Vec<OutputMode> = stdout.modes().collect();
+
+    let target = match mode {
+        ConsoleMode::Keep => return,
+        ConsoleMode::Auto => modes.iter().copied().max_by_key(|mode| mode.columns() * mode.rows()),
+        ConsoleMode::Size(columns, rows) => {
+            let found = modes.iter().copied().find(|mode| mode.columns() == columns && mode.rows() == rows);
+            if found.is_none() {
+                log::warn!("Console does not support the configured mode {}x{}, keeping the current mode", columns, rows);
+            }
+            found
+        }
+    };
+
+    let Some(target) = target.filter(|target| Some(*target) != current) else {
+        return;
+    };
+
+    match stdout.set_mode(target) {
+        Ok(()) => {
+            let _ = stdout.clear();
+            log::info!("Switched console to text mode {} ({}x{})", target.index(), target.columns(), target.rows());
+        }
+        Err(error) => {
+            log::warn!("Failed to switch console to {}x{} ({:?}), keeping the current mode", target.columns(), target.rows(), error.status())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConsoleMode;
+
+    #[test]
+    fn parses_console_modes() {
+        assert_eq!(ConsoleMode::parse("auto"), Ok(ConsoleMode::Auto));
+        assert_eq!(ConsoleMode::parse("keep"), Ok(ConsoleMode::Keep));
+        assert_eq!(ConsoleMode::parse("128x40"), Ok(ConsoleMode::Size(128, 40)));
+        assert!(ConsoleMode::parse("128x").is_err());
+        assert!(ConsoleMode::parse("0x25").is_err());
+        assert!(ConsoleMode::parse("large").is_err());
+        assert_eq!(ConsoleMode::Size(100, 31).name(), "100x31");
+    }
+}
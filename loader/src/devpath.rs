@@ -0,0 +1,234 @@
+//! Text rendering of device paths for logging.
+//!
+//! The firmware's `DevicePathToText` protocol is used when available. Some firmware doesn't install it,
+//! so a minimal formatter covering the nodes found in boot device paths is used as a fallback.
+
+extern crate alloc;
+
+use {
+    alloc::{
+        format,
+        string::{String, ToString},
+        vec::Vec,
+    },
+    uefi::{
+        prelude::*,
+        proto::device_path::{
+            text::{AllowShortcuts, DisplayOnly},
+            DevicePath,
+        },
+        table::boot::{OpenProtocolAttributes, OpenProtocolParams},
+        Guid,
+    },
+};
+
+/// Device path node types and subtypes decoded by the fallback formatter.
+const HARDWARE_DEVICE_PATH: u8 = 0x01;
+const HARDWARE_PCI: u8 = 0x01;
+const MESSAGING_DEVICE_PATH: u8 = 0x03;
+const MESSAGING_USB: u8 = 0x05;
+const MEDIA_DEVICE_PATH: u8 = 0x04;
+const MEDIA_HARD_DRIVE: u8 = 0x01;
+const MEDIA_CDROM: u8 = 0x02;
+const MEDIA_FILE_PATH: u8 = 0x04;
+const END_DEVICE_PATH: u8 = 0x7f;
+const END_INSTANCE: u8 = 0x01;
+
+/// Renders a device path as text.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+/// * `device_path` - The device path to render.
+///
+/// # Returns
+///
+/// The text produced by the `DevicePathToText` protocol, or by `format_nodes` if the protocol is missing.
+pub(crate) fn to_text(boot_services: &BootServices, device_path: &DevicePath) -> String {
+    match device_path.to_string(boot_services, DisplayOnly(false), AllowShortcuts(false)) {
+        Ok(text) => text.to_string(),
+        Err(_) => format_nodes(device_path.as_bytes()),
+    }
+}
+
+/// Renders the device path of a handle as text, queried without opening the protocol exclusively.
+///
+/// # Returns
+///
+/// The text of `to_text`, or `(no device path)` if the handle has none.
+pub(crate) fn handle_to_text(boot_services: &BootServices, handle: Handle) -> String {
+    let params = OpenProtocolParams {
+        handle,
+        agent: boot_services.image_handle(),
+        controller: None,
+    };
+
+    // SAFETY: The protocol is only read while the handle is still installed.
+    match unsafe { boot_services.open_protocol::<DevicePath>(params, OpenProtocolAttributes::GetProtocol) } {
+        Ok(device_path) => to_text(boot_services, &device_path),
+        Err(_) => String::from("(no device path)"),
+    }
+}
+
+/// Formats the raw bytes of a device path node by node.
+///
+/// PCI, USB, hard drive, CD-ROM and file path nodes are decoded, any other node is printed as
+/// `type/subtype/length`. Formatting stops at the first malformed node.
+///
+/// # Arguments
+///
+/// * `bytes` - The raw device path, as a sequence of nodes with a 4 byte header each.
+///
+/// # Returns
+///
+/// The nodes separated by `/`, with multiple instances separated by `,`.
+pub(crate) fn format_nodes(bytes: &[u8]) -> String {
+    let mut text = String::new();
+    let mut rest = bytes;
+    let mut separator = "";
+
+    while rest.len() >= 4 {
+        let (node_type, subtype) = (rest[0], rest[1]);
+        let length = u16::from_le_bytes([rest[2], rest[3]]) as usize;
+        if length < 4 || length > rest.len() {
+            text.push_str(separator);
+            text.push_str("<malformed>");
+            break;
+        }
+
+        let (node, next) = rest.split_at(length);
+        rest = next;
+
+        match node_type {
+            END_DEVICE_PATH if subtype == END_INSTANCE => {
+                text.push(',');
+                separator = "";
+            }
+            END_DEVICE_PATH => break,
+            _ => {
+                text.push_str(separator);
+                text.push_str(&format_node(node_type, subtype, node));
+                separator = "/";
+            }
+        }
+    }
+
+    text
+}
+
+/// Formats a single node, including its 4 byte header.
+fn format_node(node_type: u8, subtype: u8, node: &[u8]) -> String {
+    let data = &node[4..];
+
+    let decoded = match (node_type, subtype) {
+        (HARDWARE_DEVICE_PATH, HARDWARE_PCI) if data.len() >= 2 => Some(format!("Pci({:#x},{:#x})", data[1], data[0])),
+        (MESSAGING_DEVICE_PATH, MESSAGING_USB) if data.len() >= 2 => Some(format!("USB({:#x},{:#x})", data[0], data[1])),
+        (MEDIA_DEVICE_PATH, MEDIA_HARD_DRIVE) if data.len() >= 38 => {
+            let partition_number = read_u32(data, 0);
+            let start = read_u64(data, 4);
+            let size = read_u64(data, 12);
+            let signature = &data[20..36];
+            match data[37] {
+                1 => Some(format!("HD({},MBR,{:#010x},{:#x},{:#x})", partition_number, read_u32(signature, 0), start, size)),
+                2 => Some(format!("HD({},GPT,{},{:#x},{:#x})", partition_number, Guid::from_bytes(signature.try_into().unwrap()), start, size)),
+                _ => Some(format!("HD({},{:#x},{:#x})", partition_number, start, size)),
+            }
+        }
+        (MEDIA_DEVICE_PATH, MEDIA_CDROM) if data.len() >= 20 => {
+            Some(format!("CDROM({:#x},{:#x},{:#x})", read_u32(data, 0), read_u64(data, 4), read_u64(data, 12)))
+        }
+        (MEDIA_DEVICE_PATH, MEDIA_FILE_PATH) => {
+            let units: Vec<u16> = data
+                .chunks_exact(2)
+                .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                .take_while(|&unit| unit != 0)
+                .collect();
+            Some(char::decode_utf16(units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect())
+        }
+        _ => None,
+    };
+
+    decoded.unwrap_or_else(|| format!("{}/{}/{}", node_type, subtype, node.len()))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::format_nodes, alloc::vec::Vec};
+
+    /// ACPI PciRoot(0x0) node, not decoded by the fallback formatter.
+    const PCI_ROOT: [u8; 12] = [0x02, 0x01, 0x0c, 0x00, 0xd0, 0x41, 0x03, 0x0a, 0x00, 0x00, 0x00, 0x00];
+
+    /// Pci(0x1f,0x2) node.
+    const PCI: [u8; 6] = [0x01, 0x01, 0x06, 0x00, 0x02, 0x1f];
+
+    /// USB(0x3,0x0) node.
+    const USB: [u8; 6] = [0x03, 0x05, 0x06, 0x00, 0x03, 0x00];
+
+    /// End of entire device path node.
+    const END: [u8; 4] = [0x7f, 0xff, 0x04, 0x00];
+
+    fn hard_drive(signature: [u8; 16], signature_type: u8) -> Vec<u8> {
+        let mut node = alloc::vec![0x04, 0x01, 42, 0];
+        node.extend_from_slice(&1u32.to_le_bytes());
+        node.extend_from_slice(&0x800u64.to_le_bytes());
+        node.extend_from_slice(&0x82000u64.to_le_bytes());
+        node.extend_from_slice(&signature);
+        node.push(0x02);
+        node.push(signature_type);
+        node
+    }
+
+    fn file_path(path: &str) -> Vec<u8> {
+        let units: Vec<u8> = path.encode_utf16().chain([0]).flat_map(u16::to_le_bytes).collect();
+        let mut node = alloc::vec![0x04, 0x04];
+        node.extend_from_slice(&((units.len() + 4) as u16).to_le_bytes());
+        node.extend_from_slice(&units);
+        node
+    }
+
+    #[test]
+    fn decodes_gpt_partition_and_file() {
+        let guid = [
+            0x2c, 0xf6, 0xf2, 0x8c, 0x9b, 0xbc, 0x21, 0x48, 0x80, 0x8d, 0xec, 0x9e, 0xc4, 0x21, 0xa1, 0xa0,
+        ];
+        let path = [&PCI_ROOT[..], &PCI, &hard_drive(guid, 2), &file_path(r"\EFI\Boot\bootx64.efi"), &END].concat();
+        assert_eq!(format_nodes(&path), r"2/1/12/Pci(0x1f,0x2)/HD(1,GPT,8cf2f62c-bc9b-4821-808d-ec9ec421a1a0,0x800,0x82000)/\EFI\Boot\bootx64.efi");
+    }
+
+    #[test]
+    fn decodes_mbr_partition() {
+        let mut signature = [0u8; 16];
+        signature[..4].copy_from_slice(&0xdead_beefu32.to_le_bytes());
+        let path = [&USB[..], &hard_drive(signature, 1), &END].concat();
+        assert_eq!(format_nodes(&path), "USB(0x3,0x0)/HD(1,MBR,0xdeadbeef,0x800,0x82000)");
+    }
+
+    #[test]
+    fn decodes_cdrom() {
+        let mut cdrom = alloc::vec![0x04, 0x02, 24, 0];
+        cdrom.extend_from_slice(&0u32.to_le_bytes());
+        cdrom.extend_from_slice(&0x10u64.to_le_bytes());
+        cdrom.extend_from_slice(&0x2000u64.to_le_bytes());
+        assert_eq!(format_nodes(&[&cdrom[..], &END].concat()), "CDROM(0x0,0x10,0x2000)");
+    }
+
+    #[test]
+    fn separates_instances() {
+        let path = [&PCI[..], &[0x7f, 0x01, 0x04, 0x00], &USB, &END].concat();
+        assert_eq!(format_nodes(&path), "Pci(0x1f,0x2),USB(0x3,0x0)");
+    }
+
+    #[test]
+    fn reports_malformed_nodes() {
+        let path = [&PCI[..], &[0x01, 0x01, 0x02, 0x00]].concat();
+        assert_eq!(format_nodes(&path), "Pci(0x1f,0x2)/<malformed>");
+    }
+}
@@ -0,0 +1,155 @@
+extern crate alloc;
+
+use {
+    crate::{
+        compress::CompressError,
+        images::{ImageError, ReadError},
+        pe::PeError,
+        retry::RetryError,
+    },
+    alloc::{format, string::String},
+    shared::handoff::{
+        ABORT_REASON_FEATURE_CONTROL_LOCKED, ABORT_REASON_NO_EPT, ABORT_REASON_NO_MTRR, ABORT_REASON_NO_VMX, ABORT_REASON_UNSPECIFIED,
+        ABORT_REASON_UNSUPPORTED_CPU,
+    },
+    thiserror_no_std::Error,
+    uefi::Status,
+};
+
+/// Errors that end the loader, one per failing stage.
+#[derive(Error, Debug)]
+pub(crate) enum LoaderError {
+    #[error("[2/8] Processor does not support the hypervisor")]
+    UnsupportedPlatform,
+
+    #[error("[2/8] Failed to search for the hypervisor image: {0}")]
+    HypervisorSearchFailed(ImageError),
+
+    #[error("[2/8] Hypervisor image not present on any of {0} volume(s)")]
+    HypervisorNotFound(usize),
+
+    #[error("[3/8] Hypervisor selection aborted by user")]
+    HypervisorSelectionAborted,
+
+    #[error("[3/8] Failed to read hypervisor image: {0}")]
+    HypervisorReadFailed(ReadError),
+
+    #[error("[3/8] Failed to decompress hypervisor image: {0}")]
+    HypervisorDecompressFailed(CompressError),
+
+    #[error("[3/8] Hypervisor image is invalid: {0}")]
+    InvalidHypervisorImage(PeError),
+
+    #[error("[3/8] Hypervisor image failed the integrity check")]
+    HypervisorIntegrityCheckFailed,
+
+    #[error("[3/8] Secure Boot is enabled and the hypervisor image is unsigned, sign it with a key enrolled in db or disable Secure Boot")]
+    HypervisorUnsigned,
+
+    #[error("[3/8] Failed to measure hypervisor image into the TPM ({0:?})")]
+    HypervisorMeasurementFailed(Status),
+
+    #[error("[4/8] Failed to load hypervisor after {} attempt(s) ({})", .0.attempts, describe_load_failure(.0.status))]
+    HypervisorLoadFailed(RetryError),
+
+    #[error("[5/8] Failed to start hypervisor ({0:?})")]
+    HypervisorStartFailed(Status),
+
+    #[error("[5/8] Hypervisor returned control to the loader but does not respond to the presence probe")]
+    HypervisorNotResident,
+
+    #[error("[5/8] Hypervisor is not running on {0} of {1} enabled processor(s)")]
+    HypervisorNotOnAllProcessors(usize, usize),
+
+    #[error("[5/8] Hypervisor did not start and asked to boot Windows without it")]
+    HypervisorDeclined,
+
+    #[error("[5/8] Hypervisor asked to stop the boot: {}", describe_abort_reason(*.0))]
+    HypervisorAbortedBoot(u32),
+
+    #[error("[6/8] Failed to search for the Windows boot manager: {0}")]
+    BootManagerSearchFailed(ImageError),
+
+    #[error("[6/8] Windows boot manager not present on any of {0} volume(s)")]
+    BootManagerNotFound(usize),
+
+    #[error("[7/8] Selection aborted by user")]
+    SelectionAborted,
+
+    #[error("[7/8] Not starting Windows, BitLocker would likely ask for the recovery key")]
+    BitLockerNotAcknowledged,
+
+    #[error("[8/8] Failed to load boot manager after {} attempt(s) ({})", .0.attempts, describe_load_failure(.0.status))]
+    BootManagerLoadFailed(RetryError),
+
+    #[error("[8/8] Failed to start boot manager ({0:?})")]
+    BootManagerStartFailed(Status),
+}
+
+impl LoaderError {
+    /// Returns whether the error means a search found nothing, possibly because the volume did not show up yet.
+    pub(crate) fn is_not_found(&self) -> bool {
+        matches!(
+            self,
+            LoaderError::HypervisorNotFound(_)
+                | LoaderError::BootManagerNotFound(_)
+                | LoaderError::HypervisorSearchFailed(ImageError::NoFileSystems)
+                | LoaderError::BootManagerSearchFailed(ImageError::NoFileSystems)
+        )
+    }
+
+    /// Returns whether the hypervisor image failed once the firmware took it, which the backup image is tried for.
+    pub(crate) fn is_start_failure(&self) -> bool {
+        matches!(self, LoaderError::HypervisorLoadFailed(_) | LoaderError::HypervisorStartFailed(_) | LoaderError::HypervisorNotResident)
+    }
+
+    /// Returns the status the loader exits with, distinct for every variant so that firmware boot
+    /// managers and test harnesses can tell the failing stage apart.
+    pub(crate) fn status(&self) -> Status {
+        match self {
+            LoaderError::UnsupportedPlatform => Status::UNSUPPORTED,
+            LoaderError::HypervisorSearchFailed(_) => Status::NOT_READY,
+            LoaderError::HypervisorNotFound(_) => Status::NOT_FOUND,
+            LoaderError::HypervisorSelectionAborted => Status::MEDIA_CHANGED,
+            LoaderError::HypervisorReadFailed(_) => Status::DEVICE_ERROR,
+            LoaderError::HypervisorDecompressFailed(_) => Status::CRC_ERROR,
+            LoaderError::InvalidHypervisorImage(_) => Status::VOLUME_CORRUPTED,
+            LoaderError::HypervisorIntegrityCheckFailed => Status::SECURITY_VIOLATION,
+            LoaderError::HypervisorUnsigned => Status::INCOMPATIBLE_VERSION,
+            LoaderError::HypervisorMeasurementFailed(_) => Status::COMPROMISED_DATA,
+            LoaderError::HypervisorLoadFailed(_) => Status::LOAD_ERROR,
+            LoaderError::HypervisorStartFailed(_) => Status::NOT_STARTED,
+            LoaderError::HypervisorNotResident => Status::TIMEOUT,
+            LoaderError::HypervisorNotOnAllProcessors(..) => Status::OUT_OF_RESOURCES,
+            LoaderError::HypervisorDeclined => Status::WRITE_PROTECTED,
+            LoaderError::HypervisorAbortedBoot(_) => Status::VOLUME_FULL,
+            LoaderError::BootManagerSearchFailed(_) => Status::NO_MAPPING,
+            LoaderError::BootManagerNotFound(_) => Status::NO_MEDIA,
+            LoaderError::SelectionAborted => Status::ABORTED,
+            LoaderError::BitLockerNotAcknowledged => Status::NO_RESPONSE,
+            LoaderError::BootManagerLoadFailed(_) => Status::ACCESS_DENIED,
+            LoaderError::BootManagerStartFailed(_) => Status::PROTOCOL_ERROR,
+        }
+    }
+}
+
+/// Explains a `load_image` failure, `SECURITY_VIOLATION` gets a hint at Secure Boot instead of the raw status.
+fn describe_load_failure(status: Status) -> String {
+    match status {
+        Status::SECURITY_VIOLATION => String::from("rejected by Secure Boot, the image is not signed by a key enrolled in db"),
+        status => format!("{:?}", status),
+    }
+}
+
+/// Explains the `shared::handoff::ABORT_REASON_*` code the hypervisor stopped the boot for.
+fn describe_abort_reason(reason: u32) -> String {
+    match reason {
+        ABORT_REASON_UNSPECIFIED => String::from("no reason given"),
+        ABORT_REASON_UNSUPPORTED_CPU => String::from("the processor is not an Intel processor"),
+        ABORT_REASON_NO_VMX => String::from("the processor does not support VT-x"),
+        ABORT_REASON_NO_EPT => String::from("the processor does not support the required EPT and VPID features"),
+        ABORT_REASON_NO_MTRR => String::from("the processor does not support MTRRs"),
+        ABORT_REASON_FEATURE_CONTROL_LOCKED => String::from("the firmware locked VT-x off, enable it in the firmware setup"),
+        reason => format!("unknown reason {}", reason),
+    }
+}
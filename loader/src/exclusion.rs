@@ -0,0 +1,145 @@
+//! Exclusion of boot manager candidates by the volume they are on.
+//!
+//! `\EFI\Microsoft\Boot\bootmgfw.efi` also exists on Windows recovery partitions, and booting one of those
+//! starts WinRE instead of Windows. The candidates found by the search are matched against the configured
+//! `exclude_volumes` and `include_volumes` lists before the menu is shown, by partition GUID or by volume
+//! label.
+
+extern crate alloc;
+
+use {
+    crate::images::BootTarget,
+    alloc::{string::String, vec::Vec},
+    core::fmt,
+    uefi::Guid,
+};
+
+/// An entry of the `exclude_volumes` or `include_volumes` list.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum VolumePattern {
+    /// Matches the unique or the type GUID of a GPT partition.
+    Guid(Guid),
+
+    /// Matches the volume label, ignoring case.
+    Label(String),
+}
+
+impl VolumePattern {
+    /// Parses a list entry, which is a GUID with or without braces, or a volume label otherwise.
+    pub(crate) fn parse(text: &str) -> Self {
+        let unbraced = text.strip_prefix('{').and_then(|text| text.strip_suffix('}')).unwrap_or(text);
+
+        match Guid::try_parse(unbraced) {
+            Ok(guid) => Self::Guid(guid),
+            Err(_) => Self::Label(String::from(text)),
+        }
+    }
+
+    /// Returns whether the pattern matches `volume`.
+    pub(crate) fn matches(&self, volume: &Volume) -> bool {
+        match self {
+            Self::Guid(guid) => volume.partition_guid == Some(*guid) || volume.partition_type == Some(*guid),
+            Self::Label(label) => volume.label.is_some_and(|volume_label| volume_label.eq_ignore_ascii_case(label)),
+        }
+    }
+}
+
+impl fmt::Display for VolumePattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Guid(guid) => write!(f, "{}", guid),
+            Self::Label(label) => write!(f, "{}", label),
+        }
+    }
+}
+
+/// The properties of a candidate's volume the lists are matched against.
+pub(crate) struct Volume<'a> {
+    pub label: Option<&'a str>,
+    pub partition_guid: Option<Guid>,
+    pub partition_type: Option<Guid>,
+}
+
+impl<'a> From<&'a BootTarget> for Volume<'a> {
+    fn from(target: &'a BootTarget) -> Self {
+        Self {
+            label: target.volume_label.as_deref(),
+            partition_guid: target.partition_guid,
+            partition_type: target.partition_type,
+        }
+    }
+}
+
+/// Returns whether a volume is excluded.
+///
+/// # Arguments
+///
+/// * `volume` - The volume of the candidate.
+/// * `exclude` - Volumes that are never booted by default.
+/// * `include` - If not empty, the only volumes that are booted by default.
+pub(crate) fn is_excluded(volume: &Volume, exclude: &[VolumePattern], include: &[VolumePattern]) -> bool {
+    let included = include.is_empty() || include.iter().any(|pattern| pattern.matches(volume));
+    !included || exclude.iter().any(|pattern| pattern.matches(volume))
+}
+
+/// Separates the excluded candidates from the others, keeping the order of both.
+///
+/// # Returns
+///
+/// The candidates that remain, followed by the excluded ones.
+pub(crate) fn split_excluded(targets: Vec<BootTarget>, exclude: &[VolumePattern], include: &[VolumePattern]) -> (Vec<BootTarget>, Vec<BootTarget>) {
+    targets
+        .into_iter()
+        .partition(|target| !is_excluded(&Volume::from(target), exclude, include))
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, alloc::string::ToString};
+
+    const RECOVERY_GUID: Guid = uefi::guid!("5c6e1f3a-2b1d-4d8e-9a7f-0e1d2c3b4a59");
+    const WINRE_TYPE: Guid = uefi::guid!("de94bba4-06d1-4d40-a16a-bfd50179d6ac");
+
+    fn volume(label: Option<&str>, partition_guid: Option<Guid>, partition_type: Option<Guid>) -> Volume {
+        Volume {
+            label,
+            partition_guid,
+            partition_type,
+        }
+    }
+
+    fn patterns(list: &[&str]) -> Vec<VolumePattern> {
+        list.iter().map(|text| VolumePattern::parse(text)).collect()
+    }
+
+    #[test]
+    fn patterns_are_guids_or_labels() {
+        assert_eq!(VolumePattern::parse("5C6E1F3A-2B1D-4D8E-9A7F-0E1D2C3B4A59"), VolumePattern::Guid(RECOVERY_GUID));
+        assert_eq!(VolumePattern::parse("{5c6e1f3a-2b1d-4d8e-9a7f-0e1d2c3b4a59}"), VolumePattern::Guid(RECOVERY_GUID));
+        assert_eq!(VolumePattern::parse("WINRE"), VolumePattern::Label("WINRE".to_string()));
+        assert_eq!(VolumePattern::parse("{Recovery}"), VolumePattern::Label("{Recovery}".to_string()));
+        assert_eq!(VolumePattern::Guid(RECOVERY_GUID).to_string(), "5c6e1f3a-2b1d-4d8e-9a7f-0e1d2c3b4a59");
+    }
+
+    #[test]
+    fn excluded_by_label_or_guid() {
+        let exclude = patterns(&["Recovery", "WINRE", "de94bba4-06d1-4d40-a16a-bfd50179d6ac"]);
+
+        assert!(is_excluded(&volume(Some("recovery"), None, None), &exclude, &[]));
+        assert!(is_excluded(&volume(Some("Windows"), None, Some(WINRE_TYPE)), &exclude, &[]));
+        assert!(!is_excluded(&volume(Some("SYSTEM"), Some(RECOVERY_GUID), None), &exclude, &[]));
+        assert!(!is_excluded(&volume(None, None, None), &exclude, &[]));
+        assert!(is_excluded(&volume(None, Some(RECOVERY_GUID), None), &patterns(&["5c6e1f3a-2b1d-4d8e-9a7f-0e1d2c3b4a59"]), &[]));
+    }
+
+    #[test]
+    fn include_list_keeps_only_matching_volumes() {
+        let include = patterns(&["SYSTEM"]);
+
+        assert!(!is_excluded(&volume(Some("System"), None, None), &[], &include));
+        assert!(is_excluded(&volume(Some("Recovery"), None, None), &[], &include));
+        assert!(is_excluded(&volume(None, Some(RECOVERY_GUID), None), &[], &include));
+        assert!(is_excluded(&volume(Some("SYSTEM"), None, None), &include, &include));
+        assert!(!is_excluded(&volume(None, None, None), &[], &[]));
+    }
+}
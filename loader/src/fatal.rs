@@ -0,0 +1,146 @@
+//! What happens after the loader failed.
+//!
+//! Many firmwares sit on a black screen when the loader returns an error, until the power button is held.
+//! Depending on `on_fatal`, the loader instead asks whether to reboot, shut down or return to the firmware,
+//! or reboots on its own. The answer is read from the console and from the UART the log is mirrored to, so
+//! headless machines can be answered over the serial line as well.
+
+use {
+    crate::{config::FatalPolicy, logging},
+    core::sync::atomic::{AtomicU8, Ordering},
+    uefi::{prelude::*, proto::console::text::Key, table::runtime::ResetType},
+};
+
+/// Interval between two polls of the console and the serial port.
+const POLL_INTERVAL_US: usize = 10_000;
+
+/// How long the error stays on the screen before an automatic reboot.
+const REBOOT_DELAY_US: usize = 5_000_000;
+
+/// The `on_fatal` setting, `FatalPolicy::Prompt` until the configuration is read.
+static POLICY: AtomicU8 = AtomicU8::new(FatalPolicy::Prompt as u8);
+
+/// The ways out of a failed boot.
+#[derive(Debug, PartialEq)]
+pub(crate) enum FatalAction {
+    /// Cold reset of the machine.
+    Reboot,
+
+    /// Power the machine off.
+    Shutdown,
+
+    /// Return to the firmware with the error status.
+    Firmware,
+}
+
+/// Sets the `on_fatal` setting applied by `handle`.
+pub(crate) fn set_policy(policy: FatalPolicy) {
+    POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+/// Returns the `on_fatal` setting applied by `handle`.
+fn policy() -> FatalPolicy {
+    match POLICY.load(Ordering::Relaxed) {
+        value if value == FatalPolicy::Reboot as u8 => FatalPolicy::Reboot,
+        value if value == FatalPolicy::Firmware as u8 => FatalPolicy::Firmware,
+        _ => FatalPolicy::Prompt,
+    }
+}
+
+/// Maps a character typed at the prompt to its action, `None` for characters without meaning.
+pub(crate) fn action_for_char(ch: char) -> Option<FatalAction> {
+    match ch {
+        'r' | 'R' => Some(FatalAction::Reboot),
+        's' | 'S' => Some(FatalAction::Shutdown),
+        'f' | 'F' => Some(FatalAction::Firmware),
+        _ => None,
+    }
+}
+
+/// Applies the `on_fatal` setting after the loader failed.
+///
+/// # Arguments
+///
+/// * `system_table` - The UEFI system table, used for console input and to reset the machine.
+/// * `status` - The status the loader failed with.
+///
+/// # Returns
+///
+/// The status to return to the firmware, if the machine is not reset.
+pub(crate) fn handle(system_table: &mut SystemTable<Boot>, status: Status) -> Status {
+    let action = match policy() {
+        FatalPolicy::Prompt => prompt(system_table),
+        FatalPolicy::Reboot => {
+            log::error!("Rebooting in {} seconds (on_fatal = reboot)", REBOOT_DELAY_US / 1_000_000);
+            system_table.boot_services().stall(REBOOT_DELAY_US);
+            FatalAction::Reboot
+        }
+        FatalPolicy::Firmware => FatalAction::Firmware,
+    };
+
+    match action {
+        FatalAction::Reboot => {
+            log::info!("Rebooting..");
+            system_table.runtime_services().reset(ResetType::COLD, status, None)
+        }
+        FatalAction::Shutdown => {
+            log::info!("Shutting down..");
+            system_table.runtime_services().reset(ResetType::SHUTDOWN, status, None)
+        }
+        FatalAction::Firmware => {
+            log::info!("Returning to the firmware with {:?}", status);
+            status
+        }
+    }
+}
+
+/// Asks what to do and waits for a valid answer on the console or the serial port.
+fn prompt(system_table: &mut SystemTable<Boot>) -> FatalAction {
+    let _ = system_table.stdin().reset(false);
+    log::error!("Press R to reboot, S to shut down, F to continue to the firmware.");
+
+    let mut console = true;
+    loop {
+        let typed = match console.then(|| system_table.stdin().read_key()) {
+            Some(Ok(Some(Key::Printable(c)))) => Some(char::from(c)),
+            Some(Err(error)) => {
+                log::warn!("Failed to read key from console ({:?})", error);
+                console = false;
+                None
+            }
+            Some(Ok(_)) | None => None,
+        };
+
+        if !console && !logging::has_serial_port() {
+            log::warn!("No console to answer on, continuing to the firmware");
+            return FatalAction::Firmware;
+        }
+
+        match typed.or_else(|| logging::read_serial_byte().map(char::from)).and_then(action_for_char) {
+            Some(action) => return action,
+            None => system_table.boot_services().stall(POLL_INTERVAL_US),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prompt_keys_map_to_actions() {
+        assert_eq!(action_for_char('r'), Some(FatalAction::Reboot));
+        assert_eq!(action_for_char('S'), Some(FatalAction::Shutdown));
+        assert_eq!(action_for_char('f'), Some(FatalAction::Firmware));
+        assert_eq!(action_for_char('\r'), None);
+        assert_eq!(action_for_char('x'), None);
+    }
+
+    #[test]
+    fn policy_round_trips() {
+        for expected in [FatalPolicy::Reboot, FatalPolicy::Firmware, FatalPolicy::Prompt] {
+            set_policy(expected);
+            assert!(policy() == expected);
+        }
+    }
+}
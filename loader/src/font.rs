@@ -0,0 +1,123 @@
+//! 8x16 bitmap font for the graphical menu, covering printable ASCII.
+//!
+//! Rasterized from DejaVu Sans Mono Bold, whose license allows derived fonts. Every glyph is 16 rows of
+//! 8 pixels, the most significant bit being the leftmost pixel.
+
+/// Width of a glyph in pixels.
+pub(crate) const GLYPH_WIDTH: usize = 8;
+
+/// Height of a glyph in pixels.
+pub(crate) const GLYPH_HEIGHT: usize = 16;
+
+/// The first character with a glyph.
+const FIRST: char = ' ';
+
+/// Returns the glyph of `ch`, or the one of `?` for characters outside printable ASCII.
+pub(crate) fn glyph(ch: char) -> &'static [u8; GLYPH_HEIGHT] {
+    let index = match ch {
+        ' '..='~' => ch as usize - FIRST as usize,
+        _ => '?' as usize - FIRST as usize,
+    };
+
+    &GLYPHS[index]
+}
+
+/// Glyphs of `' '` to `'~'`.
+#[rustfmt::skip]
+const GLYPHS: [[u8; GLYPH_HEIGHT]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x00, 0x00, 0x00, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x00, 0x00, 0x18, 0x18, 0x00, 0x00, 0x00], // '!'
+    [0x00, 0x00, 0x00, 0x66, 0x66, 0x66, 0x24, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '"'
+    [0x00, 0x00, 0x00, 0x1a, 0x16, 0x7f, 0x7f, 0x34, 0x2c, 0xfe, 0x6c, 0x68, 0x48, 0x00, 0x00, 0x00], // '#'
+    [0x00, 0x00, 0x00, 0x08, 0x3c, 0x7e, 0x68, 0x78, 0x3e, 0x0e, 0x0e, 0x7e, 0x38, 0x08, 0x00, 0x00], // '$'
+    [0x00, 0x00, 0x00, 0x60, 0xf0, 0xd0, 0x70, 0x0c, 0x34, 0x0f, 0x09, 0x0f, 0x06, 0x00, 0x00, 0x00], // '%'
+    [0x00, 0x00, 0x00, 0x3c, 0x74, 0x60, 0x30, 0x78, 0xdb, 0xcf, 0xc6, 0x7e, 0x3b, 0x00, 0x00, 0x00], // '&'
+    [0x00, 0x00, 0x00, 0x18, 0x18, 0x18, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '\''
+    [0x00, 0x00, 0x00, 0x0c, 0x18, 0x18, 0x18, 0x30, 0x30, 0x30, 0x18, 0x18, 0x18, 0x0c, 0x00, 0x00], // '('
+    [0x00, 0x00, 0x00, 0x30, 0x18, 0x18, 0x18, 0x0c, 0x0c, 0x0c, 0x18, 0x18, 0x18, 0x30, 0x00, 0x00], // ')'
+    [0x00, 0x00, 0x00, 0x18, 0x7e, 0x3c, 0x3c, 0x5a, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '*'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x18, 0x18, 0x18, 0xff, 0x18, 0x18, 0x18, 0x00, 0x00, 0x00, 0x00], // '+'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x18, 0x18, 0x18, 0x10, 0x00, 0x00], // ','
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x3c, 0x3c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '-'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x18, 0x18, 0x18, 0x00, 0x00, 0x00], // '.'
+    [0x00, 0x00, 0x00, 0x02, 0x06, 0x04, 0x0c, 0x08, 0x18, 0x10, 0x30, 0x20, 0x60, 0x40, 0x00, 0x00], // '/'
+    [0x00, 0x00, 0x00, 0x3c, 0x7e, 0x66, 0x66, 0x7e, 0x66, 0x66, 0x66, 0x3c, 0x18, 0x00, 0x00, 0x00], // '0'
+    [0x00, 0x00, 0x00, 0x38, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x7e, 0x7e, 0x00, 0x00, 0x00], // '1'
+    [0x00, 0x00, 0x00, 0x7c, 0x4e, 0x06, 0x06, 0x0c, 0x18, 0x30, 0x60, 0x7e, 0x7e, 0x00, 0x00, 0x00], // '2'
+    [0x00, 0x00, 0x00, 0x7c, 0x6e, 0x06, 0x0e, 0x3c, 0x0e, 0x06, 0x06, 0x7e, 0x7c, 0x00, 0x00, 0x00], // '3'
+    [0x00, 0x00, 0x00, 0x0c, 0x1c, 0x1c, 0x3c, 0x6c, 0x4e, 0x7e, 0x7e, 0x0c, 0x04, 0x00, 0x00, 0x00], // '4'
+    [0x00, 0x00, 0x00, 0x7e, 0x7c, 0x60, 0x78, 0x7e, 0x06, 0x06, 0x06, 0x7e, 0x78, 0x00, 0x00, 0x00], // '5'
+    [0x00, 0x00, 0x00, 0x3e, 0x70, 0x60, 0x7c, 0x7e, 0x66, 0x66, 0x66, 0x3e, 0x1c, 0x00, 0x00, 0x00], // '6'
+    [0x00, 0x00, 0x00, 0x7e, 0x7e, 0x06, 0x0c, 0x0c, 0x18, 0x18, 0x18, 0x30, 0x30, 0x00, 0x00, 0x00], // '7'
+    [0x00, 0x00, 0x00, 0x3c, 0x66, 0x66, 0x66, 0x3c, 0x66, 0x66, 0x66, 0x7e, 0x3c, 0x00, 0x00, 0x00], // '8'
+    [0x00, 0x00, 0x00, 0x3c, 0x6e, 0x66, 0x66, 0x6e, 0x7e, 0x06, 0x06, 0x7c, 0x38, 0x00, 0x00, 0x00], // '9'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x18, 0x18, 0x00, 0x00, 0x18, 0x18, 0x18, 0x00, 0x00, 0x00], // ':'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x18, 0x18, 0x00, 0x00, 0x18, 0x18, 0x18, 0x10, 0x10, 0x00], // ';'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x1e, 0x78, 0x60, 0x78, 0x0e, 0x02, 0x00, 0x00, 0x00, 0x00], // '<'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x7e, 0x7e, 0x00, 0x7e, 0x7e, 0x00, 0x00, 0x00, 0x00, 0x00], // '='
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x78, 0x1e, 0x06, 0x1e, 0x70, 0x40, 0x00, 0x00, 0x00, 0x00], // '>'
+    [0x00, 0x00, 0x00, 0x3c, 0x66, 0x06, 0x0c, 0x18, 0x18, 0x18, 0x00, 0x18, 0x10, 0x00, 0x00, 0x00], // '?'
+    [0x00, 0x00, 0x00, 0x08, 0x3e, 0x62, 0xcf, 0xdf, 0xb3, 0xb3, 0xdf, 0xce, 0x60, 0x3e, 0x1c, 0x00], // '@'
+    [0x00, 0x00, 0x00, 0x18, 0x3c, 0x3c, 0x3c, 0x24, 0x7e, 0x7e, 0x66, 0xc3, 0x42, 0x00, 0x00, 0x00], // 'A'
+    [0x00, 0x00, 0x00, 0x7c, 0x6e, 0x66, 0x66, 0x7c, 0x66, 0x63, 0x67, 0x7e, 0x7c, 0x00, 0x00, 0x00], // 'B'
+    [0x00, 0x00, 0x00, 0x1e, 0x3e, 0x60, 0x60, 0x60, 0x60, 0x60, 0x70, 0x3e, 0x1e, 0x00, 0x00, 0x00], // 'C'
+    [0x00, 0x00, 0x00, 0x7c, 0x7e, 0x66, 0x66, 0x66, 0x66, 0x66, 0x6e, 0x7c, 0x70, 0x00, 0x00, 0x00], // 'D'
+    [0x00, 0x00, 0x00, 0x7e, 0x7e, 0x60, 0x60, 0x7e, 0x60, 0x60, 0x60, 0x7e, 0x7e, 0x00, 0x00, 0x00], // 'E'
+    [0x00, 0x00, 0x00, 0x7e, 0x7e, 0x60, 0x60, 0x7e, 0x70, 0x60, 0x60, 0x60, 0x60, 0x00, 0x00, 0x00], // 'F'
+    [0x00, 0x00, 0x00, 0x3e, 0x7e, 0x60, 0x60, 0x66, 0x6e, 0x62, 0x72, 0x3e, 0x1c, 0x00, 0x00, 0x00], // 'G'
+    [0x00, 0x00, 0x00, 0x66, 0x66, 0x66, 0x66, 0x7e, 0x66, 0x66, 0x66, 0x66, 0x66, 0x00, 0x00, 0x00], // 'H'
+    [0x00, 0x00, 0x00, 0x7e, 0x7e, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x7e, 0x7e, 0x00, 0x00, 0x00], // 'I'
+    [0x00, 0x00, 0x00, 0x3e, 0x3e, 0x06, 0x06, 0x06, 0x06, 0x06, 0x4e, 0x7c, 0x38, 0x00, 0x00, 0x00], // 'J'
+    [0x00, 0x00, 0x00, 0x66, 0x6e, 0x6c, 0x78, 0x78, 0x7c, 0x6c, 0x66, 0x66, 0x43, 0x00, 0x00, 0x00], // 'K'
+    [0x00, 0x00, 0x00, 0x60, 0x60, 0x60, 0x60, 0x60, 0x60, 0x60, 0x70, 0x7f, 0x3e, 0x00, 0x00, 0x00], // 'L'
+    [0x00, 0x00, 0x00, 0xe7, 0xe7, 0xff, 0xff, 0xdb, 0xdb, 0xc3, 0xc3, 0xc3, 0x42, 0x00, 0x00, 0x00], // 'M'
+    [0x00, 0x00, 0x00, 0x66, 0x66, 0x76, 0x76, 0x7e, 0x6e, 0x6e, 0x6e, 0x66, 0x46, 0x00, 0x00, 0x00], // 'N'
+    [0x00, 0x00, 0x00, 0x3c, 0x7e, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x7e, 0x18, 0x00, 0x00, 0x00], // 'O'
+    [0x00, 0x00, 0x00, 0x7c, 0x7e, 0x66, 0x66, 0x7e, 0x7c, 0x60, 0x60, 0x60, 0x60, 0x00, 0x00, 0x00], // 'P'
+    [0x00, 0x00, 0x00, 0x3c, 0x7e, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x7e, 0x1c, 0x06, 0x00, 0x00], // 'Q'
+    [0x00, 0x00, 0x00, 0x7c, 0x7e, 0x66, 0x66, 0x7c, 0x7c, 0x6c, 0x66, 0x66, 0x63, 0x00, 0x00, 0x00], // 'R'
+    [0x00, 0x00, 0x00, 0x3e, 0x66, 0x60, 0x70, 0x3c, 0x0e, 0x06, 0x46, 0x7e, 0x3c, 0x00, 0x00, 0x00], // 'S'
+    [0x00, 0x00, 0x00, 0x7e, 0x7e, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x00, 0x00, 0x00], // 'T'
+    [0x00, 0x00, 0x00, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x7e, 0x3c, 0x00, 0x00, 0x00], // 'U'
+    [0x00, 0x00, 0x00, 0xc3, 0x66, 0x66, 0x66, 0x66, 0x3c, 0x3c, 0x3c, 0x3c, 0x18, 0x00, 0x00, 0x00], // 'V'
+    [0x00, 0x00, 0x00, 0xc3, 0xc3, 0xdb, 0xdb, 0x5b, 0x7e, 0x7e, 0x66, 0x66, 0x66, 0x00, 0x00, 0x00], // 'W'
+    [0x00, 0x00, 0x00, 0x66, 0x66, 0x3c, 0x3c, 0x18, 0x3c, 0x3c, 0x66, 0x66, 0x42, 0x00, 0x00, 0x00], // 'X'
+    [0x00, 0x00, 0x00, 0xc3, 0x66, 0x66, 0x3c, 0x3c, 0x18, 0x18, 0x18, 0x18, 0x18, 0x00, 0x00, 0x00], // 'Y'
+    [0x00, 0x00, 0x00, 0x7f, 0x7e, 0x0e, 0x0c, 0x18, 0x38, 0x30, 0x60, 0x7f, 0x7e, 0x00, 0x00, 0x00], // 'Z'
+    [0x00, 0x00, 0x1c, 0x1c, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x1c, 0x00, 0x00], // '['
+    [0x00, 0x00, 0x00, 0x40, 0x60, 0x20, 0x30, 0x10, 0x18, 0x08, 0x0c, 0x04, 0x06, 0x02, 0x00, 0x00], // '\\'
+    [0x00, 0x00, 0x38, 0x38, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x38, 0x00, 0x00], // ']'
+    [0x00, 0x00, 0x00, 0x18, 0x3c, 0x66, 0x42, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '^'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff], // '_'
+    [0x00, 0x00, 0x30, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '`'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x3c, 0x7e, 0x06, 0x7e, 0x66, 0x66, 0x7e, 0x3e, 0x00, 0x00, 0x00], // 'a'
+    [0x00, 0x00, 0x40, 0x60, 0x60, 0x6c, 0x7e, 0x66, 0x66, 0x66, 0x66, 0x7e, 0x6c, 0x00, 0x00, 0x00], // 'b'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x1c, 0x3e, 0x60, 0x60, 0x60, 0x60, 0x3e, 0x1c, 0x00, 0x00, 0x00], // 'c'
+    [0x00, 0x00, 0x02, 0x06, 0x06, 0x36, 0x7e, 0x66, 0x66, 0x66, 0x66, 0x7e, 0x36, 0x00, 0x00, 0x00], // 'd'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x3c, 0x7e, 0x66, 0x7f, 0x7e, 0x60, 0x7e, 0x1e, 0x00, 0x00, 0x00], // 'e'
+    [0x00, 0x00, 0x06, 0x1e, 0x18, 0x7e, 0x7e, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x00, 0x00, 0x00], // 'f'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x3e, 0x7e, 0x66, 0x66, 0x66, 0x66, 0x7e, 0x16, 0x06, 0x7c, 0x00], // 'g'
+    [0x00, 0x00, 0x20, 0x60, 0x60, 0x6c, 0x7e, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x00, 0x00, 0x00], // 'h'
+    [0x00, 0x00, 0x18, 0x18, 0x00, 0x38, 0x78, 0x18, 0x18, 0x18, 0x18, 0x7e, 0x7e, 0x00, 0x00, 0x00], // 'i'
+    [0x00, 0x00, 0x0c, 0x0c, 0x00, 0x38, 0x3c, 0x0c, 0x0c, 0x0c, 0x0c, 0x0c, 0x0c, 0x18, 0x78, 0x00], // 'j'
+    [0x00, 0x00, 0x20, 0x60, 0x60, 0x66, 0x6c, 0x78, 0x78, 0x7c, 0x6c, 0x66, 0x62, 0x00, 0x00, 0x00], // 'k'
+    [0x00, 0x00, 0x70, 0x70, 0x30, 0x30, 0x30, 0x30, 0x30, 0x30, 0x18, 0x1e, 0x0e, 0x00, 0x00, 0x00], // 'l'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x76, 0xfe, 0xdb, 0xdb, 0xdb, 0xdb, 0xdb, 0x5a, 0x00, 0x00, 0x00], // 'm'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x6c, 0x7e, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x00, 0x00, 0x00], // 'n'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x3c, 0x7e, 0x66, 0x66, 0x66, 0x66, 0x7e, 0x3c, 0x00, 0x00, 0x00], // 'o'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x6c, 0x7e, 0x66, 0x66, 0x66, 0x66, 0x7e, 0x6c, 0x60, 0x60, 0x00], // 'p'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x36, 0x7e, 0x66, 0x66, 0x66, 0x66, 0x7e, 0x36, 0x06, 0x06, 0x00], // 'q'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x36, 0x3f, 0x30, 0x30, 0x30, 0x30, 0x30, 0x30, 0x00, 0x00, 0x00], // 'r'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x3c, 0x7c, 0x60, 0x7c, 0x1e, 0x06, 0x7e, 0x3c, 0x00, 0x00, 0x00], // 's'
+    [0x00, 0x00, 0x00, 0x10, 0x18, 0x7e, 0x7e, 0x18, 0x18, 0x18, 0x18, 0x1e, 0x0e, 0x00, 0x00, 0x00], // 't'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x7e, 0x36, 0x00, 0x00, 0x00], // 'u'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x42, 0x66, 0x66, 0x66, 0x3c, 0x3c, 0x3c, 0x18, 0x00, 0x00, 0x00], // 'v'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x81, 0xc3, 0xdb, 0x5a, 0x7e, 0x7e, 0x66, 0x66, 0x00, 0x00, 0x00], // 'w'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x66, 0x6e, 0x3c, 0x18, 0x3c, 0x3c, 0x66, 0x66, 0x00, 0x00, 0x00], // 'x'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x42, 0x66, 0x66, 0x34, 0x3c, 0x3c, 0x18, 0x18, 0x18, 0x70, 0x00], // 'y'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x7e, 0x7e, 0x0c, 0x1c, 0x38, 0x30, 0x7e, 0x7e, 0x00, 0x00, 0x00], // 'z'
+    [0x00, 0x00, 0x04, 0x1e, 0x18, 0x18, 0x18, 0x18, 0x70, 0x38, 0x18, 0x18, 0x18, 0x1c, 0x0e, 0x00], // '{'
+    [0x00, 0x00, 0x00, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18], // '|'
+    [0x00, 0x00, 0x20, 0x78, 0x18, 0x18, 0x18, 0x18, 0x0e, 0x1c, 0x18, 0x18, 0x18, 0x38, 0x70, 0x00], // '}'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x30, 0x7e, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '~'
+];
@@ -0,0 +1,435 @@
+//! Graphical selection menu drawn with the Graphics Output Protocol.
+//!
+//! The menu box is rendered into an in-memory `BltPixel` buffer and copied to the middle of the screen.
+//! Rendering doesn't touch the firmware, so everything but `Screen` works on any buffer and is tested on
+//! the host.
+
+extern crate alloc;
+
+use {
+    crate::{
+        font::{self, GLYPH_HEIGHT, GLYPH_WIDTH},
+        menu,
+    },
+    alloc::{format, string::String, vec::Vec},
+    uefi::{
+        prelude::*,
+        proto::console::gop::{BltOp, BltPixel, BltRegion, GraphicsOutput},
+        table::boot::{OpenProtocolAttributes, OpenProtocolParams, ScopedProtocol},
+    },
+};
+
+/// Color of the screen around and inside the menu box.
+const BACKGROUND: BltPixel = BltPixel::new(0x10, 0x14, 0x1c);
+
+/// Color of the box border and the countdown bar.
+const ACCENT: BltPixel = BltPixel::new(0x3a, 0x8e, 0xe6);
+
+/// Color of the countdown bar's track.
+const TRACK: BltPixel = BltPixel::new(0x28, 0x30, 0x3c);
+
+/// Color of regular text.
+const TEXT: BltPixel = BltPixel::new(0xd0, 0xd4, 0xdc);
+
+/// Color of the highlighted entry's text, drawn on `ACCENT`.
+const HIGHLIGHT_TEXT: BltPixel = BltPixel::new(0xff, 0xff, 0xff);
+
+/// Color of the text of excluded entries, and of their highlight.
+const DIMMED_TEXT: BltPixel = BltPixel::new(0x70, 0x76, 0x80);
+
+/// Title shown at the top of the box.
+const TITLE: &str = "Illusion - select the boot manager to start";
+
+/// Space between the border and the contents.
+const PADDING: usize = 16;
+
+/// Width of the box border.
+const BORDER: usize = 2;
+
+/// Height of a text line including the space to the next one.
+const LINE_HEIGHT: usize = GLYPH_HEIGHT + 4;
+
+/// Height of the countdown bar.
+const BAR_HEIGHT: usize = 6;
+
+/// Text columns of the widest box, wider screens leave more space around it.
+const MAX_COLUMNS: usize = 96;
+
+/// Text columns of the narrowest box, narrower screens get the text menu.
+const MIN_COLUMNS: usize = 40;
+
+/// A rectangular buffer of pixels that text and boxes are drawn into.
+pub(crate) struct Canvas<'a> {
+    pixels: &'a mut [BltPixel],
+    width: usize,
+    height: usize,
+}
+
+impl<'a> Canvas<'a> {
+    /// Wraps `pixels`, a buffer of rows that are `width` pixels wide.
+    pub(crate) fn new(pixels: &'a mut [BltPixel], width: usize) -> Self {
+        let height = pixels.len() / width.max(1);
+        Self { pixels, width, height }
+    }
+
+    /// Returns the pixel at `x`, `y`.
+    #[cfg(test)]
+    fn pixel(&self, x: usize, y: usize) -> BltPixel {
+        self.pixels[y * self.width + x]
+    }
+
+    /// Fills a rectangle, clipped to the canvas.
+    pub(crate) fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: BltPixel) {
+        let right = x.saturating_add(width).min(self.width);
+        let bottom = y.saturating_add(height).min(self.height);
+
+        for row in y.min(bottom)..bottom {
+            self.pixels[row * self.width + x.min(right)..row * self.width + right].fill(color);
+        }
+    }
+
+    /// Draws the outline of a rectangle `thickness` pixels wide.
+    pub(crate) fn draw_frame(&mut self, x: usize, y: usize, width: usize, height: usize, thickness: usize, color: BltPixel) {
+        self.fill_rect(x, y, width, thickness, color);
+        self.fill_rect(x, (y + height).saturating_sub(thickness), width, thickness, color);
+        self.fill_rect(x, y, thickness, height, color);
+        self.fill_rect((x + width).saturating_sub(thickness), y, thickness, height, color);
+    }
+
+    /// Draws `text` with its first glyph's top left corner at `x`, `y`, filling the glyph cells with `background`.
+    pub(crate) fn draw_text(&mut self, x: usize, y: usize, text: &str, foreground: BltPixel, background: BltPixel) {
+        for (column, ch) in text.chars().enumerate() {
+            let left = x + column * GLYPH_WIDTH;
+            if left >= self.width {
+                break;
+            }
+
+            for (row, bits) in font::glyph(ch).iter().enumerate() {
+                if y + row >= self.height {
+                    break;
+                }
+
+                for bit in 0..GLYPH_WIDTH.min(self.width - left) {
+                    let color = match bits & (0x80 >> bit) != 0 {
+                        true => foreground,
+                        false => background,
+                    };
+                    self.pixels[(y + row) * self.width + left + bit] = color;
+                }
+            }
+        }
+    }
+}
+
+/// Size of the menu box and the position of its parts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Layout {
+    /// Characters per text line.
+    pub columns: usize,
+
+    /// Number of entry lines, including the trailing one that isn't a boot candidate.
+    pub lines: usize,
+
+    /// Width of the box in pixels.
+    pub width: usize,
+
+    /// Height of the box in pixels.
+    pub height: usize,
+}
+
+impl Layout {
+    /// Lays out a box for `lines` entry lines that takes up at most three quarters of the screen width.
+    ///
+    /// # Returns
+    ///
+    /// The layout, or `None` if the box doesn't fit on the screen.
+    pub(crate) fn new(screen_width: usize, screen_height: usize, lines: usize) -> Option<Self> {
+        let columns = (screen_width * 3 / 4).saturating_sub(2 * PADDING) / GLYPH_WIDTH;
+        if columns < MIN_COLUMNS {
+            return None;
+        }
+
+        let mut layout = Self {
+            columns: columns.min(MAX_COLUMNS),
+            lines,
+            width: 0,
+            height: 0,
+        };
+        layout.width = layout.columns * GLYPH_WIDTH + 2 * PADDING;
+        layout.height = layout.bar_top() + BAR_HEIGHT + PADDING;
+
+        (layout.height <= screen_height).then_some(layout)
+    }
+
+    /// Returns the top of entry line `index`, below the title and an empty line.
+    fn line_top(&self, index: usize) -> usize {
+        PADDING + (index + 2) * LINE_HEIGHT
+    }
+
+    /// Returns the top of the status line, half a line below the last entry line.
+    fn status_top(&self) -> usize {
+        self.line_top(self.lines) + LINE_HEIGHT / 2
+    }
+
+    /// Returns the top of the countdown bar.
+    fn bar_top(&self) -> usize {
+        self.status_top() + LINE_HEIGHT
+    }
+
+    /// Returns the width of the area inside the padding.
+    fn inner_width(&self) -> usize {
+        self.columns * GLYPH_WIDTH
+    }
+}
+
+/// Returns how many of `width` pixels of the countdown bar are filled when `waited_us` of `timeout_us` passed.
+///
+/// The bar starts full and shrinks to nothing as the countdown runs out.
+pub(crate) fn bar_fill(width: usize, waited_us: u64, timeout_us: u64) -> usize {
+    match timeout_us {
+        0 => 0,
+        _ => (width as u64 * timeout_us.saturating_sub(waited_us) / timeout_us) as usize,
+    }
+}
+
+/// Draws the box with the title and the entry lines, highlighting the entry at `selected`.
+///
+/// # Arguments
+///
+/// * `canvas` - The canvas of the box, `layout.width` by `layout.height` pixels.
+/// * `layout` - The layout of the box.
+/// * `entries` - One description per boot candidate, numbered from 1.
+/// * `trailer` - The line after the candidates, which is never highlighted.
+/// * `selected` - The index of the highlighted entry.
+/// * `locked` - The index of the first excluded entry, which is greyed out with the ones after it.
+pub(crate) fn draw_entries(canvas: &mut Canvas, layout: &Layout, entries: &[String], trailer: &str, selected: usize, locked: usize) {
+    canvas.fill_rect(0, 0, layout.width, layout.status_top(), BACKGROUND);
+    canvas.draw_frame(0, 0, layout.width, layout.height, BORDER, ACCENT);
+    canvas.draw_text(PADDING, PADDING, &menu::fit_line(TITLE, layout.columns), HIGHLIGHT_TEXT, BACKGROUND);
+
+    for (index, entry) in entries.iter().enumerate() {
+        let (foreground, background) = match (index == selected, index >= locked) {
+            (true, false) => (HIGHLIGHT_TEXT, ACCENT),
+            (true, true) => (HIGHLIGHT_TEXT, DIMMED_TEXT),
+            (false, false) => (TEXT, BACKGROUND),
+            (false, true) => (DIMMED_TEXT, BACKGROUND),
+        };
+
+        let top = layout.line_top(index);
+        let line = menu::fit_line(&format!(" {}. {}", index + 1, entry), layout.columns);
+        canvas.fill_rect(PADDING, top - 2, layout.inner_width(), LINE_HEIGHT, background);
+        canvas.draw_text(PADDING, top, &line, foreground, background);
+    }
+
+    let line = menu::fit_line(&format!(" {}", trailer), layout.columns);
+    canvas.draw_text(PADDING, layout.line_top(entries.len()), &line, TEXT, BACKGROUND);
+}
+
+/// Draws the status line and the countdown bar.
+///
+/// # Arguments
+///
+/// * `canvas` - The canvas of the box, `layout.width` by `layout.height` pixels.
+/// * `layout` - The layout of the box.
+/// * `status` - The text of the status line.
+/// * `bar` - The filled width of the countdown bar, `None` hides it.
+pub(crate) fn draw_status(canvas: &mut Canvas, layout: &Layout, status: &str, bar: Option<usize>) {
+    canvas.draw_text(PADDING, layout.status_top(), &menu::fit_line(status, layout.columns), TEXT, BACKGROUND);
+
+    let top = layout.bar_top();
+    match bar {
+        Some(filled) => {
+            canvas.fill_rect(PADDING, top, filled, BAR_HEIGHT, ACCENT);
+            canvas.fill_rect(PADDING + filled, top, layout.inner_width().saturating_sub(filled), BAR_HEIGHT, TRACK);
+        }
+        None => canvas.fill_rect(PADDING, top, layout.inner_width(), BAR_HEIGHT, BACKGROUND),
+    }
+}
+
+/// The menu box shown on the screen.
+pub(crate) struct Screen<'a> {
+    gop: ScopedProtocol<'a, GraphicsOutput>,
+    pixels: Vec<BltPixel>,
+    layout: Layout,
+
+    /// Screen position of the box's top left corner.
+    origin: (usize, usize),
+
+    /// The status text and bar fill last copied to the screen.
+    shown_status: Option<(String, Option<usize>)>,
+}
+
+impl<'a> Screen<'a> {
+    /// Clears the screen and prepares a box for `lines` entry lines.
+    ///
+    /// # Arguments
+    ///
+    /// * `boot_services` - A reference to the UEFI boot services.
+    /// * `lines` - The number of entry lines, including the trailing one.
+    ///
+    /// # Returns
+    ///
+    /// The screen, or why the graphical menu can't be shown.
+    pub(crate) fn open(boot_services: &'a BootServices, lines: usize) -> Result<Self, &'static str> {
+        let handle = boot_services
+            .get_handle_for_protocol::<GraphicsOutput>()
+            .map_err(|_| "no Graphics Output Protocol")?;
+        let params = OpenProtocolParams {
+            handle,
+            agent: boot_services.image_handle(),
+            controller: None,
+        };
+
+        // SAFETY: The console driver keeps using the protocol. Nothing is written to the console while the
+        // menu is shown, and the console is cleared once it closes.
+        let mut gop = unsafe { boot_services.open_protocol::<GraphicsOutput>(params, OpenProtocolAttributes::GetProtocol) }
+            .map_err(|_| "failed to open the Graphics Output Protocol")?;
+
+        let (width, height) = gop.current_mode_info().resolution();
+        let layout = Layout::new(width, height, lines).ok_or("the screen resolution is too low")?;
+        log::debug!("Drawing the graphical menu at {}x{} in a {}x{} box", width, height, layout.width, layout.height);
+
+        gop.blt(BltOp::VideoFill {
+            color: BACKGROUND,
+            dest: (0, 0),
+            dims: (width, height),
+        })
+        .map_err(|_| "failed to clear the screen")?;
+
+        Ok(Self {
+            gop,
+            pixels: alloc::vec![BACKGROUND; layout.width * layout.height],
+            layout,
+            origin: ((width - layout.width) / 2, (height - layout.height) / 2),
+            shown_status: None,
+        })
+    }
+
+    /// Draws the entries, highlighting the one at `selected` and greying out the ones from `locked` on.
+    pub(crate) fn draw_entries(&mut self, entries: &[String], trailer: &str, selected: usize, locked: usize) {
+        draw_entries(&mut Canvas::new(&mut self.pixels, self.layout.width), &self.layout, entries, trailer, selected, locked);
+        self.shown_status = None;
+        self.present(0, self.layout.height);
+    }
+
+    /// Draws the status line and, while `countdown` holds the waited and the total time, the countdown bar.
+    ///
+    /// Nothing is copied to the screen if neither the text nor the bar changed since the last call.
+    pub(crate) fn draw_status(&mut self, status: &str, countdown: Option<(u64, u64)>) {
+        let bar = countdown.map(|(waited_us, timeout_us)| bar_fill(self.layout.inner_width(), waited_us, timeout_us));
+        if self
+            .shown_status
+            .as_ref()
+            .is_some_and(|(shown, shown_bar)| shown == status && *shown_bar == bar)
+        {
+            return;
+        }
+
+        draw_status(&mut Canvas::new(&mut self.pixels, self.layout.width), &self.layout, status, bar);
+        self.shown_status = Some((String::from(status), bar));
+
+        let top = self.layout.status_top();
+        self.present(top, self.layout.bar_top() + BAR_HEIGHT - top);
+    }
+
+    /// Copies `rows` rows of the box starting at `top` to the screen.
+    fn present(&mut self, top: usize, rows: usize) {
+        let result = self.gop.blt(BltOp::BufferToVideo {
+            buffer: &self.pixels,
+            src: BltRegion::SubRectangle {
+                coords: (0, top),
+                px_stride: self.layout.width,
+            },
+            dest: (self.origin.0, self.origin.1 + top),
+            dims: (self.layout.width, rows),
+        });
+
+        if let Err(error) = result {
+            log::debug!("Failed to copy the menu to the screen ({:?})", error.status());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, alloc::string::ToString};
+
+    /// Returns the color components of a pixel, which doesn't implement `PartialEq`.
+    fn rgb(pixel: BltPixel) -> (u8, u8, u8) {
+        (pixel.red, pixel.green, pixel.blue)
+    }
+
+    #[test]
+    fn rectangles_are_clipped() {
+        let mut pixels = alloc::vec![BACKGROUND; 4 * 3];
+        let mut canvas = Canvas::new(&mut pixels, 4);
+
+        canvas.fill_rect(2, 1, 10, 10, ACCENT);
+        canvas.fill_rect(7, 0, 2, 2, TEXT);
+
+        assert_eq!(rgb(canvas.pixel(1, 1)), rgb(BACKGROUND));
+        assert_eq!(rgb(canvas.pixel(2, 1)), rgb(ACCENT));
+        assert_eq!(rgb(canvas.pixel(3, 2)), rgb(ACCENT));
+        assert_eq!(rgb(canvas.pixel(3, 0)), rgb(BACKGROUND));
+    }
+
+    #[test]
+    fn glyphs_are_drawn_from_the_font() {
+        let mut pixels = alloc::vec![TRACK; 20 * GLYPH_HEIGHT];
+        let mut canvas = Canvas::new(&mut pixels, 20);
+
+        // The second glyph is cut off at the right edge without panicking.
+        canvas.draw_text(0, 0, "|=", TEXT, BACKGROUND);
+
+        let glyph = font::glyph('|');
+        for (row, bits) in glyph.iter().enumerate() {
+            for bit in 0..GLYPH_WIDTH {
+                let expected = if bits & (0x80 >> bit) != 0 { TEXT } else { BACKGROUND };
+                assert_eq!(rgb(canvas.pixel(bit, row)), rgb(expected));
+            }
+        }
+        assert_eq!(rgb(canvas.pixel(GLYPH_WIDTH * 2, 0)), rgb(TRACK));
+        assert_eq!(font::glyph('\u{e9}'), font::glyph('?'));
+    }
+
+    #[test]
+    fn layout_fits_the_screen() {
+        let layout = Layout::new(1024, 768, 4).unwrap();
+        assert_eq!(layout.columns, 92);
+        assert_eq!(layout.width, 92 * GLYPH_WIDTH + 2 * PADDING);
+        assert!(layout.height <= 768);
+
+        assert_eq!(Layout::new(1920, 1080, 4).unwrap().columns, MAX_COLUMNS);
+        assert_eq!(Layout::new(320, 200, 4), None);
+        assert_eq!(Layout::new(1024, 200, 40), None);
+    }
+
+    #[test]
+    fn countdown_bar_shrinks() {
+        assert_eq!(bar_fill(200, 0, 5_000_000), 200);
+        assert_eq!(bar_fill(200, 2_500_000, 5_000_000), 100);
+        assert_eq!(bar_fill(200, 6_000_000, 5_000_000), 0);
+        assert_eq!(bar_fill(200, 0, 0), 0);
+    }
+
+    #[test]
+    fn selected_entry_is_highlighted() {
+        let layout = Layout::new(800, 600, 3).unwrap();
+        let mut pixels = alloc::vec![TRACK; layout.width * layout.height];
+        let mut canvas = Canvas::new(&mut pixels, layout.width);
+        let entries = ["first".to_string(), "second".to_string()];
+
+        draw_entries(&mut canvas, &layout, &entries, "0. Return to firmware", 1, entries.len());
+        draw_status(&mut canvas, &layout, "status", Some(10));
+
+        // The right end of each line is past its text, so only the highlight colors it.
+        let right = PADDING + layout.inner_width() - 1;
+        assert_eq!(rgb(canvas.pixel(right, layout.line_top(0))), rgb(BACKGROUND));
+        assert_eq!(rgb(canvas.pixel(right, layout.line_top(1))), rgb(ACCENT));
+        assert_eq!(rgb(canvas.pixel(right, layout.line_top(2))), rgb(BACKGROUND));
+        assert_eq!(rgb(canvas.pixel(0, 0)), rgb(ACCENT));
+
+        assert_eq!(rgb(canvas.pixel(PADDING + 9, layout.bar_top())), rgb(ACCENT));
+        assert_eq!(rgb(canvas.pixel(PADDING + 10, layout.bar_top())), rgb(TRACK));
+    }
+}
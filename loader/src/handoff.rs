@@ -0,0 +1,118 @@
+//! The handoff to the hypervisor, installed as a protocol on its image handle.
+//!
+//! The loader fills a `shared::handoff::LoaderHandoff` with its build, the UART it logs to, the digest of
+//! the hypervisor image, what it knows about the boot, the features chosen in `illusion.cfg` and the log
+//! ring of `crate::hvlog`, and installs it on the handle returned by `load_image` before `start_image`. The structure is allocated from runtime services data, so it stays
+//! valid after the loader returned and after `ExitBootServices`. The boot manager is only selected after
+//! the hypervisor started, its device path is added to the same structure right before it is started.
+
+use {
+    crate::{build_info::LOADER_BUILD_INFO, logging},
+    core::{
+        ffi::c_void,
+        ptr,
+        sync::atomic::{AtomicPtr, Ordering},
+    },
+    shared::{
+        features::HvFeatureFlags,
+        handoff::{self, HvResult, LoaderHandoff, FLAG_DEBUG_BUILD, HANDOFF_PROTOCOL_GUID, HANDOFF_SIZE},
+    },
+    uefi::{prelude::*, proto::device_path::DevicePath, table::boot::MemoryType, Guid},
+};
+
+/// GUID of the handoff protocol.
+const HANDOFF_GUID: Guid = Guid::parse_or_panic(HANDOFF_PROTOCOL_GUID);
+
+/// The handoff installed for the running hypervisor, null until one is installed.
+static INSTALLED: AtomicPtr<LoaderHandoff> = AtomicPtr::new(ptr::null_mut());
+
+/// Describes the loader and the hypervisor image for the handoff.
+///
+/// # Arguments
+///
+/// * `digest` - The SHA-256 digest of the hypervisor image as stored on disk or downloaded.
+/// * `flags` - The `shared::handoff::FLAG_*` bits describing how the image was loaded.
+/// * `features` - The features the hypervisor enables for this boot.
+pub(crate) fn describe(digest: [u8; 32], flags: u64, features: HvFeatureFlags) -> LoaderHandoff {
+    let mut handoff = LoaderHandoff::new();
+    handoff.flags = flags;
+    handoff.features = features.bits();
+    if LOADER_BUILD_INFO.profile == "debug" {
+        handoff.flags |= FLAG_DEBUG_BUILD;
+    }
+
+    handoff.loader_version = handoff::to_fixed_string(LOADER_BUILD_INFO.version);
+    handoff.loader_git_hash = handoff::to_fixed_string(LOADER_BUILD_INFO.git_hash);
+    handoff.serial_port = logging::serial_port().unwrap_or(0);
+    handoff.serial_baud_rate = logging::SERIAL_BAUD_RATE;
+    handoff.image_sha256 = digest;
+    handoff
+}
+
+/// Installs the handoff on the image handle of the hypervisor.
+///
+/// A hypervisor that doesn't know the protocol ignores it, so failing to install it only costs the
+/// hypervisor the information.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+/// * `handle` - The handle of the loaded hypervisor image.
+/// * `handoff` - The handoff to copy into runtime services data.
+pub(crate) fn install(boot_services: &BootServices, handle: Handle, handoff: &LoaderHandoff) -> uefi::Result {
+    // The handoff of an earlier image, e.g. the primary one before the backup, must not answer for this one.
+    INSTALLED.store(ptr::null_mut(), Ordering::Relaxed);
+
+    let memory = boot_services
+        .allocate_pool(MemoryType::RUNTIME_SERVICES_DATA, HANDOFF_SIZE)?
+        .cast::<LoaderHandoff>();
+
+    // Safety: the pool is large enough and, as all pool allocations, 8-byte aligned.
+    unsafe { memory.as_ptr().write(*handoff) };
+
+    if let Err(error) = unsafe { boot_services.install_protocol_interface(Some(handle), &HANDOFF_GUID, memory.as_ptr() as *const c_void) } {
+        let _ = unsafe { boot_services.free_pool(memory.as_ptr().cast()) };
+        return Err(error);
+    }
+
+    INSTALLED.store(memory.as_ptr(), Ordering::Relaxed);
+    Ok(())
+}
+
+/// Returns what the hypervisor asked for in the installed handoff, `HvResult::Continue` without one.
+pub(crate) fn hypervisor_result() -> HvResult {
+    let installed = INSTALLED.load(Ordering::Relaxed);
+    if installed.is_null() {
+        return HvResult::Continue;
+    }
+
+    // Safety: see `set_chainload_target`. The hypervisor wrote the fields through its own pointer.
+    unsafe { ptr::read_volatile(installed) }.result()
+}
+
+/// Removes the log ring from the installed handoff before its pages are freed.
+pub(crate) fn clear_log_ring() {
+    let installed = INSTALLED.load(Ordering::Relaxed);
+    if !installed.is_null() {
+        // Safety: see `set_chainload_target`.
+        unsafe { &mut *installed }.set_log_ring(0, 0);
+    }
+}
+
+/// Adds the boot manager about to be started to the installed handoff.
+///
+/// # Arguments
+///
+/// * `device_path` - The device path of the boot manager.
+pub(crate) fn set_chainload_target(device_path: &DevicePath) {
+    let installed = INSTALLED.load(Ordering::Relaxed);
+    if installed.is_null() {
+        return;
+    }
+
+    // Safety: the handoff stays allocated for the lifetime of the hypervisor, which only reads it.
+    let handoff = unsafe { &mut *installed };
+    if !handoff.set_chainload_device_path(device_path.as_bytes()) {
+        log::debug!("Boot manager device path of {} bytes does not fit the hypervisor handoff", device_path.as_bytes().len());
+    }
+}
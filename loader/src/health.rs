@@ -0,0 +1,161 @@
+//! Health of the installed hypervisor image, used to fall back to the previous image after an update.
+//!
+//! When the image fails to load, to start or to stay resident, the loader starts the backup kept by the
+//! update as `illusion.efi.bak` and stores the digest of the failing image with the number of tries and
+//! the last status in the non-volatile `IllusionHvHealth` variable. Later boots go straight to the backup
+//! while the installed image still has that digest, and the record is cleared once an image other than a
+//! known bad one starts.
+
+use {
+    crate::{last_boot::ILLUSION_VENDOR, sha256::DIGEST_SIZE},
+    uefi::{prelude::*, table::runtime::VariableAttributes, CStr16},
+};
+
+/// Name of the variable holding the health record.
+const HEALTH_VARIABLE: &CStr16 = cstr16!("IllusionHvHealth");
+
+/// Version of the record layout written by this loader.
+const RECORD_VERSION: u8 = 1;
+
+/// Size of the record layout written by this loader.
+pub(crate) const RECORD_SIZE: usize = 2 + 8 + DIGEST_SIZE;
+
+/// Upper bound for records written by later versions with more fields.
+const MAX_RECORD_SIZE: usize = 128;
+
+/// The failures of one hypervisor image.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct HealthRecord {
+    /// The number of boots the image failed to start in.
+    pub tries: u8,
+
+    /// The status of the last failure.
+    pub status: Status,
+
+    /// The SHA-256 digest of the failing image as stored on disk.
+    pub digest: [u8; DIGEST_SIZE],
+}
+
+impl HealthRecord {
+    /// Returns the record after the image with `digest` failed once more with `status`.
+    pub(crate) fn failed(previous: Option<&HealthRecord>, digest: [u8; DIGEST_SIZE], status: Status) -> Self {
+        let tries = match previous {
+            Some(previous) if previous.digest == digest => previous.tries.saturating_add(1),
+            _ => 1,
+        };
+
+        Self { tries, status, digest }
+    }
+
+    /// Encodes the record in little endian byte order.
+    pub(crate) fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0u8; RECORD_SIZE];
+        bytes[0] = RECORD_VERSION;
+        bytes[1] = self.tries;
+        bytes[2..10].copy_from_slice(&(self.status.0 as u64).to_le_bytes());
+        bytes[10..].copy_from_slice(&self.digest);
+        bytes
+    }
+
+    /// Decodes a record, ignoring fields appended by later versions.
+    ///
+    /// # Returns
+    ///
+    /// The record, or `None` if the data is too short or carries no valid version.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < RECORD_SIZE || bytes[0] == 0 {
+            return None;
+        }
+
+        Some(Self {
+            tries: bytes[1],
+            status: Status(u64::from_le_bytes(bytes[2..10].try_into().ok()?) as usize),
+            digest: bytes[10..RECORD_SIZE].try_into().ok()?,
+        })
+    }
+}
+
+/// Reads the health record.
+///
+/// # Arguments
+///
+/// * `runtime_services` - A reference to the UEFI runtime services.
+///
+/// # Returns
+///
+/// The record, or `None` if no image failed or the variable is unreadable or malformed.
+pub(crate) fn load(runtime_services: &RuntimeServices) -> Option<HealthRecord> {
+    let mut buffer = [0u8; MAX_RECORD_SIZE];
+
+    match runtime_services.get_variable(HEALTH_VARIABLE, &ILLUSION_VENDOR, &mut buffer) {
+        Ok((data, _)) => {
+            let record = HealthRecord::from_bytes(data);
+            if record.is_none() {
+                log::warn!("Ignoring malformed {} of {} bytes", HEALTH_VARIABLE, data.len());
+            }
+            record
+        }
+        Err(error) if error.status() == Status::NOT_FOUND => None,
+        Err(error) => {
+            log::warn!("Failed to read {} ({:?})", HEALTH_VARIABLE, error.status());
+            None
+        }
+    }
+}
+
+/// Stores the health record.
+///
+/// # Arguments
+///
+/// * `runtime_services` - A reference to the UEFI runtime services.
+/// * `record` - The failures of the installed image.
+pub(crate) fn store(runtime_services: &RuntimeServices, record: &HealthRecord) {
+    let attributes = VariableAttributes::NON_VOLATILE | VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS;
+
+    match runtime_services.set_variable(HEALTH_VARIABLE, &ILLUSION_VENDOR, attributes, &record.to_bytes()) {
+        Ok(()) => log::debug!("Recorded hypervisor failure {} in {}", record.tries, HEALTH_VARIABLE),
+        Err(error) => log::warn!("Failed to record hypervisor failure in {} ({:?})", HEALTH_VARIABLE, error.status()),
+    }
+}
+
+/// Deletes the health record once an image started.
+///
+/// # Arguments
+///
+/// * `runtime_services` - A reference to the UEFI runtime services.
+pub(crate) fn clear(runtime_services: &RuntimeServices) {
+    match runtime_services.delete_variable(HEALTH_VARIABLE, &ILLUSION_VENDOR) {
+        Ok(()) => log::debug!("Cleared {}", HEALTH_VARIABLE),
+        Err(error) if error.status() == Status::NOT_FOUND => {}
+        Err(error) => log::warn!("Failed to clear {} ({:?})", HEALTH_VARIABLE, error.status()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_round_trip() {
+        let record = HealthRecord::failed(None, [0xab; DIGEST_SIZE], Status::NOT_STARTED);
+        let bytes = record.to_bytes();
+        assert_eq!(&bytes[..2], &[RECORD_VERSION, 1]);
+        assert_eq!(HealthRecord::from_bytes(&bytes), Some(record));
+
+        let mut longer = [0u8; RECORD_SIZE + 8];
+        longer[..RECORD_SIZE].copy_from_slice(&bytes);
+        assert!(HealthRecord::from_bytes(&longer).is_some());
+        assert_eq!(HealthRecord::from_bytes(&bytes[..RECORD_SIZE - 1]), None);
+        assert_eq!(HealthRecord::from_bytes(&[0u8; RECORD_SIZE]), None);
+    }
+
+    #[test]
+    fn tries_count_failures_of_the_same_image() {
+        let first = HealthRecord::failed(None, [1; DIGEST_SIZE], Status::NOT_STARTED);
+        let second = HealthRecord::failed(Some(&first), [1; DIGEST_SIZE], Status::TIMEOUT);
+        assert_eq!((second.tries, second.status), (2, Status::TIMEOUT));
+
+        let other = HealthRecord::failed(Some(&second), [2; DIGEST_SIZE], Status::LOAD_ERROR);
+        assert_eq!(other.tries, 1);
+    }
+}
@@ -0,0 +1,135 @@
+//! Reads the status protocol the hypervisor installs, see `shared::hvstatus`.
+//!
+//! The loader queries it right after `start_image` returned, as the first caller of the protocol and a
+//! sanity check of the hypervisor's own view of the processors. Hypervisors without the protocol, e.g.
+//! older builds, are only noted.
+
+use {
+    core::mem::size_of,
+    shared::{
+        handoff,
+        hvstatus::{
+            HvCpuStatus, HvHookSwapStatus, HvStats, HvStatusProtocol, HvVersion, CPU_STATE_FAILED, CPU_STATE_STARTING, CPU_STATE_VIRTUALIZED,
+            HOOK_SWAP_VIRTUALIZATION_EXCEPTION, HOOK_SWAP_VM_EXIT, MAX_PROCESSORS, STATUS_PROTOCOL_GUID, STATUS_PROTOCOL_V1_SIZE,
+        },
+    },
+    uefi::{prelude::*, proto::Protocol, Guid, Identify},
+};
+
+/// The interface installed by the hypervisor.
+#[repr(transparent)]
+struct StatusProtocol(HvStatusProtocol);
+
+unsafe impl Identify for StatusProtocol {
+    const GUID: Guid = Guid::parse_or_panic(STATUS_PROTOCOL_GUID);
+}
+
+impl Protocol for StatusProtocol {}
+
+/// Logs what the hypervisor reports through its status protocol.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+pub(crate) fn log_status(boot_services: &BootServices) {
+    let Ok(protocol) = boot_services
+        .get_handle_for_protocol::<StatusProtocol>()
+        .and_then(|handle| boot_services.open_protocol_exclusive::<StatusProtocol>(handle))
+    else {
+        log::debug!("[5/8] Hypervisor does not provide the status protocol");
+        return;
+    };
+
+    let protocol = &protocol.0;
+    if protocol.revision < 1 || protocol.size < STATUS_PROTOCOL_V1_SIZE {
+        log::warn!("[5/8] Ignoring the status protocol of revision {} and size {}", protocol.revision, protocol.size);
+        return;
+    }
+
+    let mut version = HvVersion {
+        version: [0; handoff::BUILD_STRING_SIZE],
+        features: 0,
+    };
+    let mut stats = HvStats {
+        processors: 0,
+        virtualized: 0,
+        vm_exits: 0,
+        hypercalls: 0,
+    };
+
+    // Safety: the functions only write to the structure they are passed.
+    if Status(unsafe { (protocol.get_version)(&mut version) }).is_error() || Status(unsafe { (protocol.get_stats)(&mut stats) }).is_error() {
+        log::warn!("[5/8] The status protocol of the hypervisor refused to answer");
+        return;
+    }
+
+    log::info!(
+        "[5/8] Hypervisor {} reports {} of {} processor(s) virtualized, {} VM exit(s) and {} hypercall(s) so far",
+        handoff::from_fixed_string(&version.version),
+        stats.virtualized,
+        stats.processors,
+        stats.vm_exits,
+        stats.hypercalls
+    );
+
+    // Revision 1 hypervisors end the interface before `get_hook_swap_status`.
+    if protocol.revision >= 2 && protocol.size as usize >= size_of::<HvStatusProtocol>() {
+        let mut hook_swaps = HvHookSwapStatus { mode: 0, ve_processors: 0 };
+        // Safety: see above.
+        if Status(unsafe { (protocol.get_hook_swap_status)(&mut hook_swaps) }).is_success() {
+            log::info!(
+                "[5/8] EPT hook views are switched {}, #VE handled on {} processor(s)",
+                hook_swap_mode_name(hook_swaps.mode),
+                hook_swaps.ve_processors
+            );
+        }
+    }
+
+    for index in 0..(stats.processors as usize).min(MAX_PROCESSORS) {
+        let mut cpu = HvCpuStatus { apic_id: 0, state: 0 };
+        // Safety: see above.
+        if Status(unsafe { (protocol.get_cpu_status)(index as u32, &mut cpu) }).is_error() {
+            break;
+        }
+        log::debug!("[5/8] Processor {} (APIC ID {}): {}", index, cpu.apic_id, state_name(cpu.state));
+    }
+}
+
+/// Returns how a `CPU_STATE_*` is logged.
+fn state_name(state: u32) -> &'static str {
+    match state {
+        CPU_STATE_STARTING => "did not return to the guest",
+        CPU_STATE_VIRTUALIZED => "virtualized",
+        CPU_STATE_FAILED => "failed, running bare metal",
+        _ => "unknown state",
+    }
+}
+
+/// Returns how a `HOOK_SWAP_*` is logged.
+fn hook_swap_mode_name(mode: u32) -> &'static str {
+    match mode {
+        HOOK_SWAP_VM_EXIT => "in VM exits",
+        HOOK_SWAP_VIRTUALIZATION_EXCEPTION => "by the guest #VE handler",
+        _ => "in an unknown way",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn states_are_named() {
+        assert_eq!(state_name(CPU_STATE_VIRTUALIZED), "virtualized");
+        assert_eq!(state_name(CPU_STATE_STARTING), "did not return to the guest");
+        assert_eq!(state_name(CPU_STATE_FAILED), "failed, running bare metal");
+        assert_eq!(state_name(0), "unknown state");
+    }
+
+    #[test]
+    fn hook_swap_modes_are_named() {
+        assert_eq!(hook_swap_mode_name(HOOK_SWAP_VM_EXIT), "in VM exits");
+        assert_eq!(hook_swap_mode_name(HOOK_SWAP_VIRTUALIZATION_EXCEPTION), "by the guest #VE handler");
+        assert_eq!(hook_swap_mode_name(0), "in an unknown way");
+    }
+}
@@ -0,0 +1,172 @@
+//! Patching of the configuration section of the hypervisor image.
+//!
+//! The hypervisor image carries its settings in the `.ilcfg` section (see `shared::hvconfig`). When
+//! `illusion.cfg` sets any of the `hv_*` keys, the loader overwrites the blob in its copy of the image and
+//! loads the patched buffer instead of the file. Settings that are not set keep the value the image was
+//! built with. An image without the section, or with a blob of another layout, is loaded unmodified.
+
+use {
+    crate::{config::LoaderConfig, pe},
+    core::fmt,
+    shared::hvconfig::{
+        ConfigError, HvConfig, CONFIG_FLAG_FULL_TLB_FLUSHES, CONFIG_FLAG_LOG_EPT_HOOKS, CONFIG_FLAG_PATCHED, CONFIG_SECTION, CONFIG_SIZE,
+    },
+};
+
+/// Reasons why the configuration section is not patched.
+#[derive(Debug, PartialEq)]
+pub(crate) enum PatchError {
+    /// The image has no configuration section.
+    MissingSection,
+
+    /// The section doesn't hold a blob of the layout known to the loader.
+    Invalid(ConfigError),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::MissingSection => write!(f, "the image has no {} section", CONFIG_SECTION),
+            PatchError::Invalid(ConfigError::TooSmall(size)) => write!(f, "the {} section is only {} bytes", CONFIG_SECTION, size),
+            PatchError::Invalid(ConfigError::BadMagic(_)) => write!(f, "the {} section does not start with the configuration magic", CONFIG_SECTION),
+            PatchError::Invalid(ConfigError::UnsupportedVersion(version)) => {
+                write!(f, "the {} section has unknown version {}", CONFIG_SECTION, version)
+            }
+            PatchError::Invalid(ConfigError::SizeMismatch(size)) => {
+                write!(f, "the {} section holds {} bytes, expected {}", CONFIG_SECTION, size, CONFIG_SIZE)
+            }
+        }
+    }
+}
+
+/// Returns whether the configuration sets any of the `hv_*` keys.
+pub(crate) fn is_requested(config: &LoaderConfig) -> bool {
+    config.hv_log_level.is_some()
+        || config.hv_log_ept_hooks.is_some()
+        || config.hv_full_tlb_flushes.is_some()
+        || config.hv_vmcall_key.is_some()
+        || config.hv_tsc_skew_limit.is_some()
+}
+
+/// Applies the `hv_*` keys to the blob found in the image.
+pub(crate) fn apply_overrides(mut blob: HvConfig, config: &LoaderConfig) -> HvConfig {
+    if let Some(level) = config.hv_log_level {
+        // `LevelFilter` and `shared::hvconfig::LOG_LEVEL_*` number the levels the same way.
+        blob.log_level = level as u32;
+    }
+
+    match config.hv_log_ept_hooks {
+        Some(true) => blob.flags |= CONFIG_FLAG_LOG_EPT_HOOKS,
+        Some(false) => blob.flags &= !CONFIG_FLAG_LOG_EPT_HOOKS,
+        None => {}
+    }
+
+    match config.hv_full_tlb_flushes {
+        Some(true) => blob.flags |= CONFIG_FLAG_FULL_TLB_FLUSHES,
+        Some(false) => blob.flags &= !CONFIG_FLAG_FULL_TLB_FLUSHES,
+        None => {}
+    }
+
+    if let Some(key) = config.hv_vmcall_key {
+        blob.vmcall_key = key;
+    }
+
+    if let Some(cycles) = config.hv_tsc_skew_limit {
+        blob.tsc_skew_limit = cycles;
+    }
+
+    blob.flags |= CONFIG_FLAG_PATCHED;
+    blob
+}
+
+/// Overwrites the configuration blob of an image with the `hv_*` keys.
+///
+/// # Arguments
+///
+/// * `image` - The raw bytes of the image, changed only if the blob is valid.
+/// * `config` - The loader configuration.
+///
+/// # Returns
+///
+/// The blob written, or why the image was left unmodified.
+pub(crate) fn patch(image: &mut [u8], config: &LoaderConfig) -> Result<HvConfig, PatchError> {
+    let section = pe::find_section(image, CONFIG_SECTION).ok_or(PatchError::MissingSection)?;
+    let blob = &mut image[section];
+
+    let patched = apply_overrides(HvConfig::from_bytes(blob).map_err(PatchError::Invalid)?, config);
+    blob[..CONFIG_SIZE].copy_from_slice(&patched.to_bytes());
+    Ok(patched)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        shared::{
+            hvconfig::{LOG_LEVEL_DEBUG, LOG_LEVEL_OFF, LOG_LEVEL_TRACE},
+            PASSWORD,
+        },
+    };
+
+    /// A minimal EFI runtime driver with a `.text` section and a `.ilcfg` section at file offset 0x400.
+    const FIXTURE: &[u8] = include_bytes!("../tests/fixtures/ilcfg.efi");
+
+    /// File offset of the configuration blob in the fixture.
+    const BLOB_OFFSET: usize = 0x400;
+
+    fn configured(text: &str) -> LoaderConfig {
+        LoaderConfig::parse(text.as_bytes())
+    }
+
+    #[test]
+    fn fixture_carries_the_default_blob() {
+        assert!(pe::validate(FIXTURE).is_ok());
+        assert_eq!(pe::find_section(FIXTURE, CONFIG_SECTION), Some(BLOB_OFFSET..BLOB_OFFSET + 0x200));
+        assert_eq!(HvConfig::from_bytes(&FIXTURE[BLOB_OFFSET..]), Ok(HvConfig::DEFAULT));
+    }
+
+    #[test]
+    fn patch_overwrites_only_the_blob() {
+        let mut image = FIXTURE.to_vec();
+        let config = configured("hv_log_level = trace\nhv_log_ept_hooks = true\nhv_vmcall_key = 0x1234abcd\nhv_tsc_skew_limit = 5000\n");
+        assert!(is_requested(&config));
+
+        let patched = patch(&mut image, &config).unwrap();
+        assert_eq!((patched.log_level, patched.vmcall_key, patched.tsc_skew_limit), (LOG_LEVEL_TRACE, 0x1234abcd, 5000));
+        assert_eq!(patched.flags, CONFIG_FLAG_LOG_EPT_HOOKS | CONFIG_FLAG_PATCHED);
+        assert_eq!(HvConfig::from_bytes(&image[BLOB_OFFSET..]), Ok(patched));
+
+        assert_eq!(&image[..BLOB_OFFSET], &FIXTURE[..BLOB_OFFSET]);
+        assert_eq!(&image[BLOB_OFFSET + CONFIG_SIZE..], &FIXTURE[BLOB_OFFSET + CONFIG_SIZE..]);
+    }
+
+    #[test]
+    fn unset_keys_keep_the_built_in_values() {
+        let config = configured("hv_log_level = off\n");
+        let patched = apply_overrides(HvConfig::DEFAULT, &config);
+        assert_eq!((patched.log_level, patched.vmcall_key, patched.flags), (LOG_LEVEL_OFF, PASSWORD, CONFIG_FLAG_PATCHED));
+
+        assert!(!is_requested(&configured("")));
+        assert_eq!(HvConfig::DEFAULT.log_level, LOG_LEVEL_DEBUG);
+
+        let patched = apply_overrides(HvConfig::DEFAULT, &configured("hv_full_tlb_flushes = true\n"));
+        assert_eq!(patched.flags, CONFIG_FLAG_FULL_TLB_FLUSHES | CONFIG_FLAG_PATCHED);
+    }
+
+    #[test]
+    fn invalid_sections_are_left_alone() {
+        let mut image = FIXTURE.to_vec();
+        image[BLOB_OFFSET] = b'X';
+        let corrupted = image.clone();
+        let config = configured("hv_log_level = trace\n");
+        assert!(matches!(patch(&mut image, &config), Err(PatchError::Invalid(ConfigError::BadMagic(_)))));
+        assert_eq!(image, corrupted);
+        image[BLOB_OFFSET] = FIXTURE[BLOB_OFFSET];
+
+        // Renaming the section hides it from the loader.
+        let name = image.windows(6).position(|window| window == b".ilcfg").unwrap();
+        image[name + 1] = b'x';
+        assert_eq!(patch(&mut image, &config), Err(PatchError::MissingSection));
+        assert_eq!(&image[BLOB_OFFSET..], &FIXTURE[BLOB_OFFSET..]);
+    }
+}
@@ -0,0 +1,114 @@
+//! The ring the hypervisor copies its log to while it starts, see `shared::logring`.
+//!
+//! The hypervisor logs to the console while it starts, where a failure scrolls its messages out of sight
+//! and the loader's log file never contains them. The loader allocates the ring before starting the
+//! hypervisor and prints its lines with a `[hv]` prefix once `start_image` returned, whether the hypervisor
+//! started or not. The hypervisor stops writing to the ring before its entry point returns, so the pages
+//! are freed right after.
+
+use {
+    crate::handoff,
+    core::slice,
+    shared::logring::{LogRing, MAX_LINE_SIZE},
+    uefi::{
+        prelude::*,
+        table::boot::{AllocateType, MemoryType},
+    },
+};
+
+/// Number of pages of the ring, room for a few hundred lines.
+const LOG_RING_PAGES: usize = 16;
+
+/// Size of the ring memory in bytes.
+const LOG_RING_SIZE: usize = LOG_RING_PAGES * 4096;
+
+/// The ring allocated for one start of the hypervisor.
+pub(crate) struct HvLog {
+    address: u64,
+}
+
+impl HvLog {
+    /// Allocates and creates an empty ring.
+    ///
+    /// The pages are runtime services data, like the handoff pointing to them.
+    ///
+    /// # Arguments
+    ///
+    /// * `boot_services` - A reference to the UEFI boot services.
+    pub(crate) fn allocate(boot_services: &BootServices) -> Option<Self> {
+        let address = boot_services
+            .allocate_pages(AllocateType::AnyPages, MemoryType::RUNTIME_SERVICES_DATA, LOG_RING_PAGES)
+            .ok()?;
+
+        let ring = Self { address };
+        LogRing::create(ring.memory()).ok()?;
+        Some(ring)
+    }
+
+    /// Returns the physical address of the ring.
+    pub(crate) fn address(&self) -> u64 {
+        self.address
+    }
+
+    /// Returns the size of the ring memory in bytes.
+    pub(crate) fn size(&self) -> usize {
+        LOG_RING_SIZE
+    }
+
+    /// Prints the lines the hypervisor left in the ring and frees it.
+    ///
+    /// # Arguments
+    ///
+    /// * `boot_services` - A reference to the UEFI boot services.
+    pub(crate) fn drain(self, boot_services: &BootServices) {
+        match LogRing::open(self.memory()) {
+            Ok(mut ring) => {
+                if ring.dropped() != 0 {
+                    log::warn!("[hv] {} earlier line(s) did not fit the log ring", ring.dropped());
+                }
+
+                let mut buffer = [0u8; MAX_LINE_SIZE];
+                while let Some(line) = ring.pop(&mut buffer) {
+                    let text = utf8_prefix(&buffer[..line.length]);
+                    match line.truncated {
+                        true => log::info!("[hv] {}..", text),
+                        false => log::info!("[hv] {}", text),
+                    }
+                }
+            }
+            Err(error) => log::warn!("Ignoring the hypervisor log ring: {:?}", error),
+        }
+
+        // The hypervisor doesn't touch the ring anymore, but the handoff shouldn't point to freed memory.
+        handoff::clear_log_ring();
+        if let Err(error) = unsafe { boot_services.free_pages(self.address, LOG_RING_PAGES) } {
+            log::debug!("Failed to free the hypervisor log ring ({:?})", error.status());
+        }
+    }
+
+    /// Returns the ring memory.
+    fn memory(&self) -> &'static mut [u8] {
+        // Safety: the pages are owned by `self` until `drain` frees them, and the hypervisor only writes to
+        // them while `start_image` runs.
+        unsafe { slice::from_raw_parts_mut(self.address as *mut u8, LOG_RING_SIZE) }
+    }
+}
+
+/// Returns the longest valid UTF-8 prefix, cutting a line may split a character.
+fn utf8_prefix(bytes: &[u8]) -> &str {
+    match core::str::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(error) => core::str::from_utf8(&bytes[..error.valid_up_to()]).unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::utf8_prefix;
+
+    #[test]
+    fn split_characters_are_dropped() {
+        assert_eq!(utf8_prefix(b"VMXON failed"), "VMXON failed");
+        assert_eq!(utf8_prefix(&"CPU \u{b5}code".as_bytes()[..5]), "CPU ");
+    }
+}
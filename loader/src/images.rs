@@ -1,68 +1,432 @@
 extern crate alloc;
 
 use {
-    alloc::{borrow::ToOwned, boxed::Box, vec::Vec},
+    crate::{bootvars::BootEntry, devpath, preflight::CpuVendor, retry},
+    alloc::{
+        borrow::ToOwned,
+        boxed::Box,
+        string::{String, ToString},
+        vec::Vec,
+    },
+    core::{
+        ffi::c_void,
+        fmt::{self, Display},
+        ptr,
+        sync::atomic::{AtomicPtr, Ordering},
+    },
     uefi::{
         prelude::*,
         proto::{
             device_path::{
                 build::{media::FilePath, DevicePathBuilder},
-                DevicePath,
+                media::PartitionSignature,
+                DevicePath, DevicePathNodeEnum, DeviceType,
             },
+            loaded_image::LoadedImage,
             media::{
-                file::{File, FileAttribute, FileMode},
+                block::BlockIO,
+                file::{Directory, File, FileAttribute, FileInfo, FileMode, FileSystemVolumeLabel},
                 fs::SimpleFileSystem,
+                partition::{GptPartitionType, PartitionInfo},
             },
         },
-        table::boot::{HandleBuffer, SearchType},
-        CStr16, Identify,
+        table::boot::{EventType, HandleBuffer, OpenProtocolAttributes, OpenProtocolParams, SearchType, TimerTrigger, Tpl},
+        CStr16, CString16, Event, Guid, Identify,
     },
 };
 
-const WINDOWS_BOOT_MANAGER_PATH: &CStr16 = cstr16!(r"\EFI\Microsoft\Boot\bootmgfw.efi");
-const HYPERVISOR_PATH: &CStr16 = cstr16!(r"\EFI\Boot\illusion.efi");
+/// Images chainloaded when `illusion.cfg` does not configure any, in order of preference.
+pub(crate) const DEFAULT_CHAINLOAD_PATHS: [&CStr16; 1] = [cstr16!(r"\EFI\Microsoft\Boot\bootmgfw.efi")];
+
+/// Directory whose vendor subdirectories are searched when `deep_scan` is enabled.
+pub(crate) const DEEP_SCAN_DIR: &CStr16 = cstr16!(r"\EFI");
+
+/// Hypervisor image for each processor vendor. The first entry doubles as the default for unknown vendors.
+pub(crate) const HYPERVISOR_PATHS: [(CpuVendor, &CStr16); 2] = [
+    // Safety: `to_ucs2` only encodes ASCII without NUL characters and terminates the string.
+    (CpuVendor::Intel, unsafe { CStr16::from_u16_with_nul_unchecked(&INTEL_HYPERVISOR_PATH) }),
+    (CpuVendor::Amd, unsafe { CStr16::from_u16_with_nul_unchecked(&AMD_HYPERVISOR_PATH) }),
+];
+
+/// `shared::HYPERVISOR_PATH` as UCS-2.
+const INTEL_HYPERVISOR_PATH: [u16; shared::HYPERVISOR_PATH.len() + 1] = shared::to_ucs2(shared::HYPERVISOR_PATH);
+
+/// `shared::HYPERVISOR_SVM_PATH` as UCS-2.
+const AMD_HYPERVISOR_PATH: [u16; shared::HYPERVISOR_SVM_PATH.len() + 1] = shared::to_ucs2(shared::HYPERVISOR_SVM_PATH);
+
+/// Upper bound for files read with `read_file`, far above any image the loader deals with.
+pub(crate) const MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Size of the chunks `read_file` reads at once.
+const READ_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Reasons why `read_file` could not return the contents of a file.
+#[derive(Debug, PartialEq)]
+pub(crate) enum ReadError {
+    /// The file does not exist.
+    NotFound,
+
+    /// The path names a directory.
+    IsDirectory,
+
+    /// The path names a file where a directory is expected.
+    NotADirectory,
+
+    /// The file is larger than `MAX_FILE_SIZE`.
+    TooLarge(u64),
+
+    /// The firmware failed to open or read the volume or file.
+    Read(Status),
+}
+
+impl Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::NotFound => write!(f, "file not found"),
+            ReadError::IsDirectory => write!(f, "path is a directory"),
+            ReadError::NotADirectory => write!(f, "path is not a directory"),
+            ReadError::TooLarge(size) => write!(f, "file is {} bytes, more than the {} bytes limit", size, MAX_FILE_SIZE),
+            ReadError::Read(status) => write!(f, "read error {:?}", status),
+        }
+    }
+}
+
+impl From<uefi::Error> for ReadError {
+    fn from(error: uefi::Error) -> Self {
+        match error.status() {
+            Status::NOT_FOUND => ReadError::NotFound,
+            status => ReadError::Read(status),
+        }
+    }
+}
+
+/// Default time a single volume may take to be probed before it is skipped.
+pub(crate) const DEFAULT_PROBE_TIMEOUT_MS: u64 = 2_000;
+
+/// Number of slow handles remembered by `SLOW_HANDLES`.
+const MAX_SLOW_HANDLES: usize = 16;
+
+/// Handles that exceeded the probe budget once and are skipped by every later search, so that a broken
+/// device only stalls the boot once.
+static SLOW_HANDLES: [AtomicPtr<c_void>; MAX_SLOW_HANDLES] = [const { AtomicPtr::new(ptr::null_mut()) }; MAX_SLOW_HANDLES];
+
+/// Controls which volumes are probed while searching for files.
+#[derive(Clone, Copy)]
+pub(crate) struct SearchOptions {
+    /// How long probing a single volume may take before it is skipped, `0` disables the check.
+    pub probe_timeout_ms: u64,
+
+    /// Don't probe volumes on removable media.
+    pub skip_removable: bool,
+
+    /// How often opening a file is attempted when the volume reports it is not ready.
+    pub attempts: u32,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        Self {
+            probe_timeout_ms: DEFAULT_PROBE_TIMEOUT_MS,
+            skip_removable: false,
+            attempts: retry::DEFAULT_ATTEMPTS,
+        }
+    }
+}
+
+/// Timer measuring whether probing a volume exceeded the budget.
+///
+/// Boot services are synchronous, so a hanging call can't be interrupted. The timer reports afterwards
+/// that the budget was exceeded, and the handle is skipped from then on.
+pub(crate) struct ProbeTimer<'a> {
+    boot_services: &'a BootServices,
+    event: Option<Event>,
+}
+
+impl<'a> ProbeTimer<'a> {
+    /// Starts the timer, or returns an inactive timer if `timeout_ms` is `0` or the timer can't be created.
+    pub(crate) fn start(boot_services: &'a BootServices, timeout_ms: u64) -> Self {
+        let event = match timeout_ms {
+            0 => None,
+            // SAFETY: The event has no notification function and is closed when the timer is dropped.
+            _ => unsafe { boot_services.create_event(EventType::TIMER, Tpl::CALLBACK, None, None) }
+                .ok()
+                .filter(|event| boot_services.set_timer(event, TimerTrigger::Relative(timeout_ms * 10_000)).is_ok()),
+        };
+
+        Self { boot_services, event }
+    }
+
+    /// Returns whether the budget was exceeded.
+    pub(crate) fn expired(&self) -> bool {
+        match &self.event {
+            // SAFETY: The clone is only used for the check, the event stays owned by the timer.
+            Some(event) => self.boot_services.check_event(unsafe { event.unsafe_clone() }).unwrap_or(false),
+            None => false,
+        }
+    }
+}
+
+impl Drop for ProbeTimer<'_> {
+    fn drop(&mut self) {
+        if let Some(event) = self.event.take() {
+            let _ = self.boot_services.close_event(event);
+        }
+    }
+}
+
+/// Returns whether a handle exceeded the probe budget before.
+fn is_slow(handle: Handle) -> bool {
+    SLOW_HANDLES.iter().any(|slow| slow.load(Ordering::Relaxed) == handle.as_ptr())
+}
+
+/// Remembers a handle that exceeded the probe budget.
+fn mark_slow(handle: Handle) {
+    for slow in SLOW_HANDLES.iter() {
+        if slow
+            .compare_exchange(ptr::null_mut(), handle.as_ptr(), Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            return;
+        }
+    }
+}
+
+/// Reasons why searching the filesystems for a file failed, as opposed to the file not being present.
+#[derive(Debug, PartialEq)]
+pub(crate) enum ImageError {
+    /// The firmware exposes no `SimpleFileSystem` handles at all.
+    NoFileSystems,
+
+    /// Locating the `SimpleFileSystem` handles failed.
+    LocateFailed(Status),
+
+    /// The file was found on the volume with the given handle index, but the volume has no device path.
+    DevicePathUnavailable(usize),
+
+    /// The file was found on the volume with the given handle index, but its device path can't be built.
+    DevicePathBuildFailed(usize),
+}
+
+impl Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::NoFileSystems => write!(f, "no SimpleFileSystem handles; is the ESP accessible?"),
+            ImageError::LocateFailed(status) => write!(f, "failed to locate SimpleFileSystem handles ({:?})", status),
+            ImageError::DevicePathUnavailable(handle) => write!(f, "file found on handle {}, but the volume has no device path", handle),
+            ImageError::DevicePathBuildFailed(handle) => write!(f, "file found on handle {}, but its device path can't be built", handle),
+        }
+    }
+}
 
 /// Represents a bootable target discovered on a specific filesystem handle.
 pub(crate) struct BootTarget {
     pub device_path: Box<DevicePath>,
+    pub device_path_text: String,
+    pub path: CString16,
     pub handle: Handle,
     pub handle_index: usize,
+    pub is_removable: bool,
+    pub is_partition: bool,
+    pub size_bytes: u64,
+    pub volume_label: Option<String>,
+    pub partition_guid: Option<Guid>,
+    pub partition_type: Option<Guid>,
+
+    /// The firmware boot entry the target was taken from, if it was not found through a chainload path.
+    pub boot_entry: Option<BootEntry>,
+}
+
+impl BootTarget {
+    /// Returns whether the target is on an EFI system partition.
+    pub(crate) fn is_esp(&self) -> bool {
+        self.partition_type == Some(GptPartitionType::EFI_SYSTEM_PARTITION.0)
+    }
+
+    /// Returns the key identifying the volume the target is on.
+    pub(crate) fn volume_key(&self) -> VolumeKey {
+        match self.partition_guid {
+            Some(guid) => VolumeKey::Gpt(guid.to_bytes()),
+            None => volume_key(self.device_path.as_bytes()),
+        }
+    }
+
+    /// Describes the target for the selection menu, e.g.
+    /// `SYSTEM (ESP, 260 MiB, GUID 1f2a3b4c..., handle 3): \EFI\Microsoft\Boot\bootmgfw.efi`.
+    pub(crate) fn describe(&self) -> String {
+        let kind = match (self.is_esp(), self.is_partition) {
+            (true, _) => "ESP",
+            (false, true) => "partition",
+            (false, false) => "whole-disk",
+        };
+
+        let mut details = alloc::format!("{}, {} MiB", kind, self.size_bytes / (1024 * 1024));
+        if self.is_removable {
+            details.push_str(", removable");
+        }
+        if let Some(guid) = self.partition_guid {
+            let guid = guid.to_string();
+            details = alloc::format!("{}, GUID {}...", details, &guid[..8]);
+        }
+
+        let description = match &self.volume_label {
+            Some(label) => alloc::format!("{} ({}, handle {}): {}", label, details, self.handle_index, self.path),
+            None => alloc::format!("handle {} ({}): {}", self.handle_index, details, self.path),
+        };
+
+        match &self.boot_entry {
+            Some(entry) => alloc::format!("{} {} - {}", entry.name(), entry.option.description, description),
+            None => description,
+        }
+    }
 }
 
-/// Finds the device path for a given file path and returns the first match.
+/// Media details reported by the `BlockIO` protocol of a filesystem handle.
+struct MediaInfo {
+    is_removable: bool,
+    is_partition: bool,
+    size_bytes: u64,
+}
+
+/// Finds the first filesystem containing a given file path.
 ///
 /// # Arguments
 ///
 /// * `boot_services` - A reference to the UEFI boot services.
+/// * `options` - How the volumes are probed.
 /// * `path` - The file path to search for, as a `CStr16`.
 ///
 /// # Returns
 ///
-/// If a device containing the specified file is found, this function returns an `Option` containing
-/// a `DevicePath` to the file. If no such device is found, it returns `None`.
-pub(crate) fn find_device_path(boot_services: &BootServices, path: &CStr16) -> Option<Box<DevicePath>> {
-    enumerate_device_paths(boot_services, path).into_iter().map(|t| t.device_path).next()
+/// The first `BootTarget` containing the file, `None` if no volume contains it, or why the search failed.
+pub(crate) fn find_target(boot_services: &BootServices, options: SearchOptions, path: &CStr16) -> Result<Option<BootTarget>, ImageError> {
+    Ok(enumerate_device_paths(boot_services, options, path)?.into_iter().next())
 }
 
 /// Enumerates all device paths for a given file path across all SimpleFileSystem handles.
-pub(crate) fn enumerate_device_paths(boot_services: &BootServices, path: &CStr16) -> Vec<BootTarget> {
-    let handles: HandleBuffer = match boot_services.locate_handle_buffer(SearchType::ByProtocol(&SimpleFileSystem::GUID)) {
-        Ok(h) => {
-            log::info!("Discovered {} SimpleFileSystem handle(s) while searching for the target path", h.len());
-            h
+///
+/// An empty `Vec` means that the search succeeded, but no volume contains the file.
+pub(crate) fn enumerate_device_paths(boot_services: &BootServices, options: SearchOptions, path: &CStr16) -> Result<Vec<BootTarget>, ImageError> {
+    let what = alloc::format!("open {}", path);
+    let open =
+        |root: &mut Directory| retry::retry(boot_services, options.attempts, &what, || root.open(path, FileMode::Read, FileAttribute::READ_ONLY));
+
+    enumerate_volumes(boot_services, options, |root, idx1| match open(root) {
+        Ok(_) => {
+            log::debug!("Target file exists on handle {}", idx1);
+            alloc::vec![CString16::from(path)]
         }
-        Err(_) => {
-            log::error!("Failed to locate handles for SimpleFileSystem protocol");
+        // Some filesystem drivers match names case-sensitively, retry by walking the directories.
+        Err(_) => match resolve_path(root, &path.to_string()).and_then(|resolved| CString16::try_from(resolved.as_str()).ok()) {
+            Some(resolved) => {
+                log::debug!("Target file exists on handle {} as {}", idx1, resolved);
+                alloc::vec![resolved]
+            }
+            None => {
+                log::debug!("Target file not found on handle {}", idx1);
+                Vec::new()
+            }
+        },
+    })
+}
+
+/// Enumerates `<dir>\<vendor>\<filename>` for every subdirectory of `dir` across all SimpleFileSystem handles.
+///
+/// Only one directory level below `dir` is searched, which covers the `\EFI\<vendor>` layout without walking
+/// large trees.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+/// * `options` - How the volumes are probed.
+/// * `dir` - The directory whose subdirectories are searched, usually `\EFI`.
+/// * `filename` - The file name to look for in each subdirectory, matched ignoring case.
+///
+/// # Returns
+///
+/// A `BootTarget` for every file found, carrying the discovered full path, or why the search failed.
+pub(crate) fn enumerate_by_pattern(
+    boot_services: &BootServices,
+    options: SearchOptions,
+    dir: &CStr16,
+    filename: &str,
+) -> Result<Vec<BootTarget>, ImageError> {
+    let dir = dir.to_string();
+
+    enumerate_volumes(boot_services, options, |root, idx1| {
+        let Some(dir) = resolve_path(root, &dir) else {
+            log::debug!("No {} directory on handle {}", dir, idx1);
             return Vec::new();
-        }
-    };
+        };
 
-    let mut targets = Vec::new();
+        let Some(vendors) = root.list(&dir) else {
+            log::debug!("Failed to list {} on handle {}", dir, idx1);
+            return Vec::new();
+        };
+
+        vendors
+            .iter()
+            .filter(|vendor| *vendor != "." && *vendor != "..")
+            .filter_map(|vendor| {
+                let vendor_dir = alloc::format!("{}\\{}", dir, vendor);
+                let entries = root.list(&vendor_dir)?;
+                let found = alloc::format!("{}\\{}", vendor_dir, match_component(&entries, filename)?);
+                log::debug!("Found {} on handle {}", found, idx1);
+                CString16::try_from(found.as_str()).ok()
+            })
+            .collect()
+    })
+}
+
+/// Builds a `BootTarget` for every file `find` reports on each SimpleFileSystem handle.
+///
+/// Handles exposing a volume that was already seen through a lower handle are skipped.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+/// * `options` - How the volumes are probed.
+/// * `find` - Returns the paths of the files found on a volume, given its root directory and handle index.
+///
+/// # Returns
+///
+/// All targets found, or the first failure if files were found but no target could be built from them.
+fn enumerate_volumes(
+    boot_services: &BootServices,
+    options: SearchOptions,
+    mut find: impl FnMut(&mut Directory, usize) -> Vec<CString16>,
+) -> Result<Vec<BootTarget>, ImageError> {
+    let handles = locate_volumes(boot_services)?;
+    log::info!("Discovered {} SimpleFileSystem handle(s) while searching for the target path", handles.len());
+
+    let mut targets: Vec<BootTarget> = Vec::new();
+    let mut keys: Vec<(VolumeKey, usize)> = Vec::new();
+    let mut failure = None;
 
     for (idx, handle) in handles.iter().enumerate() {
         let idx1 = idx + 1;
         log::debug!("Checking handle {}/{}", idx1, handles.len());
 
+        if is_slow(*handle) {
+            log::debug!("Skipping handle {}, it exceeded the probe budget before", idx1);
+            continue;
+        }
+
+        let media = query_media(boot_services, *handle).unwrap_or_else(|| {
+            log::debug!("BlockIO not available for handle {}, assuming fixed whole-disk media", idx1);
+            MediaInfo {
+                is_removable: false,
+                is_partition: false,
+                size_bytes: 0,
+            }
+        });
+
+        if options.skip_removable && media.is_removable {
+            log::debug!("Skipping handle {} on removable media", idx1);
+            continue;
+        }
+
+        let timer = ProbeTimer::start(boot_services, options.probe_timeout_ms);
+
         let mut file_system = match boot_services.open_protocol_exclusive::<SimpleFileSystem>(*handle) {
             Ok(fs) => fs,
             Err(_) => {
@@ -79,82 +443,887 @@ pub(crate) fn enumerate_device_paths(boot_services: &BootServices, path: &CStr16
             }
         };
 
-        match root.open(path, FileMode::Read, FileAttribute::READ_ONLY) {
-            Ok(_) => {
-                log::debug!("Target file exists on handle {}", idx1);
-            }
-            Err(_) => {
-                log::debug!("Target file not found on handle {}", idx1);
-                continue;
-            }
+        let found_paths = find(&mut root, idx1);
+
+        if timer.expired() {
+            log::warn!("Handle {} took longer than {} ms to probe, skipping it", idx1, options.probe_timeout_ms);
+            mark_slow(*handle);
+            continue;
+        }
+
+        if found_paths.is_empty() {
+            continue;
         }
 
         let device_path = match boot_services.open_protocol_exclusive::<DevicePath>(*handle) {
             Ok(dp) => dp,
             Err(_) => {
                 log::debug!("open_protocol(DevicePath) failed for handle {}", idx1);
+                failure.get_or_insert(ImageError::DevicePathUnavailable(idx1));
                 continue;
             }
         };
 
-        let mut storage = Vec::new();
-        let builder = DevicePathBuilder::with_vec(&mut storage);
-        let builder = device_path.node_iter().fold(builder, |builder, item| builder.push(&item).unwrap());
-
-        let boot_path = match builder.push(&FilePath { path_name: path }).ok().and_then(|b| b.finalize().ok()) {
-            Some(p) => p,
-            None => {
-                log::debug!("Failed to build final device path for handle {}", idx1);
-                continue;
+        let volume_label = match root.get_boxed_info::<FileSystemVolumeLabel>() {
+            Ok(info) => Some(info.volume_label().to_string()).filter(|label| !label.trim().is_empty()),
+            Err(error) => {
+                log::debug!("Failed to read volume label on handle {} ({:?})", idx1, error.status());
+                None
             }
         };
 
-        log::info!("Discovered target on handle {}/{}", idx1, handles.len());
-        targets.push(BootTarget {
-            device_path: boot_path.to_owned(),
-            handle: *handle,
-            handle_index: idx1,
-        });
+        let (partition_guid, partition_type) = match query_gpt_partition(boot_services, *handle) {
+            Some((guid, partition_type)) => (Some(guid), Some(partition_type)),
+            None => (partition_guid(&device_path), None),
+        };
+
+        let key = match partition_guid {
+            Some(guid) => VolumeKey::Gpt(guid.to_bytes()),
+            None => volume_key(device_path.as_bytes()),
+        };
+
+        if let Some((_, existing)) = keys.iter().find(|(existing, _)| *existing == key) {
+            log::debug!("Handle {} resolves to the same volume as handle {}, merging duplicate candidates", idx1, existing);
+            continue;
+        }
+        keys.push((key, idx1));
+
+        for found_path in found_paths {
+            let mut storage = Vec::new();
+            let builder = DevicePathBuilder::with_vec(&mut storage);
+            let builder = device_path.node_iter().fold(builder, |builder, item| builder.push(&item).unwrap());
+
+            let boot_path = match builder.push(&FilePath { path_name: &found_path }).ok().and_then(|b| b.finalize().ok()) {
+                Some(p) => p,
+                None => {
+                    log::debug!("Failed to build final device path for handle {}", idx1);
+                    failure.get_or_insert(ImageError::DevicePathBuildFailed(idx1));
+                    continue;
+                }
+            };
+
+            let device_path_text = devpath::to_text(boot_services, boot_path);
+            log::info!("Discovered target {} on handle {}/{}: {}", found_path, idx1, handles.len(), device_path_text);
+            targets.push(BootTarget {
+                device_path: boot_path.to_owned(),
+                device_path_text,
+                path: found_path,
+                handle: *handle,
+                handle_index: idx1,
+                is_removable: media.is_removable,
+                is_partition: media.is_partition,
+                size_bytes: media.size_bytes,
+                volume_label: volume_label.clone(),
+                partition_guid,
+                partition_type,
+                boot_entry: None,
+            });
+        }
     }
 
     if targets.is_empty() {
         log::debug!("No device paths found for target");
+        if let Some(failure) = failure {
+            return Err(failure);
+        }
     } else {
         log::info!("Found {} candidate target(s)", targets.len());
     }
 
-    targets
+    Ok(targets)
+}
+
+/// Locates all handles supporting the `SimpleFileSystem` protocol.
+pub(crate) fn locate_volumes(boot_services: &BootServices) -> Result<HandleBuffer, ImageError> {
+    match boot_services.locate_handle_buffer(SearchType::ByProtocol(&SimpleFileSystem::GUID)) {
+        Ok(handles) if handles.is_empty() => Err(ImageError::NoFileSystems),
+        Ok(handles) => Ok(handles),
+        Err(error) if error.status() == Status::NOT_FOUND => Err(ImageError::NoFileSystems),
+        Err(error) => {
+            log::error!("Failed to locate handles for SimpleFileSystem protocol");
+            Err(ImageError::LocateFailed(error.status()))
+        }
+    }
+}
+
+/// Connects all controllers recursively, so that drivers bind to devices that appeared after the firmware
+/// connected the boot devices.
+pub(crate) fn connect_controllers(boot_services: &BootServices) {
+    let handles = match boot_services.locate_handle_buffer(SearchType::AllHandles) {
+        Ok(handles) => handles,
+        Err(error) => {
+            log::debug!("Failed to locate handles to connect ({:?})", error.status());
+            return;
+        }
+    };
+
+    // Most handles have no driver to connect and report NOT_FOUND, which is expected.
+    let connected = handles
+        .iter()
+        .filter(|handle| boot_services.connect_controller(**handle, None, None, true).is_ok())
+        .count();
+    log::debug!("Connected {} of {} handle(s)", connected, handles.len());
+}
+
+/// Returns the number of volumes searched for files, used to report files that are present on none of them.
+pub(crate) fn volume_count(boot_services: &BootServices) -> Result<usize, ImageError> {
+    Ok(locate_volumes(boot_services)?.len())
+}
+
+/// Source of directory listings used to resolve paths on filesystems that match names case-sensitively.
+pub(crate) trait DirectoryListing {
+    /// Lists the entry names of the directory at `path`, `""` being the root.
+    ///
+    /// # Returns
+    ///
+    /// The names of all entries, or `None` if the directory can't be opened or read.
+    fn list(&mut self, path: &str) -> Option<Vec<String>>;
+}
+
+impl DirectoryListing for Directory {
+    fn list(&mut self, path: &str) -> Option<Vec<String>> {
+        if path.is_empty() {
+            self.reset_entry_readout().ok()?;
+            return read_entry_names(self);
+        }
+
+        let path = CString16::try_from(path).ok()?;
+        let mut directory = self.open(&path, FileMode::Read, FileAttribute::READ_ONLY).ok()?.into_directory()?;
+        read_entry_names(&mut directory)
+    }
+}
+
+/// Reads the names of all remaining entries of a directory.
+fn read_entry_names(directory: &mut Directory) -> Option<Vec<String>> {
+    let mut names = Vec::new();
+    while let Some(info) = directory.read_entry_boxed().ok()? {
+        names.push(info.file_name().to_string());
+    }
+    Some(names)
+}
+
+/// Splits a path into its components, skipping empty components left by leading or repeated separators.
+pub(crate) fn path_components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('\\').filter(|component| !component.is_empty())
+}
+
+/// Finds the directory entry matching a path component.
+///
+/// # Arguments
+///
+/// * `entries` - The entry names of the directory.
+/// * `component` - The path component to look for.
+///
+/// # Returns
+///
+/// The exactly matching entry if there is one, otherwise the first entry that matches ignoring case.
+pub(crate) fn match_component<'a>(entries: &'a [String], component: &str) -> Option<&'a str> {
+    let ignoring_case = |entry: &&String| {
+        entry
+            .chars()
+            .flat_map(char::to_lowercase)
+            .eq(component.chars().flat_map(char::to_lowercase))
+    };
+
+    entries
+        .iter()
+        .find(|entry| *entry == component)
+        .or_else(|| entries.iter().find(ignoring_case))
+        .map(String::as_str)
+}
+
+/// Resolves a path component by component, matching each one against the directory listing ignoring case.
+///
+/// # Arguments
+///
+/// * `listing` - The filesystem to resolve the path on.
+/// * `path` - The absolute path to resolve.
+///
+/// # Returns
+///
+/// The path with the spelling of the entries found on disk, or `None` if a component does not exist.
+pub(crate) fn resolve_path(listing: &mut impl DirectoryListing, path: &str) -> Option<String> {
+    let mut resolved = String::new();
+
+    for component in path_components(path) {
+        let entries = listing.list(&resolved)?;
+        let entry = match_component(&entries, component)?;
+        resolved.push('\\');
+        resolved.push_str(entry);
+    }
+
+    Some(resolved).filter(|resolved| !resolved.is_empty())
+}
+
+/// Identifies the volume behind a device path, used to detect handles exposing the same volume.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum VolumeKey {
+    /// The unique partition GUID of a GPT partition.
+    Gpt([u8; 16]),
+
+    /// The disk signature and partition number of an MBR partition.
+    Mbr(u32, u32),
+
+    /// The device path bytes without file path and end nodes, for volumes without a hard drive node.
+    Path(Vec<u8>),
+}
+
+/// Device path node type of media nodes.
+const MEDIA_DEVICE_PATH: u8 = 0x04;
+
+/// Media node subtype of hard drive (partition) nodes.
+const MEDIA_HARD_DRIVE: u8 = 0x01;
+
+/// Media node subtype of file path nodes.
+const MEDIA_FILE_PATH: u8 = 0x04;
+
+/// Device path node type of end nodes.
+const END_DEVICE_PATH: u8 = 0x7f;
+
+/// Normalizes the raw bytes of a device path into a key identifying its volume.
+///
+/// The partition signature of a hard drive node identifies the partition regardless of the hardware
+/// path it was reached through. Without one, the path itself is compared with file path and end nodes
+/// removed. Parsing stops at the first malformed node.
+///
+/// # Arguments
+///
+/// * `bytes` - The raw device path, as a sequence of nodes with a 4 byte header each.
+///
+/// # Returns
+///
+/// The key for the volume the device path points into.
+pub(crate) fn volume_key(bytes: &[u8]) -> VolumeKey {
+    let mut path = Vec::new();
+    let mut rest = bytes;
+
+    while rest.len() >= 4 {
+        let (node_type, subtype) = (rest[0], rest[1]);
+        let length = u16::from_le_bytes([rest[2], rest[3]]) as usize;
+        if length < 4 || length > rest.len() {
+            break;
+        }
+
+        let (node, next) = rest.split_at(length);
+        rest = next;
+
+        match (node_type, subtype) {
+            (END_DEVICE_PATH, _) | (MEDIA_DEVICE_PATH, MEDIA_FILE_PATH) => continue,
+            (MEDIA_DEVICE_PATH, MEDIA_HARD_DRIVE) if node.len() >= 42 => {
+                // Partition number (4), start (8), size (8), signature (16), MBR type (1), signature type (1).
+                let partition_number = u32::from_le_bytes(node[4..8].try_into().unwrap());
+                let signature: [u8; 16] = node[24..40].try_into().unwrap();
+                match node[41] {
+                    1 => return VolumeKey::Mbr(u32::from_le_bytes(signature[..4].try_into().unwrap()), partition_number),
+                    2 => return VolumeKey::Gpt(signature),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+
+        path.extend_from_slice(node);
+    }
+
+    VolumeKey::Path(path)
+}
+
+/// Reads the media details of a filesystem handle from its `BlockIO` protocol.
+///
+/// The protocol is only queried, not opened exclusively, as an exclusive open would disconnect the
+/// disk and filesystem drivers stacked on top of it.
+fn query_media(boot_services: &BootServices, handle: Handle) -> Option<MediaInfo> {
+    let params = OpenProtocolParams {
+        handle,
+        agent: boot_services.image_handle(),
+        controller: None,
+    };
+
+    // SAFETY: The protocol is only read while the handle is still installed, before any image is started.
+    let block_io = unsafe { boot_services.open_protocol::<BlockIO>(params, OpenProtocolAttributes::GetProtocol) }.ok()?;
+    let media = block_io.media();
+
+    Some(MediaInfo {
+        is_removable: media.is_removable_media(),
+        is_partition: media.is_logical_partition(),
+        size_bytes: media.last_block().saturating_add(1).saturating_mul(media.block_size() as u64),
+    })
+}
+
+/// Reads the unique and type GUIDs of a GPT partition from its `PartitionInfo` protocol.
+///
+/// The protocol was added in UEFI 2.7, so older firmware does not provide it.
+fn query_gpt_partition(boot_services: &BootServices, handle: Handle) -> Option<(Guid, Guid)> {
+    let params = OpenProtocolParams {
+        handle,
+        agent: boot_services.image_handle(),
+        controller: None,
+    };
+
+    // SAFETY: See `query_media`.
+    let partition_info = unsafe { boot_services.open_protocol::<PartitionInfo>(params, OpenProtocolAttributes::GetProtocol) }.ok()?;
+    let entry = *partition_info.gpt_partition_entry()?;
+
+    Some((entry.unique_partition_guid, entry.partition_type_guid.0))
+}
+
+/// Returns the key candidates are ordered by: fixed media before removable, partitions before whole
+/// disks, then by handle index.
+///
+/// # Arguments
+///
+/// * `is_removable` - Whether the candidate is on removable media.
+/// * `is_partition` - Whether the candidate is a partition rather than a whole disk.
+/// * `handle_index` - The 1-based index of the candidate's filesystem handle.
+fn boot_order(is_removable: bool, is_partition: bool, handle_index: usize) -> (bool, bool, usize) {
+    (is_removable, !is_partition, handle_index)
+}
+
+/// Sorts candidates so that internal disks come first and USB sticks or installation media last.
+pub(crate) fn sort_targets(targets: &mut [BootTarget]) {
+    targets.sort_by_key(|target| boot_order(target.is_removable, target.is_partition, target.handle_index));
+}
+
+/// Appends the targets of a rescan that are not among `targets` yet, comparing their volume and path.
+///
+/// # Returns
+///
+/// The number of targets added.
+pub(crate) fn merge_targets(targets: &mut Vec<BootTarget>, found: Vec<BootTarget>) -> usize {
+    let before = targets.len();
+
+    for target in found {
+        let key = target.volume_key();
+        if !targets.iter().any(|known| known.path == target.path && known.volume_key() == key) {
+            targets.push(target);
+        }
+    }
+
+    targets.len() - before
+}
+
+/// Finds all chainload targets across all attached filesystems.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+/// * `options` - How the volumes are probed.
+/// * `paths` - The image paths to search for, in order of preference.
+///
+/// # Returns
+///
+/// The candidates of every path, grouped by path in the given order and sorted with `sort_targets`
+/// within each group. The first candidate therefore belongs to the first path that was found.
+pub(crate) fn find_chainload_targets(
+    boot_services: &BootServices,
+    options: SearchOptions,
+    paths: &[CString16],
+) -> Result<Vec<BootTarget>, ImageError> {
+    let mut targets = Vec::new();
+
+    for path in paths {
+        let mut found = enumerate_device_paths(boot_services, options, path)?;
+        log::info!("Found {} candidate(s) for chainload target {}", found.len(), path);
+        sort_targets(&mut found);
+        targets.append(&mut found);
+    }
+
+    Ok(targets)
+}
+
+/// Finds the Illusion hypervisor image.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+/// * `options` - How the volumes are probed.
+/// * `path` - The hypervisor path to search for, usually from `HYPERVISOR_PATHS`.
+///
+/// # Returns
+///
+/// The preferred `BootTarget` containing the hypervisor, `None` if no volume contains it, or why the search failed.
+/// Copies on an EFI system partition are preferred over copies on other fixed disks, which are preferred over
+/// copies on removable media.
+pub(crate) fn find_hypervisor(boot_services: &BootServices, options: SearchOptions, path: &CStr16) -> Result<Option<BootTarget>, ImageError> {
+    let mut targets = enumerate_device_paths(boot_services, options, path)?;
+
+    let Some(preferred) = targets
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, target)| hypervisor_order(target.is_esp(), target.is_removable, target.handle_index))
+        .map(|(index, _)| index)
+    else {
+        return Ok(None);
+    };
+
+    if targets.len() > 1 {
+        log::warn!("Found {} copies of {}, stale copies may be picked up by mistake:", targets.len(), path);
+        for (index, target) in targets.iter().enumerate() {
+            let marker = if index == preferred { "using" } else { "ignoring" };
+            log::warn!("  {} {}", marker, target.describe());
+        }
+    }
+
+    Ok(Some(targets.swap_remove(preferred)))
 }
 
-/// Finds all device paths of the Windows boot manager across all attached filesystems.
-pub(crate) fn find_all_windows_boot_managers(boot_services: &BootServices) -> Vec<BootTarget> {
-    enumerate_device_paths(boot_services, WINDOWS_BOOT_MANAGER_PATH)
+/// Returns the key hypervisor copies are ranked by: copies on an EFI system partition first, then copies on
+/// fixed disks, then by handle index.
+///
+/// # Arguments
+///
+/// * `is_esp` - Whether the copy is on an EFI system partition.
+/// * `is_removable` - Whether the copy is on removable media.
+/// * `handle_index` - The 1-based index of the copy's filesystem handle.
+fn hypervisor_order(is_esp: bool, is_removable: bool, handle_index: usize) -> (bool, bool, usize) {
+    (!is_esp, is_removable, handle_index)
+}
+
+/// Returns the default hypervisor image path for a processor vendor.
+pub(crate) fn hypervisor_path(vendor: CpuVendor) -> &'static CStr16 {
+    HYPERVISOR_PATHS
+        .iter()
+        .find(|(candidate, _)| *candidate == vendor)
+        .map_or(HYPERVISOR_PATHS[0].1, |(_, path)| path)
 }
 
-/// Finds the device path of the Windows boot manager (first match).
+/// Finds the hypervisor image built for the given processor vendor.
+///
+/// If only the image for another vendor is present, a warning explains that the wrong binary is installed.
 ///
 /// # Arguments
 ///
 /// * `boot_services` - A reference to the UEFI boot services.
+/// * `options` - How the volumes are probed.
+/// * `vendor` - The vendor of the current processor.
 ///
 /// # Returns
 ///
-/// If a device containing the Windows boot manager is found, this function returns an `Option` containing
-/// a `DevicePath` to the file. If no such device is found, it returns `None`.
-pub(crate) fn find_windows_boot_manager(boot_services: &BootServices) -> Option<Box<DevicePath>> {
-    find_device_path(boot_services, WINDOWS_BOOT_MANAGER_PATH)
+/// The `BootTarget` of the vendor-specific image together with its path, `None` if it can't be found, or why
+/// the search failed.
+pub(crate) fn find_hypervisor_for_vendor(
+    boot_services: &BootServices,
+    options: SearchOptions,
+    vendor: CpuVendor,
+) -> Result<Option<(BootTarget, &'static CStr16)>, ImageError> {
+    let path = hypervisor_path(vendor);
+    log::info!("Using hypervisor image {} for {} processor", path, vendor.name());
+
+    if let Some(target) = find_hypervisor(boot_services, options, path)? {
+        return Ok(Some((target, path)));
+    }
+
+    for (other_vendor, other_path) in HYPERVISOR_PATHS.iter().filter(|(_, other_path)| *other_path != path) {
+        if let Ok(Some(_)) = find_target(boot_services, options, other_path) {
+            log::warn!(
+                "Found {} built for {} processors, but this is an {} processor and needs {}. The wrong hypervisor binary is installed.",
+                other_path,
+                other_vendor.name(),
+                vendor.name(),
+                path
+            );
+        }
+    }
+
+    Ok(None)
 }
 
-/// Finds the device path of the Illusion hypervisor.
+/// Finds the file names of the chainload paths in every vendor directory below `DEEP_SCAN_DIR`.
 ///
 /// # Arguments
 ///
 /// * `boot_services` - A reference to the UEFI boot services.
+/// * `options` - How the volumes are probed.
+/// * `paths` - The configured chainload paths, only their file names are used.
+/// * `known` - Candidates that were already found, which are not reported again.
 ///
 /// # Returns
 ///
-/// If a device containing the Illusion hypervisor is found, this function returns an `Option` containing
-/// a `DevicePath` to the file. If no such device is found, it returns `None`.
-pub(crate) fn find_hypervisor(boot_services: &BootServices) -> Option<Box<DevicePath>> {
-    find_device_path(boot_services, HYPERVISOR_PATH)
+/// The additional candidates, grouped by file name in the order of `paths` and sorted with `sort_targets`
+/// within each group, or why the search failed.
+pub(crate) fn find_deep_scan_targets(
+    boot_services: &BootServices,
+    options: SearchOptions,
+    paths: &[CString16],
+    known: &[BootTarget],
+) -> Result<Vec<BootTarget>, ImageError> {
+    let mut filenames: Vec<String> = Vec::new();
+    for path in paths {
+        let path = path.to_string();
+        if let Some(filename) = path_components(&path).last() {
+            if !filenames.iter().any(|known| known.eq_ignore_ascii_case(filename)) {
+                filenames.push(filename.to_string());
+            }
+        }
+    }
+
+    let mut targets = Vec::new();
+    for filename in filenames {
+        let mut found: Vec<BootTarget> = enumerate_by_pattern(boot_services, options, DEEP_SCAN_DIR, &filename)?
+            .into_iter()
+            .filter(|target| {
+                let path = target.path.to_string();
+                !known
+                    .iter()
+                    .any(|other| other.handle_index == target.handle_index && other.path.to_string().eq_ignore_ascii_case(&path))
+            })
+            .collect();
+        log::info!("Deep scan found {} additional candidate(s) for {}", found.len(), filename);
+        sort_targets(&mut found);
+        targets.append(&mut found);
+    }
+
+    Ok(targets)
+}
+
+/// Reads the whole contents of a file from the filesystem on the given handle.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+/// * `handle` - A handle supporting the `SimpleFileSystem` protocol.
+/// * `path` - The absolute path of the file on that filesystem.
+///
+/// # Returns
+///
+/// The raw file contents as stored on disk, or why they could not be read.
+pub(crate) fn read_file(boot_services: &BootServices, handle: Handle, path: &CStr16) -> Result<Vec<u8>, ReadError> {
+    let mut file_system = boot_services.open_protocol_exclusive::<SimpleFileSystem>(handle)?;
+    let mut root = file_system.open_volume()?;
+
+    let mut file = root
+        .open(path, FileMode::Read, FileAttribute::READ_ONLY)?
+        .into_regular_file()
+        .ok_or(ReadError::IsDirectory)?;
+
+    let size = file.get_boxed_info::<FileInfo>()?.file_size();
+    if size > MAX_FILE_SIZE {
+        return Err(ReadError::TooLarge(size));
+    }
+
+    let size = size as usize;
+    let mut bytes = alloc::vec![0u8; size];
+
+    // Some filesystem drivers return less than requested, keep reading until the end of the file.
+    let mut offset = 0;
+    while offset < size {
+        let end = size.min(offset + READ_CHUNK_SIZE);
+        match file.read(&mut bytes[offset..end])? {
+            0 => break,
+            read => offset += read,
+        }
+    }
+
+    bytes.truncate(offset);
+    Ok(bytes)
+}
+
+/// Reads the entries of a directory, skipping `.` and `..`.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+/// * `handle` - A handle supporting the `SimpleFileSystem` protocol.
+/// * `path` - The absolute path of the directory on that filesystem, `\` being the root.
+///
+/// # Returns
+///
+/// The entries in the order the filesystem returns them, or why they could not be read.
+pub(crate) fn list_directory(boot_services: &BootServices, handle: Handle, path: &CStr16) -> Result<Vec<Box<FileInfo>>, ReadError> {
+    let mut file_system = boot_services.open_protocol_exclusive::<SimpleFileSystem>(handle)?;
+    let mut root = file_system.open_volume()?;
+
+    let path = path.to_string();
+    let mut directory = match path_components(&path).next() {
+        None => root,
+        Some(_) => root
+            .open(&CString16::try_from(path.as_str()).map_err(|_| ReadError::NotFound)?, FileMode::Read, FileAttribute::READ_ONLY)?
+            .into_directory()
+            .ok_or(ReadError::NotADirectory)?,
+    };
+
+    let mut entries = Vec::new();
+    while let Some(info) = directory.read_entry_boxed()? {
+        let name = info.file_name().to_string();
+        if name != "." && name != ".." {
+            entries.push(info);
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Returns the GPT partition GUID of the partition a device path points into.
+///
+/// # Arguments
+///
+/// * `device_path` - The device path of a file or partition.
+///
+/// # Returns
+///
+/// The unique partition GUID from the hard drive media node, or `None` if the path contains no such node
+/// or the partition is not on a GPT disk.
+pub(crate) fn partition_guid(device_path: &DevicePath) -> Option<Guid> {
+    device_path.node_iter().find_map(|node| match node.as_enum() {
+        Ok(DevicePathNodeEnum::MediaHardDrive(hard_drive)) => match hard_drive.partition_signature() {
+            PartitionSignature::Guid(guid) => Some(guid),
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+/// Returns the device path of the device the loader image was loaded from.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+///
+/// # Returns
+///
+/// The device path of the loader's volume, or `None` if the firmware does not provide it.
+pub(crate) fn loader_device_path(boot_services: &BootServices) -> Option<Box<DevicePath>> {
+    let device = boot_services
+        .open_protocol_exclusive::<LoadedImage>(boot_services.image_handle())
+        .ok()?
+        .device()?;
+    let device_path = boot_services.open_protocol_exclusive::<DevicePath>(device).ok()?;
+
+    Some(device_path.to_owned())
+}
+
+/// Counts the leading hardware nodes two device paths have in common.
+///
+/// Media nodes (partitions, files) are not counted, so two partitions on the same disk share all of
+/// their hardware nodes.
+///
+/// # Arguments
+///
+/// * `a` - The first device path.
+/// * `b` - The second device path.
+///
+/// # Returns
+///
+/// The number of identical leading nodes before the first media node.
+pub(crate) fn common_hardware_prefix(a: &DevicePath, b: &DevicePath) -> usize {
+    a.node_iter()
+        .zip(b.node_iter())
+        .take_while(|(node_a, node_b)| node_a.device_type() != DeviceType::MEDIA && node_a == node_b)
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{
+            boot_order, hypervisor_order, match_component, path_components, resolve_path, volume_key, DirectoryListing, VolumeKey, HYPERVISOR_PATHS,
+        },
+        alloc::{
+            string::{String, ToString},
+            vec::Vec,
+        },
+        uefi::cstr16,
+    };
+
+    /// Directory listing backed by a fixed list of `(directory, entries)` pairs.
+    struct MockListing(Vec<(&'static str, Vec<&'static str>)>);
+
+    impl DirectoryListing for MockListing {
+        fn list(&mut self, path: &str) -> Option<Vec<String>> {
+            self.0
+                .iter()
+                .find(|(directory, _)| *directory == path)
+                .map(|(_, entries)| entries.iter().map(|entry| entry.to_string()).collect())
+        }
+    }
+
+    fn esp() -> MockListing {
+        MockListing(alloc::vec![
+            ("", alloc::vec!["efi", "System Volume Information"]),
+            (r"\efi", alloc::vec!["microsoft", "Boot"]),
+            (r"\efi\microsoft", alloc::vec!["boot", "Recovery"]),
+            (r"\efi\microsoft\boot", alloc::vec!["BCD", "BOOTMGFW.EFI"]),
+            (r"\efi\Boot", alloc::vec!["bootx64.efi"]),
+        ])
+    }
+
+    /// ACPI PciRoot(0x0) node.
+    const PCI_ROOT: [u8; 12] = [0x02, 0x01, 0x0c, 0x00, 0xd0, 0x41, 0x03, 0x0a, 0x00, 0x00, 0x00, 0x00];
+
+    /// Pci(0x1,0x1) node.
+    const PCI: [u8; 6] = [0x01, 0x01, 0x06, 0x00, 0x01, 0x01];
+
+    /// Sata(0x0,0xFFFF,0x0) node.
+    const SATA: [u8; 10] = [0x03, 0x12, 0x0a, 0x00, 0x00, 0x00, 0xff, 0xff, 0x00, 0x00];
+
+    /// End of entire device path node.
+    const END: [u8; 4] = [0x7f, 0xff, 0x04, 0x00];
+
+    /// Builds a hard drive node with the given signature and signature type.
+    fn hard_drive(partition_number: u32, signature: [u8; 16], signature_type: u8) -> Vec<u8> {
+        let mut node = alloc::vec![0x04, 0x01, 42, 0];
+        node.extend_from_slice(&partition_number.to_le_bytes());
+        node.extend_from_slice(&2048u64.to_le_bytes());
+        node.extend_from_slice(&532480u64.to_le_bytes());
+        node.extend_from_slice(&signature);
+        node.push(0x02);
+        node.push(signature_type);
+        node
+    }
+
+    /// Builds a file path node for `\a`.
+    fn file_path() -> Vec<u8> {
+        alloc::vec![0x04, 0x04, 0x0a, 0x00, b'\\', 0x00, b'a', 0x00, 0x00, 0x00]
+    }
+
+    fn path(nodes: &[&[u8]]) -> Vec<u8> {
+        nodes.concat()
+    }
+
+    /// Sorts `(is_removable, is_partition, handle_index)` tuples and returns the handle indices.
+    fn sorted(mut candidates: Vec<(bool, bool, usize)>) -> Vec<usize> {
+        candidates.sort_by_key(|&(is_removable, is_partition, handle_index)| boot_order(is_removable, is_partition, handle_index));
+        candidates.into_iter().map(|(_, _, handle_index)| handle_index).collect()
+    }
+
+    #[test]
+    fn hypervisor_paths_come_from_shared() {
+        assert_eq!(HYPERVISOR_PATHS[0].1, cstr16!(r"\EFI\Boot\illusion.efi"));
+        assert_eq!(HYPERVISOR_PATHS[1].1, cstr16!(r"\EFI\Boot\illusion_svm.efi"));
+    }
+
+    #[test]
+    fn fixed_before_removable() {
+        assert_eq!(sorted(alloc::vec![(true, true, 1), (false, true, 2)]), [2, 1]);
+    }
+
+    #[test]
+    fn partition_before_whole_disk() {
+        assert_eq!(sorted(alloc::vec![(false, false, 1), (false, true, 2)]), [2, 1]);
+    }
+
+    #[test]
+    fn removable_partition_after_fixed_whole_disk() {
+        assert_eq!(sorted(alloc::vec![(true, true, 1), (false, false, 2)]), [2, 1]);
+    }
+
+    #[test]
+    fn ties_keep_handle_order() {
+        assert_eq!(sorted(alloc::vec![(false, true, 3), (false, true, 1), (false, true, 2)]), [1, 2, 3]);
+    }
+
+    #[test]
+    fn mixed_candidates() {
+        let candidates = alloc::vec![(true, false, 1), (true, true, 2), (false, false, 3), (false, true, 4), (false, true, 5)];
+        assert_eq!(sorted(candidates), [4, 5, 3, 2, 1]);
+    }
+
+    #[test]
+    fn gpt_signature_ignores_hardware_path() {
+        let guid = [0x1f; 16];
+        let a = path(&[&PCI_ROOT, &PCI, &SATA, &hard_drive(1, guid, 2), &END]);
+        let b = path(&[&PCI_ROOT, &hard_drive(1, guid, 2), &file_path(), &END]);
+        assert_eq!(volume_key(&a), VolumeKey::Gpt(guid));
+        assert_eq!(volume_key(&a), volume_key(&b));
+    }
+
+    #[test]
+    fn different_gpt_partitions_differ() {
+        let a = path(&[&PCI_ROOT, &hard_drive(1, [0x1f; 16], 2), &END]);
+        let b = path(&[&PCI_ROOT, &hard_drive(2, [0x2e; 16], 2), &END]);
+        assert_ne!(volume_key(&a), volume_key(&b));
+    }
+
+    #[test]
+    fn mbr_signature_and_partition_number() {
+        let mut signature = [0u8; 16];
+        signature[..4].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+        let a = path(&[&PCI_ROOT, &hard_drive(1, signature, 1), &END]);
+        let b = path(&[&PCI_ROOT, &hard_drive(2, signature, 1), &END]);
+        assert_eq!(volume_key(&a), VolumeKey::Mbr(0x1234_5678, 1));
+        assert_ne!(volume_key(&a), volume_key(&b));
+    }
+
+    #[test]
+    fn path_without_hard_drive_ignores_file_and_end_nodes() {
+        let a = path(&[&PCI_ROOT, &PCI, &END]);
+        let b = path(&[&PCI_ROOT, &PCI, &file_path(), &END]);
+        assert_eq!(volume_key(&a), VolumeKey::Path(path(&[&PCI_ROOT, &PCI])));
+        assert_eq!(volume_key(&a), volume_key(&b));
+    }
+
+    #[test]
+    fn different_hardware_paths_differ() {
+        let a = path(&[&PCI_ROOT, &PCI, &END]);
+        let b = path(&[&PCI_ROOT, &SATA, &END]);
+        assert_ne!(volume_key(&a), volume_key(&b));
+    }
+
+    #[test]
+    fn malformed_node_stops_parsing() {
+        let a = path(&[&PCI_ROOT, &[0x01, 0x01, 0x02, 0x00], &PCI, &END]);
+        assert_eq!(volume_key(&a), VolumeKey::Path(PCI_ROOT.to_vec()));
+    }
+
+    #[test]
+    fn components_skip_empty() {
+        assert_eq!(path_components(r"\\EFI\\Boot\bootx64.efi").collect::<Vec<_>>(), ["EFI", "Boot", "bootx64.efi"]);
+    }
+
+    #[test]
+    fn component_prefers_exact_match() {
+        let entries = alloc::vec![String::from("boot"), String::from("Boot")];
+        assert_eq!(match_component(&entries, "Boot"), Some("Boot"));
+        assert_eq!(match_component(&entries, "BOOT"), Some("boot"));
+        assert_eq!(match_component(&entries, "bootx64"), None);
+    }
+
+    #[test]
+    fn resolves_lowercase_directories() {
+        let resolved = resolve_path(&mut esp(), r"\EFI\Microsoft\Boot\bootmgfw.efi");
+        assert_eq!(resolved.as_deref(), Some(r"\efi\microsoft\boot\BOOTMGFW.EFI"));
+    }
+
+    #[test]
+    fn resolves_mixed_case_directories() {
+        let resolved = resolve_path(&mut esp(), r"\EFI\BOOT\BOOTX64.EFI");
+        assert_eq!(resolved.as_deref(), Some(r"\efi\Boot\bootx64.efi"));
+    }
+
+    #[test]
+    fn missing_component_fails() {
+        assert_eq!(resolve_path(&mut esp(), r"\EFI\ubuntu\shimx64.efi"), None);
+        assert_eq!(resolve_path(&mut esp(), r"\EFI\Microsoft\Boot\bootmgfw.efi\extra"), None);
+        assert_eq!(resolve_path(&mut esp(), r"\"), None);
+    }
+
+    /// Returns the handle index of the hypervisor copy picked from `(is_esp, is_removable, handle_index)` tuples.
+    fn preferred(candidates: &[(bool, bool, usize)]) -> usize {
+        candidates
+            .iter()
+            .min_by_key(|&&(is_esp, is_removable, handle_index)| hypervisor_order(is_esp, is_removable, handle_index))
+            .unwrap()
+            .2
+    }
+
+    #[test]
+    fn hypervisor_prefers_esp() {
+        assert_eq!(preferred(&[(false, true, 1), (false, false, 2), (true, false, 3)]), 3);
+    }
+
+    #[test]
+    fn hypervisor_prefers_fixed_over_removable() {
+        assert_eq!(preferred(&[(false, true, 1), (false, false, 2)]), 2);
+        assert_eq!(preferred(&[(true, true, 1), (true, false, 2)]), 2);
+    }
+
+    #[test]
+    fn hypervisor_esp_on_removable_beats_plain_fixed() {
+        assert_eq!(preferred(&[(false, false, 1), (true, true, 2)]), 2);
+    }
+
+    #[test]
+    fn hypervisor_ties_keep_handle_order() {
+        assert_eq!(preferred(&[(true, false, 2), (true, false, 1)]), 1);
+    }
 }
@@ -0,0 +1,120 @@
+//! Persists the boot manager candidate and the hypervisor image chosen in the selection menus across reboots.
+//!
+//! The candidate is identified by the GPT partition GUID of the volume it was found on and stored in the
+//! non-volatile `IllusionLastBoot` variable, the hypervisor image by its path in `IllusionLastHv`. Any
+//! problem with the variables simply means there is nothing to remember, the menus then fall back to
+//! their defaults.
+
+extern crate alloc;
+
+use {
+    alloc::string::{String, ToString},
+    uefi::{
+        prelude::*,
+        table::runtime::{VariableAttributes, VariableVendor},
+        CStr16, Guid,
+    },
+};
+
+/// Name of the variable holding the partition GUID of the last selected candidate.
+const LAST_BOOT_VARIABLE: &CStr16 = cstr16!("IllusionLastBoot");
+
+/// Name of the variable holding the path of the last selected hypervisor image.
+const LAST_HYPERVISOR_VARIABLE: &CStr16 = cstr16!("IllusionLastHv");
+
+/// Upper bound for the stored hypervisor path in bytes.
+const MAX_PATH_SIZE: usize = 512;
+
+/// Vendor GUID under which the loader stores its variables.
+pub(crate) const ILLUSION_VENDOR: VariableVendor = VariableVendor(Guid::parse_or_panic(shared::VARIABLE_VENDOR));
+
+/// Reads the partition GUID of the previously selected candidate.
+///
+/// # Arguments
+///
+/// * `runtime_services` - A reference to the UEFI runtime services.
+///
+/// # Returns
+///
+/// The stored partition GUID, or `None` if the variable is absent, unreadable or malformed.
+pub(crate) fn load(runtime_services: &RuntimeServices) -> Option<Guid> {
+    let mut buffer = [0u8; 16];
+
+    match runtime_services.get_variable(LAST_BOOT_VARIABLE, &ILLUSION_VENDOR, &mut buffer) {
+        Ok((data, _)) if data.len() == 16 => data.try_into().ok().map(Guid::from_bytes),
+        Ok((data, _)) => {
+            log::warn!("Ignoring {} with unexpected size {}", LAST_BOOT_VARIABLE, data.len());
+            None
+        }
+        Err(error) if error.status() == Status::NOT_FOUND => {
+            log::debug!("No previously selected boot volume recorded");
+            None
+        }
+        Err(error) => {
+            log::warn!("Failed to read {} ({:?})", LAST_BOOT_VARIABLE, error.status());
+            None
+        }
+    }
+}
+
+/// Records the partition GUID of the selected candidate for the next boot.
+///
+/// # Arguments
+///
+/// * `runtime_services` - A reference to the UEFI runtime services.
+/// * `partition_guid` - The partition GUID of the selected candidate.
+pub(crate) fn store(runtime_services: &RuntimeServices, partition_guid: Guid) {
+    let attributes = VariableAttributes::NON_VOLATILE | VariableAttributes::BOOTSERVICE_ACCESS;
+
+    match runtime_services.set_variable(LAST_BOOT_VARIABLE, &ILLUSION_VENDOR, attributes, &partition_guid.to_bytes()) {
+        Ok(()) => log::debug!("Recorded boot volume {} in {}", partition_guid, LAST_BOOT_VARIABLE),
+        Err(error) => log::warn!("Failed to record selected boot volume in {} ({:?})", LAST_BOOT_VARIABLE, error.status()),
+    }
+}
+
+/// Reads the path of the previously selected hypervisor image.
+///
+/// # Arguments
+///
+/// * `runtime_services` - A reference to the UEFI runtime services.
+///
+/// # Returns
+///
+/// The stored path, or `None` if the variable is absent, unreadable or not UTF-8.
+pub(crate) fn load_hypervisor(runtime_services: &RuntimeServices) -> Option<String> {
+    let mut buffer = [0u8; MAX_PATH_SIZE];
+
+    match runtime_services.get_variable(LAST_HYPERVISOR_VARIABLE, &ILLUSION_VENDOR, &mut buffer) {
+        Ok((data, _)) => match core::str::from_utf8(data) {
+            Ok(path) => Some(String::from(path)),
+            Err(_) => {
+                log::warn!("Ignoring {} that is not UTF-8", LAST_HYPERVISOR_VARIABLE);
+                None
+            }
+        },
+        Err(error) if error.status() == Status::NOT_FOUND => {
+            log::debug!("No previously selected hypervisor image recorded");
+            None
+        }
+        Err(error) => {
+            log::warn!("Failed to read {} ({:?})", LAST_HYPERVISOR_VARIABLE, error.status());
+            None
+        }
+    }
+}
+
+/// Records the path of the selected hypervisor image for the next boot.
+///
+/// # Arguments
+///
+/// * `runtime_services` - A reference to the UEFI runtime services.
+/// * `path` - The path of the selected image.
+pub(crate) fn store_hypervisor(runtime_services: &RuntimeServices, path: &CStr16) {
+    let attributes = VariableAttributes::NON_VOLATILE | VariableAttributes::BOOTSERVICE_ACCESS;
+    let path = path.to_string();
+
+    match runtime_services.set_variable(LAST_HYPERVISOR_VARIABLE, &ILLUSION_VENDOR, attributes, path.as_bytes()) {
+        Ok(()) => log::debug!("Recorded hypervisor image {} in {}", path, LAST_HYPERVISOR_VARIABLE),
+        Err(error) => log::warn!("Failed to record selected hypervisor image in {} ({:?})", LAST_HYPERVISOR_VARIABLE, error.status()),
+    }
+}
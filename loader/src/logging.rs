@@ -0,0 +1,475 @@
+//! Loader log output, written to the UEFI console and mirrored to a 16550 UART.
+//!
+//! The logger doesn't allocate and finds the console through the global system table, so it works from
+//! the first line of `main`, before `uefi::helpers::init` set up the allocator. Every line is prefixed
+//! with the time since the logger was installed, measured with the TSC.
+//!
+//! All lines are also kept in a fixed size in-memory buffer, which can be saved to a file on the loader's
+//! own volume to debug boots that fail before the screen can be read.
+
+extern crate alloc;
+
+use {
+    crate::build_info::LOADER_BUILD_INFO,
+    alloc::{format, string::String, vec::Vec},
+    core::{
+        cell::UnsafeCell,
+        fmt::{self, Write},
+        sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering},
+    },
+    log::{LevelFilter, Log, Metadata, Record},
+    uefi::{
+        prelude::*,
+        proto::media::file::{File, FileAttribute, FileMode},
+        CStr16,
+    },
+    x86::{
+        io::{inb, outb},
+        time::rdtsc,
+    },
+};
+
+/// Verbosity until the configuration is applied, and the default of the `log_level` setting.
+pub(crate) const DEFAULT_LOG_LEVEL: LevelFilter = LevelFilter::Info;
+
+/// I/O port of the first serial port, used by default.
+pub(crate) const COM1: u16 = 0x3f8;
+
+/// I/O port of the second serial port.
+pub(crate) const COM2: u16 = 0x2f8;
+
+/// Offsets of the 16550 registers from the base port.
+const UART_DATA: u16 = 0;
+const UART_INTERRUPT_ENABLE: u16 = 1;
+const UART_FIFO_CONTROL: u16 = 2;
+const UART_LINE_CONTROL: u16 = 3;
+const UART_MODEM_CONTROL: u16 = 4;
+const UART_LINE_STATUS: u16 = 5;
+const UART_SCRATCH: u16 = 7;
+
+/// Line status bit set while a received byte waits in the data register.
+const LINE_STATUS_DATA_READY: u8 = 0x01;
+
+/// Line status bit set while the transmitter holding register can take another byte.
+const LINE_STATUS_THR_EMPTY: u8 = 0x20;
+
+/// Line control value that exposes the divisor latch in the data and interrupt enable registers.
+const LINE_CONTROL_DLAB: u8 = 0x80;
+
+/// Line control value for 8 data bits, no parity and one stop bit.
+const LINE_CONTROL_8N1: u8 = 0x03;
+
+/// Divisor of the 115200 Hz base clock for 115200 baud.
+const BAUD_DIVISOR: u16 = 1;
+
+/// Baud rate the UART is programmed for.
+pub(crate) const SERIAL_BAUD_RATE: u32 = 115_200 / BAUD_DIVISOR as u32;
+
+/// How often the line status is polled before a byte is dropped, so a dead UART can't hang the boot.
+const MAX_TRANSMIT_POLLS: u32 = 100_000;
+
+/// How long the TSC is sampled to derive its frequency.
+const CALIBRATION_US: usize = 10_000;
+
+/// Size of the in-memory log, the oldest lines are dropped once it is full.
+const LOG_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Path of the log file, relative to the root of the loader's own volume.
+const LOG_FILE_PATH: &CStr16 = cstr16!(r"\EFI\Boot\illusion-loader.log");
+
+static LOGGER: LoaderLogger = LoaderLogger;
+
+static LOG_BUFFER: LogBuffer = LogBuffer(UnsafeCell::new(LogRing::new()));
+
+/// Whether `save_log_file` writes the file, set from the configuration.
+static LOG_FILE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Base port of the UART the log is mirrored to, `0` if mirroring is off.
+static SERIAL_PORT: AtomicU16 = AtomicU16::new(0);
+
+/// TSC value when the logger was installed.
+static TSC_START: AtomicU64 = AtomicU64::new(0);
+
+/// TSC ticks per millisecond, `0` if unknown.
+static TSC_PER_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Installs the logger, mirroring to COM1 until `set_serial_port` is called.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services, used to calibrate the TSC.
+pub(crate) fn init(boot_services: &BootServices) {
+    let start = unsafe { rdtsc() };
+    boot_services.stall(CALIBRATION_US);
+    let ticks = unsafe { rdtsc() }.saturating_sub(start);
+
+    TSC_START.store(start, Ordering::Relaxed);
+    TSC_PER_MS.store(ticks / (CALIBRATION_US as u64 / 1000), Ordering::Relaxed);
+
+    set_serial_port(Some(COM1));
+
+    // Only fails if a logger is already installed, which then keeps receiving the output.
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(DEFAULT_LOG_LEVEL);
+}
+
+/// Changes the UART the log is mirrored to.
+///
+/// # Arguments
+///
+/// * `port` - The base I/O port of the UART, `None` to stop mirroring.
+///
+/// # Returns
+///
+/// Whether the log is now mirrored, `false` if mirroring was turned off or no UART responds at `port`.
+pub(crate) fn set_serial_port(port: Option<u16>) -> bool {
+    let port = port.filter(|&port| unsafe { init_uart(port) });
+    SERIAL_PORT.store(port.unwrap_or(0), Ordering::Relaxed);
+    port.is_some()
+}
+
+/// Returns whether the log is mirrored to a UART.
+pub(crate) fn has_serial_port() -> bool {
+    SERIAL_PORT.load(Ordering::Relaxed) != 0
+}
+
+/// Returns the I/O port of the UART the log is mirrored to.
+pub(crate) fn serial_port() -> Option<u16> {
+    Some(SERIAL_PORT.load(Ordering::Relaxed)).filter(|&port| port != 0)
+}
+
+/// Reads a byte received by the UART the log is mirrored to, for prompts answered over the serial line.
+///
+/// # Returns
+///
+/// The byte, or `None` if nothing was received or the log is not mirrored.
+pub(crate) fn read_serial_byte() -> Option<u8> {
+    match SERIAL_PORT.load(Ordering::Relaxed) {
+        0 => None,
+        port => Uart(port).read_byte(),
+    }
+}
+
+/// Enables or disables writing the log file in `save_log_file`.
+pub(crate) fn set_log_file(enabled: bool) {
+    LOG_FILE_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Writes the in-memory log to `\EFI\Boot\illusion-loader.log` if enabled, replacing the previous file.
+///
+/// Called right before control is handed to another image and when the loader fails, so the file always
+/// holds the complete log of the last boot.
+///
+/// # Arguments
+///
+/// * `system_table` - The UEFI system table, also the source of the firmware details in the header.
+/// * `outcome` - How the run ended, written to the header.
+pub(crate) fn save_log_file(system_table: &SystemTable<Boot>, outcome: &str) {
+    if !LOG_FILE_ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let (dropped, lines) = unsafe { (*LOG_BUFFER.0.get()).contents() };
+
+    let mut contents = format!(
+        "Illusion loader {}\nFirmware: {} (revision {:#x}), UEFI {}\nOutcome: {}\n",
+        LOADER_BUILD_INFO,
+        system_table.firmware_vendor(),
+        system_table.firmware_revision(),
+        system_table.uefi_revision(),
+        outcome
+    )
+    .into_bytes();
+    if dropped != 0 {
+        contents.extend_from_slice(format!("[{} bytes of older log lines dropped]\n", dropped).as_bytes());
+    }
+    contents.extend_from_slice(&lines);
+
+    match write_log_file(system_table.boot_services(), &contents) {
+        Ok(()) => log::debug!("Saved {} bytes of log to {}", contents.len(), LOG_FILE_PATH),
+        Err(error) => log::warn!("Failed to save log to {} ({:?})", LOG_FILE_PATH, error.status()),
+    }
+}
+
+/// Replaces the log file on the loader's own volume with `contents`.
+fn write_log_file(boot_services: &BootServices, contents: &[u8]) -> uefi::Result {
+    let mut file_system = boot_services.get_image_file_system(boot_services.image_handle())?;
+    let mut root = file_system.open_volume()?;
+
+    // Opening an existing file doesn't truncate it, so it is deleted first.
+    if let Ok(file) = root.open(LOG_FILE_PATH, FileMode::ReadWrite, FileAttribute::empty()) {
+        file.delete()?;
+    }
+
+    let mut file = root
+        .open(LOG_FILE_PATH, FileMode::CreateReadWrite, FileAttribute::empty())?
+        .into_regular_file()
+        .ok_or(uefi::Error::from(Status::INVALID_PARAMETER))?;
+
+    file.write(contents).map_err(|error| uefi::Error::from(error.status()))?;
+    file.flush()
+}
+
+/// Formats binary data as hex dump lines of 16 bytes, e.g. `0010: 57 49 4e 44 ...  WIND...`.
+///
+/// # Arguments
+///
+/// * `bytes` - The data to format.
+///
+/// # Returns
+///
+/// One line per 16 bytes, starting with the offset and ending with the printable ASCII characters.
+pub(crate) fn hex_dump(bytes: &[u8]) -> Vec<String> {
+    bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let hex: String = chunk.iter().map(|byte| format!("{:02x} ", byte)).collect();
+            let ascii: String = chunk
+                .iter()
+                .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+                .collect();
+            format!("{:04x}: {:<48} {}", index * 16, hex, ascii)
+        })
+        .collect()
+}
+
+/// Parses a `log_level` setting.
+pub(crate) fn parse_level(value: &str) -> Result<LevelFilter, &'static str> {
+    match value {
+        "error" => Ok(LevelFilter::Error),
+        "warn" => Ok(LevelFilter::Warn),
+        "info" => Ok(LevelFilter::Info),
+        "debug" => Ok(LevelFilter::Debug),
+        "trace" => Ok(LevelFilter::Trace),
+        _ => Err("expected error, warn, info, debug or trace"),
+    }
+}
+
+/// Parses a `serial` setting: `off`, `com1`, `com2` or a hexadecimal I/O port like `0x3e8`.
+pub(crate) fn parse_serial_port(value: &str) -> Result<Option<u16>, &'static str> {
+    match value {
+        "off" => Ok(None),
+        "com1" => Ok(Some(COM1)),
+        "com2" => Ok(Some(COM2)),
+        _ => match value.strip_prefix("0x").map(|digits| u16::from_str_radix(digits, 16)) {
+            Some(Ok(port)) if port != 0 => Ok(Some(port)),
+            _ => Err("expected off, com1, com2 or a hexadecimal I/O port"),
+        },
+    }
+}
+
+/// Formats a `serial` setting the way `parse_serial_port` accepts it.
+pub(crate) fn serial_port_name(port: Option<u16>) -> String {
+    match port {
+        None => String::from("off"),
+        Some(COM1) => String::from("com1"),
+        Some(COM2) => String::from("com2"),
+        Some(port) => format!("{:#x}", port),
+    }
+}
+
+/// Programs the UART for 115200 8N1 with FIFOs enabled.
+///
+/// # Returns
+///
+/// `false` if the scratch register doesn't hold a written value, i.e. there is no UART at `port`.
+unsafe fn init_uart(port: u16) -> bool {
+    outb(port + UART_SCRATCH, 0xa5);
+    if inb(port + UART_SCRATCH) != 0xa5 {
+        return false;
+    }
+
+    outb(port + UART_INTERRUPT_ENABLE, 0x00);
+    outb(port + UART_LINE_CONTROL, LINE_CONTROL_DLAB);
+    outb(port + UART_DATA, BAUD_DIVISOR as u8);
+    outb(port + UART_INTERRUPT_ENABLE, (BAUD_DIVISOR >> 8) as u8);
+    outb(port + UART_LINE_CONTROL, LINE_CONTROL_8N1);
+    outb(port + UART_FIFO_CONTROL, 0xc7);
+    outb(port + UART_MODEM_CONTROL, 0x03);
+
+    true
+}
+
+/// Milliseconds since the logger was installed, `None` if the TSC frequency is unknown.
+pub(crate) fn elapsed_ms() -> Option<u64> {
+    let per_ms = TSC_PER_MS.load(Ordering::Relaxed);
+    let elapsed = unsafe { rdtsc() }.saturating_sub(TSC_START.load(Ordering::Relaxed));
+    elapsed.checked_div(per_ms)
+}
+
+/// Writes a record as `[seconds.millis] [LEVEL]: file@line: message`.
+fn write_record(writer: &mut impl Write, timestamp_ms: Option<u64>, record: &Record<'_>) -> fmt::Result {
+    if let Some(ms) = timestamp_ms {
+        write!(writer, "[{:>4}.{:03}] ", ms / 1000, ms % 1000)?;
+    }
+
+    writeln!(writer, "[{:>5}]: {}@{:03}: {}", record.level(), record.file().unwrap_or("<unknown file>"), record.line().unwrap_or(0), record.args())
+}
+
+/// Logger writing to the console while boot services are active and to the configured UART.
+struct LoaderLogger;
+
+impl Log for LoaderLogger {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        let timestamp_ms = elapsed_ms();
+
+        // Errors are ignored, there is nowhere left to report them.
+        if let Some(mut system_table) = uefi::table::system_table_boot() {
+            let _ = write_record(system_table.stdout(), timestamp_ms, record);
+        }
+
+        let port = SERIAL_PORT.load(Ordering::Relaxed);
+        if port != 0 {
+            let _ = write_record(&mut Uart(port), timestamp_ms, record);
+        }
+
+        let _ = write_record(unsafe { &mut *LOG_BUFFER.0.get() }, timestamp_ms, record);
+    }
+
+    fn flush(&self) {}
+}
+
+/// The in-memory log shared by all callers.
+struct LogBuffer(UnsafeCell<LogRing<LOG_BUFFER_SIZE>>);
+
+// The loader only runs on the boot processor and never logs from event notifications.
+unsafe impl Sync for LogBuffer {}
+
+/// A ring buffer of log text that overwrites its oldest bytes once full.
+struct LogRing<const N: usize> {
+    bytes: [u8; N],
+
+    /// Number of bytes ever written, the next byte goes to `written % N`.
+    written: usize,
+}
+
+impl<const N: usize> LogRing<N> {
+    const fn new() -> Self {
+        Self { bytes: [0; N], written: 0 }
+    }
+
+    /// Returns the buffered text, starting at a line boundary once older lines were overwritten.
+    ///
+    /// # Returns
+    ///
+    /// The number of dropped bytes, including the partial line at the start of the buffer, and the rest.
+    fn contents(&self) -> (usize, Vec<u8>) {
+        if self.written <= N {
+            return (0, self.bytes[..self.written].to_vec());
+        }
+
+        let start = self.written % N;
+        let mut contents = [&self.bytes[start..], &self.bytes[..start]].concat();
+        let partial = contents.iter().position(|&byte| byte == b'\n').map_or(0, |index| index + 1);
+        contents.drain(..partial);
+
+        (self.written - N + partial, contents)
+    }
+}
+
+impl<const N: usize> Write for LogRing<N> {
+    fn write_str(&mut self, string: &str) -> fmt::Result {
+        for &byte in string.as_bytes() {
+            self.bytes[self.written % N] = byte;
+            self.written += 1;
+        }
+        Ok(())
+    }
+}
+
+/// A 16550 UART at the given base port.
+struct Uart(u16);
+
+impl Uart {
+    fn write_byte(&mut self, byte: u8) {
+        for _ in 0..MAX_TRANSMIT_POLLS {
+            if unsafe { inb(self.0 + UART_LINE_STATUS) } & LINE_STATUS_THR_EMPTY != 0 {
+                unsafe { outb(self.0 + UART_DATA, byte) };
+                return;
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        match unsafe { inb(self.0 + UART_LINE_STATUS) } & LINE_STATUS_DATA_READY != 0 {
+            true => Some(unsafe { inb(self.0 + UART_DATA) }),
+            false => None,
+        }
+    }
+}
+
+impl Write for Uart {
+    fn write_str(&mut self, string: &str) -> fmt::Result {
+        for byte in string.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{hex_dump, parse_level, parse_serial_port, serial_port_name, LogRing, COM1, COM2},
+        core::fmt::Write,
+        log::LevelFilter,
+    };
+
+    #[test]
+    fn parses_levels() {
+        assert_eq!(parse_level("error"), Ok(LevelFilter::Error));
+        assert_eq!(parse_level("trace"), Ok(LevelFilter::Trace));
+        assert!(parse_level("off").is_err());
+        assert!(parse_level("INFO").is_err());
+    }
+
+    #[test]
+    fn parses_serial_ports() {
+        assert_eq!(parse_serial_port("off"), Ok(None));
+        assert_eq!(parse_serial_port("com1"), Ok(Some(COM1)));
+        assert_eq!(parse_serial_port("com2"), Ok(Some(COM2)));
+        assert_eq!(parse_serial_port("0x3e8"), Ok(Some(0x3e8)));
+        assert!(parse_serial_port("0x0").is_err());
+        assert!(parse_serial_port("1016").is_err());
+        assert!(parse_serial_port("com3").is_err());
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for port in [None, Some(COM1), Some(COM2), Some(0x3e8)] {
+            assert_eq!(parse_serial_port(&serial_port_name(port)), Ok(port));
+        }
+    }
+
+    #[test]
+    fn formats_hex_dumps() {
+        let lines = hex_dump(b"WINDOWS\0\x01\x00\x00\x00\x88\x00\x00\x00BCD");
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], "0000: 57 49 4e 44 4f 57 53 00 01 00 00 00 88 00 00 00  WINDOWS.........");
+        assert_eq!(lines[1], "0010: 42 43 44                                         BCD");
+        assert!(hex_dump(&[]).is_empty());
+    }
+
+    #[test]
+    fn ring_keeps_everything_until_full() {
+        let mut ring = LogRing::<16>::new();
+        ring.write_str("one\ntwo\n").unwrap();
+        assert_eq!(ring.contents(), (0, b"one\ntwo\n".to_vec()));
+    }
+
+    #[test]
+    fn ring_drops_oldest_lines() {
+        let mut ring = LogRing::<16>::new();
+        ring.write_str("first\nsecond\nthird\n").unwrap();
+        // 19 bytes were written, the first 3 are overwritten and the rest of "first\n" is a partial line.
+        assert_eq!(ring.contents(), (6, b"second\nthird\n".to_vec()));
+    }
+}
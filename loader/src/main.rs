@@ -1,190 +1,1231 @@
-#![no_main]
-#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), no_std)]
 
 extern crate alloc;
 
+mod args;
+mod bitlocker;
+mod boot_attempt;
+mod bootvars;
+mod build_info;
+mod compress;
+mod config;
+mod console;
+mod devpath;
+mod error;
+mod exclusion;
+mod fatal;
+mod font;
+mod gfx;
+mod handoff;
+mod health;
+mod hv_status;
+mod hvconfig;
+mod hvlog;
 mod images;
+mod last_boot;
+mod logging;
+mod measure;
+mod memmap;
+mod menu;
+mod mp;
+mod netboot;
+mod pe;
+mod policy;
+mod preflight;
+mod presence;
+mod progress;
+mod retry;
+mod rng;
+mod scrub;
+mod secure_boot;
+mod sha256;
+mod shell;
+mod skip_once;
+mod update;
+mod verify;
+mod vmcall_key;
+mod watchdog;
 
-use uefi::{
-    prelude::*,
-    proto::{loaded_image::LoadedImage, media::block::BlockIO},
-    table::boot::LoadImageSource,
+use {
+    crate::{
+        config::{format_features, ChainloadSource, FailurePolicy, LoaderConfig, SecureBootPolicy},
+        error::LoaderError,
+        health::HealthRecord,
+        images::{BootTarget, SearchOptions},
+        menu::{Selection, SingleChoice},
+        policy::DefaultChoice,
+        preflight::CpuVendor,
+        secure_boot::SecureBootState,
+        sha256::Sha256,
+    },
+    alloc::{
+        boxed::Box,
+        string::{String, ToString},
+        vec::Vec,
+    },
+    shared::handoff::{HvResult, FLAG_IMAGE_VERIFIED, FLAG_MEASURED, FLAG_NETWORK_IMAGE, FLAG_SECURE_BOOT},
+    uefi::{prelude::*, proto::loaded_image::LoadedImage, table::boot::LoadImageSource, CStr16, CString16},
 };
 
+/// Key that raises the log level to trace for one boot when pressed early.
+const VERBOSE_KEY: char = 'd';
+
+/// Key that skips the hypervisor for one boot when pressed early.
+const SKIP_KEY: char = 's';
+
+/// How long after the loader started the early keys are polled for.
+const EARLY_KEY_WINDOW_MS: u64 = 1_000;
+
 #[entry]
 unsafe fn main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
+    logging::init(system_table.boot_services());
+
     if let Err(error) = uefi::helpers::init(&mut system_table) {
         log::error!("[0/8] Failed to initialize UEFI services ({:?})", error);
         return Status::ABORTED;
     }
 
     log::info!("[1/8] UEFI services initialized");
+    build_info::log_banner(&system_table);
 
-    log::info!("[2/8] Searching Illusion hypervisor (illusion.efi)..");
-
-    match images::find_hypervisor(system_table.boot_services()) {
-        Some(hypervisor_device_path) => {
-            log::info!("[3/8] Found hypervisor device path");
-            log::info!("[4/8] Loading hypervisor into memory..");
-
-            match system_table.boot_services().load_image(
-                image_handle,
-                LoadImageSource::FromDevicePath {
-                    device_path: &hypervisor_device_path,
-                    from_boot_manager: false,
-                },
-            ) {
-                Ok(handle) => {
-                    // Provide detailed information about the loaded hypervisor image before starting it
-                    match system_table.boot_services().open_protocol_exclusive::<LoadedImage>(handle) {
-                        Ok(li) => {
-                            let (base, size) = li.info();
-                            log::info!("[5/8] Loaded hypervisor image: base={:#x}, size={:#x} ({} bytes)", base as usize, size, size);
-                            log::debug!("[5/8] Hypervisor memory types: code={:?}, data={:?}", li.code_type(), li.data_type());
-                        }
-                        Err(e) => {
-                            log::warn!("[5/8] Loaded hypervisor, but failed to query LoadedImage info ({:?})", e);
-                        }
-                    }
+    match run(image_handle, &mut system_table) {
+        Ok(()) => Status::SUCCESS,
+        Err(error) => {
+            log::error!("{}", error);
+            logging::save_log_file(&system_table, &alloc::format!("failed with {:?}", error.status()));
+            fatal::handle(&mut system_table, error.status())
+        }
+    }
+}
 
-                    log::info!("[5/8] Transferring control to hypervisor entry (StartImage)..");
-                    if let Err(error) = system_table.boot_services().start_image(handle) {
-                        log::error!("Failed to start hypervisor ({:?})", error);
-                        return Status::ABORTED;
-                    }
-                    log::info!("[5/8] Hypervisor returned control to loader");
+/// The stages of a boot, run in this order unless a stage decides otherwise.
+enum BootStage {
+    /// Reading the configuration and the early keys.
+    Init,
+
+    /// Starting the hypervisor, unless it is skipped or already running.
+    LoadHypervisor,
+
+    /// Finding the boot manager to start, or letting the user pick one.
+    SelectTarget,
+
+    /// Loading and starting the selected boot manager.
+    Chainload(Box<BootTarget>),
+
+    /// Returning to the firmware instead of starting a boot manager.
+    ReturnToFirmware,
+
+    /// The loader is done and returns to the firmware.
+    Done,
+}
+
+impl BootStage {
+    /// Returns the `[n/8]` step the stage starts at, recorded as the progress of the boot.
+    fn number(&self) -> u8 {
+        match self {
+            BootStage::Init => 1,
+            BootStage::LoadHypervisor => 2,
+            BootStage::SelectTarget => 6,
+            BootStage::Chainload(_) | BootStage::ReturnToFirmware => 7,
+            BootStage::Done => 8,
+        }
+    }
+}
+
+/// What the stages find out and pass on to the following ones.
+struct LoaderContext<'a> {
+    /// The handle of the loader image.
+    image_handle: Handle,
+
+    /// The UEFI system table.
+    system_table: &'a mut SystemTable<Boot>,
+
+    /// The configuration, read by `stage_init`.
+    config: LoaderConfig,
+
+    /// Whether the firmware enforces Secure Boot.
+    secure_boot_enforced: bool,
+
+    /// The vendor of the current processor.
+    vendor: CpuVendor,
+
+    /// Whether the hypervisor was already running when the loader started.
+    illusion_running: bool,
+
+    /// Whether the skip key was pressed early.
+    skip_key: bool,
+
+    /// Whether Windows ends up running on top of the hypervisor, reported right before the handoff.
+    virtualized: bool,
+
+    /// The number of boot manager candidates found, `0` before the search.
+    candidates: usize,
+}
+
+/// Runs the loader stages after the UEFI services have been initialized.
+///
+/// # Arguments
+///
+/// * `image_handle` - The handle of the loader image.
+/// * `system_table` - The UEFI system table.
+///
+/// # Returns
+///
+/// `Ok(())` if the boot manager returned successfully or the loader returns to the firmware, otherwise the
+/// error of the failing stage.
+fn run(image_handle: Handle, system_table: &mut SystemTable<Boot>) -> Result<(), LoaderError> {
+    let mut context = LoaderContext {
+        image_handle,
+        system_table,
+        config: LoaderConfig::default(),
+        secure_boot_enforced: false,
+        vendor: CpuVendor::Other,
+        illusion_running: false,
+        skip_key: false,
+        virtualized: false,
+        candidates: 0,
+    };
+
+    progress::log_previous(context.system_table.runtime_services());
+
+    let mut stage = BootStage::Init;
+    loop {
+        let number = stage.number();
+        progress::record(context.system_table.runtime_services(), number, Status::SUCCESS, context.candidates);
+
+        let next = match stage {
+            BootStage::Init => stage_init(&mut context),
+            BootStage::LoadHypervisor => stage_load_hypervisor(&mut context),
+            BootStage::SelectTarget => stage_select_target(&mut context),
+            BootStage::Chainload(boot_manager) => stage_chainload(&mut context, boot_manager),
+            BootStage::ReturnToFirmware => {
+                return_to_firmware(context.system_table, &context.config, context.virtualized);
+                Ok(BootStage::Done)
+            }
+            BootStage::Done => return Ok(()),
+        };
+
+        stage = next.inspect_err(|error| progress::record(context.system_table.runtime_services(), number, error.status(), context.candidates))?;
+    }
+}
+
+/// Reads the configuration and the early keys and sets up logging.
+fn stage_init(context: &mut LoaderContext) -> Result<BootStage, LoaderError> {
+    let system_table = &mut *context.system_table;
+
+    // The early key window, the menu, failure prompts and downloads may all wait for longer than the
+    // firmware's watchdog allows.
+    watchdog::disable(system_table.boot_services());
+
+    let secure_boot = SecureBootState::read(system_table.runtime_services());
+    match (secure_boot.enabled, secure_boot.setup_mode) {
+        (true, false) => log::info!("[1/8] Secure Boot is enabled, unsigned images will be rejected by the firmware"),
+        (true, true) => log::info!("[1/8] Secure Boot is enabled, but the platform is in setup mode"),
+        (false, _) => log::info!("[1/8] Secure Boot is disabled"),
+    }
+    context.secure_boot_enforced = secure_boot.is_enforced();
+
+    let config = &mut context.config;
+    *config = LoaderConfig::load(system_table.boot_services());
+    args::apply_load_options(system_table.boot_services(), config);
+
+    log::set_max_level(config.log_level);
+    console::apply(system_table.stdout(), config.console_mode);
+
+    let [verbose_key, skip_key] = menu::early_keys(system_table, [VERBOSE_KEY, SKIP_KEY], EARLY_KEY_WINDOW_MS);
+    if verbose_key {
+        log::set_max_level(log::LevelFilter::Trace);
+        log::info!("[1/8] '{}' pressed, logging everything for this boot", VERBOSE_KEY);
+    }
+    context.skip_key = skip_key;
+
+    logging::set_log_file(config.log_file);
+    fatal::set_policy(config.on_fatal);
+    let serial_enabled = logging::set_serial_port(config.serial);
+    if let (Some(port), false) = (config.serial, serial_enabled) {
+        log::warn!("No UART responds at I/O port {:#x}, serial logging disabled", port);
+    }
+
+    context.illusion_running = presence::is_illusion_running();
+    context.vendor = CpuVendor::detect();
+    context.virtualized = context.illusion_running;
+
+    Ok(BootStage::LoadHypervisor)
+}
+
+/// Starts the hypervisor unless it is skipped, already running or the platform can't run it.
+fn stage_load_hypervisor(context: &mut LoaderContext) -> Result<BootStage, LoaderError> {
+    let LoaderContext {
+        image_handle,
+        system_table,
+        config,
+        vendor,
+        illusion_running,
+        ..
+    } = context;
+    let (image_handle, vendor, illusion_running) = (*image_handle, *vendor, *illusion_running);
+
+    if config.reset_boot_attempts {
+        boot_attempt::reset(system_table.runtime_services());
+    }
+
+    // Boots that started the hypervisor but never reached Windows, the hypervisor clears the counter.
+    let boot_attempts = boot_attempt::load(system_table.runtime_services());
+    let safe_mode = policy::is_safe_mode(config.boot_attempt_limit, boot_attempts);
+    if safe_mode {
+        log::error!("==============================================================================");
+        log::error!("SAFE MODE: the last {} boots with the hypervisor did not reach Windows", boot_attempts);
+        log::error!("Booting without the hypervisor until reset_boot_attempts=true is set in illusion.cfg");
+        log::error!("==============================================================================");
+    }
+
+    // The variable is consumed even if the hypervisor is skipped for another reason.
+    let skip_variable = skip_once::take(system_table.runtime_services());
+    let skip_reason = policy::skip_reason(config.skip_hypervisor, context.skip_key, skip_variable, safe_mode);
+
+    if let Some(reason) = skip_reason {
+        log::info!("[2/8] Skipping Illusion hypervisor as requested by {}", reason);
+    } else if illusion_running && !config.force_load {
+        log::info!("[2/8] Illusion hypervisor is already running, skipping to Windows boot manager");
+    } else if let Err(missing) = match illusion_running {
+        // When reloading on top of a running hypervisor, the guest view of the VMX MSRs is virtualized and
+        // the platform is known to be capable, so the check is skipped.
+        true => Ok(()),
+        false => preflight::check_virtualization_support(vendor),
+    } {
+        for requirement in &missing {
+            log::error!("[2/8] Virtualization pre-flight check failed: {}", requirement);
+        }
+
+        continue_without_hypervisor(system_table, config.on_unsupported_cpu, LoaderError::UnsupportedPlatform)?;
+    } else {
+        if illusion_running {
+            log::warn!("Illusion hypervisor is already running, loading it again as requested by --force-load");
+        } else {
+            log::info!("[2/8] Virtualization pre-flight check passed ({} processor)", vendor.name());
+        }
+
+        if config.boot_attempt_limit != 0 {
+            boot_attempt::store(system_table.runtime_services(), boot_attempts + 1);
+        }
+
+        match start_hypervisor(image_handle, system_table, config, vendor, context.secure_boot_enforced) {
+            Ok(()) => context.virtualized = true,
+            Err(error @ LoaderError::HypervisorUnsigned) if config.on_unsigned_secure_boot == SecureBootPolicy::Continue => {
+                continue_without_hypervisor(system_table, FailurePolicy::Continue, error)?
+            }
+            Err(error @ LoaderError::HypervisorDeclined) => continue_without_hypervisor(system_table, FailurePolicy::Continue, error)?,
+            Err(error @ LoaderError::HypervisorAbortedBoot(_)) => continue_without_hypervisor(system_table, FailurePolicy::Abort, error)?,
+            Err(error) => continue_without_hypervisor(system_table, config.on_hypervisor_failure, error)?,
+        }
+    }
+
+    Ok(BootStage::SelectTarget)
+}
+
+/// Picks the boot manager to start, or decides to return to the firmware.
+fn stage_select_target(context: &mut LoaderContext) -> Result<BootStage, LoaderError> {
+    let boot_manager = match context.config.chainload_source {
+        ChainloadSource::Firmware => None,
+        ChainloadSource::Paths | ChainloadSource::BootOrder => select_boot_manager(context.system_table, &context.config, &mut context.candidates)?,
+    };
+
+    match boot_manager {
+        Some(boot_manager) => Ok(BootStage::Chainload(Box::new(boot_manager))),
+        None => Ok(BootStage::ReturnToFirmware),
+    }
+}
+
+/// Loads and starts the selected boot manager, returning once it exits.
+fn stage_chainload(context: &mut LoaderContext, boot_manager: Box<BootTarget>) -> Result<BootStage, LoaderError> {
+    let LoaderContext {
+        image_handle,
+        system_table,
+        config,
+        virtualized,
+        ..
+    } = context;
+
+    // The device path identifies the selected volume and file independently of the handle enumeration order.
+    if config.measure {
+        let description = alloc::format!("Illusion boot manager selection: {}", boot_manager.device_path_text);
+        match measure::measure_selection(system_table.boot_services(), config.measure_pcr, &description) {
+            Ok(true) => log::info!("[7/8] Measured boot manager selection into PCR {}", config.measure_pcr),
+            Ok(false) => log::debug!("[7/8] No TPM present, boot manager selection not measured"),
+            Err(status) => log::warn!("[7/8] Failed to measure boot manager selection into the TPM ({:?})", status),
+        }
+    }
+
+    if *virtualized && !config.acknowledge_bitlocker {
+        check_bitlocker(system_table)?;
+    }
+
+    handoff::set_chainload_target(&boot_manager.device_path);
+
+    log::info!("Loading boot manager into memory..");
+
+    log::info!("Stalling for {} ms before handing off to Windows boot manager..", config.handoff_stall_ms);
+    system_table.boot_services().stall(config.handoff_stall_ms.saturating_mul(1000) as usize);
+
+    let boot_services = system_table.boot_services();
+    let handle = retry::retry(boot_services, config.load_attempts, "load boot manager", || {
+        boot_services.load_image(
+            *image_handle,
+            LoadImageSource::FromDevicePath {
+                device_path: &boot_manager.device_path,
+                from_boot_manager: false,
+            },
+        )
+    })
+    .map_err(LoaderError::BootManagerLoadFailed)?;
+
+    // The buffer is only dropped at the end of this function, after `start_image` returned.
+    let load_options = boot_manager_load_options(system_table.runtime_services(), config, &boot_manager);
+    if let Some(options) = &load_options {
+        set_load_options(system_table.boot_services(), handle, options);
+    }
+
+    match virtualized {
+        true => log::info!("[8/8] Loaded boot manager into memory, starting Windows virtualized by Illusion.."),
+        false => log::info!("[8/8] Loaded boot manager into memory, starting Windows bare (without hypervisor).."),
+    }
+
+    if config.scrub_loader_image {
+        scrub::scrub_loader_image(system_table.boot_services());
+    }
+
+    if config.log_memory_map {
+        memmap::log_summary(system_table.boot_services(), config.log_memory_map_min_mib);
+    }
+
+    logging::save_log_file(system_table, "starting Windows boot manager");
+    watchdog::arm(system_table.boot_services());
+
+    system_table
+        .boot_services()
+        .start_image(handle)
+        .map_err(|error| LoaderError::BootManagerStartFailed(error.status()))?;
+
+    Ok(BootStage::Done)
+}
+
+/// Warns that BitLocker will likely ask for the recovery key and lets the user back out.
+///
+/// The hypervisor image is not one of the boot applications BitLocker expects in the measurements it sealed
+/// its key against, so unlocking the volume fails until the recovery key is entered.
+///
+/// # Returns
+///
+/// `Ok(())` if no encrypted volume was found or the user chose to continue.
+fn check_bitlocker(system_table: &mut SystemTable<Boot>) -> Result<(), LoaderError> {
+    let volumes = bitlocker::find_encrypted_volumes(system_table.boot_services());
+    if volumes.is_empty() {
+        log::debug!("[7/8] No BitLocker volume found");
+        return Ok(());
+    }
+
+    log::warn!("********************************************************************************");
+    log::warn!("BitLocker is enabled on {} volume(s):", volumes.len());
+    for volume in &volumes {
+        log::warn!("  {}", volume);
+    }
+    log::warn!("Starting Windows behind the hypervisor changes the measured boot state, so Windows");
+    log::warn!("will likely ask for the BitLocker recovery key. Have it at hand or suspend BitLocker.");
+    log::warn!("Set acknowledge_bitlocker = true in the configuration to skip this question.");
+    log::warn!("********************************************************************************");
+
+    match menu::confirm(system_table, "Start Windows anyway?") {
+        true => Ok(()),
+        false => Err(LoaderError::BitLockerNotAcknowledged),
+    }
+}
+
+/// Finds, checks, loads and starts the hypervisor image.
+///
+/// # Arguments
+///
+/// * `image_handle` - The handle of the loader image, used as the parent of the hypervisor image.
+/// * `system_table` - The UEFI system table.
+/// * `config` - The loader configuration.
+/// * `vendor` - The vendor of the current processor, used to pick the hypervisor image.
+/// * `secure_boot` - Whether the firmware enforces Secure Boot, which subjects unsigned images to the
+///   `on_unsigned_secure_boot` policy.
+///
+/// # Returns
+///
+/// `Ok(())` once the hypervisor returned control to the loader, or the error of the failing stage.
+fn start_hypervisor(
+    image_handle: Handle,
+    system_table: &mut SystemTable<Boot>,
+    config: &LoaderConfig,
+    vendor: CpuVendor,
+    secure_boot: bool,
+) -> Result<(), LoaderError> {
+    log::info!("[2/8] Searching Illusion hypervisor..");
+
+    // Without a local copy there is no device path for the image, no sidecar digest file and no backup.
+    if let Some(file) = fetch_network_hypervisor(system_table.boot_services(), config) {
+        return start_hypervisor_image(image_handle, system_table, config, secure_boot, &file, None);
+    }
+
+    let (hypervisor, hypervisor_path) = select_local_hypervisor(system_table, config, vendor)?;
+    log::info!("[3/8] Found hypervisor device path ({})", hypervisor_path);
+    update::install_pending(system_table.boot_services(), hypervisor.handle, &hypervisor_path, config);
+
+    let file = images::read_file(system_table.boot_services(), hypervisor.handle, &hypervisor_path).map_err(LoaderError::HypervisorReadFailed)?;
+    let digest = Sha256::digest(&file);
+
+    let health = health::load(system_table.runtime_services());
+    if let Some(record) = health.as_ref().filter(|record| record.digest == digest) {
+        if let Some(backup) = find_backup_hypervisor(system_table.boot_services(), config, &hypervisor, &hypervisor_path) {
+            log::warn!(
+                "[3/8] {} failed to start in {} previous boot(s), last with {:?}, starting the backup",
+                hypervisor_path,
+                record.tries,
+                record.status
+            );
+            return start_backup_hypervisor(image_handle, system_table, config, secure_boot, backup);
+        }
+    }
+
+    let error = match start_hypervisor_image(image_handle, system_table, config, secure_boot, &file, Some((&hypervisor, &hypervisor_path))) {
+        Ok(()) => {
+            log::info!("[5/8] Booted primary hypervisor image {}", hypervisor_path);
+            if health.is_some() {
+                health::clear(system_table.runtime_services());
+            }
+            return Ok(());
+        }
+        Err(error) if error.is_start_failure() => error,
+        Err(error) => return Err(error),
+    };
+
+    health::store(system_table.runtime_services(), &HealthRecord::failed(health.as_ref(), digest, error.status()));
+
+    match find_backup_hypervisor(system_table.boot_services(), config, &hypervisor, &hypervisor_path) {
+        Some(backup) => {
+            log::error!("{}", error);
+            log::warn!("[5/8] Marked {} as bad, falling back to {}", hypervisor_path, backup.1);
+            start_backup_hypervisor(image_handle, system_table, config, secure_boot, backup)
+        }
+        None => Err(error),
+    }
+}
+
+/// Finds the previous hypervisor image kept by an update next to the installed one.
+///
+/// # Returns
+///
+/// The filesystem holding the backup and its path on it, `None` if there is no backup.
+fn find_backup_hypervisor(
+    boot_services: &BootServices,
+    config: &LoaderConfig,
+    hypervisor: &BootTarget,
+    path: &CStr16,
+) -> Option<(BootTarget, CString16)> {
+    let backup_path = CString16::try_from(alloc::format!("{}{}", path, update::BACKUP_EXTENSION).as_str()).ok()?;
+
+    let backup = images::enumerate_device_paths(boot_services, config.search_options(true), &backup_path)
+        .ok()?
+        .into_iter()
+        .find(|target| target.handle == hypervisor.handle);
+
+    if backup.is_none() {
+        log::debug!("No backup hypervisor image {}", backup_path);
+    }
+    backup.map(|target| (target, backup_path))
+}
+
+/// Starts the backup hypervisor image after the installed one failed.
+///
+/// # Returns
+///
+/// `Ok(())` once the backup returned control to the loader, or the error of the failing stage.
+fn start_backup_hypervisor(
+    image_handle: Handle,
+    system_table: &mut SystemTable<Boot>,
+    config: &LoaderConfig,
+    secure_boot: bool,
+    (backup, backup_path): (BootTarget, CString16),
+) -> Result<(), LoaderError> {
+    let started = images::read_file(system_table.boot_services(), backup.handle, &backup_path)
+        .map_err(LoaderError::HypervisorReadFailed)
+        .and_then(|file| start_hypervisor_image(image_handle, system_table, config, secure_boot, &file, Some((&backup, &backup_path))));
+
+    match &started {
+        Ok(()) => log::warn!("[5/8] Fell back to backup hypervisor image {}", backup_path),
+        Err(error) => log::error!("[5/8] Both the installed and the backup hypervisor image failed, the backup with: {}", error),
+    }
+
+    started
+}
+
+/// Checks, loads and starts a hypervisor image read into memory.
+///
+/// # Arguments
+///
+/// * `image_handle` - The handle of the loader image, used as the parent of the hypervisor image.
+/// * `system_table` - The UEFI system table.
+/// * `config` - The loader configuration.
+/// * `secure_boot` - Whether the firmware enforces Secure Boot.
+/// * `file` - The image as stored on disk or downloaded.
+/// * `local` - The filesystem holding the image and its path on it, `None` for a downloaded image.
+///
+/// # Returns
+///
+/// `Ok(())` once the hypervisor returned control to the loader and responds to the presence probe, or the
+/// error of the failing stage.
+fn start_hypervisor_image(
+    image_handle: Handle,
+    system_table: &mut SystemTable<Boot>,
+    config: &LoaderConfig,
+    secure_boot: bool,
+    file: &[u8],
+    local: Option<(&BootTarget, &CStr16)>,
+) -> Result<(), LoaderError> {
+    let boot_services = system_table.boot_services();
+
+    let decompressed = match compress::is_compressed(file) {
+        true => {
+            let image = compress::decompress(file).map_err(LoaderError::HypervisorDecompressFailed)?;
+            log::info!("[3/8] Decompressed hypervisor image from {} to {} bytes", file.len(), image.len());
+            Some(image)
+        }
+        false => None,
+    };
+    let image = decompressed.as_deref().unwrap_or(file);
+
+    let info = pe::validate(image).map_err(LoaderError::InvalidHypervisorImage)?;
+    log::info!(
+        "[3/8] Hypervisor image is a valid x86-64 EFI image (subsystem {}, {} sections, size of image {:#x})",
+        info.subsystem,
+        info.section_count,
+        info.size_of_image
+    );
+
+    // The firmware unloads an application as soon as its entry point returns, while a runtime driver that
+    // returns `SUCCESS` stays resident. Only then does the hypervisor survive the loader returning.
+    if info.subsystem != pe::SUBSYSTEM_EFI_RUNTIME_DRIVER {
+        log::warn!("[3/8] Hypervisor image is not an EFI runtime driver, its memory is freed once it returns");
+    }
+
+    if secure_boot && !info.is_signed {
+        match config.on_unsigned_secure_boot {
+            SecureBootPolicy::Attempt => {
+                log::warn!("[3/8] Secure Boot is enabled and the hypervisor image is unsigned, attempting to load it anyway")
+            }
+            SecureBootPolicy::Continue | SecureBootPolicy::Abort => return Err(LoaderError::HypervisorUnsigned),
+        }
+    }
+
+    // The digest covers the file as stored on disk, so that it matches `sha256sum` of the installed file.
+    let sidecar = local.map(|(hypervisor, path)| (hypervisor.handle, path));
+    let verified = verify::verify_hypervisor(boot_services, sidecar, config, file)?;
+
+    // Changing the image invalidates its Authenticode signature, which the firmware checks under Secure Boot.
+    let patched = match hvconfig::is_requested(config) {
+        false => None,
+        true if secure_boot && info.is_signed => {
+            log::warn!("[3/8] Not patching the hypervisor configuration, the firmware would reject the changed signed image");
+            None
+        }
+        true => {
+            let mut patched = image.to_vec();
+            match hvconfig::patch(&mut patched, config) {
+                Ok(blob) => {
+                    log::info!("[3/8] Patched the hypervisor configuration (log level {}, flags {:#x})", blob.log_level, blob.flags);
+                    Some(patched)
                 }
                 Err(error) => {
-                    log::error!("Failed to load hypervisor ({:?})", error);
-                    return Status::ABORTED;
+                    log::warn!("[3/8] Not patching the hypervisor configuration: {}, loading the image unmodified", error);
+                    None
                 }
             }
         }
-        None => {
-            log::error!("Failed to find hypervisor image");
-            return Status::ABORTED;
+    };
+    let image = patched.as_deref().unwrap_or(image);
+
+    let mut measured = false;
+    if config.measure {
+        let device_path = local.map(|(hypervisor, _)| &*hypervisor.device_path);
+        match measure::measure_hypervisor(boot_services, config.measure_pcr, image, device_path) {
+            Ok(true) => {
+                log::info!("[3/8] Measured hypervisor image into PCR {}", config.measure_pcr);
+                measured = true;
+            }
+            Ok(false) => log::info!("[3/8] No TPM present, hypervisor image not measured"),
+            Err(status) => return Err(LoaderError::HypervisorMeasurementFailed(status)),
         }
+    }
+
+    // Loading the verified bytes rules out the file changing between the check and the firmware reading it.
+    // Decompressed, patched and downloaded images only exist in memory. `image` is only dropped after
+    // `start_image` returns.
+    let source = || match (local, config.load_from_buffer || verified || decompressed.is_some() || patched.is_some()) {
+        (Some((hypervisor, _)), false) => LoadImageSource::FromDevicePath {
+            device_path: &hypervisor.device_path,
+            from_boot_manager: false,
+        },
+        (local, _) => LoadImageSource::FromBuffer {
+            buffer: image,
+            file_path: local.map(|(hypervisor, _)| &*hypervisor.device_path),
+        },
     };
 
-    log::info!("[6/8] Searching Windows boot manager (bootmgfw.efi)..");
+    log::info!("[4/8] Hypervisor features: {}", format_features(config.hv_features));
+
+    match source() {
+        LoadImageSource::FromDevicePath { .. } => log::info!("[4/8] Loading hypervisor into memory.."),
+        _ => log::info!("[4/8] Loading hypervisor into memory from the {} byte buffer..", image.len()),
+    }
+
+    let handle = retry::retry(boot_services, config.load_attempts, "load hypervisor", || boot_services.load_image(image_handle, source()))
+        .map_err(LoaderError::HypervisorLoadFailed)?;
+
+    // Provide detailed information about the loaded hypervisor image before starting it
+    match boot_services.open_protocol_exclusive::<LoadedImage>(handle) {
+        Ok(li) => {
+            let (base, size) = li.info();
+            log::info!("[5/8] Loaded hypervisor image: base={:#x}, size={:#x} ({} bytes)", base as usize, size, size);
+            log::debug!("[5/8] Hypervisor memory types: code={:?}, data={:?}", li.code_type(), li.data_type());
+        }
+        Err(e) => {
+            log::warn!("[5/8] Loaded hypervisor, but failed to query LoadedImage info ({:?})", e);
+        }
+    }
+
+    let flags = [
+        (verified, FLAG_IMAGE_VERIFIED),
+        (local.is_none(), FLAG_NETWORK_IMAGE),
+        (measured, FLAG_MEASURED),
+        (secure_boot, FLAG_SECURE_BOOT),
+    ]
+    .iter()
+    .filter(|(set, _)| *set)
+    .fold(0, |flags, (_, flag)| flags | flag);
+    let mut loader_handoff = handoff::describe(Sha256::digest(file), flags, config.hv_features);
+    let key = vmcall_key::select(boot_services, config);
+    loader_handoff.set_vmcall_key(key);
+    let hv_log = hvlog::HvLog::allocate(boot_services);
+    if let Some(ring) = &hv_log {
+        loader_handoff.set_log_ring(ring.address(), ring.size());
+    }
+    match handoff::install(boot_services, handle, &loader_handoff) {
+        Ok(()) => {
+            log::debug!("[5/8] Installed the loader handoff on the hypervisor image");
+            vmcall_key::persist(system_table.runtime_services(), key);
+        }
+        Err(error) => log::warn!("[5/8] Failed to install the loader handoff ({:?}), starting the hypervisor without it", error.status()),
+    }
+
+    log::info!("[5/8] Transferring control to hypervisor entry (StartImage)..");
+    logging::save_log_file(system_table, "starting hypervisor");
+
+    // A hypervisor that hangs while virtualizing the processors resets the machine instead of freezing it.
+    watchdog::arm(boot_services);
+    let started = boot_services.start_image(handle);
+    watchdog::disable(boot_services);
+    if let Some(ring) = hv_log {
+        ring.drain(boot_services);
+    }
+
+    // An answer in the handoff takes precedence over the status, a hypervisor declining the platform may
+    // return either.
+    match handoff::hypervisor_result() {
+        HvResult::Continue => {}
+        HvResult::ContinueWithoutHv => return Err(LoaderError::HypervisorDeclined),
+        HvResult::AbortBoot(reason) => return Err(LoaderError::HypervisorAbortedBoot(reason)),
+        // Left to the check of all processors below, which applies the failure policy.
+        HvResult::ProcessorsFailed(count) => log::warn!("[5/8] Hypervisor failed to virtualize {} processor(s)", count),
+    }
+    started.map_err(|error| LoaderError::HypervisorStartFailed(error.status()))?;
+    log::info!("[5/8] Hypervisor returned control to loader");
+    hv_status::log_status(boot_services);
+
+    // A hypervisor that returned without virtualizing the processor, or whose memory was freed, does not answer.
+    if !presence::is_illusion_running() {
+        return Err(LoaderError::HypervisorNotResident);
+    }
+
+    // A processor that failed to enter VMX operation keeps running the guest bare metal without a word.
+    let summary = presence::check_all_processors(system_table.boot_services());
+    if summary.bare_metal != 0 {
+        return Err(LoaderError::HypervisorNotOnAllProcessors(summary.bare_metal, summary.bare_metal + summary.virtualized + summary.unverified));
+    }
+    log::info!("[5/8] Hypervisor is running on {} processor(s), {} not probed", summary.virtualized, summary.unverified);
+
+    Ok(())
+}
+
+/// Downloads the hypervisor image from the configured network source.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+/// * `config` - The loader configuration.
+///
+/// # Returns
+///
+/// The downloaded image, or `None` if no network source is configured or the download failed, in which
+/// case the image is searched on the local filesystems.
+fn fetch_network_hypervisor(boot_services: &BootServices, config: &LoaderConfig) -> Option<Vec<u8>> {
+    let source = config.hypervisor_source.as_ref()?;
 
-    let candidates = {
-        let bs = system_table.boot_services();
-        images::find_all_windows_boot_managers(bs)
+    log::info!("[2/8] Fetching hypervisor from {}..", source);
+    match netboot::fetch(boot_services, source, config.net_timeout_ms) {
+        Ok(file) => {
+            log::info!("[3/8] Downloaded hypervisor image ({} bytes)", file.len());
+            Some(file)
+        }
+        Err(error) => {
+            log::warn!("[2/8] Failed to fetch hypervisor from {}: {}, falling back to local search", source, error);
+            None
+        }
+    }
+}
+
+/// Finds the hypervisor image on the local filesystems, letting the user choose if several configured
+/// images are present.
+///
+/// # Arguments
+///
+/// * `system_table` - The UEFI system table.
+/// * `config` - The loader configuration.
+/// * `vendor` - The vendor of the current processor, used to pick the hypervisor image.
+///
+/// # Returns
+///
+/// The filesystem holding the image and its path on it, or the error of the search or the selection.
+fn select_local_hypervisor(
+    system_table: &mut SystemTable<Boot>,
+    config: &LoaderConfig,
+    vendor: CpuVendor,
+) -> Result<(BootTarget, CString16), LoaderError> {
+    let mut found = retry::rescan(
+        system_table.boot_services(),
+        config.rescan_attempts,
+        config.rescan_interval_ms,
+        "hypervisor",
+        || find_local_hypervisors(system_table.boot_services(), config, vendor),
+        |result| result.as_ref().is_err_and(LoaderError::is_not_found),
+    )?;
+
+    if found.len() == 1 {
+        return Ok(found.swap_remove(0));
+    }
+
+    log::info!("[3/8] Multiple hypervisor images detected ({}).", found.len());
+    let descriptions: Vec<String> = found.iter().map(|(target, _)| target.describe()).collect();
+
+    let last_hypervisor = last_boot::load_hypervisor(system_table.runtime_services());
+    let remembered = last_hypervisor.and_then(|last| found.iter().position(|(_, path)| path.to_string() == last));
+    if let Some(index) = remembered {
+        log::info!("Option {} is the previously selected hypervisor image, defaulting to it", index + 1);
+    }
+
+    let selection = match menu::select(
+        system_table,
+        &descriptions,
+        descriptions.len(),
+        remembered.unwrap_or(0),
+        config.selection_timeout_ms,
+        config.graphical_menu,
+        config.log_keys,
+    ) {
+        Selection::Chosen(selection) => selection,
+        Selection::Aborted | Selection::Firmware => return Err(LoaderError::HypervisorSelectionAborted),
     };
 
-    if candidates.is_empty() {
-        log::error!("Failed to find Windows boot manager image");
-        return Status::ABORTED;
+    log::info!("Selected hypervisor image {}", selection + 1);
+    if remembered != Some(selection) {
+        last_boot::store_hypervisor(system_table.runtime_services(), &found[selection].1);
     }
 
-    // If there are multiple candidates, present a simple manual selection menu.
-    let selected_device_path = if candidates.len() == 1 {
-        log::info!("[7/8] Found Windows boot manager device path");
-        candidates[0].device_path.as_ref()
-    } else {
-        log::info!("[7/8] Multiple Windows boot manager candidates detected ({}).", candidates.len());
-        log::info!("Please select which one to start by pressing 1-{}.", candidates.len());
-        {
-            let bs = system_table.boot_services();
-            for (i, target) in candidates.iter().enumerate() {
-                // Try to provide some context using BlockIO information.
-                let mut desc = alloc::format!("handle {}", target.handle_index);
-                if let Ok(blockio) = bs.open_protocol_exclusive::<BlockIO>(target.handle) {
-                    let media = blockio.media();
-                    let size_bytes = (media.last_block().saturating_add(1)).saturating_mul(media.block_size() as u64);
-                    let size_mb = size_bytes / (1024 * 1024) as u64;
-                    desc = alloc::format!(
-                        "{} | {} | {} | approx {} MiB",
-                        desc,
-                        if media.is_removable_media() { "removable" } else { "fixed" },
-                        if media.is_logical_partition() { "partition" } else { "whole-disk" },
-                        size_mb
-                    );
-                }
-                log::info!("  {}. {}", i + 1, desc);
+    Ok(found.swap_remove(selection))
+}
+
+/// Searches the hypervisor images on the local filesystems.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+/// * `config` - The loader configuration.
+/// * `vendor` - The vendor of the current processor, used to pick the hypervisor image.
+///
+/// # Returns
+///
+/// The filesystem holding each image found and its path on it, in the order of the configured paths, or the
+/// error of the search.
+fn find_local_hypervisors(
+    boot_services: &BootServices,
+    config: &LoaderConfig,
+    vendor: CpuVendor,
+) -> Result<Vec<(BootTarget, CString16)>, LoaderError> {
+    let search = config.search_options(true);
+
+    let found: Vec<(BootTarget, CString16)> = match config.hypervisor_paths.as_slice() {
+        [] => find_hypervisor_image(boot_services, search, images::hypervisor_path(vendor), Some(vendor))?
+            .into_iter()
+            .collect(),
+        paths => {
+            let mut found = Vec::new();
+            for path in paths {
+                found.extend(find_hypervisor_image(boot_services, search, path, None)?);
             }
+            found
         }
-        log::info!("Press ENTER to select option 1 (default). Press ESC to abort.");
-        log::info!("Defaulting to option 1 automatically in 5 seconds if no input is received.");
-
-        // Read from console input until a valid selection is made or a timeout occurs
-        let _ = system_table.stdin().reset(false);
-
-        let mut selection: usize = 0; // default to first option
-        let timeout_ms: u64 = 5_000; // 5 seconds
-        let poll_interval_us: u64 = 10_000; // 10ms per poll
-        let mut waited_us: u64 = 0;
-
-        'sel_loop: loop {
-            match system_table.stdin().read_key() {
-                Ok(Some(key)) => {
-                    use uefi::proto::console::text::{Key, ScanCode};
-                    match key {
-                        Key::Printable(c) => {
-                            let ch: char = c.into();
-                            if let Some(d) = ch.to_digit(10) {
-                                let idx = d as usize;
-                                if idx >= 1 && idx <= candidates.len() {
-                                    selection = idx - 1;
-                                    break 'sel_loop;
-                                }
-                            } else if ch == '\r' || ch == '\n' {
-                                break 'sel_loop; // default selection (0)
-                            }
-                        }
-                        Key::Special(ScanCode::ESCAPE) => {
-                            log::error!("Selection aborted by user");
-                            return Status::ABORTED;
-                        }
-                        _ => {}
-                    }
-                }
-                Ok(None) => {
-                    if waited_us >= timeout_ms * 1000 {
-                        log::info!("No selection made within {} seconds, defaulting to option 1.", timeout_ms / 1000);
-                        break 'sel_loop;
+    };
+
+    if found.is_empty() {
+        let volumes = images::volume_count(boot_services).map_err(LoaderError::HypervisorSearchFailed)?;
+        return Err(LoaderError::HypervisorNotFound(volumes));
+    }
+
+    Ok(found)
+}
+
+/// Searches one hypervisor image, preferring a compressed image next to it.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+/// * `search` - How the volumes are probed.
+/// * `path` - The path of the uncompressed image.
+/// * `vendor` - The processor vendor if `path` is its default image, to report images for other vendors.
+///
+/// # Returns
+///
+/// The filesystem holding the image and its path on it, `None` if it is not present, or the error of the search.
+fn find_hypervisor_image(
+    boot_services: &BootServices,
+    search: SearchOptions,
+    path: &CStr16,
+    vendor: Option<CpuVendor>,
+) -> Result<Option<(BootTarget, CString16)>, LoaderError> {
+    // A compressed image next to the uncompressed path takes precedence.
+    let compressed_path = CString16::try_from(alloc::format!("{}{}", path, compress::COMPRESSED_EXTENSION).as_str()).ok();
+    let compressed_target = match &compressed_path {
+        Some(path) => images::find_hypervisor(boot_services, search, path).map_err(LoaderError::HypervisorSearchFailed)?,
+        None => None,
+    };
+
+    match compressed_target.zip(compressed_path) {
+        Some(found) => Ok(Some(found)),
+        None => match vendor {
+            Some(vendor) => images::find_hypervisor_for_vendor(boot_services, search, vendor)
+                .map(|found| found.map(|(target, path)| (target, CString16::from(path)))),
+            None => images::find_hypervisor(boot_services, search, path).map(|target| target.map(|target| (target, CString16::from(path)))),
+        }
+        .map_err(LoaderError::HypervisorSearchFailed),
+    }
+}
+
+/// Searches the boot manager candidates, in the firmware boot entries or on the chainload paths.
+///
+/// # Arguments
+///
+/// * `system_table` - The UEFI system table.
+/// * `config` - The loader configuration.
+///
+/// # Returns
+///
+/// All candidates found, possibly none, or the error of the failing search.
+fn find_boot_managers(system_table: &SystemTable<Boot>, config: &LoaderConfig) -> Result<Vec<BootTarget>, LoaderError> {
+    let search = config.search_options(false);
+
+    let mut candidates = match config.chainload_source {
+        ChainloadSource::BootOrder => {
+            log::info!("[6/8] Resolving the firmware boot entries in BootOrder..");
+            bootvars::find_boot_order_targets(system_table.boot_services(), system_table.runtime_services(), search)
+                .map_err(LoaderError::BootManagerSearchFailed)?
+        }
+        ChainloadSource::Paths | ChainloadSource::Firmware => Vec::new(),
+    };
+
+    if candidates.is_empty() {
+        if config.chainload_source == ChainloadSource::BootOrder {
+            log::warn!("[6/8] No usable boot entry in BootOrder, searching the chainload paths instead");
+        }
+
+        log::info!("[6/8] Searching Windows boot manager ({} chainload path(s))..", config.chainload.len());
+        candidates =
+            images::find_chainload_targets(system_table.boot_services(), search, &config.chainload).map_err(LoaderError::BootManagerSearchFailed)?;
+    }
+
+    if config.deep_scan && candidates.iter().all(|target| target.boot_entry.is_none()) {
+        log::info!("Deep scan enabled, searching the vendor directories in {}", images::DEEP_SCAN_DIR);
+        match images::find_deep_scan_targets(system_table.boot_services(), search, &config.chainload, &candidates) {
+            Ok(mut found) => candidates.append(&mut found),
+            Err(error) => log::warn!("Deep scan failed: {}", error),
+        }
+    }
+
+    Ok(candidates)
+}
+
+/// Separates the candidates on volumes excluded by the configuration.
+///
+/// # Returns
+///
+/// The candidates offered by default, followed by the excluded ones listed in the menu, which are none
+/// unless `show_excluded` is set.
+fn exclude_candidates(candidates: Vec<BootTarget>, config: &LoaderConfig) -> (Vec<BootTarget>, Vec<BootTarget>) {
+    let (candidates, excluded) = exclusion::split_excluded(candidates, &config.exclude_volumes, &config.include_volumes);
+    for target in &excluded {
+        log::info!("[6/8] Excluding candidate {}", target.describe());
+    }
+
+    match config.show_excluded {
+        true => (candidates, excluded),
+        false => (candidates, Vec::new()),
+    }
+}
+
+/// Finds the Windows boot manager, letting the user choose if there are multiple candidates.
+///
+/// # Arguments
+///
+/// * `system_table` - The UEFI system table.
+/// * `config` - The loader configuration.
+/// * `found` - Set to the number of candidates found, for the progress record.
+///
+/// # Returns
+///
+/// The boot manager to start, `None` if the user chose to return to the firmware, or the error of the
+/// failing stage.
+fn select_boot_manager(system_table: &mut SystemTable<Boot>, config: &LoaderConfig, found: &mut usize) -> Result<Option<BootTarget>, LoaderError> {
+    let search = || match exclude_candidates(find_boot_managers(system_table, config)?, config) {
+        (candidates, excluded) if candidates.is_empty() && excluded.is_empty() => {
+            let volumes = images::volume_count(system_table.boot_services()).map_err(LoaderError::BootManagerSearchFailed)?;
+            Err(LoaderError::BootManagerNotFound(volumes))
+        }
+        found => Ok(found),
+    };
+
+    let (mut candidates, mut excluded) =
+        retry::rescan(system_table.boot_services(), config.rescan_attempts, config.rescan_interval_ms, "Windows boot manager", search, |result| {
+            result.as_ref().is_err_and(LoaderError::is_not_found)
+        })?;
+    *found = candidates.len() + excluded.len();
+
+    while candidates.len() == 1 && excluded.is_empty() {
+        log::info!("[7/8] Found {} on handle {}", candidates[0].path, candidates[0].handle_index);
+        if config.single_candidate_timeout_ms == 0 {
+            return Ok(Some(candidates.swap_remove(0)));
+        }
+
+        match menu::confirm_single(system_table, &candidates[0].describe(), config.single_candidate_timeout_ms) {
+            SingleChoice::Proceed => return Ok(Some(candidates.swap_remove(0))),
+            SingleChoice::Aborted => return Err(LoaderError::SelectionAborted),
+            SingleChoice::Rescan => {
+                log::info!("[6/8] Rescanning the filesystems for further candidates..");
+                match find_boot_managers(system_table, config).map(|rescanned| exclude_candidates(rescanned, config)) {
+                    Ok((rescanned, rescanned_excluded)) => {
+                        images::merge_targets(&mut excluded, rescanned_excluded);
+                        log::info!("Rescan found {} new candidate(s)", images::merge_targets(&mut candidates, rescanned));
                     }
-                    system_table.boot_services().stall(poll_interval_us as usize);
-                    waited_us += poll_interval_us;
-                }
-                Err(e) => {
-                    log::warn!("Failed to read key from console ({:?}), defaulting to option 1", e);
-                    break 'sel_loop;
+                    Err(error) => log::warn!("Rescan failed: {}", error),
                 }
+                *found = candidates.len() + excluded.len();
             }
         }
+    }
 
-        let target = &candidates[selection];
-        log::info!("Selected candidate {} (handle {})", selection + 1, target.handle_index);
-        target.device_path.as_ref()
-    };
+    // If there are multiple candidates, present a manual selection menu.
+    match excluded.is_empty() {
+        true => log::info!("[7/8] Multiple Windows boot manager candidates detected ({}).", candidates.len()),
+        false => log::info!("[7/8] Windows boot manager candidates detected ({}, {} excluded).", candidates.len(), excluded.len()),
+    }
 
-    log::info!("Loading boot manager into memory..");
+    // Candidates of the first chainload path that was found come first, the others are only picked explicitly.
+    let paths: Vec<&CString16> = candidates.iter().map(|target| &target.path).collect();
+    let primary = policy::primary_count(&paths);
 
-    log::info!("Stalling for 3 seconds before handing off to Windows boot manager..");
-    system_table.boot_services().stall(3_000_000);
+    // Prefer the candidate on the same disk as the loader over whichever happened to be enumerated first.
+    let same_disk = images::loader_device_path(system_table.boot_services()).and_then(|loader_path| {
+        let prefixes: Vec<usize> = candidates[..primary]
+            .iter()
+            .map(|target| images::common_hardware_prefix(&loader_path, &target.device_path))
+            .collect();
+        policy::closest_candidate(&prefixes)
+    });
 
-    match system_table.boot_services().load_image(
-        image_handle,
-        LoadImageSource::FromDevicePath {
-            device_path: selected_device_path,
-            from_boot_manager: false,
-        },
-    ) {
-        Ok(handle) => {
-            log::info!("[8/8] Loaded boot manager into memory, starting..");
+    // Excluded candidates follow the others, the countdown never picks one of them.
+    let locked = candidates.len();
+    let timeout_ms = if locked == 0 { 0 } else { config.selection_timeout_ms };
+    candidates.append(&mut excluded);
 
-            if let Err(error) = system_table.boot_services().start_image(handle) {
-                log::error!("Failed to start boot manager ({:?})", error);
-                return Status::ABORTED;
+    let descriptions: Vec<String> = candidates
+        .iter()
+        .enumerate()
+        .map(|(index, target)| {
+            log::debug!("Candidate {}: {}", index + 1, target.device_path_text);
+            let mut desc = target.describe();
+            if same_disk == Some(index) {
+                desc = alloc::format!("{} (same disk as loader)", desc);
             }
+            desc
+        })
+        .collect();
+
+    let last_boot = last_boot::load(system_table.runtime_services());
+    let remembered = last_boot.and_then(|guid| candidates[..locked].iter().position(|target| target.partition_guid == Some(guid)));
+
+    if config.default_candidate.is_none() && last_boot.is_some() && remembered.is_none() {
+        log::info!("Previously selected volume is no longer present");
+    }
+
+    let (default_selection, choice) = policy::default_selection(config.default_candidate, locked, remembered, same_disk);
+    match choice {
+        DefaultChoice::ConfiguredMissing(candidate) => log::warn!("Configured default candidate {} does not exist, using option 1", candidate),
+        DefaultChoice::Remembered => {
+            log::info!("Option {} is on the previously selected volume, defaulting to previously selected volume", default_selection + 1)
         }
-        Err(error) => {
-            log::error!("Failed to load boot manager ({:?})", error);
-            return Status::ABORTED;
+        DefaultChoice::SameDisk => log::info!("Option {} is on the same disk as the loader, defaulting to it", default_selection + 1),
+        DefaultChoice::Configured | DefaultChoice::First => {}
+    }
+
+    let selection = match menu::select(system_table, &descriptions, locked, default_selection, timeout_ms, config.graphical_menu, config.log_keys) {
+        Selection::Chosen(selection) => selection,
+        Selection::Aborted => return Err(LoaderError::SelectionAborted),
+        Selection::Firmware => return Ok(None),
+    };
+
+    let target = &candidates[selection];
+    log::info!("Selected candidate {} (handle {})", selection + 1, target.handle_index);
+
+    match target.partition_guid {
+        Some(guid) if last_boot != Some(guid) => last_boot::store(system_table.runtime_services(), guid),
+        Some(_) => {}
+        None => log::debug!("Selected candidate is not on a GPT partition, not remembering it"),
+    }
+
+    Ok(Some(candidates.swap_remove(selection)))
+}
+
+/// Prepares returning to the firmware instead of chainloading a boot manager.
+///
+/// The hypervisor image was loaded as a child of the loader, but a runtime driver stays resident after its
+/// entry point returned, so the loader exiting does not unload it. The presence probe confirms that the
+/// processor is still virtualized before the firmware boot menu takes over.
+///
+/// # Arguments
+///
+/// * `system_table` - The UEFI system table.
+/// * `config` - The loader configuration.
+/// * `virtualized` - Whether the hypervisor was started or already running.
+fn return_to_firmware(system_table: &SystemTable<Boot>, config: &LoaderConfig, virtualized: bool) {
+    log::info!("[7/8] Returning to the firmware instead of chainloading a boot manager");
+
+    match (virtualized, presence::is_illusion_running()) {
+        (true, true) => log::info!("[8/8] Illusion hypervisor responds to the presence probe, it stays active after the loader exits"),
+        (true, false) => log::warn!("[8/8] Illusion hypervisor was started but does not respond to the presence probe"),
+        (false, _) => log::info!("[8/8] Returning to the firmware without the hypervisor"),
+    }
+
+    if config.measure {
+        match measure::measure_selection(system_table.boot_services(), config.measure_pcr, "Illusion boot manager selection: firmware") {
+            Ok(true) => log::info!("[7/8] Measured boot manager selection into PCR {}", config.measure_pcr),
+            Ok(false) => log::debug!("[7/8] No TPM present, boot manager selection not measured"),
+            Err(status) => log::warn!("[7/8] Failed to measure boot manager selection into the TPM ({:?})", status),
+        }
+    }
+
+    logging::save_log_file(system_table, "returning to firmware");
+    watchdog::arm(system_table.boot_services());
+}
+
+/// Picks the load options passed to the boot manager.
+///
+/// # Arguments
+///
+/// * `runtime_services` - A reference to the UEFI runtime services, used to find the matching boot entry.
+/// * `config` - The loader configuration.
+/// * `boot_manager` - The selected boot manager.
+///
+/// # Returns
+///
+/// The `chainload_options` setting, otherwise the optional data of the `Boot####` entry starting the same
+/// file, or `None` if neither exists.
+fn boot_manager_load_options(runtime_services: &RuntimeServices, config: &LoaderConfig, boot_manager: &BootTarget) -> Option<Vec<u8>> {
+    let options = match &config.chainload_options {
+        Some(options) => {
+            log::info!("Passing the configured load options \"{}\" to the boot manager", options);
+            args::encode_load_options(options)
         }
+        None => {
+            let entry = match &boot_manager.boot_entry {
+                Some(entry) => entry.clone(),
+                None => bootvars::find_matching_entry(runtime_services, boot_manager)?,
+            };
+
+            if entry.option.optional_data.is_empty() {
+                log::debug!("{} ({}) has no optional data to pass to the boot manager", entry.name(), entry.option.description);
+                return None;
+            }
+
+            log::info!(
+                "Passing the {} byte optional data of {} ({}) to the boot manager",
+                entry.option.optional_data.len(),
+                entry.name(),
+                entry.option.description
+            );
+            entry.option.optional_data
+        }
+    };
+
+    for line in logging::hex_dump(&options) {
+        log::debug!("  {}", line);
+    }
+
+    Some(options)
+}
+
+/// Sets the load options of a loaded image before it is started.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+/// * `handle` - The handle of the loaded, not yet started image.
+/// * `options` - The load options, which must stay alive until the image returned from `start_image`.
+fn set_load_options(boot_services: &BootServices, handle: Handle, options: &[u8]) {
+    match boot_services.open_protocol_exclusive::<LoadedImage>(handle) {
+        // SAFETY: The caller keeps `options` alive for as long as the image may read its load options.
+        Ok(mut loaded_image) => unsafe { loaded_image.set_load_options(options.as_ptr(), options.len() as u32) },
+        Err(error) => log::warn!("Failed to open LoadedImage to set load options ({:?})", error.status()),
     }
+}
 
-    Status::SUCCESS
+/// Applies a failure policy after the hypervisor could not be started.
+///
+/// # Arguments
+///
+/// * `system_table` - The UEFI system table, used to prompt the user.
+/// * `policy` - The configured policy for this kind of failure.
+/// * `error` - The error that prevented the hypervisor from starting.
+///
+/// # Returns
+///
+/// `Ok(())` if booting should continue without the hypervisor, otherwise `error`.
+fn continue_without_hypervisor(system_table: &mut SystemTable<Boot>, policy: FailurePolicy, error: LoaderError) -> Result<(), LoaderError> {
+    let proceed = match policy {
+        FailurePolicy::Abort => false,
+        FailurePolicy::Continue => {
+            log::error!("{}", error);
+            true
+        }
+        FailurePolicy::Prompt => {
+            log::error!("{}", error);
+            menu::confirm(system_table, "Continue booting Windows without the hypervisor?")
+        }
+    };
+
+    match proceed {
+        true => {
+            log::warn!("Continuing to Windows boot manager without the hypervisor");
+            Ok(())
+        }
+        false => Err(error),
+    }
 }
@@ -0,0 +1,128 @@
+//! Measurement of the hypervisor image and the boot manager selection into the TPM.
+//!
+//! Both are extended into the same PCR through the TCG2 protocol, so that the event log shows whether a
+//! boot went through Illusion and which boot manager it handed off to. Without a TPM nothing is measured.
+
+extern crate alloc;
+
+use {
+    alloc::vec::Vec,
+    core::mem::MaybeUninit,
+    uefi::{
+        prelude::*,
+        proto::{
+            device_path::DevicePath,
+            tcg::{
+                v2::{HashLogExtendEventFlags, PcrEventInputs, Tcg},
+                EventType, PcrIndex,
+            },
+        },
+        table::boot::ScopedProtocol,
+    },
+};
+
+/// PCR the events are extended into by default, the first one reserved for the OS loader.
+pub(crate) const DEFAULT_MEASURE_PCR: u32 = 12;
+
+/// Highest PCR index of a PC client TPM.
+pub(crate) const MAX_PCR: u32 = 23;
+
+/// Size of the `EFI_TCG2_EVENT` size field and header preceding the event data.
+const EVENT_HEADER_SIZE: usize = 18;
+
+/// Measures the hypervisor image as a `EV_EFI_BOOT_SERVICES_APPLICATION` event.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+/// * `pcr` - The PCR to extend.
+/// * `image` - The image that is about to be loaded.
+/// * `device_path` - The device path of the file the image was read from, `None` for downloaded images.
+///
+/// # Returns
+///
+/// `Ok(true)` if the image was measured, `Ok(false)` if there is no TPM, or the status of the failed extend.
+pub(crate) fn measure_hypervisor(boot_services: &BootServices, pcr: u32, image: &[u8], device_path: Option<&DevicePath>) -> Result<bool, Status> {
+    let device_path = device_path.map(DevicePath::as_bytes).unwrap_or_default();
+    let event = image_load_event(image.as_ptr() as u64, image.len() as u64, device_path);
+
+    extend(boot_services, pcr, EventType::EFI_BOOT_SERVICES_APPLICATION, image, &event)
+}
+
+/// Measures the boot manager the loader hands off to as a `EV_EFI_ACTION` event.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+/// * `pcr` - The PCR to extend.
+/// * `description` - The text describing the selected boot manager, which is both hashed and logged.
+///
+/// # Returns
+///
+/// `Ok(true)` if the selection was measured, `Ok(false)` if there is no TPM, or the status of the failed extend.
+pub(crate) fn measure_selection(boot_services: &BootServices, pcr: u32, description: &str) -> Result<bool, Status> {
+    extend(boot_services, pcr, EventType::EFI_ACTION, description.as_bytes(), description.as_bytes())
+}
+
+/// Hashes `data` into `pcr` and logs `event_data` in the TCG2 event log.
+fn extend(boot_services: &BootServices, pcr: u32, event_type: EventType, data: &[u8], event_data: &[u8]) -> Result<bool, Status> {
+    let Some(mut tcg) = open_tpm(boot_services) else {
+        return Ok(false);
+    };
+
+    let mut buffer = alloc::vec![MaybeUninit::<u8>::uninit(); EVENT_HEADER_SIZE + event_data.len()];
+    let event = PcrEventInputs::new_in_buffer(&mut buffer, PcrIndex(pcr), event_type, event_data).map_err(|error| error.status())?;
+
+    tcg.hash_log_extend_event(HashLogExtendEventFlags::empty(), data, event)
+        .map_err(|error| error.status())?;
+
+    Ok(true)
+}
+
+/// Opens the TCG2 protocol if a TPM is present.
+fn open_tpm(boot_services: &BootServices) -> Option<ScopedProtocol<Tcg>> {
+    let handle = boot_services.get_handle_for_protocol::<Tcg>().ok()?;
+    let mut tcg = boot_services.open_protocol_exclusive::<Tcg>(handle).ok()?;
+
+    match tcg.get_capability() {
+        Ok(capability) if capability.tpm_present() => Some(tcg),
+        _ => None,
+    }
+}
+
+/// Builds the `UEFI_IMAGE_LOAD_EVENT` structure logged for an application.
+///
+/// The link time address is left zero, the image is measured before the firmware relocates it.
+///
+/// # Arguments
+///
+/// * `address` - Where the image is located in memory.
+/// * `length` - The size of the image in bytes.
+/// * `device_path` - The raw device path of the image file, may be empty.
+fn image_load_event(address: u64, length: u64, device_path: &[u8]) -> Vec<u8> {
+    let mut event = Vec::with_capacity(32 + device_path.len());
+    event.extend_from_slice(&address.to_le_bytes());
+    event.extend_from_slice(&length.to_le_bytes());
+    event.extend_from_slice(&0u64.to_le_bytes());
+    event.extend_from_slice(&(device_path.len() as u64).to_le_bytes());
+    event.extend_from_slice(device_path);
+    event
+}
+
+#[cfg(test)]
+mod tests {
+    use super::image_load_event;
+
+    #[test]
+    fn image_load_event_layout() {
+        let device_path = [0x7f, 0xff, 0x04, 0x00];
+        let event = image_load_event(0x1000, 0x2345, &device_path);
+
+        assert_eq!(event.len(), 36);
+        assert_eq!(&event[0..8], &0x1000u64.to_le_bytes());
+        assert_eq!(&event[8..16], &0x2345u64.to_le_bytes());
+        assert_eq!(&event[16..24], &[0; 8]);
+        assert_eq!(&event[24..32], &4u64.to_le_bytes());
+        assert_eq!(&event[32..], &device_path);
+    }
+}
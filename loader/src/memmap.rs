@@ -0,0 +1,186 @@
+//! Summary of the UEFI memory map right before the handoff to the boot manager.
+//!
+//! Windows reporting less memory than installed usually comes down to large regions of runtime
+//! memory, which the firmware keeps for itself after boot services exit. The hypervisor allocates its
+//! memory that way, so the large runtime regions are listed individually.
+
+extern crate alloc;
+
+use {
+    alloc::{format, string::String, vec::Vec},
+    uefi::{
+        prelude::*,
+        table::boot::{MemoryDescriptor, MemoryType},
+    },
+};
+
+/// Size of a page in the memory map.
+const PAGE_SIZE: u64 = 0x1000;
+
+/// Runtime regions at least this large are listed by default.
+pub(crate) const DEFAULT_RUNTIME_REGION_MIB: u64 = 1;
+
+/// The regions of one memory type.
+#[derive(Debug, PartialEq)]
+pub(crate) struct TypeSummary {
+    /// The memory type.
+    pub ty: MemoryType,
+
+    /// The number of descriptors with this type.
+    pub regions: usize,
+
+    /// The total number of pages of the descriptors.
+    pub pages: u64,
+}
+
+/// Aggregates descriptors by memory type.
+///
+/// # Returns
+///
+/// One summary per memory type present, ordered by type.
+pub(crate) fn summarize<'a>(descriptors: impl IntoIterator<Item = &'a MemoryDescriptor>) -> Vec<TypeSummary> {
+    let mut summaries: Vec<TypeSummary> = Vec::new();
+
+    for descriptor in descriptors {
+        match summaries.iter_mut().find(|summary| summary.ty == descriptor.ty) {
+            Some(summary) => {
+                summary.regions += 1;
+                summary.pages += descriptor.page_count;
+            }
+            None => summaries.push(TypeSummary {
+                ty: descriptor.ty,
+                regions: 1,
+                pages: descriptor.page_count,
+            }),
+        }
+    }
+
+    summaries.sort_by_key(|summary| summary.ty.0);
+    summaries
+}
+
+/// Returns the runtime services code and data regions of at least `min_mib` MiB.
+pub(crate) fn large_runtime_regions<'a>(descriptors: impl IntoIterator<Item = &'a MemoryDescriptor>, min_mib: u64) -> Vec<&'a MemoryDescriptor> {
+    descriptors
+        .into_iter()
+        .filter(|descriptor| matches!(descriptor.ty, MemoryType::RUNTIME_SERVICES_CODE | MemoryType::RUNTIME_SERVICES_DATA))
+        .filter(|descriptor| descriptor.page_count * PAGE_SIZE >= min_mib * 1024 * 1024)
+        .collect()
+}
+
+/// Formats a number of pages in MiB with one decimal, e.g. `12.5`.
+pub(crate) fn format_mib(pages: u64) -> String {
+    let kib = pages * (PAGE_SIZE / 1024);
+    format!("{}.{}", kib / 1024, kib % 1024 * 10 / 1024)
+}
+
+/// Logs the memory map aggregated by type, followed by the large runtime regions.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+/// * `min_runtime_mib` - Runtime regions at least this large are listed individually.
+pub(crate) fn log_summary(boot_services: &BootServices, min_runtime_mib: u64) {
+    let memory_map = match boot_services.memory_map(MemoryType::LOADER_DATA) {
+        Ok(memory_map) => memory_map,
+        Err(error) => {
+            log::warn!("Failed to get the memory map ({:?})", error.status());
+            return;
+        }
+    };
+
+    let summaries = summarize(memory_map.entries());
+    log::info!("Memory map at handoff ({} descriptors):", memory_map.entries().len());
+    log::info!("  {:<24} {:>7} {:>12}", "Type", "Regions", "MiB");
+    for summary in &summaries {
+        log::info!("  {:<24} {:>7} {:>12}", format!("{:?}", summary.ty), summary.regions, format_mib(summary.pages));
+    }
+
+    let total: u64 = summaries.iter().map(|summary| summary.pages).sum();
+    log::info!("  {:<24} {:>7} {:>12}", "Total", memory_map.entries().len(), format_mib(total));
+
+    let runtime = large_runtime_regions(memory_map.entries(), min_runtime_mib);
+    if runtime.is_empty() {
+        log::info!("No runtime services region of {} MiB or more", min_runtime_mib);
+    }
+
+    for descriptor in runtime {
+        log::info!(
+            "  {:?} at {:#x}-{:#x} ({} MiB), likely the hypervisor",
+            descriptor.ty,
+            descriptor.phys_start,
+            descriptor.phys_start + descriptor.page_count * PAGE_SIZE - 1,
+            format_mib(descriptor.page_count)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn descriptor(ty: MemoryType, phys_start: u64, page_count: u64) -> MemoryDescriptor {
+        MemoryDescriptor {
+            ty,
+            phys_start,
+            page_count,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn descriptors_are_aggregated_by_type() {
+        let descriptors = [
+            descriptor(MemoryType::CONVENTIONAL, 0x10_0000, 0x100),
+            descriptor(MemoryType::RUNTIME_SERVICES_DATA, 0x20_0000, 2),
+            descriptor(MemoryType::LOADER_CODE, 0x30_0000, 4),
+            descriptor(MemoryType::CONVENTIONAL, 0x40_0000, 0x300),
+        ];
+
+        assert_eq!(
+            summarize(&descriptors),
+            [
+                TypeSummary {
+                    ty: MemoryType::LOADER_CODE,
+                    regions: 1,
+                    pages: 4
+                },
+                TypeSummary {
+                    ty: MemoryType::RUNTIME_SERVICES_DATA,
+                    regions: 1,
+                    pages: 2
+                },
+                TypeSummary {
+                    ty: MemoryType::CONVENTIONAL,
+                    regions: 2,
+                    pages: 0x400
+                },
+            ]
+        );
+        assert!(summarize(&[]).is_empty());
+    }
+
+    #[test]
+    fn only_large_runtime_regions_are_listed() {
+        let descriptors = [
+            descriptor(MemoryType::RUNTIME_SERVICES_DATA, 0x1000, 0x10),
+            descriptor(MemoryType::RUNTIME_SERVICES_DATA, 0x10_0000, 0x800),
+            descriptor(MemoryType::RUNTIME_SERVICES_CODE, 0x90_0000, 0x100),
+            descriptor(MemoryType::BOOT_SERVICES_DATA, 0x100_0000, 0x1000),
+        ];
+
+        let regions: Vec<u64> = large_runtime_regions(&descriptors, 1)
+            .iter()
+            .map(|descriptor| descriptor.phys_start)
+            .collect();
+        assert_eq!(regions, [0x10_0000, 0x90_0000]);
+        assert_eq!(large_runtime_regions(&descriptors, 8).len(), 1);
+    }
+
+    #[test]
+    fn sizes_are_formatted_in_mib() {
+        assert_eq!(format_mib(0x100), "1.0");
+        assert_eq!(format_mib(0x280), "2.5");
+        assert_eq!(format_mib(1), "0.0");
+    }
+}
@@ -0,0 +1,947 @@
+//! Interactive selection menu used when more than one boot candidate is available.
+//!
+//! The menu is drawn in place with cursor positioning and highlights the current entry, or in a box on the
+//! framebuffer if the graphical menu is enabled. Consoles that can't position the cursor get the plain log
+//! based flow instead.
+
+extern crate alloc;
+
+use {
+    crate::{gfx, logging, shell},
+    alloc::{format, string::String},
+    core::fmt::Write,
+    uefi::{
+        prelude::*,
+        proto::console::text::{Color, Key, Output, ScanCode},
+    },
+};
+
+/// Interval between two polls of the console input.
+const POLL_INTERVAL_US: u64 = 10_000;
+
+/// How long to wait for a further digit before committing a typed number.
+const INTER_KEY_TIMEOUT_US: u64 = 1_000_000;
+
+/// Fallback console width when the current text mode can't be queried.
+const DEFAULT_COLUMNS: usize = 80;
+
+/// Text of the extra entry that returns to the firmware, selected by typing `0`.
+const FIRMWARE_ENTRY: &str = "0. Return to firmware";
+
+/// Marks a line shortened to the console width, plain ASCII as not every console font has `…`.
+const ELLIPSIS: &str = "...";
+
+/// The character reported by the console for the backspace key.
+const BACKSPACE: char = '\u{8}';
+
+/// The outcome of a selection.
+pub(crate) enum Selection {
+    /// The entry at the given index was chosen, either explicitly or by timeout.
+    Chosen(usize),
+
+    /// The user pressed ESC.
+    Aborted,
+
+    /// The user typed `0` to return to the firmware instead of booting an entry.
+    Firmware,
+}
+
+/// The outcome of the confirmation window shown for a single candidate.
+#[derive(Debug, PartialEq)]
+pub(crate) enum SingleChoice {
+    /// Boot the candidate.
+    Proceed,
+
+    /// The user pressed ESC.
+    Aborted,
+
+    /// The user pressed `r` to search the filesystems again.
+    Rescan,
+}
+
+/// What a key press means to the menu.
+#[derive(Debug, PartialEq)]
+enum Action {
+    /// Move the highlight one entry up.
+    Up,
+
+    /// Move the highlight one entry down.
+    Down,
+
+    /// Boot the highlighted entry, or the typed number if there is one.
+    Confirm,
+
+    /// Abort the selection.
+    Abort,
+
+    /// Append a digit to the typed number.
+    Digit(char),
+
+    /// Boot the entry at the given index right away, selected by its function key.
+    Option(usize),
+
+    /// Delete the last typed digit.
+    Backspace,
+
+    /// Open the debug shell.
+    Shell,
+
+    /// Make the excluded entries selectable.
+    Unlock,
+
+    /// The key has no meaning in the menu.
+    Ignore,
+}
+
+/// Maps a key press to a menu action.
+///
+/// Besides the digit row, digits are accepted from the keypad with NumLock off, where it sends cursor
+/// keys, and as typed on layouts that need SHIFT for the digit row. F1 to F9 choose options 1 to 9, `d`
+/// opens the debug shell and `x` unlocks the excluded entries.
+///
+/// # Arguments
+///
+/// * `key` - The key read from the console.
+fn action_for_key(key: Key) -> Action {
+    match key {
+        Key::Special(ScanCode::UP) => Action::Up,
+        Key::Special(ScanCode::DOWN) => Action::Down,
+        Key::Special(ScanCode::ESCAPE) => Action::Abort,
+        Key::Special(scan) if (ScanCode::FUNCTION_1..=ScanCode::FUNCTION_9).contains(&scan) => {
+            Action::Option((scan.0 - ScanCode::FUNCTION_1.0) as usize)
+        }
+        Key::Special(scan) => match keypad_digit(scan) {
+            Some(digit) => Action::Digit(digit),
+            None => Action::Ignore,
+        },
+        Key::Printable(c) => match char::from(c) {
+            '\r' | '\n' => Action::Confirm,
+            BACKSPACE => Action::Backspace,
+            'd' | 'D' => Action::Shell,
+            'x' | 'X' => Action::Unlock,
+            ch => match typed_digit(ch) {
+                Some(digit) => Action::Digit(digit),
+                None => Action::Ignore,
+            },
+        },
+    }
+}
+
+/// Returns the digit of a keypad key with NumLock off.
+///
+/// Keypad 8 and 2 send UP and DOWN, which keep moving the highlight, and keypad 5 sends nothing.
+fn keypad_digit(scan: ScanCode) -> Option<char> {
+    match scan {
+        ScanCode::INSERT => Some('0'),
+        ScanCode::END => Some('1'),
+        ScanCode::PAGE_DOWN => Some('3'),
+        ScanCode::LEFT => Some('4'),
+        ScanCode::RIGHT => Some('6'),
+        ScanCode::HOME => Some('7'),
+        ScanCode::PAGE_UP => Some('9'),
+        _ => None,
+    }
+}
+
+/// Returns the ASCII digit of a typed character.
+///
+/// Accepts ASCII and full-width digits, and the characters the unshifted digit row produces on AZERTY
+/// layouts.
+fn typed_digit(ch: char) -> Option<char> {
+    const AZERTY_DIGIT_ROW: [char; 10] = ['\u{e0}', '&', '\u{e9}', '"', '\'', '(', '-', '\u{e8}', '_', '\u{e7}'];
+
+    match ch {
+        '0'..='9' => Some(ch),
+        '\u{ff10}'..='\u{ff19}' => char::from_digit(ch as u32 - 0xff10, 10),
+        _ => AZERTY_DIGIT_ROW
+            .iter()
+            .position(|&key| key == ch)
+            .and_then(|digit| char::from_digit(digit as u32, 10)),
+    }
+}
+
+/// Maps a key press to a menu action, logging both if `log_keys` is set so unrecognized keys can be reported.
+fn read_action(key: Key, log_keys: bool) -> Action {
+    let action = action_for_key(key);
+    if log_keys {
+        log::info!("Key {:?} -> {:?}", key, action);
+    }
+
+    action
+}
+
+/// The result of feeding a key to a `NumberInput`.
+enum NumberEvent {
+    /// The typed number is a valid entry, but more digits could still follow.
+    Pending(usize),
+
+    /// The typed number is a valid entry and no further digit could make it valid, so it is committed.
+    Complete(usize),
+
+    /// The typed number does not correspond to an entry and was discarded.
+    Rejected(usize),
+
+    /// All digits were deleted.
+    Cleared,
+}
+
+/// Accumulates a multi-digit entry number typed by the user.
+struct NumberInput {
+    /// The digits typed so far.
+    digits: String,
+
+    /// Time elapsed since the last digit was typed.
+    idle_us: u64,
+
+    /// The number of entries in the menu.
+    count: usize,
+}
+
+impl NumberInput {
+    fn new(count: usize) -> Self {
+        Self {
+            digits: String::new(),
+            idle_us: 0,
+            count,
+        }
+    }
+
+    /// Returns the typed digits, empty if nothing is pending.
+    fn digits(&self) -> &str {
+        &self.digits
+    }
+
+    /// Returns the 1-based number typed so far.
+    fn value(&self) -> usize {
+        self.digits.parse().unwrap_or(0)
+    }
+
+    /// Appends a digit, rejecting numbers that can't match an entry.
+    fn push(&mut self, digit: char) -> NumberEvent {
+        self.digits.push(digit);
+        self.idle_us = 0;
+
+        let value = self.value();
+        if value == 0 || value > self.count {
+            self.digits.clear();
+            return NumberEvent::Rejected(value);
+        }
+
+        // Once appending another digit would exceed the entry count, there is nothing to wait for.
+        match value.saturating_mul(10) > self.count {
+            true => NumberEvent::Complete(self.take().unwrap_or(value - 1)),
+            false => NumberEvent::Pending(value - 1),
+        }
+    }
+
+    /// Deletes the last digit.
+    fn pop(&mut self) -> NumberEvent {
+        self.digits.pop();
+        self.idle_us = 0;
+
+        match self.digits.is_empty() {
+            true => NumberEvent::Cleared,
+            false => NumberEvent::Pending(self.value() - 1),
+        }
+    }
+
+    /// Advances the inter-key timer.
+    ///
+    /// # Returns
+    ///
+    /// The index of the typed entry once no further digit arrived in time.
+    fn tick(&mut self, elapsed_us: u64) -> Option<usize> {
+        if self.digits.is_empty() {
+            return None;
+        }
+
+        self.idle_us += elapsed_us;
+        match self.idle_us >= INTER_KEY_TIMEOUT_US {
+            true => self.take(),
+            false => None,
+        }
+    }
+
+    /// Commits and clears the typed number.
+    ///
+    /// # Returns
+    ///
+    /// The index of the typed entry, or `None` if nothing was typed.
+    fn take(&mut self) -> Option<usize> {
+        let value = self.value();
+        self.digits.clear();
+
+        value.checked_sub(1)
+    }
+}
+
+/// Lets the user pick one of `entries`, defaulting to `default` when no key is pressed within `timeout_ms`.
+///
+/// The first key press stops the countdown, after which the menu waits for an explicit choice. Leaving
+/// the debug shell shows the menu again with a fresh countdown. Entries from `locked` on are excluded by the
+/// configuration, they are greyed out and can only be chosen after pressing `x`.
+///
+/// # Arguments
+///
+/// * `system_table` - The UEFI system table, used for console input and output.
+/// * `entries` - One description line per candidate.
+/// * `locked` - Index of the first excluded entry, `entries.len()` if there is none.
+/// * `default` - Index of the entry selected by default, which has to be below `locked` unless `timeout_ms` is `0`.
+/// * `timeout_ms` - How long to wait for input before booting the default entry, `0` waits forever.
+/// * `graphical` - Draw the menu on the framebuffer if the screen allows it.
+/// * `log_keys` - Log every key press and the action it was mapped to.
+///
+/// # Returns
+///
+/// The selected entry, `Selection::Aborted` if the user pressed ESC, or `Selection::Firmware` if the user
+/// typed `0`.
+pub(crate) fn select(
+    system_table: &mut SystemTable<Boot>,
+    entries: &[String],
+    locked: usize,
+    default: usize,
+    timeout_ms: u64,
+    graphical: bool,
+    log_keys: bool,
+) -> Selection {
+    loop {
+        if let Some(selection) = show(system_table, entries, locked, default, timeout_ms, graphical, log_keys) {
+            return selection;
+        }
+
+        if let Some(index) = shell::run(system_table, entries.len()) {
+            return Selection::Chosen(index);
+        }
+    }
+}
+
+/// Shows the menu once, on the framebuffer, in place on the console or as log lines.
+///
+/// # Returns
+///
+/// The selection, or `None` if the user asked for the debug shell.
+fn show(
+    system_table: &mut SystemTable<Boot>,
+    entries: &[String],
+    locked: usize,
+    default: usize,
+    timeout_ms: u64,
+    graphical: bool,
+    log_keys: bool,
+) -> Option<Selection> {
+    let _ = system_table.stdin().reset(false);
+
+    // The screen borrows the boot services of a second handle of the system table, as the first one is
+    // needed mutably to read the keys.
+    if let Some(screen_table) = graphical.then(uefi::table::system_table_boot).flatten() {
+        match gfx::Screen::open(screen_table.boot_services(), entries.len() + 1) {
+            Ok(mut screen) => return select_interactive(system_table, &mut screen, entries, locked, default, timeout_ms, log_keys),
+            Err(reason) => log::info!("Graphical menu is unavailable ({}), falling back to the text menu", reason),
+        }
+    }
+
+    match reserve_menu_area(system_table.stdout(), entries.len() + 2) {
+        Some(top) => {
+            let mut console = TextMenu {
+                top,
+                status_row: top + entries.len() + 1,
+                width: console_width(system_table.stdout()),
+                shown_status: String::new(),
+            };
+            select_interactive(system_table, &mut console, entries, locked, default, timeout_ms, log_keys)
+        }
+        None => {
+            log::debug!("Console does not support cursor positioning, falling back to the log based menu");
+            select_with_log(system_table, entries, locked, default, timeout_ms, log_keys)
+        }
+    }
+}
+
+/// Scrolls the console so that `rows` lines below the current position are available for the menu.
+///
+/// # Returns
+///
+/// The first row of the reserved area, or `None` if the console can't position the cursor.
+fn reserve_menu_area(stdout: &mut Output, rows: usize) -> Option<usize> {
+    for _ in 0..rows {
+        stdout.write_str("\n").ok()?;
+    }
+
+    let (_, row) = stdout.cursor_position();
+    let top = row.checked_sub(rows)?;
+
+    stdout.set_cursor_position(0, top).ok()?;
+    Some(top)
+}
+
+/// Returns the usable width of the console, leaving the last column free to avoid automatic line wraps.
+fn console_width(stdout: &Output) -> usize {
+    let columns = match stdout.current_mode() {
+        Ok(Some(mode)) => mode.columns(),
+        _ => DEFAULT_COLUMNS,
+    };
+
+    columns.saturating_sub(1).max(1)
+}
+
+/// Writes `text` at the start of `row`, padded or truncated to exactly `width` characters.
+fn draw_line(stdout: &mut Output, row: usize, width: usize, text: &str) {
+    let _ = stdout.set_cursor_position(0, row);
+    let _ = stdout.write_str(&fit_line(text, width));
+}
+
+/// Pads `text` with spaces to `width` characters, or shortens it with a trailing `...` if it is longer.
+pub(crate) fn fit_line(text: &str, width: usize) -> String {
+    match text.chars().count() > width && width > ELLIPSIS.len() {
+        true => text.chars().take(width - ELLIPSIS.len()).chain(ELLIPSIS.chars()).collect(),
+        false => text.chars().chain(core::iter::repeat(' ')).take(width).collect(),
+    }
+}
+
+/// Draws all entries followed by the firmware entry, highlighting the selected one in inverse video and
+/// greying out the entries from `locked` on.
+fn draw_entries(stdout: &mut Output, entries: &[String], selected: usize, locked: usize, top: usize, width: usize) {
+    for (index, entry) in entries.iter().enumerate() {
+        let (marker, foreground, background) = match (index == selected, index >= locked) {
+            (true, false) => ('>', Color::Black, Color::LightGray),
+            (true, true) => ('>', Color::Black, Color::DarkGray),
+            (false, false) => (' ', Color::LightGray, Color::Black),
+            (false, true) => (' ', Color::DarkGray, Color::Black),
+        };
+
+        let _ = stdout.set_color(foreground, background);
+        draw_line(stdout, top + index, width, &format!("{} {}. {}", marker, index + 1, entry));
+    }
+
+    let _ = stdout.set_color(Color::LightGray, Color::Black);
+    draw_line(stdout, top + entries.len(), width, &format!("  {}", FIRMWARE_ENTRY));
+
+    let _ = stdout.set_color(Color::LightGray, Color::Black);
+}
+
+/// Formats the countdown as the list of seconds elapsed so far, e.g. `5... 4... 3...`.
+///
+/// # Arguments
+///
+/// * `timeout_us` - The total countdown duration.
+/// * `waited_us` - The time already waited.
+fn countdown_text(timeout_us: u64, waited_us: u64) -> String {
+    let total = timeout_us.div_ceil(1_000_000);
+    let remaining = timeout_us.saturating_sub(waited_us).div_ceil(1_000_000).max(1);
+
+    (remaining..=total)
+        .rev()
+        .map(|seconds| format!("{}... ", seconds))
+        .collect::<String>()
+        .trim_end()
+        .into()
+}
+
+/// Where the interactive menu is drawn.
+trait MenuSurface {
+    /// Draws all entries followed by the firmware entry, highlighting the one at `selected` and greying out
+    /// the ones from `locked` on.
+    fn draw_entries(&mut self, system_table: &mut SystemTable<Boot>, entries: &[String], selected: usize, locked: usize);
+
+    /// Draws the status line below the entries, passing the waited and the total time while counting down.
+    fn draw_status(&mut self, system_table: &mut SystemTable<Boot>, status: &str, countdown: Option<(u64, u64)>);
+
+    /// Leaves the console ready for the log output that follows the menu.
+    fn close(&mut self, system_table: &mut SystemTable<Boot>);
+}
+
+/// The menu drawn in place on the text console.
+struct TextMenu {
+    /// The first row of the area reserved for the menu.
+    top: usize,
+
+    /// The row of the status line, below the entries and the firmware entry.
+    status_row: usize,
+
+    /// The usable width of the console.
+    width: usize,
+
+    /// The status line currently shown.
+    shown_status: String,
+}
+
+impl MenuSurface for TextMenu {
+    fn draw_entries(&mut self, system_table: &mut SystemTable<Boot>, entries: &[String], selected: usize, locked: usize) {
+        draw_entries(system_table.stdout(), entries, selected, locked, self.top, self.width);
+    }
+
+    fn draw_status(&mut self, system_table: &mut SystemTable<Boot>, status: &str, _countdown: Option<(u64, u64)>) {
+        if status != self.shown_status {
+            draw_line(system_table.stdout(), self.status_row, self.width, status);
+            self.shown_status = String::from(status);
+        }
+    }
+
+    fn close(&mut self, system_table: &mut SystemTable<Boot>) {
+        let stdout = system_table.stdout();
+        let _ = stdout.set_color(Color::LightGray, Color::Black);
+        let _ = stdout.set_cursor_position(0, self.status_row);
+        let _ = stdout.write_str("\n");
+        let _ = stdout.enable_cursor(true);
+    }
+}
+
+impl MenuSurface for gfx::Screen<'_> {
+    fn draw_entries(&mut self, _system_table: &mut SystemTable<Boot>, entries: &[String], selected: usize, locked: usize) {
+        gfx::Screen::draw_entries(self, entries, FIRMWARE_ENTRY, selected, locked);
+    }
+
+    fn draw_status(&mut self, _system_table: &mut SystemTable<Boot>, status: &str, countdown: Option<(u64, u64)>) {
+        gfx::Screen::draw_status(self, status, countdown);
+    }
+
+    fn close(&mut self, system_table: &mut SystemTable<Boot>) {
+        // Clearing repaints the whole screen in text mode, replacing the menu box.
+        let _ = system_table.stdout().clear();
+    }
+}
+
+/// Runs the interactive menu with arrow key navigation on `surface`.
+///
+/// # Returns
+///
+/// The selection, or `None` if the user asked for the debug shell.
+fn select_interactive(
+    system_table: &mut SystemTable<Boot>,
+    surface: &mut impl MenuSurface,
+    entries: &[String],
+    mut locked: usize,
+    default: usize,
+    timeout_ms: u64,
+    log_keys: bool,
+) -> Option<Selection> {
+    let timeout_us = timeout_ms.saturating_mul(1000);
+
+    let mut selected = default;
+    let mut number = NumberInput::new(entries.len());
+    let mut waited_us: u64 = 0;
+    let mut counting_down = timeout_ms != 0;
+    let mut rejected: Option<usize> = None;
+    let mut excluded: Option<usize> = None;
+
+    let _ = system_table.stdout().enable_cursor(false);
+    surface.draw_entries(system_table, entries, selected, locked);
+
+    let (selection, read_error) = loop {
+        match system_table.stdin().read_key() {
+            Ok(Some(key)) => {
+                counting_down = false;
+                rejected = None;
+                excluded = None;
+
+                let event = match read_action(key, log_keys) {
+                    Action::Up => {
+                        number.take();
+                        selected = selected.checked_sub(1).unwrap_or(entries.len() - 1);
+                        None
+                    }
+                    Action::Down => {
+                        number.take();
+                        selected = (selected + 1) % entries.len();
+                        None
+                    }
+                    Action::Confirm => match number.take().unwrap_or(selected) {
+                        index if index >= locked => {
+                            excluded = Some(index);
+                            None
+                        }
+                        index => break (Some(Selection::Chosen(index)), None),
+                    },
+                    Action::Abort => break (Some(Selection::Aborted), None),
+                    Action::Digit('0') if number.digits().is_empty() => break (Some(Selection::Firmware), None),
+                    Action::Digit(digit) => Some(number.push(digit)),
+                    Action::Option(index) if index < locked => {
+                        surface.draw_entries(system_table, entries, index, locked);
+                        break (Some(Selection::Chosen(index)), None);
+                    }
+                    Action::Option(index) if index < entries.len() => {
+                        number.take();
+                        excluded = Some(index);
+                        None
+                    }
+                    Action::Option(index) => {
+                        number.take();
+                        Some(NumberEvent::Rejected(index + 1))
+                    }
+                    Action::Backspace => Some(number.pop()),
+                    Action::Shell => break (None, None),
+                    Action::Unlock => {
+                        locked = entries.len();
+                        None
+                    }
+                    Action::Ignore => None,
+                };
+
+                match event {
+                    Some(NumberEvent::Pending(index)) => selected = index,
+                    Some(NumberEvent::Complete(index)) if index >= locked => {
+                        selected = index;
+                        excluded = Some(index);
+                    }
+                    Some(NumberEvent::Complete(index)) => {
+                        surface.draw_entries(system_table, entries, index, locked);
+                        break (Some(Selection::Chosen(index)), None);
+                    }
+                    Some(NumberEvent::Rejected(value)) => rejected = Some(value),
+                    Some(NumberEvent::Cleared) | None => {}
+                }
+
+                surface.draw_entries(system_table, entries, selected, locked);
+            }
+            Ok(None) => {
+                match number.tick(POLL_INTERVAL_US) {
+                    Some(index) if index >= locked => excluded = Some(index),
+                    Some(index) => break (Some(Selection::Chosen(index)), None),
+                    None => {}
+                }
+
+                if counting_down {
+                    if waited_us >= timeout_us {
+                        break (Some(Selection::Chosen(selected)), None);
+                    }
+                    waited_us += POLL_INTERVAL_US;
+                }
+
+                system_table.boot_services().stall(POLL_INTERVAL_US as usize);
+            }
+            Err(error) => break (Some(Selection::Chosen(selected)), Some(error)),
+        }
+
+        let status = if let Some(value) = rejected {
+            format!("There is no option {}, enter a number between 1 and {}.", value, entries.len())
+        } else if let Some(index) = excluded {
+            format!("Option {} is excluded by the configuration, press X to unlock the excluded entries.", index + 1)
+        } else if !number.digits().is_empty() {
+            format!("Option: {}_  ENTER to confirm, BACKSPACE to delete, ESC to abort.", number.digits())
+        } else if counting_down {
+            format!("Booting option {} in {}  (press any key to stop)", selected + 1, countdown_text(timeout_us, waited_us))
+        } else if locked < entries.len() {
+            String::from("UP/DOWN to move, ENTER to boot the highlighted entry, X to unlock excluded entries, ESC to abort.")
+        } else {
+            String::from("UP/DOWN to move, ENTER to boot the highlighted entry, D for the debug shell, ESC to abort.")
+        };
+
+        surface.draw_status(system_table, &status, counting_down.then_some((waited_us, timeout_us)));
+    };
+
+    surface.close(system_table);
+
+    if let Some(error) = read_error {
+        log::warn!("Failed to read key from console ({:?}), defaulting to option {}", error, selected + 1);
+    } else if counting_down {
+        log::info!("No selection made within {} ms, defaulting to option {}.", timeout_ms, selected + 1);
+    }
+
+    selection
+}
+
+/// Runs the plain menu that only logs the entries and accepts typed numbers.
+///
+/// # Returns
+///
+/// The selection, or `None` if the user asked for the debug shell.
+fn select_with_log(
+    system_table: &mut SystemTable<Boot>,
+    entries: &[String],
+    mut locked: usize,
+    default: usize,
+    timeout_ms: u64,
+    log_keys: bool,
+) -> Option<Selection> {
+    log::info!("Please select which one to start by typing 1-{}, or 0 to return to the firmware.", entries.len());
+    for (index, entry) in entries.iter().enumerate() {
+        match index >= locked {
+            true => log::info!("  {}. {} (excluded, press X to unlock)", index + 1, entry),
+            false => log::info!("  {}. {}", index + 1, entry),
+        }
+    }
+    log::info!("  {}", FIRMWARE_ENTRY);
+
+    log::info!("Press ENTER to select option {} (default), D for the debug shell. Press ESC to abort.", default + 1);
+    if timeout_ms == 0 {
+        log::info!("Waiting for a selection, there is no timeout.");
+    } else {
+        log::info!("Defaulting to option {} automatically in {} ms if no input is received.", default + 1, timeout_ms);
+    }
+
+    let timeout_us = timeout_ms.saturating_mul(1000);
+    let mut number = NumberInput::new(entries.len());
+    let mut waited_us: u64 = 0;
+    let mut counting_down = timeout_ms != 0;
+    let mut shown_seconds = u64::MAX;
+
+    loop {
+        match system_table.stdin().read_key() {
+            Ok(Some(key)) => {
+                if counting_down {
+                    counting_down = false;
+                    log::info!("Countdown stopped, waiting for a selection.");
+                }
+
+                let event = match read_action(key, log_keys) {
+                    Action::Digit('0') if number.digits().is_empty() => return Some(Selection::Firmware),
+                    Action::Digit(digit) => number.push(digit),
+                    Action::Option(index) if index < locked => return Some(Selection::Chosen(index)),
+                    Action::Option(index) if index < entries.len() => {
+                        number.take();
+                        NumberEvent::Complete(index)
+                    }
+                    Action::Option(index) => {
+                        number.take();
+                        NumberEvent::Rejected(index + 1)
+                    }
+                    Action::Backspace => number.pop(),
+                    Action::Confirm => match number.take().unwrap_or(default) {
+                        index if index >= locked => NumberEvent::Complete(index),
+                        index => return Some(Selection::Chosen(index)),
+                    },
+                    Action::Abort => return Some(Selection::Aborted),
+                    Action::Shell => return None,
+                    Action::Unlock if locked < entries.len() => {
+                        locked = entries.len();
+                        log::info!("Excluded entries unlocked.");
+                        continue;
+                    }
+                    Action::Up | Action::Down | Action::Unlock | Action::Ignore => continue,
+                };
+
+                match event {
+                    NumberEvent::Pending(_) => log::info!("Option: {}", number.digits()),
+                    NumberEvent::Complete(index) if index >= locked => {
+                        log::warn!("Option {} is excluded by the configuration, press X to unlock the excluded entries.", index + 1)
+                    }
+                    NumberEvent::Complete(index) => return Some(Selection::Chosen(index)),
+                    NumberEvent::Rejected(value) => log::warn!("There is no option {}, enter a number between 1 and {}.", value, entries.len()),
+                    NumberEvent::Cleared => log::info!("Option: (none)"),
+                }
+            }
+            Ok(None) => {
+                match number.tick(POLL_INTERVAL_US) {
+                    Some(index) if index >= locked => {
+                        log::warn!("Option {} is excluded by the configuration, press X to unlock the excluded entries.", index + 1)
+                    }
+                    Some(index) => return Some(Selection::Chosen(index)),
+                    None => {}
+                }
+
+                if counting_down {
+                    if waited_us >= timeout_us {
+                        log::info!("No selection made within {} ms, defaulting to option {}.", timeout_ms, default + 1);
+                        return Some(Selection::Chosen(default));
+                    }
+
+                    let seconds = (timeout_us - waited_us).div_ceil(1_000_000);
+                    if seconds != shown_seconds {
+                        shown_seconds = seconds;
+                        log::info!("Booting option {} in {}...", default + 1, seconds);
+                    }
+
+                    waited_us += POLL_INTERVAL_US;
+                }
+
+                system_table.boot_services().stall(POLL_INTERVAL_US as usize);
+            }
+            Err(error) => {
+                log::warn!("Failed to read key from console ({:?}), defaulting to option {}", error, default + 1);
+                return Some(Selection::Chosen(default));
+            }
+        }
+    }
+}
+
+/// Shows the only candidate found and boots it after `timeout_ms`, unless the user intervenes.
+///
+/// The firmware may still be enumerating slow devices when the search ran, so the candidate can be a
+/// recovery partition while the real ESP shows up moments later.
+///
+/// # Arguments
+///
+/// * `system_table` - The UEFI system table, used for console input.
+/// * `description` - The description of the candidate.
+/// * `timeout_ms` - How long to wait before booting the candidate.
+///
+/// # Returns
+///
+/// `SingleChoice::Proceed` once the time is up or the user pressed ENTER, `SingleChoice::Aborted` for ESC
+/// and `SingleChoice::Rescan` for `r`.
+pub(crate) fn confirm_single(system_table: &mut SystemTable<Boot>, description: &str, timeout_ms: u64) -> SingleChoice {
+    let _ = system_table.stdin().reset(false);
+    log::info!("Booting {} in {} ms.", description, timeout_ms);
+    log::info!("Press ENTER to boot now, R to rescan the filesystems, ESC to abort.");
+
+    let mut waited_us: u64 = 0;
+    while waited_us < timeout_ms.saturating_mul(1000) {
+        match system_table.stdin().read_key() {
+            Ok(Some(key)) => {
+                if let Some(choice) = single_choice_for_key(key) {
+                    return choice;
+                }
+            }
+            Ok(None) => {
+                system_table.boot_services().stall(POLL_INTERVAL_US as usize);
+                waited_us += POLL_INTERVAL_US;
+            }
+            Err(error) => {
+                log::warn!("Failed to read key from console ({:?}), booting the candidate", error);
+                return SingleChoice::Proceed;
+            }
+        }
+    }
+
+    SingleChoice::Proceed
+}
+
+/// Maps a key pressed while a single candidate is shown to its outcome, `None` for keys without meaning.
+fn single_choice_for_key(key: Key) -> Option<SingleChoice> {
+    match key {
+        Key::Special(ScanCode::ESCAPE) => Some(SingleChoice::Aborted),
+        Key::Printable(c) => match char::from(c) {
+            '\r' | '\n' => Some(SingleChoice::Proceed),
+            'r' | 'R' => Some(SingleChoice::Rescan),
+            _ => None,
+        },
+        Key::Special(_) => None,
+    }
+}
+
+/// Checks which of `keys` are pressed before the loader has been running for `deadline_ms`.
+///
+/// Keys typed or held while the firmware started the loader are already queued. The console is polled
+/// until all keys were seen or the deadline passed, or read once if the time since the start is unknown.
+///
+/// # Arguments
+///
+/// * `system_table` - The UEFI system table, used for console input.
+/// * `keys` - The keys to look for, compared case-insensitively.
+/// * `deadline_ms` - Time since the loader started after which polling stops.
+///
+/// # Returns
+///
+/// For every key whether it was pressed.
+pub(crate) fn early_keys<const N: usize>(system_table: &mut SystemTable<Boot>, keys: [char; N], deadline_ms: u64) -> [bool; N] {
+    let mut pressed = [false; N];
+
+    while !pressed.iter().all(|&pressed| pressed) {
+        match system_table.stdin().read_key() {
+            Ok(Some(Key::Printable(c))) => {
+                if let Some(index) = keys.iter().position(|key| char::from(c).eq_ignore_ascii_case(key)) {
+                    pressed[index] = true;
+                }
+            }
+            Ok(Some(_)) => {}
+            Ok(None) if logging::elapsed_ms().is_some_and(|elapsed| elapsed < deadline_ms) => {
+                system_table.boot_services().stall(POLL_INTERVAL_US as usize)
+            }
+            Ok(None) | Err(_) => break,
+        }
+    }
+
+    pressed
+}
+
+/// Asks a yes/no question and waits for the answer.
+///
+/// # Arguments
+///
+/// * `system_table` - The UEFI system table, used for console input.
+/// * `question` - The question to display, without the answer hint.
+///
+/// # Returns
+///
+/// `true` if the user pressed `y`, `false` for `n`, ENTER or ESC.
+pub(crate) fn confirm(system_table: &mut SystemTable<Boot>, question: &str) -> bool {
+    let _ = system_table.stdin().reset(false);
+    log::warn!("{} [y/N]", question);
+
+    loop {
+        match system_table.stdin().read_key() {
+            Ok(Some(Key::Printable(c))) => match char::from(c) {
+                'y' | 'Y' => return true,
+                'n' | 'N' | '\r' | '\n' => return false,
+                _ => {}
+            },
+            Ok(Some(Key::Special(ScanCode::ESCAPE))) => return false,
+            Ok(Some(_)) => {}
+            Ok(None) => system_table.boot_services().stall(POLL_INTERVAL_US as usize),
+            Err(error) => {
+                log::warn!("Failed to read key from console ({:?}), assuming no", error);
+                return false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{action_for_key, countdown_text, fit_line, single_choice_for_key, Action, SingleChoice},
+        uefi::{
+            proto::console::text::{Key, ScanCode},
+            Char16,
+        },
+    };
+
+    fn printable(ch: char) -> Key {
+        Key::Printable(Char16::try_from(ch).unwrap())
+    }
+
+    #[test]
+    fn lines_fit_the_console_width() {
+        assert_eq!(fit_line("short", 8), "short   ");
+        assert_eq!(fit_line("exactly8", 8), "exactly8");
+        assert_eq!(fit_line(r"handle 3: \EFI\Microsoft\Boot\bootmgfw.efi", 16), r"handle 3: \EF...");
+        assert_eq!(fit_line("abcdef", 2), "ab");
+    }
+
+    #[test]
+    fn countdown_lists_elapsed_seconds() {
+        assert_eq!(countdown_text(5_000_000, 0), "5...");
+        assert_eq!(countdown_text(5_000_000, 1_500_000), "5... 4...");
+        assert_eq!(countdown_text(5_000_000, 5_000_000), "5... 4... 3... 2... 1...");
+        assert_eq!(countdown_text(2_500_000, 0), "3...");
+    }
+
+    #[test]
+    fn navigation_keys_map_to_actions() {
+        assert_eq!(action_for_key(Key::Special(ScanCode::UP)), Action::Up);
+        assert_eq!(action_for_key(Key::Special(ScanCode::DOWN)), Action::Down);
+        assert_eq!(action_for_key(Key::Special(ScanCode::ESCAPE)), Action::Abort);
+        assert_eq!(action_for_key(printable('\r')), Action::Confirm);
+        assert_eq!(action_for_key(printable('\u{8}')), Action::Backspace);
+        assert_eq!(action_for_key(printable('q')), Action::Ignore);
+        assert_eq!(action_for_key(Key::Special(ScanCode::FUNCTION_10)), Action::Ignore);
+        assert_eq!(action_for_key(printable('d')), Action::Shell);
+        assert_eq!(action_for_key(printable('X')), Action::Unlock);
+    }
+
+    #[test]
+    fn digits_are_recognized_in_all_encodings() {
+        assert_eq!(action_for_key(printable('2')), Action::Digit('2'));
+        assert_eq!(action_for_key(printable('\u{ff17}')), Action::Digit('7'));
+        assert_eq!(action_for_key(printable('\u{e9}')), Action::Digit('2'));
+        assert_eq!(action_for_key(printable('\u{e0}')), Action::Digit('0'));
+        assert_eq!(action_for_key(Key::Special(ScanCode::END)), Action::Digit('1'));
+        assert_eq!(action_for_key(Key::Special(ScanCode::PAGE_UP)), Action::Digit('9'));
+        assert_eq!(action_for_key(Key::Special(ScanCode::INSERT)), Action::Digit('0'));
+    }
+
+    #[test]
+    fn single_candidate_keys() {
+        assert_eq!(single_choice_for_key(Key::Special(ScanCode::ESCAPE)), Some(SingleChoice::Aborted));
+        assert_eq!(single_choice_for_key(printable('R')), Some(SingleChoice::Rescan));
+        assert_eq!(single_choice_for_key(printable('\r')), Some(SingleChoice::Proceed));
+        assert_eq!(single_choice_for_key(printable('x')), None);
+        assert_eq!(single_choice_for_key(Key::Special(ScanCode::UP)), None);
+    }
+
+    #[test]
+    fn function_keys_choose_options() {
+        assert_eq!(action_for_key(Key::Special(ScanCode::FUNCTION_1)), Action::Option(0));
+        assert_eq!(action_for_key(Key::Special(ScanCode::FUNCTION_9)), Action::Option(8));
+    }
+}
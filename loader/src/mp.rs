@@ -0,0 +1,128 @@
+//! Runs checks on every processor through the MP Services protocol.
+//!
+//! Checks are plain functions without access to boot services, logging or allocation, which application
+//! processors must not use. They run on one processor at a time, the bootstrap processor included, and
+//! their results are collected into one entry per processor. Firmware without MP Services only runs the
+//! check on the bootstrap processor.
+
+extern crate alloc;
+
+use {
+    alloc::vec::Vec,
+    core::{ffi::c_void, time::Duration},
+    uefi::{prelude::*, proto::pi::mp::MpServices},
+};
+
+/// How long a single application processor may take to run a check.
+const AP_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// What running a check on one processor produced.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum CpuOutcome<T> {
+    /// The check ran and returned a value.
+    Ran(T),
+
+    /// The processor is disabled, the firmware doesn't schedule anything on it.
+    Disabled,
+
+    /// The firmware failed to run the check on the processor, e.g. because it timed out.
+    Failed(Status),
+}
+
+/// The result of a check on one processor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct CpuResult<T> {
+    /// The processor number assigned by MP Services.
+    pub number: usize,
+
+    /// The local APIC ID of the processor.
+    pub apic_id: u64,
+
+    /// Whether this is the bootstrap processor the loader runs on.
+    pub is_bsp: bool,
+
+    /// What the check produced.
+    pub outcome: CpuOutcome<T>,
+}
+
+/// The check and its result, shared with the processor running it.
+struct Dispatch<T> {
+    check: fn() -> T,
+    result: Option<T>,
+}
+
+/// Runs `check` on every processor.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+/// * `check` - The check to run, which must not use boot services.
+///
+/// # Returns
+///
+/// One entry per processor, ordered by processor number.
+pub(crate) fn run_on_each_cpu<T: Copy>(boot_services: &BootServices, check: fn() -> T) -> Vec<CpuResult<T>> {
+    let bsp_only = || {
+        alloc::vec![CpuResult {
+            number: 0,
+            apic_id: 0,
+            is_bsp: true,
+            outcome: CpuOutcome::Ran(check()),
+        }]
+    };
+
+    let Some(mp_services) = boot_services
+        .get_handle_for_protocol::<MpServices>()
+        .and_then(|handle| boot_services.open_protocol_exclusive::<MpServices>(handle))
+        .ok()
+    else {
+        log::debug!("No MP Services protocol, checking the bootstrap processor only");
+        return bsp_only();
+    };
+
+    let Ok(count) = mp_services.get_number_of_processors() else {
+        return bsp_only();
+    };
+
+    (0..count.total)
+        .map(|number| {
+            let Ok(info) = mp_services.get_processor_info(number) else {
+                return CpuResult {
+                    number,
+                    apic_id: 0,
+                    is_bsp: false,
+                    outcome: CpuOutcome::Failed(Status::NOT_FOUND),
+                };
+            };
+
+            let is_bsp = info.is_bsp();
+            let outcome = match (is_bsp, info.is_enabled()) {
+                (true, _) => CpuOutcome::Ran(check()),
+                (false, false) => CpuOutcome::Disabled,
+                (false, true) => {
+                    let mut dispatch = Dispatch { check, result: None };
+                    let argument = &mut dispatch as *mut Dispatch<T> as *mut c_void;
+
+                    match mp_services.startup_this_ap(number, run_dispatch::<T>, argument, None, Some(AP_TIMEOUT)) {
+                        Ok(()) => dispatch.result.map_or(CpuOutcome::Failed(Status::ABORTED), CpuOutcome::Ran),
+                        Err(error) => CpuOutcome::Failed(error.status()),
+                    }
+                }
+            };
+
+            CpuResult {
+                number,
+                apic_id: info.processor_id,
+                is_bsp,
+                outcome,
+            }
+        })
+        .collect()
+}
+
+/// Runs the check of a `Dispatch` on an application processor.
+extern "efiapi" fn run_dispatch<T>(argument: *mut c_void) {
+    // Safety: `run_on_each_cpu` passes a `Dispatch<T>` that outlives the blocking `startup_this_ap`.
+    let dispatch = unsafe { &mut *(argument as *mut Dispatch<T>) };
+    dispatch.result = Some((dispatch.check)());
+}
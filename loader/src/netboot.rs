@@ -0,0 +1,191 @@
+//! Fetching the hypervisor image from a TFTP server through the PXE Base Code protocol.
+//!
+//! Enabled with `hypervisor_source = net:<server>/<path>` in `illusion.cfg`, where `<server>` is an IPv4
+//! address. The network interface is configured through DHCP if the firmware hasn't done so already.
+
+extern crate alloc;
+
+use {
+    crate::images::{ProbeTimer, MAX_FILE_SIZE},
+    alloc::{string::String, vec::Vec},
+    core::fmt::{self, Display},
+    uefi::{
+        prelude::*,
+        proto::network::{pxe::BaseCode, IpAddress},
+        CStr8,
+    },
+};
+
+/// Prefix of `hypervisor_source` values naming a TFTP server.
+const NET_PREFIX: &str = "net:";
+
+/// Default time the whole transfer may take before the loader falls back to the local search.
+pub(crate) const DEFAULT_NET_TIMEOUT_MS: u64 = 30_000;
+
+/// A file on a TFTP server.
+#[derive(Debug, PartialEq)]
+pub(crate) struct NetSource {
+    /// IPv4 address of the server.
+    pub server: [u8; 4],
+
+    /// Path of the file on the server.
+    pub path: String,
+}
+
+impl Display for NetSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.server;
+        write!(f, "{}{}.{}.{}.{}/{}", NET_PREFIX, a, b, c, d, self.path)
+    }
+}
+
+/// Reasons why the image could not be fetched from the network.
+#[derive(Debug)]
+pub(crate) enum NetError {
+    /// The firmware provides no PXE Base Code protocol.
+    NoNetwork,
+
+    /// Starting the network interface or DHCP failed.
+    Setup(Status),
+
+    /// The file is larger than `MAX_FILE_SIZE`.
+    TooLarge(u64),
+
+    /// The TFTP transfer failed.
+    Transfer(Status),
+
+    /// The transfer took longer than the configured timeout.
+    Timeout(u64),
+}
+
+impl Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetError::NoNetwork => write!(f, "no PXE Base Code protocol, is network boot enabled in the firmware?"),
+            NetError::Setup(status) => write!(f, "failed to configure the network interface ({:?})", status),
+            NetError::TooLarge(size) => write!(f, "file is {} bytes, more than the {} bytes limit", size, MAX_FILE_SIZE),
+            NetError::Transfer(status) => write!(f, "TFTP transfer failed ({:?})", status),
+            NetError::Timeout(timeout_ms) => write!(f, "transfer took longer than {} ms", timeout_ms),
+        }
+    }
+}
+
+/// Parses a `net:<server>/<path>` source.
+///
+/// # Returns
+///
+/// The server and path, or a description of why the value was rejected.
+pub(crate) fn parse_source(value: &str) -> Result<NetSource, &'static str> {
+    let rest = value.strip_prefix(NET_PREFIX).ok_or("expected net:<server>/<path>")?;
+    let (server, path) = rest.split_once('/').ok_or("expected net:<server>/<path>")?;
+
+    let mut octets = [0u8; 4];
+    let mut parts = server.split('.');
+    for octet in octets.iter_mut() {
+        *octet = parts.next().and_then(|part| part.parse().ok()).ok_or("server must be an IPv4 address")?;
+    }
+    if parts.next().is_some() {
+        return Err("server must be an IPv4 address");
+    }
+
+    if path.is_empty() || !path.is_ascii() {
+        return Err("path must be a non-empty ASCII string");
+    }
+
+    Ok(NetSource {
+        server: octets,
+        path: String::from(path),
+    })
+}
+
+/// Downloads a file from a TFTP server.
+///
+/// Boot services are synchronous, so `timeout_ms` is checked between the steps of the transfer. A single
+/// TFTP request is bounded by the firmware's own retransmission timeout.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+/// * `source` - The server and file to download.
+/// * `timeout_ms` - How long the whole transfer may take, `0` disables the check.
+///
+/// # Returns
+///
+/// The contents of the file, or why it could not be downloaded.
+pub(crate) fn fetch(boot_services: &BootServices, source: &NetSource, timeout_ms: u64) -> Result<Vec<u8>, NetError> {
+    let timer = ProbeTimer::start(boot_services, timeout_ms);
+    let check_timeout = || match timer.expired() {
+        true => Err(NetError::Timeout(timeout_ms)),
+        false => Ok(()),
+    };
+
+    let handle = boot_services
+        .find_handles::<BaseCode>()
+        .ok()
+        .and_then(|handles| handles.first().copied())
+        .ok_or(NetError::NoNetwork)?;
+    let mut base_code = boot_services
+        .open_protocol_exclusive::<BaseCode>(handle)
+        .map_err(|_| NetError::NoNetwork)?;
+
+    if !base_code.mode().started {
+        base_code.start(false).map_err(|error| NetError::Setup(error.status()))?;
+    }
+
+    if !base_code.mode().dhcp_ack_received {
+        log::info!("Configuring network interface through DHCP..");
+        base_code.dhcp(false).map_err(|error| NetError::Setup(error.status()))?;
+    }
+    check_timeout()?;
+
+    let server = IpAddress::new_v4(source.server);
+    let path: Vec<u8> = source.path.bytes().chain([0]).collect();
+    let path = CStr8::from_bytes_with_nul(&path).map_err(|_| NetError::Transfer(Status::INVALID_PARAMETER))?;
+
+    let size = base_code
+        .tftp_get_file_size(&server, path)
+        .map_err(|error| NetError::Transfer(error.status()))?;
+    if size > MAX_FILE_SIZE {
+        return Err(NetError::TooLarge(size));
+    }
+    check_timeout()?;
+
+    log::info!("Downloading {} bytes from {}..", size, source);
+    let mut bytes = alloc::vec![0u8; size as usize];
+    let read = base_code
+        .tftp_read_file(&server, path, Some(&mut bytes))
+        .map_err(|error| NetError::Transfer(error.status()))?;
+    check_timeout()?;
+
+    bytes.truncate(read as usize);
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_source, NetSource};
+
+    #[test]
+    fn parses_server_and_path() {
+        let source = parse_source("net:192.168.1.10/images/illusion.efi").unwrap();
+        assert_eq!(
+            source,
+            NetSource {
+                server: [192, 168, 1, 10],
+                path: "images/illusion.efi".into(),
+            }
+        );
+        assert_eq!(alloc::format!("{}", source), "net:192.168.1.10/images/illusion.efi");
+    }
+
+    #[test]
+    fn rejects_invalid_sources() {
+        assert!(parse_source("tftp:192.168.1.10/illusion.efi").is_err());
+        assert!(parse_source("net:192.168.1.10").is_err());
+        assert!(parse_source("net:192.168.1/illusion.efi").is_err());
+        assert!(parse_source("net:192.168.1.10.5/illusion.efi").is_err());
+        assert!(parse_source("net:192.168.1.256/illusion.efi").is_err());
+        assert!(parse_source("net:fileserver/illusion.efi").is_err());
+        assert!(parse_source("net:192.168.1.10/").is_err());
+    }
+}
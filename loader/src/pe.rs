@@ -0,0 +1,531 @@
+//! Lightweight PE/COFF header validation of the hypervisor image.
+//!
+//! Firmware `LoadImage` reports corrupt images with an opaque status. Checking the headers beforehand
+//! allows the loader to say what is actually wrong with the file.
+
+extern crate alloc;
+
+use {
+    alloc::{string::String, vec::Vec},
+    core::{
+        fmt::{self, Display},
+        ops::Range,
+    },
+};
+
+/// `MZ`, the DOS header signature.
+const DOS_SIGNATURE: u16 = 0x5a4d;
+
+/// Offset of `e_lfanew` in the DOS header.
+const DOS_LFANEW_OFFSET: usize = 0x3c;
+
+/// `PE\0\0`, the NT headers signature.
+const NT_SIGNATURE: u32 = 0x0000_4550;
+
+/// Size of the COFF file header following the NT signature.
+const FILE_HEADER_SIZE: usize = 20;
+
+/// Size of a single section header.
+const SECTION_HEADER_SIZE: usize = 40;
+
+/// Offset of the characteristics in a section header.
+const SECTION_CHARACTERISTICS_OFFSET: usize = 36;
+
+/// `IMAGE_SCN_MEM_WRITE`, set for sections that are writable once loaded.
+const SECTION_MEM_WRITE: u32 = 0x8000_0000;
+
+/// `IMAGE_FILE_MACHINE_AMD64`.
+const MACHINE_AMD64: u16 = 0x8664;
+
+/// `IMAGE_FILE_MACHINE_I386`.
+const MACHINE_I386: u16 = 0x014c;
+
+/// Optional header magic of a PE32 (32-bit) image.
+const PE32_MAGIC: u16 = 0x010b;
+
+/// Optional header magic of a PE32+ (64-bit) image.
+const PE32_PLUS_MAGIC: u16 = 0x020b;
+
+/// `IMAGE_SUBSYSTEM_EFI_APPLICATION`.
+const SUBSYSTEM_EFI_APPLICATION: u16 = 10;
+
+/// `IMAGE_SUBSYSTEM_EFI_BOOT_SERVICE_DRIVER`.
+const SUBSYSTEM_EFI_BOOT_SERVICE_DRIVER: u16 = 11;
+
+/// `IMAGE_SUBSYSTEM_EFI_RUNTIME_DRIVER`, the subsystem the hypervisor is linked with.
+pub(crate) const SUBSYSTEM_EFI_RUNTIME_DRIVER: u16 = 12;
+
+/// Offsets of the fields read from the PE32+ optional header.
+const OPTIONAL_ENTRY_POINT_OFFSET: usize = 16;
+const OPTIONAL_SIZE_OF_IMAGE_OFFSET: usize = 56;
+const OPTIONAL_SIZE_OF_HEADERS_OFFSET: usize = 60;
+const OPTIONAL_SUBSYSTEM_OFFSET: usize = 68;
+const OPTIONAL_NUMBER_OF_RVA_AND_SIZES_OFFSET: usize = 108;
+
+/// Offset of the size of the certificate table, the fifth data directory, in the PE32+ optional header.
+const OPTIONAL_CERTIFICATE_TABLE_SIZE_OFFSET: usize = 148;
+
+/// Index of the certificate table in the data directories.
+const CERTIFICATE_TABLE_INDEX: u32 = 4;
+
+/// Minimum optional header size that contains all the fields above.
+const MIN_OPTIONAL_HEADER_SIZE: usize = 70;
+
+/// Summary of a validated image.
+#[derive(Debug, PartialEq)]
+pub(crate) struct PeInfo {
+    /// Size of the image once loaded into memory.
+    pub size_of_image: u32,
+
+    /// Relative virtual address of the entry point.
+    pub entry_point: u32,
+
+    /// Number of sections in the image.
+    pub section_count: usize,
+
+    /// The EFI subsystem, which decides whether the image stays resident after its entry point returns.
+    pub subsystem: u16,
+
+    /// Whether the image carries an Authenticode signature. Says nothing about whether it is trusted.
+    pub is_signed: bool,
+}
+
+/// Reasons why an image is rejected.
+#[derive(Debug, PartialEq)]
+pub(crate) enum PeError {
+    /// The file ends before the named header is complete.
+    TruncatedHeader(&'static str),
+
+    /// The file does not start with `MZ`.
+    MissingDosSignature,
+
+    /// `e_lfanew` does not point at `PE\0\0`.
+    MissingNtSignature,
+
+    /// The image is built for 32-bit x86.
+    Image32Bit,
+
+    /// The image is built for another machine type.
+    UnsupportedMachine(u16),
+
+    /// The optional header magic is neither PE32 nor PE32+.
+    UnknownOptionalHeader(u16),
+
+    /// The image is neither an EFI application nor an EFI driver.
+    NotEfiApplication(u16),
+
+    /// The headers are larger than the whole image.
+    HeadersExceedImage { size_of_headers: u32, size_of_image: u32 },
+
+    /// The raw data of a section extends beyond the end of the file.
+    TruncatedSection(String),
+
+    /// A section is mapped beyond `SizeOfImage`.
+    SectionOutsideImage(String),
+}
+
+impl Display for PeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeError::TruncatedHeader(header) => write!(f, "file truncated in the {}", header),
+            PeError::MissingDosSignature => write!(f, "missing MZ signature, not a PE image"),
+            PeError::MissingNtSignature => write!(f, "missing PE signature at the offset given by the DOS header"),
+            PeError::Image32Bit => write!(f, "image is 32-bit"),
+            PeError::UnsupportedMachine(machine) => write!(f, "image is built for machine type {:#06x}, expected x86-64", machine),
+            PeError::UnknownOptionalHeader(magic) => write!(f, "unknown optional header magic {:#06x}", magic),
+            PeError::NotEfiApplication(subsystem) => write!(f, "image subsystem is {}, expected EFI application or driver", subsystem),
+            PeError::HeadersExceedImage {
+                size_of_headers,
+                size_of_image,
+            } => {
+                write!(f, "headers ({:#x} bytes) exceed the image size ({:#x} bytes)", size_of_headers, size_of_image)
+            }
+            PeError::TruncatedSection(name) => write!(f, "file truncated at section {}", name),
+            PeError::SectionOutsideImage(name) => write!(f, "section {} extends beyond the image size", name),
+        }
+    }
+}
+
+/// Validates the headers of an x86-64 EFI application or driver.
+///
+/// # Arguments
+///
+/// * `image` - The raw on-disk bytes of the image.
+///
+/// # Returns
+///
+/// A summary of the image, or the first problem found.
+pub(crate) fn validate(image: &[u8]) -> Result<PeInfo, PeError> {
+    if read_u16(image, 0).ok_or(PeError::TruncatedHeader("DOS header"))? != DOS_SIGNATURE {
+        return Err(PeError::MissingDosSignature);
+    }
+
+    let nt_offset = read_u32(image, DOS_LFANEW_OFFSET).ok_or(PeError::TruncatedHeader("DOS header"))? as usize;
+    if read_u32(image, nt_offset).ok_or(PeError::TruncatedHeader("NT headers"))? != NT_SIGNATURE {
+        return Err(PeError::MissingNtSignature);
+    }
+
+    let file_header = nt_offset + 4;
+    if image.len() < file_header + FILE_HEADER_SIZE {
+        return Err(PeError::TruncatedHeader("COFF file header"));
+    }
+
+    match read_u16(image, file_header).unwrap_or(0) {
+        MACHINE_AMD64 => {}
+        MACHINE_I386 => return Err(PeError::Image32Bit),
+        machine => return Err(PeError::UnsupportedMachine(machine)),
+    }
+
+    let section_count = read_u16(image, file_header + 2).unwrap_or(0) as usize;
+    let optional_header_size = read_u16(image, file_header + 16).unwrap_or(0) as usize;
+
+    let optional_header = file_header + FILE_HEADER_SIZE;
+    match read_u16(image, optional_header).ok_or(PeError::TruncatedHeader("optional header"))? {
+        PE32_PLUS_MAGIC => {}
+        PE32_MAGIC => return Err(PeError::Image32Bit),
+        magic => return Err(PeError::UnknownOptionalHeader(magic)),
+    }
+
+    if optional_header_size < MIN_OPTIONAL_HEADER_SIZE || image.len() < optional_header + optional_header_size {
+        return Err(PeError::TruncatedHeader("optional header"));
+    }
+
+    let entry_point = read_u32(image, optional_header + OPTIONAL_ENTRY_POINT_OFFSET).unwrap_or(0);
+    let size_of_image = read_u32(image, optional_header + OPTIONAL_SIZE_OF_IMAGE_OFFSET).unwrap_or(0);
+    let size_of_headers = read_u32(image, optional_header + OPTIONAL_SIZE_OF_HEADERS_OFFSET).unwrap_or(0);
+    let subsystem = read_u16(image, optional_header + OPTIONAL_SUBSYSTEM_OFFSET).unwrap_or(0);
+
+    if !matches!(subsystem, SUBSYSTEM_EFI_APPLICATION | SUBSYSTEM_EFI_BOOT_SERVICE_DRIVER | SUBSYSTEM_EFI_RUNTIME_DRIVER) {
+        return Err(PeError::NotEfiApplication(subsystem));
+    }
+
+    if size_of_headers > size_of_image {
+        return Err(PeError::HeadersExceedImage {
+            size_of_headers,
+            size_of_image,
+        });
+    }
+
+    let section_table = optional_header + optional_header_size;
+    if image.len() < section_table + section_count * SECTION_HEADER_SIZE {
+        return Err(PeError::TruncatedHeader("section table"));
+    }
+
+    for index in 0..section_count {
+        let header = &image[section_table + index * SECTION_HEADER_SIZE..][..SECTION_HEADER_SIZE];
+        let name = section_name(&header[..8]);
+
+        let virtual_size = read_u32(header, 8).unwrap_or(0) as u64;
+        let virtual_address = read_u32(header, 12).unwrap_or(0) as u64;
+        let raw_size = read_u32(header, 16).unwrap_or(0) as u64;
+        let raw_offset = read_u32(header, 20).unwrap_or(0) as u64;
+
+        if raw_size != 0 && raw_offset + raw_size > image.len() as u64 {
+            return Err(PeError::TruncatedSection(name));
+        }
+
+        // A zero virtual size means the section occupies its raw size in memory.
+        let mapped_size = if virtual_size == 0 { raw_size } else { virtual_size };
+        if virtual_address + mapped_size > size_of_image as u64 {
+            return Err(PeError::SectionOutsideImage(name));
+        }
+    }
+
+    Ok(PeInfo {
+        size_of_image,
+        entry_point,
+        section_count,
+        subsystem,
+        is_signed: has_certificate_table(image, optional_header, optional_header_size),
+    })
+}
+
+/// Finds the unused bytes after the writable sections of an image loaded into memory.
+///
+/// Each section is mapped at its virtual address and padded up to the next section or the end of the
+/// image. Only writable sections are reported, firmware may map the others read-only.
+///
+/// # Arguments
+///
+/// * `image` - The loaded image, `SizeOfImage` bytes starting at the image base.
+///
+/// # Returns
+///
+/// The offsets of the padding behind every writable section, empty if the headers can't be parsed.
+pub(crate) fn writable_section_slack(image: &[u8]) -> Vec<Range<usize>> {
+    let Some(sections) = loaded_sections(image) else {
+        return Vec::new();
+    };
+
+    let mut slack = Vec::new();
+    for (index, &(start, size, characteristics)) in sections.iter().enumerate() {
+        let end = start.saturating_add(size);
+        let next = sections.get(index + 1).map(|&(next, _, _)| next).unwrap_or(image.len());
+
+        if characteristics & SECTION_MEM_WRITE != 0 && end < next && next <= image.len() {
+            slack.push(end..next);
+        }
+    }
+
+    slack
+}
+
+/// Finds the raw data of a section in the on-disk image.
+///
+/// # Arguments
+///
+/// * `image` - The raw on-disk bytes of the image.
+/// * `name` - The section name, e.g. `.ilcfg`.
+///
+/// # Returns
+///
+/// The file offsets of the raw data, `None` if there is no such section or it extends beyond the file.
+pub(crate) fn find_section(image: &[u8], name: &str) -> Option<Range<usize>> {
+    let nt_offset = read_u32(image, DOS_LFANEW_OFFSET)? as usize;
+    if read_u16(image, 0)? != DOS_SIGNATURE || read_u32(image, nt_offset)? != NT_SIGNATURE {
+        return None;
+    }
+
+    let file_header = nt_offset + 4;
+    let section_count = read_u16(image, file_header + 2)? as usize;
+    let section_table = file_header + FILE_HEADER_SIZE + read_u16(image, file_header + 16)? as usize;
+
+    for index in 0..section_count {
+        let header = image.get(section_table + index * SECTION_HEADER_SIZE..)?.get(..SECTION_HEADER_SIZE)?;
+        if section_name(&header[..8]) != name {
+            continue;
+        }
+
+        let raw_size = read_u32(header, 16)? as usize;
+        let raw_offset = read_u32(header, 20)? as usize;
+        let end = raw_offset.checked_add(raw_size).filter(|&end| end <= image.len())?;
+        return Some(raw_offset..end);
+    }
+
+    None
+}
+
+/// Reads the virtual address, virtual size and characteristics of every section, sorted by address.
+fn loaded_sections(image: &[u8]) -> Option<Vec<(usize, usize, u32)>> {
+    let nt_offset = read_u32(image, DOS_LFANEW_OFFSET)? as usize;
+    if read_u16(image, 0)? != DOS_SIGNATURE || read_u32(image, nt_offset)? != NT_SIGNATURE {
+        return None;
+    }
+
+    let file_header = nt_offset + 4;
+    let section_count = read_u16(image, file_header + 2)? as usize;
+    let section_table = file_header + FILE_HEADER_SIZE + read_u16(image, file_header + 16)? as usize;
+
+    let mut sections = (0..section_count)
+        .map(|index| {
+            let header = section_table + index * SECTION_HEADER_SIZE;
+            Some((
+                read_u32(image, header + 12)? as usize,
+                read_u32(image, header + 8)? as usize,
+                read_u32(image, header + SECTION_CHARACTERISTICS_OFFSET)?,
+            ))
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    sections.sort_by_key(|&(start, _, _)| start);
+    Some(sections)
+}
+
+/// Returns whether the certificate table data directory is present and not empty.
+fn has_certificate_table(image: &[u8], optional_header: usize, optional_header_size: usize) -> bool {
+    if optional_header_size < OPTIONAL_CERTIFICATE_TABLE_SIZE_OFFSET + 4 {
+        return false;
+    }
+
+    let directory_count = read_u32(image, optional_header + OPTIONAL_NUMBER_OF_RVA_AND_SIZES_OFFSET).unwrap_or(0);
+    directory_count > CERTIFICATE_TABLE_INDEX && read_u32(image, optional_header + OPTIONAL_CERTIFICATE_TABLE_SIZE_OFFSET).unwrap_or(0) != 0
+}
+
+/// Converts the null-padded section name into a string.
+fn section_name(raw: &[u8]) -> String {
+    raw.iter().take_while(|&&byte| byte != 0).map(|&byte| byte as char).collect()
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(offset..offset.checked_add(2)?)?.try_into().ok()?))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(offset..offset.checked_add(4)?)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, alloc::vec::Vec};
+
+    const NT_OFFSET: usize = 0x80;
+    const OPTIONAL_HEADER_SIZE: usize = 0xf0;
+    const SECTION_TABLE: usize = NT_OFFSET + 4 + FILE_HEADER_SIZE + OPTIONAL_HEADER_SIZE;
+
+    /// Builds a minimal x86-64 EFI application with a `.text` and a `.data` section.
+    fn build_image() -> Vec<u8> {
+        let mut image = alloc::vec![0u8; 0x600];
+
+        image[0..2].copy_from_slice(&DOS_SIGNATURE.to_le_bytes());
+        image[DOS_LFANEW_OFFSET..DOS_LFANEW_OFFSET + 4].copy_from_slice(&(NT_OFFSET as u32).to_le_bytes());
+        image[NT_OFFSET..NT_OFFSET + 4].copy_from_slice(&NT_SIGNATURE.to_le_bytes());
+
+        let file_header = NT_OFFSET + 4;
+        image[file_header..file_header + 2].copy_from_slice(&MACHINE_AMD64.to_le_bytes());
+        image[file_header + 2..file_header + 4].copy_from_slice(&2u16.to_le_bytes());
+        image[file_header + 16..file_header + 18].copy_from_slice(&(OPTIONAL_HEADER_SIZE as u16).to_le_bytes());
+
+        let optional_header = file_header + FILE_HEADER_SIZE;
+        write_u16(&mut image, optional_header, PE32_PLUS_MAGIC);
+        write_u32(&mut image, optional_header + OPTIONAL_ENTRY_POINT_OFFSET, 0x1000);
+        write_u32(&mut image, optional_header + OPTIONAL_SIZE_OF_IMAGE_OFFSET, 0x3000);
+        write_u32(&mut image, optional_header + OPTIONAL_SIZE_OF_HEADERS_OFFSET, 0x200);
+        write_u16(&mut image, optional_header + OPTIONAL_SUBSYSTEM_OFFSET, SUBSYSTEM_EFI_APPLICATION);
+
+        write_section(&mut image, 0, b".text", 0x1000, 0x200, 0x200);
+        write_section(&mut image, 1, b".data", 0x2000, 0x200, 0x400);
+
+        image
+    }
+
+    fn write_u16(image: &mut [u8], offset: usize, value: u16) {
+        image[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn write_u32(image: &mut [u8], offset: usize, value: u32) {
+        image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn write_section(image: &mut [u8], index: usize, name: &[u8], virtual_address: u32, raw_size: u32, raw_offset: u32) {
+        let header = SECTION_TABLE + index * SECTION_HEADER_SIZE;
+        image[header..header + name.len()].copy_from_slice(name);
+        write_u32(image, header + 8, raw_size);
+        write_u32(image, header + 12, virtual_address);
+        write_u32(image, header + 16, raw_size);
+        write_u32(image, header + 20, raw_offset);
+    }
+
+    #[test]
+    fn accepts_valid_images() {
+        let info = validate(&build_image()).unwrap();
+        assert_eq!(
+            info,
+            PeInfo {
+                size_of_image: 0x3000,
+                entry_point: 0x1000,
+                section_count: 2,
+                subsystem: SUBSYSTEM_EFI_APPLICATION,
+                is_signed: false,
+            }
+        );
+    }
+
+    #[test]
+    fn detects_certificate_tables() {
+        let mut image = build_image();
+        let optional_header = NT_OFFSET + 4 + FILE_HEADER_SIZE;
+        write_u32(&mut image, optional_header + OPTIONAL_CERTIFICATE_TABLE_SIZE_OFFSET, 0x100);
+        assert!(!validate(&image).unwrap().is_signed);
+
+        write_u32(&mut image, optional_header + OPTIONAL_NUMBER_OF_RVA_AND_SIZES_OFFSET, 16);
+        assert!(validate(&image).unwrap().is_signed);
+    }
+
+    #[test]
+    fn finds_writable_section_slack() {
+        // Lay the image out as the firmware maps it, every section at its virtual address.
+        let file = build_image();
+        let mut image = alloc::vec![0u8; 0x3000];
+        image[..0x400].copy_from_slice(&file[..0x400]);
+        assert_eq!(writable_section_slack(&image), Vec::new());
+
+        write_u32(&mut image, SECTION_TABLE + SECTION_HEADER_SIZE + SECTION_CHARACTERISTICS_OFFSET, SECTION_MEM_WRITE);
+        assert_eq!(writable_section_slack(&image), alloc::vec![0x2200..0x3000]);
+
+        assert_eq!(writable_section_slack(&image[..0x40]), Vec::new());
+    }
+
+    #[test]
+    fn finds_sections_by_name() {
+        let mut image = build_image();
+        assert_eq!(find_section(&image, ".data"), Some(0x400..0x600));
+        assert_eq!(find_section(&image, ".ilcfg"), None);
+
+        write_u16(&mut image, NT_OFFSET + 4 + 2, 3);
+        write_section(&mut image, 2, b".ilcfg", 0x2200, 0x200, 0x600);
+        assert_eq!(find_section(&image, ".ilcfg"), None);
+
+        image.resize(0x800, 0);
+        assert_eq!(find_section(&image, ".ilcfg"), Some(0x600..0x800));
+        assert_eq!(find_section(&image[..0x40], ".text"), None);
+    }
+
+    #[test]
+    fn rejects_missing_signatures() {
+        let mut image = build_image();
+        image[0] = b'X';
+        assert_eq!(validate(&image), Err(PeError::MissingDosSignature));
+
+        let mut image = build_image();
+        image[NT_OFFSET] = b'X';
+        assert_eq!(validate(&image), Err(PeError::MissingNtSignature));
+    }
+
+    #[test]
+    fn rejects_truncated_headers() {
+        assert_eq!(validate(&[]), Err(PeError::TruncatedHeader("DOS header")));
+        assert_eq!(validate(&build_image()[..0x3c]), Err(PeError::TruncatedHeader("DOS header")));
+        assert_eq!(validate(&build_image()[..0x40]), Err(PeError::TruncatedHeader("NT headers")));
+        assert_eq!(validate(&build_image()[..NT_OFFSET + 10]), Err(PeError::TruncatedHeader("COFF file header")));
+        assert_eq!(validate(&build_image()[..SECTION_TABLE - 8]), Err(PeError::TruncatedHeader("optional header")));
+        assert_eq!(validate(&build_image()[..SECTION_TABLE + 50]), Err(PeError::TruncatedHeader("section table")));
+    }
+
+    #[test]
+    fn rejects_32bit_images() {
+        let mut image = build_image();
+        write_u16(&mut image, NT_OFFSET + 4, MACHINE_I386);
+        assert_eq!(validate(&image), Err(PeError::Image32Bit));
+        assert_eq!(PeError::Image32Bit.to_string(), "image is 32-bit");
+
+        let mut image = build_image();
+        write_u16(&mut image, NT_OFFSET + 4 + FILE_HEADER_SIZE, PE32_MAGIC);
+        assert_eq!(validate(&image), Err(PeError::Image32Bit));
+    }
+
+    #[test]
+    fn rejects_unsupported_machines() {
+        let mut image = build_image();
+        write_u16(&mut image, NT_OFFSET + 4, 0xaa64);
+        assert_eq!(validate(&image), Err(PeError::UnsupportedMachine(0xaa64)));
+    }
+
+    #[test]
+    fn accepts_only_efi_subsystems() {
+        let mut image = build_image();
+        write_u16(&mut image, NT_OFFSET + 4 + FILE_HEADER_SIZE + OPTIONAL_SUBSYSTEM_OFFSET, 3);
+        assert_eq!(validate(&image), Err(PeError::NotEfiApplication(3)));
+
+        write_u16(&mut image, NT_OFFSET + 4 + FILE_HEADER_SIZE + OPTIONAL_SUBSYSTEM_OFFSET, SUBSYSTEM_EFI_RUNTIME_DRIVER);
+        assert_eq!(validate(&image).unwrap().subsystem, SUBSYSTEM_EFI_RUNTIME_DRIVER);
+    }
+
+    #[test]
+    fn rejects_truncated_sections() {
+        let image = build_image();
+        let error = validate(&image[..0x500]).unwrap_err();
+        assert_eq!(error, PeError::TruncatedSection(String::from(".data")));
+        assert_eq!(error.to_string(), "file truncated at section .data");
+    }
+
+    #[test]
+    fn rejects_inconsistent_sizes() {
+        let mut image = build_image();
+        write_u32(&mut image, NT_OFFSET + 4 + FILE_HEADER_SIZE + OPTIONAL_SIZE_OF_IMAGE_OFFSET, 0x2100);
+        assert_eq!(validate(&image), Err(PeError::SectionOutsideImage(String::from(".data"))));
+
+        let mut image = build_image();
+        write_u32(&mut image, NT_OFFSET + 4 + FILE_HEADER_SIZE + OPTIONAL_SIZE_OF_HEADERS_OFFSET, 0x4000);
+        assert!(matches!(validate(&image), Err(PeError::HeadersExceedImage { .. })));
+    }
+}
@@ -0,0 +1,146 @@
+//! Decisions of the boot flow that only depend on what the stages found out.
+//!
+//! The stages in `main.rs` gather their inputs from the firmware and act on the outcome. Everything in
+//! between is kept here, free of UEFI calls, so that it is tested on the host.
+
+/// Why the hypervisor is skipped for this boot.
+///
+/// # Arguments
+///
+/// * `load_options` - `--no-hypervisor` was passed in the load options.
+/// * `key` - The skip key was pressed early.
+/// * `variable` - The `IllusionSkipOnce` variable was set.
+/// * `safe_mode` - Safe mode is engaged after repeated unfinished boots.
+///
+/// # Returns
+///
+/// The first reason that applies, in the order of the arguments, or `None` if the hypervisor is started.
+pub(crate) fn skip_reason(load_options: bool, key: bool, variable: bool, safe_mode: bool) -> Option<&'static str> {
+    match (load_options, key, variable, safe_mode) {
+        (true, _, _, _) => Some("load options"),
+        (_, true, _, _) => Some("key press"),
+        (_, _, true, _) => Some("IllusionSkipOnce variable"),
+        (_, _, _, true) => Some("safe mode"),
+        _ => None,
+    }
+}
+
+/// Returns whether safe mode is engaged, i.e. `attempts` unfinished boots reached a `limit` other than `0`.
+pub(crate) fn is_safe_mode(limit: u32, attempts: u32) -> bool {
+    limit != 0 && attempts >= limit
+}
+
+/// Returns how many leading candidates share the chainload path of the first one.
+///
+/// Candidates are listed in the order of the chainload paths, and only those of the first path that was
+/// found are considered for the default selection. The others are only booted when picked explicitly.
+pub(crate) fn primary_count<T: PartialEq>(paths: &[T]) -> usize {
+    paths.iter().take_while(|path| Some(*path) == paths.first()).count()
+}
+
+/// Picks the candidate closest to the loader.
+///
+/// # Arguments
+///
+/// * `prefixes` - For every candidate the number of hardware device path nodes it shares with the loader.
+///
+/// # Returns
+///
+/// The index of the candidate sharing the most nodes, the first one of those on ties, or `None` if no
+/// candidate shares any.
+pub(crate) fn closest_candidate(prefixes: &[usize]) -> Option<usize> {
+    prefixes
+        .iter()
+        .enumerate()
+        .filter(|(_, prefix)| **prefix > 0)
+        .max_by_key(|(index, prefix)| (**prefix, core::cmp::Reverse(*index)))
+        .map(|(index, _)| index)
+}
+
+/// Why a candidate is selected by default.
+#[derive(Debug, PartialEq)]
+pub(crate) enum DefaultChoice {
+    /// It is the configured `default_candidate`.
+    Configured,
+
+    /// The configured `default_candidate` with the given number doesn't exist, so the first one is used.
+    ConfiguredMissing(usize),
+
+    /// It is on the volume selected in the previous boot.
+    Remembered,
+
+    /// It is on the same disk as the loader.
+    SameDisk,
+
+    /// Nothing else applied.
+    First,
+}
+
+/// Selects the candidate the menu defaults to.
+///
+/// # Arguments
+///
+/// * `configured` - The 1-based `default_candidate` setting.
+/// * `count` - The number of candidates.
+/// * `remembered` - The candidate on the previously selected volume.
+/// * `same_disk` - The candidate on the same disk as the loader.
+///
+/// # Returns
+///
+/// The index of the default candidate and why it was chosen.
+pub(crate) fn default_selection(
+    configured: Option<usize>,
+    count: usize,
+    remembered: Option<usize>,
+    same_disk: Option<usize>,
+) -> (usize, DefaultChoice) {
+    match (configured, remembered, same_disk) {
+        (Some(candidate), _, _) if candidate != 0 && candidate <= count => (candidate - 1, DefaultChoice::Configured),
+        (Some(candidate), _, _) => (0, DefaultChoice::ConfiguredMissing(candidate)),
+        (None, Some(index), _) => (index, DefaultChoice::Remembered),
+        (None, None, Some(index)) => (index, DefaultChoice::SameDisk),
+        (None, None, None) => (0, DefaultChoice::First),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_skip_reason_wins() {
+        assert_eq!(skip_reason(false, false, false, false), None);
+        assert_eq!(skip_reason(true, true, true, true), Some("load options"));
+        assert_eq!(skip_reason(false, true, true, false), Some("key press"));
+        assert_eq!(skip_reason(false, false, false, true), Some("safe mode"));
+    }
+
+    #[test]
+    fn safe_mode_needs_a_limit() {
+        assert!(is_safe_mode(3, 3));
+        assert!(!is_safe_mode(3, 2));
+        assert!(!is_safe_mode(0, 10));
+    }
+
+    #[test]
+    fn primary_candidates_share_the_first_path() {
+        assert_eq!(primary_count(&["bootmgfw", "bootmgfw", "bootx64", "bootmgfw"]), 2);
+        assert_eq!(primary_count::<&str>(&[]), 0);
+    }
+
+    #[test]
+    fn closest_candidate_prefers_longer_prefixes() {
+        assert_eq!(closest_candidate(&[0, 2, 3, 3]), Some(2));
+        assert_eq!(closest_candidate(&[0, 0]), None);
+    }
+
+    #[test]
+    fn default_selection_order() {
+        assert_eq!(default_selection(Some(2), 3, Some(0), Some(1)), (1, DefaultChoice::Configured));
+        assert_eq!(default_selection(Some(4), 3, Some(2), None), (0, DefaultChoice::ConfiguredMissing(4)));
+        assert_eq!(default_selection(Some(0), 3, None, None), (0, DefaultChoice::ConfiguredMissing(0)));
+        assert_eq!(default_selection(None, 3, Some(2), Some(1)), (2, DefaultChoice::Remembered));
+        assert_eq!(default_selection(None, 3, None, Some(1)), (1, DefaultChoice::SameDisk));
+        assert_eq!(default_selection(None, 3, None, None), (0, DefaultChoice::First));
+    }
+}
@@ -0,0 +1,193 @@
+//! Pre-flight check of the processor's virtualization support.
+//!
+//! Runs before the hypervisor image is loaded, so that a platform without usable VT-x or AMD-V is reported
+//! as such instead of failing later inside `vmxon` or `vmrun`.
+
+extern crate alloc;
+
+use {
+    alloc::{string::String, vec::Vec},
+    core::fmt::{self, Display},
+    x86::{
+        cpuid::CpuId,
+        msr::{rdmsr, IA32_FEATURE_CONTROL, IA32_VMX_PROCBASED_CTLS, IA32_VMX_PROCBASED_CTLS2},
+    },
+};
+
+/// IA32_FEATURE_CONTROL lock bit. Once set, the MSR can't be modified until the next reset.
+const FEATURE_CONTROL_LOCK: u64 = 1 << 0;
+
+/// IA32_FEATURE_CONTROL bit enabling VMXON outside of SMX operation.
+const FEATURE_CONTROL_VMX_OUTSIDE_SMX: u64 = 1 << 2;
+
+/// Primary processor-based control "activate secondary controls".
+const PROCBASED_ACTIVATE_SECONDARY_CONTROLS: u64 = 1 << 31;
+
+/// Secondary processor-based control "enable EPT".
+const PROCBASED2_ENABLE_EPT: u64 = 1 << 1;
+
+/// The AMD `VM_CR` MSR.
+const MSR_VM_CR: u32 = 0xc001_0114;
+
+/// `VM_CR` bit set when SVM has been disabled by firmware.
+const VM_CR_SVMDIS: u64 = 1 << 4;
+
+/// Processor vendors the loader distinguishes between.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum CpuVendor {
+    /// `GenuineIntel`, virtualized with VT-x.
+    Intel,
+
+    /// `AuthenticAMD`, virtualized with AMD-V (SVM).
+    Amd,
+
+    /// Any other vendor.
+    Other,
+}
+
+impl CpuVendor {
+    /// Reads the vendor of the current processor from CPUID leaf 0.
+    pub(crate) fn detect() -> Self {
+        match vendor_string().as_str() {
+            "GenuineIntel" => CpuVendor::Intel,
+            "AuthenticAMD" => CpuVendor::Amd,
+            _ => CpuVendor::Other,
+        }
+    }
+
+    /// Returns the vendor name used in log messages.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            CpuVendor::Intel => "Intel",
+            CpuVendor::Amd => "AMD",
+            CpuVendor::Other => "unknown",
+        }
+    }
+}
+
+/// Returns the raw vendor identification string of the current processor.
+fn vendor_string() -> String {
+    CpuId::new().get_vendor_info().map(|info| String::from(info.as_str())).unwrap_or_default()
+}
+
+/// A requirement of the hypervisor that the platform does not meet.
+pub(crate) enum MissingRequirement {
+    /// The processor is neither an Intel nor an AMD processor.
+    SupportedVendor(String),
+
+    /// CPUID.1:ECX.VMX is clear.
+    VmxSupport,
+
+    /// IA32_FEATURE_CONTROL is locked with VMX outside SMX disabled.
+    VmxEnabledInFirmware,
+
+    /// The secondary processor-based controls are not available.
+    SecondaryControls,
+
+    /// Extended page tables are not available.
+    Ept,
+
+    /// CPUID.80000001h:ECX.SVM is clear.
+    SvmSupport,
+
+    /// `VM_CR.SVMDIS` is set.
+    SvmEnabledInFirmware,
+
+    /// Nested page tables are not available.
+    Npt,
+}
+
+impl Display for MissingRequirement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MissingRequirement::SupportedVendor(vendor) => write!(f, "processor vendor is {}, an Intel or AMD processor is required", vendor),
+            MissingRequirement::VmxSupport => write!(f, "processor does not support VMX (CPUID.1:ECX.VMX is clear)"),
+            MissingRequirement::VmxEnabledInFirmware => {
+                write!(f, "VMX is disabled in firmware (IA32_FEATURE_CONTROL is locked without VMX outside SMX)")
+            }
+            MissingRequirement::SecondaryControls => write!(f, "secondary processor-based VM-execution controls are not supported"),
+            MissingRequirement::Ept => write!(f, "extended page tables (EPT) are not supported"),
+            MissingRequirement::SvmSupport => write!(f, "processor does not support SVM (CPUID.80000001h:ECX.SVM is clear)"),
+            MissingRequirement::SvmEnabledInFirmware => write!(f, "SVM is disabled in firmware (VM_CR.SVMDIS is set)"),
+            MissingRequirement::Npt => write!(f, "nested page tables (NPT) are not supported"),
+        }
+    }
+}
+
+/// Checks that the current processor can run the hypervisor built for its vendor.
+///
+/// # Arguments
+///
+/// * `vendor` - The vendor of the current processor.
+///
+/// # Returns
+///
+/// `Ok(())` if all requirements are met, otherwise every requirement that is missing.
+pub(crate) fn check_virtualization_support(vendor: CpuVendor) -> Result<(), Vec<MissingRequirement>> {
+    match vendor {
+        CpuVendor::Intel => check_vmx_support(),
+        CpuVendor::Amd => check_svm_support(),
+        CpuVendor::Other => Err(alloc::vec![MissingRequirement::SupportedVendor(vendor_string())]),
+    }
+}
+
+/// Checks the VT-x requirements. Checks that depend on an earlier failed one are skipped, as reading VMX
+/// capability MSRs faults without VMX support.
+fn check_vmx_support() -> Result<(), Vec<MissingRequirement>> {
+    let cpuid = CpuId::new();
+
+    if !cpuid.get_feature_info().is_some_and(|info| info.has_vmx()) {
+        return Err(alloc::vec![MissingRequirement::VmxSupport]);
+    }
+
+    let mut missing = Vec::new();
+
+    // An unlocked IA32_FEATURE_CONTROL is fine, the hypervisor enables VMX and locks it itself.
+    let feature_control = unsafe { rdmsr(IA32_FEATURE_CONTROL) };
+    log::debug!("IA32_FEATURE_CONTROL = {:#x}", feature_control);
+    if feature_control & FEATURE_CONTROL_LOCK != 0 && feature_control & FEATURE_CONTROL_VMX_OUTSIDE_SMX == 0 {
+        missing.push(MissingRequirement::VmxEnabledInFirmware);
+    }
+
+    // The upper 32 bits of the capability MSRs report which controls may be set to 1.
+    let procbased = unsafe { rdmsr(IA32_VMX_PROCBASED_CTLS) } >> 32;
+    if procbased & PROCBASED_ACTIVATE_SECONDARY_CONTROLS == 0 {
+        missing.push(MissingRequirement::SecondaryControls);
+    } else {
+        let procbased2 = unsafe { rdmsr(IA32_VMX_PROCBASED_CTLS2) } >> 32;
+        if procbased2 & PROCBASED2_ENABLE_EPT == 0 {
+            missing.push(MissingRequirement::Ept);
+        }
+    }
+
+    match missing.is_empty() {
+        true => Ok(()),
+        false => Err(missing),
+    }
+}
+
+/// Checks the AMD-V requirements.
+fn check_svm_support() -> Result<(), Vec<MissingRequirement>> {
+    let cpuid = CpuId::new();
+
+    if !cpuid.get_extended_processor_and_feature_identifiers().is_some_and(|info| info.has_svm()) {
+        return Err(alloc::vec![MissingRequirement::SvmSupport]);
+    }
+
+    let mut missing = Vec::new();
+
+    let vm_cr = unsafe { rdmsr(MSR_VM_CR) };
+    log::debug!("VM_CR = {:#x}", vm_cr);
+    if vm_cr & VM_CR_SVMDIS != 0 {
+        missing.push(MissingRequirement::SvmEnabledInFirmware);
+    }
+
+    if !cpuid.get_svm_info().is_some_and(|info| info.has_nested_paging()) {
+        missing.push(MissingRequirement::Npt);
+    }
+
+    match missing.is_empty() {
+        true => Ok(()),
+        false => Err(missing),
+    }
+}
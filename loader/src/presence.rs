@@ -0,0 +1,116 @@
+//! Detection of an already running Illusion hypervisor.
+//!
+//! The loader may be re-entered, for example when the firmware boot menu returns to it or when it is
+//! chainloaded twice. Starting the hypervisor again would virtualize the processors a second time.
+//!
+//! After starting the hypervisor, the same probe runs on every processor, since the hypervisor may fail to
+//! virtualize some of them while the others carry on.
+
+use {
+    crate::mp::{self, CpuOutcome, CpuResult},
+    core::arch::x86_64::__cpuid_count,
+    shared::{PASSWORD, PRESENCE_LEAF, PRESENCE_SIGNATURE},
+    uefi::prelude::*,
+};
+
+/// Checks whether Illusion is already running on the current processor.
+///
+/// # Returns
+///
+/// `true` if the presence leaf returned the Illusion signature.
+pub(crate) fn is_illusion_running() -> bool {
+    // Without a hypervisor, the leaf is out of range and the processor returns unrelated data,
+    // which can't match the signature.
+    let result = unsafe { __cpuid_count(PRESENCE_LEAF, PASSWORD as u32) };
+
+    [result.ebx, result.ecx, result.edx] == PRESENCE_SIGNATURE
+}
+
+/// How many processors answered the presence probe.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct PresenceSummary {
+    /// Processors running the hypervisor.
+    pub virtualized: usize,
+
+    /// Enabled processors running without the hypervisor.
+    pub bare_metal: usize,
+
+    /// Processors the probe could not run on, which are neither counted as running it nor as missing it.
+    pub unverified: usize,
+
+    /// Processors disabled by the firmware, which the hypervisor doesn't start on either.
+    pub disabled: usize,
+}
+
+/// Runs the presence probe on every processor and logs one line per processor.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+pub(crate) fn check_all_processors(boot_services: &BootServices) -> PresenceSummary {
+    let results = mp::run_on_each_cpu(boot_services, is_illusion_running);
+
+    for result in &results {
+        let role = if result.is_bsp { ", BSP" } else { "" };
+        match result.outcome {
+            CpuOutcome::Ran(true) => log::info!("[5/8] CPU {:>3} (APIC ID {:#x}{}): virtualized", result.number, result.apic_id, role),
+            CpuOutcome::Ran(false) => log::error!("[5/8] CPU {:>3} (APIC ID {:#x}{}): NOT virtualized", result.number, result.apic_id, role),
+            CpuOutcome::Disabled => log::info!("[5/8] CPU {:>3} (APIC ID {:#x}{}): disabled", result.number, result.apic_id, role),
+            CpuOutcome::Failed(status) => {
+                log::warn!("[5/8] CPU {:>3} (APIC ID {:#x}{}): not probed ({:?})", result.number, result.apic_id, role, status)
+            }
+        }
+    }
+
+    summarize(&results)
+}
+
+/// Counts the processors by the outcome of the presence probe.
+fn summarize(results: &[CpuResult<bool>]) -> PresenceSummary {
+    results.iter().fold(PresenceSummary::default(), |mut summary, result| {
+        match result.outcome {
+            CpuOutcome::Ran(true) => summary.virtualized += 1,
+            CpuOutcome::Ran(false) => summary.bare_metal += 1,
+            CpuOutcome::Disabled => summary.disabled += 1,
+            CpuOutcome::Failed(_) => summary.unverified += 1,
+        }
+        summary
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cpu(number: usize, outcome: CpuOutcome<bool>) -> CpuResult<bool> {
+        CpuResult {
+            number,
+            apic_id: number as u64 * 2,
+            is_bsp: number == 0,
+            outcome,
+        }
+    }
+
+    #[test]
+    fn processors_are_counted_by_outcome() {
+        let results = [
+            cpu(0, CpuOutcome::Ran(true)),
+            cpu(1, CpuOutcome::Ran(true)),
+            cpu(2, CpuOutcome::Ran(false)),
+            cpu(3, CpuOutcome::Disabled),
+            cpu(4, CpuOutcome::Failed(Status::TIMEOUT)),
+        ];
+
+        let summary = summarize(&results);
+        assert_eq!(
+            summary,
+            PresenceSummary {
+                virtualized: 2,
+                bare_metal: 1,
+                unverified: 1,
+                disabled: 1,
+            }
+        );
+        assert_eq!(summarize(&results[..2]).bare_metal, 0);
+    }
+}
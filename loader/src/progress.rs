@@ -0,0 +1,154 @@
+//! Breadcrumbs of the boot flow in the `IllusionProgress` variable.
+//!
+//! Every stage transition writes a small record with the stage reached, the last status and the number of
+//! boot manager candidates. The next loader start logs the record left behind before overwriting it, which
+//! tells how far a boot without display or serial port got. The variable is volatile and only lives until
+//! the next reset, so it covers a loader started again without a reset, e.g. from the firmware boot menu,
+//! but not a crash that reboots the machine. Failing to read or write it never affects the boot.
+
+use {
+    crate::last_boot::ILLUSION_VENDOR,
+    uefi::{prelude::*, table::runtime::VariableAttributes, CStr16},
+};
+
+/// Name of the variable holding the progress record.
+const PROGRESS_VARIABLE: &CStr16 = cstr16!("IllusionProgress");
+
+/// Upper bound for records written by later versions with more fields.
+const MAX_RECORD_SIZE: usize = 64;
+
+/// The progress of one loader start as stored in the variable.
+///
+/// Later versions only append fields, so older loaders can still read the fields they know.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct ProgressRecord {
+    /// The format version, `RECORD_VERSION` for this layout.
+    pub version: u8,
+
+    /// The `[n/8]` stage reached.
+    pub stage: u8,
+
+    /// The number of boot manager candidates found, `0` before the search.
+    pub candidates: u16,
+
+    /// The last status, `SUCCESS` unless the stage failed.
+    pub status: u64,
+}
+
+/// Version of the record layout written by this loader.
+pub(crate) const RECORD_VERSION: u8 = 1;
+
+/// Size of the record layout written by this loader.
+pub(crate) const RECORD_SIZE: usize = core::mem::size_of::<ProgressRecord>();
+
+impl ProgressRecord {
+    /// Creates a record of the current layout.
+    pub(crate) fn new(stage: u8, status: Status, candidates: usize) -> Self {
+        Self {
+            version: RECORD_VERSION,
+            stage,
+            candidates: u16::try_from(candidates).unwrap_or(u16::MAX),
+            status: status.0 as u64,
+        }
+    }
+
+    /// Encodes the record in little endian byte order.
+    pub(crate) fn to_bytes(self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0u8; RECORD_SIZE];
+        bytes[0] = self.version;
+        bytes[1] = self.stage;
+        bytes[2..4].copy_from_slice(&{ self.candidates }.to_le_bytes());
+        bytes[4..12].copy_from_slice(&{ self.status }.to_le_bytes());
+        bytes
+    }
+
+    /// Decodes a record, ignoring fields appended by later versions.
+    ///
+    /// # Returns
+    ///
+    /// The record, or `None` if the data is too short or carries no valid version.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < RECORD_SIZE || bytes[0] == 0 {
+            return None;
+        }
+
+        Some(Self {
+            version: bytes[0],
+            stage: bytes[1],
+            candidates: u16::from_le_bytes(bytes[2..4].try_into().ok()?),
+            status: u64::from_le_bytes(bytes[4..12].try_into().ok()?),
+        })
+    }
+}
+
+/// Logs the record left behind by the previous loader start, if there is one.
+///
+/// # Arguments
+///
+/// * `runtime_services` - A reference to the UEFI runtime services.
+pub(crate) fn log_previous(runtime_services: &RuntimeServices) {
+    let mut buffer = [0u8; MAX_RECORD_SIZE];
+
+    match runtime_services.get_variable(PROGRESS_VARIABLE, &ILLUSION_VENDOR, &mut buffer) {
+        Ok((data, _)) => match ProgressRecord::from_bytes(data) {
+            Some(record) => log::info!(
+                "Previous loader start reached stage {}/8 with status {:?} and {} candidate(s)",
+                record.stage,
+                Status(record.status as usize),
+                { record.candidates }
+            ),
+            None => log::warn!("Ignoring malformed {} of {} bytes", PROGRESS_VARIABLE, data.len()),
+        },
+        Err(error) if error.status() == Status::NOT_FOUND => log::debug!("No progress recorded by a previous loader start"),
+        Err(error) => log::debug!("Failed to read {} ({:?})", PROGRESS_VARIABLE, error.status()),
+    }
+}
+
+/// Records the stage reached.
+///
+/// # Arguments
+///
+/// * `runtime_services` - A reference to the UEFI runtime services.
+/// * `stage` - The `[n/8]` stage reached.
+/// * `status` - The last status, `SUCCESS` unless the stage failed.
+/// * `candidates` - The number of boot manager candidates found so far.
+pub(crate) fn record(runtime_services: &RuntimeServices, stage: u8, status: Status, candidates: usize) {
+    let attributes = VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS;
+    let record = ProgressRecord::new(stage, status, candidates);
+
+    if let Err(error) = runtime_services.set_variable(PROGRESS_VARIABLE, &ILLUSION_VENDOR, attributes, &record.to_bytes()) {
+        log::debug!("Failed to record progress in {} ({:?})", PROGRESS_VARIABLE, error.status());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_round_trip() {
+        let record = ProgressRecord::new(6, Status::NOT_FOUND, 2);
+        let bytes = record.to_bytes();
+        assert_eq!(RECORD_SIZE, 12);
+        assert_eq!(&bytes[..4], &[RECORD_VERSION, 6, 2, 0]);
+        assert_eq!(ProgressRecord::from_bytes(&bytes), Some(record));
+    }
+
+    #[test]
+    fn later_versions_are_read() {
+        let mut bytes = [0u8; 16];
+        bytes[..RECORD_SIZE].copy_from_slice(&ProgressRecord::new(8, Status::SUCCESS, 1).to_bytes());
+        bytes[0] = 2;
+
+        let record = ProgressRecord::from_bytes(&bytes).unwrap();
+        assert_eq!((record.version, record.stage, { record.candidates }), (2, 8, 1));
+    }
+
+    #[test]
+    fn malformed_records_are_rejected() {
+        assert_eq!(ProgressRecord::from_bytes(&[1, 2, 3]), None);
+        assert_eq!(ProgressRecord::from_bytes(&[0u8; RECORD_SIZE]), None);
+        assert_eq!({ ProgressRecord::new(1, Status::SUCCESS, 100_000).candidates }, u16::MAX);
+    }
+}
@@ -0,0 +1,209 @@
+//! Retrying of firmware calls that fail transiently, e.g. while an external disk spins up.
+//!
+//! Only statuses that say the device is not ready yet are retried. Anything that a second attempt can't
+//! change, like a missing file or a Secure Boot rejection, fails immediately. Searches that find nothing
+//! can be repeated as well, for controllers that only expose their filesystems a while after boot.
+
+use {crate::images, uefi::prelude::*};
+
+/// Attempts made by default, including the first one.
+pub(crate) const DEFAULT_ATTEMPTS: u32 = 3;
+
+/// Time to wait between two attempts.
+const RETRY_STALL_US: usize = 500_000;
+
+/// Searches made by default, including the first one, so a missing file fails right away.
+pub(crate) const DEFAULT_RESCAN_ATTEMPTS: u32 = 1;
+
+/// Default time to wait before searching again.
+pub(crate) const DEFAULT_RESCAN_INTERVAL_MS: u64 = 1_000;
+
+/// A failure after all attempts were made.
+#[derive(Debug, PartialEq)]
+pub(crate) struct RetryError {
+    /// The status of the last attempt.
+    pub status: Status,
+
+    /// The number of attempts made, including the first one.
+    pub attempts: u32,
+}
+
+/// Returns whether a failure may go away by itself.
+pub(crate) fn is_transient(status: Status) -> bool {
+    matches!(status, Status::DEVICE_ERROR | Status::NOT_READY | Status::TIMEOUT)
+}
+
+/// Runs `operation` until it succeeds, fails permanently or `attempts` attempts were made.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services, used to wait between attempts.
+/// * `attempts` - The maximum number of attempts, `0` is treated as `1`.
+/// * `what` - Describes the operation in the retry messages, e.g. `load boot manager`.
+/// * `operation` - The firmware call to make.
+///
+/// # Returns
+///
+/// The result of the first successful attempt, or the status of the last attempt and the attempt count.
+pub(crate) fn retry<T>(boot_services: &BootServices, attempts: u32, what: &str, operation: impl FnMut() -> uefi::Result<T>) -> Result<T, RetryError> {
+    retry_with(attempts, what, || boot_services.stall(RETRY_STALL_US), operation)
+}
+
+/// Implements `retry` with an arbitrary wait between the attempts.
+fn retry_with<T>(attempts: u32, what: &str, mut wait: impl FnMut(), mut operation: impl FnMut() -> uefi::Result<T>) -> Result<T, RetryError> {
+    let attempts = attempts.max(1);
+    let mut attempt = 1;
+
+    loop {
+        match operation() {
+            Ok(value) => return Ok(value),
+            Err(error) if attempt < attempts && is_transient(error.status()) => {
+                log::warn!("Attempt {}/{} to {} failed ({:?}), retrying in {} ms", attempt, attempts, what, error.status(), RETRY_STALL_US / 1000);
+                wait();
+                attempt += 1;
+            }
+            Err(error) => {
+                return Err(RetryError {
+                    status: error.status(),
+                    attempts: attempt,
+                })
+            }
+        }
+    }
+}
+
+/// Runs `search` until `missing` no longer applies to its result or `attempts` searches were made.
+///
+/// Before every further search the loader waits and connects all controllers again, so that drivers bind
+/// to devices that appeared in the meantime.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+/// * `attempts` - The maximum number of searches, `0` is treated as `1`.
+/// * `interval_ms` - Time to wait before searching again.
+/// * `what` - Describes what is searched in the rescan messages, e.g. `hypervisor`.
+/// * `search` - The search to make.
+/// * `missing` - Tells whether a result means nothing was found.
+///
+/// # Returns
+///
+/// The result of the first search that found something, or of the last one.
+pub(crate) fn rescan<T>(
+    boot_services: &BootServices,
+    attempts: u32,
+    interval_ms: u64,
+    what: &str,
+    search: impl FnMut() -> T,
+    missing: impl Fn(&T) -> bool,
+) -> T {
+    let wait = || {
+        boot_services.stall((interval_ms as usize).saturating_mul(1000));
+        images::connect_controllers(boot_services);
+    };
+
+    rescan_with(attempts, interval_ms, what, wait, search, missing)
+}
+
+/// Implements `rescan` with an arbitrary wait between the searches.
+fn rescan_with<T>(
+    attempts: u32,
+    interval_ms: u64,
+    what: &str,
+    mut wait: impl FnMut(),
+    mut search: impl FnMut() -> T,
+    missing: impl Fn(&T) -> bool,
+) -> T {
+    let attempts = attempts.max(1);
+    let mut attempt = 1;
+
+    loop {
+        let result = search();
+        if attempt >= attempts || !missing(&result) {
+            return result;
+        }
+
+        log::warn!("Search {}/{} for the {} found nothing, rescanning in {} ms", attempt, attempts, what, interval_ms);
+        wait();
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, uefi::Error};
+
+    /// Runs `retry_with` on the given sequence of results, returning its result and the number of waits.
+    fn run(attempts: u32, results: &[Status]) -> (Result<u32, RetryError>, u32) {
+        let mut calls = 0;
+        let mut waits = 0;
+        let result = retry_with(
+            attempts,
+            "test",
+            || waits += 1,
+            || {
+                let status = results[calls];
+                calls += 1;
+                match status {
+                    Status::SUCCESS => Ok(calls as u32),
+                    status => Err(Error::from(status)),
+                }
+            },
+        );
+
+        (result, waits)
+    }
+
+    #[test]
+    fn transient_failures_are_retried() {
+        assert_eq!(run(3, &[Status::DEVICE_ERROR, Status::NOT_READY, Status::SUCCESS]), (Ok(3), 2));
+        assert_eq!(run(3, &[Status::SUCCESS]), (Ok(1), 0));
+    }
+
+    #[test]
+    fn permanent_failures_are_not_retried() {
+        for status in [Status::NOT_FOUND, Status::SECURITY_VIOLATION] {
+            assert_eq!(run(3, &[status]), (Err(RetryError { status, attempts: 1 }), 0));
+        }
+    }
+
+    #[test]
+    fn rescans_stop_once_found() {
+        let mut waits = 0;
+        let mut searches = 0;
+        let found = rescan_with(
+            5,
+            0,
+            "test",
+            || waits += 1,
+            || {
+                searches += 1;
+                searches
+            },
+            |&result| result < 3,
+        );
+        assert_eq!((found, waits), (3, 2));
+
+        let mut waits = 0;
+        assert_eq!(rescan_with(2, 0, "test", || waits += 1, || None::<u32>, Option::is_none), None);
+        assert_eq!(waits, 1);
+
+        assert_eq!(rescan_with(0, 0, "test", || panic!("waited"), || None::<u32>, Option::is_none), None);
+    }
+
+    #[test]
+    fn last_failure_reports_attempts() {
+        let result = run(2, &[Status::TIMEOUT, Status::DEVICE_ERROR]);
+        assert_eq!(
+            result,
+            (
+                Err(RetryError {
+                    status: Status::DEVICE_ERROR,
+                    attempts: 2
+                }),
+                1
+            )
+        );
+        assert_eq!(run(0, &[Status::TIMEOUT]).0.unwrap_err().attempts, 1);
+    }
+}
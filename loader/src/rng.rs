@@ -0,0 +1,160 @@
+//! Random numbers for secrets the loader generates each boot.
+//!
+//! The EFI RNG protocol is the preferred source. Firmware without it falls back to RDRAND, and processors
+//! without RDRAND to the time stamp counter sampled around short stalls. The fallback samples are folded
+//! together with `mix`, so that every bit of the result depends on every sample.
+
+use {
+    core::{
+        arch::x86_64::{__cpuid, _rdrand64_step},
+        fmt,
+    },
+    uefi::{prelude::*, proto::rng::Rng},
+    x86::time::rdtsc,
+};
+
+/// Number of samples the fallback folds into one value.
+const FALLBACK_SAMPLES: usize = 16;
+
+/// Number of times RDRAND is retried before giving up on it, as recommended by Intel.
+const RDRAND_RETRIES: usize = 10;
+
+/// Initial state of the fallback, the fractional part of the golden ratio.
+const MIX_SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// Where a random value comes from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum RandomSource {
+    /// The EFI RNG protocol of the firmware.
+    Firmware,
+
+    /// The RDRAND instruction.
+    Rdrand,
+
+    /// The time stamp counter, the weakest source.
+    TimeStampCounter,
+}
+
+impl fmt::Display for RandomSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RandomSource::Firmware => write!(f, "the firmware RNG"),
+            RandomSource::Rdrand => write!(f, "RDRAND"),
+            RandomSource::TimeStampCounter => write!(f, "the time stamp counter"),
+        }
+    }
+}
+
+/// Returns a random 64-bit value from the best available source.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+///
+/// # Returns
+///
+/// The value and where it comes from.
+pub(crate) fn random_u64(boot_services: &BootServices) -> (u64, RandomSource) {
+    if let Some(value) = firmware_u64(boot_services) {
+        return (value, RandomSource::Firmware);
+    }
+
+    if let Some(value) = rdrand_u64() {
+        return (value, RandomSource::Rdrand);
+    }
+
+    // The stall makes the number of cycles between samples depend on the timer and the memory system.
+    let samples = (0..FALLBACK_SAMPLES).map(|_| {
+        boot_services.stall(1);
+        unsafe { rdtsc() }
+    });
+    (fold(samples), RandomSource::TimeStampCounter)
+}
+
+/// Mixes a sample into the state.
+///
+/// The finalizer of SplitMix64, applied to the rotated state combined with the sample. A flipped bit in
+/// either input flips about half of the output bits, and the order of the samples matters.
+pub(crate) fn mix(state: u64, sample: u64) -> u64 {
+    let mut z = (state.rotate_left(23) ^ sample).wrapping_add(MIX_SEED);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Folds samples into one value with `mix`.
+pub(crate) fn fold(samples: impl IntoIterator<Item = u64>) -> u64 {
+    samples.into_iter().fold(MIX_SEED, mix)
+}
+
+/// Reads a value from the EFI RNG protocol.
+fn firmware_u64(boot_services: &BootServices) -> Option<u64> {
+    let handle = boot_services.get_handle_for_protocol::<Rng>().ok()?;
+    let mut rng = boot_services.open_protocol_exclusive::<Rng>(handle).ok()?;
+
+    let mut bytes = [0u8; 8];
+    rng.get_rng(None, &mut bytes).ok()?;
+    Some(u64::from_le_bytes(bytes))
+}
+
+/// Folds RDRAND samples into one value, `None` if the processor doesn't support it or it keeps failing.
+fn rdrand_u64() -> Option<u64> {
+    // CPUID.01H:ECX.RDRAND[bit 30]
+    if unsafe { __cpuid(1) }.ecx & (1 << 30) == 0 {
+        return None;
+    }
+
+    let mut samples = [0u64; FALLBACK_SAMPLES];
+    for sample in samples.iter_mut() {
+        *sample = (0..RDRAND_RETRIES).find_map(|_| unsafe { rdrand_step() })?;
+    }
+    Some(fold(samples))
+}
+
+/// Executes RDRAND once, `None` if it didn't return a value.
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand_step() -> Option<u64> {
+    let mut value = 0;
+    (_rdrand64_step(&mut value) == 1).then_some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mix_is_deterministic_and_order_sensitive() {
+        assert_eq!(mix(1, 2), mix(1, 2));
+        assert_ne!(mix(1, 2), mix(2, 1));
+        assert_ne!(fold([1, 2, 3]), fold([3, 2, 1]));
+        assert_ne!(fold([]), fold([0]));
+        assert_eq!(fold([]), MIX_SEED);
+    }
+
+    #[test]
+    fn single_bit_flips_avalanche() {
+        for sample in [0u64, 1, 0x1234_5678, u64::MAX] {
+            let mixed = mix(MIX_SEED, sample);
+            for bit in 0..64 {
+                let flipped = (mix(MIX_SEED, sample ^ (1 << bit)) ^ mixed).count_ones();
+                assert!((12..=52).contains(&flipped), "bit {} of {:#x} flips {} output bits", bit, sample, flipped);
+
+                let flipped = (mix(MIX_SEED ^ (1 << bit), sample) ^ mixed).count_ones();
+                assert!((12..=52).contains(&flipped), "bit {} of the state flips {} output bits", bit, flipped);
+            }
+        }
+    }
+
+    #[test]
+    fn slowly_counting_samples_spread_out() {
+        // Time stamp counter samples only differ in their low bits.
+        let first = fold((0..FALLBACK_SAMPLES as u64).map(|i| 0x1_0000_0000 + i * 40));
+        let second = fold((0..FALLBACK_SAMPLES as u64).map(|i| 0x1_0000_0001 + i * 40));
+        assert!((first ^ second).count_ones() >= 12);
+        assert_ne!(first >> 32, 0);
+
+        let constant = fold([0u64; FALLBACK_SAMPLES]);
+        assert_ne!(constant, 0);
+        assert_ne!(constant, fold([0u64; FALLBACK_SAMPLES - 1]));
+    }
+}
@@ -0,0 +1,83 @@
+//! Removal of the loader's traces from its own image before the boot manager is started.
+//!
+//! The loader stays loaded while the boot manager runs, as `start_image` returns into it if the boot
+//! manager fails. Its pages can therefore not be freed or moved, but what points at them can be reduced:
+//! the file path and load options in the `LoadedImage` protocol, the `LoadedImageDevicePath` protocol on
+//! the image handle and the padding behind the writable sections. The firmware frees the image once the
+//! loader returns.
+
+use {
+    crate::pe,
+    core::ffi::c_void,
+    uefi::{
+        prelude::*,
+        proto::{device_path::LoadedImageDevicePath, loaded_image::LoadedImage},
+        table::boot::{OpenProtocolAttributes, OpenProtocolParams},
+        Identify,
+    },
+};
+
+/// Layout of the start of `EFI_LOADED_IMAGE_PROTOCOL` up to the file path, which `LoadedImage` can't set.
+#[repr(C)]
+struct LoadedImageHeader {
+    revision: u32,
+    parent_handle: *const c_void,
+    system_table: *const c_void,
+    device_handle: *const c_void,
+    file_path: *const c_void,
+}
+
+/// Scrubs the loader's own image.
+///
+/// Call it as late as possible: afterwards the loader can't find its own file path anymore. The device
+/// handle is kept, so the log can still be saved to the loader's volume.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+pub(crate) fn scrub_loader_image(boot_services: &BootServices) {
+    let image_handle = boot_services.image_handle();
+
+    match boot_services.open_protocol_exclusive::<LoadedImage>(image_handle) {
+        Ok(mut loaded_image) => {
+            let (base, size) = loaded_image.info();
+
+            // SAFETY: `LoadedImage` is a transparent wrapper of the protocol structure, whose layout starts
+            // with `LoadedImageHeader`. Nothing but the loader reads its own file path or load options.
+            unsafe {
+                (*(&mut *loaded_image as *mut LoadedImage).cast::<LoadedImageHeader>()).file_path = core::ptr::null();
+                loaded_image.set_load_options(core::ptr::null(), 0);
+            }
+            log::debug!("Cleared the loader's file path and load options");
+
+            // SAFETY: The image is mapped for `size` bytes at `base` for as long as the loader runs.
+            let image = unsafe { core::slice::from_raw_parts_mut(base as *mut u8, size as usize) };
+            let slack = pe::writable_section_slack(image);
+            let zeroed: usize = slack.iter().map(|range| range.len()).sum();
+            for range in slack {
+                image[range].fill(0);
+            }
+            log::debug!("Zeroed {} bytes of section padding in the loader image", zeroed);
+        }
+        Err(error) => log::warn!("Failed to open the loader's LoadedImage to scrub it ({:?})", error.status()),
+    }
+
+    let params = OpenProtocolParams {
+        handle: image_handle,
+        agent: image_handle,
+        controller: None,
+    };
+
+    // SAFETY: The interface pointer is only used to uninstall the protocol, not dereferenced afterwards.
+    let uninstalled = unsafe {
+        boot_services
+            .open_protocol::<LoadedImageDevicePath>(params, OpenProtocolAttributes::GetProtocol)
+            .map(|device_path| &*device_path as *const LoadedImageDevicePath as *const c_void)
+            .and_then(|interface| boot_services.uninstall_protocol_interface(image_handle, &LoadedImageDevicePath::GUID, interface))
+    };
+
+    match uninstalled {
+        Ok(()) => log::debug!("Uninstalled the loader's LoadedImageDevicePath protocol"),
+        Err(error) => log::debug!("Failed to uninstall the loader's LoadedImageDevicePath protocol ({:?})", error.status()),
+    }
+}
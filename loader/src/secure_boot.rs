@@ -0,0 +1,65 @@
+//! Detection of the Secure Boot state from the `SecureBoot` and `SetupMode` global variables.
+//!
+//! With Secure Boot enforced, the firmware refuses to load images that aren't signed by a key in `db`,
+//! which `load_image` only reports as `SECURITY_VIOLATION`. Knowing the state up front lets the loader
+//! explain the failure or avoid it according to the configured policy.
+
+use uefi::{prelude::*, table::runtime::VariableVendor, CStr16};
+
+/// Name of the variable that is `1` if the platform enforces Secure Boot.
+const SECURE_BOOT_VARIABLE: &CStr16 = cstr16!("SecureBoot");
+
+/// Name of the variable that is `1` if no platform key is enrolled.
+const SETUP_MODE_VARIABLE: &CStr16 = cstr16!("SetupMode");
+
+/// Secure Boot state of the platform.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SecureBootState {
+    /// Whether Secure Boot is enabled.
+    pub enabled: bool,
+
+    /// Whether the platform is in setup mode, in which signatures are not checked.
+    pub setup_mode: bool,
+}
+
+impl SecureBootState {
+    /// Reads the Secure Boot state.
+    ///
+    /// # Arguments
+    ///
+    /// * `runtime_services` - A reference to the UEFI runtime services.
+    ///
+    /// # Returns
+    ///
+    /// The state of the platform. Missing variables, as on firmware without Secure Boot support, are treated
+    /// as disabled.
+    pub(crate) fn read(runtime_services: &RuntimeServices) -> Self {
+        Self {
+            enabled: read_flag(runtime_services, SECURE_BOOT_VARIABLE),
+            setup_mode: read_flag(runtime_services, SETUP_MODE_VARIABLE),
+        }
+    }
+
+    /// Returns whether the firmware checks image signatures.
+    pub(crate) fn is_enforced(self) -> bool {
+        self.enabled && !self.setup_mode
+    }
+}
+
+/// Reads a single byte boolean global variable.
+fn read_flag(runtime_services: &RuntimeServices, name: &CStr16) -> bool {
+    let mut buffer = [0u8; 1];
+
+    match runtime_services.get_variable(name, &VariableVendor::GLOBAL_VARIABLE, &mut buffer) {
+        Ok(([value], _)) => *value == 1,
+        Ok((data, _)) => {
+            log::warn!("Ignoring {} with unexpected size {}", name, data.len());
+            false
+        }
+        Err(error) if error.status() == Status::NOT_FOUND => false,
+        Err(error) => {
+            log::warn!("Failed to read {} ({:?})", name, error.status());
+            false
+        }
+    }
+}
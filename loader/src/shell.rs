@@ -0,0 +1,484 @@
+//! Debug shell entered by pressing `d` at the selection menu.
+//!
+//! When boot discovery misbehaves, the shell shows the filesystems, handles and variables the loader
+//! sees without booting another operating system. Commands are parsed and their output is formatted
+//! here, the filesystem access goes through the helpers in `images.rs`.
+
+extern crate alloc;
+
+use {
+    crate::{config, devpath, images, logging},
+    alloc::{
+        format,
+        string::{String, ToString},
+        vec::Vec,
+    },
+    core::fmt::Write,
+    uefi::{
+        prelude::*,
+        proto::{
+            console::text::{Key, ScanCode},
+            media::block::BlockIO,
+        },
+        table::boot::SearchType,
+        CString16, Identify,
+    },
+};
+
+/// Interval between two polls of the console input.
+const POLL_INTERVAL_US: usize = 10_000;
+
+/// Longest command line accepted, further characters are dropped.
+const MAX_LINE_LEN: usize = 256;
+
+/// Largest number of bytes `hexdump` prints.
+const MAX_DUMP_LEN: usize = 64 * 1024;
+
+/// Number of bytes of a variable `var` prints at most.
+const MAX_VARIABLE_DUMP_LEN: usize = 1024;
+
+/// The prompt written before every command line.
+const PROMPT: &str = "shell> ";
+
+/// The character reported by the console for the backspace key.
+const BACKSPACE: char = '\u{8}';
+
+/// Names of the variable attribute bits, in bit order.
+const ATTRIBUTE_NAMES: [&str; 7] = ["NV", "BS", "RT", "HW", "AW", "TAW", "APPEND"];
+
+/// The command summary printed by `help`.
+const HELP: [&str; 8] = [
+    "handles               list the SimpleFileSystem and BlockIO handles",
+    "fs <n>                select filesystem n of the handles list for ls and hexdump",
+    "ls [path]             list a directory on the selected filesystem",
+    "hexdump <path> <len>  dump the first len bytes of a file on the selected filesystem",
+    "var <name>            dump the UEFI variables with this name",
+    "boot <n>              leave the shell and boot candidate n",
+    "exit                  return to the menu, as does ESC",
+    "help                  show this list",
+];
+
+/// A parsed shell command.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Command {
+    /// Print the command summary.
+    Help,
+
+    /// List the filesystem and block device handles.
+    Handles,
+
+    /// Select the filesystem with the given 1-based number of the handles list.
+    Fs(usize),
+
+    /// List the directory at the path.
+    Ls(String),
+
+    /// Dump the first `len` bytes of the file at `path`.
+    Hexdump { path: String, len: usize },
+
+    /// Dump the variables with the name.
+    Var(String),
+
+    /// Boot the candidate with the given 1-based number.
+    Boot(usize),
+
+    /// Return to the menu.
+    Exit,
+
+    /// The line was empty.
+    Empty,
+}
+
+/// Parses a command line.
+///
+/// # Returns
+///
+/// The command, or a message describing what is wrong with the line.
+pub(crate) fn parse(line: &str) -> Result<Command, &'static str> {
+    let mut words = line.split_whitespace();
+    let Some(name) = words.next() else {
+        return Ok(Command::Empty);
+    };
+
+    let arguments: Vec<&str> = words.collect();
+    let command = match (name.to_ascii_lowercase().as_str(), arguments.as_slice()) {
+        ("help" | "?", []) => Command::Help,
+        ("handles", []) => Command::Handles,
+        ("fs", [number]) => Command::Fs(
+            parse_number(number)
+                .filter(|&number| number != 0)
+                .ok_or("usage: fs <n>, n as listed by handles")?,
+        ),
+        ("fs", _) => return Err("usage: fs <n>, n as listed by handles"),
+        ("ls", []) => Command::Ls(normalize_path("")),
+        ("ls", [path]) => Command::Ls(normalize_path(path)),
+        ("ls", _) => return Err("usage: ls [path]"),
+        ("hexdump", [path, len]) => {
+            let len = parse_number(len).ok_or("usage: hexdump <path> <len>, len in decimal or 0x hex")?;
+            if len > MAX_DUMP_LEN {
+                return Err("hexdump prints at most 65536 bytes");
+            }
+            Command::Hexdump {
+                path: normalize_path(path),
+                len,
+            }
+        }
+        ("hexdump", _) => return Err("usage: hexdump <path> <len>"),
+        ("var", [name]) => Command::Var(String::from(*name)),
+        ("var", _) => return Err("usage: var <name>"),
+        ("boot", [number]) => Command::Boot(
+            parse_number(number)
+                .filter(|&number| number != 0)
+                .ok_or("usage: boot <n>, n as shown in the menu")?,
+        ),
+        ("boot", _) => return Err("usage: boot <n>"),
+        ("exit" | "quit", []) => Command::Exit,
+        ("help" | "?" | "handles" | "exit" | "quit", _) => return Err("this command takes no arguments"),
+        _ => return Err("unknown command, type help for the list"),
+    };
+
+    Ok(command)
+}
+
+/// Parses a number given in decimal or as `0x` prefixed hex.
+fn parse_number(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// Turns a path typed in the shell into an absolute UEFI path, accepting `/` as separator.
+fn normalize_path(path: &str) -> String {
+    config::normalize_path(&format!("\\{}", path))
+}
+
+/// What a key typed at the prompt does to the command line.
+#[derive(Debug, PartialEq)]
+pub(crate) enum LineEvent {
+    /// The character was appended and is echoed.
+    Echo(char),
+
+    /// The last character was deleted.
+    Erase,
+
+    /// ENTER completed the line.
+    Submit(String),
+
+    /// The key changed nothing.
+    Ignore,
+}
+
+/// The command line being typed.
+pub(crate) struct LineEditor {
+    /// The characters typed so far.
+    line: String,
+}
+
+impl LineEditor {
+    pub(crate) fn new() -> Self {
+        Self { line: String::new() }
+    }
+
+    /// Applies a typed character to the line.
+    pub(crate) fn feed(&mut self, ch: char) -> LineEvent {
+        match ch {
+            '\r' | '\n' => LineEvent::Submit(core::mem::take(&mut self.line)),
+            BACKSPACE => match self.line.pop() {
+                Some(_) => LineEvent::Erase,
+                None => LineEvent::Ignore,
+            },
+            _ if ch.is_control() || self.line.chars().count() >= MAX_LINE_LEN => LineEvent::Ignore,
+            _ => {
+                self.line.push(ch);
+                LineEvent::Echo(ch)
+            }
+        }
+    }
+}
+
+/// Formats a directory entry as its size, or `<DIR>`, followed by the name.
+pub(crate) fn format_entry(name: &str, size: u64, is_directory: bool) -> String {
+    match is_directory {
+        true => format!("{:>12}  {}\\", "<DIR>", name),
+        false => format!("{:>12}  {}", size, name),
+    }
+}
+
+/// Formats a line of the handles list.
+///
+/// # Arguments
+///
+/// * `number` - The 1-based filesystem number used with `fs`, `None` for block devices without filesystem.
+/// * `block_io` - The handle supports the `BlockIO` protocol.
+/// * `device_path` - The device path of the handle as text.
+pub(crate) fn format_handle(number: Option<usize>, block_io: bool, device_path: &str) -> String {
+    let protocols = match (number.is_some(), block_io) {
+        (true, true) => "FS BLK",
+        (true, false) => "FS",
+        (false, _) => "BLK",
+    };
+
+    let number = number.map_or(String::from("-"), |number| number.to_string());
+    format!("{:>4}  {:<6}  {}", number, protocols, device_path)
+}
+
+/// Formats variable attributes as their short names, e.g. `NV BS RT`.
+pub(crate) fn attribute_names(bits: u32) -> String {
+    let mut names: Vec<String> = ATTRIBUTE_NAMES
+        .iter()
+        .enumerate()
+        .filter(|(bit, _)| bits & (1 << bit) != 0)
+        .map(|(_, name)| String::from(*name))
+        .collect();
+
+    let unknown = bits & !((1 << ATTRIBUTE_NAMES.len()) - 1);
+    if unknown != 0 {
+        names.push(format!("{:#x}", unknown));
+    }
+
+    match names.is_empty() {
+        true => String::from("none"),
+        false => names.join(" "),
+    }
+}
+
+/// Runs the shell until the user exits it or picks a candidate.
+///
+/// # Arguments
+///
+/// * `system_table` - The UEFI system table, used for console input and output.
+/// * `candidates` - The number of candidates in the menu, for `boot`.
+///
+/// # Returns
+///
+/// The index of the candidate chosen with `boot`, or `None` to return to the menu.
+pub(crate) fn run(system_table: &mut SystemTable<Boot>, candidates: usize) -> Option<usize> {
+    let _ = system_table.stdout().enable_cursor(true);
+    log::info!("Debug shell, type help for the commands or exit to return to the menu");
+
+    let mut volume = 1;
+    loop {
+        let line = read_line(system_table)?;
+
+        match parse(&line) {
+            Ok(Command::Empty) => {}
+            Ok(Command::Help) => HELP.iter().for_each(|line| log::info!("  {}", line)),
+            Ok(Command::Handles) => list_handles(system_table.boot_services()),
+            Ok(Command::Fs(number)) => match volume_handle(system_table.boot_services(), number) {
+                Some(_) => {
+                    volume = number;
+                    log::info!("Selected filesystem {}", number);
+                }
+                None => log::warn!("There is no filesystem {}, see handles", number),
+            },
+            Ok(Command::Ls(path)) => list_directory(system_table.boot_services(), volume, &path),
+            Ok(Command::Hexdump { path, len }) => dump_file(system_table.boot_services(), volume, &path, len),
+            Ok(Command::Var(name)) => dump_variable(system_table.runtime_services(), &name),
+            Ok(Command::Boot(number)) if number <= candidates => return Some(number - 1),
+            Ok(Command::Boot(number)) => log::warn!("There is no candidate {}, enter a number between 1 and {}", number, candidates),
+            Ok(Command::Exit) => return None,
+            Err(message) => log::warn!("{}", message),
+        }
+    }
+}
+
+/// Prompts for a command line and reads it, echoing the typed characters.
+///
+/// # Returns
+///
+/// The line, or `None` if the user pressed ESC or the console can't be read.
+fn read_line(system_table: &mut SystemTable<Boot>) -> Option<String> {
+    let _ = system_table.stdout().write_str(PROMPT);
+    let mut editor = LineEditor::new();
+
+    loop {
+        match system_table.stdin().read_key() {
+            Ok(Some(Key::Printable(c))) => match editor.feed(char::from(c)) {
+                LineEvent::Echo(ch) => {
+                    let _ = system_table.stdout().write_char(ch);
+                }
+                LineEvent::Erase => {
+                    let _ = system_table.stdout().write_str("\u{8} \u{8}");
+                }
+                LineEvent::Submit(line) => {
+                    let _ = system_table.stdout().write_str("\r\n");
+                    return Some(line);
+                }
+                LineEvent::Ignore => {}
+            },
+            Ok(Some(Key::Special(ScanCode::ESCAPE))) => {
+                let _ = system_table.stdout().write_str("\r\n");
+                return None;
+            }
+            Ok(Some(_)) => {}
+            Ok(None) => system_table.boot_services().stall(POLL_INTERVAL_US),
+            Err(error) => {
+                log::warn!("Failed to read key from console ({:?}), leaving the shell", error);
+                return None;
+            }
+        }
+    }
+}
+
+/// Returns the handle of the filesystem with the given 1-based number of the handles list.
+fn volume_handle(boot_services: &BootServices, number: usize) -> Option<Handle> {
+    images::locate_volumes(boot_services).ok()?.get(number.checked_sub(1)?).copied()
+}
+
+/// Lists the filesystem handles, numbered as in the discovery log, followed by the other block devices.
+fn list_handles(boot_services: &BootServices) {
+    let volumes: Vec<Handle> = match images::locate_volumes(boot_services) {
+        Ok(handles) => handles.to_vec(),
+        Err(error) => {
+            log::warn!("{}", error);
+            Vec::new()
+        }
+    };
+
+    let block_devices: Vec<Handle> = boot_services
+        .locate_handle_buffer(SearchType::ByProtocol(&BlockIO::GUID))
+        .map(|handles| handles.to_vec())
+        .unwrap_or_default();
+
+    log::info!("{:>4}  {:<6}  Device path", "FS", "Kind");
+    for handle in volumes.iter().chain(block_devices.iter().filter(|handle| !volumes.contains(handle))) {
+        let number = volumes.iter().position(|volume| volume == handle).map(|index| index + 1);
+        log::info!("{}", format_handle(number, block_devices.contains(handle), &devpath::handle_to_text(boot_services, *handle)));
+    }
+}
+
+/// Lists the directory at `path` on the filesystem with the given number.
+fn list_directory(boot_services: &BootServices, volume: usize, path: &str) {
+    let (Some(handle), Ok(uefi_path)) = (volume_handle(boot_services, volume), CString16::try_from(path)) else {
+        log::warn!("Filesystem {} or path {} is not available", volume, path);
+        return;
+    };
+
+    match images::list_directory(boot_services, handle, &uefi_path) {
+        Ok(entries) => {
+            log::info!("Directory {} on filesystem {}, {} entries", path, volume, entries.len());
+            for entry in entries {
+                log::info!("{}", format_entry(&entry.file_name().to_string(), entry.file_size(), entry.is_directory()));
+            }
+        }
+        Err(error) => log::warn!("Failed to list {} on filesystem {}: {}", path, volume, error),
+    }
+}
+
+/// Dumps the first `len` bytes of the file at `path` on the filesystem with the given number.
+fn dump_file(boot_services: &BootServices, volume: usize, path: &str, len: usize) {
+    let (Some(handle), Ok(uefi_path)) = (volume_handle(boot_services, volume), CString16::try_from(path)) else {
+        log::warn!("Filesystem {} or path {} is not available", volume, path);
+        return;
+    };
+
+    match images::read_file(boot_services, handle, &uefi_path) {
+        Ok(bytes) => {
+            log::info!("{} on filesystem {} is {} bytes", path, volume, bytes.len());
+            for line in logging::hex_dump(&bytes[..bytes.len().min(len)]) {
+                log::info!("{}", line);
+            }
+        }
+        Err(error) => log::warn!("Failed to read {} on filesystem {}: {}", path, volume, error),
+    }
+}
+
+/// Dumps every variable named `name`, whatever its vendor.
+fn dump_variable(runtime_services: &RuntimeServices, name: &str) {
+    let keys = match runtime_services.variable_keys() {
+        Ok(keys) => keys,
+        Err(error) => {
+            log::warn!("Failed to enumerate variables ({:?})", error.status());
+            return;
+        }
+    };
+
+    let mut found = false;
+    for (key_name, vendor) in keys.iter().filter_map(|key| Some((key.name().ok()?, &key.vendor))) {
+        if key_name.to_string() != name {
+            continue;
+        }
+
+        found = true;
+        match runtime_services.get_variable_boxed(key_name, vendor) {
+            Ok((data, attributes)) => {
+                log::info!("{}-{}: {} bytes, attributes {}", name, vendor.0, data.len(), attribute_names(attributes.bits()));
+                for line in logging::hex_dump(&data[..data.len().min(MAX_VARIABLE_DUMP_LEN)]) {
+                    log::info!("{}", line);
+                }
+            }
+            Err(error) => log::warn!("Failed to read {}-{} ({:?})", name, vendor.0, error.status()),
+        }
+    }
+
+    if !found {
+        log::warn!("There is no variable named {}", name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_parsed() {
+        assert_eq!(parse("  "), Ok(Command::Empty));
+        assert_eq!(parse("HELP"), Ok(Command::Help));
+        assert_eq!(parse("handles"), Ok(Command::Handles));
+        assert_eq!(parse("fs 2"), Ok(Command::Fs(2)));
+        assert_eq!(parse("ls"), Ok(Command::Ls(String::from("\\"))));
+        assert_eq!(parse("ls EFI/Boot"), Ok(Command::Ls(String::from(r"\EFI\Boot"))));
+        assert_eq!(parse(r"ls \EFI\"), Ok(Command::Ls(String::from(r"\EFI\"))));
+        assert_eq!(
+            parse(r"hexdump \EFI\Boot\bootx64.efi 0x40"),
+            Ok(Command::Hexdump {
+                path: String::from(r"\EFI\Boot\bootx64.efi"),
+                len: 64
+            })
+        );
+        assert_eq!(parse("var BootOrder"), Ok(Command::Var(String::from("BootOrder"))));
+        assert_eq!(parse("boot 3"), Ok(Command::Boot(3)));
+        assert_eq!(parse("exit"), Ok(Command::Exit));
+    }
+
+    #[test]
+    fn invalid_commands_are_rejected() {
+        assert!(parse("fs 0").is_err());
+        assert!(parse("fs one").is_err());
+        assert!(parse("boot").is_err());
+        assert!(parse("hexdump file").is_err());
+        assert!(parse("hexdump file 100000").is_err());
+        assert!(parse("handles now").is_err());
+        assert!(parse("format c:").is_err());
+    }
+
+    #[test]
+    fn lines_are_edited() {
+        let mut editor = LineEditor::new();
+        assert_eq!(editor.feed(BACKSPACE), LineEvent::Ignore);
+        assert_eq!(editor.feed('l'), LineEvent::Echo('l'));
+        assert_eq!(editor.feed('x'), LineEvent::Echo('x'));
+        assert_eq!(editor.feed(BACKSPACE), LineEvent::Erase);
+        assert_eq!(editor.feed('s'), LineEvent::Echo('s'));
+        assert_eq!(editor.feed('\u{1}'), LineEvent::Ignore);
+        assert_eq!(editor.feed('\r'), LineEvent::Submit(String::from("ls")));
+        assert_eq!(editor.feed('\r'), LineEvent::Submit(String::new()));
+
+        (0..MAX_LINE_LEN).for_each(|_| {
+            editor.feed('a');
+        });
+        assert_eq!(editor.feed('a'), LineEvent::Ignore);
+    }
+
+    #[test]
+    fn output_is_formatted() {
+        assert_eq!(format_entry("EFI", 0, true), "       <DIR>  EFI\\");
+        assert_eq!(format_entry("illusion.cfg", 512, false), "         512  illusion.cfg");
+        assert_eq!(format_handle(Some(2), true, "PciRoot(0x0)"), "   2  FS BLK  PciRoot(0x0)");
+        assert_eq!(format_handle(None, true, "PciRoot(0x0)"), "   -  BLK     PciRoot(0x0)");
+        assert_eq!(attribute_names(0x7), "NV BS RT");
+        assert_eq!(attribute_names(0x106), "BS RT 0x100");
+        assert_eq!(attribute_names(0), "none");
+    }
+}
@@ -0,0 +1,42 @@
+//! One-shot request to boot Windows without the hypervisor.
+//!
+//! Creating the `IllusionSkipOnce` variable under the loader's vendor GUID, volatile or non-volatile and
+//! with any content, makes the next boot skip the hypervisor phase. The loader deletes the variable when
+//! it sees it, so the boot after that is virtualized again.
+
+use {
+    crate::last_boot::ILLUSION_VENDOR,
+    uefi::{prelude::*, CStr16},
+};
+
+/// Name of the variable requesting a boot without the hypervisor.
+const SKIP_ONCE_VARIABLE: &CStr16 = cstr16!("IllusionSkipOnce");
+
+/// Checks for and consumes a skip request.
+///
+/// # Arguments
+///
+/// * `runtime_services` - A reference to the UEFI runtime services.
+///
+/// # Returns
+///
+/// `true` if the variable was present. The request is honored even if the variable can't be deleted.
+pub(crate) fn take(runtime_services: &RuntimeServices) -> bool {
+    match runtime_services.get_variable_size(SKIP_ONCE_VARIABLE, &ILLUSION_VENDOR) {
+        Ok(_) => {}
+        Err(error) if error.status() == Status::NOT_FOUND => return false,
+        Err(error) => {
+            log::warn!("Failed to read {} ({:?}), not skipping the hypervisor", SKIP_ONCE_VARIABLE, error.status());
+            return false;
+        }
+    }
+
+    match runtime_services.delete_variable(SKIP_ONCE_VARIABLE, &ILLUSION_VENDOR) {
+        Ok(()) => log::debug!("Deleted {}", SKIP_ONCE_VARIABLE),
+        Err(error) => {
+            log::warn!("Failed to delete {} ({:?}), the hypervisor will be skipped again on the next boot", SKIP_ONCE_VARIABLE, error.status())
+        }
+    }
+
+    true
+}
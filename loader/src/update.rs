@@ -0,0 +1,250 @@
+//! Installation of a hypervisor update staged next to the image.
+//!
+//! Windows rarely mounts the ESP, so an update is copied there as `illusion.efi.new`, optionally with its
+//! digest in `illusion.efi.new.sha256`, and installed by the loader before the image is read. The update is
+//! written to `illusion.efi.tmp` first and only renamed into place once it is complete, with the previous
+//! image kept as `illusion.efi.bak`. An update that fails validation is renamed to `illusion.efi.new.bad`,
+//! so that it is not tried again on every boot.
+
+extern crate alloc;
+
+use {
+    crate::{
+        compress,
+        config::LoaderConfig,
+        images::{self, ReadError},
+        pe,
+        verify::{self, SIDECAR_EXTENSION},
+    },
+    alloc::{format, string::String},
+    uefi::{
+        prelude::*,
+        proto::media::{
+            file::{Directory, File, FileAttribute, FileHandle, FileInfo, FileMode},
+            fs::SimpleFileSystem,
+        },
+        CStr16, CString16,
+    },
+};
+
+/// Extension of the staged update.
+const PENDING_EXTENSION: &str = ".new";
+
+/// Extension of the update while it is written next to the image.
+const TEMPORARY_EXTENSION: &str = ".tmp";
+
+/// Extension the previous image is kept with.
+pub(crate) const BACKUP_EXTENSION: &str = ".bak";
+
+/// Extension a rejected update is renamed to.
+const QUARANTINE_EXTENSION: &str = ".bad";
+
+/// Returns the last component of a path, the name a file is renamed to in its directory.
+pub(crate) fn file_name(path: &str) -> &str {
+    path.rsplit('\\').next().unwrap_or(path)
+}
+
+/// Installs the update staged for the hypervisor image, if there is one.
+///
+/// Failing to install a valid update only leaves the current image in place, the boot continues either way.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+/// * `handle` - The filesystem holding the hypervisor image.
+/// * `path` - The path of the hypervisor image on that filesystem.
+/// * `config` - The loader configuration, for the digest the update is verified against.
+pub(crate) fn install_pending(boot_services: &BootServices, handle: Handle, path: &CStr16, config: &LoaderConfig) {
+    let image = format!("{}", path);
+    let pending = format!("{}{}", image, PENDING_EXTENSION);
+    let Ok(pending_path) = CString16::try_from(pending.as_str()) else {
+        return;
+    };
+
+    let bytes = match images::read_file(boot_services, handle, &pending_path) {
+        Ok(bytes) => bytes,
+        Err(ReadError::NotFound) => return,
+        Err(error) => {
+            log::warn!("[3/8] Failed to read the staged hypervisor update {} ({})", pending, error);
+            return;
+        }
+    };
+
+    log::info!("[3/8] Found staged hypervisor update {} ({} bytes)", pending, bytes.len());
+    let sidecar = exists(boot_services, handle, &format!("{}{}", pending, SIDECAR_EXTENSION));
+
+    if let Err(reason) = validate(boot_services, handle, &image, &pending_path, sidecar, config, &bytes) {
+        log::error!("[3/8] Rejecting hypervisor update {}: {}", pending, reason);
+        match quarantine(boot_services, handle, &pending, sidecar) {
+            Ok(()) => log::warn!("[3/8] Renamed the rejected update to {}{}", pending, QUARANTINE_EXTENSION),
+            Err(error) => log::warn!("[3/8] Failed to set the rejected update aside ({:?})", error.status()),
+        }
+        return;
+    }
+
+    match replace(boot_services, handle, &image, &bytes, sidecar) {
+        Ok(()) => log::info!("[3/8] Installed hypervisor update {}, previous image kept as {}{}", image, image, BACKUP_EXTENSION),
+        Err(error) => log::warn!("[3/8] Failed to install hypervisor update ({:?}), keeping the current image", error.status()),
+    }
+}
+
+/// Checks that the staged update is an image the loader would start.
+///
+/// # Returns
+///
+/// Why the update is rejected.
+fn validate(
+    boot_services: &BootServices,
+    handle: Handle,
+    image: &str,
+    pending: &CStr16,
+    sidecar: bool,
+    config: &LoaderConfig,
+    bytes: &[u8],
+) -> Result<(), String> {
+    let decompressed = match compress::is_compressed(bytes) {
+        true => Some(compress::decompress(bytes).map_err(|error| format!("{}", error))?),
+        false => None,
+    };
+    pe::validate(decompressed.as_deref().unwrap_or(bytes)).map_err(|error| format!("{}", error))?;
+
+    // The digest file of the current image would reject the update once it is in place.
+    if config.hypervisor_sha256.is_none() && !sidecar && exists(boot_services, handle, &format!("{}{}", image, SIDECAR_EXTENSION)) {
+        return Err(format!("{}{} exists, but the update comes without a digest file", image, SIDECAR_EXTENSION));
+    }
+
+    verify::verify_hypervisor(boot_services, Some((handle, pending)), config, bytes).map_err(|error| format!("{}", error))?;
+    Ok(())
+}
+
+/// Writes the update next to the image and swaps it in, keeping the previous image as backup.
+fn replace(boot_services: &BootServices, handle: Handle, image: &str, bytes: &[u8], sidecar: bool) -> uefi::Result {
+    let mut file_system = boot_services.open_protocol_exclusive::<SimpleFileSystem>(handle)?;
+    let mut root = file_system.open_volume()?;
+
+    let temporary = format!("{}{}", image, TEMPORARY_EXTENSION);
+    let backup = format!("{}{}", image, BACKUP_EXTENSION);
+
+    // A previous attempt may have been interrupted after creating the temporary file.
+    delete(&mut root, &temporary)?;
+    if let Err(error) = write(&mut root, &temporary, bytes) {
+        let _ = delete(&mut root, &temporary);
+        return Err(error);
+    }
+
+    delete(&mut root, &backup)?;
+    rename(&mut root, image, file_name(&backup))?;
+    if let Err(error) = rename(&mut root, &temporary, file_name(image)) {
+        let _ = rename(&mut root, &backup, file_name(image));
+        return Err(error);
+    }
+
+    delete(&mut root, &format!("{}{}", image, PENDING_EXTENSION))?;
+
+    // The digest file of the previous image moves with it, so that the backup can still be verified.
+    if sidecar {
+        let installed = format!("{}{}", image, SIDECAR_EXTENSION);
+        let backup_sidecar = format!("{}{}", backup, SIDECAR_EXTENSION);
+        delete(&mut root, &backup_sidecar)?;
+        match rename(&mut root, &installed, file_name(&backup_sidecar)) {
+            Err(error) if error.status() == Status::NOT_FOUND => {}
+            result => result?,
+        }
+        rename(&mut root, &format!("{}{}{}", image, PENDING_EXTENSION, SIDECAR_EXTENSION), file_name(&installed))?;
+    }
+
+    Ok(())
+}
+
+/// Renames a rejected update and its digest file, replacing the ones rejected before.
+fn quarantine(boot_services: &BootServices, handle: Handle, pending: &str, sidecar: bool) -> uefi::Result {
+    let mut file_system = boot_services.open_protocol_exclusive::<SimpleFileSystem>(handle)?;
+    let mut root = file_system.open_volume()?;
+
+    let mut files = alloc::vec![String::from(pending)];
+    if sidecar {
+        files.push(format!("{}{}", pending, SIDECAR_EXTENSION));
+    }
+
+    for file in &files {
+        let quarantined = format!("{}{}", file, QUARANTINE_EXTENSION);
+        delete(&mut root, &quarantined)?;
+        if rename(&mut root, file, file_name(&quarantined)).is_err() {
+            // Deleting the update also keeps the boot from retrying it.
+            delete(&mut root, file)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns whether a file exists.
+fn exists(boot_services: &BootServices, handle: Handle, path: &str) -> bool {
+    match CString16::try_from(path) {
+        Ok(path) => images::read_file(boot_services, handle, &path).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Opens a file relative to the root directory.
+fn open(root: &mut Directory, path: &str, mode: FileMode) -> uefi::Result<FileHandle> {
+    let path = CString16::try_from(path).map_err(|_| uefi::Error::from(Status::INVALID_PARAMETER))?;
+    root.open(&path, mode, FileAttribute::empty())
+}
+
+/// Creates a file holding `bytes`, which must not exist yet.
+fn write(root: &mut Directory, path: &str, bytes: &[u8]) -> uefi::Result {
+    let mut file = open(root, path, FileMode::CreateReadWrite)?
+        .into_regular_file()
+        .ok_or(uefi::Error::from(Status::ACCESS_DENIED))?;
+
+    file.write(bytes).map_err(|error| uefi::Error::from(error.status()))?;
+    file.flush()
+}
+
+/// Deletes a file, succeeding if it does not exist.
+fn delete(root: &mut Directory, path: &str) -> uefi::Result {
+    match open(root, path, FileMode::ReadWrite) {
+        Ok(file) => file.delete(),
+        Err(error) if error.status() == Status::NOT_FOUND => Ok(()),
+        Err(error) => Err(error),
+    }
+}
+
+/// Renames a file within its directory.
+fn rename(root: &mut Directory, path: &str, name: &str) -> uefi::Result {
+    let name = CString16::try_from(name).map_err(|_| uefi::Error::from(Status::INVALID_PARAMETER))?;
+    let mut file = open(root, path, FileMode::ReadWrite)?;
+    let info = file.get_boxed_info::<FileInfo>()?;
+
+    // The info is built in place and has to be aligned like the structure.
+    let align = core::mem::align_of::<u64>();
+    let mut storage = alloc::vec![0u8; core::mem::size_of_val(&*info) + name.num_bytes() + align];
+    let offset = storage.as_ptr().align_offset(align);
+
+    let renamed = FileInfo::new(
+        &mut storage[offset..],
+        info.file_size(),
+        info.physical_size(),
+        *info.create_time(),
+        *info.last_access_time(),
+        *info.modification_time(),
+        info.attribute(),
+        &name,
+    )
+    .map_err(|_| uefi::Error::from(Status::BUFFER_TOO_SMALL))?;
+
+    file.set_info(renamed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_names_are_the_last_component() {
+        assert_eq!(file_name(r"\EFI\Boot\illusion.efi.bak"), "illusion.efi.bak");
+        assert_eq!(file_name("illusion.efi"), "illusion.efi");
+        assert_eq!(file_name(r"\illusion.efi.new.bad"), "illusion.efi.new.bad");
+    }
+}
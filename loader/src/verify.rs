@@ -0,0 +1,115 @@
+//! Integrity check of the hypervisor image before it is started.
+//!
+//! The SHA-256 digest of the on-disk image is compared against the `hypervisor_sha256` value from
+//! `illusion.cfg`, or against a sidecar file next to the image (e.g. `illusion.efi.sha256`) in the format
+//! produced by `sha256sum`.
+
+extern crate alloc;
+
+use {
+    crate::{
+        config::LoaderConfig,
+        error::LoaderError,
+        images,
+        sha256::{Sha256, DIGEST_SIZE},
+    },
+    alloc::{format, string::String},
+    uefi::{prelude::*, CStr16, CString16},
+};
+
+/// Extension appended to the hypervisor path to locate the sidecar digest file.
+pub(crate) const SIDECAR_EXTENSION: &str = ".sha256";
+
+/// Verifies the hypervisor image against the expected digest.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+/// * `sidecar` - The filesystem handle and path of the hypervisor image, used to look up the sidecar file.
+///   `None` if the image was not read from a local filesystem.
+/// * `config` - The loader configuration holding the expected digest and the `--no-verify` override.
+/// * `image` - The raw on-disk bytes of the hypervisor image.
+///
+/// # Returns
+///
+/// `Ok(true)` if the digest matched, `Ok(false)` if the check was skipped, or
+/// `LoaderError::HypervisorIntegrityCheckFailed` if the digest does not match.
+pub(crate) fn verify_hypervisor(
+    boot_services: &BootServices,
+    sidecar: Option<(Handle, &CStr16)>,
+    config: &LoaderConfig,
+    image: &[u8],
+) -> Result<bool, LoaderError> {
+    if config.skip_verify {
+        log::warn!("Skipping hypervisor integrity check as requested by load options");
+        return Ok(false);
+    }
+
+    let expected = match config.hypervisor_sha256 {
+        Some(digest) => digest,
+        None => match sidecar.and_then(|(handle, path)| read_sidecar_digest(boot_services, handle, path)) {
+            Some(digest) => digest,
+            None => {
+                log::warn!("No expected SHA-256 digest configured for the hypervisor, skipping integrity check");
+                return Ok(false);
+            }
+        },
+    };
+
+    let computed = Sha256::digest(image);
+
+    if computed != expected {
+        log::error!("Hypervisor image failed integrity check");
+        log::error!("  computed SHA-256: {}", to_hex(&computed));
+        log::error!("  expected SHA-256: {}", to_hex(&expected));
+        return Err(LoaderError::HypervisorIntegrityCheckFailed);
+    }
+
+    log::info!("Hypervisor image SHA-256 verified ({})", to_hex(&computed));
+    Ok(true)
+}
+
+/// Reads the expected digest from the sidecar file next to the hypervisor image.
+fn read_sidecar_digest(boot_services: &BootServices, handle: Handle, image_path: &CStr16) -> Option<[u8; DIGEST_SIZE]> {
+    let path = CString16::try_from(format!("{}{}", image_path, SIDECAR_EXTENSION).as_str()).ok()?;
+
+    let bytes = match images::read_file(boot_services, handle, &path) {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            log::debug!("No sidecar digest file {} ({})", path, error);
+            return None;
+        }
+    };
+
+    // Accept both a bare digest and `sha256sum` output, which is followed by the file name.
+    let text = String::from_utf8_lossy(&bytes);
+    match text.split_whitespace().next().and_then(parse_digest) {
+        Some(digest) => {
+            log::info!("Loaded expected hypervisor digest from {}", path);
+            Some(digest)
+        }
+        None => {
+            log::warn!("Sidecar digest file {} does not contain a valid SHA-256 digest", path);
+            None
+        }
+    }
+}
+
+/// Parses a SHA-256 digest written as 64 hexadecimal digits.
+pub(crate) fn parse_digest(value: &str) -> Option<[u8; DIGEST_SIZE]> {
+    if value.len() != DIGEST_SIZE * 2 || !value.is_ascii() {
+        return None;
+    }
+
+    let mut digest = [0u8; DIGEST_SIZE];
+    for (byte, pair) in digest.iter_mut().zip(value.as_bytes().chunks_exact(2)) {
+        *byte = u8::from_str_radix(core::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+
+    Some(digest)
+}
+
+/// Formats a digest as lowercase hexadecimal digits.
+pub(crate) fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
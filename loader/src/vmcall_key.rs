@@ -0,0 +1,101 @@
+//! The hypercall key of this boot.
+//!
+//! A key built into the hypervisor image is found by anything able to scan memory or the EFI system
+//! partition. Unless `hv_vmcall_key` fixes one, the loader generates a new key every boot, passes it to the
+//! hypervisor in the handoff and stores it in the volatile `IllusionVmcallKey` variable, where the client
+//! reads it. The variable is gone after the next reset, and Windows only lets elevated processes read it.
+//! Only a fingerprint of the key is ever logged.
+
+use {
+    crate::{config::LoaderConfig, last_boot::ILLUSION_VENDOR, rng},
+    shared::PASSWORD,
+    uefi::{prelude::*, table::runtime::VariableAttributes, CStr16},
+};
+
+/// Name of the variable holding the key.
+// Safety: `to_ucs2` only encodes ASCII without NUL characters and terminates the string.
+const VMCALL_KEY_VARIABLE: &CStr16 = unsafe { CStr16::from_u16_with_nul_unchecked(&VMCALL_KEY_VARIABLE_NAME) };
+
+/// `shared::VMCALL_KEY_VARIABLE` as UCS-2.
+const VMCALL_KEY_VARIABLE_NAME: [u16; shared::VMCALL_KEY_VARIABLE.len() + 1] = shared::to_ucs2(shared::VMCALL_KEY_VARIABLE);
+
+/// Returns the key for this boot, the configured one or a freshly generated one.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+/// * `config` - The loader configuration.
+pub(crate) fn select(boot_services: &BootServices, config: &LoaderConfig) -> u64 {
+    if let Some(key) = config.hv_vmcall_key {
+        log::info!("[5/8] Using the configured hypercall key (fingerprint {:04x})", fingerprint(key));
+        return key;
+    }
+
+    let (mut key, source) = rng::random_u64(boot_services);
+    // Neither value is worth a retry of the source, mixing them once more is as good.
+    while !is_usable(key) {
+        key = rng::mix(key, PASSWORD);
+    }
+
+    log::info!("[5/8] Generated the hypercall key from {} (fingerprint {:04x})", source, fingerprint(key));
+    key
+}
+
+/// Stores the key for the client.
+///
+/// # Arguments
+///
+/// * `runtime_services` - A reference to the UEFI runtime services.
+/// * `key` - The key passed to the hypervisor.
+pub(crate) fn persist(runtime_services: &RuntimeServices, key: u64) {
+    let attributes = VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS;
+
+    match runtime_services.set_variable(VMCALL_KEY_VARIABLE, &ILLUSION_VENDOR, attributes, &key.to_le_bytes()) {
+        Ok(()) => log::debug!("[5/8] Stored the hypercall key in {}", VMCALL_KEY_VARIABLE),
+        Err(error) => log::warn!(
+            "[5/8] Failed to store the hypercall key in {} ({:?}), the client cannot reach the hypervisor",
+            VMCALL_KEY_VARIABLE,
+            error.status()
+        ),
+    }
+}
+
+/// Returns a 16-bit fingerprint that tells keys apart in logs without revealing any of their bits.
+pub(crate) fn fingerprint(key: u64) -> u16 {
+    (rng::mix(0, key) >> 48) as u16
+}
+
+/// Returns whether a generated key can be used.
+///
+/// Zero is what an unset register holds, and `PASSWORD` is the well-known key built into the image.
+fn is_usable(key: u64) -> bool {
+    key != 0 && key != PASSWORD
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, uefi::cstr16};
+
+    #[test]
+    fn variable_name_comes_from_shared() {
+        assert_eq!(VMCALL_KEY_VARIABLE, cstr16!("IllusionVmcallKey"));
+    }
+
+    #[test]
+    fn fingerprints_hide_the_key() {
+        let key = 0x8f3e_21d4_5a6b_7c90;
+        assert_eq!(fingerprint(key), fingerprint(key));
+        assert_ne!(fingerprint(key), fingerprint(key ^ 1));
+
+        for word in 0..4 {
+            assert_ne!(u64::from(fingerprint(key)), (key >> (word * 16)) & 0xffff);
+        }
+    }
+
+    #[test]
+    fn well_known_keys_are_not_used() {
+        assert!(!is_usable(0));
+        assert!(!is_usable(PASSWORD));
+        assert!(is_usable(rng::mix(0, PASSWORD)));
+    }
+}
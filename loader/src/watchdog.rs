@@ -0,0 +1,37 @@
+//! Control of the platform watchdog the firmware arms before starting the loader.
+//!
+//! The firmware resets the machine if an image doesn't exit boot services within 5 minutes. The selection
+//! menu, failure prompts and downloads may legitimately wait longer, so the watchdog is disabled while the
+//! loader runs and re-armed before control passes to an image that might hang.
+
+use uefi::prelude::*;
+
+/// Timeout the watchdog is re-armed with, the value the firmware uses for every image it starts.
+const WATCHDOG_TIMEOUT_S: usize = 5 * 60;
+
+/// Watchdog code logged by the firmware on expiry, the first one not reserved for firmware use.
+const WATCHDOG_CODE: u64 = 0x1_0000;
+
+/// Disables the watchdog before a phase that may wait for the user indefinitely.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+pub(crate) fn disable(boot_services: &BootServices) {
+    match boot_services.set_watchdog_timer(0, WATCHDOG_CODE, None) {
+        Ok(()) => log::debug!("Disabled the platform watchdog"),
+        Err(error) => log::warn!("Failed to disable the platform watchdog ({:?}), long waits may reset the machine", error.status()),
+    }
+}
+
+/// Re-arms the watchdog before starting an image.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services.
+pub(crate) fn arm(boot_services: &BootServices) {
+    match boot_services.set_watchdog_timer(WATCHDOG_TIMEOUT_S, WATCHDOG_CODE, None) {
+        Ok(()) => log::debug!("Armed the platform watchdog with {} s", WATCHDOG_TIMEOUT_S),
+        Err(error) => log::warn!("Failed to arm the platform watchdog ({:?})", error.status()),
+    }
+}
@@ -0,0 +1,197 @@
+//! Behavior of the hypervisor chosen per boot.
+//!
+//! The loader builds `HvFeatureFlags` from the `hv_features` key of `illusion.cfg` and passes them in the
+//! handoff, so one image can log verbosely on a test machine and stay quiet on another. Flags are only
+//! ever added, a hypervisor ignores the bits it doesn't know. Some flags exclude each other, see `EXCLUSIVE`.
+
+use core::ops::BitOr;
+
+/// A set of hypervisor features.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HvFeatureFlags(u64);
+
+impl HvFeatureFlags {
+    /// Log every VM exit with the process it interrupted.
+    pub const LOG_VMEXITS: Self = Self(1 << 0);
+
+    /// Clear the hypervisor present bit of CPUID leaf 1.
+    pub const HIDE_CPUID_LEAF: Self = Self(1 << 1);
+
+    /// Hide the time spent in the hypervisor from the time stamp counter the guest reads.
+    pub const RDTSC_COMPENSATION: Self = Self(1 << 2);
+
+    /// Accept hypercalls that install or remove EPT hooks.
+    pub const EPT_HOOKS: Self = Self(1 << 3);
+
+    /// The image is expected to use Intel VT-x.
+    pub const PREFER_VMX: Self = Self(1 << 4);
+
+    /// The image is expected to use AMD SVM.
+    pub const PREFER_SVM: Self = Self(1 << 5);
+
+    /// Clear the VMX bit of CPUID leaf 1, so nested hypervisors don't try to start.
+    pub const HIDE_VMX: Self = Self(1 << 6);
+
+    /// Report Illusion in the hypervisor CPUID leaves while the hypervisor present bit is visible.
+    pub const FAKE_HYPERVISOR_LEAVES: Self = Self(1 << 7);
+
+    /// Let a guest-resident #VE handler switch the views of EPT hooks with VMFUNC where the processor supports it.
+    pub const VE_HOOK_SWAPS: Self = Self(1 << 8);
+
+    /// Exit on HLT and enter the guest in the HLT activity state, so its idle time can be measured.
+    pub const HLT_EXITING: Self = Self(1 << 9);
+
+    /// Report VMX enabled with a reduced capability set in IA32_FEATURE_CONTROL and the VMX capability MSRs,
+    /// unless `HIDE_VMX` hides it from CPUID.
+    pub const EXPOSE_NESTED_VMX: Self = Self(1 << 10);
+
+    /// Masquerade as Hyper-V in the hypervisor CPUID leaves, the synthetic MSRs and hypercalls, see `crate::hyperv`.
+    pub const HYPERV_MASQUERADE: Self = Self(1 << 11);
+
+    /// Cache the guest page walks of the hypervisor per processor, which makes INVLPG, INVPCID and MOV to CR3 exit.
+    pub const TRANSLATION_CACHE: Self = Self(1 << 12);
+
+    /// What the hypervisor does without a handoff.
+    pub const DEFAULT: Self = Self(Self::LOG_VMEXITS.0 | Self::HIDE_CPUID_LEAF.0 | Self::EPT_HOOKS.0);
+
+    /// The names the flags go by in `illusion.cfg` and in logs.
+    pub const NAMES: [(&'static str, Self); 13] = [
+        ("log_vmexits", Self::LOG_VMEXITS),
+        ("hide_cpuid_leaf", Self::HIDE_CPUID_LEAF),
+        ("rdtsc_compensation", Self::RDTSC_COMPENSATION),
+        ("ept_hooks", Self::EPT_HOOKS),
+        ("prefer_vmx", Self::PREFER_VMX),
+        ("prefer_svm", Self::PREFER_SVM),
+        ("hide_vmx", Self::HIDE_VMX),
+        ("fake_hypervisor_leaves", Self::FAKE_HYPERVISOR_LEAVES),
+        ("ve_hook_swaps", Self::VE_HOOK_SWAPS),
+        ("hlt_exiting", Self::HLT_EXITING),
+        ("expose_nested_vmx", Self::EXPOSE_NESTED_VMX),
+        ("hyperv_masquerade", Self::HYPERV_MASQUERADE),
+        ("translation_cache", Self::TRANSLATION_CACHE),
+    ];
+
+    /// The pairs of flags that can't be enabled together, as both decide what the hypervisor CPUID leaves report.
+    ///
+    /// The loader clears the other flag of a pair when one is enabled. A hypervisor given both prefers hiding.
+    pub const EXCLUSIVE: [(Self, Self); 2] = [
+        (Self::HIDE_CPUID_LEAF, Self::HYPERV_MASQUERADE),
+        (Self::FAKE_HYPERVISOR_LEAVES, Self::HYPERV_MASQUERADE),
+    ];
+
+    /// Returns the empty set.
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Returns the set encoded in `bits`, including bits without a name.
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    /// Returns the bits encoding the set.
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// Returns whether all flags of `other` are set.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Sets the flags of `other`.
+    pub fn insert(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+
+    /// Clears the flags of `other`.
+    pub fn remove(&mut self, other: Self) {
+        self.0 &= !other.0;
+    }
+
+    /// Returns the flags that can't be enabled together with any flag of `self`, see `EXCLUSIVE`.
+    pub fn exclusive_with(self) -> Self {
+        Self::EXCLUSIVE.iter().fold(Self::empty(), |excluded, &(first, second)| {
+            if self.contains(first) {
+                excluded | second
+            } else if self.contains(second) {
+                excluded | first
+            } else {
+                excluded
+            }
+        })
+    }
+
+    /// Returns the flag called `name`, `None` for unknown names.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::NAMES.iter().find(|(candidate, _)| *candidate == name).map(|(_, flag)| *flag)
+    }
+
+    /// Returns the names of the flags that are set, in the order of `NAMES`.
+    pub fn names(self) -> impl Iterator<Item = &'static str> {
+        Self::NAMES
+            .into_iter()
+            .filter(move |(_, flag)| self.contains(*flag))
+            .map(|(name, _)| name)
+    }
+
+    /// Returns the bits that are set but have no name.
+    pub fn unknown_bits(self) -> u64 {
+        Self::NAMES.iter().fold(self.0, |bits, (_, flag)| bits & !flag.0)
+    }
+}
+
+impl Default for HvFeatureFlags {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl BitOr for HvFeatureFlags {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_match_the_flags() {
+        let all = HvFeatureFlags::NAMES.iter().fold(HvFeatureFlags::empty(), |all, (_, flag)| all | *flag);
+        assert_eq!(all.bits(), 0x1fff);
+        assert_eq!(all.unknown_bits(), 0);
+
+        for (name, flag) in HvFeatureFlags::NAMES {
+            assert_eq!(HvFeatureFlags::from_name(name), Some(flag));
+            assert_eq!(flag.bits().count_ones(), 1);
+        }
+        assert_eq!(HvFeatureFlags::from_name("stealth"), None);
+    }
+
+    #[test]
+    fn sets_list_their_names() {
+        let mut flags = HvFeatureFlags::DEFAULT;
+        flags.remove(HvFeatureFlags::LOG_VMEXITS);
+        flags.insert(HvFeatureFlags::RDTSC_COMPENSATION);
+
+        let mut names = flags.names();
+        assert_eq!(names.next(), Some("hide_cpuid_leaf"));
+        assert_eq!(names.next(), Some("rdtsc_compensation"));
+        assert_eq!(names.next(), Some("ept_hooks"));
+        assert_eq!(names.next(), None);
+
+        assert_eq!(HvFeatureFlags::from_bits(1 << 40 | 1).unknown_bits(), 1 << 40);
+    }
+
+    #[test]
+    fn masquerading_excludes_the_other_leaves() {
+        assert_eq!(HvFeatureFlags::HYPERV_MASQUERADE.exclusive_with(), HvFeatureFlags::HIDE_CPUID_LEAF | HvFeatureFlags::FAKE_HYPERVISOR_LEAVES);
+        assert_eq!(HvFeatureFlags::HIDE_CPUID_LEAF.exclusive_with(), HvFeatureFlags::HYPERV_MASQUERADE);
+        assert_eq!(HvFeatureFlags::LOG_VMEXITS.exclusive_with(), HvFeatureFlags::empty());
+    }
+}
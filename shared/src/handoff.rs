@@ -0,0 +1,438 @@
+//! The handoff from the loader to the hypervisor.
+//!
+//! The loader allocates a `LoaderHandoff` from runtime services data and installs it as the protocol
+//! `HANDOFF_PROTOCOL_GUID` on the image handle of the hypervisor before starting it, so the hypervisor
+//! doesn't have to rediscover what the loader already knows. The layout is part of the ABI between the two
+//! images, which may come from different builds: fields are only ever appended, and the hypervisor checks
+//! `magic`, `size` and `version` before reading anything else.
+//!
+//! The handoff also carries one answer back: before its entry point returns, the hypervisor may store an
+//! `HvResult` asking the loader to boot without it or to stop the boot. A hypervisor that doesn't know the
+//! field leaves it at `RESULT_CONTINUE`.
+
+use crate::features::HvFeatureFlags;
+
+/// GUID of the protocol the handoff is installed as on the image handle of the hypervisor.
+pub const HANDOFF_PROTOCOL_GUID: &str = "8a4e2f61-3c7b-4d95-b0e8-1f6a9c2d7e53";
+
+/// Value of `LoaderHandoff::magic` ("ILHNDOFF" in little-endian order).
+pub const HANDOFF_MAGIC: u64 = u64::from_le_bytes(*b"ILHNDOFF");
+
+/// Version of the layout defined here.
+pub const HANDOFF_VERSION: u32 = 1;
+
+/// Size of the layout defined here.
+pub const HANDOFF_SIZE: usize = core::mem::size_of::<LoaderHandoff>();
+
+/// Size of the fixed, NUL padded strings describing the loader build.
+pub const BUILD_STRING_SIZE: usize = 16;
+
+/// Space for the device path of the boot manager, longer paths are left out.
+pub const MAX_DEVICE_PATH_SIZE: usize = 512;
+
+/// The image hash was checked against a configured digest or a digest file.
+pub const FLAG_IMAGE_VERIFIED: u64 = 1 << 0;
+
+/// The image was downloaded instead of read from a local volume.
+pub const FLAG_NETWORK_IMAGE: u64 = 1 << 1;
+
+/// The image was measured into the TPM.
+pub const FLAG_MEASURED: u64 = 1 << 2;
+
+/// The firmware enforces Secure Boot.
+pub const FLAG_SECURE_BOOT: u64 = 1 << 3;
+
+/// The loader is a debug build.
+pub const FLAG_DEBUG_BUILD: u64 = 1 << 4;
+
+/// `chainload_device_path` holds the boot manager the loader starts next.
+///
+/// The hypervisor is started before the boot manager is selected, so the loader sets this flag later,
+/// right before it starts the boot manager.
+pub const FLAG_CHAINLOAD_TARGET: u64 = 1 << 5;
+
+/// `vmcall_key` holds the hypercall key the loader generated for this boot.
+pub const FLAG_VMCALL_KEY: u64 = 1 << 6;
+
+/// `HvResult::Continue`.
+pub const RESULT_CONTINUE: u32 = 0;
+
+/// `HvResult::ContinueWithoutHv`.
+pub const RESULT_CONTINUE_WITHOUT_HV: u32 = 1;
+
+/// `HvResult::AbortBoot`.
+pub const RESULT_ABORT_BOOT: u32 = 2;
+
+/// `HvResult::ProcessorsFailed`.
+pub const RESULT_PROCESSORS_FAILED: u32 = 3;
+
+/// No reason given.
+pub const ABORT_REASON_UNSPECIFIED: u32 = 0;
+
+/// The processor is not an Intel processor.
+pub const ABORT_REASON_UNSUPPORTED_CPU: u32 = 1;
+
+/// The processor doesn't support VMX.
+pub const ABORT_REASON_NO_VMX: u32 = 2;
+
+/// The processor lacks the EPT and VPID features the hypervisor needs.
+pub const ABORT_REASON_NO_EPT: u32 = 3;
+
+/// The processor doesn't support MTRRs.
+pub const ABORT_REASON_NO_MTRR: u32 = 4;
+
+/// The firmware locked IA32_FEATURE_CONTROL with VMX outside SMX disabled.
+pub const ABORT_REASON_FEATURE_CONTROL_LOCKED: u32 = 5;
+
+/// What the hypervisor asks the loader to do once its entry point returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HvResult {
+    /// Go on as if the hypervisor had not answered, the default.
+    Continue,
+
+    /// The hypervisor is not running, boot Windows without it regardless of the failure policy.
+    ContinueWithoutHv,
+
+    /// Don't boot Windows, for the `ABORT_REASON_*` code.
+    AbortBoot(u32),
+
+    /// The hypervisor runs, but this many processors failed to enter VMX operation and run bare metal.
+    ProcessorsFailed(u32),
+}
+
+/// What the loader passes on to the hypervisor.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoaderHandoff {
+    /// `HANDOFF_MAGIC`.
+    pub magic: u64,
+
+    /// The size of the structure written by the loader, at least `HANDOFF_SIZE`.
+    pub size: u32,
+
+    /// `HANDOFF_VERSION` of the loader.
+    pub version: u32,
+
+    /// `FLAG_*` bits.
+    pub flags: u64,
+
+    /// The crate version of the loader.
+    pub loader_version: [u8; BUILD_STRING_SIZE],
+
+    /// The short git hash the loader was built from.
+    pub loader_git_hash: [u8; BUILD_STRING_SIZE],
+
+    /// The I/O port of the UART the loader logs to, `0` if serial logging is disabled.
+    pub serial_port: u16,
+
+    /// Zero, aligns `serial_baud_rate`.
+    pub reserved0: u16,
+
+    /// The baud rate the UART is programmed for.
+    pub serial_baud_rate: u32,
+
+    /// The SHA-256 digest of the hypervisor image as stored on disk or downloaded.
+    pub image_sha256: [u8; 32],
+
+    /// The number of valid bytes in `chainload_device_path`.
+    pub chainload_device_path_size: u32,
+
+    /// Zero, aligns `chainload_device_path`.
+    pub reserved1: u32,
+
+    /// The device path of the boot manager, valid with `FLAG_CHAINLOAD_TARGET`.
+    pub chainload_device_path: [u8; MAX_DEVICE_PATH_SIZE],
+
+    /// The value RAX has to hold for a CPUID to be taken as a hypercall, valid with `FLAG_VMCALL_KEY`.
+    ///
+    /// It replaces the key the hypervisor image was built or patched with.
+    pub vmcall_key: u64,
+
+    /// The `HvFeatureFlags` bits chosen for this boot.
+    pub features: u64,
+
+    /// The physical address of the `shared::logring` ring the hypervisor copies its log to, `0` for none.
+    pub log_ring_address: u64,
+
+    /// The size of the ring memory in bytes.
+    pub log_ring_size: u64,
+
+    /// The `RESULT_*` answer of the hypervisor, written before its entry point returns.
+    pub result: u32,
+
+    /// The `ABORT_REASON_*` code of `RESULT_ABORT_BOOT`, the processor count of `RESULT_PROCESSORS_FAILED`.
+    pub result_reason: u32,
+}
+
+/// Why a handoff is rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandoffError {
+    /// The structure doesn't start with `HANDOFF_MAGIC`.
+    BadMagic(u64),
+
+    /// The loader writes a different layout.
+    UnsupportedVersion(u32),
+
+    /// The structure is smaller than the layout defined here.
+    TooSmall(u32),
+}
+
+impl LoaderHandoff {
+    /// Creates an empty handoff of the current layout.
+    pub const fn new() -> Self {
+        Self {
+            magic: HANDOFF_MAGIC,
+            size: HANDOFF_SIZE as u32,
+            version: HANDOFF_VERSION,
+            flags: 0,
+            loader_version: [0; BUILD_STRING_SIZE],
+            loader_git_hash: [0; BUILD_STRING_SIZE],
+            serial_port: 0,
+            reserved0: 0,
+            serial_baud_rate: 0,
+            image_sha256: [0; 32],
+            chainload_device_path_size: 0,
+            reserved1: 0,
+            chainload_device_path: [0; MAX_DEVICE_PATH_SIZE],
+            vmcall_key: 0,
+            features: HvFeatureFlags::DEFAULT.bits(),
+            log_ring_address: 0,
+            log_ring_size: 0,
+            result: RESULT_CONTINUE,
+            result_reason: ABORT_REASON_UNSPECIFIED,
+        }
+    }
+
+    /// Checks the handoff at `ptr` before anything but its header is read.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be aligned and point to at least 16 readable bytes, and to `size` bytes the caller may
+    /// access for `'a` if the header matches.
+    pub unsafe fn from_ptr<'a>(ptr: *mut LoaderHandoff) -> Result<&'a mut LoaderHandoff, HandoffError> {
+        let magic = core::ptr::addr_of!((*ptr).magic).read();
+        let size = core::ptr::addr_of!((*ptr).size).read();
+        let version = core::ptr::addr_of!((*ptr).version).read();
+
+        check_header(magic, size, version)?;
+        Ok(&mut *ptr)
+    }
+
+    /// Checks that the handoff has the layout defined here.
+    pub fn check(&self) -> Result<(), HandoffError> {
+        check_header(self.magic, self.size, self.version)
+    }
+
+    /// Stores the device path of the boot manager and sets `FLAG_CHAINLOAD_TARGET`.
+    ///
+    /// Returns `false` and leaves the flag clear if the device path doesn't fit.
+    pub fn set_chainload_device_path(&mut self, device_path: &[u8]) -> bool {
+        let Some(target) = self.chainload_device_path.get_mut(..device_path.len()) else {
+            self.flags &= !FLAG_CHAINLOAD_TARGET;
+            self.chainload_device_path_size = 0;
+            return false;
+        };
+
+        target.copy_from_slice(device_path);
+        self.chainload_device_path_size = device_path.len() as u32;
+        self.flags |= FLAG_CHAINLOAD_TARGET;
+        true
+    }
+
+    /// Stores the hypercall key and sets `FLAG_VMCALL_KEY`.
+    pub fn set_vmcall_key(&mut self, key: u64) {
+        self.vmcall_key = key;
+        self.flags |= FLAG_VMCALL_KEY;
+    }
+
+    /// Returns the hypercall key, `None` if the loader didn't generate one.
+    pub fn vmcall_key(&self) -> Option<u64> {
+        (self.flags & FLAG_VMCALL_KEY != 0).then_some(self.vmcall_key)
+    }
+
+    /// Returns the features chosen for this boot.
+    pub fn features(&self) -> HvFeatureFlags {
+        HvFeatureFlags::from_bits(self.features)
+    }
+
+    /// Stores the address and size of the log ring, `0` for both removes it.
+    pub fn set_log_ring(&mut self, address: u64, size: usize) {
+        self.log_ring_address = address;
+        self.log_ring_size = size as u64;
+    }
+
+    /// Returns the address and size of the log ring, `None` if the loader didn't pass one.
+    pub fn log_ring(&self) -> Option<(u64, usize)> {
+        (self.log_ring_address != 0 && self.log_ring_size != 0).then_some((self.log_ring_address, self.log_ring_size as usize))
+    }
+
+    /// Stores the answer of the hypervisor.
+    pub fn set_result(&mut self, result: HvResult) {
+        (self.result, self.result_reason) = match result {
+            HvResult::Continue => (RESULT_CONTINUE, ABORT_REASON_UNSPECIFIED),
+            HvResult::ContinueWithoutHv => (RESULT_CONTINUE_WITHOUT_HV, ABORT_REASON_UNSPECIFIED),
+            HvResult::AbortBoot(reason) => (RESULT_ABORT_BOOT, reason),
+            HvResult::ProcessorsFailed(count) => (RESULT_PROCESSORS_FAILED, count),
+        };
+    }
+
+    /// Returns the answer of the hypervisor, `HvResult::Continue` for values a newer hypervisor may add.
+    pub fn result(&self) -> HvResult {
+        match self.result {
+            RESULT_CONTINUE_WITHOUT_HV => HvResult::ContinueWithoutHv,
+            RESULT_ABORT_BOOT => HvResult::AbortBoot(self.result_reason),
+            RESULT_PROCESSORS_FAILED => HvResult::ProcessorsFailed(self.result_reason),
+            _ => HvResult::Continue,
+        }
+    }
+
+    /// Returns the device path of the boot manager, `None` until the loader selected one.
+    pub fn chainload_device_path(&self) -> Option<&[u8]> {
+        match self.flags & FLAG_CHAINLOAD_TARGET {
+            0 => None,
+            _ => self.chainload_device_path.get(..self.chainload_device_path_size as usize),
+        }
+    }
+}
+
+impl Default for LoaderHandoff {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Checks the fields identifying the layout of a handoff.
+fn check_header(magic: u64, size: u32, version: u32) -> Result<(), HandoffError> {
+    if magic != HANDOFF_MAGIC {
+        return Err(HandoffError::BadMagic(magic));
+    }
+
+    if version != HANDOFF_VERSION {
+        return Err(HandoffError::UnsupportedVersion(version));
+    }
+
+    if (size as usize) < HANDOFF_SIZE {
+        return Err(HandoffError::TooSmall(size));
+    }
+
+    Ok(())
+}
+
+/// Copies a string into a fixed, NUL padded field, truncating it if it doesn't fit.
+pub fn to_fixed_string<const N: usize>(text: &str) -> [u8; N] {
+    let mut field = [0u8; N];
+    let length = text.len().min(N);
+    field[..length].copy_from_slice(&text.as_bytes()[..length]);
+    field
+}
+
+/// Returns the string stored in a fixed, NUL padded field, `"?"` if it is not valid UTF-8.
+pub fn from_fixed_string(field: &[u8]) -> &str {
+    let length = field.iter().position(|&byte| byte == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..length]).unwrap_or("?")
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, core::mem::offset_of};
+
+    #[test]
+    fn layout_is_stable() {
+        assert_eq!(HANDOFF_SIZE, 656);
+        assert_eq!(core::mem::align_of::<LoaderHandoff>(), 8);
+        assert_eq!(offset_of!(LoaderHandoff, magic), 0);
+        assert_eq!(offset_of!(LoaderHandoff, size), 8);
+        assert_eq!(offset_of!(LoaderHandoff, version), 12);
+        assert_eq!(offset_of!(LoaderHandoff, flags), 16);
+        assert_eq!(offset_of!(LoaderHandoff, loader_version), 24);
+        assert_eq!(offset_of!(LoaderHandoff, loader_git_hash), 40);
+        assert_eq!(offset_of!(LoaderHandoff, serial_port), 56);
+        assert_eq!(offset_of!(LoaderHandoff, serial_baud_rate), 60);
+        assert_eq!(offset_of!(LoaderHandoff, image_sha256), 64);
+        assert_eq!(offset_of!(LoaderHandoff, chainload_device_path_size), 96);
+        assert_eq!(offset_of!(LoaderHandoff, chainload_device_path), 104);
+        assert_eq!(offset_of!(LoaderHandoff, vmcall_key), 616);
+        assert_eq!(offset_of!(LoaderHandoff, features), 624);
+        assert_eq!(offset_of!(LoaderHandoff, log_ring_address), 632);
+        assert_eq!(offset_of!(LoaderHandoff, log_ring_size), 640);
+        assert_eq!(offset_of!(LoaderHandoff, result), 648);
+        assert_eq!(offset_of!(LoaderHandoff, result_reason), 652);
+        assert_eq!(LoaderHandoff::new().log_ring(), None);
+        assert_eq!(LoaderHandoff::new().features(), HvFeatureFlags::DEFAULT);
+    }
+
+    #[test]
+    fn mismatched_layouts_are_rejected() {
+        assert_eq!(LoaderHandoff::new().check(), Ok(()));
+        assert_eq!(&HANDOFF_MAGIC.to_le_bytes(), b"ILHNDOFF");
+
+        let mut handoff = LoaderHandoff::new();
+        handoff.magic = 0;
+        assert_eq!(handoff.check(), Err(HandoffError::BadMagic(0)));
+
+        let mut handoff = LoaderHandoff::new();
+        handoff.version = HANDOFF_VERSION + 1;
+        assert_eq!(handoff.check(), Err(HandoffError::UnsupportedVersion(HANDOFF_VERSION + 1)));
+
+        let mut handoff = LoaderHandoff::new();
+        handoff.size = 64;
+        assert_eq!(handoff.check(), Err(HandoffError::TooSmall(64)));
+
+        let mut handoff = LoaderHandoff::new();
+        assert!(unsafe { LoaderHandoff::from_ptr(&mut handoff) }.is_ok());
+        handoff.magic = u64::from_le_bytes(*b"ILHNDOF2");
+        assert!(matches!(unsafe { LoaderHandoff::from_ptr(&mut handoff) }, Err(HandoffError::BadMagic(_))));
+    }
+
+    #[test]
+    fn chainload_device_path_is_bounded() {
+        let mut handoff = LoaderHandoff::new();
+        assert_eq!(handoff.chainload_device_path(), None);
+
+        assert!(handoff.set_chainload_device_path(&[4, 4, 8, 0, 0x7f, 0xff, 4, 0]));
+        assert_eq!(handoff.chainload_device_path(), Some(&[4, 4, 8, 0, 0x7f, 0xff, 4, 0][..]));
+
+        assert!(!handoff.set_chainload_device_path(&[0; MAX_DEVICE_PATH_SIZE + 1]));
+        assert_eq!(handoff.chainload_device_path(), None);
+    }
+
+    #[test]
+    fn vmcall_key_is_only_valid_with_its_flag() {
+        let mut handoff = LoaderHandoff::new();
+        assert_eq!(handoff.vmcall_key(), None);
+
+        handoff.set_vmcall_key(0x8f3e_21d4_5a6b_7c90);
+        assert_eq!(handoff.vmcall_key(), Some(0x8f3e_21d4_5a6b_7c90));
+        assert_eq!(handoff.flags, FLAG_VMCALL_KEY);
+    }
+
+    #[test]
+    fn results_round_trip() {
+        let mut handoff = LoaderHandoff::new();
+        assert_eq!(handoff.result(), HvResult::Continue);
+
+        handoff.set_result(HvResult::AbortBoot(ABORT_REASON_FEATURE_CONTROL_LOCKED));
+        assert_eq!((handoff.result, handoff.result_reason), (RESULT_ABORT_BOOT, 5));
+        assert_eq!(handoff.result(), HvResult::AbortBoot(ABORT_REASON_FEATURE_CONTROL_LOCKED));
+
+        handoff.set_result(HvResult::ContinueWithoutHv);
+        assert_eq!(handoff.result(), HvResult::ContinueWithoutHv);
+
+        handoff.set_result(HvResult::ProcessorsFailed(3));
+        assert_eq!((handoff.result, handoff.result_reason), (RESULT_PROCESSORS_FAILED, 3));
+        assert_eq!(handoff.result(), HvResult::ProcessorsFailed(3));
+
+        handoff.result = 7;
+        assert_eq!(handoff.result(), HvResult::Continue);
+    }
+
+    #[test]
+    fn fixed_strings_round_trip() {
+        let field: [u8; BUILD_STRING_SIZE] = to_fixed_string("0.1.0");
+        assert_eq!(&field[..6], b"0.1.0\0");
+        assert_eq!(from_fixed_string(&field), "0.1.0");
+
+        let truncated: [u8; 4] = to_fixed_string("abcdef");
+        assert_eq!(from_fixed_string(&truncated), "abcd");
+    }
+}
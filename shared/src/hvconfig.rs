@@ -0,0 +1,223 @@
+//! The configuration blob embedded in the hypervisor image.
+//!
+//! The hypervisor image reserves the PE section `CONFIG_SECTION` holding an `HvConfig` with the values it
+//! was built with. When the loader loads the image from a buffer, it can overwrite the blob with values
+//! from `illusion.cfg` before `load_image`, which changes the behavior of the hypervisor without rebuilding
+//! it. Both sides check `magic`, `size` and `version` before using the blob.
+
+use crate::PASSWORD;
+
+/// Name of the PE section holding the blob.
+pub const CONFIG_SECTION: &str = ".ilcfg";
+
+/// Value of `HvConfig::magic` ("ILCONFIG" in little-endian order).
+pub const CONFIG_MAGIC: u64 = u64::from_le_bytes(*b"ILCONFIG");
+
+/// Version of the layout defined here.
+pub const CONFIG_VERSION: u32 = 1;
+
+/// Size of the layout defined here.
+pub const CONFIG_SIZE: usize = core::mem::size_of::<HvConfig>();
+
+/// Log every EPT hook the guest runs into, not only hooks being installed and removed.
+pub const CONFIG_FLAG_LOG_EPT_HOOKS: u64 = 1 << 0;
+
+/// Invalidate the TLB entries of all VPIDs whenever any translation may be stale, for debugging.
+pub const CONFIG_FLAG_FULL_TLB_FLUSHES: u64 = 1 << 1;
+
+/// The blob was written by the loader rather than by the build.
+pub const CONFIG_FLAG_PATCHED: u64 = 1 << 63;
+
+/// `HvConfig::tsc_skew_limit` of blobs that leave it at zero.
+pub const DEFAULT_TSC_SKEW_LIMIT: u32 = 100_000_000;
+
+/// `HvConfig::log_level` that disables logging. The levels are numbered like `log::LevelFilter`.
+pub const LOG_LEVEL_OFF: u32 = 0;
+
+/// `HvConfig::log_level` for errors only.
+pub const LOG_LEVEL_ERROR: u32 = 1;
+
+/// `HvConfig::log_level` for warnings and errors.
+pub const LOG_LEVEL_WARN: u32 = 2;
+
+/// `HvConfig::log_level` for informational messages and above.
+pub const LOG_LEVEL_INFO: u32 = 3;
+
+/// `HvConfig::log_level` for debug messages and above.
+pub const LOG_LEVEL_DEBUG: u32 = 4;
+
+/// `HvConfig::log_level` for everything.
+pub const LOG_LEVEL_TRACE: u32 = 5;
+
+/// Settings of the hypervisor that can be changed without rebuilding the image.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HvConfig {
+    /// `CONFIG_MAGIC`.
+    pub magic: u64,
+
+    /// `CONFIG_SIZE` of the build that wrote the blob.
+    pub size: u32,
+
+    /// `CONFIG_VERSION` of the build that wrote the blob.
+    pub version: u32,
+
+    /// `CONFIG_FLAG_*` bits.
+    pub flags: u64,
+
+    /// The value RAX has to hold for a CPUID to be taken as a hypercall.
+    pub vmcall_key: u64,
+
+    /// The highest `LOG_LEVEL_*` that is logged.
+    pub log_level: u32,
+
+    /// The most cycles RDTSC compensation may hold the time stamp counter of a processor back, which bounds
+    /// how far processors drift apart. Zero, as in blobs written before the field existed, stands for
+    /// `DEFAULT_TSC_SKEW_LIMIT`.
+    pub tsc_skew_limit: u32,
+}
+
+/// Why a blob is rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// There are fewer bytes than the layout defined here.
+    TooSmall(usize),
+
+    /// The blob doesn't start with `CONFIG_MAGIC`.
+    BadMagic(u64),
+
+    /// The blob has a different layout.
+    UnsupportedVersion(u32),
+
+    /// The blob claims a different size than the layout defined here.
+    SizeMismatch(u32),
+}
+
+impl HvConfig {
+    /// The values the hypervisor is built with.
+    pub const DEFAULT: HvConfig = HvConfig {
+        magic: CONFIG_MAGIC,
+        size: CONFIG_SIZE as u32,
+        version: CONFIG_VERSION,
+        flags: 0,
+        vmcall_key: PASSWORD,
+        log_level: LOG_LEVEL_DEBUG,
+        tsc_skew_limit: 0,
+    };
+
+    /// Checks that the blob has the layout defined here.
+    pub fn check(&self) -> Result<(), ConfigError> {
+        if self.magic != CONFIG_MAGIC {
+            return Err(ConfigError::BadMagic(self.magic));
+        }
+
+        if self.version != CONFIG_VERSION {
+            return Err(ConfigError::UnsupportedVersion(self.version));
+        }
+
+        if self.size as usize != CONFIG_SIZE {
+            return Err(ConfigError::SizeMismatch(self.size));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the skew limit in cycles, with zero replaced by `DEFAULT_TSC_SKEW_LIMIT`.
+    pub fn tsc_skew_limit(&self) -> u32 {
+        match self.tsc_skew_limit {
+            0 => DEFAULT_TSC_SKEW_LIMIT,
+            limit => limit,
+        }
+    }
+
+    /// Encodes the blob in little endian byte order.
+    pub fn to_bytes(&self) -> [u8; CONFIG_SIZE] {
+        let mut bytes = [0u8; CONFIG_SIZE];
+        bytes[0..8].copy_from_slice(&self.magic.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.size.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.version.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.vmcall_key.to_le_bytes());
+        bytes[32..36].copy_from_slice(&self.log_level.to_le_bytes());
+        bytes[36..40].copy_from_slice(&self.tsc_skew_limit.to_le_bytes());
+        bytes
+    }
+
+    /// Decodes and checks a blob.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ConfigError> {
+        if bytes.len() < CONFIG_SIZE {
+            return Err(ConfigError::TooSmall(bytes.len()));
+        }
+
+        let u32_at = |offset: usize| u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]);
+        let u64_at = |offset: usize| u64::from(u32_at(offset)) | u64::from(u32_at(offset + 4)) << 32;
+
+        let config = Self {
+            magic: u64_at(0),
+            size: u32_at(8),
+            version: u32_at(12),
+            flags: u64_at(16),
+            vmcall_key: u64_at(24),
+            log_level: u32_at(32),
+            tsc_skew_limit: u32_at(36),
+        };
+
+        config.check()?;
+        Ok(config)
+    }
+}
+
+impl Default for HvConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout_is_stable() {
+        assert_eq!(CONFIG_SIZE, 40);
+        assert_eq!(core::mem::offset_of!(HvConfig, vmcall_key), 24);
+        assert_eq!(core::mem::offset_of!(HvConfig, log_level), 32);
+        assert_eq!(&HvConfig::DEFAULT.to_bytes()[..8], b"ILCONFIG");
+    }
+
+    #[test]
+    fn blobs_round_trip() {
+        let config = HvConfig {
+            flags: CONFIG_FLAG_LOG_EPT_HOOKS | CONFIG_FLAG_PATCHED,
+            vmcall_key: 0x1122_3344_5566_7788,
+            log_level: LOG_LEVEL_TRACE,
+            tsc_skew_limit: 5_000,
+            ..HvConfig::DEFAULT
+        };
+        assert_eq!(HvConfig::from_bytes(&config.to_bytes()), Ok(config));
+        assert_eq!((config.tsc_skew_limit(), HvConfig::DEFAULT.tsc_skew_limit()), (5_000, DEFAULT_TSC_SKEW_LIMIT));
+
+        let mut longer = [0u8; CONFIG_SIZE + 24];
+        longer[..CONFIG_SIZE].copy_from_slice(&config.to_bytes());
+        assert_eq!(HvConfig::from_bytes(&longer), Ok(config));
+    }
+
+    #[test]
+    fn mismatched_blobs_are_rejected() {
+        let bytes = HvConfig::DEFAULT.to_bytes();
+        assert_eq!(HvConfig::from_bytes(&bytes[..CONFIG_SIZE - 1]), Err(ConfigError::TooSmall(CONFIG_SIZE - 1)));
+        assert_eq!(HvConfig::from_bytes(&[0u8; CONFIG_SIZE]), Err(ConfigError::BadMagic(0)));
+
+        let newer = HvConfig {
+            version: CONFIG_VERSION + 1,
+            ..HvConfig::DEFAULT
+        };
+        assert_eq!(HvConfig::from_bytes(&newer.to_bytes()), Err(ConfigError::UnsupportedVersion(CONFIG_VERSION + 1)));
+
+        let larger = HvConfig {
+            size: 48,
+            ..HvConfig::DEFAULT
+        };
+        assert_eq!(HvConfig::from_bytes(&larger.to_bytes()), Err(ConfigError::SizeMismatch(48)));
+    }
+}
@@ -0,0 +1,157 @@
+//! The status protocol the hypervisor installs for EFI applications started after it.
+//!
+//! Diagnostic tools running in boot services can't issue hypercalls without knowing the key of this boot,
+//! so the hypervisor also installs an `HvStatusProtocol` on a handle of its own. The functions only read
+//! counters the hypervisor updates atomically: they neither allocate, lock nor log, and may be called from
+//! any TPL. The layout is versioned like the handoff, fields are only ever appended and `revision` tells a
+//! caller which functions exist.
+
+use crate::handoff::BUILD_STRING_SIZE;
+
+/// GUID of the status protocol.
+pub const STATUS_PROTOCOL_GUID: &str = "c3f1a7d2-6e4b-4a08-9d5c-7b2e8f41a093";
+
+/// Revision of the layout defined here.
+pub const STATUS_PROTOCOL_REVISION: u32 = 2;
+
+/// The size of the protocol of revision 1, which had no `get_hook_swap_status`.
+pub const STATUS_PROTOCOL_V1_SIZE: u32 = 32;
+
+/// Number of processors the hypervisor keeps a status for.
+pub const MAX_PROCESSORS: usize = 256;
+
+/// `EFI_SUCCESS`.
+pub const STATUS_SUCCESS: usize = 0;
+
+/// `EFI_INVALID_PARAMETER`, returned for a null output pointer.
+pub const STATUS_INVALID_PARAMETER: usize = (1 << (usize::BITS - 1)) | 2;
+
+/// `EFI_NOT_FOUND`, returned for a processor index no processor reached.
+pub const STATUS_NOT_FOUND: usize = (1 << (usize::BITS - 1)) | 14;
+
+/// The processor started entering VMX operation, but never returned to the guest.
+pub const CPU_STATE_STARTING: u32 = 1;
+
+/// The processor runs as a guest of the hypervisor.
+pub const CPU_STATE_VIRTUALIZED: u32 = 2;
+
+/// The processor gave up entering VMX operation and returned to the guest bare metal.
+pub const CPU_STATE_FAILED: u32 = 3;
+
+/// The views of EPT hooks are switched in VM exits.
+pub const HOOK_SWAP_VM_EXIT: u32 = 1;
+
+/// The views of EPT hooks are switched by a guest-resident #VE handler with VMFUNC, without VM exits.
+pub const HOOK_SWAP_VIRTUALIZATION_EXCEPTION: u32 = 2;
+
+/// The build of the hypervisor.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HvVersion {
+    /// The crate version, NUL padded.
+    pub version: [u8; BUILD_STRING_SIZE],
+
+    /// The `HvFeatureFlags` bits enabled for this boot.
+    pub features: u64,
+}
+
+/// The status of one processor.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HvCpuStatus {
+    /// The initial APIC ID of the processor.
+    pub apic_id: u32,
+
+    /// `CPU_STATE_*`.
+    pub state: u32,
+}
+
+/// Counters of the hypervisor since it started.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HvStats {
+    /// The number of processors that started entering VMX operation.
+    pub processors: u32,
+
+    /// The number of processors running as a guest.
+    pub virtualized: u32,
+
+    /// The VM exits handled on all processors.
+    pub vm_exits: u64,
+
+    /// The hypercalls handled on all processors.
+    pub hypercalls: u64,
+}
+
+/// How the views of EPT hooks are switched.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HvHookSwapStatus {
+    /// `HOOK_SWAP_*` selected when the processors were virtualized.
+    pub mode: u32,
+
+    /// The number of processors whose guest handles #VE already, the others still switch in VM exits.
+    pub ve_processors: u32,
+}
+
+/// Fills `version`, returns an EFI status.
+pub type GetVersionFn = unsafe extern "efiapi" fn(version: *mut HvVersion) -> usize;
+
+/// Fills `status` for the `cpu_index`-th processor to start entering VMX operation, the bootstrap
+/// processor is `0`. Returns an EFI status, `STATUS_NOT_FOUND` past the last processor.
+pub type GetCpuStatusFn = unsafe extern "efiapi" fn(cpu_index: u32, status: *mut HvCpuStatus) -> usize;
+
+/// Fills `stats`, returns an EFI status.
+pub type GetStatsFn = unsafe extern "efiapi" fn(stats: *mut HvStats) -> usize;
+
+/// Fills `status`, returns an EFI status. Revision 2.
+pub type GetHookSwapStatusFn = unsafe extern "efiapi" fn(status: *mut HvHookSwapStatus) -> usize;
+
+/// The interface installed as `STATUS_PROTOCOL_GUID`.
+#[repr(C)]
+pub struct HvStatusProtocol {
+    /// `STATUS_PROTOCOL_REVISION` of the hypervisor.
+    pub revision: u32,
+
+    /// The size of the structure installed, at least the size of the revision the caller knows.
+    pub size: u32,
+
+    /// Returns the build of the hypervisor.
+    pub get_version: GetVersionFn,
+
+    /// Returns the status of one processor.
+    pub get_cpu_status: GetCpuStatusFn,
+
+    /// Returns the counters of the hypervisor.
+    pub get_stats: GetStatsFn,
+
+    /// Returns how the views of EPT hooks are switched, revision 2.
+    pub get_hook_swap_status: GetHookSwapStatusFn,
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, core::mem::offset_of};
+
+    #[test]
+    fn layout_is_stable() {
+        assert_eq!(core::mem::size_of::<HvStatusProtocol>(), 40);
+        assert_eq!(offset_of!(HvStatusProtocol, get_hook_swap_status), STATUS_PROTOCOL_V1_SIZE as usize);
+        assert_eq!(offset_of!(HvStatusProtocol, size), 4);
+        assert_eq!(offset_of!(HvStatusProtocol, get_version), 8);
+        assert_eq!(offset_of!(HvStatusProtocol, get_cpu_status), 16);
+        assert_eq!(offset_of!(HvStatusProtocol, get_stats), 24);
+
+        assert_eq!(core::mem::size_of::<HvVersion>(), 24);
+        assert_eq!(core::mem::size_of::<HvCpuStatus>(), 8);
+        assert_eq!(core::mem::size_of::<HvStats>(), 24);
+        assert_eq!(offset_of!(HvStats, vm_exits), 8);
+        assert_eq!(core::mem::size_of::<HvHookSwapStatus>(), 8);
+    }
+
+    #[test]
+    fn error_statuses_have_the_high_bit_set() {
+        assert_eq!(STATUS_INVALID_PARAMETER as u64, 0x8000_0000_0000_0002);
+        assert_eq!(STATUS_NOT_FOUND as u64, 0x8000_0000_0000_000e);
+    }
+}
@@ -0,0 +1,318 @@
+//! The in-memory layout of hypercalls.
+//!
+//! The client passes the address of a `HypercallRequest` in RCX of a CPUID executed with the hypercall key in RAX,
+//! and the hypervisor answers with a `HypercallResponse` in RAX and RBX. Both structures are `#[repr(C)]`
+//! with explicit integer codes, so the client and the hypervisor don't need to be built by the same
+//! compiler to agree on them. `ClientCommand` is the decoded form both sides work with.
+
+use crate::{ClientCommand, ClientDataPayload, Command, CommandStatus, HookData, ProcessMemoryOperation};
+
+/// `HypercallRequest::process_id` is set.
+pub const REQUEST_HAS_PROCESS_ID: u32 = 1 << 0;
+
+/// `HypercallRequest::guest_cr3` is set.
+pub const REQUEST_HAS_GUEST_CR3: u32 = 1 << 1;
+
+/// `HypercallRequest::address` is set.
+pub const REQUEST_HAS_ADDRESS: u32 = 1 << 2;
+
+/// A hypercall as laid out in guest memory.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HypercallRequest {
+    /// The `Command` code.
+    pub command: u64,
+
+    /// `REQUEST_HAS_*` bits telling which of the optional memory fields are set.
+    pub flags: u32,
+
+    /// The syscall number of the function to hook.
+    pub syscall_number: u16,
+
+    /// Zero, aligns `function_hash`.
+    pub reserved: u16,
+
+    /// The DJB2 hash of the function to hook.
+    pub function_hash: u32,
+
+    /// Zero, aligns `process_id`.
+    pub reserved1: u32,
+
+    /// The process to open.
+    pub process_id: u64,
+
+    /// The directory table base of the process to access.
+    pub guest_cr3: u64,
+
+    /// The address in the process to access.
+    pub address: u64,
+
+    /// The address of the client buffer.
+    pub buffer: u64,
+
+    /// The size of the client buffer in bytes.
+    pub buffer_size: u64,
+}
+
+/// The answer to a hypercall, returned in RAX and RBX.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HypercallResponse {
+    /// The `CommandStatus` code.
+    pub status: u64,
+
+    /// A value returned by the command, `0` for commands without one.
+    pub value: u64,
+}
+
+/// The EPT view of one processor, `Command::QueryEptViews` fills the client buffer with one per processor that started.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct EptViewReport {
+    /// The initial APIC ID of the processor.
+    pub apic_id: u32,
+
+    /// The `hvstatus::CPU_STATE_*` of the processor, `0` for the records past the last processor.
+    pub state: u32,
+
+    /// The EPTP index of the view the guest ran with at the latest VM exit, `0` is the primary EPT.
+    pub view: u32,
+
+    /// The number of views of the processor, the primary EPT included.
+    pub views: u32,
+}
+
+/// The counters of the hypervisor since it started, `Command::QueryStats` fills the client buffer with one.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StatsReport {
+    /// The number of processors that started entering VMX operation.
+    pub processors: u32,
+
+    /// The number of processors running as a guest.
+    pub virtualized: u32,
+
+    /// The VM exits handled on all processors.
+    pub vm_exits: u64,
+
+    /// The hypercalls handled on all processors.
+    pub hypercalls: u64,
+
+    /// The NMIs of the guest the processors took and queued for the guest.
+    pub nmis_queued: u64,
+
+    /// The queued NMIs injected into the guest.
+    pub nmis_reinjected: u64,
+
+    /// The NMIs of the guest lost, because one was pending already or the guest couldn't take it in time.
+    pub nmis_dropped: u64,
+
+    /// The MOVs from debug registers the guest executed, each one a VM exit.
+    pub debug_register_reads: u64,
+
+    /// The MOVs to debug registers the guest executed, each one a VM exit.
+    pub debug_register_writes: u64,
+
+    /// The INIT signals that reset a virtualized processor, like the ones starting the application processors.
+    pub init_signals: u64,
+
+    /// The SIPIs that started a virtualized processor waiting for one after an INIT.
+    pub startup_ipis: u64,
+}
+
+const _: () = assert!(core::mem::size_of::<HypercallRequest>() == 64);
+const _: () = assert!(core::mem::size_of::<HypercallResponse>() == 16);
+const _: () = assert!(core::mem::size_of::<EptViewReport>() == 16);
+const _: () = assert!(core::mem::size_of::<StatsReport>() == 80);
+
+impl HypercallRequest {
+    /// Decodes the request.
+    ///
+    /// # Returns
+    ///
+    /// The command, or `None` if the command code is unknown.
+    pub fn decode(&self) -> Option<ClientCommand> {
+        let command = Command::from_u64(self.command);
+        let payload = match command {
+            Command::EnableKernelEptHook | Command::DisableKernelEptHook => ClientDataPayload::Hook(HookData {
+                function_hash: self.function_hash,
+                syscall_number: self.syscall_number,
+            }),
+            Command::OpenProcess | Command::ReadProcessMemory | Command::WriteProcessMemory | Command::QueryEptViews | Command::QueryStats => {
+                let optional = |flag: u32, value: u64| (self.flags & flag != 0).then_some(value);
+                ClientDataPayload::Memory(ProcessMemoryOperation {
+                    process_id: optional(REQUEST_HAS_PROCESS_ID, self.process_id),
+                    guest_cr3: optional(REQUEST_HAS_GUEST_CR3, self.guest_cr3),
+                    address: optional(REQUEST_HAS_ADDRESS, self.address),
+                    buffer: self.buffer,
+                    buffer_size: self.buffer_size,
+                })
+            }
+            Command::Invalid => return None,
+        };
+
+        Some(ClientCommand { command, payload })
+    }
+
+    /// Converts `HypercallRequest` to a pointer.
+    pub fn as_ptr(&self) -> u64 {
+        self as *const HypercallRequest as u64
+    }
+
+    /// Converts a pointer to `HypercallRequest`.
+    pub fn from_ptr(ptr: u64) -> &'static HypercallRequest {
+        unsafe { &*(ptr as *const HypercallRequest) }
+    }
+}
+
+impl ClientCommand {
+    /// Encodes the command as laid out in guest memory.
+    pub fn encode(&self) -> HypercallRequest {
+        let mut request = HypercallRequest {
+            command: self.command as u64,
+            ..Default::default()
+        };
+
+        match self.payload {
+            ClientDataPayload::Hook(hook) => {
+                request.function_hash = hook.function_hash;
+                request.syscall_number = hook.syscall_number;
+            }
+            ClientDataPayload::Memory(memory) => {
+                for (value, flag, field) in [
+                    (memory.process_id, REQUEST_HAS_PROCESS_ID, &mut request.process_id),
+                    (memory.guest_cr3, REQUEST_HAS_GUEST_CR3, &mut request.guest_cr3),
+                    (memory.address, REQUEST_HAS_ADDRESS, &mut request.address),
+                ] {
+                    if let Some(value) = value {
+                        *field = value;
+                        request.flags |= flag;
+                    }
+                }
+                request.buffer = memory.buffer;
+                request.buffer_size = memory.buffer_size;
+            }
+        }
+
+        request
+    }
+}
+
+impl HypercallResponse {
+    /// Creates the answer to a hypercall.
+    pub fn new(status: CommandStatus, value: u64) -> Self {
+        Self {
+            status: status.to_u64(),
+            value,
+        }
+    }
+
+    /// Returns the status of the hypercall, `None` for an unknown code.
+    pub fn status(&self) -> Option<CommandStatus> {
+        CommandStatus::from_u64(self.status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, core::mem::offset_of};
+
+    #[test]
+    fn request_layout_is_stable() {
+        assert_eq!(offset_of!(HypercallRequest, command), 0);
+        assert_eq!(offset_of!(HypercallRequest, flags), 8);
+        assert_eq!(offset_of!(HypercallRequest, syscall_number), 12);
+        assert_eq!(offset_of!(HypercallRequest, function_hash), 16);
+        assert_eq!(offset_of!(HypercallRequest, process_id), 24);
+        assert_eq!(offset_of!(HypercallRequest, guest_cr3), 32);
+        assert_eq!(offset_of!(HypercallRequest, address), 40);
+        assert_eq!(offset_of!(HypercallRequest, buffer), 48);
+        assert_eq!(offset_of!(HypercallRequest, buffer_size), 56);
+        assert_eq!(offset_of!(HypercallResponse, value), 8);
+    }
+
+    #[test]
+    fn hook_commands_round_trip() {
+        for command in [Command::EnableKernelEptHook, Command::DisableKernelEptHook] {
+            let hook = ClientCommand {
+                command,
+                payload: ClientDataPayload::Hook(HookData {
+                    function_hash: 0x7c0dfcaa,
+                    syscall_number: 0x36,
+                }),
+            };
+
+            let request = hook.encode();
+            assert_eq!((request.command, request.flags), (command as u64, 0));
+            assert_eq!(request.decode(), Some(hook));
+        }
+    }
+
+    #[test]
+    fn memory_commands_round_trip() {
+        let open = ClientCommand {
+            command: Command::OpenProcess,
+            payload: ClientDataPayload::Memory(ProcessMemoryOperation {
+                process_id: Some(4),
+                guest_cr3: None,
+                address: None,
+                buffer: 0x1000,
+                buffer_size: 8,
+            }),
+        };
+        let request = open.encode();
+        assert_eq!(request.flags, REQUEST_HAS_PROCESS_ID);
+        assert_eq!(request.decode(), Some(open));
+
+        let read = ClientCommand {
+            command: Command::ReadProcessMemory,
+            payload: ClientDataPayload::Memory(ProcessMemoryOperation {
+                process_id: None,
+                guest_cr3: Some(0x1ad000),
+                address: Some(0),
+                buffer: 0x2000,
+                buffer_size: 0x100,
+            }),
+        };
+        let request = read.encode();
+        assert_eq!(request.flags, REQUEST_HAS_GUEST_CR3 | REQUEST_HAS_ADDRESS);
+        assert_eq!(request.decode(), Some(read));
+
+        let views = ClientCommand {
+            command: Command::QueryEptViews,
+            payload: ClientDataPayload::Memory(ProcessMemoryOperation {
+                process_id: None,
+                guest_cr3: None,
+                address: None,
+                buffer: 0x3000,
+                buffer_size: 4 * core::mem::size_of::<EptViewReport>() as u64,
+            }),
+        };
+        assert_eq!(views.encode().decode(), Some(views));
+
+        let stats = ClientCommand {
+            command: Command::QueryStats,
+            payload: ClientDataPayload::Memory(ProcessMemoryOperation {
+                process_id: None,
+                guest_cr3: None,
+                address: None,
+                buffer: 0x4000,
+                buffer_size: core::mem::size_of::<StatsReport>() as u64,
+            }),
+        };
+        assert_eq!(stats.encode().decode(), Some(stats));
+    }
+
+    #[test]
+    fn unknown_commands_are_rejected() {
+        let request = HypercallRequest {
+            command: 7,
+            ..Default::default()
+        };
+        assert_eq!(request.decode(), None);
+
+        let response = HypercallResponse::new(CommandStatus::Success, 0x1ad000);
+        assert_eq!((response.status(), response.value), (Some(CommandStatus::Success), 0x1ad000));
+        assert_eq!(HypercallResponse { status: 7, value: 0 }.status(), None);
+    }
+}
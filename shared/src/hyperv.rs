@@ -0,0 +1,62 @@
+//! The part of the Hyper-V interface the hypervisor answers when it masquerades as Hyper-V.
+//!
+//! With `HvFeatureFlags::HYPERV_MASQUERADE` the hypervisor reports the Hyper-V vendor and interface in the
+//! hypervisor CPUID leaves, implements the synthetic MSRs Windows writes early in boot and fails every hypercall
+//! with `HV_STATUS_INVALID_HYPERCALL_CODE`, so software that checks for Hyper-V finds one that offers nothing.
+//!
+//! Reference: https://learn.microsoft.com/en-us/virtualization/hyper-v-on-windows/tlfs/tlfs
+
+/// The highest hypervisor leaf Hyper-V reports in EAX of leaf 0x40000000.
+pub const HYPERV_MAX_LEAF: u32 = 0x4000_0005;
+
+/// Vendor string of Hyper-V, as returned in EBX, ECX and EDX by leaf 0x40000000.
+pub const HYPERV_VENDOR: &[u8; 12] = b"Microsoft Hv";
+
+/// Signature returned in EBX, ECX and EDX by leaf 0x40000000 (`HYPERV_VENDOR` in little-endian order).
+pub const HYPERV_VENDOR_SIGNATURE: [u32; 3] = [
+    u32::from_le_bytes([HYPERV_VENDOR[0], HYPERV_VENDOR[1], HYPERV_VENDOR[2], HYPERV_VENDOR[3]]),
+    u32::from_le_bytes([HYPERV_VENDOR[4], HYPERV_VENDOR[5], HYPERV_VENDOR[6], HYPERV_VENDOR[7]]),
+    u32::from_le_bytes([HYPERV_VENDOR[8], HYPERV_VENDOR[9], HYPERV_VENDOR[10], HYPERV_VENDOR[11]]),
+];
+
+/// Interface signature returned in EAX of leaf 0x40000001, "Hv#1" for the Microsoft hypervisor interface.
+pub const HYPERV_INTERFACE_SIGNATURE: u32 = u32::from_le_bytes(*b"Hv#1");
+
+/// Bit 5 of EAX of leaf 0x40000003, the partition may access the guest OS ID and hypercall MSRs.
+pub const HV_ACCESS_HYPERCALL_MSRS: u32 = 1 << 5;
+
+/// The synthetic MSR identifying the guest operating system.
+pub const HV_X64_MSR_GUEST_OS_ID: u32 = 0x4000_0000;
+
+/// The synthetic MSR enabling the hypercall page.
+pub const HV_X64_MSR_HYPERCALL: u32 = 0x4000_0001;
+
+/// [Bit 0] HV_X64_MSR_HYPERCALL: the hypercall page is enabled.
+pub const HV_HYPERCALL_ENABLE: u64 = 1 << 0;
+
+/// [Bit 1] HV_X64_MSR_HYPERCALL: the MSR is locked until the next reset.
+pub const HV_HYPERCALL_LOCKED: u64 = 1 << 1;
+
+/// [Bits 63:12] HV_X64_MSR_HYPERCALL: the guest physical page number of the hypercall page.
+pub const HV_HYPERCALL_PAGE_MASK: u64 = !0xFFF;
+
+/// The hypercall status for a call code the hypervisor doesn't implement.
+pub const HV_STATUS_INVALID_HYPERCALL_CODE: u64 = 0x0002;
+
+/// Returns whether EBX, ECX and EDX of leaf 0x40000000 name Hyper-V.
+pub fn is_hyperv_vendor(ebx: u32, ecx: u32, edx: u32) -> bool {
+    [ebx, ecx, edx] == HYPERV_VENDOR_SIGNATURE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures_match_hyperv() {
+        assert_eq!(HYPERV_VENDOR_SIGNATURE, [0x7263_694D, 0x666F_736F, 0x7648_2074]);
+        assert_eq!(HYPERV_INTERFACE_SIGNATURE, 0x3123_7648);
+        assert!(is_hyperv_vendor(0x7263_694D, 0x666F_736F, 0x7648_2074));
+        assert!(!is_hyperv_vendor(0x7263_694D, 0x666F_736F, 0));
+    }
+}
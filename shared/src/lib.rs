@@ -1,8 +1,65 @@
 #![no_std]
 
+pub mod features;
+pub mod handoff;
+pub mod hvconfig;
+pub mod hvstatus;
+pub mod hypercall;
+pub mod hyperv;
+pub mod logring;
+pub mod vmcall;
+
+/// Path of the Intel hypervisor image on the EFI system partition.
+pub const HYPERVISOR_PATH: &str = r"\EFI\Boot\illusion.efi";
+
+/// Path of the AMD hypervisor image on the EFI system partition.
+pub const HYPERVISOR_SVM_PATH: &str = r"\EFI\Boot\illusion_svm.efi";
+
 /// The password used for authentication with the hypervisor.
+///
+/// It is the hypercall key built into the image, the loader replaces it with a new key every boot.
 pub const PASSWORD: u64 = 0xDEADBEEF;
 
+/// CPUID leaf used to detect whether Illusion is running, when queried with `PASSWORD` as the sub-leaf.
+///
+/// Without the password, the leaf is passed through unmodified so the hypervisor stays hidden.
+pub const PRESENCE_LEAF: u32 = 0x4000_0000;
+
+/// Vendor string of the hypervisor, as returned in EBX, ECX and EDX by the presence leaf.
+pub const HYPERVISOR_VENDOR: &[u8; 12] = b"Illusion\0\0\0\0";
+
+/// Signature returned in EBX, ECX and EDX by the presence leaf (`HYPERVISOR_VENDOR` in little-endian order).
+pub const PRESENCE_SIGNATURE: [u32; 3] = [
+    u32::from_le_bytes([HYPERVISOR_VENDOR[0], HYPERVISOR_VENDOR[1], HYPERVISOR_VENDOR[2], HYPERVISOR_VENDOR[3]]),
+    u32::from_le_bytes([HYPERVISOR_VENDOR[4], HYPERVISOR_VENDOR[5], HYPERVISOR_VENDOR[6], HYPERVISOR_VENDOR[7]]),
+    u32::from_le_bytes([HYPERVISOR_VENDOR[8], HYPERVISOR_VENDOR[9], HYPERVISOR_VENDOR[10], HYPERVISOR_VENDOR[11]]),
+];
+
+/// Vendor GUID of the UEFI variables shared between the loader and the hypervisor.
+pub const VARIABLE_VENDOR: &str = "5d7c4b1e-8f3a-4c62-9e1d-2a6b0f9c7e41";
+
+/// Name of the volatile UEFI variable holding the hypercall key the loader generated for this boot.
+///
+/// Windows only lets elevated processes holding `SeSystemEnvironmentPrivilege` read it.
+pub const VMCALL_KEY_VARIABLE: &str = "IllusionVmcallKey";
+
+/// Encodes an ASCII string as a NUL terminated UCS-2 string at compile time.
+///
+/// `N` must be the length of the string plus one, and the string must be ASCII without NUL characters.
+pub const fn to_ucs2<const N: usize>(text: &str) -> [u16; N] {
+    let bytes = text.as_bytes();
+    assert!(bytes.len() + 1 == N, "N must be the length of the string plus one");
+
+    let mut ucs2 = [0u16; N];
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(bytes[i] != 0 && bytes[i].is_ascii(), "the string must be ASCII without NUL characters");
+        ucs2[i] = bytes[i] as u16;
+        i += 1;
+    }
+    ucs2
+}
+
 /// Enumeration of possible commands that can be issued to the hypervisor.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u64)]
@@ -23,8 +80,14 @@ pub enum Command {
     /// Command to write the memory of a process.
     WriteProcessMemory = 4,
 
+    /// Command to report the EPT view every processor runs the guest with, for debugging.
+    QueryEptViews = 5,
+
+    /// Command to report the counters of the hypervisor, for debugging.
+    QueryStats = 6,
+
     /// Invalid command.
-    Invalid,
+    Invalid = u64::MAX,
 }
 
 impl Command {
@@ -36,6 +99,8 @@ impl Command {
             2 => Command::OpenProcess,
             3 => Command::ReadProcessMemory,
             4 => Command::WriteProcessMemory,
+            5 => Command::QueryEptViews,
+            6 => Command::QueryStats,
             _ => Command::Invalid,
         }
     }
@@ -109,3 +174,43 @@ impl ClientCommand {
         unsafe { &*(ptr as *const ClientCommand) }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presence_signature_is_the_vendor_string() {
+        assert_eq!(PRESENCE_SIGNATURE, [0x756c6c49, 0x6e6f6973, 0x00000000]);
+    }
+
+    #[test]
+    fn paths_encode_as_ucs2() {
+        const PATH: [u16; HYPERVISOR_PATH.len() + 1] = to_ucs2(HYPERVISOR_PATH);
+        assert_eq!(PATH[0], u16::from(b'\\'));
+        assert_eq!(PATH[HYPERVISOR_PATH.len() - 1], u16::from(b'i'));
+        assert_eq!(PATH[HYPERVISOR_PATH.len()], 0);
+    }
+
+    #[test]
+    fn command_codes_round_trip() {
+        for command in [
+            Command::EnableKernelEptHook,
+            Command::DisableKernelEptHook,
+            Command::OpenProcess,
+            Command::ReadProcessMemory,
+            Command::WriteProcessMemory,
+            Command::QueryEptViews,
+            Command::QueryStats,
+            Command::Invalid,
+        ] {
+            assert_eq!(Command::from_u64(command as u64), command);
+        }
+        assert_eq!(Command::from_u64(7), Command::Invalid);
+
+        for status in [CommandStatus::Success, CommandStatus::Failure] {
+            assert_eq!(CommandStatus::from_u64(status.to_u64()), Some(status));
+        }
+        assert_eq!(CommandStatus::from_u64(2), None);
+    }
+}
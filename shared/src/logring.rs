@@ -0,0 +1,360 @@
+//! A ring of log lines in memory shared by the loader and the hypervisor.
+//!
+//! The loader allocates the memory, creates the ring and passes it in the handoff. The hypervisor appends
+//! the lines it logs while its entry point runs, and the loader prints them once `start_image` returned,
+//! so the log of a hypervisor that failed to start isn't lost with the console it was written to.
+//!
+//! The memory starts with a header of `HEADER_SIZE` bytes, followed by the ring data. `head` and `tail`
+//! count the bytes ever written and read, their difference is the number of bytes in use. Every record is
+//! a little-endian `u16` length followed by that many bytes, with `RECORD_TRUNCATED` set in the length if
+//! the line was cut to `MAX_LINE_SIZE`. Records wrap around the end of the data. When a new record doesn't
+//! fit, the oldest records are dropped, the lines right before a failure matter most.
+
+/// Value of the magic field ("ILLOGRNG" in little-endian order).
+pub const LOG_RING_MAGIC: u64 = u64::from_le_bytes(*b"ILLOGRNG");
+
+/// Size of the header preceding the ring data.
+pub const HEADER_SIZE: usize = 32;
+
+/// Size of the length preceding every line.
+pub const RECORD_HEADER_SIZE: usize = 2;
+
+/// Longest line stored, longer lines are cut.
+pub const MAX_LINE_SIZE: usize = 512;
+
+/// Bit of the record length marking a line that was cut.
+pub const RECORD_TRUNCATED: u16 = 1 << 15;
+
+/// Offsets of the header fields.
+const MAGIC_OFFSET: usize = 0;
+const CAPACITY_OFFSET: usize = 8;
+const DROPPED_OFFSET: usize = 12;
+const HEAD_OFFSET: usize = 16;
+const TAIL_OFFSET: usize = 24;
+
+/// Why memory isn't accepted as a ring.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogRingError {
+    /// The memory is too small for the header and one record.
+    TooSmall(usize),
+
+    /// The memory doesn't start with `LOG_RING_MAGIC`.
+    BadMagic(u64),
+
+    /// The header describes a ring that doesn't fit the memory.
+    Corrupted,
+}
+
+/// A line taken from the ring.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogLine {
+    /// The number of bytes copied to the buffer.
+    pub length: usize,
+
+    /// The line was cut, when it was stored or because the buffer was too small.
+    pub truncated: bool,
+}
+
+/// A ring of log lines on top of borrowed memory.
+pub struct LogRing<'a> {
+    memory: &'a mut [u8],
+}
+
+impl<'a> LogRing<'a> {
+    /// Creates an empty ring in `memory`.
+    pub fn create(memory: &'a mut [u8]) -> Result<Self, LogRingError> {
+        if memory.len() <= HEADER_SIZE + RECORD_HEADER_SIZE {
+            return Err(LogRingError::TooSmall(memory.len()));
+        }
+
+        let capacity = (memory.len() - HEADER_SIZE).min(u32::MAX as usize) as u32;
+        let mut ring = Self { memory };
+        ring.write_u64(MAGIC_OFFSET, LOG_RING_MAGIC);
+        ring.write_u32(CAPACITY_OFFSET, capacity);
+        ring.write_u32(DROPPED_OFFSET, 0);
+        ring.write_u64(HEAD_OFFSET, 0);
+        ring.write_u64(TAIL_OFFSET, 0);
+        Ok(ring)
+    }
+
+    /// Opens a ring created in `memory`, checking the header first.
+    pub fn open(memory: &'a mut [u8]) -> Result<Self, LogRingError> {
+        if memory.len() <= HEADER_SIZE + RECORD_HEADER_SIZE {
+            return Err(LogRingError::TooSmall(memory.len()));
+        }
+
+        let ring = Self { memory };
+        let magic = ring.read_u64(MAGIC_OFFSET);
+        if magic != LOG_RING_MAGIC {
+            return Err(LogRingError::BadMagic(magic));
+        }
+
+        let capacity = ring.read_u32(CAPACITY_OFFSET) as usize;
+        let (head, tail) = (ring.read_u64(HEAD_OFFSET), ring.read_u64(TAIL_OFFSET));
+        if capacity <= RECORD_HEADER_SIZE || capacity > ring.memory.len() - HEADER_SIZE || tail > head || head - tail > capacity as u64 {
+            return Err(LogRingError::Corrupted);
+        }
+
+        Ok(ring)
+    }
+
+    /// Appends a line, dropping the oldest lines if it doesn't fit.
+    pub fn push(&mut self, line: &[u8]) {
+        let capacity = self.capacity();
+        let length = line.len().min(MAX_LINE_SIZE).min(capacity - RECORD_HEADER_SIZE);
+        let size = (RECORD_HEADER_SIZE + length) as u64;
+
+        while capacity as u64 - self.used() < size {
+            self.drop_oldest();
+        }
+
+        let mut header = length as u16;
+        if length < line.len() {
+            header |= RECORD_TRUNCATED;
+        }
+
+        let head = self.read_u64(HEAD_OFFSET);
+        self.write_data(head, &header.to_le_bytes());
+        self.write_data(head + RECORD_HEADER_SIZE as u64, &line[..length]);
+        self.write_u64(HEAD_OFFSET, head + size);
+    }
+
+    /// Takes the oldest line, copying as much of it as fits into `buffer`.
+    ///
+    /// # Returns
+    ///
+    /// The line, or `None` once the ring is empty or its records are corrupted.
+    pub fn pop(&mut self, buffer: &mut [u8]) -> Option<LogLine> {
+        let (tail, stored, truncated) = self.oldest()?;
+
+        let length = stored.min(buffer.len());
+        self.read_data(tail + RECORD_HEADER_SIZE as u64, &mut buffer[..length]);
+        self.write_u64(TAIL_OFFSET, tail + (RECORD_HEADER_SIZE + stored) as u64);
+
+        Some(LogLine {
+            length,
+            truncated: truncated || length < stored,
+        })
+    }
+
+    /// Returns the number of lines dropped to make room for newer ones.
+    pub fn dropped(&self) -> u32 {
+        self.read_u32(DROPPED_OFFSET)
+    }
+
+    /// Returns whether the ring holds no lines.
+    pub fn is_empty(&self) -> bool {
+        self.used() == 0
+    }
+
+    /// Returns the size of the ring data.
+    fn capacity(&self) -> usize {
+        self.read_u32(CAPACITY_OFFSET) as usize
+    }
+
+    /// Returns the number of bytes in use.
+    fn used(&self) -> u64 {
+        self.read_u64(HEAD_OFFSET) - self.read_u64(TAIL_OFFSET)
+    }
+
+    /// Returns the position, length and truncation bit of the oldest record.
+    ///
+    /// A record reaching past `head` can only be the result of corruption, the ring is emptied then.
+    fn oldest(&mut self) -> Option<(u64, usize, bool)> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let tail = self.read_u64(TAIL_OFFSET);
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        self.read_data(tail, &mut header);
+        let header = u16::from_le_bytes(header);
+        let length = (header & !RECORD_TRUNCATED) as usize;
+
+        if (RECORD_HEADER_SIZE + length) as u64 > self.used() {
+            let head = self.read_u64(HEAD_OFFSET);
+            self.write_u64(TAIL_OFFSET, head);
+            return None;
+        }
+
+        Some((tail, length, header & RECORD_TRUNCATED != 0))
+    }
+
+    /// Drops the oldest record and counts it.
+    fn drop_oldest(&mut self) {
+        match self.oldest() {
+            Some((tail, length, _)) => self.write_u64(TAIL_OFFSET, tail + (RECORD_HEADER_SIZE + length) as u64),
+            None => return,
+        }
+
+        let dropped = self.dropped().saturating_add(1);
+        self.write_u32(DROPPED_OFFSET, dropped);
+    }
+
+    /// Copies `bytes` into the ring data at `position`, wrapping around the end.
+    fn write_data(&mut self, position: u64, bytes: &[u8]) {
+        let capacity = self.capacity();
+        let start = (position % capacity as u64) as usize;
+        let first = bytes.len().min(capacity - start);
+
+        let data = &mut self.memory[HEADER_SIZE..HEADER_SIZE + capacity];
+        data[start..start + first].copy_from_slice(&bytes[..first]);
+        data[..bytes.len() - first].copy_from_slice(&bytes[first..]);
+    }
+
+    /// Copies ring data at `position` into `bytes`, wrapping around the end.
+    fn read_data(&self, position: u64, bytes: &mut [u8]) {
+        let capacity = self.capacity();
+        let start = (position % capacity as u64) as usize;
+        let first = bytes.len().min(capacity - start);
+
+        let data = &self.memory[HEADER_SIZE..HEADER_SIZE + capacity];
+        bytes[..first].copy_from_slice(&data[start..start + first]);
+        let rest = bytes.len() - first;
+        bytes[first..].copy_from_slice(&data[..rest]);
+    }
+
+    fn read_u32(&self, offset: usize) -> u32 {
+        u32::from_le_bytes([
+            self.memory[offset],
+            self.memory[offset + 1],
+            self.memory[offset + 2],
+            self.memory[offset + 3],
+        ])
+    }
+
+    fn read_u64(&self, offset: usize) -> u64 {
+        u64::from(self.read_u32(offset)) | u64::from(self.read_u32(offset + 4)) << 32
+    }
+
+    fn write_u32(&mut self, offset: usize, value: u32) {
+        self.memory[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, offset: usize, value: u64) {
+        self.memory[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use {
+        super::*,
+        std::{format, string::String, vec::Vec},
+    };
+
+    /// Takes the next line as a string, `None` once the ring is empty.
+    fn next(ring: &mut LogRing, buffer: &mut [u8]) -> Option<(String, bool)> {
+        let line = ring.pop(buffer)?;
+        Some((String::from_utf8(buffer[..line.length].to_vec()).unwrap(), line.truncated))
+    }
+
+    #[test]
+    fn lines_come_out_in_order() {
+        let mut memory = std::vec![0u8; 256];
+        let mut ring = LogRing::create(&mut memory).unwrap();
+        assert!(ring.is_empty());
+
+        ring.push(b"first");
+        ring.push(b"");
+        ring.push(b"third");
+
+        let mut buffer = [0u8; 64];
+        assert_eq!(next(&mut ring, &mut buffer), Some((String::from("first"), false)));
+        assert_eq!(next(&mut ring, &mut buffer), Some((String::new(), false)));
+        assert_eq!(next(&mut ring, &mut buffer), Some((String::from("third"), false)));
+        assert_eq!(next(&mut ring, &mut buffer), None);
+        assert_eq!(ring.dropped(), 0);
+    }
+
+    #[test]
+    fn reopened_rings_keep_their_lines() {
+        let mut memory = std::vec![0u8; 128];
+        LogRing::create(&mut memory).unwrap().push(b"written by the hypervisor");
+
+        let mut ring = LogRing::open(&mut memory).unwrap();
+        let mut buffer = [0u8; 64];
+        assert_eq!(next(&mut ring, &mut buffer), Some((String::from("written by the hypervisor"), false)));
+    }
+
+    #[test]
+    fn records_wrap_around_and_drop_the_oldest() {
+        // 40 bytes of data hold four records of 2 + 8 bytes.
+        let mut memory = std::vec![0u8; HEADER_SIZE + 40];
+        let mut ring = LogRing::create(&mut memory).unwrap();
+
+        let lines: Vec<String> = (0..7).map(|i| format!("line {:03}", i)).collect();
+        for line in &lines {
+            ring.push(line.as_bytes());
+        }
+        assert_eq!(ring.dropped(), 3);
+
+        let mut buffer = [0u8; 64];
+        let remaining: Vec<String> = core::iter::from_fn(|| next(&mut ring, &mut buffer).map(|(line, _)| line)).collect();
+        assert_eq!(remaining, lines[3..]);
+
+        // Records of an odd size end up split across the end of the data.
+        for i in 0..10 {
+            ring.push(format!("odd {}", i).as_bytes());
+            assert_eq!(next(&mut ring, &mut buffer), Some((format!("odd {}", i), false)));
+        }
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn long_lines_are_truncated() {
+        let mut memory = std::vec![0u8; HEADER_SIZE + 2048];
+        let mut ring = LogRing::create(&mut memory).unwrap();
+
+        ring.push(&[b'x'; MAX_LINE_SIZE + 100]);
+        let mut buffer = [0u8; 1024];
+        assert_eq!(
+            ring.pop(&mut buffer),
+            Some(LogLine {
+                length: MAX_LINE_SIZE,
+                truncated: true
+            })
+        );
+
+        // A buffer shorter than the line gets its start, the rest of the line is skipped.
+        ring.push(b"0123456789");
+        ring.push(b"next");
+        let mut short = [0u8; 4];
+        assert_eq!(next(&mut ring, &mut short), Some((String::from("0123"), true)));
+        assert_eq!(next(&mut ring, &mut short), Some((String::from("next"), false)));
+
+        // A line longer than the whole ring keeps what fits.
+        let mut memory = std::vec![0u8; HEADER_SIZE + 16];
+        let mut ring = LogRing::create(&mut memory).unwrap();
+        ring.push(b"older");
+        ring.push(b"0123456789abcdefghij");
+        assert_eq!(next(&mut ring, &mut buffer), Some((String::from("0123456789abcd"), true)));
+        assert_eq!(ring.dropped(), 1);
+    }
+
+    #[test]
+    fn damaged_rings_are_rejected() {
+        assert_eq!(LogRing::create(&mut [0u8; HEADER_SIZE]).err(), Some(LogRingError::TooSmall(HEADER_SIZE)));
+        assert_eq!(LogRing::open(&mut [0u8; 64]).err(), Some(LogRingError::BadMagic(0)));
+
+        let mut memory = std::vec![0u8; 64];
+        LogRing::create(&mut memory).unwrap();
+        memory[CAPACITY_OFFSET..CAPACITY_OFFSET + 4].copy_from_slice(&1000u32.to_le_bytes());
+        assert_eq!(LogRing::open(&mut memory).err(), Some(LogRingError::Corrupted));
+
+        let mut memory = std::vec![0u8; 64];
+        LogRing::create(&mut memory).unwrap();
+        memory[TAIL_OFFSET] = 1;
+        assert_eq!(LogRing::open(&mut memory).err(), Some(LogRingError::Corrupted));
+
+        // A record longer than the bytes in use empties the ring instead of reading garbage.
+        let mut memory = std::vec![0u8; 64];
+        LogRing::create(&mut memory).unwrap().push(b"abc");
+        memory[HEADER_SIZE] = 30;
+        let mut ring = LogRing::open(&mut memory).unwrap();
+        assert_eq!(ring.pop(&mut [0u8; 64]), None);
+        assert!(ring.is_empty());
+    }
+}
@@ -0,0 +1,588 @@
+//! The register convention of VMCALL hypercalls.
+//!
+//! Unlike the CPUID hypercalls of `crate::hypercall`, VMCALL hypercalls pass everything in registers: the caller
+//! sets RAX to `VMCALL_MAGIC`, RCX to the hypercall key of this boot, RDX to the `VmcallNumber` and R8 through R12
+//! to its arguments. The hypervisor answers with a `VmcallStatus` in RAX and up to three values in RDX, R8 and R9,
+//! the other registers are preserved. A VMCALL without the magic and the key raises #UD as it would outside VMX
+//! operation, so a guest can't tell the hypervisor is there without knowing the key.
+//!
+//! Call numbers are grouped in ranges of 256: the built-in calls, then the memory, hook, process and syscall
+//! primitives. `ping` negotiates the version, `get_capabilities` tells which ranges the hypervisor serves.
+//! `read_events` drains the event log of the hypervisor, an `EventRecord` per event, and `audit_hooks` logs the
+//! code someone else patched on the pages of EPT hooks.
+//!
+//! The memory calls copy between the caller's buffer and guest memory, walking the guest's page tables in the
+//! hypervisor rather than asking the kernel. R8 is the CR3 of the address space, zero for the caller's, R9 the
+//! address, R10 the buffer in the caller's address space, R11 the bytes to copy, at most `MAX_MEMORY_LENGTH`, and
+//! R12 the `MEMORY_*` flags. RDX returns the bytes copied, with `PartialCopy` when the copy stopped at a page that
+//! isn't mapped, or isn't writable, on either side.
+//!
+//! The process calls read the process list of a Windows guest's kernel. `find_process` looks a process up by the
+//! name in R8 and R9, `enumerate_processes` writes a `ProcessEntry` for every process to the buffer in R8, room
+//! for R9 of them. Both fail with `UnsupportedGuest` for a guest whose build of Windows the hypervisor doesn't
+//! know the layout of. `resolve_kernel_export` returns the address of an export of ntoskrnl.exe by name.
+//!
+//! The syscall calls program the rules of the syscall watch: while a rule is set, the hypervisor hooks the entry
+//! point LSTAR holds and logs an `EVENT_SYSCALL` for every syscall a rule matches. A rule is a syscall number in
+//! R8, or `SYSCALL_WATCH_ANY`, and a CR3 in R9, zero for every address space.
+
+/// The value of RAX of an authenticated VMCALL, "Illusion" in little-endian order.
+pub const VMCALL_MAGIC: u64 = u64::from_le_bytes(*b"Illusion");
+
+/// The major version of the convention, calls are only served to callers with the same one.
+pub const VMCALL_VERSION_MAJOR: u16 = 1;
+
+/// The minor version of the convention, raised for every call added to it.
+pub const VMCALL_VERSION_MINOR: u16 = 5;
+
+/// The version `ping` returns, the major version in bits 31:16 and the minor one in bits 15:0.
+pub const VMCALL_VERSION: u32 = (VMCALL_VERSION_MAJOR as u32) << 16 | VMCALL_VERSION_MINOR as u32;
+
+/// `get_capabilities`: the built-in calls, always set.
+pub const CAPABILITY_BUILTIN: u64 = 1 << 0;
+
+/// `get_capabilities`: the memory primitives.
+pub const CAPABILITY_MEMORY: u64 = 1 << 1;
+
+/// `get_capabilities`: the hook primitives, only with `HvFeatureFlags::EPT_HOOKS`.
+pub const CAPABILITY_HOOKS: u64 = 1 << 2;
+
+/// `get_capabilities`: the process primitives.
+pub const CAPABILITY_PROCESSES: u64 = 1 << 3;
+
+/// `get_capabilities`: the syscall primitives, only with `HvFeatureFlags::EPT_HOOKS`.
+pub const CAPABILITY_SYSCALLS: u64 = 1 << 4;
+
+/// The most bytes a memory call copies.
+pub const MAX_MEMORY_LENGTH: u64 = 0x10000;
+
+/// Memory calls: write guest memory through the host mapping even where the guest's page tables make it read-only.
+pub const MEMORY_FORCE_WRITE: u64 = 1 << 0;
+
+/// The bytes of a process name Windows keeps, `find_process` compares the first ones of a longer name.
+pub const PROCESS_NAME_LENGTH: usize = 15;
+
+/// The longest export name `resolve_kernel_export` looks up.
+pub const MAX_EXPORT_NAME_LENGTH: usize = 63;
+
+/// The most rules the syscall watch holds.
+pub const MAX_SYSCALL_WATCHES: usize = 32;
+
+/// A syscall watch rule matching every syscall number.
+pub const SYSCALL_WATCH_ANY: u64 = u64::MAX;
+
+/// `EventRecord::kind`: a syscall the watch matched, its number, the CR3 and the return address in RCX.
+pub const EVENT_SYSCALL: u32 = 1;
+
+/// `EventRecord::kind`: bytes of a hooked page the guest sees modified since the hook, the guest-physical address
+/// of the page, the offset of the bytes and their length.
+pub const EVENT_GUEST_PATCH: u32 = 2;
+
+/// `EventRecord::kind`: bytes of the shadow page of a hook modified outside the hooks, the values of
+/// `EVENT_GUEST_PATCH`.
+pub const EVENT_SHADOW_DRIFT: u32 = 3;
+
+/// The ranges of call numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmcallRange {
+    /// `0x000..=0x0FF`: the calls every hypervisor serves.
+    Builtin,
+
+    /// `0x100..=0x1FF`: reading and writing guest memory.
+    Memory,
+
+    /// `0x200..=0x2FF`: installing and removing EPT hooks.
+    Hook,
+
+    /// `0x300..=0x3FF`: finding processes, their address spaces and the kernel.
+    Process,
+
+    /// `0x400..=0x4FF`: watching the syscalls of the guest.
+    Syscall,
+}
+
+impl VmcallRange {
+    /// Returns the range of a call number, `None` past the defined ranges.
+    pub fn of(number: u64) -> Option<Self> {
+        match number >> 8 {
+            0 => Some(Self::Builtin),
+            1 => Some(Self::Memory),
+            2 => Some(Self::Hook),
+            3 => Some(Self::Process),
+            4 => Some(Self::Syscall),
+            _ => None,
+        }
+    }
+
+    /// Returns the `CAPABILITY_*` bit of the range.
+    pub fn capability(self) -> u64 {
+        match self {
+            Self::Builtin => CAPABILITY_BUILTIN,
+            Self::Memory => CAPABILITY_MEMORY,
+            Self::Hook => CAPABILITY_HOOKS,
+            Self::Process => CAPABILITY_PROCESSES,
+            Self::Syscall => CAPABILITY_SYSCALLS,
+        }
+    }
+
+    /// Returns whether user mode may issue the calls of the range.
+    ///
+    /// Hooks change kernel code, so only the kernel, CPL 0, may ask for them.
+    pub fn allows_user_mode(self) -> bool {
+        !matches!(self, Self::Hook)
+    }
+}
+
+/// The calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum VmcallNumber {
+    /// Checks the hypervisor answers. R8 is the `VMCALL_VERSION` of the caller, RDX returns the hypervisor's and
+    /// R8 the `HvFeatureFlags` bits of this boot. Fails with `VersionMismatch` for another major version, RDX still
+    /// returning the hypervisor's.
+    Ping = 0x000,
+
+    /// Returns the `CAPABILITY_*` bits of the calls the hypervisor serves in RDX.
+    GetCapabilities = 0x001,
+
+    /// Returns the VM exits in RDX, the hypercalls in R8 and the virtualized processors in R9.
+    GetStats = 0x002,
+
+    /// Moves the oldest events of the log to the array of `EventRecord` at R8, room for R9 of them. RDX returns
+    /// the events written, R8 the events lost to a full log since the previous call.
+    ReadEvents = 0x003,
+
+    /// Compares the pages of all EPT hooks with the clean copies taken when they were hooked, logging an
+    /// `EVENT_GUEST_PATCH` or `EVENT_SHADOW_DRIFT` per run of modified bytes. RDX returns the pages audited, R8
+    /// those the guest sees modified and R9 the runs of modified bytes.
+    AuditHooks = 0x004,
+
+    /// Copies guest virtual memory of the address space in R8 to the buffer.
+    ReadGva = 0x100,
+
+    /// Copies the buffer to guest virtual memory of the address space in R8, see `MEMORY_FORCE_WRITE`.
+    WriteGva = 0x101,
+
+    /// Copies guest physical memory to the buffer, R8 is ignored.
+    ReadGpa = 0x102,
+
+    /// Copies the buffer to guest physical memory, R8 is ignored.
+    WriteGpa = 0x103,
+
+    /// Finds the process whose name is the R9 bytes at the address R8, ignoring case. RDX returns the
+    /// guest-physical address of its EPROCESS, R8 its CR3 and R9 its process ID, fails with `NotFound`.
+    FindProcess = 0x300,
+
+    /// Writes a `ProcessEntry` per process to the array at R8, room for R9 of them. RDX returns the processes,
+    /// which may be more than fit, R8 the entries written.
+    EnumerateProcesses = 0x301,
+
+    /// Returns the address of the export of ntoskrnl.exe named by the R9 bytes at the address R8 in RDX and the
+    /// base of ntoskrnl.exe in R8, fails with `NotFound`.
+    ResolveKernelExport = 0x302,
+
+    /// Adds the rule of R8 and R9 to the syscall watch, hooking the syscall entry point for the first one. Adding
+    /// a rule twice is a no-op, fails with `Exhausted` past `MAX_SYSCALL_WATCHES` rules.
+    SyscallWatchAdd = 0x400,
+
+    /// Removes the rule of R8 and R9, unhooking the syscall entry point with the last one, fails with `NotFound`.
+    SyscallWatchRemove = 0x401,
+
+    /// Writes a `SyscallWatchEntry` per rule to the array at R8, room for R9 of them. RDX returns the rules, R8
+    /// the entries written.
+    SyscallWatchList = 0x402,
+
+    /// Returns the syscalls the hook intercepted in RDX, those a rule matched in R8 and the VM exits the hook
+    /// took in R9.
+    GetSyscallStats = 0x403,
+}
+
+impl VmcallNumber {
+    /// Returns the call with this number, `None` for the other numbers.
+    pub fn from_u64(value: u64) -> Option<Self> {
+        match value {
+            0x000 => Some(Self::Ping),
+            0x001 => Some(Self::GetCapabilities),
+            0x002 => Some(Self::GetStats),
+            0x003 => Some(Self::ReadEvents),
+            0x004 => Some(Self::AuditHooks),
+            0x100 => Some(Self::ReadGva),
+            0x101 => Some(Self::WriteGva),
+            0x102 => Some(Self::ReadGpa),
+            0x103 => Some(Self::WriteGpa),
+            0x300 => Some(Self::FindProcess),
+            0x301 => Some(Self::EnumerateProcesses),
+            0x302 => Some(Self::ResolveKernelExport),
+            0x400 => Some(Self::SyscallWatchAdd),
+            0x401 => Some(Self::SyscallWatchRemove),
+            0x402 => Some(Self::SyscallWatchList),
+            0x403 => Some(Self::GetSyscallStats),
+            _ => None,
+        }
+    }
+}
+
+/// The outcome of a VMCALL hypercall, returned in RAX.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum VmcallStatus {
+    /// The call succeeded.
+    Success = 0,
+
+    /// No call has this number.
+    UnknownCall = 1,
+
+    /// The call is reserved, but the hypervisor doesn't serve it.
+    NotImplemented = 2,
+
+    /// The call isn't allowed at the CPL of the caller.
+    AccessDenied = 3,
+
+    /// An argument is invalid.
+    InvalidParameter = 4,
+
+    /// The caller speaks another major version of the convention.
+    VersionMismatch = 5,
+
+    /// The call failed.
+    Failure = 6,
+
+    /// A memory call copied only the bytes in RDX.
+    PartialCopy = 7,
+
+    /// Nothing matched the call.
+    NotFound = 8,
+
+    /// The guest isn't an operating system the call knows.
+    UnsupportedGuest = 9,
+
+    /// A table of the hypervisor the call adds to is full.
+    Exhausted = 10,
+}
+
+impl VmcallStatus {
+    /// Returns the status with this code, `None` for an unknown code.
+    pub fn from_u64(value: u64) -> Option<Self> {
+        match value {
+            0 => Some(Self::Success),
+            1 => Some(Self::UnknownCall),
+            2 => Some(Self::NotImplemented),
+            3 => Some(Self::AccessDenied),
+            4 => Some(Self::InvalidParameter),
+            5 => Some(Self::VersionMismatch),
+            6 => Some(Self::Failure),
+            7 => Some(Self::PartialCopy),
+            8 => Some(Self::NotFound),
+            9 => Some(Self::UnsupportedGuest),
+            10 => Some(Self::Exhausted),
+            _ => None,
+        }
+    }
+}
+
+/// The registers of a VMCALL the convention uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct VmcallRegisters {
+    pub rax: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+}
+
+/// A decoded VMCALL hypercall.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmcallRequest {
+    /// The call number, RDX.
+    pub number: u64,
+
+    /// The arguments, R8 through R12.
+    pub args: [u64; 5],
+}
+
+impl VmcallRequest {
+    /// Returns the registers issuing the call.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The hypercall key of this boot.
+    pub fn encode(&self, key: u64) -> VmcallRegisters {
+        VmcallRegisters {
+            rax: VMCALL_MAGIC,
+            rcx: key,
+            rdx: self.number,
+            r8: self.args[0],
+            r9: self.args[1],
+            r10: self.args[2],
+            r11: self.args[3],
+            r12: self.args[4],
+        }
+    }
+
+    /// Decodes the registers of a VMCALL.
+    ///
+    /// # Arguments
+    ///
+    /// * `registers` - The registers of the guest.
+    /// * `key` - The hypercall key of this boot.
+    ///
+    /// # Returns
+    ///
+    /// The request, `None` if the registers don't carry the magic and the key.
+    pub fn decode(registers: &VmcallRegisters, key: u64) -> Option<Self> {
+        (registers.rax == VMCALL_MAGIC && registers.rcx == key).then_some(Self {
+            number: registers.rdx,
+            args: [registers.r8, registers.r9, registers.r10, registers.r11, registers.r12],
+        })
+    }
+}
+
+/// The answer to a VMCALL hypercall.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmcallResponse {
+    /// The outcome, RAX.
+    pub status: VmcallStatus,
+
+    /// The values, RDX, R8 and R9, zero where the call returns none.
+    pub values: [u64; 3],
+}
+
+impl VmcallResponse {
+    /// Creates an answer without values.
+    pub fn status(status: VmcallStatus) -> Self {
+        Self { status, values: [0; 3] }
+    }
+
+    /// Creates a successful answer.
+    pub fn success(values: [u64; 3]) -> Self {
+        Self {
+            status: VmcallStatus::Success,
+            values,
+        }
+    }
+
+    /// Writes the answer to the registers returned to the caller.
+    pub fn encode(&self, registers: &mut VmcallRegisters) {
+        registers.rax = self.status as u64;
+        registers.rdx = self.values[0];
+        registers.r8 = self.values[1];
+        registers.r9 = self.values[2];
+    }
+
+    /// Decodes the answer from the registers the VMCALL returned with, `None` for an unknown status.
+    pub fn decode(registers: &VmcallRegisters) -> Option<Self> {
+        Some(Self {
+            status: VmcallStatus::from_u64(registers.rax)?,
+            values: [registers.rdx, registers.r8, registers.r9],
+        })
+    }
+}
+
+/// A process of the guest, as `enumerate_processes` writes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(C)]
+pub struct ProcessEntry {
+    /// The guest-physical address of the EPROCESS of the process.
+    pub eprocess: u64,
+
+    /// The CR3 of the address space of the process, its DirectoryTableBase.
+    pub directory_table_base: u64,
+
+    /// The process ID.
+    pub process_id: u64,
+
+    /// The name of the image of the process, NUL-padded.
+    pub image_file_name: [u8; PROCESS_NAME_LENGTH + 1],
+}
+
+impl ProcessEntry {
+    /// The bytes of an entry.
+    pub const SIZE: usize = core::mem::size_of::<Self>();
+
+    /// Returns the name of the image, up to the first NUL.
+    pub fn name(&self) -> &[u8] {
+        let length = self
+            .image_file_name
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(self.image_file_name.len());
+        &self.image_file_name[..length]
+    }
+
+    /// Returns the entry in the byte order of the guest.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0..8].copy_from_slice(&self.eprocess.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.directory_table_base.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.process_id.to_le_bytes());
+        bytes[24..].copy_from_slice(&self.image_file_name);
+        bytes
+    }
+}
+
+/// A rule of the syscall watch, as `syscall_watch_list` writes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(C)]
+pub struct SyscallWatchEntry {
+    /// The syscall number, `SYSCALL_WATCH_ANY` for every one.
+    pub number: u64,
+
+    /// The CR3 the syscalls are made with, zero for every address space.
+    pub cr3: u64,
+
+    /// The syscalls the rule matched.
+    pub hits: u64,
+}
+
+impl SyscallWatchEntry {
+    /// The bytes of an entry.
+    pub const SIZE: usize = core::mem::size_of::<Self>();
+
+    /// Returns the entry in the byte order of the guest.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0..8].copy_from_slice(&self.number.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.cr3.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.hits.to_le_bytes());
+        bytes
+    }
+}
+
+/// An event of the hypervisor, as `read_events` writes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(C)]
+pub struct EventRecord {
+    /// The `EVENT_*` kind of the event, which tells what `values` hold.
+    pub kind: u32,
+
+    /// The initial APIC ID of the processor the event happened on.
+    pub processor: u32,
+
+    /// The values of the event.
+    pub values: [u64; 3],
+}
+
+impl EventRecord {
+    /// The bytes of a record.
+    pub const SIZE: usize = core::mem::size_of::<Self>();
+
+    /// Returns the record in the byte order of the guest.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0..4].copy_from_slice(&self.kind.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.processor.to_le_bytes());
+        for (chunk, value) in bytes[8..].chunks_exact_mut(8).zip(self.values) {
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+/// Returns whether a caller speaking `version` may use the convention of this crate.
+pub fn version_compatible(version: u32) -> bool {
+    (version >> 16) as u16 == VMCALL_VERSION_MAJOR
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: u64 = 0x1234_5678_9abc_def0;
+
+    #[test]
+    fn requests_round_trip_through_registers() {
+        let request = VmcallRequest {
+            number: VmcallNumber::Ping as u64,
+            args: [VMCALL_VERSION as u64, 2, 3, 4, 5],
+        };
+
+        let registers = request.encode(KEY);
+        assert_eq!((registers.rax, registers.rcx, registers.rdx, registers.r10), (0x6e6f_6973_756c_6c49, KEY, 0, 3));
+        assert_eq!((registers.r11, registers.r12), (4, 5));
+        assert_eq!(VmcallRequest::decode(&registers, KEY), Some(request));
+
+        // Without the magic or with another key, the VMCALL isn't ours.
+        assert_eq!(VmcallRequest::decode(&registers, KEY + 1), None);
+        assert_eq!(VmcallRequest::decode(&VmcallRegisters { rax: 0, ..registers }, KEY), None);
+    }
+
+    #[test]
+    fn responses_round_trip_through_registers() {
+        let mut registers = VmcallRequest {
+            number: 2,
+            args: [7, 8, 9, 10, 11],
+        }
+        .encode(KEY);
+        VmcallResponse::success([1, 2, 3]).encode(&mut registers);
+        assert_eq!(registers.rcx, KEY);
+        assert_eq!(VmcallResponse::decode(&registers), Some(VmcallResponse::success([1, 2, 3])));
+
+        VmcallResponse::status(VmcallStatus::AccessDenied).encode(&mut registers);
+        assert_eq!((registers.rax, registers.rdx), (3, 0));
+        assert_eq!(VmcallResponse::decode(&VmcallRegisters { rax: 11, ..registers }), None);
+
+        for code in 0..11 {
+            assert_eq!(VmcallStatus::from_u64(code).map(|status| status as u64), Some(code));
+        }
+    }
+
+    #[test]
+    fn numbers_fall_into_ranges() {
+        assert_eq!(VmcallRange::of(VmcallNumber::GetStats as u64), Some(VmcallRange::Builtin));
+        assert_eq!(VmcallRange::of(0x1FF), Some(VmcallRange::Memory));
+        assert_eq!(VmcallRange::of(0x200), Some(VmcallRange::Hook));
+        assert_eq!(VmcallRange::of(0x3FF), Some(VmcallRange::Process));
+        assert_eq!(VmcallRange::of(0x400), Some(VmcallRange::Syscall));
+        assert_eq!(VmcallRange::of(0x500), None);
+        assert!(!VmcallRange::Hook.allows_user_mode() && VmcallRange::Memory.allows_user_mode());
+
+        assert_eq!(VmcallNumber::from_u64(1), Some(VmcallNumber::GetCapabilities));
+        assert_eq!(VmcallNumber::from_u64(3), Some(VmcallNumber::ReadEvents));
+        assert_eq!(VmcallNumber::from_u64(4), Some(VmcallNumber::AuditHooks));
+        assert_eq!(VmcallNumber::from_u64(5), None);
+        assert_eq!(VmcallNumber::from_u64(0x103), Some(VmcallNumber::WriteGpa));
+        assert_eq!(VmcallRange::of(VmcallNumber::ReadGva as u64), Some(VmcallRange::Memory));
+        assert!(version_compatible(VMCALL_VERSION | 0xFF) && !version_compatible(2 << 16));
+        assert_eq!(VmcallRange::of(VmcallNumber::EnumerateProcesses as u64), Some(VmcallRange::Process));
+    }
+
+    #[test]
+    fn process_entries_have_a_fixed_layout() {
+        let mut entry = ProcessEntry {
+            eprocess: 0x1000,
+            directory_table_base: 0x2000,
+            process_id: 4,
+            ..Default::default()
+        };
+        entry.image_file_name[..6].copy_from_slice(b"System");
+
+        let bytes = entry.to_bytes();
+        assert_eq!(ProcessEntry::SIZE, 40);
+        assert_eq!((bytes[1], bytes[9], bytes[16], &bytes[24..30]), (0x10, 0x20, 4, &b"System"[..]));
+        assert_eq!(entry.name(), b"System");
+    }
+
+    #[test]
+    fn watch_entries_and_events_have_a_fixed_layout() {
+        let entry = SyscallWatchEntry {
+            number: 0x55,
+            cr3: 0x1AD000,
+            hits: 3,
+        };
+        let bytes = entry.to_bytes();
+        assert_eq!(SyscallWatchEntry::SIZE, 24);
+        assert_eq!((bytes[0], bytes[9], bytes[10], bytes[16]), (0x55, 0xD0, 0x1A, 3));
+
+        let event = EventRecord {
+            kind: EVENT_SYSCALL,
+            processor: 2,
+            values: [0x55, 0x1AD000, 0x7FFE_0000],
+        };
+        let bytes = event.to_bytes();
+        assert_eq!(EventRecord::SIZE, 32);
+        assert_eq!((bytes[0], bytes[4], bytes[8], bytes[17], bytes[26]), (1, 2, 0x55, 0xD0, 0xFE));
+    }
+}
@@ -0,0 +1,50 @@
+//! Clears the loader's boot attempt counter once Windows takes over the machine.
+//!
+//! The loader increments `IllusionBootAttempt` before starting the hypervisor and skips it after too many
+//! boots that never got this far. Reaching `ExitBootServices` with the hypervisor running counts as a
+//! successful boot.
+
+use {
+    core::{ffi::c_void, ptr::NonNull},
+    log::debug,
+    uefi::{
+        prelude::*,
+        table::{
+            boot::{EventType, Tpl},
+            runtime::VariableVendor,
+        },
+        CStr16, Event, Guid,
+    },
+};
+
+/// Name of the variable holding the loader's boot attempt counter.
+const BOOT_ATTEMPT_VARIABLE: &CStr16 = cstr16!("IllusionBootAttempt");
+
+/// Vendor GUID of the variables shared with the loader.
+const ILLUSION_VENDOR: VariableVendor = VariableVendor(Guid::parse_or_panic(shared::VARIABLE_VENDOR));
+
+/// Registers the callback clearing the boot attempt counter when boot services are exited.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services table.
+///
+/// # Returns
+///
+/// Returns a `uefi::Result` indicating whether the event was created.
+pub fn clear_on_exit_boot_services(boot_services: &BootServices) -> uefi::Result<()> {
+    // The event is never closed, the callback lives in the resident runtime driver image.
+    unsafe { boot_services.create_event(EventType::SIGNAL_EXIT_BOOT_SERVICES, Tpl::NOTIFY, Some(clear_boot_attempt), None)? };
+    debug!("Registered ExitBootServices callback clearing {}", BOOT_ATTEMPT_VARIABLE);
+
+    Ok(())
+}
+
+/// Deletes the boot attempt counter.
+///
+/// Runs while the OS loader exits boot services, so it must neither allocate nor log.
+unsafe extern "efiapi" fn clear_boot_attempt(_event: Event, _context: Option<NonNull<c_void>>) {
+    if let Some(system_table) = uefi::table::system_table_boot() {
+        let _ = system_table.runtime_services().delete_variable(BOOT_ATTEMPT_VARIABLE, &ILLUSION_VENDOR);
+    }
+}
@@ -0,0 +1,49 @@
+//! The configuration blob of the image, in its own PE section so the loader can patch it.
+
+use {
+    core::ptr,
+    log::{debug, info, warn, LevelFilter},
+    shared::hvconfig::{HvConfig, CONFIG_FLAG_PATCHED},
+};
+
+/// The blob the loader patches, the build's defaults unless `illusion.cfg` changes them.
+#[used]
+#[link_section = ".ilcfg"]
+static CONFIG: HvConfig = HvConfig::DEFAULT;
+
+/// Reads the blob and applies it to the hypervisor.
+///
+/// # Returns
+///
+/// The highest level of messages that are logged.
+pub fn apply() -> LevelFilter {
+    // The loader changes the blob after compilation, so the compiler must not assume the initial value.
+    let mut config = unsafe { ptr::read_volatile(&CONFIG) };
+    if let Err(e) = config.check() {
+        warn!("Ignoring the configuration section: {:?}", e);
+        config = HvConfig::DEFAULT;
+    }
+
+    match config.flags & CONFIG_FLAG_PATCHED {
+        0 => debug!("Using the built-in configuration"),
+        _ => info!("Using the configuration patched in by the loader (flags {:#x})", config.flags),
+    }
+
+    hypervisor::config::apply(&config);
+    level_filter(config.log_level)
+}
+
+/// Converts a `shared::hvconfig::LOG_LEVEL_*` value.
+fn level_filter(level: u32) -> LevelFilter {
+    [
+        LevelFilter::Off,
+        LevelFilter::Error,
+        LevelFilter::Warn,
+        LevelFilter::Info,
+        LevelFilter::Debug,
+        LevelFilter::Trace,
+    ]
+    .get(level as usize)
+    .copied()
+    .unwrap_or(LevelFilter::Trace)
+}
@@ -0,0 +1,119 @@
+//! Reads the handoff the loader installed on the image handle of the hypervisor.
+//!
+//! The loader passes its build, the UART it logs to and the digest of this image in a
+//! `shared::handoff::LoaderHandoff`, along with the hypercall key for this boot. The hypervisor also runs when started from the shell or by another
+//! loader, so a missing or mismatched handoff is logged and otherwise ignored. Log lines are copied to the
+//! log ring the loader passes from here on, see `crate::logger`. The image answers through the same
+//! structure with `set_result`.
+
+use {
+    core::{
+        ptr,
+        sync::atomic::{AtomicPtr, Ordering},
+    },
+    log::{debug, info, warn},
+    shared::{
+        features::HvFeatureFlags,
+        handoff::{self, HvResult, LoaderHandoff, HANDOFF_PROTOCOL_GUID},
+    },
+    uefi::{prelude::*, proto::Protocol, Guid, Identify},
+};
+
+/// The handoff of the loader, null if the image was started without one.
+static HANDOFF: AtomicPtr<LoaderHandoff> = AtomicPtr::new(ptr::null_mut());
+
+/// The header of the handoff protocol, the rest is only read once the header matches.
+#[repr(C)]
+pub struct HandoffProtocol {
+    _header: [u64; 2],
+}
+
+unsafe impl Identify for HandoffProtocol {
+    const GUID: Guid = Guid::parse_or_panic(HANDOFF_PROTOCOL_GUID);
+}
+
+impl Protocol for HandoffProtocol {}
+
+/// Looks up and checks the handoff installed on the image handle.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services table.
+/// * `image_handle` - The handle of the hypervisor image.
+pub fn init(boot_services: &BootServices, image_handle: Handle) {
+    let interface = match boot_services.open_protocol_exclusive::<HandoffProtocol>(image_handle) {
+        Ok(protocol) => &*protocol as *const HandoffProtocol as *mut LoaderHandoff,
+        Err(e) => {
+            debug!("Started without a loader handoff ({:?})", e.status());
+            return;
+        }
+    };
+
+    // The loader allocated the handoff from runtime services data, it outlives the closed protocol.
+    let handoff = match unsafe { LoaderHandoff::from_ptr(interface) } {
+        Ok(handoff) => handoff,
+        Err(e) => {
+            warn!("Ignoring the loader handoff: {:?}", e);
+            return;
+        }
+    };
+
+    HANDOFF.store(handoff as *mut LoaderHandoff, Ordering::Relaxed);
+
+    if let Some((address, size)) = handoff.log_ring() {
+        // Safety: the loader frees the ring only after this image returned, `main` detaches it before.
+        if let Err(e) = unsafe { crate::logger::attach_ring(address, size) } {
+            warn!("Ignoring the loader log ring: {:?}", e);
+        }
+    }
+
+    info!(
+        "Started by loader {} ({}), image SHA-256 {:02x}{:02x}{:02x}{:02x}.., flags {:#x}",
+        handoff::from_fixed_string(&handoff.loader_version),
+        handoff::from_fixed_string(&handoff.loader_git_hash),
+        handoff.image_sha256[0],
+        handoff.image_sha256[1],
+        handoff.image_sha256[2],
+        handoff.image_sha256[3],
+        handoff.flags
+    );
+    // The key itself is never logged, the client reads it from the variable the loader wrote.
+    if let Some(key) = handoff.vmcall_key() {
+        hypervisor::config::set_vmcall_key(key);
+        info!("Using the hypercall key the loader generated for this boot");
+    }
+
+    let features = handoff.features();
+    hypervisor::config::set_features(features);
+    info!("Features for this boot: {:#x}", features.bits());
+    if features.contains(HvFeatureFlags::RDTSC_COMPENSATION) {
+        info!("RDTSC compensation is enabled, holding the time stamp counter back by at most {} cycles", hypervisor::config::tsc_skew_limit());
+    }
+    if features.contains(HvFeatureFlags::PREFER_SVM) {
+        warn!("The loader expected an AMD SVM hypervisor, this image only supports Intel VT-x");
+    }
+    if features.unknown_bits() != 0 {
+        debug!("Ignoring unknown feature bits {:#x}", features.unknown_bits());
+    }
+
+    match handoff.serial_port {
+        0 => debug!("Loader serial logging is disabled"),
+        port => debug!("Loader logs to the UART at {:#x} with {} baud", port, handoff.serial_baud_rate),
+    }
+}
+
+/// Tells the loader what to do once the entry point returned, ignored without a loader handoff.
+///
+/// # Arguments
+///
+/// * `result` - The answer to store in the handoff.
+pub fn set_result(result: HvResult) {
+    let handoff = HANDOFF.load(Ordering::Relaxed);
+    if handoff.is_null() {
+        debug!("No loader handoff to answer {:?} in", result);
+        return;
+    }
+
+    // Safety: the loader reads the handoff only after `start_image` returned.
+    unsafe { (*handoff).set_result(result) };
+}
@@ -0,0 +1,99 @@
+//! Logs to the UEFI console and, while the hypervisor starts, to the loader's log ring.
+//!
+//! Messages are printed like the logger of the `uefi` crate did. When the loader passes a
+//! `shared::logring` in the handoff, every line is also copied into it, so the loader can repeat the
+//! startup log once `StartImage` returned. The ring is detached before the entry point returns, the loader
+//! frees its pages right after. Every line is kept in the recent lines of the hypervisor too, which crash
+//! reports of the host repeat.
+
+use {
+    core::{fmt::Write, ptr, slice},
+    hypervisor::logger::{record_recent_line, LineBuffer},
+    log::{Log, Metadata, Record},
+    shared::logring::LogRing,
+    spin::Mutex,
+    uefi::{prelude::*, proto::console::text::Output},
+};
+
+/// The global logger.
+static LOGGER: Logger = Logger {
+    state: Mutex::new(State {
+        console: ptr::null_mut(),
+        ring: None,
+    }),
+};
+
+/// Where log lines go.
+struct State {
+    /// The console output of the system table, null once disabled.
+    console: *mut Output,
+
+    /// The loader's log ring while it is attached.
+    ring: Option<LogRing<'static>>,
+}
+
+/// Logger writing to the console and the attached log ring.
+struct Logger {
+    state: Mutex<State>,
+}
+
+// Safety: the console pointer is only used with the lock held.
+unsafe impl Send for State {}
+
+/// Installs the logger, writing to the console of `system_table`.
+///
+/// # Arguments
+///
+/// * `system_table` - A mutable reference to the UEFI System Table.
+pub fn init(system_table: &mut SystemTable<Boot>) -> Result<(), log::SetLoggerError> {
+    LOGGER.state.lock().console = system_table.stdout() as *mut Output;
+    log::set_logger(&LOGGER)
+}
+
+/// Copies every following line into the log ring at `address`.
+///
+/// # Arguments
+///
+/// * `address` - The physical address of the ring, as passed in the handoff.
+/// * `size` - The size of the ring memory in bytes.
+///
+/// # Safety
+///
+/// The memory must stay allocated until `detach_ring` is called.
+pub unsafe fn attach_ring(address: u64, size: usize) -> Result<(), shared::logring::LogRingError> {
+    let memory = unsafe { slice::from_raw_parts_mut(address as *mut u8, size) };
+    let ring = LogRing::open(memory)?;
+    LOGGER.state.lock().ring = Some(ring);
+    Ok(())
+}
+
+/// Stops copying lines into the log ring, which the loader frees after the entry point returned.
+pub fn detach_ring() {
+    LOGGER.state.lock().ring = None;
+}
+
+impl Log for Logger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let file = record.file().unwrap_or("<unknown file>");
+        let line = record.line().unwrap_or(0);
+        let mut state = self.state.lock();
+
+        // Errors are ignored, there is nowhere to report them.
+        if let Some(console) = unsafe { state.console.as_mut() } {
+            let _ = writeln!(console, "[{:>5}]: {:>12}@{:03}: {}", record.level(), file, line, record.args());
+        }
+
+        let mut buffer = LineBuffer::new();
+        let _ = write!(buffer, "[{:>5}] {}@{}: {}", record.level(), file, line, record.args());
+        if let Some(ring) = state.ring.as_mut() {
+            ring.push(buffer.as_bytes());
+        }
+        record_recent_line(buffer.as_bytes());
+    }
+
+    fn flush(&self) {}
+}
@@ -9,15 +9,24 @@ extern crate alloc;
 
 use {
     crate::{processor::start_hypervisor_on_all_processors, setup::setup, stack::init},
-    hypervisor::allocator::heap_init,
+    hypervisor::{allocator::heap_init, error::HypervisorError, vmm::check_platform},
     log::*,
+    shared::handoff::{
+        HvResult, ABORT_REASON_FEATURE_CONTROL_LOCKED, ABORT_REASON_NO_EPT, ABORT_REASON_NO_MTRR, ABORT_REASON_NO_VMX, ABORT_REASON_UNSPECIFIED,
+        ABORT_REASON_UNSUPPORTED_CPU,
+    },
     uefi::prelude::*,
 };
 
+pub mod boot_attempt;
+pub mod config;
+pub mod handoff;
 pub mod hide;
+pub mod logger;
 pub mod processor;
 pub mod setup;
 pub mod stack;
+pub mod status;
 pub mod virtualize;
 
 /// Custom panic handler for the UEFI application.
@@ -45,7 +54,7 @@ fn panic_handler(info: &core::panic::PanicInfo) -> ! {
 ///
 /// # Arguments
 ///
-/// * `_image_handle` - Handle to the loaded image of the application.
+/// * `image_handle` - Handle to the loaded image of the application.
 /// * `system_table` - Reference to the UEFI System Table.
 ///
 /// # Returns
@@ -53,7 +62,7 @@ fn panic_handler(info: &core::panic::PanicInfo) -> ! {
 /// The status of the application execution. Returns `Status::SUCCESS` on successful execution,
 /// or `Status::ABORTED` if the hypervisor fails to install.
 #[entry]
-fn main(_image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
+fn main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     unsafe {
         // Initialize the stack allocator.
         init(&mut system_table);
@@ -62,7 +71,7 @@ fn main(_image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     }
 
     // Initialize UEFI console logger so the user sees at least one message on screen
-    if let Err(e) = uefi::helpers::init(&mut system_table) {
+    if let Err(e) = logger::init(&mut system_table) {
         // If this fails, we still continue with serial logging below
         // but there will be no on-screen message.
         let _ = e;
@@ -71,13 +80,34 @@ fn main(_image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
         log::info!("illusion.efi started. Logging to EFI Shell console. For more details, ensure the log level is set appropriately.");
     }
 
-    // Ensure the UEFI logger prints debug messages too
-    log::set_max_level(LevelFilter::Debug);
+    // Debug messages by default, unless the loader patched another level into the configuration section.
+    log::set_max_level(config::apply());
 
     info!("The Matrix is an illusion");
 
+    let status = run(image_handle, &system_table);
+
+    // The loader frees the log ring as soon as this image returns.
+    logger::detach_ring();
+    status
+}
+
+/// Sets up and starts the hypervisor once logging is initialized.
+///
+/// # Arguments
+///
+/// * `image_handle` - Handle to the loaded image of the application.
+/// * `system_table` - Reference to the UEFI System Table.
+///
+/// # Returns
+///
+/// The status `main` returns.
+fn run(image_handle: Handle, system_table: &SystemTable<Boot>) -> Status {
     let boot_services = system_table.boot_services();
 
+    // Everything the loader knows about this boot, if it was started by the loader.
+    handoff::init(boot_services, image_handle);
+
     #[cfg(feature = "hide_uefi_memory")]
     {
         debug!("Hiding hypervisor memory from UEFI");
@@ -87,6 +117,14 @@ fn main(_image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
         }
     }
 
+    // An unsupported platform is found before any processor is virtualized, the loader stops the boot
+    // instead of starting Windows on a half-virtualized machine.
+    if let Err(e) = check_platform() {
+        error!("The hypervisor cannot run on this platform: {:?}", e);
+        handoff::set_result(HvResult::AbortBoot(abort_reason(&e)));
+        return Status::UNSUPPORTED;
+    }
+
     // Set up the hypervisor
     debug!("Setting up the hypervisor");
     if let Err(e) = setup(boot_services) {
@@ -101,6 +139,28 @@ fn main(_image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
         return Status::ABORTED;
     }
 
+    // Tools started later only lose a way to ask for the status without a hypercall.
+    if let Err(e) = status::install(boot_services) {
+        error!("Failed to install the status protocol: {:?}", e);
+    }
+
+    // A failure only means that the loader keeps counting this boot as unfinished.
+    if let Err(e) = boot_attempt::clear_on_exit_boot_services(boot_services) {
+        error!("Failed to register the boot attempt callback: {:?}", e);
+    }
+
     // Return success status to UEFI environment.
     Status::SUCCESS
 }
+
+/// Returns the `shared::handoff::ABORT_REASON_*` code the loader explains `error` with.
+fn abort_reason(error: &HypervisorError) -> u32 {
+    match error {
+        HypervisorError::CPUUnsupported => ABORT_REASON_UNSUPPORTED_CPU,
+        HypervisorError::VMXUnsupported => ABORT_REASON_NO_VMX,
+        HypervisorError::EPTUnsupported => ABORT_REASON_NO_EPT,
+        HypervisorError::MTRRUnsupported => ABORT_REASON_NO_MTRR,
+        HypervisorError::VMXBIOSLock => ABORT_REASON_FEATURE_CONTROL_LOCKED,
+        _ => ABORT_REASON_UNSPECIFIED,
+    }
+}
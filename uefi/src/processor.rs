@@ -2,15 +2,26 @@
 //! facilitating the initialization of virtualization across multiple processors.
 
 use {
-    crate::virtualize::virtualize_system,
+    crate::{handoff, virtualize::virtualize_system},
     core::ffi::c_void,
-    hypervisor::intel::capture::{capture_registers, GuestRegisters},
+    hypervisor::intel::{
+        capture::{capture_registers, GuestRegisters},
+        vcpu,
+    },
     log::*,
+    shared::{
+        handoff::HvResult,
+        hvstatus::{CPU_STATE_FAILED, CPU_STATE_VIRTUALIZED},
+    },
     uefi::{prelude::*, proto::pi::mp::MpServices},
 };
 
 /// Starts the hypervisor on all processors.
 ///
+/// The bootstrap processor goes first. If it fails, no application processor is started and the loader
+/// is asked to boot without the hypervisor. Application processors that fail keep running bare metal and
+/// are reported through the handoff, see `hypervisor::intel::vcpu`.
+///
 /// # Arguments
 ///
 /// * `boot_services` - A reference to the UEFI Boot Services.
@@ -26,19 +37,28 @@ pub fn start_hypervisor_on_all_processors(boot_services: &BootServices) -> uefi:
     info!("Total processors: {}", processor_count.total);
     info!("Enabled processors: {}", processor_count.enabled);
 
+    // Don't forget to virtualize this thread...
+    if !start_hypervisor() {
+        error!("Failed to virtualize the bootstrap processor, not starting the others");
+        handoff::set_result(HvResult::ContinueWithoutHv);
+        return Err(Status::ABORTED.into());
+    }
+
     if processor_count.enabled == 1 {
-        info!("Found only one processor, virtualizing it");
-        start_hypervisor();
+        info!("Found only one processor, virtualized it");
     } else {
         info!("Found multiple processors, virtualizing all of them");
 
-        // Don't forget to virtualize this thread...
-        start_hypervisor();
-
         // Virtualize all other threads...
         mp_services.startup_all_aps(true, start_hypervisor_on_ap as _, core::ptr::null_mut(), None, None)?;
     }
 
+    let failed = vcpu::count(CPU_STATE_FAILED);
+    if failed != 0 {
+        warn!("{} of {} processor(s) failed to start the hypervisor and run bare metal", failed, vcpu::started());
+        handoff::set_result(HvResult::ProcessorsFailed(failed as u32));
+    }
+
     info!("The hypervisor has been installed successfully!");
 
     Ok(())
@@ -54,14 +74,25 @@ extern "efiapi" fn start_hypervisor_on_ap(_procedure_argument: *mut c_void) {
 }
 
 /// Initiates the virtualization process.
-fn start_hypervisor() {
+///
+/// # Returns
+///
+/// Whether the current processor now runs as a guest of the hypervisor.
+fn start_hypervisor() -> bool {
+    // Recorded before the registers are captured, so the guest resuming below still finds its entry.
+    let Some(vcpu) = vcpu::start() else {
+        error!("Processor {} is out of the range of supported APIC IDs", vcpu::current_apic_id());
+        return false;
+    };
+
     let mut guest_registers = GuestRegisters::default();
     // Unsafe block to capture the current CPU's register state.
     let is_virtualized = unsafe { capture_registers(&mut guest_registers) };
 
     // After `vmlaunch`, Guest execution will begin here. We then check for an existing hypervisor:
     // if absent, proceed with installation; otherwise, no further action is needed.
-    // The guest will return here and it will have it's value of rax set to 1, meaning the logical core is virtualized.
+    // The guest will return here and it will have it's value of rax set to 1, meaning the logical core is virtualized,
+    // or that the hypervisor gave up on it and marked it as failed.
     guest_registers.rax = 1;
 
     // Proceed with virtualization only if the current processor is not yet virtualized.
@@ -70,4 +101,11 @@ fn start_hypervisor() {
         debug!("Virtualizing the system");
         virtualize_system(&guest_registers);
     }
+
+    if vcpu.state() == CPU_STATE_FAILED {
+        return false;
+    }
+
+    vcpu.set_state(CPU_STATE_VIRTUALIZED);
+    true
 }
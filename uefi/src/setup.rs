@@ -8,14 +8,21 @@ use {
         allocator::box_zeroed,
         intel::{
             hooks::hook_manager::{HookManager, SHARED_HOOK_MANAGER},
+            host_arch,
+            memory_map::{set_physical_memory_map, PhysicalMemoryMap},
+            mtrr::Mtrr,
             page::Page,
         },
     },
-    log::debug,
-    uefi::{prelude::BootServices, proto::loaded_image::LoadedImage},
+    log::{debug, warn},
+    uefi::{
+        prelude::BootServices,
+        proto::loaded_image::LoadedImage,
+        table::boot::{MemoryType, PAGE_SIZE},
+    },
 };
 
-/// Sets up the hypervisor by recording the image base, creating a dummy page, initializing the shared hook manager, and nullifying relocations.
+/// Sets up the hypervisor by recording the image base, creating a dummy page, initializing the shared hook manager, capturing the memory map, and nullifying relocations.
 ///
 /// # Arguments
 ///
@@ -31,6 +38,11 @@ pub fn setup(boot_services: &BootServices) -> uefi::Result<()> {
     let dummpy_page_pa = create_dummy_page(0xFF);
     HookManager::initialize_shared_hook_manager(dummpy_page_pa);
 
+    // Without the memory map the EPT maps everything below 512GB.
+    if let Err(e) = capture_memory_map(boot_services) {
+        warn!("Failed to capture the memory map, identity mapping the first 512GB: {:?}", e);
+    }
+
     let image_base = loaded_image.info().0 as u64;
     zap_relocations(image_base);
 
@@ -40,7 +52,7 @@ pub fn setup(boot_services: &BootServices) -> uefi::Result<()> {
 /// Records the base address and size of the loaded UEFI image.
 ///
 /// This function retrieves the base address and size of the loaded UEFI image
-/// and records this information for memory tracking purposes.
+/// and records this information for memory tracking purposes and for host crash reports.
 ///
 /// # Arguments
 ///
@@ -49,12 +61,36 @@ pub fn record_image_base(loaded_image: &LoadedImage) {
     let (image_base, image_size) = loaded_image.info();
     let image_range = image_base as usize..(image_base as usize + image_size as usize);
     debug!("Loaded image base: {:#x?}", image_range);
+    host_arch::record_image(image_base as u64, image_size);
 
     // Lock the shared hook manager
     let mut hook_manager = SHARED_HOOK_MANAGER.lock();
     hook_manager.record_allocation(image_base as usize, image_size as usize);
 }
 
+/// Captures the UEFI memory map the EPT identity map of every processor is built from.
+///
+/// The firmware map is completed with the low 4GB and the MMIO ranges the MTRRs imply, see
+/// `hypervisor::intel::memory_map`.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services table.
+pub fn capture_memory_map(boot_services: &BootServices) -> uefi::Result<()> {
+    let memory_map = boot_services.memory_map(MemoryType::LOADER_DATA)?;
+
+    let mut map = PhysicalMemoryMap::new();
+    for descriptor in memory_map.entries() {
+        map.add(descriptor.phys_start..descriptor.phys_start + descriptor.page_count * PAGE_SIZE as u64);
+    }
+    map.add_platform_ranges(&Mtrr::new());
+
+    debug!("Physical memory map: {} ranges up to {:#x}", map.ranges().count(), map.end());
+    set_physical_memory_map(map);
+
+    Ok(())
+}
+
 /// Creates a dummy page filled with a specific byte value.
 ///
 /// This function allocates a page of memory and fills it with a specified byte value.
@@ -0,0 +1,96 @@
+//! Installs the status protocol of `shared::hvstatus` once the processors are virtualized.
+//!
+//! The protocol is installed on a new handle and never removed, its interface and functions live in this
+//! image, which stays resident as long as the hypervisor runs.
+
+use {
+    core::{ffi::c_void, ptr},
+    hypervisor::{config, stats},
+    log::debug,
+    shared::{
+        handoff,
+        hvstatus::{
+            HvCpuStatus, HvHookSwapStatus, HvStats, HvStatusProtocol, HvVersion, STATUS_INVALID_PARAMETER, STATUS_NOT_FOUND, STATUS_PROTOCOL_GUID,
+            STATUS_PROTOCOL_REVISION, STATUS_SUCCESS,
+        },
+    },
+    uefi::{prelude::*, Guid},
+};
+
+/// GUID of the status protocol.
+const STATUS_GUID: Guid = Guid::parse_or_panic(STATUS_PROTOCOL_GUID);
+
+/// The interface installed for the protocol.
+static PROTOCOL: HvStatusProtocol = HvStatusProtocol {
+    revision: STATUS_PROTOCOL_REVISION,
+    size: core::mem::size_of::<HvStatusProtocol>() as u32,
+    get_version,
+    get_cpu_status,
+    get_stats,
+    get_hook_swap_status,
+};
+
+/// Installs the status protocol on a new handle.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services table.
+///
+/// # Returns
+///
+/// Returns a `uefi::Result` indicating whether the protocol was installed.
+pub fn install(boot_services: &BootServices) -> uefi::Result<()> {
+    let interface = ptr::addr_of!(PROTOCOL) as *const c_void;
+    let handle = unsafe { boot_services.install_protocol_interface(None, &STATUS_GUID, interface)? };
+    debug!("Installed the status protocol on handle {:?}", handle.as_ptr());
+
+    Ok(())
+}
+
+/// Fills the build of the hypervisor.
+unsafe extern "efiapi" fn get_version(version: *mut HvVersion) -> usize {
+    let Some(version) = (unsafe { version.as_mut() }) else {
+        return STATUS_INVALID_PARAMETER;
+    };
+
+    *version = HvVersion {
+        version: handoff::to_fixed_string(env!("CARGO_PKG_VERSION")),
+        features: config::features().bits(),
+    };
+    STATUS_SUCCESS
+}
+
+/// Fills the status of the `cpu_index`-th processor to start.
+unsafe extern "efiapi" fn get_cpu_status(cpu_index: u32, status: *mut HvCpuStatus) -> usize {
+    let Some(status) = (unsafe { status.as_mut() }) else {
+        return STATUS_INVALID_PARAMETER;
+    };
+
+    match stats::cpu_status(cpu_index as usize) {
+        Some(cpu_status) => {
+            *status = cpu_status;
+            STATUS_SUCCESS
+        }
+        None => STATUS_NOT_FOUND,
+    }
+}
+
+/// Fills the counters of the hypervisor.
+unsafe extern "efiapi" fn get_stats(counters: *mut HvStats) -> usize {
+    let Some(counters) = (unsafe { counters.as_mut() }) else {
+        return STATUS_INVALID_PARAMETER;
+    };
+
+    *counters = stats::stats();
+    STATUS_SUCCESS
+}
+
+/// Fills how the views of EPT hooks are switched.
+unsafe extern "efiapi" fn get_hook_swap_status(status: *mut HvHookSwapStatus) -> usize {
+    let Some(status) = (unsafe { status.as_mut() }) else {
+        return STATUS_INVALID_PARAMETER;
+    };
+
+    *status = stats::hook_swap_status();
+    STATUS_SUCCESS
+}
@@ -5,15 +5,18 @@
 
 use {
     crate::stack::allocate_host_stack,
-    core::{alloc::Layout, arch::global_asm},
+    core::{alloc::Layout, arch::global_asm, mem::size_of},
     hypervisor::{
         global_const::STACK_PAGES_PER_PROCESSOR,
-        intel::{capture::GuestRegisters, page::Page},
+        intel::{capture::GuestRegisters, page::Page, regions::ContiguousPage},
         vmm::start_hypervisor,
     },
     log::debug,
 };
 
+/// The pages of the host stack of one processor.
+type HostStack = ContiguousPage<[Page; STACK_PAGES_PER_PROCESSOR]>;
+
 /// Installs the hypervisor on the current processor.
 ///
 /// # Arguments
@@ -22,19 +25,21 @@ use {
 pub fn virtualize_system(guest_registers: &GuestRegisters) -> ! {
     debug!("Allocating stack space for host");
 
-    let layout = Layout::array::<Page>(STACK_PAGES_PER_PROCESSOR).unwrap();
-    let stack = unsafe { allocate_host_stack(layout) };
-    let size = layout.size();
-
-    debug!("Zeroing stack space for host");
-    unsafe { core::ptr::write_bytes(stack, 0, size) }
+    let layout = Layout::new::<HostStack>();
+    let stack = unsafe { allocate_host_stack(layout) } as *mut HostStack;
 
     if stack == core::ptr::null_mut() {
         panic!("Failed to allocate stack");
     }
 
-    let stack_base = stack as u64 + layout.size() as u64 - 0x10;
-    log::trace!("Stack range: {:#x?}", stack as u64..stack_base);
+    debug!("Zeroing stack space for host");
+    // Safety: the allocation is large and aligned enough for the region, which `init` zeroes.
+    let stack = unsafe { &mut *stack };
+    stack.init();
+
+    let stack_start = stack.physical_address();
+    let stack_base = stack_start + size_of::<[Page; STACK_PAGES_PER_PROCESSOR]>() as u64 - 0x10;
+    log::trace!("Stack range: {:#x?}", stack_start..stack_base);
 
     unsafe { switch_stack(guest_registers, start_hypervisor as usize, stack_base as _) };
 }